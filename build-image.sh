@@ -2,4 +2,10 @@
 
 
 cargo build --color=always
-cargo bootimage
\ No newline at end of file
+./ksyms.sh target/x86_64-blog_os/debug/blog_os
+cargo bootimage
+# the initial ramdisk goes to QEMU next to the image, as
+#   -fw_cfg name=opt/blog_os/initrd,file=target/initrd.tar
+if [ -d initrd ]; then
+    tar --format=ustar -cf target/initrd.tar -C initrd .
+fi
@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+# Takes the crash dump out of a serial log, one file per section, see src/crash/kdump.rs.
+#   ./crashdump.sh serial.log [directory]
+set -e
+
+log="$1"
+out="${2:-crashdump}"
+if [ -z "$log" ]; then
+    echo "usage: $0 <serial log> [directory]" >&2
+    exit 1
+fi
+mkdir -p "$out"
+
+# the sections of the dump as hex files
+awk -v out="$out" '
+    /^crashdump: begin / { dumping = 1; next }
+    !dumping { next }
+    /^crashdump: section / { name = $3; file = out "/" name ".hex"; printf "" > file; next }
+    /^crashdump: end / { close(file); print $3, $4, $5 > (out "/sections"); name = ""; next }
+    /^crashdump: finish/ { dumping = 0; next }
+    name != "" { print > file }
+' "$log"
+
+if [ ! -s "$out/sections" ]; then
+    echo "crashdump: no dump in $log" >&2
+    exit 1
+fi
+
+status=0
+while read -r name length crc; do
+    xxd -r -p "$out/$name.hex" > "$out/$name"
+    rm "$out/$name.hex"
+    size=$(stat -c %s "$out/$name")
+    # gzip ends with the CRC32 of its input, little endian
+    actual=$(gzip -c "$out/$name" | tail -c8 | head -c4 | od -An -tx4 | tr -d ' ')
+    if [ "$size" != "$length" ] || [ "$actual" != "$crc" ]; then
+        echo "crashdump: $name is damaged, $size of $length bytes, crc $actual instead of $crc" >&2
+        status=1
+    else
+        echo "$name: $size bytes"
+    fi
+done < "$out/sections"
+rm "$out/sections"
+exit $status
@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+# Fills the kernel's .ksyms section with its function symbols, for symbolized backtraces.
+set -e
+
+kernel="$1"
+size=$(objdump -h "$kernel" | awk '$2 == ".ksyms" { print $3 }')
+if [ -z "$size" ]; then
+    echo "ksyms: $kernel has no .ksyms section" >&2
+    exit 1
+fi
+size=$((16#$size))
+
+table=$(mktemp)
+trap 'rm -f "$table"' EXIT
+{
+    printf 'KSYM\n'
+    # functions only, sorted by address, without the hashes rustc appends
+    nm -n -C --defined-only "$kernel" \
+        | grep -E '^[0-9a-f]{16} [tT] ' \
+        | cut -d' ' -f1,3- \
+        | sed -E 's/::h[0-9a-f]{16}$//'
+} > "$table"
+
+if [ "$(stat -c %s "$table")" -ge "$size" ]; then
+    echo "ksyms: the symbol table does not fit into $size bytes" >&2
+    exit 1
+fi
+truncate -s "$size" "$table"
+objcopy --update-section .ksyms="$table" "$kernel"
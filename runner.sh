@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+# Cargo runner: embeds the symbol table, then boots the kernel as before.
+set -e
+
+crate="$(dirname "$0")"
+"$crate/ksyms.sh" "$1"
+
+# the initrd/ directory as the initial ramdisk, see src/fs/initrd.rs; tests go without,
+# and get a FAT32 volume made by the host's tools instead, see src/fs/testdata/fat32.sh
+qemu_args=()
+case "$1" in
+    */deps/*)
+        if command -v mkfs.fat > /dev/null && command -v mcopy > /dev/null; then
+            "$crate/src/fs/testdata/fat32.sh" "$crate/target/fat32.img"
+            qemu_args+=(-fw_cfg "name=opt/blog_os/fat32.img,file=$crate/target/fat32.img")
+        else
+            echo "runner: no dosfstools or mtools, the host-made FAT32 test will fail" >&2
+        fi
+        ;;
+    *)
+        if [ -d "$crate/initrd" ]; then
+            tar --format=ustar -cf "$crate/target/initrd.tar" -C "$crate/initrd" .
+            qemu_args+=(-fw_cfg "name=opt/blog_os/initrd,file=$crate/target/initrd.tar")
+        fi
+        ;;
+esac
+
+status=0
+if [ -n "$BLOG_OS_TEST_FILTER" ]; then
+    # read by the kernel's test runner, see src/testing.rs
+    printf 'filter %s\n' "$BLOG_OS_TEST_FILTER" | bootimage runner "$@" "${qemu_args[@]}" || status=$?
+else
+    bootimage runner "$@" "${qemu_args[@]}" || status=$?
+fi
+
+# the codes written to isa-debug-exit, see src/qemu.rs
+case $status in
+    35) echo "test run failed" >&2 ;;
+    37) echo "test run timed out" >&2 ;;
+    39) echo "test run panicked" >&2 ;;
+    41) echo "test run failed an assertion" >&2 ;;
+    43) echo "test run failed a kernel assertion" >&2 ;;
+esac
+exit $status
@@ -0,0 +1,223 @@
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+/* ACPI tables.
+    Only reading what the firmware left: the RSDP is found in the first KiB of the
+    EBDA or in the BIOS area below 1 MiB, and points to the RSDT, or with ACPI 2 the
+    XSDT, which lists the other tables by physical address. Every table is checked
+    against its checksum before it is believed. Nothing is written and no AML is run:
+    the DSDT is only searched for the \_S5 package, which says how to turn off.
+ */
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// the real mode segment of the EBDA is kept here
+const EBDA_SEGMENT: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+const HEADER_LEN: usize = 36;
+
+// the FADT's flag for a reset register
+const RESET_REG_SUP: u32 = 1 << 10;
+// the FADT up to the century field, the last one read from every table
+const FADT_MIN_LEN: usize = 109;
+
+// the AML opcodes a sleep state package is made of
+const NAME_OP: u8 = 0x08;
+const ROOT_PREFIX: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+
+/// Where a register of the hardware is, in ACPI's Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// What the kernel uses of the Fixed ACPI Description Table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// The register a write of the value to resets the machine.
+    pub reset: Option<(GenericAddress, u8)>,
+    /// The physical address of the DSDT.
+    pub dsdt: u64,
+    /// The port `acpi_enable` is written to, to hand the hardware to the OS; 0 when it always has it.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    /// The ports of the PM1 control registers, 0 for a block that is not there.
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    /// The CMOS register the real time clock keeps the century in, 0 when it does not.
+    pub century: u8,
+}
+
+/// `len` bytes of physical memory from `address`.
+fn physical(address: u64, len: usize) -> &'static [u8] {
+    let virt = memory::phys_to_virt(PhysAddr::new(address));
+    unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn find_rsdp_in(start: u64, end: u64) -> Option<&'static [u8]> {
+    let address = (start..end).step_by(16).find(|&address| {
+        let rsdp = physical(address, 20);
+        &rsdp[..8] == RSDP_SIGNATURE && checksum_ok(rsdp)
+    })?;
+    // ACPI 2 and later add the XSDT, and a checksum over the longer structure
+    if physical(address, 20)[15] >= 2 {
+        let rsdp = physical(address, u32_at(physical(address, 24), 20) as usize);
+        return checksum_ok(rsdp).then_some(rsdp);
+    }
+    Some(physical(address, 20))
+}
+
+fn rsdp() -> Option<&'static [u8]> {
+    // physical memory is only reachable once paging is set up
+    if memory::physical_memory_offset().is_null() {
+        return None;
+    }
+    let ebda = (u16::from_le_bytes(physical(EBDA_SEGMENT, 2).try_into().unwrap()) as u64) << 4;
+    let in_ebda = (ebda != 0).then(|| find_rsdp_in(ebda, ebda + 1024)).flatten();
+    in_ebda.or_else(|| find_rsdp_in(BIOS_AREA.0, BIOS_AREA.1))
+}
+
+/// The table at `address`, if its checksum holds.
+fn table(address: u64) -> Option<&'static [u8]> {
+    let len = u32_at(physical(address, HEADER_LEN), 4) as usize;
+    let table = physical(address, len.max(HEADER_LEN));
+    checksum_ok(table).then_some(table)
+}
+
+/// The table with `signature`, as in `*b"FACP"`, header included.
+pub fn find_table(signature: [u8; 4]) -> Option<&'static [u8]> {
+    let rsdp = rsdp()?;
+    let (root, entry_len) = if rsdp[15] >= 2 && rsdp.len() >= 32 && u64_at(rsdp, 24) != 0 {
+        (table(u64_at(rsdp, 24))?, 8)
+    } else {
+        (table(u32_at(rsdp, 16) as u64)?, 4)
+    };
+    root[HEADER_LEN..].chunks_exact(entry_len)
+        .map(|entry| if entry_len == 8 { u64_at(entry, 0) } else { u32_at(entry, 0) as u64 })
+        .filter_map(table)
+        .find(|table| table[..4] == signature)
+}
+
+pub fn fadt() -> Option<Fadt> {
+    parse_fadt(find_table(*b"FACP")?)
+}
+
+/// A table cut short by the firmware gives `None` rather than reads past its end.
+fn parse_fadt(table: &[u8]) -> Option<Fadt> {
+    if table.len() < FADT_MIN_LEN {
+        return None;
+    }
+    // ACPI 1 tables end before the reset register
+    let reset = (table.len() > 128 && u32_at(table, 112) & RESET_REG_SUP != 0).then(|| {
+        (GenericAddress { space: table[116], address: u64_at(table, 120) }, table[128])
+    });
+    // the 64 bit address wins when there is one
+    let x_dsdt = if table.len() >= 148 { u64_at(table, 140) } else { 0 };
+    Some(Fadt {
+        reset,
+        dsdt: if x_dsdt != 0 { x_dsdt } else { u32_at(table, 40) as u64 },
+        smi_command: u32_at(table, 48),
+        acpi_enable: table[52],
+        pm1a_control: u32_at(table, 64),
+        pm1b_control: u32_at(table, 68),
+        century: table[108],
+    })
+}
+
+/// The DSDT, which the FADT points to rather than the RSDT.
+pub fn dsdt() -> Option<&'static [u8]> {
+    let table = table(fadt()?.dsdt)?;
+    (table[..4] == *b"DSDT").then_some(table)
+}
+
+/// An integer constant at the start of `aml`, and what follows it.
+fn integer(aml: &[u8]) -> Option<(u8, &[u8])> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, &aml[1..])),
+        ONE_OP => Some((1, &aml[1..])),
+        BYTE_PREFIX => Some((*aml.get(1)?, aml.get(2..)?)),
+        _ => None,
+    }
+}
+
+/// The SLP_TYPa and SLP_TYPb values of the sleep state package `name` in `aml`.
+fn sleep_types(aml: &[u8], name: &[u8; 4]) -> Option<(u8, u8)> {
+    // the name is used elsewhere too; the package is where it is defined
+    let at = (0..aml.len().saturating_sub(3)).find(|&at| {
+        &aml[at..at + 4] == name
+            && (at >= 1 && aml[at - 1] == NAME_OP || at >= 2 && aml[at - 2] == NAME_OP && aml[at - 1] == ROOT_PREFIX)
+    })?;
+    let package = aml.get(at + 4..)?;
+    if *package.first()? != PACKAGE_OP {
+        return None;
+    }
+    // the top two bits of the length's lead byte count the bytes after it, then the element count
+    let length_bytes = (*package.get(1)? >> 6) as usize + 1;
+    let (a, rest) = integer(package.get(1 + length_bytes + 1..)?)?;
+    let b = integer(rest).map_or(0, |(b, _)| b);
+    Some((a, b))
+}
+
+/// The SLP_TYPa and SLP_TYPb values that turn the machine off, from the DSDT.
+pub fn s5_sleep_types() -> Option<(u8, u8)> {
+    sleep_types(dsdt()?, b"_S5_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_tables() {
+        // QEMU's firmware always has them
+        assert!(rsdp().is_some());
+        assert!(fadt().is_some());
+        assert!(find_table(*b"NONE").is_none());
+        assert!(dsdt().is_some());
+        assert_ne!(fadt().unwrap().pm1a_control, 0);
+        assert!(s5_sleep_types().is_some());
+    }
+
+    #[test_case]
+    fn test_sleep_types() {
+        // Name (\_S5, Package (0x04) { 0x05, One, Zero, Zero }), after a reference to it
+        let aml = [0x70, b'_', b'S', b'5', b'_', NAME_OP, ROOT_PREFIX, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x08, 0x04, BYTE_PREFIX, 0x05, ONE_OP, ZERO_OP, ZERO_OP];
+        assert_eq!(sleep_types(&aml, b"_S5_"), Some((5, 1)));
+        assert_eq!(sleep_types(&aml[..13], b"_S5_"), None);
+        assert_eq!(sleep_types(&aml, b"_S3_"), None);
+    }
+
+    #[test_case]
+    fn test_short_fadt() {
+        let mut table = [0u8; 116];
+        table[64..68].copy_from_slice(&0x604u32.to_le_bytes());
+        table[108] = 0x32;
+        // an ACPI 1 table, without the reset register or the 64 bit DSDT address
+        let fadt = parse_fadt(&table).unwrap();
+        assert_eq!((fadt.pm1a_control, fadt.century, fadt.reset), (0x604, 0x32, None));
+        assert!(parse_fadt(&table[..FADT_MIN_LEN - 1]).is_none());
+        assert!(parse_fadt(&table[..HEADER_LEN]).is_none());
+    }
+}
@@ -0,0 +1,270 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::backtrace::Backtrace;
+use crate::sync::SpinLock;
+use crate::{cmdline, symbols};
+
+/* allocation tracking, for hunting leaks.
+    With `kmemleak` on the command line every allocation is entered in a table on the
+    side, with the return addresses it was made from, and taken out again when it is
+    freed; `report`, and /proc/kmemleak, list what is still there grouped by the
+    function that asked for it. The table is fixed, the allocator cannot allocate for
+    itself, and allocations made while it is full are only counted. Off by default,
+    every allocation walks the stack.
+ */
+
+const CAPACITY: usize = 8192;
+// enough to get past the allocator's own frames and the collection that called it
+const DEPTH: usize = 8;
+// largest number of distinct stacks a report tells apart
+const MAX_GROUPS: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Record {
+    // 0 for a free slot
+    ptr: usize,
+    size: usize,
+    trace: [u64; DEPTH],
+}
+
+const EMPTY: Record = Record { ptr: 0, size: 0, trace: [0; DEPTH] };
+
+// open addressing by pointer, with linear probing
+struct Table<const N: usize> {
+    records: [Record; N],
+    untracked: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TABLE: SpinLock<Table<CAPACITY>> = SpinLock::new(Table::new());
+
+fn hash(ptr: usize) -> usize {
+    // allocations are at least 8 byte aligned, the low bits say nothing
+    (ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 7
+}
+
+impl<const N: usize> Table<N> {
+    const fn new() -> Self {
+        Table { records: [EMPTY; N], untracked: 0 }
+    }
+
+    fn home(ptr: usize) -> usize {
+        hash(ptr) % N
+    }
+
+    fn insert(&mut self, record: Record) {
+        let mut slot = Self::home(record.ptr);
+        for _ in 0..N {
+            if self.records[slot].ptr == 0 {
+                self.records[slot] = record;
+                return;
+            }
+            slot = (slot + 1) % N;
+        }
+        self.untracked += 1;
+    }
+
+    fn find(&self, ptr: usize) -> Option<usize> {
+        let mut slot = Self::home(ptr);
+        for _ in 0..N {
+            match self.records[slot].ptr {
+                0 => return None,
+                found if found == ptr => return Some(slot),
+                _ => slot = (slot + 1) % N,
+            }
+        }
+        None
+    }
+
+    /// Frees `slot`, moving later records of the same run up so lookups still find them.
+    fn remove(&mut self, slot: usize) {
+        let mut hole = slot;
+        let mut next = slot;
+        loop {
+            next = (next + 1) % N;
+            let ptr = self.records[next].ptr;
+            if ptr == 0 {
+                break;
+            }
+            // a record may fill the hole when its home is not between the two
+            let home = Self::home(ptr);
+            let between = if hole <= next { hole < home && home <= next } else { hole < home || home <= next };
+            if !between {
+                self.records[hole] = self.records[next];
+                hole = next;
+            }
+        }
+        self.records[hole] = EMPTY;
+    }
+
+    fn clear(&mut self) {
+        self.records = [EMPTY; N];
+        self.untracked = 0;
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Tracks the allocations from now on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stops tracking and forgets what was tracked.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+    TABLE.lock().clear();
+}
+
+/// Turns tracking on when the command line asks for it, call once memory is set up.
+pub fn init() {
+    if cmdline::get("kmemleak").is_some() {
+        enable();
+    }
+}
+
+/// Called by the allocator for every allocation.
+#[inline(always)]
+pub(super) fn allocated(ptr: *mut u8, size: usize) {
+    let backtrace = Backtrace::capture();
+    let frames = backtrace.frames();
+    let mut trace = [0; DEPTH];
+    let len = frames.len().min(DEPTH);
+    trace[..len].copy_from_slice(&frames[..len]);
+    TABLE.lock().insert(Record { ptr: ptr as usize, size, trace });
+}
+
+/// Called by the allocator for every free.
+pub(super) fn freed(ptr: *mut u8) {
+    let mut table = TABLE.lock();
+    // allocations from before tracking was on are not in the table
+    if let Some(slot) = table.find(ptr as usize) {
+        table.remove(slot);
+    }
+}
+
+/// The first function on `trace` that is not part of allocating, or the innermost frame.
+fn origin(trace: &[u64]) -> (u64, Option<&'static str>) {
+    const ALLOCATING: &[&str] = &["alloc::", "<alloc::", "core::", "<core::", "__r", "<blog_os::allocator::KernelAllocator"];
+    let mut named = trace.iter().filter(|&&address| address != 0).map(|&address| {
+        (address, symbols::resolve(address - 1).map(|(name, _)| name))
+    });
+    let first = named.clone().next().unwrap_or((0, None));
+    named
+        .find(|(_, name)| name.is_some_and(|name| !ALLOCATING.iter().any(|prefix| name.starts_with(prefix))))
+        .unwrap_or(first)
+}
+
+/// The allocations still live, grouped by where they were made, largest first.
+pub fn report() -> String {
+    struct Group {
+        trace: [u64; DEPTH],
+        count: usize,
+        bytes: usize,
+    }
+
+    // allocated up front, the allocator cannot be used with the table locked
+    let mut groups: Vec<Group> = Vec::with_capacity(MAX_GROUPS);
+    let (mut other_count, mut other_bytes) = (0, 0);
+    let untracked = {
+        let table = TABLE.lock();
+        for record in table.records.iter().filter(|record| record.ptr != 0) {
+            let full = groups.len() == MAX_GROUPS;
+            match groups.iter_mut().find(|group| group.trace == record.trace) {
+                Some(group) => {
+                    group.count += 1;
+                    group.bytes += record.size;
+                }
+                None if !full => {
+                    groups.push(Group { trace: record.trace, count: 1, bytes: record.size });
+                }
+                None => {
+                    other_count += 1;
+                    other_bytes += record.size;
+                }
+            }
+        }
+        table.untracked
+    };
+
+    // stacks that differ further out come from the same function
+    let mut origins: Vec<(u64, Option<&str>, usize, usize)> = Vec::new();
+    for group in &groups {
+        let (address, name) = origin(&group.trace);
+        let same = origins.iter_mut().find(|(other, other_name, ..)| match name {
+            Some(name) => *other_name == Some(name),
+            None => *other == address,
+        });
+        match same {
+            Some((_, _, count, bytes)) => {
+                *count += group.count;
+                *bytes += group.bytes;
+            }
+            None => origins.push((address, name, group.count, group.bytes)),
+        }
+    }
+    origins.sort_unstable_by_key(|origin| core::cmp::Reverse(origin.3));
+
+    let count = origins.iter().map(|origin| origin.2).sum::<usize>() + other_count;
+    let bytes = origins.iter().map(|origin| origin.3).sum::<usize>() + other_bytes;
+    let mut text = String::new();
+    let _ = writeln!(text, "{} allocations, {} bytes never freed; {} untracked", count, bytes, untracked);
+    for (address, name, count, bytes) in origins {
+        let _ = write!(text, "{:>10} bytes in {:>5} from {:#018x}", bytes, count, address);
+        match name {
+            Some(name) => {
+                let _ = writeln!(text, " {}", name);
+            }
+            None => text.push('\n'),
+        }
+    }
+    if other_count > 0 {
+        let _ = writeln!(text, "{:>10} bytes in {:>5} from elsewhere", other_bytes, other_count);
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[inline(never)]
+    fn leaky() -> Box<[u8; 40]> {
+        Box::new([7; 40])
+    }
+
+    #[test_case]
+    fn test_table() {
+        let mut table = Table::<16>::new();
+        // three pointers with the same home, the middle one removed
+        let home = Table::<16>::home;
+        let pointers: Vec<usize> = (1..).map(|index| index * 8).filter(|&ptr| home(ptr) == home(8)).take(3).collect();
+        for &ptr in &pointers {
+            table.insert(Record { ptr, ..EMPTY });
+        }
+        let slot = table.find(pointers[1]).unwrap();
+        table.remove(slot);
+        assert_eq!(table.find(pointers[1]), None);
+        assert!(table.find(pointers[0]).is_some() && table.find(pointers[2]).is_some());
+    }
+
+    #[test_case]
+    fn test_report() {
+        enable();
+        let kept = leaky();
+        let text = report();
+        disable();
+        assert!(text.contains("untracked"), "{}", text);
+        // the test runner embeds the symbol table, a plain cargo build does not
+        if symbols::is_loaded() {
+            assert!(text.contains("leaks::test::leaky"), "{}", text);
+        }
+        drop(kept);
+    }
+}
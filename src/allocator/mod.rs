@@ -0,0 +1,135 @@
+pub mod leaks;
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::LockedHeap;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::fault_inject;
+
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
+// the heap lives in .bss until the kernel manages its own page tables
+static mut HEAP_SPACE: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+/* the heap lock is only ever held with interrupts disabled.
+    Otherwise a thread preempted while allocating would leave the lock taken,
+    and the scheduler allocating from the timer interrupt would spin forever.
+ */
+pub struct KernelAllocator(LockedHeap);
+
+// what is allocated right now, which the test runner compares around every test
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if fault_inject::HEAP.should_fail() {
+            return core::ptr::null_mut();
+        }
+        let ptr = without_interrupts(|| self.0.alloc(layout));
+        if !ptr.is_null() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            if leaks::is_enabled() {
+                leaks::allocated(ptr, layout.size());
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.dealloc(ptr, layout));
+        ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+        BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        if leaks::is_enabled() {
+            leaks::freed(ptr);
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator(LockedHeap::empty());
+
+pub fn init_heap() {
+    unsafe {
+        let heap_start = addr_of_mut!(HEAP_SPACE) as *mut u8;
+        without_interrupts(|| ALLOCATOR.0.lock().init(heap_start, HEAP_SIZE));
+    }
+}
+
+/// Bytes of the heap handed out right now.
+pub fn heap_used() -> usize {
+    without_interrupts(|| ALLOCATOR.0.lock().used())
+}
+
+/// Live allocations and the bytes asked for, without the allocator's rounding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub allocations: usize,
+    pub bytes: usize,
+}
+
+pub fn usage() -> HeapUsage {
+    HeapUsage {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        bytes: BYTES.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_simple_allocation() {
+        let heap_value_1 = Box::new(41);
+        let heap_value_2 = Box::new(13);
+        assert_eq!(*heap_value_1, 41);
+        assert_eq!(*heap_value_2, 13);
+    }
+
+    #[test_case]
+    fn test_large_vec() {
+        let n = 1000;
+        let mut vec = Vec::new();
+        for i in 0..n {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn test_usage_counts_live_allocations() {
+        let before = super::usage();
+        let boxed = Box::new([0u8; 24]);
+        let after = super::usage();
+        // other CPUs allocate meanwhile, the box is counted at least
+        assert!(after.allocations > before.allocations && after.bytes >= before.bytes + 24);
+        drop(boxed);
+        assert!(super::usage().bytes < after.bytes);
+    }
+
+    #[test_case]
+    fn test_many_boxes() {
+        // more boxes than fit into the heap at once, freed memory has to be reused
+        for i in 0..super::HEAP_SIZE / core::mem::size_of::<usize>() + 1 {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+    }
+
+    crate::bench_case! {
+        fn bench_box(bencher) {
+            bencher.iter(|| Box::new(7u64));
+        }
+    }
+
+    crate::bench_case! {
+        fn bench_vec_growth(bencher) {
+            bencher.iter(|| (0..64u64).collect::<Vec<_>>());
+        }
+    }
+}
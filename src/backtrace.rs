@@ -0,0 +1,137 @@
+use core::arch::asm;
+use core::fmt;
+
+use crate::interrupts::ExceptionStackFrame;
+use crate::memory::{self, address_space};
+use crate::symbols;
+
+/* stack backtraces.
+    The kernel is built with frame pointers, so every function starts by pushing the
+    caller's rbp and pointing rbp at it: [rbp] is the caller's frame, [rbp + 8] the
+    address it returns to. Following that chain gives the return addresses up to the
+    first thread function, whose rbp starts out 0. The interrupt entry stubs push a
+    frame of their own holding the interrupted rip, so a walk from inside a handler
+    continues into the code that was interrupted. Nothing is allocated, a walk works
+    from the panic handler. Addresses are shown with the function they are in when
+    the kernel carries its symbol table.
+ */
+
+/// Deeper stacks are cut.
+pub const MAX_FRAMES: usize = 32;
+
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+/// Whether a frame record can be read at `rbp` without faulting.
+fn readable(rbp: u64) -> bool {
+    // user stacks are not followed
+    if rbp == 0 || rbp & 0x7 != 0 || (address_space::USER_START..address_space::USER_END).contains(&rbp) {
+        return false;
+    }
+    // both words of the record, which may straddle a page boundary
+    memory::is_mapped(rbp) && memory::is_mapped(rbp + 8)
+}
+
+impl Backtrace {
+    const fn empty() -> Self {
+        Backtrace { frames: [0; MAX_FRAMES], len: 0 }
+    }
+
+    /// The return addresses from the caller of the current function on.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        let mut backtrace = Backtrace::empty();
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// Starts at the instruction an exception interrupted, call from its handler.
+    #[inline(always)]
+    pub fn from_exception(stack_frame: &ExceptionStackFrame) -> Self {
+        let mut backtrace = Backtrace::capture();
+        let rip = stack_frame.instruction_pointer();
+        match backtrace.frames().iter().position(|&address| address == rip) {
+            Some(index) => {
+                backtrace.frames.copy_within(index..backtrace.len, 0);
+                backtrace.len -= index;
+            }
+            // the handler's frames were not found, there is the rip at least
+            None => {
+                backtrace = Backtrace::empty();
+                backtrace.push(rip);
+            }
+        }
+        backtrace
+    }
+
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    fn walk(&mut self, mut rbp: u64) {
+        while readable(rbp) {
+            let record = rbp as *const u64;
+            let (caller, address) = unsafe { (record.read(), record.add(1).read()) };
+            if address == 0 || !self.push(address) {
+                break;
+            }
+            rbp = caller;
+        }
+    }
+
+    /// Return addresses, innermost first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (index, &address) in self.frames().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x}", index, address)?;
+            // a return address may be past the end of a function that never returns
+            if let Some((name, offset)) = symbols::resolve(address - 1) {
+                write!(f, " {}+{:#x}", name, offset + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn inner() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    #[inline(never)]
+    fn outer() -> Backtrace {
+        inner()
+    }
+
+    #[test_case]
+    fn test_capture() {
+        let backtrace = outer();
+        let frames = backtrace.frames();
+        // inner returns into outer, outer into this test
+        let outer_start = outer as *const () as u64;
+        assert!(frames[0] > outer_start && frames[0] < outer_start + 0x100, "{}", backtrace);
+        assert!(frames.len() > 2);
+        assert!(!readable(0));
+        assert!(!readable(address_space::USER_START));
+    }
+}
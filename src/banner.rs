@@ -0,0 +1,183 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::arch::x86_64::{CpuidResult, __cpuid_count};
+use core::fmt::{self, Write};
+
+use crate::memory::{frame, PAGE_SIZE};
+use crate::{allocator, block, crash, fs, interrupts, pci, print, profile, smp, trace};
+
+/* the boot banner.
+    What the kernel found, printed once `init` is done, so the difference between
+    two QEMU configurations shows at a glance: the CPU and its features, memory,
+    the PCI devices, what was brought up and what the command line turned on.
+ */
+
+// where the lists are wrapped
+const WIDTH: usize = 72;
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+const FEATURES: &[(u32, Register, u32, &str)] = &[
+    (1, Register::Edx, 0, "fpu"),
+    (1, Register::Edx, 4, "tsc"),
+    (1, Register::Edx, 9, "apic"),
+    (1, Register::Edx, 25, "sse"),
+    (1, Register::Edx, 26, "sse2"),
+    (1, Register::Ecx, 0, "sse3"),
+    (1, Register::Ecx, 9, "ssse3"),
+    (1, Register::Ecx, 19, "sse4.1"),
+    (1, Register::Ecx, 20, "sse4.2"),
+    (1, Register::Ecx, 21, "x2apic"),
+    (1, Register::Ecx, 23, "popcnt"),
+    (1, Register::Ecx, 26, "xsave"),
+    (1, Register::Ecx, 28, "avx"),
+    (1, Register::Ecx, 30, "rdrand"),
+    (1, Register::Ecx, 31, "hypervisor"),
+    (7, Register::Ebx, 3, "bmi1"),
+    (7, Register::Ebx, 5, "avx2"),
+    (7, Register::Ebx, 7, "smep"),
+    (7, Register::Ebx, 8, "bmi2"),
+    (7, Register::Ebx, 18, "rdseed"),
+    (7, Register::Ebx, 20, "smap"),
+    (0x8000_0001, Register::Edx, 20, "nx"),
+    (0x8000_0001, Register::Edx, 26, "pdpe1gb"),
+    (0x8000_0001, Register::Edx, 27, "rdtscp"),
+];
+
+/// Leaf `leaf` of cpuid, `None` past the last leaf of its range.
+fn cpuid(leaf: u32) -> Option<CpuidResult> {
+    let max = unsafe { __cpuid_count(leaf & 0x8000_0000, 0) }.eax;
+    (leaf <= max).then(|| unsafe { __cpuid_count(leaf, 0) })
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_matches(|c: char| c == '\0' || c == ' ').into()
+}
+
+pub fn cpu_vendor() -> String {
+    let leaf = cpuid(0).unwrap();
+    let bytes: Vec<u8> = [leaf.ebx, leaf.edx, leaf.ecx].iter().flat_map(|register| register.to_le_bytes()).collect();
+    text(&bytes)
+}
+
+/// The model as the CPU names it, `None` without the brand string leaves.
+pub fn cpu_model() -> Option<String> {
+    let mut bytes = Vec::new();
+    for leaf in 0x8000_0002..=0x8000_0004 {
+        let leaf = cpuid(leaf)?;
+        bytes.extend([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx].iter().flat_map(|register| register.to_le_bytes()));
+    }
+    Some(text(&bytes))
+}
+
+/// The features of those the kernel cares about that the CPU has.
+pub fn cpu_features() -> Vec<&'static str> {
+    FEATURES.iter().filter(|&&(leaf, register, bit, _)| {
+        cpuid(leaf).is_some_and(|leaf| {
+            let value = match register {
+                Register::Ebx => leaf.ebx,
+                Register::Ecx => leaf.ecx,
+                Register::Edx => leaf.edx,
+            };
+            value & (1 << bit) != 0
+        })
+    }).map(|&(_, _, _, name)| name).collect()
+}
+
+/// Writes `words` after `label`, wrapped and indented.
+fn list<T: ToString>(out: &mut dyn Write, label: &str, words: impl IntoIterator<Item = T>) -> fmt::Result {
+    let mut line = alloc::format!("{:<8}", label);
+    let mut empty = true;
+    for word in words {
+        let word = word.to_string();
+        if !empty && line.len() + 1 + word.len() > WIDTH {
+            writeln!(out, "{}", line)?;
+            line = alloc::format!("{:<8}", "");
+        }
+        line.push(' ');
+        line.push_str(&word);
+        empty = false;
+    }
+    if empty {
+        line.push_str(" none");
+    }
+    writeln!(out, "{}", line)
+}
+
+/// What the command line and the build turned on.
+fn options() -> Vec<&'static str> {
+    let options = [
+        ("net", cfg!(feature = "net")),
+        ("fs", cfg!(feature = "fs")),
+        ("graphics", cfg!(feature = "graphics")),
+        ("smp", cfg!(feature = "smp")),
+        ("shell", cfg!(feature = "shell")),
+        ("trace", trace::is_enabled()),
+        ("profile", profile::is_enabled()),
+        ("irqlatency", interrupts::latency::is_enabled()),
+        ("crashdump", crash::kdump::is_enabled()),
+        ("smoltcp", cfg!(feature = "smoltcp")),
+        ("debug", cfg!(debug_assertions)),
+    ];
+    options.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect()
+}
+
+pub fn write(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "blog_os {}", env!("CARGO_PKG_VERSION"))?;
+    let model = cpu_model().unwrap_or_default();
+    writeln!(out, "{:<8} {} {}", "cpu", cpu_vendor(), model)?;
+    list(out, "", cpu_features())?;
+    writeln!(out, "{:<8} {} online", "cpus", smp::online_cpus())?;
+    writeln!(
+        out,
+        "{:<8} {} MiB, {} KiB heap",
+        "memory",
+        frame::total_frames() as u64 * PAGE_SIZE / (1024 * 1024),
+        allocator::HEAP_SIZE / 1024,
+    )?;
+    let devices = pci::devices();
+    if devices.is_empty() {
+        writeln!(out, "{:<8} none", "pci")?;
+    }
+    for (index, device) in devices.iter().enumerate() {
+        let label = if index == 0 { "pci" } else { "" };
+        writeln!(out, "{:<8} {} {:04x}:{:04x} {}", label, device.location, device.vendor, device.device, device.class_name())?;
+    }
+    list(out, "block", block::devices())?;
+    #[cfg(feature = "net")]
+    list(out, "net", crate::net::interfaces())?;
+    let mounts: Vec<String> = fs::mount::mounts().iter()
+        .map(|mount| alloc::format!("{} {}", mount.path(), mount.filesystem().name()))
+        .collect();
+    list(out, "fs", mounts)?;
+    list(out, "on", options())
+}
+
+/// Prints the banner on the console.
+pub fn print() {
+    let mut text = String::new();
+    let _ = write(&mut text);
+    print!("{}", text);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_banner() {
+        let mut text = String::new();
+        write(&mut text).unwrap();
+        let cpu = text.lines().nth(1).unwrap();
+        assert!(cpu.starts_with("cpu      ") && cpu.contains(&cpu_vendor()), "{}", text);
+        // the kernel does not run without them
+        assert!(cpu_features().contains(&"sse2") && cpu_features().contains(&"apic"));
+        assert!(text.lines().all(|line| line.len() <= WIDTH), "{}", text);
+        assert!(!cfg!(feature = "fs") || text.contains("/proc procfs"), "{}", text);
+    }
+}
@@ -0,0 +1,115 @@
+use alloc::vec::Vec;
+use core::hint::black_box;
+
+use crate::time::tsc;
+use crate::{serial_print, serial_println, Testable};
+
+/* microbenchmarks.
+    A benchmark is a test that times a closure, the way libtest's #[bench] does:
+        bench_case! { fn bench_map(bencher) { let space = ...; bencher.iter(|| ...); } }
+    Each sample is one call between serialized TSC reads, less what the reads cost on
+    their own. The minimum and the median of the samples are printed; timer ticks and
+    other CPUs land in some samples, neither moves much from them.
+ */
+
+/// Calls timed, after as many untimed ones to warm caches and the TLB.
+pub const SAMPLES: usize = 1001;
+
+/// Cycles a sample measured.
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub min: u64,
+    pub median: u64,
+}
+
+impl Summary {
+    fn of(samples: &mut [u64]) -> Self {
+        samples.sort_unstable();
+        Summary { min: samples[0], median: samples[samples.len() / 2] }
+    }
+}
+
+pub struct Bencher {
+    summary: Option<Summary>,
+}
+
+impl Bencher {
+    /// Times `f`, what it returns is kept from being optimized away.
+    pub fn iter<T>(&mut self, mut f: impl FnMut() -> T) {
+        let mut samples = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            black_box(f());
+        }
+        let overhead = overhead();
+        for _ in 0..SAMPLES {
+            let start = tsc::start();
+            black_box(f());
+            let cycles = tsc::stop() - start;
+            samples.push(cycles.saturating_sub(overhead));
+        }
+        self.summary = Some(Summary::of(&mut samples));
+    }
+}
+
+/// What the timing itself takes, the least of many empty samples.
+fn overhead() -> u64 {
+    (0..SAMPLES)
+        .map(|_| {
+            let start = tsc::start();
+            tsc::stop() - start
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+pub struct Bench {
+    name: &'static str,
+    bench: fn(&mut Bencher),
+}
+
+impl Bench {
+    pub const fn new(name: &'static str, bench: fn(&mut Bencher)) -> Self {
+        Bench { name, bench }
+    }
+}
+
+impl Testable for Bench {
+    fn run(&self) {
+        serial_print!("{}...\t", self.name);
+        let mut bencher = Bencher { summary: None };
+        (self.bench)(&mut bencher);
+        let summary = bencher.summary.expect("the benchmark never called iter");
+        serial_println!("min {} cycles ({:?}), median {} cycles ({:?}) [ok]",
+            summary.min, tsc::cycles_to_duration(summary.min),
+            summary.median, tsc::cycles_to_duration(summary.median));
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// A benchmark, run with the tests; the body gets a `&mut Bencher` named `$bencher`.
+#[macro_export]
+macro_rules! bench_case {
+    (fn $name: ident($bencher: ident) $body: block) => {
+        fn $name($bencher: &mut $crate::bench::Bencher) $body
+
+        mod $name {
+            #[test_case]
+            const BENCH: $crate::bench::Bench = $crate::bench::Bench::new(module_path!(), super::$name);
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_summary() {
+        let mut samples = [9, 3, 7, 1, 5];
+        let summary = Summary::of(&mut samples);
+        assert_eq!((summary.min, summary.median), (1, 5));
+    }
+}
@@ -0,0 +1,489 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::{check_request, BlockDevice, BlockError};
+use crate::sync::{Mutex, SpinLock};
+use crate::workqueue;
+
+/* the block cache.
+    Sits between a filesystem and its device and is a device itself, so filesystems
+    use it without knowing. Reads fill the cache, runs of missing blocks are fetched
+    with one device request. Writes only land in the cache and mark the blocks dirty;
+    they reach the device when evicted, on `sync`, or from the periodic writeback
+    that the work queue runs for every cache.
+
+    A reader going through the device in order gets the blocks after the ones it asked
+    for read ahead by the work queue, in a window that doubles while the reader keeps
+    up. Dirty neighbours go to the device together, as one request per run.
+ */
+
+/// How often dirty blocks of every cache are written back.
+pub const WRITEBACK_INTERVAL: Duration = Duration::from_secs(5);
+
+// readahead window after the first sequential read, it grows up to half the cache
+const READAHEAD_MIN: u64 = 4;
+const READAHEAD_MAX: u64 = 64;
+// longest run of dirty blocks written with one request
+const MAX_WRITE_RUN: u64 = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty blocks written to the device.
+    pub writebacks: u64,
+    pub evictions: u64,
+    /// Blocks read before anybody asked for them.
+    pub readahead: u64,
+}
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    // position in `lru`
+    stamp: u64,
+}
+
+struct State {
+    entries: BTreeMap<u64, Entry>,
+    // least recently used first, stamp to block
+    lru: BTreeMap<u64, u64>,
+    next_stamp: u64,
+    stats: CacheStats,
+    // block after the last read, to tell sequential access from random
+    next_read: u64,
+    // current readahead window, 0 while access is random
+    window: u64,
+    // readahead was started for every block below this
+    readahead_end: u64,
+}
+
+impl State {
+    fn touch(&mut self, block: u64) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        let entry = self.entries.get_mut(&block).expect("touched block is not cached");
+        self.lru.remove(&entry.stamp);
+        entry.stamp = stamp;
+        self.lru.insert(stamp, block);
+    }
+
+    fn is_dirty(&self, block: u64) -> bool {
+        self.entries.get(&block).is_some_and(|entry| entry.dirty)
+    }
+
+    /// The run of dirty blocks around the dirty `block`, at most `MAX_WRITE_RUN` long.
+    fn dirty_run(&self, block: u64) -> Range<u64> {
+        let mut first = block;
+        while block - first + 1 < MAX_WRITE_RUN && first > 0 && self.is_dirty(first - 1) {
+            first -= 1;
+        }
+        let mut end = block + 1;
+        while end - first < MAX_WRITE_RUN && self.is_dirty(end) {
+            end += 1;
+        }
+        first..end
+    }
+
+    /// Writes the dirty blocks of `run` with one request and marks them clean.
+    fn write_run(&mut self, device: &dyn BlockDevice, run: Range<u64>) -> Result<(), BlockError> {
+        let mut data = Vec::new();
+        for block in run.clone() {
+            data.extend_from_slice(&self.entries[&block].data);
+        }
+        device.write_blocks(run.start, &data)?;
+        for block in run.clone() {
+            self.entries.get_mut(&block).unwrap().dirty = false;
+        }
+        self.stats.writebacks += run.end - run.start;
+        Ok(())
+    }
+
+    /// Makes room for one more block, writing the evicted one back with its dirty neighbours.
+    fn evict(&mut self, device: &dyn BlockDevice) -> Result<(), BlockError> {
+        let Some((&stamp, &block)) = self.lru.iter().next() else {
+            return Ok(());
+        };
+        if self.is_dirty(block) {
+            let run = self.dirty_run(block);
+            self.write_run(device, run)?;
+        }
+        self.lru.remove(&stamp);
+        self.entries.remove(&block);
+        self.stats.evictions += 1;
+        Ok(())
+    }
+
+    fn insert(&mut self, device: &dyn BlockDevice, capacity: usize, block: u64, data: Box<[u8]>, dirty: bool) -> Result<(), BlockError> {
+        if let Some(entry) = self.entries.get_mut(&block) {
+            entry.data = data;
+            entry.dirty |= dirty;
+            self.touch(block);
+        } else {
+            while self.entries.len() >= capacity {
+                self.evict(device)?;
+            }
+            // a stamp of its own, no other entry's place in `lru` is taken
+            let stamp = self.next_stamp;
+            self.next_stamp += 1;
+            self.entries.insert(block, Entry { data, dirty, stamp });
+            self.lru.insert(stamp, block);
+        }
+        Ok(())
+    }
+
+    /// Reads the blocks of `blocks` that are not cached, a request per missing run.
+    /// Returns the number of blocks read.
+    fn fill(&mut self, device: &dyn BlockDevice, capacity: usize, blocks: Range<u64>) -> Result<u64, BlockError> {
+        let block_size = device.block_size();
+        let mut read = 0;
+        let mut block = blocks.start;
+        while block < blocks.end {
+            let run = (block..blocks.end).take_while(|block| !self.entries.contains_key(block)).count() as u64;
+            if run == 0 {
+                block += 1;
+                continue;
+            }
+            let mut data = vec![0u8; run as usize * block_size];
+            device.read_blocks(block, &mut data)?;
+            for (offset, data) in data.chunks_exact(block_size).enumerate() {
+                self.insert(device, capacity, block + offset as u64, data.into(), false)?;
+            }
+            read += run;
+            block += run;
+        }
+        Ok(read)
+    }
+
+    /// Follows a read of `blocks`, returns what to read ahead of it, if anything.
+    fn note_read(&mut self, blocks: Range<u64>, limit: u64, num_blocks: u64) -> Option<Range<u64>> {
+        let sequential = blocks.start == self.next_read;
+        self.next_read = blocks.end;
+        if !sequential || limit == 0 {
+            self.window = 0;
+            self.readahead_end = 0;
+            return None;
+        }
+        // the next window is started once the reader is halfway into the last one
+        if self.window != 0 && blocks.end + self.window / 2 < self.readahead_end {
+            return None;
+        }
+        self.window = if self.window == 0 { READAHEAD_MIN } else { self.window * 2 }.min(limit);
+        let from = self.readahead_end.max(blocks.end);
+        let to = (from + self.window).min(num_blocks);
+        self.readahead_end = to.max(self.readahead_end);
+        (from < to).then_some(from..to)
+    }
+}
+
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    // handed to readahead work, which must not keep the cache alive
+    this: Weak<BlockCache>,
+    // in blocks
+    capacity: usize,
+    // a sleeping lock, device requests are made while holding it
+    state: Mutex<State>,
+}
+
+static CACHES: SpinLock<Vec<Weak<BlockCache>>> = SpinLock::new(Vec::new());
+static WRITEBACK_STARTED: AtomicBool = AtomicBool::new(false);
+
+impl BlockCache {
+    /// Caches up to `capacity` blocks of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<Self> {
+        assert!(capacity > 0, "cache without room for a block");
+        let cache = Arc::new_cyclic(|this| BlockCache {
+            device,
+            this: this.clone(),
+            capacity,
+            state: Mutex::new(State {
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                next_stamp: 0,
+                stats: CacheStats::default(),
+                next_read: u64::MAX,
+                window: 0,
+                readahead_end: 0,
+            }),
+        });
+        CACHES.lock().push(Arc::downgrade(&cache));
+        if !WRITEBACK_STARTED.swap(true, Ordering::AcqRel) {
+            workqueue::spawn_after(WRITEBACK_INTERVAL, writeback);
+        }
+        cache
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().stats
+    }
+
+    pub fn cached_blocks(&self) -> usize {
+        self.state.lock().entries.len()
+    }
+
+    pub fn dirty_blocks(&self) -> usize {
+        self.state.lock().entries.values().filter(|entry| entry.dirty).count()
+    }
+
+    /// Writes every dirty block to the device, in block order, and flushes it.
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        let mut from = 0;
+        while let Some(block) = state.entries.range(from..).find(|(_, entry)| entry.dirty).map(|(&block, _)| block) {
+            let run = state.dirty_run(block);
+            from = run.end;
+            state.write_run(&*self.device, run)?;
+        }
+        self.device.flush()
+    }
+
+    /// Drops every clean block, dirty ones stay until written back.
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock();
+        let clean: Vec<u64> = state.entries.iter().filter(|(_, entry)| !entry.dirty).map(|(&block, _)| block).collect();
+        for block in clean {
+            let entry = state.entries.remove(&block).unwrap();
+            state.lru.remove(&entry.stamp);
+        }
+    }
+
+    fn readahead_limit(&self) -> u64 {
+        (self.capacity as u64 / 2).min(READAHEAD_MAX)
+    }
+
+    /// Has a worker read `blocks` into the cache. Failures are dropped, whoever
+    /// reads the blocks later gets to see them.
+    fn start_readahead(&self, blocks: Range<u64>) {
+        let cache = self.this.clone();
+        workqueue::spawn(move || {
+            let Some(cache) = cache.upgrade() else {
+                return;
+            };
+            let mut state = cache.state.lock();
+            if let Ok(read) = state.fill(&*cache.device, cache.capacity, blocks) {
+                state.stats.readahead += read;
+            }
+        });
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, start, buffer.len())?;
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        let mut index = 0;
+        while index < count {
+            let block = start + index;
+            let out = &mut buffer[index as usize * block_size..];
+            if let Some(entry) = state.entries.get(&block) {
+                out[..block_size].copy_from_slice(&entry.data);
+                state.stats.hits += 1;
+                state.touch(block);
+                index += 1;
+                continue;
+            }
+
+            // one request for the whole run of missing blocks
+            let run = (index..count).take_while(|&i| !state.entries.contains_key(&(start + i))).count();
+            let out = &mut out[..run * block_size];
+            self.device.read_blocks(block, out)?;
+            state.stats.misses += run as u64;
+            // only as much as fits, the rest of a long read goes uncached
+            for (offset, data) in out.chunks_exact(block_size).enumerate().take(self.capacity) {
+                state.insert(&*self.device, self.capacity, block + offset as u64, data.into(), false)?;
+            }
+            index += run as u64;
+        }
+        let readahead = state.note_read(start..start + count, self.readahead_limit(), self.num_blocks());
+        drop(state);
+        if let Some(blocks) = readahead {
+            self.start_readahead(blocks);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        let mut state = self.state.lock();
+        for (offset, data) in buffer.chunks_exact(self.block_size()).enumerate() {
+            state.insert(&*self.device, self.capacity, start + offset as u64, data.into(), true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.sync()
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        // nobody is left to hear about a failure
+        let _ = self.sync();
+    }
+}
+
+/// Writes back every cache, returns the first error.
+pub fn sync_all() -> Result<(), BlockError> {
+    let caches: Vec<Arc<BlockCache>> = {
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.iter().filter_map(Weak::upgrade).collect()
+    };
+    caches.iter().map(|cache| cache.sync()).fold(Ok(()), Result::and)
+}
+
+fn writeback() {
+    if let Err(error) = sync_all() {
+        log::error!("writeback failed: {:?}", error);
+    }
+    workqueue::spawn_after(WRITEBACK_INTERVAL, writeback);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+    use alloc::vec;
+    use core::sync::atomic::AtomicU64;
+
+    /// Counts the requests that reach the disk.
+    struct Counting {
+        disk: RamDisk,
+        reads: AtomicU64,
+        writes: AtomicU64,
+    }
+
+    impl BlockDevice for Counting {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.disk.num_blocks()
+        }
+
+        fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.disk.read_blocks(start, buffer)
+        }
+
+        fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.disk.write_blocks(start, buffer)
+        }
+    }
+
+    fn counting_disk() -> Arc<Counting> {
+        Arc::new(Counting { disk: RamDisk::new(512, 128), reads: AtomicU64::new(0), writes: AtomicU64::new(0) })
+    }
+
+    #[test_case]
+    fn test_reads_are_cached() {
+        let disk = counting_disk();
+        disk.disk.write_blocks(3, &[3u8; 512]).unwrap();
+        let cache = BlockCache::new(disk.clone(), 4);
+        let mut buffer = vec![0u8; 4 * 512];
+        cache.read_blocks(0, &mut buffer).unwrap();
+        assert_eq!(disk.reads.load(Ordering::SeqCst), 1);
+        cache.read_blocks(0, &mut buffer).unwrap();
+        assert_eq!(disk.reads.load(Ordering::SeqCst), 1);
+        assert!(buffer[3 * 512..].iter().all(|&byte| byte == 3));
+        assert_eq!(cache.stats().hits, 4);
+
+        // block 4 pushes out block 0, the least recently used
+        cache.read_blocks(1, &mut buffer[..512]).unwrap();
+        cache.read_blocks(4, &mut buffer[..512]).unwrap();
+        assert_eq!(cache.stats().evictions, 1);
+        cache.read_blocks(1, &mut buffer[..512]).unwrap();
+        assert_eq!(disk.reads.load(Ordering::SeqCst), 2);
+        cache.read_blocks(0, &mut buffer[..512]).unwrap();
+        assert_eq!(disk.reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test_case]
+    fn test_write_back() {
+        let disk = counting_disk();
+        let cache = BlockCache::new(disk.clone(), 2);
+        cache.write_blocks(5, &[5u8; 1024]).unwrap();
+        assert_eq!(disk.writes.load(Ordering::SeqCst), 0);
+        assert_eq!(cache.dirty_blocks(), 2);
+
+        let mut buffer = [0u8; 512];
+        cache.read_blocks(6, &mut buffer).unwrap();
+        assert_eq!(buffer, [5u8; 512]);
+        assert_eq!(disk.reads.load(Ordering::SeqCst), 0);
+
+        // evicting the dirty block 5 writes it out first
+        cache.write_blocks(7, &[7u8; 512]).unwrap();
+        assert_eq!(disk.writes.load(Ordering::SeqCst), 1);
+        disk.disk.read_blocks(5, &mut buffer).unwrap();
+        assert_eq!(buffer, [5u8; 512]);
+
+        // block 6 went out with its neighbour 5, only 7 is left
+        cache.sync().unwrap();
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(disk.writes.load(Ordering::SeqCst), 2);
+        disk.disk.read_blocks(7, &mut buffer).unwrap();
+        assert_eq!(buffer, [7u8; 512]);
+    }
+
+    #[test_case]
+    fn test_dirty_runs_are_written_together() {
+        let disk = counting_disk();
+        let cache = BlockCache::new(disk.clone(), 16);
+        for block in [3u8, 1, 2, 9, 8] {
+            cache.write_blocks(block as u64, &[block; 512]).unwrap();
+        }
+        cache.sync().unwrap();
+        // one request for 1..4 and one for 8..10
+        assert_eq!(disk.writes.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().writebacks, 5);
+        let mut buffer = [0u8; 512];
+        disk.disk.read_blocks(2, &mut buffer).unwrap();
+        assert_eq!(buffer, [2u8; 512]);
+    }
+
+    #[test_case]
+    fn test_sequential_reads_are_read_ahead() {
+        let disk = counting_disk();
+        let cache = BlockCache::new(disk.clone(), 32);
+        let mut buffer = [0u8; 512];
+        for block in [40, 7, 90] {
+            cache.read_blocks(block, &mut buffer).unwrap();
+        }
+        workqueue::flush();
+        assert_eq!(cache.stats().readahead, 0);
+
+        for block in 0..64 {
+            cache.read_blocks(block, &mut buffer).unwrap();
+            // a reader slower than the disk, the readahead is always done in time
+            workqueue::flush();
+        }
+        let stats = cache.stats();
+        // blocks 0 and 1 tell the cache the reader is sequential, the rest was there in time
+        assert_eq!(stats.misses, 3 + 2, "{:?}", stats);
+        assert!(stats.readahead >= 62);
+        assert!(disk.reads.load(Ordering::SeqCst) <= 3 + 8);
+    }
+}
@@ -0,0 +1,306 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::partition::PartitionError;
+use super::BlockDevice;
+
+/* the GUID partition table.
+    The header sits in block 1 and a backup copy in the last block of the disk, each
+    pointing at its own array of partition entries. Both the header and the array are
+    covered by a CRC32; when the primary copy fails its checks the backup is used.
+ */
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const PRIMARY_LBA: u64 = 1;
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+// more entries than anyone formats a disk with, a larger count means a damaged header
+const MAX_ENTRIES: usize = 1024;
+const NAME_UNITS: usize = 36;
+
+/// A GUID as stored on disk, the first three fields little endian.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(0xc12a7328, 0xf81f, 0x11d2, [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+    pub const BASIC_DATA: Guid = Guid::from_fields(0xebd0a0a2, 0xb9e5, 0x4433, [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+    pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(0x0fc63daf, 0x8483, 0x4772, [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4]);
+
+    /// Builds a GUID from the fields of its usual text form.
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(f, "{:08x}-{:04x}-{:04x}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]))?;
+        g[8..10].iter().try_for_each(|byte| write!(f, "{:02x}", byte))?;
+        f.write_str("-")?;
+        g[10..].iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// Index in the entry array plus one.
+    pub number: usize,
+    pub type_guid: Guid,
+    pub guid: Guid,
+    pub start: u64,
+    pub blocks: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptTable {
+    pub disk_guid: Guid,
+    pub partitions: Vec<GptPartition>,
+    /// The primary header or its entries were damaged.
+    pub from_backup: bool,
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 { (value >> 1) ^ 0xedb8_8320 } else { value >> 1 };
+            bit += 1;
+        }
+        table[index] = value;
+        index += 1;
+    }
+    table
+};
+
+/// CRC32 as used by GPT, the common reflected 0x04c11db7 polynomial.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+/// Feeds `bytes` into a CRC32 being computed piece by piece: start from `!0` and
+/// invert the result, as `crc32` does.
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &byte| CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn guid_at(bytes: &[u8], offset: usize) -> Guid {
+    Guid(bytes[offset..offset + 16].try_into().unwrap())
+}
+
+struct Header {
+    disk_guid: Guid,
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    num_entries: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// The header in block `lba`, None unless signature, size, location and CRC check out.
+fn read_header(device: &dyn BlockDevice, lba: u64) -> Result<Option<Header>, PartitionError> {
+    let mut block = vec![0u8; device.block_size()];
+    device.read_blocks(lba, &mut block)?;
+    // a block too small for the header cannot hold one
+    if block.len() < MIN_HEADER_SIZE {
+        return Ok(None);
+    }
+    let size = u32_at(&block, 12) as usize;
+    if &block[..8] != SIGNATURE || !(MIN_HEADER_SIZE..=block.len()).contains(&size) || u64_at(&block, 24) != lba {
+        return Ok(None);
+    }
+    let stored_crc = u32_at(&block, 16);
+    block[16..20].fill(0);
+    if crc32(&block[..size]) != stored_crc {
+        return Ok(None);
+    }
+    let header = Header {
+        disk_guid: guid_at(&block, 56),
+        first_usable: u64_at(&block, 40),
+        last_usable: u64_at(&block, 48),
+        entries_lba: u64_at(&block, 72),
+        num_entries: u32_at(&block, 80) as usize,
+        entry_size: u32_at(&block, 84) as usize,
+        entries_crc: u32_at(&block, 88),
+    };
+    let entry_size_valid = (MIN_ENTRY_SIZE..=block.len()).contains(&header.entry_size) && header.entry_size.is_power_of_two();
+    if !entry_size_valid || header.num_entries > MAX_ENTRIES || header.first_usable > header.last_usable {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// The entries of `header`, None if they do not match its CRC.
+fn read_entries(device: &dyn BlockDevice, header: &Header) -> Result<Option<Vec<GptPartition>>, PartitionError> {
+    let block_size = device.block_size();
+    let length = header.num_entries * header.entry_size;
+    let mut array = vec![0u8; length.div_ceil(block_size) * block_size];
+    device.read_blocks(header.entries_lba, &mut array)?;
+    if crc32(&array[..length]) != header.entries_crc {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in array[..length].chunks_exact(header.entry_size).enumerate() {
+        let type_guid = guid_at(entry, 0);
+        if type_guid == Guid::UNUSED {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if first < header.first_usable || last > header.last_usable || first > last {
+            return Err(PartitionError::Invalid);
+        }
+        let units = entry[56..56 + NAME_UNITS * 2].chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        let name = char::decode_utf16(units.take_while(|&unit| unit != 0))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        partitions.push(GptPartition {
+            number: index + 1,
+            type_guid,
+            guid: guid_at(entry, 16),
+            start: first,
+            blocks: last - first + 1,
+            attributes: u64_at(entry, 48),
+            name,
+        });
+    }
+    Ok(Some(partitions))
+}
+
+/// Reads the partition table of `device`, falling back to the backup copy at its end.
+pub fn read(device: &dyn BlockDevice) -> Result<GptTable, PartitionError> {
+    let backup_lba = device.num_blocks().checked_sub(1).ok_or(PartitionError::NoTable)?;
+    let mut found_header = false;
+    for (lba, from_backup) in [(PRIMARY_LBA, false), (backup_lba, true)] {
+        let Some(header) = read_header(device, lba)? else {
+            continue;
+        };
+        found_header = true;
+        if header.last_usable >= device.num_blocks() {
+            continue;
+        }
+        if let Some(partitions) = read_entries(device, &header)? {
+            return Ok(GptTable { disk_guid: header.disk_guid, partitions, from_backup });
+        }
+    }
+    Err(if found_header { PartitionError::Invalid } else { PartitionError::NoTable })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{BlockDevice, RamDisk};
+
+    const BLOCKS: u64 = 64;
+    const ENTRIES: usize = 4;
+
+    fn write_table(disk: &RamDisk, lba: u64, entries_lba: u64) {
+        let mut array = [0u8; ENTRIES * MIN_ENTRY_SIZE];
+        let entry = &mut array[MIN_ENTRY_SIZE..2 * MIN_ENTRY_SIZE];
+        entry[..16].copy_from_slice(&Guid::LINUX_FILESYSTEM.0);
+        entry[16..32].copy_from_slice(&[0x11; 16]);
+        entry[32..40].copy_from_slice(&10u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&19u64.to_le_bytes());
+        for (index, unit) in "root".encode_utf16().enumerate() {
+            entry[56 + index * 2..58 + index * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        disk.write_blocks(entries_lba, &array).unwrap();
+
+        let mut header = [0u8; 512];
+        header[..8].copy_from_slice(SIGNATURE);
+        header[12..16].copy_from_slice(&(MIN_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[40..48].copy_from_slice(&6u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(BLOCKS - 6).to_le_bytes());
+        header[56..72].copy_from_slice(&[0x22; 16]);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(MIN_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&array).to_le_bytes());
+        let crc = crc32(&header[..MIN_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(lba, &header).unwrap();
+    }
+
+    #[test_case]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test_case]
+    fn test_guid_display() {
+        assert_eq!(alloc::format!("{}", Guid::EFI_SYSTEM), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+    }
+
+    #[test_case]
+    fn test_primary_and_backup() {
+        let disk = RamDisk::new(512, BLOCKS);
+        write_table(&disk, PRIMARY_LBA, 2);
+        write_table(&disk, BLOCKS - 1, BLOCKS - 2);
+        let table = read(&disk).expect("no table");
+        assert!(!table.from_backup);
+        assert_eq!(table.disk_guid, Guid([0x22; 16]));
+        assert_eq!(table.partitions.len(), 1);
+        let partition = &table.partitions[0];
+        assert_eq!((partition.number, partition.start, partition.blocks), (2, 10, 10));
+        assert_eq!(partition.type_guid, Guid::LINUX_FILESYSTEM);
+        assert_eq!(partition.name, "root");
+
+        // a damaged primary entry array fails its CRC
+        disk.write_blocks(2, &[0xff; 512]).unwrap();
+        let table = read(&disk).expect("backup not used");
+        assert!(table.from_backup);
+        assert_eq!(table.partitions[0].name, "root");
+
+        disk.write_blocks(BLOCKS - 1, &[0; 512]).unwrap();
+        assert_eq!(read(&disk), Err(PartitionError::Invalid));
+        assert_eq!(read(&RamDisk::new(512, BLOCKS)), Err(PartitionError::NoTable));
+    }
+
+    #[test_case]
+    fn test_bad_sizes() {
+        assert!(matches!(read_header(&RamDisk::new(16, BLOCKS), PRIMARY_LBA), Ok(None)));
+        let disk = RamDisk::new(512, BLOCKS);
+        write_table(&disk, PRIMARY_LBA, 2);
+        assert!(matches!(read_header(&disk, PRIMARY_LBA), Ok(Some(_))));
+        // entries larger than a block, with a CRC that matches
+        let mut header = [0u8; 512];
+        disk.read_blocks(PRIMARY_LBA, &mut header).unwrap();
+        header[84..88].copy_from_slice(&1024u32.to_le_bytes());
+        header[16..20].fill(0);
+        let crc = crc32(&header[..MIN_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(PRIMARY_LBA, &header).unwrap();
+        assert!(matches!(read_header(&disk, PRIMARY_LBA), Ok(None)));
+    }
+}
@@ -0,0 +1,171 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::partition::PartitionError;
+use super::BlockDevice;
+
+/* the master boot record.
+    The first block ends in the 0x55 0xaa signature, right before it sit four 16 byte
+    entries. An extended partition holds a chain of extended boot records, each with
+    one logical partition relative to itself and a link to the next one relative to
+    the start of the extended partition. Addresses count in blocks of the device.
+ */
+
+const SIGNATURE_OFFSET: usize = 510;
+const ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const PRIMARY_ENTRIES: usize = 4;
+// logical partitions followed before the chain is taken for a loop
+const MAX_LOGICAL: usize = 128;
+
+pub const TYPE_EMPTY: u8 = 0x00;
+pub const TYPE_EXTENDED_CHS: u8 = 0x05;
+pub const TYPE_EXTENDED_LBA: u8 = 0x0f;
+/// The single entry of the MBR in front of a GPT disk.
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbrPartition {
+    pub number: usize,
+    pub kind: u8,
+    pub bootable: bool,
+    pub start: u64,
+    pub blocks: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    status: u8,
+    kind: u8,
+    start: u64,
+    blocks: u64,
+}
+
+impl Entry {
+    fn parse(bytes: &[u8]) -> Self {
+        let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        Entry { status: bytes[0], kind: bytes[4], start: word(8) as u64, blocks: word(12) as u64 }
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.kind, TYPE_EXTENDED_CHS | TYPE_EXTENDED_LBA)
+    }
+}
+
+fn read_entries(device: &dyn BlockDevice, block: u64) -> Result<[Entry; PRIMARY_ENTRIES], PartitionError> {
+    if device.block_size() < 512 {
+        return Err(PartitionError::NoTable);
+    }
+    let mut buffer = vec![0u8; device.block_size()];
+    device.read_blocks(block, &mut buffer)?;
+    if buffer[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+        return Err(PartitionError::NoTable);
+    }
+    Ok(core::array::from_fn(|index| {
+        let offset = ENTRIES_OFFSET + index * ENTRY_SIZE;
+        Entry::parse(&buffer[offset..offset + ENTRY_SIZE])
+    }))
+}
+
+/// The partitions in the MBR of `device`, logical ones included, extended containers left out.
+pub fn read(device: &dyn BlockDevice) -> Result<Vec<MbrPartition>, PartitionError> {
+    let mut partitions = Vec::new();
+    for (index, entry) in read_entries(device, 0)?.iter().enumerate() {
+        if entry.kind == TYPE_EMPTY || entry.blocks == 0 {
+            continue;
+        }
+        if entry.is_extended() {
+            read_logical(device, entry.start, &mut partitions)?;
+            continue;
+        }
+        partitions.push(MbrPartition {
+            number: index + 1,
+            kind: entry.kind,
+            bootable: entry.status & 0x80 != 0,
+            start: entry.start,
+            blocks: entry.blocks,
+        });
+    }
+    Ok(partitions)
+}
+
+fn read_logical(device: &dyn BlockDevice, extended: u64, partitions: &mut Vec<MbrPartition>) -> Result<(), PartitionError> {
+    let mut ebr = extended;
+    for number in PRIMARY_ENTRIES + 1..PRIMARY_ENTRIES + 1 + MAX_LOGICAL {
+        let [logical, next, ..] = read_entries(device, ebr)?;
+        if logical.kind != TYPE_EMPTY && logical.blocks > 0 {
+            partitions.push(MbrPartition {
+                number,
+                kind: logical.kind,
+                bootable: logical.status & 0x80 != 0,
+                start: ebr + logical.start,
+                blocks: logical.blocks,
+            });
+        }
+        if !next.is_extended() || next.start == 0 {
+            return Ok(());
+        }
+        ebr = extended + next.start;
+    }
+    Err(PartitionError::Invalid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{self, BlockDevice, PartitionKind, RamDisk};
+    use alloc::sync::Arc;
+
+    fn write_entry(block: &mut [u8], index: usize, kind: u8, start: u32, blocks: u32) {
+        let entry = &mut block[ENTRIES_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+        block[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    #[test_case]
+    fn test_primary_and_logical_partitions() {
+        // the disk stays registered
+        crate::testing::keeps_heap();
+        let disk = Arc::new(RamDisk::new(512, 64));
+        let mut block = [0u8; 512];
+        write_entry(&mut block, 0, 0x83, 2, 10);
+        write_entry(&mut block, 2, TYPE_EXTENDED_LBA, 20, 40);
+        block[ENTRIES_OFFSET] = 0x80;
+        disk.write_blocks(0, &block).unwrap();
+        // two logical partitions, each one block after its EBR
+        let mut ebr = [0u8; 512];
+        write_entry(&mut ebr, 0, 0x0c, 1, 5);
+        write_entry(&mut ebr, 1, TYPE_EXTENDED_LBA, 10, 8);
+        disk.write_blocks(20, &ebr).unwrap();
+        let mut ebr = [0u8; 512];
+        write_entry(&mut ebr, 0, 0x83, 1, 7);
+        disk.write_blocks(30, &ebr).unwrap();
+
+        let partitions = read(&*disk).expect("no table");
+        let summary: Vec<_> = partitions.iter().map(|p| (p.number, p.start, p.blocks)).collect();
+        assert_eq!(summary, [(1, 2, 10), (5, 21, 5), (6, 31, 7)]);
+        assert!(partitions[0].bootable && !partitions[1].bootable);
+
+        block::register("mbr-test", disk.clone()).unwrap();
+        let names = block::partition::scan("mbr-test").expect("scan failed");
+        assert_eq!(names, ["mbr-testp1", "mbr-testp5", "mbr-testp6"]);
+        let logical = block::get("mbr-testp5").unwrap();
+        assert_eq!(logical.num_blocks(), 5);
+        logical.write_blocks(4, &[7u8; 512]).unwrap();
+        let mut buffer = [0u8; 512];
+        disk.read_blocks(25, &mut buffer).unwrap();
+        assert_eq!(buffer, [7u8; 512]);
+        assert!(logical.write_blocks(5, &buffer).is_err());
+        assert_eq!(block::partition::info("mbr-testp6").map(|info| info.kind), Some(PartitionKind::Mbr(0x83)));
+        block::partition::forget("mbr-test");
+        assert!(block::get("mbr-testp1").is_none());
+        block::unregister("mbr-test");
+    }
+
+    #[test_case]
+    fn test_blank_disk_has_no_table() {
+        assert_eq!(read(&RamDisk::new(512, 4)), Err(PartitionError::NoTable));
+    }
+}
@@ -0,0 +1,160 @@
+pub mod cache;
+pub mod gpt;
+pub mod mbr;
+pub mod partition;
+pub mod ramdisk;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+
+use crate::fault_inject;
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+use crate::sync::SpinLock;
+
+pub use cache::BlockCache;
+pub use partition::{Partition, PartitionError, PartitionInfo, PartitionKind};
+pub use ramdisk::RamDisk;
+
+/* block devices.
+    Storage is addressed in fixed size blocks, whatever the driver behind it: ATA,
+    AHCI, virtio-blk or a disk in memory. Filesystems are written against the trait
+    and find their device by name in the registry, drivers register what they found.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request runs past the last block.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    BadLength,
+    ReadOnly,
+    /// The device reported a failure.
+    Io,
+    /// A device with that name is already registered.
+    Exists,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Bytes per block, a power of two.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Fills `buffer` from consecutive blocks starting at `start`.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer` to consecutive blocks starting at `start`.
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Waits until everything written so far reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Size of the device in bytes.
+    fn size(&self) -> u64 {
+        self.num_blocks() * self.block_size() as u64
+    }
+}
+
+/// Checks a request of `length` bytes at block `start`, returns the number of blocks;
+/// every device calls it first, which makes it where block requests fail on purpose.
+pub fn check_request(device: &dyn BlockDevice, start: u64, length: usize) -> Result<u64, BlockError> {
+    if fault_inject::BLOCK.should_fail() {
+        return Err(BlockError::Io);
+    }
+    let block_size = device.block_size();
+    if length & (block_size - 1) != 0 {
+        return Err(BlockError::BadLength);
+    }
+    let count = (length / block_size) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.num_blocks() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: SpinLock<BTreeMap<String, Arc<dyn BlockDevice>>> = SpinLock::new(BTreeMap::new());
+
+/// Makes `device` available under `name`, as in "ata0" or "ram0".
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(BlockError::Exists);
+    }
+    devices.insert(name.to_string(), device);
+    Ok(())
+}
+
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().remove(name)
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// Names of all registered devices, sorted.
+pub fn devices() -> Vec<String> {
+    DEVICES.lock().keys().cloned().collect()
+}
+
+/// Registers `lsblk`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("lsblk", "list the block devices", lsblk);
+}
+
+#[cfg(feature = "shell")]
+fn lsblk(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("lsblk"));
+    }
+    let _ = writeln!(out, "NAME         BLOCK     BLOCKS        SIZE");
+    for name in devices() {
+        // unregistered since the names were taken
+        let Some(device) = get(&name) else {
+            continue;
+        };
+        let _ = writeln!(out, "{:<10} {:>7} {:>10} {:>8} kB", name, device.block_size(), device.num_blocks(), device.size() / 1024);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_registry() {
+        let disk = Arc::new(RamDisk::new(512, 8));
+        register("test-registry", disk.clone()).expect("register failed");
+        assert_eq!(register("test-registry", disk), Err(BlockError::Exists));
+        assert!(devices().iter().any(|name| name == "test-registry"));
+
+        let device = get("test-registry").expect("device missing");
+        assert_eq!(device.size(), 8 * 512);
+        assert_eq!(check_request(&*device, 7, 512), Ok(1));
+        assert_eq!(check_request(&*device, 7, 1024), Err(BlockError::OutOfRange));
+        assert_eq!(check_request(&*device, 0, 100), Err(BlockError::BadLength));
+
+        assert!(unregister("test-registry").is_some());
+        assert!(get("test-registry").is_none());
+    }
+
+    #[cfg(feature = "shell")]
+    #[test_case]
+    fn test_lsblk() {
+        register("test-lsblk", Arc::new(RamDisk::new(512, 8))).expect("register failed");
+        let mut text = String::new();
+        lsblk(&[], &mut text).unwrap();
+        unregister("test-lsblk");
+        assert!(text.lines().any(|line| line.starts_with("test-lsblk") && line.ends_with(" 512          8        4 kB")), "{}", text);
+        assert_eq!(lsblk(&["ram0"], &mut text), Err(ShellError::Usage("lsblk")));
+    }
+}
@@ -0,0 +1,199 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::gpt::{self, Guid};
+use super::{check_request, mbr, BlockDevice, BlockError};
+use crate::sync::SpinLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Block(BlockError),
+    NoDevice,
+    /// No partition table signature.
+    NoTable,
+    /// An entry lies outside the device or the table is damaged.
+    Invalid,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(error: BlockError) -> Self {
+        PartitionError::Block(error)
+    }
+}
+
+/// A range of blocks of another device, itself usable as a device.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    blocks: u64,
+}
+
+impl Partition {
+    pub fn new(device: Arc<dyn BlockDevice>, start: u64, blocks: u64) -> Result<Self, PartitionError> {
+        match start.checked_add(blocks) {
+            Some(end) if blocks > 0 && end <= device.num_blocks() => Ok(Partition { device, start, blocks }),
+            _ => Err(PartitionError::Invalid),
+        }
+    }
+
+    /// First block on the underlying device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.device.read_blocks(self.start + start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.device.write_blocks(self.start + start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// What the partition table says about a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The one byte system id of an MBR entry.
+    Mbr(u8),
+    Gpt { type_guid: Guid, guid: Guid, name: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// The device the partition is part of.
+    pub disk: String,
+    pub number: usize,
+    pub start: u64,
+    pub blocks: u64,
+    pub kind: PartitionKind,
+}
+
+// by the name the partition is registered under
+static PARTITIONS: SpinLock<BTreeMap<String, PartitionInfo>> = SpinLock::new(BTreeMap::new());
+
+/// Table entry of a partition registered by `scan`.
+pub fn info(name: &str) -> Option<PartitionInfo> {
+    PARTITIONS.lock().get(name).cloned()
+}
+
+/* partition scanning.
+    Reads the partition table of a registered device and registers every partition
+    as "<device>p<number>", ram0p1 for the first one of ram0. Numbers follow the
+    table: 1 to 4 are the primary MBR entries, logical partitions start at 5, GPT
+    partitions count through the entry array. An MBR with a protective entry, or
+    none at all, sends the scan to the GPT.
+ */
+pub fn scan(name: &str) -> Result<Vec<String>, PartitionError> {
+    let device = super::get(name).ok_or(PartitionError::NoDevice)?;
+    let found: Vec<_> = match mbr::read(&*device) {
+        Ok(entries) if entries.iter().all(|entry| entry.kind != mbr::TYPE_GPT_PROTECTIVE) => entries.into_iter()
+            .map(|entry| (entry.number, entry.start, entry.blocks, PartitionKind::Mbr(entry.kind)))
+            .collect(),
+        Ok(_) | Err(PartitionError::NoTable) => gpt::read(&*device)?.partitions.into_iter()
+            .map(|entry| {
+                let kind = PartitionKind::Gpt { type_guid: entry.type_guid, guid: entry.guid, name: entry.name };
+                (entry.number, entry.start, entry.blocks, kind)
+            })
+            .collect(),
+        Err(error) => return Err(error),
+    };
+
+    let mut names: Vec<String> = Vec::new();
+    for (number, start, blocks, kind) in found {
+        let partition_name = format!("{}p{}", name, number);
+        let registered = Partition::new(device.clone(), start, blocks)
+            .and_then(|partition| Ok(super::register(&partition_name, Arc::new(partition))?));
+        if let Err(error) = registered {
+            // all of the table or nothing
+            for registered in &names {
+                PARTITIONS.lock().remove(registered);
+                super::unregister(registered);
+            }
+            return Err(error);
+        }
+        let info = PartitionInfo { disk: String::from(name), number, start, blocks, kind };
+        PARTITIONS.lock().insert(partition_name.clone(), info);
+        names.push(partition_name);
+    }
+    Ok(names)
+}
+
+/// Unregisters the partitions `scan` found on `name`.
+pub fn forget(name: &str) {
+    let mut partitions = PARTITIONS.lock();
+    partitions.retain(|partition, info| {
+        if info.disk != name {
+            return true;
+        }
+        super::unregister(partition);
+        false
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{self, RamDisk};
+
+    #[test_case]
+    fn test_scan_protective_mbr() {
+        let disk = Arc::new(RamDisk::new(512, 16));
+        let mut block = [0u8; 512];
+        block[446 + 4] = mbr::TYPE_GPT_PROTECTIVE;
+        block[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        block[446 + 12..446 + 16].copy_from_slice(&15u32.to_le_bytes());
+        block[510..].copy_from_slice(&[0x55, 0xaa]);
+        disk.write_blocks(0, &block).unwrap();
+        block::register("scan-test", disk).unwrap();
+
+        // protective, but no GPT behind it
+        assert_eq!(scan("scan-test"), Err(PartitionError::NoTable));
+        assert_eq!(scan("no-such-disk"), Err(PartitionError::NoDevice));
+        forget("scan-test");
+        block::unregister("scan-test");
+    }
+
+    #[test_case]
+    fn test_scan_failing_halfway() {
+        let disk = Arc::new(RamDisk::new(512, 16));
+        let mut block = [0u8; 512];
+        for (index, start) in [1u32, 8].into_iter().enumerate() {
+            let entry = &mut block[446 + index * 16..446 + (index + 1) * 16];
+            entry[4] = 0x83;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&7u32.to_le_bytes());
+        }
+        block[510..].copy_from_slice(&[0x55, 0xaa]);
+        disk.write_blocks(0, &block).unwrap();
+        block::register("undo-test", disk.clone()).unwrap();
+        // the name of the second partition is taken
+        block::register("undo-testp2", disk).unwrap();
+
+        assert_eq!(scan("undo-test"), Err(PartitionError::Block(BlockError::Exists)));
+        assert!(block::get("undo-testp1").is_none());
+        assert!(info("undo-testp1").is_none());
+        block::unregister("undo-testp2");
+        assert_eq!(scan("undo-test").map(|names| names.len()), Ok(2));
+        forget("undo-test");
+        block::unregister("undo-test");
+    }
+}
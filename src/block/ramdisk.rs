@@ -0,0 +1,98 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{check_request, BlockDevice, BlockError};
+use crate::sync::RwLock;
+
+/// A block device backed by heap memory, for tests and as a disk before real drivers.
+pub struct RamDisk {
+    block_size: usize,
+    data: RwLock<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `num_blocks` blocks.
+    pub fn new(block_size: usize, num_blocks: u64) -> Self {
+        Self::from_bytes(block_size, vec![0; block_size * num_blocks as usize])
+    }
+
+    /// A disk holding `data`, padded with zeroes to a whole block.
+    pub fn from_bytes(block_size: usize, mut data: Vec<u8>) -> Self {
+        assert!(block_size.is_power_of_two(), "block size must be a power of two");
+        data.resize(data.len().next_multiple_of(block_size), 0);
+        RamDisk { block_size, data: RwLock::new(data), read_only: false }
+    }
+
+    /// A disk starting out as a copy of `image`, as built into the kernel with
+    /// `include_bytes!`. Writes go to the copy.
+    pub fn from_image(block_size: usize, image: &'static [u8]) -> Self {
+        Self::from_bytes(block_size, image.to_vec())
+    }
+
+    /// Refuses writes from now on.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.data.read().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        let offset = start as usize * self.block_size;
+        buffer.copy_from_slice(&self.data.read()[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        check_request(self, start, buffer.len())?;
+        let offset = start as usize * self.block_size;
+        self.data.write()[offset..offset + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_read_back_writes() {
+        let disk = RamDisk::new(512, 4);
+        let block = [0xabu8; 1024];
+        disk.write_blocks(2, &block).expect("write failed");
+        let mut buffer = [0u8; 1536];
+        disk.read_blocks(1, &mut buffer).expect("read failed");
+        assert!(buffer[..512].iter().all(|&byte| byte == 0));
+        assert!(buffer[512..].iter().all(|&byte| byte == 0xab));
+        assert_eq!(disk.write_blocks(3, &block), Err(BlockError::OutOfRange));
+
+        let disk = RamDisk::from_bytes(512, vec![1; 600]).read_only();
+        assert_eq!(disk.num_blocks(), 2);
+        assert_eq!(disk.write_blocks(0, &block[..512]), Err(BlockError::ReadOnly));
+    }
+
+    #[test_case]
+    fn test_image_is_copied() {
+        static IMAGE: [u8; 1024] = [7; 1024];
+        let disk = RamDisk::from_image(512, &IMAGE);
+        disk.write_blocks(1, &[0u8; 512]).expect("write failed");
+        let mut buffer = [0u8; 1024];
+        disk.read_blocks(0, &mut buffer).expect("read failed");
+        assert!(buffer[..512].iter().all(|&byte| byte == 7));
+        assert!(buffer[512..].iter().all(|&byte| byte == 0));
+        assert!(IMAGE.iter().all(|&byte| byte == 7));
+    }
+}
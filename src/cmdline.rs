@@ -0,0 +1,90 @@
+use alloc::string::String;
+use core::str::FromStr;
+use spin::Once;
+
+use crate::fw_cfg;
+
+/* the kernel command line.
+    Words separated by spaces, `key=value` or a bare `flag`. The bootloader passes
+    none, so it comes from QEMU's firmware configuration device, as in
+        -fw_cfg name=opt/blog_os/cmdline,string="log=debug,net=trace"
+    and without one from BLOG_OS_CMDLINE at build time. Some of what is read:
+        log=<filter>, loglevel=<level>   see logger.rs
+        console=<sinks>                  where `print!` goes, see console.rs
+        noapic                           leave the local APIC off, the 8259 alone
+        init=<path>                      the program to start as the first process
+        trace                            tracepoints on, see trace.rs
+        profile[=<ticks>]                the sampling profiler, see profile.rs
+        irqlatency                       interrupt latency per vector, see interrupts/latency.rs
+        crashdump                        a crash dump on panic, see crash/kdump.rs
+        fail.<point>=<n>                 every n-th call fails, see fault_inject.rs
+ */
+
+const FILE_NAME: &str = "opt/blog_os/cmdline";
+
+static CMDLINE: Once<String> = Once::new();
+
+/// The file the command line is in, if QEMU got one.
+fn from_fw_cfg() -> Option<String> {
+    let bytes = fw_cfg::file(FILE_NAME)?;
+    Some(String::from_utf8_lossy(&bytes).trim_end_matches('\0').into())
+}
+
+/// Reads the command line once, before anything asks for it.
+pub fn init() {
+    CMDLINE.call_once(|| from_fw_cfg().or_else(|| option_env!("BLOG_OS_CMDLINE").map(String::from)).unwrap_or_default());
+}
+
+/// The whole command line, empty before `init`.
+pub fn raw() -> &'static str {
+    CMDLINE.get().map_or("", String::as_str)
+}
+
+fn find<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split_whitespace().rev().find_map(|word| match word.split_once('=') {
+        Some((name, value)) if name == key => Some(value),
+        None if word == key => Some(""),
+        _ => None,
+    })
+}
+
+/// The value of `key`, "" for a bare flag; a key given twice counts the last time.
+pub fn get(key: &str) -> Option<&'static str> {
+    find(raw(), key)
+}
+
+fn is_on(value: &str) -> bool {
+    !matches!(value, "0" | "off" | "no" | "false")
+}
+
+/// Whether `key` is given, bare or with any value but 0, off, no or false.
+pub fn flag(key: &str) -> bool {
+    get(key).is_some_and(is_on)
+}
+
+/// The value of `key` as a `T`; one that does not parse is warned about and ignored.
+pub fn parse<T: FromStr>(key: &str) -> Option<T> {
+    let value = get(key)?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        log::warn!("bad value on the command line: {}={}", key, value);
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_find() {
+        let line = "log=warn quiet log.sinks=serial,vga log=net=debug";
+        assert_eq!(find(line, "log"), Some("net=debug"));
+        assert_eq!(find(line, "quiet"), Some(""));
+        assert_eq!(find(line, "log.sinks"), Some("serial,vga"));
+        assert_eq!(find(line, "sinks"), None);
+        assert_eq!(find("", "log"), None);
+        assert!(is_on("") && is_on("yes") && !is_on("off"));
+        assert_eq!(find("loglevel=debug", "loglevel").map(str::parse), Some(Ok(log::LevelFilter::Debug)));
+    }
+}
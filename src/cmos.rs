@@ -0,0 +1,50 @@
+use x86_64::instructions::port::Port;
+
+use crate::sync::SpinLock;
+
+/* the CMOS.
+    The 128 bytes of memory kept alive by the battery, next to the real time clock,
+    reached by writing a register number to 0x70 and the value through 0x71. What
+    the clock keeps is in the first 14 registers; a few of the rest are not used by
+    QEMU's firmware and keep whatever the kernel leaves there across a reset.
+ */
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+// the top bit of the index turns NMIs off, it is left clear
+const REGISTER_MASK: u8 = 0x7f;
+
+/// A register nothing else writes, for the kernel's own use.
+pub const SCRATCH: u8 = 0x7f;
+
+// the index and data ports, used in pairs
+static PORTS: SpinLock<(Port<u8>, Port<u8>)> = SpinLock::new((Port::new(INDEX), Port::new(DATA)));
+
+pub fn read(register: u8) -> u8 {
+    let mut ports = PORTS.lock();
+    unsafe {
+        ports.0.write(register & REGISTER_MASK);
+        ports.1.read()
+    }
+}
+
+pub fn write(register: u8, value: u8) {
+    let mut ports = PORTS.lock();
+    unsafe {
+        ports.0.write(register & REGISTER_MASK);
+        ports.1.write(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_scratch() {
+        let saved = read(SCRATCH);
+        write(SCRATCH, 0xa5);
+        assert_eq!(read(SCRATCH), 0xa5);
+        write(SCRATCH, saved);
+    }
+}
@@ -0,0 +1,230 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use bitflags::bitflags;
+use log::{Level, LevelFilter};
+use x86_64::instructions::port::Port;
+
+use crate::sync::SpinLock;
+use crate::{cmdline, serial};
+
+/* the console.
+    What the kernel prints goes out through here to the sinks: the VGA text screen,
+    the first serial port, and with it the netconsole, and QEMU's debug console.
+    `print!` goes to the sinks turned on for it, the screen alone unless the command
+    line says otherwise, `serial_print!` to the serial port alone, which the test
+    runner reads, and log records to every sink whose level lets them through, on
+    top of the logger's filter. A kernel built without `graphics` has no screen,
+    its `print!` goes to the serial port and the VGA sink nowhere. From the command
+    line:
+        console=vga,serial                  where `print!` goes
+        console.level=vga=warn,serial=debug the log level of each sink
+        log.sinks=serial,debugcon           the sinks the log goes to at all
+ */
+
+const DEBUGCON_PORT: u16 = 0xe9;
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn, LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace,
+];
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Sinks: u8 {
+        const VGA = 1 << 0;
+        /// The first serial port, and with it the netconsole.
+        const SERIAL = 1 << 1;
+        /// QEMU's debug console, `-debugcon stdio` or `-debugcon file:log.txt`.
+        const DEBUGCON = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    InvalidSink,
+    /// A level that is not off, error, warn, info, debug or trace.
+    InvalidLevel,
+}
+
+struct Debugcon;
+
+impl Write for Debugcon {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let mut port = Port::<u8>::new(DEBUGCON_PORT);
+        for &byte in text.as_bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+const DEFAULT_PRINT_SINKS: Sinks = if cfg!(feature = "graphics") { Sinks::VGA } else { Sinks::SERIAL };
+
+static PRINT_SINKS: AtomicU8 = AtomicU8::new(DEFAULT_PRINT_SINKS.bits());
+// the level of each sink, by the index of its bit; all of the log on the screen and the serial port
+static SINK_LEVELS: [AtomicU8; 3] = [
+    AtomicU8::new(LevelFilter::Trace as u8),
+    AtomicU8::new(LevelFilter::Trace as u8),
+    AtomicU8::new(LevelFilter::Off as u8),
+];
+// keeps the lines of two CPUs apart on the debug console
+static DEBUGCON: SpinLock<Debugcon> = SpinLock::new(Debugcon);
+
+fn index(sink: Sinks) -> usize {
+    sink.bits().trailing_zeros() as usize
+}
+
+/// Writes `args` to each of `sinks`.
+pub fn write(sinks: Sinks, args: fmt::Arguments) {
+    if sinks.contains(Sinks::SERIAL) {
+        serial::_print(args);
+    }
+    #[cfg(feature = "graphics")]
+    if sinks.contains(Sinks::VGA) {
+        crate::vga_buffer::_print(args);
+    }
+    if sinks.contains(Sinks::DEBUGCON) {
+        let _ = DEBUGCON.lock().write_fmt(args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    write(print_sinks(), args);
+}
+
+/// Writes a log line to the sinks whose level takes `level`.
+pub fn log(level: Level, args: fmt::Arguments) {
+    write(sinks_for(level), args);
+}
+
+pub fn print_sinks() -> Sinks {
+    Sinks::from_bits_truncate(PRINT_SINKS.load(Ordering::Relaxed))
+}
+
+pub fn set_print_sinks(sinks: Sinks) {
+    PRINT_SINKS.store(sinks.bits(), Ordering::Relaxed);
+}
+
+/// The level of one sink.
+pub fn level(sink: Sinks) -> LevelFilter {
+    LEVELS[SINK_LEVELS[index(sink)].load(Ordering::Relaxed) as usize]
+}
+
+/// Sets the level of each of `sinks`.
+pub fn set_level(sinks: Sinks, level: LevelFilter) {
+    for sink in sinks.iter() {
+        SINK_LEVELS[index(sink)].store(level as u8, Ordering::Relaxed);
+    }
+}
+
+/// The sinks a record at `level` goes to.
+pub fn sinks_for(level: Level) -> Sinks {
+    Sinks::all().iter().filter(|&sink| level <= self::level(sink)).collect()
+}
+
+pub fn parse_sinks(spec: &str) -> Result<Sinks, ConsoleError> {
+    let mut sinks = Sinks::empty();
+    for name in spec.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        sinks |= match name {
+            "vga" => Sinks::VGA,
+            "serial" => Sinks::SERIAL,
+            "debugcon" => Sinks::DEBUGCON,
+            "none" => Sinks::empty(),
+            _ => return Err(ConsoleError::InvalidSink),
+        };
+    }
+    Ok(sinks)
+}
+
+/// Sets the levels "sink=level,..." names, checking all of them first.
+pub fn set_levels(spec: &str) -> Result<(), ConsoleError> {
+    let mut levels = [None; LEVELS.len()];
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (sinks, level) = part.split_once('=').ok_or(ConsoleError::InvalidLevel)?;
+        let level = level.parse::<LevelFilter>().map_err(|_| ConsoleError::InvalidLevel)?;
+        for sink in parse_sinks(sinks)?.iter() {
+            levels[index(sink)] = Some(level);
+        }
+    }
+    for (sink, level) in Sinks::all().iter().zip(levels) {
+        if let Some(level) = level {
+            set_level(sink, level);
+        }
+    }
+    Ok(())
+}
+
+/// Takes the sinks and their levels from the command line, once the logger is up.
+pub fn init() {
+    if let Some(spec) = cmdline::get("log.sinks") {
+        match parse_sinks(spec) {
+            Ok(sinks) => {
+                set_level(Sinks::all(), LevelFilter::Off);
+                set_level(sinks, LevelFilter::Trace);
+            }
+            Err(_) => log::warn!("bad sinks on the command line: {}", spec),
+        }
+    }
+    if let Some(spec) = cmdline::get("console") {
+        match parse_sinks(spec) {
+            Ok(sinks) => set_print_sinks(sinks),
+            Err(_) => log::warn!("bad sinks on the command line: {}", spec),
+        }
+    }
+    if let Some(spec) = cmdline::get("console.level") {
+        if set_levels(spec).is_err() {
+            log::warn!("bad console levels on the command line: {}", spec);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {$crate::console::_print(format_args!($($arg)*))};
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {$crate::print!("\n")};
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::console::write($crate::console::Sinks::SERIAL, format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => {$crate::serial_print!("\n")};
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_parse() {
+        assert_eq!(parse_sinks("serial, debugcon"), Ok(Sinks::SERIAL | Sinks::DEBUGCON));
+        assert_eq!(parse_sinks("none"), Ok(Sinks::empty()));
+        assert_eq!(parse_sinks("printer"), Err(ConsoleError::InvalidSink));
+        assert_eq!(set_levels("vga=info,printer=warn"), Err(ConsoleError::InvalidSink));
+        assert_eq!(set_levels("vga=loud"), Err(ConsoleError::InvalidLevel));
+        assert_eq!(set_levels("vga"), Err(ConsoleError::InvalidLevel));
+    }
+
+    #[test_case]
+    fn test_levels() {
+        let saved = Sinks::all().iter().map(level).collect::<alloc::vec::Vec<_>>();
+        set_levels("vga=warn, serial=debug,debugcon=off").unwrap();
+        assert_eq!(level(Sinks::VGA), LevelFilter::Warn);
+        assert_eq!(sinks_for(Level::Warn), Sinks::VGA | Sinks::SERIAL);
+        assert_eq!(sinks_for(Level::Info), Sinks::SERIAL);
+        assert_eq!(sinks_for(Level::Trace), Sinks::empty());
+        for (sink, level) in Sinks::all().iter().zip(saved) {
+            set_level(sink, level);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use uart_16550::SerialPort;
+
+use super::{ControlRegisters, Registers};
+use crate::backtrace::Backtrace;
+use crate::block::gpt::crc32_update;
+use crate::cmdline;
+use crate::dmesg::{self, Uptime};
+use crate::memory::{self, PAGE_SIZE};
+
+/* the crash dump.
+    With `crashdump` on the command line the panic handler also writes what it knows
+    in a form a script on the host can take apart again, crashdump.sh. It goes between
+        crashdump: begin 1
+        crashdump: finish
+    as sections, each the lines of hex its bytes make, 32 to a line, after
+        crashdump: section <name>
+    and before
+        crashdump: end <name> <length> <crc32>
+    so a section cut short or garbled on the way is told apart from a whole one. The
+    sections are
+        panic       the message, as text
+        registers   rax to r15, rip, rflags, then cr0, cr2, cr3 and cr4, u64 little endian
+        backtrace   return addresses, u64 little endian
+        dmesg       the log buffer, as text
+        memory      with crashdump.memory=<address>,<length>: the address, u64 little
+                    endian, then the bytes up to the first page not mapped
+    It writes straight to COM1, the serial lock may be held by whoever panicked, and
+    allocates nothing.
+ */
+
+const VERSION: u32 = 1;
+const COM1: u16 = 0x3F8;
+const BYTES_PER_LINE: usize = 32;
+// largest memory snapshot, at 115200 baud a MiB in hex already takes three minutes
+const MAX_MEMORY: u64 = 1024 * 1024;
+
+/// A section being written, hex lines as its bytes come in.
+struct Section<'a, W: Write> {
+    out: &'a mut W,
+    name: &'static str,
+    len: usize,
+    // bytes on the current line
+    column: usize,
+    crc: u32,
+}
+
+impl<'a, W: Write> Section<'a, W> {
+    fn begin(out: &'a mut W, name: &'static str) -> Result<Self, fmt::Error> {
+        writeln!(out, "crashdump: section {}", name)?;
+        Ok(Section { out, name, len: 0, column: 0, crc: !0 })
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> fmt::Result {
+        for &byte in bytes {
+            write!(self.out, "{:02x}", byte)?;
+            self.len += 1;
+            self.column += 1;
+            if self.column == BYTES_PER_LINE {
+                self.out.write_char('\n')?;
+                self.column = 0;
+            }
+        }
+        self.crc = crc32_update(self.crc, bytes);
+        Ok(())
+    }
+
+    fn write_u64s(&mut self, values: &[u64]) -> fmt::Result {
+        values.iter().try_for_each(|value| self.write_bytes(&value.to_le_bytes()))
+    }
+
+    fn end(self) -> fmt::Result {
+        if self.column > 0 {
+            self.out.write_char('\n')?;
+        }
+        writeln!(self.out, "crashdump: end {} {} {:08x}", self.name, self.len, !self.crc)
+    }
+}
+
+impl<W: Write> Write for Section<'_, W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.write_bytes(text.as_bytes())
+    }
+}
+
+/// The region `crashdump.memory` names.
+fn memory_region() -> Option<(u64, u64)> {
+    let (address, length) = cmdline::get("crashdump.memory")?.split_once(',')?;
+    let parse = |text: &str| match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    };
+    Some((parse(address)?, parse(length)?.min(MAX_MEMORY)))
+}
+
+fn write_memory(out: &mut impl Write, address: u64, length: u64) -> fmt::Result {
+    let mut section = Section::begin(out, "memory")?;
+    section.write_u64s(&[address])?;
+    let mut at = address;
+    let end = address.saturating_add(length);
+    while at < end {
+        if !memory::is_mapped(at) {
+            break;
+        }
+        let chunk = (PAGE_SIZE - at % PAGE_SIZE).min(end - at);
+        // mapped, and only read
+        let bytes = unsafe { core::slice::from_raw_parts(at as *const u8, chunk as usize) };
+        section.write_bytes(bytes)?;
+        at += chunk;
+    }
+    section.end()
+}
+
+/// Writes the dump to `out`.
+pub fn write_to(out: &mut impl Write, info: &PanicInfo, registers: &Registers, backtrace: &Backtrace) -> fmt::Result {
+    writeln!(out, "crashdump: begin {}", VERSION)?;
+
+    let mut section = Section::begin(out, "panic")?;
+    write!(section, "{}", info)?;
+    section.end()?;
+
+    let control = ControlRegisters::read();
+    let mut section = Section::begin(out, "registers")?;
+    section.write_u64s(&[
+        registers.rax, registers.rbx, registers.rcx, registers.rdx, registers.rsi, registers.rdi,
+        registers.rbp, registers.rsp, registers.r8, registers.r9, registers.r10, registers.r11,
+        registers.r12, registers.r13, registers.r14, registers.r15, registers.rip, registers.rflags,
+        control.cr0, control.cr2, control.cr3, control.cr4,
+    ])?;
+    section.end()?;
+
+    let mut section = Section::begin(out, "backtrace")?;
+    section.write_u64s(backtrace.frames())?;
+    section.end()?;
+
+    let mut section = Section::begin(out, "dmesg")?;
+    let mut result = Ok(());
+    dmesg::replay(u64::MAX, |_, time, level, text| {
+        if result.is_ok() {
+            result = writeln!(section, "[{}] {:<5} {}", Uptime(time), level, text);
+        }
+    });
+    result?;
+    section.end()?;
+
+    if let Some((address, length)) = memory_region() {
+        write_memory(out, address, length)?;
+    }
+    writeln!(out, "crashdump: finish")
+}
+
+/// Whether the command line asks for a dump.
+pub fn is_enabled() -> bool {
+    cmdline::get("crashdump").is_some()
+}
+
+/// Writes the dump to the serial port when the command line asks for one, from the panic handler.
+pub fn write(info: &PanicInfo, registers: &Registers, backtrace: &Backtrace) {
+    if !is_enabled() {
+        return;
+    }
+    let mut port = unsafe { SerialPort::new(COM1) };
+    let _ = write_to(&mut port, info, registers, backtrace);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::gpt::crc32;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    /// The bytes of section `name` in `dump`, checked against its trailer.
+    fn decode(dump: &str, name: &str) -> Vec<u8> {
+        let begin = alloc::format!("crashdump: section {}\n", name);
+        let start = dump.find(&begin).unwrap() + begin.len();
+        let end = start + dump[start..].find("crashdump: end ").unwrap();
+        let hex: String = dump[start..end].split_whitespace().collect();
+        let bytes: Vec<u8> = (0..hex.len()).step_by(2).map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap()).collect();
+        let trailer = dump[end..].lines().next().unwrap();
+        assert_eq!(trailer, alloc::format!("crashdump: end {} {} {:08x}", name, bytes.len(), crc32(&bytes)));
+        bytes
+    }
+
+    #[test_case]
+    fn test_sections() {
+        let mut dump = String::new();
+        let mut section = Section::begin(&mut dump, "test").unwrap();
+        section.write_bytes(&[0xab; 40]).unwrap();
+        write!(section, "{}", 42).unwrap();
+        section.end().unwrap();
+        let bytes = decode(&dump, "test");
+        assert_eq!(bytes.len(), 42);
+        assert_eq!(&bytes[40..], b"42");
+        assert!(dump.lines().all(|line| line.len() <= 2 * BYTES_PER_LINE));
+
+        let mut dump = String::new();
+        let here = [0x1122_3344_5566_7788u64, 0x99];
+        write_memory(&mut dump, here.as_ptr() as u64, 16).unwrap();
+        let bytes = decode(&dump, "memory");
+        assert_eq!(u64::from_le_bytes(bytes[..8].try_into().unwrap()), here.as_ptr() as u64);
+        assert_eq!(&bytes[8..16], &here[0].to_le_bytes());
+        assert_eq!(bytes.len(), 24);
+    }
+}
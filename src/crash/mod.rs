@@ -0,0 +1,191 @@
+pub mod kdump;
+
+use core::arch::asm;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+use crate::backtrace::Backtrace;
+use crate::console::{self, Sinks};
+use crate::{serial, thread};
+
+/* what the panic handler shows.
+    The registers as they were when captured, the control registers, where the
+    stack pointer is in the running thread's stack and how it got there, on the
+    screen and the serial port both. `Registers::capture` is inlined into its caller, so the panic handler
+    gets its own state, which still tells the stack the panic happened on.
+ */
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// The general purpose registers right here, except the one holding the
+    /// address of the result.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                "lea {1}, [rip]",
+                "mov [{0} + 0x80], {1}",
+                "pushfq",
+                "pop {1}",
+                "mov [{0} + 0x88], {1}",
+                in(reg) &mut registers as *mut Registers,
+                out(reg) _,
+            );
+        }
+        registers
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX={:016x} RSI={:016x} RDI={:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP={:016x} RSP={:016x} R8 ={:016x}", self.rbp, self.rsp, self.r8)?;
+        writeln!(f, "R9 ={:016x} R10={:016x} R11={:016x}", self.r9, self.r10, self.r11)?;
+        writeln!(f, "R12={:016x} R13={:016x} R14={:016x}", self.r12, self.r13, self.r14)?;
+        write!(f, "R15={:016x} RIP={:016x} RFL={:016x}", self.r15, self.rip, self.rflags)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    /// The address of the last page fault.
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (p4, flags) = Cr3::read_raw();
+        ControlRegisters {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: p4.start_address().as_u64() | flags as u64,
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "CR0={:016x} CR2={:016x}", self.cr0, self.cr2)?;
+        write!(f, "CR3={:016x} CR4={:016x}", self.cr3, self.cr4)
+    }
+}
+
+/// Where `rsp` is, given the bounds of the stack it should be in.
+struct StackPointer {
+    rsp: u64,
+    bounds: Option<(u64, u64)>,
+}
+
+impl fmt::Display for StackPointer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bounds {
+            None => write!(f, "stack: rsp {:#x}, bounds unknown", self.rsp),
+            Some((bottom, top)) if (bottom..=top).contains(&self.rsp) => {
+                write!(f, "stack: {:#x}..{:#x}, rsp {:#x}, {} bytes in use", bottom, top, self.rsp, top - self.rsp)
+            }
+            // an IST stack, or the thread overflowed its own
+            Some((bottom, top)) => write!(f, "stack: {:#x}..{:#x}, rsp {:#x} is outside", bottom, top, self.rsp),
+        }
+    }
+}
+
+// None on the bootloader's stack, or when the scheduler could not be asked
+fn current_stack() -> Option<(u64, u64)> {
+    thread::try_with_current(|thread| thread.stack().map(|stack| (stack.bottom(), stack.top()))).flatten()
+}
+
+/// Lets go of the screen and the serial port for the panic handler, after the other
+/// CPUs were halted: whoever held them, the panicking code or a halted CPU, never
+/// comes back to them.
+pub fn unlock_consoles() {
+    unsafe {
+        #[cfg(feature = "graphics")]
+        crate::vga_buffer::WRITER.force_unlock();
+        serial::SERIAL1.force_unlock();
+    }
+}
+
+fn print(args: fmt::Arguments) {
+    console::write(Sinks::VGA | Sinks::SERIAL, args);
+}
+
+/// Prints `registers`, the rest of the CPU state and `backtrace` on the screen and the serial port.
+pub fn dump(registers: &Registers, backtrace: &Backtrace) {
+    print(format_args!("{}\n", registers));
+    print(format_args!("{}\n", ControlRegisters::read()));
+    print(format_args!("{}\n", StackPointer { rsp: registers.rsp, bounds: current_stack() }));
+    print(format_args!("{}\n", backtrace));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[inline(never)]
+    fn capture_here() -> Registers {
+        Registers::capture()
+    }
+
+    #[test_case]
+    fn test_capture() {
+        let registers = capture_here();
+        let here = capture_here as *const () as u64;
+        assert!(registers.rip > here && registers.rip < here + 0x100, "{:#x}", registers.rip);
+        // interrupts are on in tests
+        assert_ne!(registers.rflags & 0x200, 0);
+        if let Some((bottom, top)) = current_stack() {
+            assert!((bottom..top).contains(&registers.rsp));
+        }
+        assert_eq!(ControlRegisters::read().cr3 & !0xfff, Cr3::read().0.start_address().as_u64());
+
+        let text = format!("{}", StackPointer { rsp: 0x1f00, bounds: Some((0x1000, 0x2000)) });
+        assert_eq!(text, "stack: 0x1000..0x2000, rsp 0x1f00, 256 bytes in use");
+        let text = format!("{}", StackPointer { rsp: 0x0ff8, bounds: Some((0x1000, 0x2000)) });
+        assert!(text.ends_with("is outside"));
+    }
+}
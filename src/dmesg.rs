@@ -0,0 +1,231 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::time::Duration;
+use log::Level;
+
+use crate::sync::SpinLock;
+
+/* the kernel log buffer.
+    Every record the logger lets through is kept here as well, numbered in order,
+    whether or not a console showed it. The buffer is a fixed ring of bytes: a new
+    record pushes out the oldest ones until it fits, so it takes no allocation and
+    works from anywhere the logger does, the panic handler included.
+ */
+
+pub const BUFFER_SIZE: usize = 64 * 1024;
+/// Longer records are cut.
+pub const MAX_TEXT_LEN: usize = 1024;
+// sequence number, microseconds since boot, level and text length in front of each text
+const HEADER_LEN: usize = 8 + 8 + 1 + 2;
+
+static RING: SpinLock<Ring<BUFFER_SIZE>> = SpinLock::new(Ring::new());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub sequence: u64,
+    /// Since boot, when it was logged.
+    pub time: Duration,
+    pub level: Level,
+    pub text: String,
+}
+
+struct Ring<const N: usize> {
+    bytes: [u8; N],
+    // offset of the oldest record, and where the next one goes
+    head: usize,
+    tail: usize,
+    used: usize,
+    first: u64,
+    next: u64,
+}
+
+fn level_from(byte: u8) -> Level {
+    match byte {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Ring { bytes: [0; N], head: 0, tail: 0, used: 0, first: 0, next: 0 }
+    }
+
+    fn copy_in(&mut self, offset: usize, data: &[u8]) {
+        for (index, &byte) in data.iter().enumerate() {
+            self.bytes[(offset + index) % N] = byte;
+        }
+    }
+
+    fn copy_out(&self, offset: usize, data: &mut [u8]) {
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = self.bytes[(offset + index) % N];
+        }
+    }
+
+    fn header(&self, offset: usize) -> (u64, u64, u8, usize) {
+        let mut header = [0u8; HEADER_LEN];
+        self.copy_out(offset, &mut header);
+        (
+            u64::from_le_bytes(header[..8].try_into().unwrap()),
+            u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            header[16],
+            u16::from_le_bytes([header[17], header[18]]) as usize,
+        )
+    }
+
+    fn push(&mut self, level: Level, time: Duration, text: &[u8]) -> u64 {
+        let text = &text[..text.len().min(MAX_TEXT_LEN).min(N - HEADER_LEN)];
+        let len = HEADER_LEN + text.len();
+        while self.used + len > N {
+            let (_, _, _, old) = self.header(self.head);
+            self.head = (self.head + HEADER_LEN + old) % N;
+            self.used -= HEADER_LEN + old;
+            self.first += 1;
+        }
+        let sequence = self.next;
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..16].copy_from_slice(&(time.as_micros() as u64).to_le_bytes());
+        header[16] = level as u8;
+        header[17..].copy_from_slice(&(text.len() as u16).to_le_bytes());
+        self.copy_in(self.tail, &header);
+        self.copy_in(self.tail + HEADER_LEN, text);
+        self.tail = (self.tail + len) % N;
+        self.used += len;
+        self.next += 1;
+        sequence
+    }
+
+    /// Calls `f` on every record from `sequence` on, oldest first.
+    fn for_each(&self, sequence: u64, mut f: impl FnMut(u64, Duration, Level, &str)) {
+        let mut text = [0u8; MAX_TEXT_LEN];
+        let mut offset = self.head;
+        for _ in self.first..self.next {
+            let (number, micros, level, len) = self.header(offset);
+            if number >= sequence {
+                self.copy_out(offset + HEADER_LEN, &mut text[..len]);
+                // a cut may have split a character
+                let valid = match core::str::from_utf8(&text[..len]) {
+                    Ok(valid) => valid,
+                    Err(error) => core::str::from_utf8(&text[..error.valid_up_to()]).unwrap(),
+                };
+                f(number, Duration::from_micros(micros), level_from(level), valid);
+            }
+            offset = (offset + HEADER_LEN + len) % N;
+        }
+    }
+}
+
+/// Formats into a fixed buffer, what does not fit is dropped.
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let count = text.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + count].copy_from_slice(&text.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Shows a time since boot as seconds with six decimals, the way records are printed.
+pub struct Uptime(pub Duration);
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>5}.{:06}", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
+/// Keeps a record logged `time` after boot, returns its sequence number.
+pub fn record(level: Level, time: Duration, args: fmt::Arguments) -> u64 {
+    let mut buffer = [0u8; MAX_TEXT_LEN];
+    let mut text = Truncating { buffer: &mut buffer, len: 0 };
+    let _ = text.write_fmt(args);
+    let len = text.len;
+    RING.lock().push(level, time, &buffer[..len])
+}
+
+/// The records from `sequence` on that are still kept, oldest first.
+pub fn entries_since(sequence: u64) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    RING.lock().for_each(sequence, |sequence, time, level, text| entries.push(Entry { sequence, time, level, text: text.into() }));
+    entries
+}
+
+/// The sequence number the next record gets.
+pub fn next_sequence() -> u64 {
+    RING.lock().next
+}
+
+/// Hands the last `count` records to `f` without allocating, for the panic
+/// handler; nothing when the buffer is locked, by the panicking code perhaps.
+pub fn replay(count: u64, f: impl FnMut(u64, Duration, Level, &str)) {
+    let Some(ring) = RING.try_lock() else {
+        return;
+    };
+    ring.for_each(ring.next.saturating_sub(count), f);
+}
+
+/// All records as text, one line each.
+pub fn text() -> String {
+    let mut text = String::new();
+    RING.lock().for_each(0, |_, time, level, line| text.push_str(&format!("[{}] {:<5} {}\n", Uptime(time), level, line)));
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    fn collect<const N: usize>(ring: &Ring<N>, sequence: u64) -> Vec<(u64, String)> {
+        let mut records = Vec::new();
+        ring.for_each(sequence, |sequence, _, _, text| records.push((sequence, text.into())));
+        records
+    }
+
+    #[test_case]
+    fn test_ring() {
+        let mut ring = Box::new(Ring::<80>::new());
+        let time = Duration::from_micros(1_500_042);
+        assert_eq!(ring.push(Level::Info, time, b"first"), 0);
+        assert_eq!(ring.push(Level::Warn, time, b"second record"), 1);
+        assert_eq!(collect(&ring, 0), [(0, "first".into()), (1, "second record".into())]);
+        assert_eq!(collect(&ring, 1), [(1, "second record".into())]);
+
+        // 24 + 32 + 38 bytes do not fit, the oldest goes and the newest wraps around
+        assert_eq!(ring.push(Level::Error, time, b"third, a longer one"), 2);
+        assert_eq!(collect(&ring, 0), [(1, "second record".into()), (2, "third, a longer one".into())]);
+        let mut kept = vec![];
+        ring.for_each(0, |_, time, level, _| kept.push((time, level)));
+        assert_eq!(kept, [(time, Level::Warn), (time, Level::Error)]);
+
+        // a record as long as the ring is cut to fit alone
+        ring.push(Level::Debug, time, &[b'x'; 100]);
+        assert_eq!(collect(&ring, 0), [(3, "x".repeat(80 - HEADER_LEN))]);
+    }
+
+    #[test_case]
+    fn test_records() {
+        let time = Duration::from_micros(2_000_001);
+        let sequence = record(Level::Info, time, format_args!("dmesg test {}", 1));
+        // other threads may log meanwhile
+        assert_eq!(entries_since(sequence)[0], Entry { sequence, time, level: Level::Info, text: "dmesg test 1".into() });
+        assert!(next_sequence() > sequence);
+        let mut replayed = Vec::new();
+        replay(next_sequence() - sequence, |sequence, _, _, text| replayed.push((sequence, String::from(text))));
+        assert_eq!(replayed[0], (sequence, "dmesg test 1".into()));
+        assert!(text().contains("[    2.000001] INFO  dmesg test 1\n"));
+    }
+}
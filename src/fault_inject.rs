@@ -0,0 +1,176 @@
+use alloc::format;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{cmdline, percpu, thread};
+
+/* fault injection, in debug builds.
+    A fault point makes every n-th call of what it guards fail the way it can fail
+    for real: a frame allocation returns `None`, a heap allocation null and a block
+    request `BlockError::Io`. The command line sets them for the whole kernel, as in
+        fail.frames=100 fail.block=7
+    and tests for the thread running them, away from the rest of the kernel; with
+    the heap that matters most, an infallible allocation that fails panics. Only
+    allocations through `try_reserve` and the like can take it.
+ */
+
+// the thread field of a point that fails for everyone
+const ANY_THREAD: u64 = u64::MAX;
+
+pub struct FaultPoint {
+    name: &'static str,
+    // 0 for never
+    every: AtomicU64,
+    // calls until the next failure
+    countdown: AtomicU64,
+    thread: AtomicU64,
+    injected: AtomicU64,
+}
+
+/// `memory::frame::allocate`.
+pub static FRAMES: FaultPoint = FaultPoint::new("frames");
+/// The kernel heap.
+pub static HEAP: FaultPoint = FaultPoint::new("heap");
+/// Every block device request, through `block::check_request`.
+pub static BLOCK: FaultPoint = FaultPoint::new("block");
+
+const POINTS: [&FaultPoint; 3] = [&FRAMES, &HEAP, &BLOCK];
+
+impl FaultPoint {
+    const fn new(name: &'static str) -> Self {
+        FaultPoint {
+            name,
+            every: AtomicU64::new(0),
+            countdown: AtomicU64::new(0),
+            thread: AtomicU64::new(ANY_THREAD),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether this call is to fail.
+    #[inline]
+    pub fn should_fail(&self) -> bool {
+        cfg!(debug_assertions) && self.every.load(Ordering::Relaxed) != 0 && self.count_call()
+    }
+
+    fn count_call(&self) -> bool {
+        let owner = self.thread.load(Ordering::Relaxed);
+        if owner != ANY_THREAD {
+            // interrupt handlers only borrow the thread they interrupted
+            let on_thread = percpu::try_this_cpu().is_some() && !percpu::in_interrupt() && thread::current_id().as_u64() == owner;
+            if !on_thread {
+                return false;
+            }
+        }
+        if self.countdown.fetch_sub(1, Ordering::Relaxed) > 1 {
+            return false;
+        }
+        self.countdown.store(self.every.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn arm(&self, every: u64, thread: u64) {
+        self.every.store(0, Ordering::Relaxed);
+        self.thread.store(thread, Ordering::Relaxed);
+        self.countdown.store(every, Ordering::Relaxed);
+        self.every.store(every, Ordering::Relaxed);
+    }
+
+    /// Fails every `every`-th call from anywhere, 0 turns the point off.
+    pub fn set(&self, every: u64) {
+        self.arm(every, ANY_THREAD);
+    }
+
+    /// Fails every `every`-th call the running thread makes, until the guard is dropped.
+    pub fn set_for_current_thread(&self, every: u64) -> Armed<'_> {
+        self.arm(every, thread::current_id().as_u64());
+        Armed { point: self }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Failures injected so far.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+}
+
+/// A fault point armed for one thread, turned off again when dropped.
+#[must_use]
+pub struct Armed<'a> {
+    point: &'a FaultPoint,
+}
+
+impl Drop for Armed<'_> {
+    fn drop(&mut self) {
+        self.point.set(0);
+    }
+}
+
+/// Arms the points the command line names, call once the heap and the command line are up.
+pub fn init() {
+    for point in POINTS {
+        if let Some(every) = cmdline::parse::<u64>(&format!("fail.{}", point.name)) {
+            if cfg!(debug_assertions) {
+                log::warn!("fault injection: every {} call to {} fails", every, point.name);
+                point.set(every);
+            } else {
+                log::warn!("fault injection: fail.{} is only honoured in debug builds", point.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    #[test_case]
+    fn test_every_nth_call() {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        static POINT: FaultPoint = FaultPoint::new("test");
+        assert!(!POINT.should_fail());
+        POINT.set(3);
+        let failed: Vec<bool> = (0..7).map(|_| POINT.should_fail()).collect();
+        assert_eq!(failed, [false, false, true, false, false, true, false]);
+        assert_eq!(POINT.injected(), 2);
+
+        // another thread is left alone
+        let armed = POINT.set_for_current_thread(1);
+        assert!(POINT.should_fail());
+        let other = thread::spawn("fault-inject", || {
+            if POINT.should_fail() {
+                thread::exit(thread::ExitCode::FAILURE);
+            }
+        });
+        assert_eq!(other.join(), thread::ExitCode::SUCCESS);
+        drop(armed);
+        assert!(!POINT.should_fail());
+    }
+
+    #[test_case]
+    fn test_frames_and_heap() {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let mut vec: Vec<u8> = Vec::new();
+        // the scheduler allocates from the timer interrupt, on this thread's behalf
+        without_interrupts(|| {
+            let _armed = HEAP.set_for_current_thread(1);
+            assert!(vec.try_reserve(64).is_err());
+        });
+        assert!(vec.try_reserve(64).is_ok());
+
+        let armed = FRAMES.set_for_current_thread(2);
+        let first = crate::memory::frame::allocate().expect("out of frames");
+        assert_eq!(crate::memory::frame::allocate(), None);
+        drop(armed);
+        crate::memory::frame::free(first);
+    }
+}
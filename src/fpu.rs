@@ -0,0 +1,210 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::ptr::{addr_of_mut, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use crate::interrupts::ExceptionStackFrame;
+
+const FPU_STATE_SIZE: usize = 1024;
+const FCW_DEFAULT: u16 = 0x037f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/* one save area per task.
+    Both fxsave (512 bytes) and xsave for x87/SSE/AVX (832 bytes) fit into it,
+    xsave requires 64 byte alignment.
+ */
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct FpuState([u8; FPU_STATE_SIZE]);
+
+impl FpuState {
+    pub const fn new() -> Self {
+        let mut area = [0u8; FPU_STATE_SIZE];
+        let fcw = FCW_DEFAULT.to_le_bytes();
+        area[0] = fcw[0];
+        area[1] = fcw[1];
+        let mxcsr = MXCSR_DEFAULT.to_le_bytes();
+        area[24] = mxcsr[0];
+        area[25] = mxcsr[1];
+        area[26] = mxcsr[2];
+        area[27] = mxcsr[3];
+        FpuState(area)
+    }
+
+    unsafe fn save(&mut self) {
+        let ptr = self.0.as_mut_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) ptr, options(nostack));
+        }
+    }
+
+    unsafe fn restore(&self) {
+        let ptr = self.0.as_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+// state whose contents currently live in the FPU registers
+static FPU_OWNER: AtomicPtr<FpuState> = AtomicPtr::new(null_mut());
+// state of the running task, loaded on the next #NM
+static FPU_CURRENT: AtomicPtr<FpuState> = AtomicPtr::new(null_mut());
+
+static mut BOOT_FPU_STATE: FpuState = FpuState::new();
+
+pub fn has_xsave() -> bool {
+    let leaf = unsafe { __cpuid_count(1, 0) };
+    leaf.ecx & (1 << 26) != 0
+}
+
+fn has_avx() -> bool {
+    let leaf = unsafe { __cpuid_count(1, 0) };
+    leaf.ecx & (1 << 28) != 0
+}
+
+fn xsave_area_size(features: XCr0Flags) -> usize {
+    unsafe { XCr0::write(features) };
+    // ebx reports the size needed for the features currently enabled in XCR0
+    unsafe { __cpuid_count(0xd, 0) }.ebx as usize
+}
+
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+    }
+
+    if has_xsave() {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE)) };
+        let mut features = XCr0Flags::X87 | XCr0Flags::SSE;
+        if has_avx() && xsave_area_size(features | XCr0Flags::AVX) <= FPU_STATE_SIZE {
+            features |= XCr0Flags::AVX;
+        }
+        if xsave_area_size(features) <= FPU_STATE_SIZE {
+            USE_XSAVE.store(true, Ordering::Relaxed);
+        }
+    }
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+        let boot = addr_of_mut!(BOOT_FPU_STATE);
+        FPU_OWNER.store(boot, Ordering::SeqCst);
+        FPU_CURRENT.store(boot, Ordering::SeqCst);
+    }
+}
+
+/* lazy switching.
+    The scheduler hands over the state of the task it is about to run. Registers are left
+    untouched and CR0.TS is set instead, the first FPU/SSE instruction of the new task then
+    traps with #NM and the swap happens in `device_not_available_handler`.
+ */
+pub fn set_current(state: *mut FpuState) {
+    FPU_CURRENT.store(state, Ordering::SeqCst);
+    unsafe {
+        if state == FPU_OWNER.load(Ordering::SeqCst) {
+            asm!("clts", options(nomem, nostack));
+        } else {
+            Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+        }
+    }
+}
+
+/// The registers currently loaded belong to `state`, used when the running context becomes a thread.
+pub fn adopt(state: *mut FpuState) {
+    FPU_OWNER.store(state, Ordering::SeqCst);
+    FPU_CURRENT.store(state, Ordering::SeqCst);
+    unsafe { asm!("clts", options(nomem, nostack)) };
+}
+
+/// Forget a state that is about to be freed so it is never saved into.
+pub fn release(state: *mut FpuState) {
+    let _ = FPU_OWNER.compare_exchange(state, null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+    let _ = FPU_CURRENT.compare_exchange(state, null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+}
+
+pub fn uses_xsave() -> bool {
+    USE_XSAVE.load(Ordering::Relaxed)
+}
+
+pub extern "C" fn device_not_available_handler(_stack_frame: &ExceptionStackFrame) {
+    unsafe {
+        asm!("clts", options(nomem, nostack));
+
+        let owner = FPU_OWNER.load(Ordering::SeqCst);
+        let current = FPU_CURRENT.load(Ordering::SeqCst);
+        if owner == current {
+            return;
+        }
+        if !owner.is_null() {
+            (*owner).save();
+        }
+        if current.is_null() {
+            asm!("fninit", options(nomem, nostack));
+        } else {
+            (*current).restore();
+        }
+        FPU_OWNER.store(current, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_xmm0_low() -> u64 {
+        let value: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    fn write_xmm0_low(value: u64) {
+        unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+    }
+
+    #[test_case]
+    fn test_sse_enabled() {
+        write_xmm0_low(0x1234_5678);
+        assert_eq!(read_xmm0_low(), 0x1234_5678);
+    }
+
+    #[test_case]
+    fn test_lazy_switch_preserves_state() {
+        static mut OTHER: FpuState = FpuState::new();
+        let boot = FPU_CURRENT.load(Ordering::SeqCst);
+
+        write_xmm0_low(0xaaaa);
+        set_current(unsafe { addr_of_mut!(OTHER) });
+        // traps with #NM, the boot state gets saved and OTHER loaded
+        write_xmm0_low(0xbbbb);
+        assert_eq!(read_xmm0_low(), 0xbbbb);
+
+        set_current(boot);
+        assert_eq!(read_xmm0_low(), 0xaaaa);
+
+        set_current(unsafe { addr_of_mut!(OTHER) });
+        assert_eq!(read_xmm0_low(), 0xbbbb);
+
+        set_current(boot);
+        release(unsafe { addr_of_mut!(OTHER) });
+    }
+}
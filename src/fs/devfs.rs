@@ -0,0 +1,185 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{mkdir, mount, DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::sync::SpinLock;
+use crate::{random, serial, tty};
+
+/* devfs.
+    The /dev directory, holding one node per character device a driver registered.
+    Nodes have no contents of their own: reads and writes go straight to the driver
+    and the file offset means nothing to it.
+ */
+
+/// A device that moves a stream of bytes, as a terminal or a serial port does.
+pub trait CharDevice: Send + Sync {
+    /// Reads what is available, blocking until there is something if the device blocks.
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError>;
+}
+
+struct DeviceNode {
+    inode: u64,
+    device: Arc<dyn CharDevice>,
+}
+
+impl Inode for DeviceNode {
+    fn metadata(&self) -> Metadata {
+        Metadata { inode: self.inode, file_type: FileType::CharDevice, size: 0, links: 1, mode: 0o666 }
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.device.read(buffer)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.device.write(buffer)
+    }
+
+    // a stream has no length to cut
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+static DEVICES: SpinLock<BTreeMap<String, Arc<DeviceNode>>> = SpinLock::new(BTreeMap::new());
+// the root directory is inode 1
+static NEXT_INODE: AtomicU64 = AtomicU64::new(2);
+
+/// Makes `device` appear as /dev/`name`.
+pub fn register(name: &str, device: Arc<dyn CharDevice>) -> Result<(), FsError> {
+    super::path::check_name(name)?;
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(FsError::Exists);
+    }
+    let inode = NEXT_INODE.fetch_add(1, Ordering::Relaxed);
+    devices.insert(name.to_string(), Arc::new(DeviceNode { inode, device }));
+    Ok(())
+}
+
+/// Removes /dev/`name`, files already open on it keep the device.
+pub fn unregister(name: &str) -> Option<Arc<dyn CharDevice>> {
+    DEVICES.lock().remove(name).map(|node| node.device.clone())
+}
+
+struct DevDirectory;
+
+impl Inode for DevDirectory {
+    fn metadata(&self) -> Metadata {
+        let entries = DEVICES.lock().len() as u64;
+        Metadata { inode: 1, file_type: FileType::Directory, size: entries, links: 2, mode: 0o755 }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        DEVICES.lock().get(name).map(|node| node.clone() as Arc<dyn Inode>).ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(DEVICES.lock().iter()
+            .map(|(name, node)| DirEntry { name: name.clone(), inode: node.inode, file_type: FileType::CharDevice })
+            .collect())
+    }
+
+    // nodes come from drivers, not from users
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn rename(&self, _name: &str, _target: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct DevFs {
+    root: Arc<DevDirectory>,
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Mounts devfs at /dev and registers the devices every machine has.
+pub fn init() {
+    mkdir("/dev").expect("cannot create /dev");
+    mount("/dev", Arc::new(DevFs { root: Arc::new(DevDirectory) })).expect("cannot mount /dev");
+    register("tty0", Arc::new(tty::Terminal)).expect("tty0 registered twice");
+    register("ttyS0", Arc::new(serial::SerialDevice)).expect("ttyS0 registered twice");
+    register("random", Arc::new(random::RandomDevice)).expect("random registered twice");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::{self, File, OpenFlags, SeekFrom};
+
+    struct Echo(SpinLock<Vec<u8>>);
+
+    impl CharDevice for Echo {
+        fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+            let mut pending = self.0.lock();
+            let count = pending.len().min(buffer.len());
+            buffer[..count].copy_from_slice(&pending[..count]);
+            pending.drain(..count);
+            Ok(count)
+        }
+
+        fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+            self.0.lock().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+    }
+
+    #[test_case]
+    fn test_device_nodes() {
+        register("echo-test", Arc::new(Echo(SpinLock::new(Vec::new())))).unwrap();
+        assert_eq!(register("echo-test", Arc::new(Echo(SpinLock::new(Vec::new())))).err(), Some(FsError::Exists));
+        let names: Vec<String> = fs::read_dir("/dev").unwrap().into_iter().map(|entry| entry.name).collect();
+        for name in ["echo-test", "random", "tty0", "ttyS0"] {
+            assert!(names.iter().any(|entry| entry == name), "{} missing", name);
+        }
+
+        let echo = fs::open("/dev/echo-test", OpenFlags::READ | OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+        assert_eq!(echo.write(b"ping"), Ok(4));
+        let mut buffer = [0u8; 8];
+        assert_eq!(echo.read(&mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"ping");
+        assert_eq!(echo.seek(SeekFrom::Start(0)), Err(FsError::NotSeekable));
+        assert_eq!(fs::stat("/dev/echo-test").unwrap().file_type, FileType::CharDevice);
+        assert_eq!(fs::create("/dev/new", FileType::Regular).err(), Some(FsError::Unsupported));
+
+        let random = fs::open("/dev/random", OpenFlags::READ).unwrap();
+        let (mut first, mut second) = ([0u8; 32], [0u8; 32]);
+        assert_eq!(random.read(&mut first), Ok(32));
+        assert_eq!(random.read(&mut second), Ok(32));
+        assert_ne!(first, second);
+
+        assert!(unregister("echo-test").is_some());
+        assert_eq!(fs::stat("/dev/echo-test").err(), Some(FsError::NotFound));
+        // still open, the node keeps working
+        assert_eq!(echo.write(b"!"), Ok(1));
+    }
+}
@@ -0,0 +1,509 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{BlockCache, BlockDevice};
+
+/* ext2, read only.
+    The superblock 1024 bytes into the volume gives the block size and how blocks
+    and inodes are split into groups; each group's descriptor says where its inode
+    table starts. An inode maps file blocks through 12 direct pointers, then one
+    single, double and triple indirect block each. A pointer of 0 is a hole.
+ */
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+const DIRECT_BLOCKS: u64 = 12;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+// symlink targets shorter than this live in the block pointers
+const FAST_SYMLINK_MAX: u64 = 60;
+const CACHE_BLOCKS: usize = 128;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+// anything else changes the layout, extents and 64 bit descriptors come with ext4
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const MODE_TYPE: u16 = 0xf000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// The file type of an inode mode, fifos and sockets show as regular files.
+fn file_type(mode: u16) -> FileType {
+    match mode & MODE_TYPE {
+        MODE_DIRECTORY => FileType::Directory,
+        MODE_CHAR_DEVICE => FileType::CharDevice,
+        MODE_BLOCK_DEVICE => FileType::BlockDevice,
+        MODE_SYMLINK => FileType::Symlink,
+        _ => FileType::Regular,
+    }
+}
+
+/// The type stored in directory entries with the filetype feature.
+fn entry_file_type(kind: u8) -> Option<FileType> {
+    match kind {
+        1 | 5 | 6 => Some(FileType::Regular),
+        2 => Some(FileType::Directory),
+        3 => Some(FileType::CharDevice),
+        4 => Some(FileType::BlockDevice),
+        7 => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+struct Volume {
+    device: Arc<BlockCache>,
+    block_size: usize,
+    // device blocks per filesystem block
+    ratio: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // first block of each group's inode table
+    inode_tables: Vec<u64>,
+    filetype: bool,
+}
+
+impl Volume {
+    fn read_block(&self, block: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        if block == 0 {
+            buffer.fill(0);
+            return Ok(());
+        }
+        Ok(self.device.read_blocks(block * self.ratio, buffer)?)
+    }
+
+    fn read_inode(&self, number: u32) -> Result<DiskInode, FsError> {
+        if number == 0 || number > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+        let index = (number - 1) as usize;
+        let group = index / self.inodes_per_group as usize;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupted)?;
+        let offset = (index % self.inodes_per_group as usize) * self.inode_size;
+        let mut block = vec![0u8; self.block_size];
+        self.read_block(table + (offset / self.block_size) as u64, &mut block)?;
+        let raw = &block[offset % self.block_size..];
+        let mode = u16_at(raw, 0);
+        let mut size = u32_at(raw, 4) as u64;
+        // regular files keep the upper half of their size where directories keep an ACL
+        if mode & MODE_TYPE == MODE_REGULAR {
+            size |= (u32_at(raw, 108) as u64) << 32;
+        }
+        Ok(DiskInode {
+            mode,
+            size,
+            links: u16_at(raw, 26),
+            sectors: u32_at(raw, 28),
+            block: raw[40..100].try_into().unwrap(),
+        })
+    }
+
+    /// The filesystem block pointed to by entry `index` of the pointer block `table`.
+    fn pointer(&self, table: u32, index: u64) -> Result<u32, FsError> {
+        if table == 0 {
+            return Ok(0);
+        }
+        let mut block = vec![0u8; self.block_size];
+        self.read_block(table as u64, &mut block)?;
+        Ok(u32_at(&block, index as usize * 4))
+    }
+}
+
+struct DiskInode {
+    mode: u16,
+    size: u64,
+    links: u16,
+    // 512 byte sectors in use, including indirect blocks
+    sectors: u32,
+    block: [u8; 60],
+}
+
+impl DiskInode {
+    fn block_pointer(&self, index: usize) -> u32 {
+        u32_at(&self.block, index * 4)
+    }
+}
+
+pub struct Ext2Inode {
+    volume: Arc<Volume>,
+    number: u32,
+    disk: DiskInode,
+}
+
+impl Ext2Inode {
+    fn load(volume: &Arc<Volume>, number: u32) -> Result<Arc<Self>, FsError> {
+        let disk = volume.read_inode(number)?;
+        Ok(Arc::new(Ext2Inode { volume: volume.clone(), number, disk }))
+    }
+
+    fn file_type(&self) -> FileType {
+        file_type(self.disk.mode)
+    }
+
+    /// The filesystem block holding block `index` of this file, 0 for a hole.
+    fn map(&self, index: u64) -> Result<u32, FsError> {
+        let per_block = (self.volume.block_size / 4) as u64;
+        if index < DIRECT_BLOCKS {
+            return Ok(self.disk.block_pointer(index as usize));
+        }
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = 1;
+        for level in 0..3 {
+            span *= per_block;
+            if index < span {
+                let mut block = self.disk.block_pointer(DIRECT_BLOCKS as usize + level);
+                // walk down one pointer block per level
+                while span > 1 {
+                    span /= per_block;
+                    block = self.volume.pointer(block, index / span)?;
+                    index %= span;
+                }
+                return Ok(block);
+            }
+            index -= span;
+        }
+        Err(FsError::Corrupted)
+    }
+
+    fn is_fast_symlink(&self) -> bool {
+        self.file_type() == FileType::Symlink && self.disk.size < FAST_SYMLINK_MAX && self.disk.sectors == 0
+    }
+
+    fn read_data(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let Some(rest) = self.disk.size.checked_sub(offset) else {
+            return Ok(0);
+        };
+        let length = buffer.len().min(rest as usize);
+        if self.is_fast_symlink() {
+            buffer[..length].copy_from_slice(&self.disk.block[offset as usize..][..length]);
+            return Ok(length);
+        }
+        let block_size = self.volume.block_size;
+        let mut block = vec![0u8; block_size];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let within = (position % block_size as u64) as usize;
+            let count = (block_size - within).min(length - done);
+            self.volume.read_block(self.map(position / block_size as u64)? as u64, &mut block)?;
+            buffer[done..done + count].copy_from_slice(&block[within..within + count]);
+            done += count;
+        }
+        Ok(length)
+    }
+
+    /// Every entry of this directory, "." and ".." included.
+    fn entries(&self) -> Result<Vec<(String, u32, Option<FileType>)>, FsError> {
+        if self.file_type() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        let mut data = vec![0u8; self.disk.size as usize];
+        self.read_data(0, &mut data)?;
+        let mut entries = Vec::new();
+        let block_size = self.volume.block_size;
+        for block in data.chunks(block_size) {
+            let mut offset = 0;
+            // entries never cross a block, the last one stretches to its end
+            while offset + 8 <= block.len() {
+                let inode = u32_at(block, offset);
+                let record = u16_at(block, offset + 4) as usize;
+                let name_length = if self.volume.filetype { block[offset + 6] as usize } else { u16_at(block, offset + 6) as usize };
+                if record < 8 || offset + record > block.len() || 8 + name_length > record {
+                    return Err(FsError::Corrupted);
+                }
+                // an inode of 0 marks an unused entry
+                if inode != 0 {
+                    let name = String::from_utf8_lossy(&block[offset + 8..offset + 8 + name_length]).into_owned();
+                    let kind = if self.volume.filetype { entry_file_type(block[offset + 7]) } else { None };
+                    entries.push((name, inode, kind));
+                }
+                offset += record;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.number as u64,
+            file_type: self.file_type(),
+            size: self.disk.size,
+            links: self.disk.links as u32,
+            mode: self.disk.mode & 0o7777,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match self.file_type() {
+            FileType::Directory => Err(FsError::IsDirectory),
+            FileType::Regular | FileType::Symlink => self.read_data(offset, buffer),
+            // device nodes do not read through the filesystem
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let (_, number, _) = self.entries()?.into_iter().find(|(entry, _, _)| entry == name).ok_or(FsError::NotFound)?;
+        Ok(Ext2Inode::load(&self.volume, number)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.entries()?
+            .into_iter()
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .map(|(name, inode, kind)| {
+                // without the filetype feature only the inode knows
+                let file_type = match kind {
+                    Some(file_type) => file_type,
+                    None => file_type(self.volume.read_inode(inode)?.mode),
+                };
+                Ok(DirEntry { name, inode: inode as u64, file_type })
+            })
+            .collect()
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _name: &str, _target: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Ext2 {
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2 {
+    /// Reads the superblock and group descriptors of `device` and mounts it read only.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let device_block = device.block_size();
+        // filesystem blocks are powers of two from 1K, so they are whole device blocks
+        if !device_block.is_power_of_two() || device_block > SUPERBLOCK_OFFSET {
+            return Err(FsError::Unsupported);
+        }
+        let mut superblock = vec![0u8; SUPERBLOCK_SIZE];
+        device.read_blocks((SUPERBLOCK_OFFSET / device_block) as u64, &mut superblock)?;
+        if u16_at(&superblock, 56) != MAGIC {
+            return Err(FsError::Corrupted);
+        }
+        let inodes_count = u32_at(&superblock, 0);
+        let blocks_count = u32_at(&superblock, 4) as u64;
+        let first_data_block = u32_at(&superblock, 20) as u64;
+        let log_block_size = u32_at(&superblock, 24);
+        let blocks_per_group = u32_at(&superblock, 32) as u64;
+        let inodes_per_group = u32_at(&superblock, 40);
+        // revision 0 has none of the extended fields
+        let (inode_size, incompat) = match u32_at(&superblock, 76) {
+            0 => (128, 0),
+            _ => (u16_at(&superblock, 88) as usize, u32_at(&superblock, 96)),
+        };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 || inode_size < 128 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024usize << log_block_size;
+        if inode_size > block_size {
+            return Err(FsError::Corrupted);
+        }
+        let ratio = (block_size / device_block) as u64;
+        if blocks_count * ratio > device.num_blocks() {
+            return Err(FsError::Corrupted);
+        }
+
+        // the descriptor table starts in the block after the superblock
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group) as usize;
+        let table_blocks = (groups * GROUP_DESCRIPTOR_SIZE).div_ceil(block_size);
+        let mut descriptors = vec![0u8; table_blocks * block_size];
+        device.read_blocks((first_data_block + 1) * ratio, &mut descriptors)?;
+        let inode_tables = descriptors.chunks_exact(GROUP_DESCRIPTOR_SIZE)
+            .take(groups)
+            .map(|descriptor| u32_at(descriptor, 8) as u64)
+            .collect();
+
+        let volume = Arc::new(Volume {
+            device: BlockCache::new(device, CACHE_BLOCKS),
+            block_size,
+            ratio,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+        });
+        let root = Ext2Inode::load(&volume, ROOT_INODE)?;
+        if root.file_type() != FileType::Directory {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Arc::new(Ext2 { root }))
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const BLOCK: usize = 1024;
+    const INODE_TABLE: usize = 5;
+
+    /* a small image as mke2fs lays it out.
+        One group of 128 blocks of 1K: superblock in block 1, descriptors in 2, the
+        bitmaps in 3 and 4, an inode table of 32 inodes from 5, data from 10 on.
+     */
+    fn put_inode(image: &mut [u8], number: usize, mode: u16, size: u32, blocks: &[u32]) {
+        let raw = &mut image[INODE_TABLE * BLOCK + (number - 1) * 128..][..128];
+        raw[0..2].copy_from_slice(&mode.to_le_bytes());
+        raw[4..8].copy_from_slice(&size.to_le_bytes());
+        raw[26..28].copy_from_slice(&1u16.to_le_bytes());
+        raw[28..32].copy_from_slice(&(blocks.iter().filter(|&&block| block != 0).count() as u32 * 2).to_le_bytes());
+        for (index, block) in blocks.iter().enumerate() {
+            raw[40 + index * 4..44 + index * 4].copy_from_slice(&block.to_le_bytes());
+        }
+    }
+
+    fn put_directory(image: &mut [u8], block: usize, entries: &[(u32, &str, u8)]) {
+        let data = &mut image[block * BLOCK..][..BLOCK];
+        let mut offset = 0;
+        for (index, (inode, name, kind)) in entries.iter().enumerate() {
+            let record = if index + 1 == entries.len() { BLOCK - offset } else { (8 + name.len()).next_multiple_of(4) };
+            data[offset..offset + 4].copy_from_slice(&inode.to_le_bytes());
+            data[offset + 4..offset + 6].copy_from_slice(&(record as u16).to_le_bytes());
+            data[offset + 6] = name.len() as u8;
+            data[offset + 7] = *kind;
+            data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+            offset += record;
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 128 * BLOCK];
+        let superblock = &mut image[SUPERBLOCK_OFFSET..][..SUPERBLOCK_SIZE];
+        superblock[0..4].copy_from_slice(&32u32.to_le_bytes());
+        superblock[4..8].copy_from_slice(&128u32.to_le_bytes());
+        superblock[20..24].copy_from_slice(&1u32.to_le_bytes());
+        superblock[32..36].copy_from_slice(&8192u32.to_le_bytes());
+        superblock[40..44].copy_from_slice(&32u32.to_le_bytes());
+        superblock[56..58].copy_from_slice(&MAGIC.to_le_bytes());
+        superblock[76..80].copy_from_slice(&1u32.to_le_bytes());
+        superblock[88..90].copy_from_slice(&128u16.to_le_bytes());
+        superblock[96..100].copy_from_slice(&INCOMPAT_FILETYPE.to_le_bytes());
+        image[2 * BLOCK + 8..2 * BLOCK + 12].copy_from_slice(&(INODE_TABLE as u32).to_le_bytes());
+
+        put_inode(&mut image, 2, MODE_DIRECTORY | 0o755, BLOCK as u32, &[10]);
+        put_directory(&mut image, 10, &[(2, ".", 2), (2, "..", 2), (12, "big", 1), (13, "docs", 2), (14, "link", 7)]);
+        // 12 direct blocks, then the single indirect block 30 pointing at 31 and a hole
+        let mut blocks: Vec<u32> = (40..52).collect();
+        blocks.push(30);
+        let size = 14 * BLOCK as u32;
+        put_inode(&mut image, 12, MODE_REGULAR | 0o644, size, &blocks);
+        image[30 * BLOCK..30 * BLOCK + 4].copy_from_slice(&31u32.to_le_bytes());
+        for (index, block) in (40..52).chain(31..32).enumerate() {
+            image[block * BLOCK..(block + 1) * BLOCK].fill(index as u8 + 1);
+        }
+        put_inode(&mut image, 13, MODE_DIRECTORY | 0o700, BLOCK as u32, &[11]);
+        put_directory(&mut image, 11, &[(13, ".", 2), (2, "..", 2), (0, "deleted", 1), (15, "notes.txt", 1)]);
+        put_inode(&mut image, 14, MODE_SYMLINK | 0o777, 14, &[]);
+        image[INODE_TABLE * BLOCK + 13 * 128 + 40..][..14].copy_from_slice(b"docs/notes.txt");
+        put_inode(&mut image, 15, MODE_REGULAR | 0o644, 6, &[12]);
+        image[12 * BLOCK..12 * BLOCK + 6].copy_from_slice(b"hello\n");
+        image
+    }
+
+    #[test_case]
+    fn test_read_image() {
+        let disk = Arc::new(RamDisk::from_bytes(512, image()).read_only());
+        let fs = Ext2::mount(disk).expect("mount failed");
+        let root = fs.root();
+        let mut names: Vec<(String, FileType)> = root.read_dir().unwrap().into_iter().map(|entry| (entry.name, entry.file_type)).collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names, [
+            (String::from("big"), FileType::Regular),
+            (String::from("docs"), FileType::Directory),
+            (String::from("link"), FileType::Symlink),
+        ]);
+
+        let big = root.lookup("big").unwrap();
+        assert_eq!(big.metadata().size, 14 * BLOCK as u64);
+        let mut buffer = vec![0u8; 3 * BLOCK];
+        // across the last direct block, the indirect one and the hole after it
+        assert_eq!(big.read_at(11 * BLOCK as u64 + 1000, &mut buffer), Ok(3 * BLOCK - 1000));
+        assert!(buffer[..24].iter().all(|&byte| byte == 12));
+        assert!(buffer[24..24 + BLOCK].iter().all(|&byte| byte == 13));
+        assert!(buffer[24 + BLOCK..3 * BLOCK - 1000].iter().all(|&byte| byte == 0));
+
+        let docs = root.lookup("docs").unwrap();
+        assert_eq!(docs.metadata().mode, 0o700);
+        assert_eq!(docs.read_dir().unwrap().len(), 1);
+        let mut note = [0u8; 16];
+        assert_eq!(docs.lookup("notes.txt").unwrap().read_at(0, &mut note), Ok(6));
+        assert_eq!(&note[..6], b"hello\n");
+        assert_eq!(docs.lookup("deleted").err(), Some(FsError::NotFound));
+
+        let link = root.lookup("link").unwrap();
+        assert_eq!(link.read_at(0, &mut note), Ok(14));
+        assert_eq!(&note[..14], b"docs/notes.txt");
+
+        assert_eq!(docs.create("new", FileType::Regular).err(), Some(FsError::ReadOnly));
+        assert_eq!(big.write_at(0, b"x"), Err(FsError::ReadOnly));
+    }
+
+    #[test_case]
+    fn test_rejects_foreign_images() {
+        let mut bytes = image();
+        bytes[SUPERBLOCK_OFFSET + 56] = 0;
+        assert_eq!(Ext2::mount(Arc::new(RamDisk::from_bytes(512, bytes))).err(), Some(FsError::Corrupted));
+        let mut bytes = image();
+        // extents
+        bytes[SUPERBLOCK_OFFSET + 96] |= 0x40;
+        assert_eq!(Ext2::mount(Arc::new(RamDisk::from_bytes(512, bytes))).err(), Some(FsError::Unsupported));
+    }
+}
@@ -0,0 +1,1239 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use super::{path, DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{BlockCache, BlockDevice};
+use crate::sync::{Mutex, MutexGuard, SpinLock};
+
+/* FAT32.
+    The volume is a boot sector with the geometry, one or more copies of the file
+    allocation table, then clusters. Each file is a chain of clusters linked through
+    the FAT; a directory is a file of 32 byte entries, long names are spread over
+    extra entries in front of the 8.3 one. Every operation holds the volume lock,
+    inodes are shared per directory entry so all openers see the same size.
+ */
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRY_MASK: u32 = 0x0fff_ffff;
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const FREE: u32 = 0;
+const FIRST_CLUSTER: u32 = 2;
+// blocks cached below the filesystem
+const CACHE_BLOCKS: usize = 128;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const DELETED: u8 = 0xe5;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+// NT flags for an all lower case base name or extension
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXTENSION: u8 = 0x10;
+
+const FS_INFO_LEAD: u32 = 0x4161_5252;
+const FS_INFO_STRUCT: u32 = 0x6141_7272;
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Where the 8.3 entry of a file sits: its directory's first cluster and slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    directory: u32,
+    slot: u32,
+}
+
+/// A directory entry in use, with the slots of its long name in front.
+struct Slot {
+    first_slot: u32,
+    slot: u32,
+    raw: [u8; DIR_ENTRY_SIZE],
+    name: String,
+}
+
+impl Slot {
+    fn attributes(&self) -> u8 {
+        self.raw[11]
+    }
+
+    fn is_directory(&self) -> bool {
+        self.attributes() & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.raw[0] == b'.'
+    }
+
+    fn first_cluster(&self) -> u32 {
+        first_cluster(&self.raw)
+    }
+
+    fn size(&self) -> u32 {
+        u32_at(&self.raw, 28)
+    }
+}
+
+fn first_cluster(raw: &[u8]) -> u32 {
+    (u16_at(raw, 20) as u32) << 16 | u16_at(raw, 26) as u32
+}
+
+fn set_first_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn is_short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&byte)
+}
+
+/// The 8.3 form of `name` and its case flags, if it has one without a long name.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || extension.contains('.') {
+        return None;
+    }
+    let case = |part: &str, flag: u8| match part {
+        _ if !part.bytes().any(|byte| byte.is_ascii_lowercase()) => Some(0),
+        _ if !part.bytes().any(|byte| byte.is_ascii_uppercase()) => Some(flag),
+        _ => None,
+    };
+    let flags = case(base, LOWER_BASE)? | case(extension, LOWER_EXTENSION)?;
+    let mut short = [b' '; 11];
+    let (short_base, short_extension) = short.split_at_mut(8);
+    for (target, byte) in short_base.iter_mut().zip(base.bytes()).chain(short_extension.iter_mut().zip(extension.bytes())) {
+        let byte = byte.to_ascii_uppercase();
+        if !is_short_char(byte) {
+            return None;
+        }
+        *target = byte;
+    }
+    Some((short, flags))
+}
+
+/// A "BASE~N.EXT" alias for a name that needs a long entry, unique among `taken`.
+fn generate_short_name(name: &str, taken: &[Slot]) -> Result<[u8; 11], FsError> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|&byte| byte != b' ' && byte != b'.')
+            .map(|byte| byte.to_ascii_uppercase())
+            .map(|byte| if is_short_char(byte) { byte } else { b'_' })
+            .collect()
+    };
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (clean(&name[..dot]), clean(&name[dot + 1..])),
+        _ => (clean(name), Vec::new()),
+    };
+    for number in 1..1_000_000u32 {
+        let suffix = alloc::format!("~{}", number);
+        let keep = base.len().min(8 - suffix.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+        let extension = &extension[..extension.len().min(3)];
+        short[8..8 + extension.len()].copy_from_slice(extension);
+        if taken.iter().all(|slot| slot.raw[..11] != short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+fn short_name_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// The name of an 8.3 entry as shown, honouring the lower case flags.
+fn display_short_name(raw: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let text = bytes.iter().map(|&byte| byte as char).collect::<String>();
+        let text = String::from(text.trim_end());
+        if lower { text.to_ascii_lowercase() } else { text }
+    };
+    let mut base = raw[..8].to_vec();
+    // a first byte of 0xe5 is stored as 0x05
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+    let base = part(&base, raw[12] & LOWER_BASE != 0);
+    let extension = part(&raw[8..11], raw[12] & LOWER_EXTENSION != 0);
+    if extension.is_empty() { base } else { alloc::format!("{}.{}", base, extension) }
+}
+
+/// The long name entries for `name`, in the order they are stored.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_CHARS);
+    // terminated by a NUL unless it fills the last entry, then padded with 0xffff
+    if units.len() < count * LONG_NAME_CHARS {
+        units.push(0);
+    }
+    units.resize(count * LONG_NAME_CHARS, 0xffff);
+    (0..count).rev().map(|index| {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = (index + 1) as u8 | if index + 1 == count { LAST_LONG_ENTRY } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let chunk = &units[index * LONG_NAME_CHARS..][..LONG_NAME_CHARS];
+        let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        for (&offset, unit) in offsets.iter().zip(chunk) {
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entry
+    }).collect()
+}
+
+fn long_name_units(entry: &[u8]) -> [u16; LONG_NAME_CHARS] {
+    let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+    offsets.map(|offset| u16_at(entry, offset))
+}
+
+struct State {
+    free_count: u32,
+    next_free: u32,
+    inodes: BTreeMap<Location, Weak<FatInode>>,
+}
+
+struct Volume {
+    device: Arc<BlockCache>,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    cluster_size: usize,
+    fat_start: u64,
+    fat_sectors: u64,
+    num_fats: u64,
+    data_start: u64,
+    // clusters are numbered from 2 to cluster_count + 1
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info: Option<u64>,
+    state: Mutex<State>,
+}
+
+impl Volume {
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster)
+    }
+
+    fn read_fat(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = cluster as usize * 4;
+        let mut sector = vec![0u8; self.sector_size];
+        self.device.read_blocks(self.fat_start + (offset / self.sector_size) as u64, &mut sector)?;
+        Ok(u32_at(&sector, offset % self.sector_size) & ENTRY_MASK)
+    }
+
+    /// Sets the entry of `cluster` in every copy of the FAT, keeping the reserved top bits.
+    fn write_fat(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let offset = cluster as usize * 4;
+        let within = offset % self.sector_size;
+        let mut sector = vec![0u8; self.sector_size];
+        for copy in 0..self.num_fats {
+            let lba = self.fat_start + copy * self.fat_sectors + (offset / self.sector_size) as u64;
+            self.device.read_blocks(lba, &mut sector)?;
+            let entry = u32_at(&sector, within) & !ENTRY_MASK | value & ENTRY_MASK;
+            sector[within..within + 4].copy_from_slice(&entry.to_le_bytes());
+            self.device.write_blocks(lba, &sector)?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`, none for 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != FREE && cluster < END_OF_CHAIN {
+            // a chain longer than the volume loops
+            if !self.is_valid_cluster(cluster) || chain.len() as u32 >= self.cluster_count {
+                return Err(FsError::Corrupted);
+            }
+            chain.push(cluster);
+            cluster = self.read_fat(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    fn transfer(&self, cluster: u32, offset: usize, length: usize, mut copy: impl FnMut(u64, usize, usize, usize) -> Result<(), FsError>) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < length {
+            let position = offset + done;
+            let within = position % self.sector_size;
+            let count = (self.sector_size - within).min(length - done);
+            copy(self.cluster_sector(cluster) + (position / self.sector_size) as u64, within, done, count)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&self, cluster: u32, offset: usize, buffer: &mut [u8]) -> Result<(), FsError> {
+        let mut sector = vec![0u8; self.sector_size];
+        self.transfer(cluster, offset, buffer.len(), |lba, within, done, count| {
+            if count == self.sector_size {
+                return Ok(self.device.read_blocks(lba, &mut buffer[done..done + count])?);
+            }
+            self.device.read_blocks(lba, &mut sector)?;
+            buffer[done..done + count].copy_from_slice(&sector[within..within + count]);
+            Ok(())
+        })
+    }
+
+    fn write_bytes(&self, cluster: u32, offset: usize, buffer: &[u8]) -> Result<(), FsError> {
+        let mut sector = vec![0u8; self.sector_size];
+        self.transfer(cluster, offset, buffer.len(), |lba, within, done, count| {
+            if count == self.sector_size {
+                return Ok(self.device.write_blocks(lba, &buffer[done..done + count])?);
+            }
+            self.device.read_blocks(lba, &mut sector)?;
+            sector[within..within + count].copy_from_slice(&buffer[done..done + count]);
+            Ok(self.device.write_blocks(lba, &sector)?)
+        })
+    }
+
+    /// Takes a free cluster, zeroes it and links it after `previous`.
+    fn allocate(&self, state: &mut State, previous: Option<u32>) -> Result<u32, FsError> {
+        let start = if self.is_valid_cluster(state.next_free) { state.next_free } else { FIRST_CLUSTER };
+        let mut cluster = start;
+        loop {
+            if self.read_fat(cluster)? == FREE {
+                break;
+            }
+            cluster = if cluster + 1 < FIRST_CLUSTER + self.cluster_count { cluster + 1 } else { FIRST_CLUSTER };
+            if cluster == start {
+                return Err(FsError::NoSpace);
+            }
+        }
+        self.write_fat(cluster, ENTRY_MASK)?;
+        if let Some(previous) = previous {
+            self.write_fat(previous, cluster)?;
+        }
+        self.write_bytes(cluster, 0, &vec![0u8; self.cluster_size])?;
+        state.free_count = state.free_count.saturating_sub(1);
+        state.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.write_fat(cluster, FREE)?;
+            state.free_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Grows or shrinks the chain at `first` to `clusters`, returns its new first cluster.
+    fn resize_chain(&self, state: &mut State, first: u32, clusters: usize) -> Result<u32, FsError> {
+        let chain = self.chain(first)?;
+        if clusters < chain.len() {
+            if clusters == 0 {
+                self.free_chain(state, first)?;
+                return Ok(FREE);
+            }
+            let rest = chain[clusters];
+            self.write_fat(chain[clusters - 1], ENTRY_MASK)?;
+            self.free_chain(state, rest)?;
+            return Ok(first);
+        }
+        let mut grown = first;
+        let mut last = chain.last().copied();
+        for _ in chain.len()..clusters {
+            let cluster = match self.allocate(state, last) {
+                Ok(cluster) => cluster,
+                Err(error) => {
+                    // give back what was added so the chain still fits the file
+                    match chain.last() {
+                        Some(&end) if end != last.unwrap() => {
+                            let added = self.read_fat(end)?;
+                            self.write_fat(end, ENTRY_MASK)?;
+                            self.free_chain(state, added)?;
+                        }
+                        Some(_) => {}
+                        None if grown != FREE => self.free_chain(state, grown)?,
+                        None => {}
+                    }
+                    return Err(error);
+                }
+            };
+            if grown == FREE {
+                grown = cluster;
+            }
+            last = Some(cluster);
+        }
+        Ok(grown)
+    }
+
+    fn read_chain(&self, first: u32) -> Result<Vec<u8>, FsError> {
+        let chain = self.chain(first)?;
+        let mut data = vec![0u8; chain.len() * self.cluster_size];
+        for (cluster, chunk) in chain.iter().zip(data.chunks_exact_mut(self.cluster_size)) {
+            self.read_bytes(*cluster, 0, chunk)?;
+        }
+        Ok(data)
+    }
+
+    /* directory contents.
+        Long name entries come right before their 8.3 entry, last part first, and carry
+        a checksum of the 8.3 name; a run that does not match is ignored as left over
+        from some other system. Slot 0 marks the end of the directory.
+     */
+    fn slots(&self, directory: u32) -> Result<Vec<Slot>, FsError> {
+        let data = self.read_chain(directory)?;
+        let mut slots = Vec::new();
+        let mut long_name: Vec<[u16; LONG_NAME_CHARS]> = Vec::new();
+        let mut long_start = 0;
+        let mut long_checksum = 0;
+        for (index, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            match entry[0] {
+                0 => break,
+                DELETED => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            if entry[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                if entry[0] & LAST_LONG_ENTRY != 0 {
+                    long_name.clear();
+                    long_start = index;
+                    long_checksum = entry[13];
+                }
+                long_name.push(long_name_units(entry));
+                continue;
+            }
+            let raw: [u8; DIR_ENTRY_SIZE] = entry.try_into().unwrap();
+            let has_long_name = !long_name.is_empty() && long_checksum == short_name_checksum(&raw);
+            let parts = core::mem::take(&mut long_name);
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let name = if has_long_name {
+                let units = parts.iter().rev().flatten().copied().take_while(|&unit| unit != 0);
+                char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+            } else {
+                display_short_name(&raw)
+            };
+            let first_slot = if has_long_name { long_start } else { index } as u32;
+            slots.push(Slot { first_slot, slot: index as u32, raw, name });
+        }
+        Ok(slots)
+    }
+
+    fn find(&self, directory: u32, name: &str) -> Result<Option<Slot>, FsError> {
+        Ok(self.slots(directory)?.into_iter().find(|slot| !slot.is_dot() && slot.name.eq_ignore_ascii_case(name)))
+    }
+
+    fn write_slot(&self, directory: u32, slot: u32, raw: &[u8]) -> Result<(), FsError> {
+        let offset = slot as usize * DIR_ENTRY_SIZE;
+        let chain = self.chain(directory)?;
+        let cluster = *chain.get(offset / self.cluster_size).ok_or(FsError::Corrupted)?;
+        self.write_bytes(cluster, offset % self.cluster_size, raw)
+    }
+
+    /// Adds an entry called `name` based on `raw`, whose name bytes are replaced.
+    fn add_entry(&self, state: &mut State, directory: u32, name: &str, mut raw: [u8; DIR_ENTRY_SIZE]) -> Result<Location, FsError> {
+        let existing = self.slots(directory)?;
+        let long_entries = match exact_short_name(name) {
+            Some((short, flags)) => {
+                raw[..11].copy_from_slice(&short);
+                raw[12] = flags;
+                Vec::new()
+            }
+            None => {
+                raw[..11].copy_from_slice(&generate_short_name(name, &existing)?);
+                raw[12] = 0;
+                long_name_entries(name, short_name_checksum(&raw))
+            }
+        };
+        let needed = long_entries.len() + 1;
+
+        // the first run of free slots long enough, growing the directory if there is none
+        let first = loop {
+            let data = self.read_chain(directory)?;
+            let mut run = 0;
+            let mut found = None;
+            for (index, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                let end = entry[0] == 0;
+                run = if end || entry[0] == DELETED { run + 1 } else { 0 };
+                if run == needed {
+                    found = Some(index + 1 - needed);
+                    break;
+                }
+                if end {
+                    // everything after the end marker is free
+                    let left = data.len() / DIR_ENTRY_SIZE - index;
+                    if run - 1 + left >= needed {
+                        found = Some(index + 1 - run);
+                    }
+                    break;
+                }
+            }
+            if let Some(found) = found {
+                break found as u32;
+            }
+            let last = self.chain(directory)?.last().copied();
+            self.allocate(state, last)?;
+        };
+        for (index, entry) in long_entries.iter().chain(core::iter::once(&raw)).enumerate() {
+            self.write_slot(directory, first + index as u32, entry)?;
+        }
+        Ok(Location { directory, slot: first + long_entries.len() as u32 })
+    }
+
+    fn remove_entry(&self, directory: u32, slot: &Slot) -> Result<(), FsError> {
+        for index in slot.first_slot..=slot.slot {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read_bytes_at_slot(directory, index, &mut raw)?;
+            raw[0] = DELETED;
+            self.write_slot(directory, index, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn read_bytes_at_slot(&self, directory: u32, slot: u32, raw: &mut [u8]) -> Result<(), FsError> {
+        let offset = slot as usize * DIR_ENTRY_SIZE;
+        let chain = self.chain(directory)?;
+        let cluster = *chain.get(offset / self.cluster_size).ok_or(FsError::Corrupted)?;
+        self.read_bytes(cluster, offset % self.cluster_size, raw)
+    }
+
+    fn is_empty_directory(&self, directory: u32) -> Result<bool, FsError> {
+        Ok(self.slots(directory)?.iter().all(Slot::is_dot))
+    }
+
+    /// What ".." entries store for `directory`, the root is written as 0.
+    fn parent_reference(&self, directory: u32) -> u32 {
+        if directory == self.root_cluster { 0 } else { directory }
+    }
+
+    fn write_fs_info(&self, state: &State) -> Result<(), FsError> {
+        let Some(lba) = self.fs_info else {
+            return Ok(());
+        };
+        let mut sector = vec![0u8; self.sector_size];
+        self.device.read_blocks(lba, &mut sector)?;
+        sector[488..492].copy_from_slice(&state.free_count.to_le_bytes());
+        sector[492..496].copy_from_slice(&state.next_free.to_le_bytes());
+        Ok(self.device.write_blocks(lba, &sector)?)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock()
+    }
+
+    /// The shared inode of the entry at `location`.
+    fn inode(self: &Arc<Self>, state: &mut State, location: Location, slot: &Slot) -> Arc<FatInode> {
+        if let Some(inode) = state.inodes.get(&location).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = Arc::new(FatInode {
+            volume: self.clone(),
+            number: (location.directory as u64) << 32 | location.slot as u64,
+            directory: slot.is_directory(),
+            read_only: slot.attributes() & ATTR_READ_ONLY != 0,
+            node: SpinLock::new(Node { first_cluster: slot.first_cluster(), size: slot.size(), location: Some(location), orphan: false }),
+        });
+        state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        state.inodes.insert(location, Arc::downgrade(&inode));
+        inode
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    first_cluster: u32,
+    size: u32,
+    // None for the root directory, which has no entry
+    location: Option<Location>,
+    // unlinked while still open, the clusters go when the last user does
+    orphan: bool,
+}
+
+pub struct FatInode {
+    volume: Arc<Volume>,
+    number: u64,
+    directory: bool,
+    read_only: bool,
+    // only changed with the volume lock held
+    node: SpinLock<Node>,
+}
+
+impl FatInode {
+    fn node(&self) -> Node {
+        *self.node.lock()
+    }
+
+    /// Stores first cluster and size in the inode and in its directory entry.
+    fn update(&self, first_cluster: u32, size: u32) -> Result<(), FsError> {
+        let location = {
+            let mut node = self.node.lock();
+            node.first_cluster = first_cluster;
+            node.size = size;
+            node.location
+        };
+        let Some(location) = location.filter(|_| !self.node().orphan) else {
+            return Ok(());
+        };
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.volume.read_bytes_at_slot(location.directory, location.slot, &mut raw)?;
+        set_first_cluster(&mut raw, first_cluster);
+        if !self.directory {
+            raw[28..32].copy_from_slice(&size.to_le_bytes());
+        }
+        self.volume.write_slot(location.directory, location.slot, &raw)
+    }
+
+    fn directory_cluster(&self) -> Result<u32, FsError> {
+        if !self.directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(self.node().first_cluster)
+    }
+
+    /// Takes entry `slot` out of this directory, the clusters go now or with its last user.
+    fn drop_entry(&self, state: &mut State, slot: &Slot) -> Result<Option<Arc<FatInode>>, FsError> {
+        let directory = self.node().first_cluster;
+        self.volume.remove_entry(directory, slot)?;
+        let location = Location { directory, slot: slot.slot };
+        match state.inodes.remove(&location).and_then(|inode| inode.upgrade()) {
+            Some(inode) => {
+                inode.node.lock().orphan = true;
+                // dropped by the caller once the volume lock is released
+                Ok(Some(inode))
+            }
+            None => {
+                self.volume.free_chain(state, slot.first_cluster())?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let node = self.node();
+        if node.orphan {
+            let mut state = self.volume.lock();
+            // nobody is left to hear about a failure
+            let _ = self.volume.free_chain(&mut state, node.first_cluster);
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let node = self.node();
+        let (file_type, size, mode) = if self.directory {
+            let clusters = self.volume.chain(node.first_cluster).map_or(0, |chain| chain.len());
+            (FileType::Directory, (clusters * self.volume.cluster_size) as u64, 0o755)
+        } else {
+            (FileType::Regular, node.size as u64, if self.read_only { 0o444 } else { 0o644 })
+        };
+        Metadata { inode: self.number, file_type, size, links: 1, mode }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if self.directory {
+            return Err(FsError::IsDirectory);
+        }
+        let _state = self.volume.lock();
+        let node = self.node();
+        let Some(rest) = (node.size as u64).checked_sub(offset) else {
+            return Ok(0);
+        };
+        let length = buffer.len().min(rest as usize);
+        let cluster_size = self.volume.cluster_size;
+        let chain = self.volume.chain(node.first_cluster)?;
+        let mut done = 0;
+        while done < length {
+            let position = offset as usize + done;
+            let cluster = *chain.get(position / cluster_size).ok_or(FsError::Corrupted)?;
+            let within = position % cluster_size;
+            let count = (cluster_size - within).min(length - done);
+            self.volume.read_bytes(cluster, within, &mut buffer[done..done + count])?;
+            done += count;
+        }
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        if self.directory {
+            return Err(FsError::IsDirectory);
+        }
+        let end = offset.checked_add(buffer.len() as u64).filter(|&end| end <= u32::MAX as u64).ok_or(FsError::NoSpace)?;
+        let mut state = self.volume.lock();
+        let node = self.node();
+        let cluster_size = self.volume.cluster_size;
+        let size = (node.size as u64).max(end);
+        let first = self.volume.resize_chain(&mut state, node.first_cluster, (size as usize).div_ceil(cluster_size))?;
+        let chain = self.volume.chain(first)?;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset as usize + done;
+            let within = position % cluster_size;
+            let count = (cluster_size - within).min(buffer.len() - done);
+            self.volume.write_bytes(chain[position / cluster_size], within, &buffer[done..done + count])?;
+            done += count;
+        }
+        // new clusters come zeroed, a gap between the old end and `offset` reads as zeroes
+        self.update(first, size as u32)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if self.directory {
+            return Err(FsError::IsDirectory);
+        }
+        let size = u32::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let mut state = self.volume.lock();
+        let node = self.node();
+        let cluster_size = self.volume.cluster_size;
+        let first = self.volume.resize_chain(&mut state, node.first_cluster, (size as usize).div_ceil(cluster_size))?;
+        // what lies past a shrinking end in the last cluster must read as zeroes if it grows again
+        let within = size as usize & (cluster_size - 1);
+        if size < node.size && within != 0 {
+            let last = *self.volume.chain(first)?.last().ok_or(FsError::Corrupted)?;
+            self.volume.write_bytes(last, within, &vec![0u8; cluster_size - within])?;
+        }
+        self.update(first, size)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let directory = self.directory_cluster()?;
+        let mut state = self.volume.lock();
+        let slot = self.volume.find(directory, name)?.ok_or(FsError::NotFound)?;
+        let location = Location { directory, slot: slot.slot };
+        Ok(self.volume.inode(&mut state, location, &slot))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let directory = self.directory_cluster()?;
+        let _state = self.volume.lock();
+        Ok(self.volume.slots(directory)?.into_iter()
+            .filter(|slot| !slot.is_dot())
+            .map(|slot| DirEntry {
+                inode: (directory as u64) << 32 | slot.slot as u64,
+                file_type: if slot.is_directory() { FileType::Directory } else { FileType::Regular },
+                name: slot.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let directory = self.directory_cluster()?;
+        path::check_name(name)?;
+        let mut state = self.volume.lock();
+        if self.volume.find(directory, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        match file_type {
+            FileType::Regular => raw[11] = ATTR_ARCHIVE,
+            FileType::Directory => {
+                raw[11] = ATTR_DIRECTORY;
+                let cluster = self.volume.allocate(&mut state, None)?;
+                set_first_cluster(&mut raw, cluster);
+                let mut dot = [0u8; DIR_ENTRY_SIZE];
+                dot[..11].copy_from_slice(b".          ");
+                dot[11] = ATTR_DIRECTORY;
+                set_first_cluster(&mut dot, cluster);
+                let mut dot_dot = dot;
+                dot_dot[..11].copy_from_slice(b"..         ");
+                set_first_cluster(&mut dot_dot, self.volume.parent_reference(directory));
+                self.volume.write_slot(cluster, 0, &dot)?;
+                self.volume.write_slot(cluster, 1, &dot_dot)?;
+            }
+            _ => return Err(FsError::Unsupported),
+        }
+        let location = match self.volume.add_entry(&mut state, directory, name, raw) {
+            Ok(location) => location,
+            Err(error) => {
+                self.volume.free_chain(&mut state, first_cluster(&raw))?;
+                return Err(error);
+            }
+        };
+        let slot = Slot { first_slot: location.slot, slot: location.slot, raw, name: String::from(name) };
+        Ok(self.volume.inode(&mut state, location, &slot))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let directory = self.directory_cluster()?;
+        let orphan;
+        {
+            let mut state = self.volume.lock();
+            let slot = self.volume.find(directory, name)?.ok_or(FsError::NotFound)?;
+            if slot.is_directory() && !self.volume.is_empty_directory(slot.first_cluster())? {
+                return Err(FsError::NotEmpty);
+            }
+            orphan = self.drop_entry(&mut state, &slot)?;
+        }
+        drop(orphan);
+        Ok(())
+    }
+
+    /* renaming.
+        The 8.3 entry moves as it is, only the name changes, so the file keeps its
+        clusters. A directory moving to another parent gets its ".." entry updated.
+     */
+    fn rename(&self, name: &str, target: &dyn Inode, new_name: &str) -> Result<(), FsError> {
+        let target = target.as_any().downcast_ref::<FatInode>().ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &target.volume) {
+            return Err(FsError::CrossDevice);
+        }
+        path::check_name(new_name)?;
+        let (from, to) = (self.directory_cluster()?, target.directory_cluster()?);
+        let replaced;
+        {
+            let mut state = self.volume.lock();
+            let moved = self.volume.find(from, name)?.ok_or(FsError::NotFound)?;
+            replaced = match self.volume.find(to, new_name)? {
+                Some(existing) if from == to && existing.slot == moved.slot => {
+                    // only the case of the name changes, or nothing at all
+                    if existing.name == new_name {
+                        return Ok(());
+                    }
+                    None
+                }
+                Some(existing) => {
+                    match (moved.is_directory(), existing.is_directory()) {
+                        (true, false) => return Err(FsError::NotDirectory),
+                        (false, true) => return Err(FsError::IsDirectory),
+                        (true, true) if !self.volume.is_empty_directory(existing.first_cluster())? => return Err(FsError::NotEmpty),
+                        _ => {}
+                    }
+                    target.drop_entry(&mut state, &existing)?
+                }
+                None => None,
+            };
+
+            // added first, a failure in between leaves the file under both names, not neither
+            let location = self.volume.add_entry(&mut state, to, new_name, moved.raw)?;
+            self.volume.remove_entry(from, &moved)?;
+            if let Some(inode) = state.inodes.remove(&Location { directory: from, slot: moved.slot }) {
+                if let Some(live) = inode.upgrade() {
+                    live.node.lock().location = Some(location);
+                }
+                state.inodes.insert(location, inode);
+            }
+            if moved.is_directory() && from != to {
+                let mut dot_dot = [0u8; DIR_ENTRY_SIZE];
+                self.volume.read_bytes_at_slot(moved.first_cluster(), 1, &mut dot_dot)?;
+                set_first_cluster(&mut dot_dot, self.volume.parent_reference(to));
+                self.volume.write_slot(moved.first_cluster(), 1, &dot_dot)?;
+            }
+        }
+        drop(replaced);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Fat32 {
+    volume: Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+impl Fat32 {
+    /// Reads the boot sector of `device` and mounts the volume behind a block cache.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let sector_size = device.block_size();
+        let mut boot = vec![0u8; sector_size];
+        device.read_blocks(0, &mut boot)?;
+        let bytes_per_sector = u16_at(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let num_fats = boot[16] as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            small => small as u64,
+        };
+        let fat_sectors = u32_at(&boot, 36) as u64;
+        let root_cluster = u32_at(&boot, 44);
+        if boot[510..512] != [0x55, 0xaa] || u16_at(&boot, 22) != 0 || u16_at(&boot, 17) != 0 {
+            return Err(FsError::Corrupted);
+        }
+        if bytes_per_sector != sector_size {
+            return Err(FsError::Unsupported);
+        }
+        if !sectors_per_cluster.is_power_of_two() || num_fats == 0 || fat_sectors == 0 || total > device.num_blocks() {
+            return Err(FsError::Corrupted);
+        }
+        let data_start = reserved + num_fats * fat_sectors;
+        let cluster_count = total.checked_sub(data_start).ok_or(FsError::Corrupted)? / sectors_per_cluster;
+        // the FAT has to have room for every cluster
+        let cluster_count = cluster_count.min(fat_sectors * sector_size as u64 / 4 - FIRST_CLUSTER as u64) as u32;
+
+        let fs_info = match u16_at(&boot, 48) as u64 {
+            0 | 0xffff => None,
+            sector => Some(sector),
+        };
+        let mut state = State { free_count: FS_INFO_UNKNOWN, next_free: FIRST_CLUSTER, inodes: BTreeMap::new() };
+        let device = BlockCache::new(device, CACHE_BLOCKS);
+        if let Some(lba) = fs_info {
+            let mut sector = vec![0u8; sector_size];
+            device.read_blocks(lba, &mut sector)?;
+            if u32_at(&sector, 0) == FS_INFO_LEAD && u32_at(&sector, 484) == FS_INFO_STRUCT {
+                state.free_count = u32_at(&sector, 488);
+                state.next_free = u32_at(&sector, 492);
+            }
+        }
+        let volume = Arc::new(Volume {
+            device,
+            sector_size,
+            sectors_per_cluster,
+            cluster_size: sector_size * sectors_per_cluster as usize,
+            fat_start: reserved,
+            fat_sectors,
+            num_fats,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fs_info,
+            state: Mutex::new(state),
+        });
+        if !volume.is_valid_cluster(root_cluster) {
+            return Err(FsError::Corrupted);
+        }
+        {
+            // an unknown free count is counted once, it is kept up to date from then on
+            let mut state = volume.lock();
+            if state.free_count == FS_INFO_UNKNOWN || state.free_count > cluster_count {
+                let mut free = 0;
+                for cluster in FIRST_CLUSTER..FIRST_CLUSTER + cluster_count {
+                    free += u32::from(volume.read_fat(cluster)? == FREE);
+                }
+                state.free_count = free;
+            }
+        }
+        let root = Arc::new(FatInode {
+            volume: volume.clone(),
+            number: 1,
+            directory: true,
+            read_only: false,
+            node: SpinLock::new(Node { first_cluster: root_cluster, size: 0, location: None, orphan: false }),
+        });
+        Ok(Arc::new(Fat32 { volume, root }))
+    }
+
+    /// Clusters not in use by any file.
+    pub fn free_clusters(&self) -> u32 {
+        self.volume.lock().free_count
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.volume.cluster_size
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let state = self.volume.lock();
+        self.volume.write_fs_info(&state)?;
+        Ok(self.volume.device.sync()?)
+    }
+}
+
+impl Drop for Fat32 {
+    fn drop(&mut self) {
+        // nobody is left to hear about a failure
+        let _ = self.sync();
+    }
+}
+
+/// Lays out an empty FAT32 volume on `device`, as mkfs.fat would.
+#[cfg(test)]
+pub(crate) fn format(device: &dyn BlockDevice, sectors_per_cluster: u8) -> Result<(), FsError> {
+    const RESERVED: u64 = 32;
+    const FATS: u64 = 2;
+    let sector_size = device.block_size();
+    let total = device.num_blocks();
+    let clusters = (total - RESERVED) / sectors_per_cluster as u64;
+    let fat_sectors = ((clusters + 2) * 4).div_ceil(sector_size as u64);
+    let data_start = RESERVED + FATS * fat_sectors;
+    let clusters = ((total - data_start) / sectors_per_cluster as u64) as u32;
+
+    let zero = vec![0u8; sector_size];
+    for lba in 0..data_start + sectors_per_cluster as u64 {
+        device.write_blocks(lba, &zero)?;
+    }
+    let mut boot = vec![0u8; sector_size];
+    boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    boot[11..13].copy_from_slice(&(sector_size as u16).to_le_bytes());
+    boot[13] = sectors_per_cluster;
+    boot[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+    boot[16] = FATS as u8;
+    boot[21] = 0xf8;
+    boot[32..36].copy_from_slice(&(total as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+    boot[44..48].copy_from_slice(&FIRST_CLUSTER.to_le_bytes());
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+    boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+    boot[66] = 0x29;
+    boot[71..82].copy_from_slice(b"NO NAME    ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+    device.write_blocks(0, &boot)?;
+
+    let mut info = vec![0u8; sector_size];
+    info[..4].copy_from_slice(&FS_INFO_LEAD.to_le_bytes());
+    info[484..488].copy_from_slice(&FS_INFO_STRUCT.to_le_bytes());
+    info[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+    info[492..496].copy_from_slice(&3u32.to_le_bytes());
+    info[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+    device.write_blocks(1, &info)?;
+
+    let mut fat = vec![0u8; sector_size];
+    fat[..4].copy_from_slice(&0x0fff_fff8u32.to_le_bytes());
+    fat[4..8].copy_from_slice(&ENTRY_MASK.to_le_bytes());
+    // the root directory, one cluster
+    fat[8..12].copy_from_slice(&ENTRY_MASK.to_le_bytes());
+    for copy in 0..FATS {
+        device.write_blocks(RESERVED + copy * fat_sectors, &fat)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{BlockError, RamDisk};
+    use crate::fs::{File, OpenFlags};
+    use alloc::collections::BTreeSet;
+
+    fn volume(blocks: u64, sectors_per_cluster: u8) -> (Arc<RamDisk>, Arc<Fat32>) {
+        let disk = Arc::new(RamDisk::new(512, blocks));
+        format(&*disk, sectors_per_cluster).expect("format failed");
+        let fs = Fat32::mount(disk.clone()).expect("mount failed");
+        (disk, fs)
+    }
+
+    /* fsck.
+        Every cluster belongs to at most one chain, every chain fits the size of its
+        file, nothing is allocated that no file uses, and the free count is right.
+     */
+    fn check(fs: &Fat32) {
+        let volume = &fs.volume;
+        let mut used = BTreeSet::new();
+        let mut directories = vec![volume.root_cluster];
+        while let Some(directory) = directories.pop() {
+            for cluster in volume.chain(directory).unwrap() {
+                assert!(used.insert(cluster), "cluster {} cross-linked", cluster);
+            }
+            for slot in volume.slots(directory).unwrap().into_iter().filter(|slot| !slot.is_dot()) {
+                if slot.is_directory() {
+                    directories.push(slot.first_cluster());
+                    continue;
+                }
+                let chain = volume.chain(slot.first_cluster()).unwrap();
+                assert_eq!(chain.len(), (slot.size() as usize).div_ceil(volume.cluster_size), "{} has a bad chain", slot.name);
+                for cluster in chain {
+                    assert!(used.insert(cluster), "cluster {} cross-linked", cluster);
+                }
+            }
+        }
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + volume.cluster_count {
+            let allocated = volume.read_fat(cluster).unwrap() != FREE;
+            assert_eq!(allocated, used.contains(&cluster), "cluster {} lost", cluster);
+            free += u32::from(!allocated);
+        }
+        assert_eq!(fs.free_clusters(), free);
+    }
+
+    #[test_case]
+    fn test_names() {
+        assert_eq!(exact_short_name("README.TXT").map(|(short, _)| short), Some(*b"README  TXT"));
+        assert_eq!(exact_short_name("readme.txt").map(|(_, flags)| flags), Some(LOWER_BASE | LOWER_EXTENSION));
+        assert!(exact_short_name("ReadMe.txt").is_none());
+        assert!(exact_short_name("a long name.text").is_none());
+        assert_eq!(&generate_short_name("a long name.text", &[]).unwrap(), b"ALONGN~1TEX");
+        assert_eq!(display_short_name(b"README  TXT\x00\x08"), "readme.TXT");
+        let entries = long_name_entries("a long file name.txt", 0x12);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0][0], 2 | LAST_LONG_ENTRY);
+    }
+
+    #[test_case]
+    fn test_write_read_and_check() {
+        let (disk, fs) = volume(1024, 1);
+        let root = fs.root();
+        let docs = root.create("Documents", FileType::Directory).unwrap();
+        let file = docs.create("a rather long file name.txt", FileType::Regular).unwrap();
+        root.create("SHORT.TXT", FileType::Regular).unwrap();
+        assert_eq!(root.create("short.txt", FileType::Regular).err(), Some(FsError::Exists));
+
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        assert_eq!(file.write_at(0, &data), Ok(3000));
+        assert_eq!(file.write_at(5000, b"tail"), Ok(4));
+        assert_eq!(file.metadata().size, 5004);
+        let mut buffer = vec![0u8; 5100];
+        assert_eq!(file.read_at(0, &mut buffer), Ok(5004));
+        assert_eq!(&buffer[..3000], &data[..]);
+        assert!(buffer[3000..5000].iter().all(|&byte| byte == 0));
+        check(&fs);
+
+        file.truncate(700).unwrap();
+        file.truncate(1500).unwrap();
+        assert_eq!(file.read_at(600, &mut buffer[..200]), Ok(200));
+        assert_eq!(&buffer[..100], &data[600..700]);
+        assert!(buffer[100..200].iter().all(|&byte| byte == 0));
+        check(&fs);
+
+        // enough entries to spill the directory into a second cluster
+        for index in 0..20 {
+            docs.create(&alloc::format!("file number {}", index), FileType::Regular).unwrap();
+        }
+        for index in (0..20).step_by(2) {
+            docs.unlink(&alloc::format!("file number {}", index)).unwrap();
+        }
+        let names: Vec<String> = docs.read_dir().unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names.len(), 11);
+        assert!(names.iter().any(|name| name == "a rather long file name.txt"));
+        check(&fs);
+
+        root.create("Archive", FileType::Directory).unwrap();
+        let archive = root.lookup("archive").unwrap();
+        docs.rename("a rather long file name.txt", &*archive, "kept.txt").unwrap();
+        root.rename("Documents", &*archive, "old documents").unwrap();
+        assert_eq!(root.unlink("Archive"), Err(FsError::NotEmpty));
+        assert_eq!(file.metadata().size, 1500);
+        check(&fs);
+
+        fs.sync().unwrap();
+        drop((file, docs, archive, root, fs));
+        let fs = Fat32::mount(disk).expect("remount failed");
+        check(&fs);
+        let archive = fs.root().lookup("Archive").unwrap();
+        let moved = archive.lookup("old documents").unwrap();
+        assert_eq!(moved.read_dir().unwrap().len(), 10);
+        assert_eq!(archive.lookup("KEPT.TXT").unwrap().metadata().size, 1500);
+    }
+
+    // the fw_cfg file runner.sh hands the host-made volume over in
+    const HOST_IMAGE: &str = "opt/blog_os/fat32.img";
+
+    #[test_case]
+    fn test_host_made_image() {
+        // made by mkfs.fat and mtools when the tests are started, see testdata/fat32.sh and runner.sh
+        let image = crate::fw_cfg::file(HOST_IMAGE)
+            .expect("no FAT32 image from the host, runner.sh makes it with dosfstools and mtools");
+        let disk = Arc::new(RamDisk::from_bytes(512, image));
+        let fs = Fat32::mount(disk.clone()).expect("mount failed");
+        check(&fs);
+        let root = fs.root();
+        let mut names: Vec<String> = root.read_dir().unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        assert_eq!(names, ["A long file name.txt", "HELLO.TXT", "docs"]);
+
+        let mut buffer = vec![0u8; 2048];
+        let hello = root.lookup("hello.txt").unwrap();
+        assert_eq!(hello.read_at(0, &mut buffer), Ok(20));
+        assert_eq!(&buffer[..20], b"Hello from the host\n");
+        assert_eq!(root.lookup("a long file name.TXT").unwrap().metadata().size, 51);
+        let expected: String = (1..=400).map(|line| alloc::format!("{}\n", line)).collect();
+        let readme = root.lookup("docs").unwrap().lookup("readme.md").unwrap();
+        assert_eq!(readme.read_at(0, &mut buffer), Ok(expected.len()));
+        assert_eq!(&buffer[..expected.len()], expected.as_bytes());
+
+        // what the kernel writes next to it still adds up
+        let docs = root.lookup("docs").unwrap();
+        docs.create("written by the kernel", FileType::Regular).unwrap().write_at(0, &buffer[..1000]).unwrap();
+        root.rename("HELLO.TXT", &*docs, "hello again.txt").unwrap();
+        check(&fs);
+        fs.sync().unwrap();
+        drop((hello, readme, docs, root, fs));
+        let fs = Fat32::mount(disk).expect("remount failed");
+        check(&fs);
+        assert_eq!(fs.root().lookup("docs").unwrap().read_dir().unwrap().len(), 3);
+    }
+
+    #[test_case]
+    fn test_unlink_open_file_and_run_out_of_space() {
+        // the file system stays mounted
+        crate::testing::keeps_heap();
+        let (_disk, fs) = volume(256, 4);
+        let free = fs.free_clusters();
+        crate::fs::mkdir("/fat32-test").unwrap();
+        crate::fs::mount("/fat32-test", fs.clone()).unwrap();
+        let victim = crate::fs::open("/fat32-test/victim", OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        victim.write(&[1u8; 4096]).unwrap();
+        crate::fs::unlink("/fat32-test/victim").unwrap();
+        // still readable, the clusters stay until the last user goes
+        victim.seek(crate::fs::SeekFrom::Start(0)).unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(victim.read(&mut buffer), Ok(16));
+        assert_eq!(fs.free_clusters(), free - 2);
+        drop(victim);
+        assert_eq!(fs.free_clusters(), free);
+        check(&fs);
+
+        let big = fs.root().create("big", FileType::Regular).unwrap();
+        let chunk = vec![7u8; fs.cluster_size()];
+        let mut offset = 0;
+        let error = loop {
+            match big.write_at(offset, &chunk) {
+                Ok(written) => offset += written as u64,
+                Err(error) => break error,
+            }
+        };
+        assert_eq!(error, FsError::NoSpace);
+        assert_eq!(fs.free_clusters(), 0);
+        big.truncate(0).unwrap();
+        assert_eq!(fs.free_clusters(), free);
+        check(&fs);
+    }
+
+    #[test_case]
+    fn test_failing_disk() {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let (_disk, fs) = volume(256, 1);
+        let root = fs.root();
+        let data = vec![3u8; 1500];
+        let mut errors = 0;
+        {
+            let _armed = crate::fault_inject::BLOCK.set_for_current_thread(3);
+            for index in 0..8 {
+                let result = root
+                    .create(&alloc::format!("file {}", index), FileType::Regular)
+                    .and_then(|file| file.write_at(0, &data));
+                match result {
+                    Ok(_) => {}
+                    Err(FsError::Io(BlockError::Io)) => errors += 1,
+                    Err(other) => panic!("{:?} instead of an I/O error", other),
+                }
+            }
+        }
+        // the failures got through as errors, and the volume is still usable
+        assert!(errors > 0);
+        assert!(root.read_dir().is_ok());
+        let file = root.create("after", FileType::Regular).unwrap();
+        assert_eq!(file.write_at(0, &data), Ok(1500));
+    }
+}
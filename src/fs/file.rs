@@ -0,0 +1,143 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::any::Any;
+
+use super::mount::Dentry;
+use super::{DirEntry, FileType, FsError, Metadata};
+use crate::sync::Mutex;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Create the file if it does not exist.
+        const CREATE = 1 << 2;
+        /// With CREATE, fail if it does exist.
+        const EXCLUSIVE = 1 << 3;
+        /// Cut a regular file opened for writing to zero length.
+        const TRUNCATE = 1 << 4;
+        /// Every write goes to the end of the file.
+        const APPEND = 1 << 5;
+        /// Fail unless it is a directory.
+        const DIRECTORY = 1 << 6;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Something a file descriptor can refer to.
+pub trait File: Send + Sync {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError>;
+
+    /// Moves the position, returns the new one.
+    fn seek(&self, _position: SeekFrom) -> Result<u64, FsError> {
+        Err(FsError::NotSeekable)
+    }
+
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// For calls that only work on one kind of file, sockets for example.
+    fn as_any(&self) -> &dyn Any;
+}
+
+/* an open file.
+    The position is shared by everyone holding the same open file, as after dup or
+    fork. Its lock is held for the whole request, so two writers appending at the
+    same time never land on the same offset.
+ */
+pub struct OpenFile {
+    dentry: Dentry,
+    flags: OpenFlags,
+    position: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub fn new(dentry: Dentry, flags: OpenFlags) -> Result<Arc<Self>, FsError> {
+        let file_type = dentry.inode().metadata().file_type;
+        match file_type {
+            FileType::Directory if flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE) => return Err(FsError::IsDirectory),
+            FileType::Directory => {}
+            _ if flags.contains(OpenFlags::DIRECTORY) => return Err(FsError::NotDirectory),
+            FileType::Regular if flags.contains(OpenFlags::WRITE | OpenFlags::TRUNCATE) => dentry.inode().truncate(0)?,
+            _ => {}
+        }
+        Ok(Arc::new(OpenFile { dentry, flags, position: Mutex::new(0) }))
+    }
+
+    pub fn dentry(&self) -> &Dentry {
+        &self.dentry
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn position(&self) -> u64 {
+        *self.position.lock()
+    }
+}
+
+impl File for OpenFile {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadMode);
+        }
+        let mut position = self.position.lock();
+        let read = self.dentry.inode().read_at(*position, buffer)?;
+        *position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadMode);
+        }
+        let mut position = self.position.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *position = self.dentry.inode().metadata().size;
+        }
+        let written = self.dentry.inode().write_at(*position, buffer)?;
+        *position += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, to: SeekFrom) -> Result<u64, FsError> {
+        let metadata = self.dentry.inode().metadata();
+        if metadata.file_type == FileType::CharDevice {
+            return Err(FsError::NotSeekable);
+        }
+        let mut position = self.position.lock();
+        let new = match to {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => position.checked_add_signed(delta),
+            SeekFrom::End(delta) => metadata.size.checked_add_signed(delta),
+        };
+        *position = new.ok_or(FsError::InvalidArgument)?;
+        Ok(*position)
+    }
+
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(self.dentry.inode().metadata())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.dentry.inode().read_dir()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,72 @@
+use super::tar::{self, EntryKind};
+use super::{create_dir_all, path, write_file, FsError};
+use crate::fw_cfg;
+
+/* the initial ramdisk.
+    A ustar archive handed to QEMU as the fw_cfg file opt/blog_os/initrd. runner.sh
+    packs the initrd/ directory of the crate into target/initrd.tar and passes it
+    along when there is one. Its contents are copied into the root ramfs at boot, so
+    programs and configuration files ship with the kernel. Links and device nodes in
+    the archive are skipped.
+ */
+
+const FILE_NAME: &str = "opt/blog_os/initrd";
+
+pub fn init() {
+    let Some(archive) = fw_cfg::file(FILE_NAME) else {
+        return;
+    };
+    match unpack(&archive, "/") {
+        Ok(count) => log::info!("{} entries unpacked", count),
+        Err(error) => log::error!("unpacking failed: {:?}", error),
+    }
+}
+
+/// Copies the files and directories of `archive` below `root`, returns how many.
+pub fn unpack(archive: &[u8], root: &str) -> Result<usize, FsError> {
+    let root = path::normalize(root)?;
+    let mut unpacked = 0;
+    for entry in tar::entries(archive) {
+        let entry = entry?;
+        let name = entry.path()?;
+        if name.is_empty() {
+            continue;
+        }
+        let target = path::normalize(&alloc::format!("{}/{}", root, name))?;
+        match entry.kind {
+            EntryKind::Directory => create_dir_all(&target)?,
+            EntryKind::File => {
+                if let Some((parent, _)) = path::split_last(&target) {
+                    create_dir_all(parent)?;
+                }
+                write_file(&target, entry.data)?;
+            }
+            EntryKind::Symlink | EntryKind::Other(_) => continue,
+        }
+        unpacked += 1;
+    }
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::{self, tar::test::append};
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_unpack() {
+        // the unpacked files stay in the root file system
+        crate::testing::keeps_heap();
+        let mut archive = Vec::new();
+        append(&mut archive, "bin/", b'5', b"");
+        append(&mut archive, "bin/init", b'0', b"\x7fELF");
+        append(&mut archive, "etc/conf/rc", b'0', b"start");
+        append(&mut archive, "etc/link", b'2', b"");
+        assert_eq!(unpack(&archive, "/initrd-test"), Ok(3));
+
+        assert_eq!(fs::read_file("/initrd-test/bin/init").as_deref(), Ok(&b"\x7fELF"[..]));
+        assert_eq!(fs::read_file("/initrd-test/etc/conf/rc").as_deref(), Ok(&b"start"[..]));
+        assert_eq!(fs::stat("/initrd-test/etc/link").err(), Some(FsError::NotFound));
+    }
+}
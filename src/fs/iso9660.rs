@@ -0,0 +1,552 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{BlockCache, BlockDevice};
+
+/* ISO9660, read only.
+    Volume descriptors start 32K into the disc, the primary one holds the record of
+    the root directory. A directory is a run of records, each naming one extent, and
+    records never cross a logical block. Plain ISO9660 names are upper case 8.3 with
+    a version suffix; Rock Ridge puts the real name, mode and symlink target in the
+    System Use area after each record, in entries that may continue elsewhere.
+ */
+
+const SECTOR_SIZE: u64 = 2048;
+const DESCRIPTORS_START: u64 = 16;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+// more descriptors than any disc has, a bad image must not send us reading forever
+const MAX_DESCRIPTORS: u64 = 64;
+const CACHE_BLOCKS: usize = 64;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const RECORD_HEADER: usize = 33;
+// continuation areas followed for one record
+const MAX_CONTINUATIONS: usize = 16;
+
+const MODE_TYPE: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// What Rock Ridge says about a record.
+#[derive(Debug, Default, Clone)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    links: Option<u32>,
+    symlink: Option<String>,
+    // a directory moved elsewhere to get around the depth limit, listed through its child link
+    relocated: bool,
+    child_link: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    extent: u32,
+    size: u32,
+    flags: u8,
+    // where the record itself is, in bytes, which makes it unique
+    position: u64,
+    rock_ridge: RockRidge,
+}
+
+impl Record {
+    fn is_self_or_parent(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.rock_ridge.mode.map(|mode| mode & MODE_TYPE) {
+            Some(MODE_DIRECTORY) => FileType::Directory,
+            Some(MODE_SYMLINK) => FileType::Symlink,
+            Some(_) => FileType::Regular,
+            None if self.flags & FLAG_DIRECTORY != 0 => FileType::Directory,
+            None => FileType::Regular,
+        }
+    }
+}
+
+/// The name of a plain record: without the ";1" version and a trailing dot.
+fn iso_name(raw: &[u8]) -> String {
+    // "\0" and "\1" stand for "." and ".."
+    if raw == [0] || raw == [1] {
+        return String::new();
+    }
+    let name = String::from_utf8_lossy(raw);
+    let name = name.split(';').next().unwrap_or("");
+    String::from(name.strip_suffix('.').unwrap_or(name))
+}
+
+struct Volume {
+    device: Arc<BlockCache>,
+    block_size: u64,
+    // bytes to skip at the start of every System Use area, `None` without Rock Ridge
+    susp_skip: Option<usize>,
+}
+
+impl Volume {
+    /// Reads `buffer.len()` bytes from byte `offset` of the disc.
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let device_block = self.device.block_size() as u64;
+        let first = offset / device_block;
+        let last = (offset + buffer.len() as u64).div_ceil(device_block);
+        let mut blocks = vec![0u8; ((last - first) * device_block) as usize];
+        self.device.read_blocks(first, &mut blocks)?;
+        let within = (offset - first * device_block) as usize;
+        buffer.copy_from_slice(&blocks[within..within + buffer.len()]);
+        Ok(())
+    }
+
+    fn read_extent(&self, extent: u32, size: u32) -> Result<Vec<u8>, FsError> {
+        let mut data = vec![0u8; size as usize];
+        self.read(extent as u64 * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    fn records(&self, extent: u32, size: u32) -> Result<Vec<Record>, FsError> {
+        let data = self.read_extent(extent, size)?;
+        let mut records = Vec::new();
+        for (index, block) in data.chunks(self.block_size as usize).enumerate() {
+            let mut offset = 0;
+            // a zero length pads out the rest of the block
+            while offset < block.len() && block[offset] != 0 {
+                let length = block[offset] as usize;
+                if length < RECORD_HEADER || offset + length > block.len() {
+                    return Err(FsError::Corrupted);
+                }
+                let position = (extent as u64 + index as u64) * self.block_size + offset as u64;
+                records.push(self.parse_record(&block[offset..offset + length], position)?);
+                offset += length;
+            }
+        }
+        Ok(records)
+    }
+
+    fn parse_record(&self, raw: &[u8], position: u64) -> Result<Record, FsError> {
+        let name_length = raw[32] as usize;
+        if RECORD_HEADER + name_length > raw.len() {
+            return Err(FsError::Corrupted);
+        }
+        let mut record = Record {
+            name: iso_name(&raw[RECORD_HEADER..RECORD_HEADER + name_length]),
+            extent: u32_at(raw, 2),
+            size: u32_at(raw, 10),
+            flags: raw[25],
+            position,
+            rock_ridge: RockRidge::default(),
+        };
+        if let Some(skip) = self.susp_skip {
+            // the name is padded to an even length
+            let start = RECORD_HEADER + name_length + (1 - name_length % 2) + skip;
+            if start < raw.len() {
+                self.parse_system_use(&raw[start..], &mut record.rock_ridge)?;
+            }
+            let is_dot = record.is_self_or_parent();
+            if let Some(name) = record.rock_ridge.name.clone().filter(|_| !is_dot) {
+                record.name = name;
+            }
+        }
+        Ok(record)
+    }
+
+    /* System Use entries.
+        Two letters, a length and a version, then the data. NM and SL may be split
+        over several entries, CE says where the area goes on once this one ends.
+     */
+    fn parse_system_use(&self, area: &[u8], rock_ridge: &mut RockRidge) -> Result<(), FsError> {
+        let mut area = area.to_vec();
+        let mut name = String::new();
+        let mut link = String::new();
+        let mut link_component_open = false;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let length = area[offset + 2] as usize;
+                if length < 4 || offset + length > area.len() {
+                    break;
+                }
+                let entry = &area[offset..offset + length];
+                let data = &entry[4..];
+                match &entry[..2] {
+                    b"NM" if !data.is_empty() => {
+                        // current and parent flags name "." and "..", which keep their own names
+                        if data[0] & 0x06 == 0 {
+                            name.push_str(&String::from_utf8_lossy(&data[1..]));
+                            rock_ridge.name = Some(name.clone());
+                        }
+                    }
+                    b"PX" if data.len() >= 12 => {
+                        rock_ridge.mode = Some(u32_at(data, 0));
+                        rock_ridge.links = Some(u32_at(data, 8));
+                    }
+                    b"SL" if !data.is_empty() => {
+                        let mut components = &data[1..];
+                        while components.len() >= 2 {
+                            let (flags, length) = (components[0], components[1] as usize);
+                            let content = components.get(2..2 + length).ok_or(FsError::Corrupted)?;
+                            if !link_component_open && !link.is_empty() && !link.ends_with('/') {
+                                link.push('/');
+                            }
+                            match flags & 0x0e {
+                                0x02 => link.push('.'),
+                                0x04 => link.push_str(".."),
+                                0x08 => link.push('/'),
+                                _ => link.push_str(&String::from_utf8_lossy(content)),
+                            }
+                            // the component goes on in the next record
+                            link_component_open = flags & 0x01 != 0;
+                            components = &components[2 + length..];
+                        }
+                        rock_ridge.symlink = Some(link.clone());
+                    }
+                    b"CE" if data.len() >= 20 => {
+                        continuation = Some((u32_at(data, 0), u32_at(data, 8), u32_at(data, 16)));
+                    }
+                    b"RE" => rock_ridge.relocated = true,
+                    b"CL" if data.len() >= 4 => rock_ridge.child_link = Some(u32_at(data, 0)),
+                    b"ST" => break,
+                    _ => {}
+                }
+                offset += length;
+            }
+            let Some((block, offset, length)) = continuation else {
+                return Ok(());
+            };
+            area = vec![0u8; length as usize];
+            self.read(block as u64 * self.block_size + offset as u64, &mut area)?;
+        }
+        Err(FsError::Corrupted)
+    }
+
+    fn inode(self: &Arc<Self>, record: Record) -> Result<Arc<IsoInode>, FsError> {
+        let mut record = record;
+        // a relocated directory is reached through the placeholder that links to it
+        if let Some(extent) = record.rock_ridge.child_link {
+            let dot = self.records(extent, self.block_size as u32)?.into_iter().next().ok_or(FsError::Corrupted)?;
+            record.extent = extent;
+            record.size = dot.size;
+            record.flags |= FLAG_DIRECTORY;
+            record.rock_ridge.mode = dot.rock_ridge.mode;
+        }
+        Ok(Arc::new(IsoInode { volume: self.clone(), record }))
+    }
+}
+
+pub struct IsoInode {
+    volume: Arc<Volume>,
+    record: Record,
+}
+
+impl IsoInode {
+    fn children(&self) -> Result<Vec<Record>, FsError> {
+        if self.record.file_type() != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(self.volume.records(self.record.extent, self.record.size)?
+            .into_iter()
+            .filter(|record| !record.is_self_or_parent() && !record.rock_ridge.relocated)
+            .collect())
+    }
+
+    fn data_type(record: &Record) -> FileType {
+        match record.rock_ridge.child_link {
+            Some(_) => FileType::Directory,
+            None => record.file_type(),
+        }
+    }
+}
+
+impl Inode for IsoInode {
+    fn metadata(&self) -> Metadata {
+        let file_type = self.record.file_type();
+        let size = match &self.record.rock_ridge.symlink {
+            Some(target) if file_type == FileType::Symlink => target.len() as u64,
+            _ => self.record.size as u64,
+        };
+        let mode = self.record.rock_ridge.mode.map_or(if file_type == FileType::Directory { 0o555 } else { 0o444 }, |mode| mode & 0o7777);
+        let links = self.record.rock_ridge.links.unwrap_or(1);
+        Metadata { inode: self.record.position, file_type, size, links, mode: mode as u16 }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match self.record.file_type() {
+            FileType::Directory => Err(FsError::IsDirectory),
+            FileType::Symlink => {
+                let target = self.record.rock_ridge.symlink.as_deref().unwrap_or("").as_bytes();
+                let rest = target.get(offset as usize..).unwrap_or(&[]);
+                let length = rest.len().min(buffer.len());
+                buffer[..length].copy_from_slice(&rest[..length]);
+                Ok(length)
+            }
+            _ => {
+                let Some(rest) = (self.record.size as u64).checked_sub(offset) else {
+                    return Ok(0);
+                };
+                let length = buffer.len().min(rest as usize);
+                self.volume.read(self.record.extent as u64 * self.volume.block_size + offset, &mut buffer[..length])?;
+                Ok(length)
+            }
+        }
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let children = self.children()?;
+        // plain names are upper case, found however they are asked for
+        let exact = self.volume.susp_skip.is_some();
+        let record = children.into_iter()
+            .find(|record| if exact { record.name == name } else { record.name.eq_ignore_ascii_case(name) })
+            .ok_or(FsError::NotFound)?;
+        Ok(self.volume.inode(record)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self.children()?
+            .into_iter()
+            .map(|record| DirEntry { inode: record.position, file_type: Self::data_type(&record), name: record.name })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _name: &str, _target: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct Iso9660 {
+    root: Arc<IsoInode>,
+}
+
+impl Iso9660 {
+    /// Finds the primary volume descriptor on `device` and mounts it read only.
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        let device_block = device.block_size() as u64;
+        if !device_block.is_power_of_two() || device_block > SECTOR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let mut volume = Volume { device: BlockCache::new(device, CACHE_BLOCKS), block_size: SECTOR_SIZE, susp_skip: None };
+
+        let mut descriptor = vec![0u8; SECTOR_SIZE as usize];
+        let mut primary = None;
+        for sector in DESCRIPTORS_START..DESCRIPTORS_START + MAX_DESCRIPTORS {
+            volume.read(sector * SECTOR_SIZE, &mut descriptor)?;
+            if &descriptor[1..6] != STANDARD_ID {
+                return Err(FsError::Corrupted);
+            }
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY => {
+                    primary = Some(descriptor.clone());
+                    break;
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or(FsError::Corrupted)?;
+        let block_size = u16::from_le_bytes([primary[128], primary[129]]) as u64;
+        if !block_size.is_power_of_two() || block_size < device_block || block_size > SECTOR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        volume.block_size = block_size;
+        let root = volume.parse_record(&primary[156..156 + 34], 156 + DESCRIPTORS_START * SECTOR_SIZE)?;
+
+        // Rock Ridge announces itself with an SP entry in the root's "." record
+        let mut dot = vec![0u8; block_size as usize];
+        volume.read(root.extent as u64 * block_size, &mut dot)?;
+        let length = dot[0] as usize;
+        if length >= RECORD_HEADER + 1 + 7 && &dot[34..36] == b"SP" && dot[38..40] == [0xbe, 0xef] {
+            volume.susp_skip = Some(dot[40] as usize);
+        }
+
+        let volume = Arc::new(volume);
+        let root = volume.inode(root)?;
+        if root.record.file_type() != FileType::Directory {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Arc::new(Iso9660 { root }))
+    }
+}
+
+impl FileSystem for Iso9660 {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    fn record(name: &[u8], extent: u32, size: u32, flags: u8, system_use: &[u8]) -> Vec<u8> {
+        let mut record = vec![0u8; RECORD_HEADER];
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = flags;
+        record[32] = name.len() as u8;
+        record.extend_from_slice(name);
+        if name.len() & 1 == 0 {
+            record.push(0);
+        }
+        record.extend_from_slice(system_use);
+        record[0] = record.len() as u8;
+        record
+    }
+
+    fn susp(signature: &[u8; 2], data: &[u8]) -> Vec<u8> {
+        let mut entry = vec![signature[0], signature[1], 4 + data.len() as u8, 1];
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    fn px(mode: u32) -> Vec<u8> {
+        let mut data = vec![0u8; 32];
+        data[..4].copy_from_slice(&mode.to_le_bytes());
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        susp(b"PX", &data)
+    }
+
+    fn nm(name: &str) -> Vec<u8> {
+        let mut data = vec![0];
+        data.extend_from_slice(name.as_bytes());
+        susp(b"NM", &data)
+    }
+
+    fn put(image: &mut [u8], sector: usize, records: &[Vec<u8>]) {
+        let mut offset = sector * SECTOR_SIZE as usize;
+        for record in records {
+            image[offset..offset + record.len()].copy_from_slice(record);
+            offset += record.len();
+        }
+    }
+
+    /* a disc as mkisofs -R writes it.
+        Descriptors in 16 and 17, the root directory in 18, DOCS in 19, file data
+        from 20, and a continuation area in 21 for the long name of the symlink.
+     */
+    fn image(rock_ridge: bool) -> Vec<u8> {
+        let mut image = vec![0u8; 24 * SECTOR_SIZE as usize];
+        let sector = SECTOR_SIZE as usize;
+        let rr = |entries: &[Vec<u8>]| if rock_ridge { entries.concat() } else { Vec::new() };
+
+        let primary = &mut image[16 * sector..17 * sector];
+        primary[0] = DESCRIPTOR_PRIMARY;
+        primary[1..6].copy_from_slice(STANDARD_ID);
+        primary[128..130].copy_from_slice(&2048u16.to_le_bytes());
+        primary[156..156 + 34].copy_from_slice(&record(&[0], 18, 2048, FLAG_DIRECTORY, &[]));
+        image[17 * sector] = DESCRIPTOR_TERMINATOR;
+        image[17 * sector + 1..17 * sector + 6].copy_from_slice(STANDARD_ID);
+
+        let mut sp = susp(b"SP", &[0xbe, 0xef, 0]);
+        sp.extend(px(MODE_DIRECTORY | 0o755));
+        let mut continuation = vec![0u8; 24];
+        continuation[0..4].copy_from_slice(&21u32.to_le_bytes());
+        continuation[16..20].copy_from_slice(&9u32.to_le_bytes());
+        // the link target "../docs/notes.txt", the file name split over two components
+        let mut link = vec![0, 0x04, 0];
+        link.extend_from_slice(&[0x00, 4]);
+        link.extend_from_slice(b"docs");
+        link.extend_from_slice(&[0x01, 5]);
+        link.extend_from_slice(b"notes");
+        link.extend_from_slice(&[0x00, 4]);
+        link.extend_from_slice(b".txt");
+        put(&mut image, 18, &[
+            record(&[0], 18, 2048, FLAG_DIRECTORY, &rr(&[sp])),
+            record(&[1], 18, 2048, FLAG_DIRECTORY, &[]),
+            record(b"DOCS", 19, 2048, FLAG_DIRECTORY, &rr(&[nm("docs"), px(MODE_DIRECTORY | 0o700)])),
+            record(b"LINK.;1", 0, 0, 0, &rr(&[px(MODE_SYMLINK | 0o777), susp(b"CE", &continuation), susp(b"SL", &link)])),
+            record(b"README.TXT;1", 20, 6, 0, &rr(&[nm("ReadMe.txt"), px(0o100644)])),
+        ]);
+        image[21 * sector..21 * sector + 9].copy_from_slice(&nm("link"));
+        put(&mut image, 19, &[
+            record(&[0], 19, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], 18, 2048, FLAG_DIRECTORY, &[]),
+            record(b"NOTES.TXT;1", 22, 3000, 0, &rr(&[nm("notes.txt")])),
+        ]);
+        image[20 * sector..20 * sector + 6].copy_from_slice(b"hello\n");
+        image[22 * sector..22 * sector + 3000].fill(b'n');
+        image
+    }
+
+    fn names(directory: &dyn Inode) -> Vec<String> {
+        let mut names: Vec<String> = directory.read_dir().unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test_case]
+    fn test_rock_ridge() {
+        let fs = Iso9660::mount(Arc::new(RamDisk::from_bytes(512, image(true)))).expect("mount failed");
+        let root = fs.root();
+        assert_eq!(names(&*root), ["ReadMe.txt", "docs", "link"]);
+        let mut buffer = [0u8; 32];
+        let readme = root.lookup("ReadMe.txt").unwrap();
+        assert_eq!(readme.metadata().mode, 0o644);
+        assert_eq!(readme.read_at(0, &mut buffer), Ok(6));
+        assert_eq!(&buffer[..6], b"hello\n");
+        assert_eq!(root.lookup("README.TXT").err(), Some(FsError::NotFound));
+
+        let link = root.lookup("link").unwrap();
+        assert_eq!(link.metadata().file_type, FileType::Symlink);
+        let length = link.read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"../docs/notes.txt");
+
+        let docs = root.lookup("docs").unwrap();
+        assert_eq!(docs.metadata().mode, 0o700);
+        let notes = docs.lookup("notes.txt").unwrap();
+        let mut data = vec![0u8; 4096];
+        // across the end of a 512 byte device block
+        assert_eq!(notes.read_at(500, &mut data), Ok(2500));
+        assert!(data[..2500].iter().all(|&byte| byte == b'n'));
+        assert_eq!(docs.create("new", FileType::Regular).err(), Some(FsError::ReadOnly));
+    }
+
+    #[test_case]
+    fn test_plain_names() {
+        let fs = Iso9660::mount(Arc::new(RamDisk::from_bytes(2048, image(false)))).expect("mount failed");
+        let root = fs.root();
+        assert_eq!(names(&*root), ["DOCS", "LINK", "README.TXT"]);
+        let notes = root.lookup("docs").unwrap().lookup("notes.txt").unwrap();
+        assert_eq!(notes.metadata().size, 3000);
+        assert_eq!(notes.metadata().mode, 0o444);
+
+        let mut broken = image(false);
+        broken[16 * SECTOR_SIZE as usize + 1] = b'X';
+        assert_eq!(Iso9660::mount(Arc::new(RamDisk::from_bytes(2048, broken))).err(), Some(FsError::Corrupted));
+    }
+}
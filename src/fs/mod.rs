@@ -0,0 +1,404 @@
+pub mod devfs;
+#[cfg(feature = "fs")]
+pub mod ext2;
+#[cfg(feature = "fs")]
+pub mod fat32;
+pub mod file;
+#[cfg(feature = "fs")]
+pub mod initrd;
+#[cfg(feature = "fs")]
+pub mod iso9660;
+pub mod mount;
+pub mod path;
+#[cfg(feature = "fs")]
+pub mod procfs;
+pub mod ramfs;
+#[cfg(feature = "fs")]
+pub mod tar;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+use crate::block::BlockError;
+#[cfg(feature = "net")]
+use crate::net::NetError;
+use crate::process::signal::Interrupted;
+
+pub use file::{File, OpenFile, OpenFlags, SeekFrom};
+pub use mount::{mount, mount_device, register_filesystem, umount, Dentry, Mount};
+
+/* the virtual filesystem.
+    Every filesystem, on disk or synthetic, hands out inodes; the VFS strings them
+    into one namespace through the mount table and resolves paths across it. Open
+    files keep the inode they were opened on, so a rename or unlink does not affect
+    anybody already reading.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    Exists,
+    NotEmpty,
+    ReadOnly,
+    /// Not absolute, or a component that cannot be a name.
+    InvalidPath,
+    NameTooLong,
+    /// Rename or link between two mounts.
+    CrossDevice,
+    Busy,
+    NoSpace,
+    /// The file was not opened for this.
+    BadMode,
+    NotSeekable,
+    /// A seek before the start, or some other argument that makes no sense.
+    InvalidArgument,
+    /// The on-disk structures do not make sense.
+    Corrupted,
+    Unsupported,
+    /// A signal for the caller ended a blocking read.
+    Interrupted,
+    Io(BlockError),
+    /// A socket failed, as sockets are files too.
+    #[cfg(feature = "net")]
+    Net(NetError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        FsError::Io(error)
+    }
+}
+
+#[cfg(feature = "net")]
+impl From<NetError> for FsError {
+    fn from(error: NetError) -> Self {
+        FsError::Net(error)
+    }
+}
+
+impl From<Interrupted> for FsError {
+    fn from(_: Interrupted) -> Self {
+        FsError::Interrupted
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+    Socket,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Unique within the filesystem.
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    pub links: u32,
+    /// Permission bits, as in 0o644.
+    pub mode: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Short type name, as in "ramfs" or "fat32".
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes out everything buffered, for filesystems that buffer.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/* a file, directory or device node of some filesystem.
+    The defaults describe an inode that supports nothing, each filesystem overrides
+    what applies to its kind of node. Directory operations take names, never paths,
+    the VFS does the walking.
+ */
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads at `offset`, returns how much was read, 0 at the end.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    /// Writes at `offset`, growing the file as needed.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::IsDirectory)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Adds an empty regular file or directory called `name`.
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Removes `name`, a directory only when it is empty.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Moves entry `name` to `new_name` in `target`, a directory of the same filesystem,
+    /// replacing what was there unless that is a directory that is not empty.
+    fn rename(&self, _name: &str, _target: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// For filesystems to find their own inode type behind `rename`'s target.
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Mounts an empty ramfs as the root, until something better comes along, /dev and /proc.
+pub fn init() {
+    register_filesystem("ramfs", |_| Ok(ramfs::RamFs::new()));
+    mount("/", ramfs::RamFs::new()).expect("root already mounted");
+    devfs::init();
+}
+
+/// The `fs` subsystem: the filesystems on disks, /proc and the initrd.
+#[cfg(feature = "fs")]
+pub fn init_filesystems() {
+    register_filesystem("procfs", |_| Ok(procfs::ProcFs::new()));
+    register_filesystem("fat32", |device| Ok(fat32::Fat32::mount(device.ok_or(FsError::InvalidArgument)?)?));
+    register_filesystem("ext2", |device| Ok(ext2::Ext2::mount(device.ok_or(FsError::InvalidArgument)?)?));
+    register_filesystem("iso9660", |device| Ok(iso9660::Iso9660::mount(device.ok_or(FsError::InvalidArgument)?)?));
+    procfs::init();
+    initrd::init();
+}
+
+/// Metadata of whatever `path` names.
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    Ok(mount::resolve(path)?.inode().metadata())
+}
+
+/// The entries of directory `path`, without "." and "..".
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    mount::resolve(path)?.inode().read_dir()
+}
+
+/// Opens `path`, creating or truncating it as `flags` ask.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<OpenFile>, FsError> {
+    let dentry = match mount::resolve(path) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => return Err(FsError::Exists),
+        Ok(dentry) => dentry,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => create(path, FileType::Regular)?,
+        Err(error) => return Err(error),
+    };
+    OpenFile::new(dentry, flags)
+}
+
+/// Creates an empty file or directory at `path`.
+pub fn create(path: &str, file_type: FileType) -> Result<Dentry, FsError> {
+    let (parent, name) = mount::resolve_parent(path)?;
+    let inode = parent.inode().create(name.as_str(), file_type)?;
+    Ok(parent.child(&name, inode))
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    create(path, FileType::Directory).map(|_| ())
+}
+
+/// Creates the directory `path` along with any missing parents.
+pub fn create_dir_all(path: &str) -> Result<(), FsError> {
+    let path = path::normalize(path)?;
+    let mut prefix = String::new();
+    for component in path::components_below(&path, "/") {
+        prefix.push('/');
+        prefix.push_str(component);
+        match mkdir(&prefix) {
+            Err(FsError::Exists) if stat(&prefix)?.file_type != FileType::Directory => return Err(FsError::NotDirectory),
+            Ok(()) | Err(FsError::Exists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the file or empty directory at `path`; mount points stay.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = mount::resolve_parent(path)?;
+    if mount::is_mount_point(&parent.child_path(&name)) {
+        return Err(FsError::Busy);
+    }
+    parent.inode().unlink(&name)
+}
+
+/// Moves `from` to `to`, both on the same mount.
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let (source, name) = mount::resolve_parent(from)?;
+    let (target, new_name) = mount::resolve_parent(to)?;
+    if !Arc::ptr_eq(source.mount(), target.mount()) {
+        return Err(FsError::CrossDevice);
+    }
+    let moved = source.child_path(&name);
+    let destination = target.child_path(&new_name);
+    if mount::is_mount_point(&moved) || mount::is_mount_point(&destination) {
+        return Err(FsError::Busy);
+    }
+    // a directory cannot move into itself
+    if destination != moved && path::is_within(&destination, &moved) {
+        return Err(FsError::InvalidArgument);
+    }
+    source.inode().rename(&name, &**target.inode(), &new_name)
+}
+
+/// Reads the whole file at `path`.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let file = open(path, OpenFlags::READ)?;
+    let mut contents = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(contents),
+            read => contents.extend_from_slice(&chunk[..read]),
+        }
+    }
+}
+
+/// Replaces the contents of the file at `path`, creating it if needed.
+pub fn write_file(path: &str, contents: &[u8]) -> Result<(), FsError> {
+    let file = open(path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+    let mut written = 0;
+    while written < contents.len() {
+        written += file.write(&contents[written..])?;
+    }
+    Ok(())
+}
+
+/// Flushes every mounted filesystem.
+pub fn sync() -> Result<(), FsError> {
+    mount::mounts().iter().map(|mount| mount.filesystem().sync()).fold(Ok(()), Result::and)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    struct StaticFile(u64, &'static [u8]);
+
+    impl Inode for StaticFile {
+        fn metadata(&self) -> Metadata {
+            Metadata { inode: self.0, file_type: FileType::Regular, size: self.1.len() as u64, links: 1, mode: 0o444 }
+        }
+
+        fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+            let rest = self.1.get(offset as usize..).unwrap_or(&[]);
+            let length = rest.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&rest[..length]);
+            Ok(length)
+        }
+
+        fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+            Err(FsError::ReadOnly)
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct StaticDir(u64, Vec<(&'static str, Arc<dyn Inode>)>);
+
+    impl Inode for StaticDir {
+        fn metadata(&self) -> Metadata {
+            Metadata { inode: self.0, file_type: FileType::Directory, size: 0, links: 2, mode: 0o555 }
+        }
+
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+            self.1.iter().find(|(entry, _)| *entry == name).map(|(_, inode)| inode.clone()).ok_or(FsError::NotFound)
+        }
+
+        fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+            Ok(self.1.iter()
+                .map(|(name, inode)| DirEntry { name: String::from(*name), inode: inode.metadata().inode, file_type: inode.metadata().file_type })
+                .collect())
+        }
+
+        fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+            Err(FsError::ReadOnly)
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    struct StaticFs(Arc<StaticDir>);
+
+    impl FileSystem for StaticFs {
+        fn name(&self) -> &'static str {
+            "static"
+        }
+
+        fn root(&self) -> Arc<dyn Inode> {
+            self.0.clone()
+        }
+    }
+
+    fn static_fs(file: &'static [u8]) -> Arc<StaticFs> {
+        let etc = StaticDir(2, vec![("hostname", Arc::new(StaticFile(3, file)) as Arc<dyn Inode>)]);
+        let mnt = StaticDir(4, Vec::new());
+        Arc::new(StaticFs(Arc::new(StaticDir(1, vec![("etc", Arc::new(etc) as Arc<dyn Inode>), ("mnt", Arc::new(mnt))]))))
+    }
+
+    #[test_case]
+    fn test_resolution_across_mounts() {
+        // the mounts stay
+        crate::testing::keeps_heap();
+        mkdir("/vfs-test").unwrap();
+        mount("/vfs-test", static_fs(b"kernel")).expect("mount failed");
+        assert_eq!(mount("/vfs-test/", static_fs(b"again")).err(), Some(FsError::Busy));
+        assert_eq!(mount("/vfs-test/etc/hostname", static_fs(b"file")).err(), Some(FsError::NotDirectory));
+        mount("/vfs-test/mnt", static_fs(b"nested")).expect("nested mount failed");
+
+        assert_eq!(read_file("/vfs-test/etc/hostname").as_deref(), Ok(&b"kernel"[..]));
+        assert_eq!(read_file("/vfs-test/mnt/etc/../etc/hostname").as_deref(), Ok(&b"nested"[..]));
+        assert_eq!(stat("/vfs-test/mnt/etc").map(|metadata| metadata.file_type), Ok(FileType::Directory));
+        let names: Vec<String> = read_dir("/vfs-test").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["etc", "mnt"]);
+
+        assert_eq!(stat("/vfs-test/etc/missing").err(), Some(FsError::NotFound));
+        assert_eq!(stat("/vfs-test/etc/hostname/x").err(), Some(FsError::NotDirectory));
+        assert_eq!(open("/vfs-test/etc", OpenFlags::WRITE).err(), Some(FsError::IsDirectory));
+        assert_eq!(unlink("/vfs-test/mnt").err(), Some(FsError::Busy));
+        assert_eq!(unlink("/vfs-test").err(), Some(FsError::Busy));
+        assert_eq!(rename("/vfs-test/mnt/etc/hostname", "/vfs-test/etc/name").err(), Some(FsError::CrossDevice));
+
+        let file = open("/vfs-test/etc/hostname", OpenFlags::READ).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(-2)), Ok(4));
+        let mut buffer = [0u8; 8];
+        assert_eq!(file.read(&mut buffer), Ok(2));
+        assert_eq!(&buffer[..2], b"el");
+        assert_eq!(file.seek(SeekFrom::Current(-10)), Err(FsError::InvalidArgument));
+        assert_eq!(file.write(b"x"), Err(FsError::BadMode));
+    }
+}
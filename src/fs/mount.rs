@@ -0,0 +1,208 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::path::{self, components_below, is_within, normalize, split_last};
+use super::{FileSystem, FileType, FsError, Inode};
+use crate::block::{self, BlockDevice};
+use crate::sync::{SpinLock, SpinRwLock};
+
+/// A filesystem attached to the namespace.
+pub struct Mount {
+    path: String,
+    filesystem: Arc<dyn FileSystem>,
+}
+
+impl Mount {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem> {
+        &self.filesystem
+    }
+}
+
+/// A resolved path: the inode it names and the mount it was found on.
+#[derive(Clone)]
+pub struct Dentry {
+    path: String,
+    inode: Arc<dyn Inode>,
+    mount: Arc<Mount>,
+}
+
+impl Dentry {
+    /// The normalized absolute path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn mount(&self) -> &Arc<Mount> {
+        &self.mount
+    }
+
+    pub fn child_path(&self, name: &str) -> String {
+        let mut child = self.path.clone();
+        if child != "/" {
+            child.push('/');
+        }
+        child.push_str(name);
+        child
+    }
+
+    /// The entry `name` of this directory, on the same mount.
+    pub fn child(&self, name: &str, inode: Arc<dyn Inode>) -> Dentry {
+        Dentry { path: self.child_path(name), inode, mount: self.mount.clone() }
+    }
+}
+
+// by normalized mount point; every path lookup reads it, only mount and umount write
+static MOUNTS: SpinRwLock<BTreeMap<String, Arc<Mount>>> = SpinRwLock::new(BTreeMap::new());
+
+/* attaching a filesystem.
+    The root comes first, anywhere else the mount point has to be a directory
+    already. Whatever that directory held is hidden while the mount is there.
+ */
+pub fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    if path != "/" {
+        let point = resolve(&path)?;
+        if point.inode.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+    }
+    let mut mounts = MOUNTS.write();
+    if mounts.contains_key(&path) {
+        return Err(FsError::Busy);
+    }
+    mounts.insert(path.clone(), Arc::new(Mount { path, filesystem }));
+    Ok(())
+}
+
+/* detaching a filesystem.
+    Refused while anything is mounted below it or anybody holds a path on it, an
+    open file for one; the mount table's own reference is the only one allowed.
+    Whatever the filesystem buffered is written out once it is off the namespace.
+ */
+pub fn umount(path: &str) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    let mount = {
+        let mut mounts = MOUNTS.write();
+        let mount = mounts.get(&path).ok_or(FsError::InvalidArgument)?;
+        let nested = mounts.keys().any(|other| *other != path && is_within(other, &path));
+        if path == "/" || nested || Arc::strong_count(mount) > 1 {
+            return Err(FsError::Busy);
+        }
+        mounts.remove(&path).unwrap()
+    };
+    mount.filesystem.sync()
+}
+
+/// Creates a filesystem instance, on a block device for the types that need one.
+pub type MountFn = fn(Option<Arc<dyn BlockDevice>>) -> Result<Arc<dyn FileSystem>, FsError>;
+
+static FILESYSTEMS: SpinLock<BTreeMap<&'static str, MountFn>> = SpinLock::new(BTreeMap::new());
+
+/// Makes the filesystem type `name` known to `mount_device`.
+pub fn register_filesystem(name: &'static str, mount: MountFn) {
+    FILESYSTEMS.lock().insert(name, mount);
+}
+
+/// Mounts a new `fstype` filesystem on the block device `device` at `path`.
+/// `device` is "none" for filesystems without one, as ramfs.
+pub fn mount_device(device: &str, path: &str, fstype: &str) -> Result<(), FsError> {
+    let create = *FILESYSTEMS.lock().get(fstype).ok_or(FsError::Unsupported)?;
+    let device = match device {
+        "none" => None,
+        name => Some(block::get(name).ok_or(FsError::NotFound)?),
+    };
+    mount(path, create(device)?)
+}
+
+pub fn is_mount_point(path: &str) -> bool {
+    MOUNTS.read().contains_key(path)
+}
+
+/// Every mount, parents before what is mounted below them.
+pub fn mounts() -> Vec<Arc<Mount>> {
+    let mut mounts: Vec<_> = MOUNTS.read().values().cloned().collect();
+    mounts.sort_by_key(|mount| mount.path.len());
+    mounts
+}
+
+/// The mount `path` lies on, the deepest one containing it.
+fn mount_of(path: &str) -> Option<Arc<Mount>> {
+    MOUNTS.read().values()
+        .filter(|mount| is_within(path, &mount.path))
+        .max_by_key(|mount| mount.path.len())
+        .cloned()
+}
+
+/// Walks `path` from the root of the filesystem it lies on.
+pub fn resolve(path: &str) -> Result<Dentry, FsError> {
+    let path = normalize(path)?;
+    let mount = mount_of(&path).ok_or(FsError::NotFound)?;
+    let mut inode = mount.filesystem.root();
+    for component in components_below(&path, &mount.path) {
+        inode = inode.lookup(component)?;
+    }
+    Ok(Dentry { path, inode, mount })
+}
+
+/// The directory `path` would be an entry of, and the entry's name.
+pub fn resolve_parent(path: &str) -> Result<(Dentry, String), FsError> {
+    let path = normalize(path)?;
+    let (parent, name) = split_last(&path).ok_or(FsError::InvalidPath)?;
+    path::check_name(name)?;
+    let parent = resolve(parent)?;
+    if parent.inode.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    Ok((parent, String::from(name)))
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+    use crate::fs::{self, fat32, OpenFlags};
+
+    #[test_case]
+    fn test_mount_and_umount() {
+        // the disk stays registered and mounted
+        crate::testing::keeps_heap();
+        let disk = Arc::new(RamDisk::new(512, 512));
+        fat32::format(&*disk, 1).unwrap();
+        block::register("mount-test0", disk).unwrap();
+        fs::mkdir("/mount-test").unwrap();
+        assert_eq!(mount_device("mount-test0", "/mount-test", "nofs").err(), Some(FsError::Unsupported));
+        assert_eq!(mount_device("missing0", "/mount-test", "fat32").err(), Some(FsError::NotFound));
+        mount_device("mount-test0", "/mount-test", "fat32").unwrap();
+        fs::write_file("/mount-test/kept", b"on disk").unwrap();
+        fs::mkdir("/mount-test/inner").unwrap();
+        mount_device("none", "/mount-test/inner", "ramfs").unwrap();
+        assert_eq!(fs::stat("/mount-test/inner/../kept").unwrap().size, 7);
+
+        let file = fs::open("/mount-test/inner/file", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        assert_eq!(umount("/mount-test"), Err(FsError::Busy));
+        assert_eq!(umount("/mount-test/inner"), Err(FsError::Busy));
+        drop(file);
+        umount("/mount-test/inner").unwrap();
+        assert_eq!(fs::stat("/mount-test/inner/file").err(), Some(FsError::NotFound));
+        umount("/mount-test").unwrap();
+        assert_eq!(fs::stat("/mount-test/kept").err(), Some(FsError::NotFound));
+        assert_eq!(umount("/mount-test"), Err(FsError::InvalidArgument));
+        assert_eq!(umount("/"), Err(FsError::Busy));
+
+        mount_device("mount-test0", "/mount-test", "fat32").unwrap();
+        assert_eq!(fs::read_file("/mount-test/kept").as_deref(), Ok(&b"on disk"[..]));
+        umount("/mount-test").unwrap();
+        fs::unlink("/mount-test").unwrap();
+        block::unregister("mount-test0");
+    }
+}
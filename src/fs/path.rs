@@ -0,0 +1,89 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::FsError;
+
+/// Longest name of a single path component.
+pub const MAX_NAME_LEN: usize = 255;
+
+/* path normalization.
+    Paths are absolute. Repeated slashes and "." go away and ".." takes back the
+    component before it, at the root it stays at the root. There are no symbolic
+    links, so this is the same as walking ".." entries on disk.
+ */
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => {
+                check_name(name)?;
+                components.push(name);
+            }
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for component in &components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Rejects names a directory entry cannot have.
+pub fn check_name(name: &str) -> Result<(), FsError> {
+    match name {
+        "" | "." | ".." => Err(FsError::InvalidPath),
+        _ if name.contains('/') || name.contains('\0') => Err(FsError::InvalidPath),
+        _ if name.len() > MAX_NAME_LEN => Err(FsError::NameTooLong),
+        _ => Ok(()),
+    }
+}
+
+/// Splits a normalized path into its parent and last component, None for the root.
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let index = path.rfind('/')?;
+    let name = &path[index + 1..];
+    if name.is_empty() {
+        return None;
+    }
+    Some((if index == 0 { "/" } else { &path[..index] }, name))
+}
+
+/// The components of a normalized path below `prefix`, a normalized path above it.
+pub fn components_below<'a>(path: &'a str, prefix: &str) -> impl Iterator<Item = &'a str> {
+    path[prefix.len()..].split('/').filter(|component| !component.is_empty())
+}
+
+/// Whether normalized `path` is `ancestor` or lies below it.
+pub fn is_within(path: &str, ancestor: &str) -> bool {
+    ancestor == "/" || path == ancestor || path.strip_prefix(ancestor).is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_normalize() {
+        assert_eq!(normalize("/").as_deref(), Ok("/"));
+        assert_eq!(normalize("//usr/./bin//").as_deref(), Ok("/usr/bin"));
+        assert_eq!(normalize("/usr/../../etc/x").as_deref(), Ok("/etc/x"));
+        assert_eq!(normalize("usr"), Err(FsError::InvalidPath));
+        assert_eq!(split_last("/usr/bin"), Some(("/usr", "bin")));
+        assert_eq!(split_last("/usr"), Some(("/", "usr")));
+        assert_eq!(split_last("/"), None);
+        assert!(is_within("/mnt/disk/a", "/mnt/disk"));
+        assert!(!is_within("/mnt/diskette", "/mnt/disk"));
+        assert_eq!(components_below("/mnt/disk/a/b", "/mnt/disk").collect::<Vec<_>>(), ["a", "b"]);
+    }
+}
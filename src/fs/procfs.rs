@@ -0,0 +1,277 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{mkdir, mount, DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::interrupts::latency;
+use crate::memory::{frame, PAGE_SIZE};
+use crate::process::table::{self, Status};
+use crate::process::ProcessId;
+use crate::thread::scheduler;
+use crate::{allocator, dmesg, percpu, profile, time, trace};
+
+/* procfs.
+    /proc, files whose contents are made up from kernel statistics each time they
+    are read. Nothing is kept between reads, so a file read in several pieces may
+    stitch together two snapshots; the files are short enough that one read gets
+    them whole. Every process in the table has a directory named after its id.
+ */
+
+type Generator = fn(Option<ProcessId>) -> Result<String, FsError>;
+type Counter = fn(&percpu::CpuStats) -> u64;
+
+// the root is inode 1, fixed files follow, process directories are spread out above
+const PROCESS_INODE_SHIFT: u32 = 8;
+
+const FILES: &[(&str, Generator)] = &[
+    ("date", |_| Ok(format!("{}\n", time::rtc::read()))),
+    ("dmesg", |_| Ok(dmesg::text())),
+    ("interrupts", |_| Ok(interrupts())),
+    ("irqlatency", |_| Ok(latency::report())),
+    ("kmemleak", |_| Ok(allocator::leaks::report())),
+    ("meminfo", |_| Ok(meminfo())),
+    ("profile", |_| Ok(profile::report())),
+    ("trace", |_| Ok(trace::text())),
+    ("uptime", |_| Ok(uptime())),
+];
+
+const PROCESS_FILES: &[(&str, Generator)] = &[
+    ("status", |pid| status(pid.unwrap())),
+];
+
+fn kilobytes(bytes: u64) -> u64 {
+    bytes / 1024
+}
+
+fn meminfo() -> String {
+    let total = frame::total_frames() as u64 * PAGE_SIZE;
+    let used = frame::allocated_frames() as u64 * PAGE_SIZE;
+    let heap_used = allocator::heap_used() as u64;
+    format!(
+        "MemTotal:  {:>8} kB\nMemFree:   {:>8} kB\nHeapTotal: {:>8} kB\nHeapUsed:  {:>8} kB\n",
+        kilobytes(total),
+        kilobytes(total - used),
+        kilobytes(allocator::HEAP_SIZE as u64),
+        kilobytes(heap_used),
+    )
+}
+
+fn interrupts() -> String {
+    let cpus = percpu::cpus();
+    let mut text = String::from("         ");
+    for cpu in &cpus {
+        let _ = write!(text, " {:>10}", format!("CPU{}", cpu.cpu_id));
+    }
+    text.push('\n');
+    let rows: [(&str, Counter); 3] = [
+        ("hardware", |stats| stats.interrupts.load(Ordering::Relaxed)),
+        ("ipi", |stats| stats.ipis.load(Ordering::Relaxed)),
+        ("syscall", |stats| stats.syscalls.load(Ordering::Relaxed)),
+    ];
+    for (name, count) in rows {
+        let _ = write!(text, "{:>8}:", name);
+        for cpu in &cpus {
+            let _ = write!(text, " {:>10}", count(&cpu.stats));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{}.{:02}", duration.as_secs(), duration.subsec_millis() / 10)
+}
+
+/// Time since boot and time the CPUs spent idle, summed over all of them.
+fn uptime() -> String {
+    let up = time::uptime();
+    let idle = scheduler::stats().iter().filter(|thread| thread.name == "idle").map(|thread| thread.run_time).sum();
+    format!("{} {}\n", seconds(up), seconds(idle))
+}
+
+fn status(pid: ProcessId) -> Result<String, FsError> {
+    let parent = table::parent_of(pid).map_or(0, ProcessId::as_u64);
+    if let Some(status) = table::zombie_status(pid) {
+        let ended = match status {
+            Status::Exited(code) => format!("ExitCode:\t{}", code.0),
+            Status::Killed(signal) => format!("Signal:\t{}", signal),
+        };
+        return Ok(format!("State:\tZ (zombie)\nPid:\t{}\nPPid:\t{}\n{}\n", pid.as_u64(), parent, ended));
+    }
+    let process = table::find(pid).ok_or(FsError::NotFound)?;
+    let (name, threads, open_files) = without_interrupts(|| {
+        let process = process.lock();
+        (process.name().to_string(), process.threads().len(), process.files().open_count())
+    });
+    Ok(format!(
+        "Name:\t{}\nState:\tR (running)\nPid:\t{}\nPPid:\t{}\nThreads:\t{}\nFiles:\t{}\n",
+        name, pid.as_u64(), parent, threads, open_files,
+    ))
+}
+
+struct ProcFile {
+    inode: u64,
+    pid: Option<ProcessId>,
+    generate: Generator,
+}
+
+impl Inode for ProcFile {
+    // the size is unknown until the contents are made up, 0 as on Linux
+    fn metadata(&self) -> Metadata {
+        Metadata { inode: self.inode, file_type: FileType::Regular, size: 0, links: 1, mode: 0o444 }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let text = (self.generate)(self.pid)?;
+        let rest = text.as_bytes().get(offset as usize..).unwrap_or(&[]);
+        let length = rest.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&rest[..length]);
+        Ok(length)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The root, or the directory of process `pid`.
+struct ProcDirectory {
+    pid: Option<ProcessId>,
+}
+
+impl ProcDirectory {
+    fn inode(&self) -> u64 {
+        self.pid.map_or(1, |pid| (pid.as_u64() + 1) << PROCESS_INODE_SHIFT)
+    }
+
+    fn files(&self) -> &'static [(&'static str, Generator)] {
+        if self.pid.is_some() { PROCESS_FILES } else { FILES }
+    }
+
+    fn file(&self, index: usize) -> Arc<dyn Inode> {
+        let (_, generate) = self.files()[index];
+        Arc::new(ProcFile { inode: self.inode() + 1 + index as u64, pid: self.pid, generate })
+    }
+}
+
+impl Inode for ProcDirectory {
+    fn metadata(&self) -> Metadata {
+        Metadata { inode: self.inode(), file_type: FileType::Directory, size: 0, links: 2, mode: 0o555 }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if let Some(index) = self.files().iter().position(|(file, _)| *file == name) {
+            return Ok(self.file(index));
+        }
+        // process ids below the root, only for processes still in the table
+        let pid = name.parse().ok().filter(|_| self.pid.is_none()).map(ProcessId::from_u64);
+        match pid {
+            Some(pid) if table::exists(pid) => Ok(Arc::new(ProcDirectory { pid: Some(pid) })),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut entries: Vec<DirEntry> = self.files().iter().enumerate()
+            .map(|(index, (name, _))| DirEntry { name: name.to_string(), inode: self.file(index).metadata().inode, file_type: FileType::Regular })
+            .collect();
+        if self.pid.is_none() {
+            entries.extend(table::ids().into_iter().map(|pid| DirEntry {
+                name: pid.as_u64().to_string(),
+                inode: ProcDirectory { pid: Some(pid) }.inode(),
+                file_type: FileType::Directory,
+            }));
+        }
+        Ok(entries)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _name: &str, _target: &dyn Inode, _new_name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ProcFs)
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcDirectory { pid: None })
+    }
+}
+
+/// Mounts procfs at /proc.
+pub fn init() {
+    mkdir("/proc").expect("cannot create /proc");
+    mount("/proc", ProcFs::new() as Arc<dyn FileSystem>).expect("cannot mount /proc");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::{self, read_file};
+    use crate::process::Process;
+    use spin::Mutex;
+
+    fn text(path: &str) -> String {
+        String::from_utf8(read_file(path).unwrap()).unwrap()
+    }
+
+    #[test_case]
+    fn test_generated_files() {
+        let meminfo = text("/proc/meminfo");
+        assert!(meminfo.starts_with("MemTotal:"), "{}", meminfo);
+        assert!(text("/proc/interrupts").contains("CPU0"));
+        let uptime = text("/proc/uptime");
+        let (up, idle) = uptime.trim_end().split_once(' ').unwrap();
+        assert!(up.contains('.') && idle.contains('.'));
+        assert!(text("/proc/date").ends_with(" UTC\n"));
+
+        let process = Arc::new(Mutex::new(Process::create("proc-test").expect("out of frames")));
+        let pid = without_interrupts(|| process.lock().id());
+        table::attach(pid, &process);
+        let directory = format!("/proc/{}", pid.as_u64());
+        assert!(fs::read_dir("/proc").unwrap().iter().any(|entry| entry.name == directory[6..]));
+        let status = text(&format!("{}/status", directory));
+        assert!(status.starts_with("Name:\tproc-test\n"), "{}", status);
+        assert!(status.contains("Files:\t3\n"));
+
+        assert_eq!(fs::write_file("/proc/uptime", b"0").err(), Some(FsError::ReadOnly));
+        drop(process);
+        assert_eq!(fs::stat(&directory).err(), Some(FsError::NotFound));
+    }
+}
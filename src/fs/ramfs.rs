@@ -0,0 +1,264 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::sync::Mutex;
+
+/* ramfs.
+    Files and directories kept on the heap and nowhere else, gone at reboot. Good
+    enough for a root filesystem until disks are, and for everything scratch.
+ */
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+}
+
+pub struct RamInode {
+    inode: u64,
+    node: Mutex<Node>,
+}
+
+impl RamInode {
+    fn new(file_type: FileType) -> Result<Arc<Self>, FsError> {
+        static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+        let node = match file_type {
+            FileType::Regular => Node::File(Vec::new()),
+            FileType::Directory => Node::Directory(BTreeMap::new()),
+            _ => return Err(FsError::Unsupported),
+        };
+        Ok(Arc::new(RamInode { inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed), node: Mutex::new(node) }))
+    }
+
+    fn file_type(&self) -> FileType {
+        match &*self.node.lock() {
+            Node::File(_) => FileType::Regular,
+            Node::Directory(_) => FileType::Directory,
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(&*self.node.lock(), Node::Directory(entries) if entries.is_empty())
+    }
+}
+
+/// Whether `existing` may be replaced by `moved` in a rename.
+fn check_replace(moved: &RamInode, existing: &RamInode) -> Result<(), FsError> {
+    match (moved.file_type(), existing.file_type()) {
+        (FileType::Directory, FileType::Directory) if !existing.is_empty_directory() => Err(FsError::NotEmpty),
+        (FileType::Directory, FileType::Regular) => Err(FsError::NotDirectory),
+        (FileType::Regular, FileType::Directory) => Err(FsError::IsDirectory),
+        _ => Ok(()),
+    }
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size, links, mode) = match &*self.node.lock() {
+            Node::File(data) => (FileType::Regular, data.len() as u64, 1, 0o644),
+            Node::Directory(entries) => {
+                let subdirectories = entries.values().filter(|entry| entry.file_type() == FileType::Directory).count();
+                (FileType::Directory, entries.len() as u64, 2 + subdirectories as u32, 0o755)
+            }
+        };
+        Metadata { inode: self.inode, file_type, size, links, mode }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node.lock();
+        let Node::File(data) = &*node else {
+            return Err(FsError::IsDirectory);
+        };
+        let rest = data.get(offset as usize..).unwrap_or(&[]);
+        let length = rest.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&rest[..length]);
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut node = self.node.lock();
+        let Node::File(data) = &mut *node else {
+            return Err(FsError::IsDirectory);
+        };
+        let end = (offset as usize).checked_add(buffer.len()).ok_or(FsError::InvalidArgument)?;
+        if end > data.len() {
+            // a write past the end leaves a hole of zeroes
+            data.try_reserve(end - data.len()).map_err(|_| FsError::NoSpace)?;
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        let Node::File(data) = &mut *node else {
+            return Err(FsError::IsDirectory);
+        };
+        data.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &*self.node.lock() {
+            Node::Directory(entries) => entries.get(name).map(|entry| entry.clone() as Arc<dyn Inode>).ok_or(FsError::NotFound),
+            Node::File(_) => Err(FsError::NotDirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let node = self.node.lock();
+        let Node::Directory(entries) = &*node else {
+            return Err(FsError::NotDirectory);
+        };
+        Ok(entries.iter()
+            .map(|(name, entry)| DirEntry { name: name.clone(), inode: entry.inode, file_type: entry.file_type() })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let mut node = self.node.lock();
+        let Node::Directory(entries) = &mut *node else {
+            return Err(FsError::NotDirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(FsError::Exists);
+        }
+        let inode = RamInode::new(file_type)?;
+        entries.insert(String::from(name), inode.clone());
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut node = self.node.lock();
+        let Node::Directory(entries) = &mut *node else {
+            return Err(FsError::NotDirectory);
+        };
+        let entry = entries.get(name).ok_or(FsError::NotFound)?;
+        if entry.file_type() == FileType::Directory && !entry.is_empty_directory() {
+            return Err(FsError::NotEmpty);
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    /* renaming.
+        With two directories involved both are locked, the one with the lower inode
+        number first, so two renames going opposite ways cannot deadlock.
+     */
+    fn rename(&self, name: &str, target: &dyn Inode, new_name: &str) -> Result<(), FsError> {
+        let target = target.as_any().downcast_ref::<RamInode>().ok_or(FsError::CrossDevice)?;
+        if core::ptr::eq(self, target) {
+            let mut node = self.node.lock();
+            let Node::Directory(entries) = &mut *node else {
+                return Err(FsError::NotDirectory);
+            };
+            let moved = entries.get(name).ok_or(FsError::NotFound)?.clone();
+            if name == new_name {
+                return Ok(());
+            }
+            if let Some(existing) = entries.get(new_name) {
+                check_replace(&moved, existing)?;
+            }
+            entries.remove(name);
+            entries.insert(String::from(new_name), moved);
+            return Ok(());
+        }
+
+        let (mut source, mut destination) = if self.inode < target.inode {
+            let source = self.node.lock();
+            (source, target.node.lock())
+        } else {
+            let destination = target.node.lock();
+            (self.node.lock(), destination)
+        };
+        let (Node::Directory(from), Node::Directory(to)) = (&mut *source, &mut *destination) else {
+            return Err(FsError::NotDirectory);
+        };
+        let moved = from.get(name).ok_or(FsError::NotFound)?.clone();
+        if let Some(existing) = to.get(new_name) {
+            // replacing the directory the entry moves out of, which is locked, and not empty
+            if core::ptr::eq(&**existing, self) {
+                return Err(FsError::NotEmpty);
+            }
+            check_replace(&moved, existing)?;
+        }
+        from.remove(name);
+        to.insert(String::from(new_name), moved);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(RamFs { root: RamInode::new(FileType::Directory).expect("directories are supported") })
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::{self, File, OpenFlags, SeekFrom};
+
+    #[test_case]
+    fn test_files_and_directories() {
+        // the test directory stays in the root file system
+        crate::testing::keeps_heap();
+        fs::mkdir("/ramfs-test").unwrap();
+        fs::mkdir("/ramfs-test/dir").unwrap();
+        assert_eq!(fs::mkdir("/ramfs-test/dir"), Err(FsError::Exists));
+        fs::write_file("/ramfs-test/dir/a", b"hello").unwrap();
+
+        let file = fs::open("/ramfs-test/dir/a", OpenFlags::READ | OpenFlags::WRITE).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write(b"!").unwrap();
+        assert_eq!(fs::read_file("/ramfs-test/dir/a").as_deref(), Ok(&b"hello\0\0\0!"[..]));
+        let appender = fs::open("/ramfs-test/dir/a", OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+        appender.write(b"?").unwrap();
+        assert_eq!(fs::stat("/ramfs-test/dir/a").unwrap().size, 10);
+
+        assert_eq!(fs::unlink("/ramfs-test/dir"), Err(FsError::NotEmpty));
+        fs::rename("/ramfs-test/dir/a", "/ramfs-test/b").unwrap();
+        assert_eq!(fs::stat("/ramfs-test/dir/a").err(), Some(FsError::NotFound));
+        // still open, the file keeps working under its new name
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write(b"J").unwrap();
+        assert_eq!(&fs::read_file("/ramfs-test/b").unwrap()[..5], b"Jello");
+
+        assert_eq!(fs::rename("/ramfs-test/dir", "/ramfs-test/b"), Err(FsError::NotDirectory));
+        assert_eq!(fs::rename("/ramfs-test", "/ramfs-test/dir/inside"), Err(FsError::InvalidArgument));
+        fs::mkdir("/ramfs-test/dir/sub").unwrap();
+        assert_eq!(fs::rename("/ramfs-test/dir/sub", "/ramfs-test/dir"), Err(FsError::NotEmpty));
+        fs::unlink("/ramfs-test/dir/sub").unwrap();
+        fs::open("/ramfs-test/b", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+        assert_eq!(fs::stat("/ramfs-test/b").unwrap().size, 0);
+        assert_eq!(fs::stat("/ramfs-test").unwrap().links, 3);
+
+        fs::unlink("/ramfs-test/b").unwrap();
+        fs::unlink("/ramfs-test/dir").unwrap();
+        fs::unlink("/ramfs-test").unwrap();
+        assert!(fs::read_dir("/").unwrap().iter().all(|entry| entry.name != "ramfs-test"));
+    }
+}
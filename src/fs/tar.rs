@@ -0,0 +1,148 @@
+use alloc::string::String;
+
+use super::FsError;
+
+/* ustar archives.
+    A sequence of 512 byte headers, each followed by the entry's data padded to a
+    whole block; two zero blocks, or simply the end of the data, close the archive.
+    Numbers are octal ASCII, names may be split into a prefix and a name field.
+ */
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// Hard links, devices, FIFOs and vendor extensions.
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    prefix: &'a [u8],
+    name: &'a [u8],
+    pub kind: EntryKind,
+    pub mode: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    /// The full name, leading "./" and trailing "/" left out.
+    pub fn path(&self) -> Result<String, FsError> {
+        let text = |field| core::str::from_utf8(field).map_err(|_| FsError::Corrupted);
+        let (prefix, name) = (text(self.prefix)?, text(self.name)?);
+        let path = if prefix.is_empty() { String::from(name) } else { alloc::format!("{}/{}", prefix, name) };
+        Ok(String::from(path.trim_start_matches("./").trim_matches('/')))
+    }
+}
+
+/// A field up to its first NUL.
+fn field(header: &[u8], offset: usize, length: usize) -> &[u8] {
+    let field = &header[offset..offset + length];
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(length);
+    &field[..end]
+}
+
+fn octal(header: &[u8], offset: usize, length: usize) -> Result<u64, FsError> {
+    let digits = field(header, offset, length);
+    let digits = core::str::from_utf8(digits).map_err(|_| FsError::Corrupted)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| FsError::Corrupted)
+}
+
+/// The header's checksum, taken with its own field read as spaces.
+pub fn checksum(header: &[u8]) -> u64 {
+    header[..BLOCK_SIZE].iter().enumerate()
+        .map(|(index, &byte)| if (148..156).contains(&index) { b' ' as u64 } else { byte as u64 })
+        .sum()
+}
+
+pub struct Archive<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+/// Walks the entries of the archive in `data`.
+pub fn entries(data: &[u8]) -> Archive<'_> {
+    Archive { data, offset: 0 }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.data.get(self.offset..self.offset + BLOCK_SIZE)?;
+        if header.iter().all(|&byte| byte == 0) {
+            return None;
+        }
+        let entry = (|| {
+            if &header[257..262] != b"ustar" || octal(header, 148, 8)? != checksum(header) {
+                return Err(FsError::Corrupted);
+            }
+            let size = octal(header, 124, 12)? as usize;
+            let start = self.offset + BLOCK_SIZE;
+            let data = self.data.get(start..start + size).ok_or(FsError::Corrupted)?;
+            let kind = match header[156] {
+                b'0' | 0 => EntryKind::File,
+                b'5' => EntryKind::Directory,
+                b'2' => EntryKind::Symlink,
+                other => EntryKind::Other(other),
+            };
+            self.offset = start + size.next_multiple_of(BLOCK_SIZE);
+            Ok(Entry { prefix: field(header, 345, 155), name: field(header, 0, 100), kind, mode: octal(header, 100, 8)? as u16, data })
+        })();
+        if entry.is_err() {
+            // nothing after a damaged header can be trusted
+            self.offset = self.data.len();
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Appends an entry the way tar writes it.
+    pub fn append(archive: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(alloc::format!("{:011o}", data.len()).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let sum = checksum(&header);
+        header[148..155].copy_from_slice(alloc::format!("{:06o}\0", sum).as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+    }
+
+    #[test_case]
+    fn test_entries() {
+        let mut archive = Vec::new();
+        append(&mut archive, "./etc/", b'5', b"");
+        append(&mut archive, "./etc/motd", b'0', &[b'x'; 600]);
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+        let entries: Vec<_> = entries(&archive).collect::<Result<_, _>>().expect("bad archive");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path().as_deref(), Ok("etc"));
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[1].path().as_deref(), Ok("etc/motd"));
+        assert_eq!(entries[1].data.len(), 600);
+        assert_eq!(entries[1].mode, 0o644);
+
+        archive[BLOCK_SIZE + 10] ^= 1;
+        let mut damaged = super::entries(&archive);
+        assert!(damaged.next().unwrap().is_ok());
+        assert_eq!(damaged.next().unwrap().err(), Some(FsError::Corrupted));
+        assert!(damaged.next().is_none());
+    }
+}
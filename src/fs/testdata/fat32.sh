@@ -0,0 +1,20 @@
+#!/bin/sh
+# Makes the FAT32 volume the fat32 tests mount at $1, with the host's dosfstools
+# and mtools: a 1 MiB volume of 512 byte clusters holding
+#   HELLO.TXT                 "Hello from the host"
+#   A long file name.txt      a VFAT long name
+#   docs/readme.md            the numbers 1 to 400, one per line, three clusters
+# runner.sh runs it for every test binary.
+set -e
+image="$1"
+rm -f "$image"
+mkfs.fat -F 32 -s 1 -n HOSTFAT -C "$image" 1024 > /dev/null
+scratch=$(mktemp -d)
+trap 'rm -rf "$scratch"' EXIT
+printf 'Hello from the host\n' > "$scratch/hello"
+printf 'A name longer than 8.3, in VFAT long name entries.\n' > "$scratch/long"
+seq 1 400 > "$scratch/readme"
+mcopy -i "$image" "$scratch/hello" ::/HELLO.TXT
+mcopy -i "$image" "$scratch/long" "::/A long file name.txt"
+mmd -i "$image" ::/docs
+mcopy -i "$image" "$scratch/readme" ::/docs/readme.md
@@ -0,0 +1,52 @@
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+use crate::sync::SpinLock;
+
+/* QEMU's firmware configuration device.
+    Hands the guest what QEMU was given on its command line, as in
+        -fw_cfg name=opt/blog_os/cmdline,string="log=debug"
+        -fw_cfg name=opt/blog_os/initrd,file=target/initrd.tar
+    An item is selected by writing its key to the selector port and then read a
+    byte at a time from the data port; the keys of named files are found in the
+    file directory. Elsewhere than in QEMU the signature does not match.
+ */
+
+const SELECTOR: u16 = 0x510;
+const DATA: u16 = 0x511;
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+// a directory entry: size, selector, reserved, then the name
+const FILE_NAME_LEN: usize = 56;
+
+// the selector and data ports, a read goes from the selection to its last byte
+static PORTS: SpinLock<(Port<u16>, Port<u8>)> = SpinLock::new((Port::new(SELECTOR), Port::new(DATA)));
+
+/// Reads `len` bytes of the item `key`, from the start.
+fn read(key: u16, len: usize) -> Vec<u8> {
+    let mut ports = PORTS.lock();
+    unsafe {
+        ports.0.write(key);
+        (0..len).map(|_| ports.1.read()).collect()
+    }
+}
+
+/// The contents of the file `name`, if this is QEMU and it got one.
+pub fn file(name: &str) -> Option<Vec<u8>> {
+    if read(SIGNATURE, 4) != b"QEMU" {
+        return None;
+    }
+    let count = u32::from_be_bytes(read(FILE_DIR, 4).try_into().unwrap()) as usize;
+    // the directory is read again from its start, the count included
+    let directory = read(FILE_DIR, 4 + count * (8 + FILE_NAME_LEN));
+    directory[4..].chunks(8 + FILE_NAME_LEN).find_map(|entry| {
+        let entry_name = &entry[8..];
+        let entry_name = &entry_name[..entry_name.iter().position(|&byte| byte == 0).unwrap_or(entry_name.len())];
+        if entry_name != name.as_bytes() {
+            return None;
+        }
+        let size = u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize;
+        let key = u16::from_be_bytes([entry[4], entry[5]]);
+        Some(read(key, size))
+    })
+}
@@ -0,0 +1,553 @@
+pub mod packet;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::process::signal::SIGTRAP;
+use crate::sync::SpinLock;
+use crate::{cmdline, memory};
+use packet::{decode_hex, parse_hex, Link, Reply, PACKET_SIZE};
+
+/* a GDB remote stub.
+    Speaks the remote serial protocol on the second serial port, so a debugger can
+    stop the kernel, look at and change registers and memory, set breakpoints and
+    step. With QEMU:
+        -serial stdio -serial tcp::1234,server,nowait -fw_cfg ...,string=gdb
+    then `target remote :1234` in GDB. The kernel stops at an `int3`, `breakpoint()`
+    here or one GDB set, after a single step, and at exceptions the kernel itself
+    caused; `gdb` on the command line stops it at boot to wait for the debugger.
+    The breakpoint and debug exceptions enter through stubs saving every register,
+    other exceptions show GDB only what the CPU pushed. A stopped CPU polls the port
+    with interrupts off, the other CPUs keep running.
+ */
+
+pub const COM2: u16 = 0x2F8;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+// GDB's x86_64 registers: 16 general purpose, rip, eflags and 6 segment selectors
+const REGISTER_COUNT: usize = 24;
+const DEBUG_VECTOR: u64 = 1;
+const BREAKPOINT_VECTOR: u64 = 3;
+
+/// The registers as the debug trap entries save them, lowest address first.
+#[derive(Debug, Default, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// The last five words, what the CPU pushed.
+    fn stack_frame(&self) -> &ExceptionStackFrame {
+        unsafe { &*(&self.rip as *const u64 as *const ExceptionStackFrame) }
+    }
+
+    fn register(&mut self, number: usize) -> Option<&mut u64> {
+        Some(match number {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18 => &mut self.cs,
+            19 => &mut self.ss,
+            _ => return None,
+        })
+    }
+}
+
+/// What a stop lets GDB see.
+enum Registers<'a> {
+    /// All of them, changes take effect when the CPU resumes.
+    Full(&'a mut TrapFrame),
+    /// Read only, from an exception handler.
+    Partial(&'a ExceptionStackFrame),
+}
+
+impl Registers<'_> {
+    fn get(&mut self, number: usize) -> Option<u64> {
+        match self {
+            Registers::Full(frame) => frame.register(number).map(|value| *value),
+            Registers::Partial(frame) => match number {
+                7 => Some(frame.stack_pointer()),
+                16 => Some(frame.instruction_pointer()),
+                17 => Some(frame.cpu_flags()),
+                18 => Some(frame.code_segment()),
+                19 => Some(frame.stack_segment()),
+                _ => None,
+            },
+        }
+    }
+
+    fn set(&mut self, number: usize, value: u64) -> bool {
+        match self {
+            Registers::Full(frame) => frame.register(number).map(|register| *register = value).is_some(),
+            Registers::Partial(_) => false,
+        }
+    }
+}
+
+fn register_size(number: usize) -> usize {
+    if number <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    Step,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+struct Stub {
+    port: SerialPort,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+static STUB: SpinLock<Stub> = SpinLock::new(Stub {
+    port: unsafe { SerialPort::new(COM2) },
+    breakpoints: [None; MAX_BREAKPOINTS],
+    packet: [0; PACKET_SIZE],
+    reply: Reply::new(),
+});
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+impl Link for SerialPort {
+    fn read_byte(&mut self) -> u8 {
+        self.receive()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.send_raw(byte);
+    }
+}
+
+fn read_memory(address: u64, bytes: &mut [u8]) -> bool {
+    if !(0..bytes.len() as u64).all(|offset| memory::is_mapped(address + offset)) {
+        return false;
+    }
+    for (offset, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { ((address + offset as u64) as *const u8).read_volatile() };
+    }
+    true
+}
+
+/// Writes read-only pages as well, kernel code is where breakpoints go.
+fn write_memory(address: u64, bytes: &[u8]) -> bool {
+    if !(0..bytes.len() as u64).all(|offset| memory::is_mapped(address + offset)) {
+        return false;
+    }
+    without_interrupts(|| unsafe {
+        let write_protect = Cr0::read().contains(Cr0Flags::WRITE_PROTECT);
+        Cr0::update(|flags| flags.remove(Cr0Flags::WRITE_PROTECT));
+        for (offset, &byte) in bytes.iter().enumerate() {
+            ((address + offset as u64) as *mut u8).write_volatile(byte);
+        }
+        if write_protect {
+            Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        }
+    });
+    true
+}
+
+fn insert_breakpoint(breakpoints: &mut [Option<Breakpoint>], address: u64) -> bool {
+    if breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == address) {
+        return true;
+    }
+    let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    let mut original = [0];
+    if !read_memory(address, &mut original) || !write_memory(address, &[INT3]) {
+        return false;
+    }
+    *slot = Some(Breakpoint { address, original: original[0] });
+    true
+}
+
+fn remove_breakpoint(breakpoints: &mut [Option<Breakpoint>], address: u64) -> bool {
+    let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_some_and(|breakpoint| breakpoint.address == address)) else {
+        return false;
+    };
+    let breakpoint = slot.take().unwrap();
+    write_memory(breakpoint.address, &[breakpoint.original])
+}
+
+/// Answers one packet into `reply`; a command that resumes the CPU returns how.
+fn handle(
+    command: &[u8],
+    registers: &mut Registers,
+    signal: i32,
+    breakpoints: &mut [Option<Breakpoint>],
+    reply: &mut Reply,
+) -> Option<Resume> {
+    reply.clear();
+    let (&kind, arguments) = command.split_first()?;
+    let split = |separator| {
+        let at = arguments.iter().position(|&byte| byte == separator)?;
+        Some((&arguments[..at], &arguments[at + 1..]))
+    };
+    match kind {
+        b'?' => {
+            reply.push(b"S");
+            reply.push_hex(&[signal as u8]);
+        }
+        b'g' => {
+            for number in 0..REGISTER_COUNT {
+                match registers.get(number) {
+                    Some(value) => reply.push_value(value, register_size(number)),
+                    None => reply.push_unavailable(register_size(number)),
+                }
+            }
+        }
+        b'G' => {
+            let mut text = arguments;
+            let mut written = true;
+            for number in 0..REGISTER_COUNT {
+                let len = register_size(number) * 2;
+                let Some(value) = text.get(..len) else {
+                    break;
+                };
+                text = &text[len..];
+                let mut bytes = [0; 8];
+                // unavailable registers come back as x's
+                if decode_hex(value, &mut bytes).is_some() {
+                    written &= registers.set(number, u64::from_le_bytes(bytes));
+                }
+            }
+            reply.push(if written { b"OK" } else { b"E01" });
+        }
+        b'p' => match parse_hex(arguments) {
+            Some(number) if (number as usize) < REGISTER_COUNT => {
+                let number = number as usize;
+                match registers.get(number) {
+                    Some(value) => reply.push_value(value, register_size(number)),
+                    None => reply.push_unavailable(register_size(number)),
+                }
+            }
+            _ => reply.push(b"E01"),
+        },
+        b'P' => {
+            let mut bytes = [0; 8];
+            let written = split(b'=').and_then(|(number, value)| {
+                let number = parse_hex(number)? as usize;
+                decode_hex(value, &mut bytes)?;
+                Some(registers.set(number, u64::from_le_bytes(bytes)))
+            });
+            reply.push(if written == Some(true) { b"OK" } else { b"E01" });
+        }
+        b'm' => {
+            let mut bytes = [0; PACKET_SIZE / 2];
+            let range = split(b',').and_then(|(address, len)| Some((parse_hex(address)?, parse_hex(len)? as usize)));
+            match range {
+                Some((address, len)) if read_memory(address, &mut bytes[..len.min(PACKET_SIZE / 2)]) => {
+                    reply.push_hex(&bytes[..len.min(PACKET_SIZE / 2)]);
+                }
+                // EFAULT
+                _ => reply.push(b"E0e"),
+            }
+        }
+        b'M' => {
+            let mut bytes = [0; PACKET_SIZE / 2];
+            let written = split(b':').and_then(|(range, data)| {
+                let at = range.iter().position(|&byte| byte == b',')?;
+                let address = parse_hex(&range[..at])?;
+                let len = decode_hex(data, &mut bytes)?;
+                (parse_hex(&range[at + 1..])? == len as u64).then_some(write_memory(address, &bytes[..len]))
+            });
+            reply.push(if written == Some(true) { b"OK" } else { b"E0e" });
+        }
+        // software breakpoints only, "0,address,kind"
+        b'Z' | b'z' if arguments.starts_with(b"0,") => {
+            let address = arguments[2..].split(|&byte| byte == b',').next().and_then(parse_hex);
+            let done = match address {
+                Some(address) if kind == b'Z' => insert_breakpoint(breakpoints, address),
+                Some(address) => remove_breakpoint(breakpoints, address),
+                None => false,
+            };
+            reply.push(if done { b"OK" } else { b"E01" });
+        }
+        b'c' | b's' => {
+            let resume = if kind == b'c' { Resume::Continue } else { Resume::Step };
+            if let Some(address) = parse_hex(arguments) {
+                registers.set(16, address);
+            }
+            // stepping takes the trap flag, which only the debug entries can set
+            if resume == Resume::Step && matches!(registers, Registers::Partial(_)) {
+                reply.push(b"E01");
+                return None;
+            }
+            return Some(resume);
+        }
+        b'D' | b'k' => {
+            for slot in breakpoints.iter_mut() {
+                if let Some(breakpoint) = slot.take() {
+                    write_memory(breakpoint.address, &[breakpoint.original]);
+                }
+            }
+            if kind == b'D' {
+                reply.push(b"OK");
+            }
+            return Some(Resume::Continue);
+        }
+        b'q' if command.starts_with(b"qSupported") => reply.push(b"PacketSize=1000"),
+        b'q' if command == b"qAttached" => reply.push(b"1"),
+        b'H' | b'T' => reply.push(b"OK"),
+        // anything else is not supported, which an empty reply says
+        _ => {}
+    }
+    None
+}
+
+impl Stub {
+    /// Reports the stop and answers GDB until it resumes the CPU.
+    fn serve(&mut self, registers: &mut Registers, signal: i32) -> Resume {
+        self.reply.clear();
+        self.reply.push(b"S");
+        self.reply.push_hex(&[signal as u8]);
+        packet::send(&mut self.port, self.reply.as_bytes());
+        loop {
+            let len = packet::receive(&mut self.port, &mut self.packet);
+            let resume = handle(&self.packet[..len], registers, signal, &mut self.breakpoints, &mut self.reply);
+            if resume.is_none() || !self.reply.as_bytes().is_empty() {
+                packet::send(&mut self.port, self.reply.as_bytes());
+            }
+            if let Some(resume) = resume {
+                return resume;
+            }
+        }
+    }
+}
+
+/// Called from the debug trap entries.
+extern "C" fn trap(frame: &mut TrapFrame, vector: u64) {
+    if vector == DEBUG_VECTOR {
+        frame.rflags &= !TRAP_FLAG;
+    }
+    if !is_enabled() {
+        if vector == BREAKPOINT_VECTOR {
+            interrupts::breakpoint_exception(frame.stack_frame());
+        }
+        return;
+    }
+    // a fault in the stub itself is left to the usual handlers
+    let Some(mut stub) = STUB.try_lock() else {
+        return;
+    };
+    // past the int3 of a breakpoint GDB set, which it wants to see at the int3
+    if vector == BREAKPOINT_VECTOR && stub.breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == frame.rip - 1) {
+        frame.rip -= 1;
+    }
+    if stub.serve(&mut Registers::Full(frame), SIGTRAP) == Resume::Step {
+        frame.rflags |= TRAP_FLAG;
+    }
+}
+
+/// Stops in the debugger at an exception the kernel caused, before it is handled
+/// as usual; nothing while no debugger is set up.
+pub fn exception(signal: i32, stack_frame: &ExceptionStackFrame) {
+    if !is_enabled() {
+        return;
+    }
+    if let Some(mut stub) = STUB.try_lock() {
+        stub.serve(&mut Registers::Partial(stack_frame), signal);
+    }
+}
+
+macro_rules! trap_entry {
+    ($name: ident, $vector: expr) => {
+        #[naked]
+        pub(crate) extern "C" fn $name() -> ! {
+            unsafe {
+                asm!(
+                    "test byte ptr [rsp + 8], 3",
+                    "jz 2f",
+                    "swapgs",
+                    "2:",
+                    "push rax",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rsi",
+                    "push rdi",
+                    "push rbp",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+
+                    // twenty words below an aligned top, the call sees a 16 byte aligned stack
+                    "mov rdi, rsp",
+                    "mov esi, {vector}",
+                    "call {trap}",
+
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rbp",
+                    "pop rdi",
+                    "pop rsi",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "pop rax",
+                    "test byte ptr [rsp + 8], 3",
+                    "jz 3f",
+                    "swapgs",
+                    "3:",
+                    "iretq",
+                    vector = const $vector,
+                    trap = sym trap,
+                    options(noreturn)
+                );
+            }
+        }
+    };
+}
+
+trap_entry!(debug_entry, DEBUG_VECTOR);
+trap_entry!(breakpoint_entry, BREAKPOINT_VECTOR);
+
+/// Lets exceptions stop in the debugger from now on.
+pub fn enable() {
+    STUB.lock().port.init();
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Stops here in the debugger, or only logs it while none is set up.
+pub fn breakpoint() {
+    unsafe { asm!("int3", options(nomem, nostack)) };
+}
+
+/// With `gdb` on the command line, waits at boot for GDB to attach.
+pub fn init() {
+    if cmdline::get("gdb").is_none() {
+        return;
+    }
+    enable();
+    log::info!("waiting for GDB on COM2");
+    breakpoint();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    static mut TARGET: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn run(command: &[u8], registers: &mut Registers, breakpoints: &mut [Option<Breakpoint>]) -> (Option<Resume>, Box<Reply>) {
+        let mut reply = Box::new(Reply::new());
+        let resume = handle(command, registers, SIGTRAP, breakpoints, &mut reply);
+        (resume, reply)
+    }
+
+    #[test_case]
+    fn test_registers() {
+        let mut frame = TrapFrame { rax: 0x1122, rip: 0xffff_8000_0000_1000, rflags: 0x202, ..TrapFrame::default() };
+        let mut breakpoints = [None; 4];
+        {
+            let mut registers = Registers::Full(&mut frame);
+            let (_, reply) = run(b"g", &mut registers, &mut breakpoints);
+            assert_eq!(&reply.as_bytes()[..16], b"2211000000000000");
+            assert_eq!(reply.as_bytes().len(), (17 * 8 + 7 * 4) * 2);
+            assert_eq!(run(b"p10", &mut registers, &mut breakpoints).1.as_bytes(), b"001000000080ffff");
+            assert_eq!(run(b"P1=0300000000000000", &mut registers, &mut breakpoints).1.as_bytes(), b"OK");
+            assert_eq!(run(b"?", &mut registers, &mut breakpoints).1.as_bytes(), b"S05");
+            assert_eq!(run(b"s", &mut registers, &mut breakpoints).0, Some(Resume::Step));
+            assert_eq!(run(b"vMustReplyEmpty", &mut registers, &mut breakpoints).1.as_bytes(), b"");
+        }
+        assert_eq!(frame.rbx, 3);
+
+        // an exception shows what the CPU pushed and no more
+        let stack_frame = frame.stack_frame();
+        let mut registers = Registers::Partial(stack_frame);
+        let (_, reply) = run(b"p0", &mut registers, &mut breakpoints);
+        assert_eq!(reply.as_bytes(), b"xxxxxxxxxxxxxxxx");
+        assert_eq!(run(b"P0=0100000000000000", &mut registers, &mut breakpoints).1.as_bytes(), b"E01");
+        assert_eq!(run(b"s", &mut registers, &mut breakpoints).0, None);
+    }
+
+    #[test_case]
+    fn test_memory_and_breakpoints() {
+        let address = unsafe { core::ptr::addr_of!(TARGET) } as u64;
+        let mut frame = TrapFrame::default();
+        let mut registers = Registers::Full(&mut frame);
+        let mut breakpoints = [None; 1];
+        let mut command = alloc::format!("m{:x},4", address);
+        assert_eq!(run(command.as_bytes(), &mut registers, &mut breakpoints).1.as_bytes(), b"01020304");
+        command = alloc::format!("M{:x},2:aabb", address + 1);
+        assert_eq!(run(command.as_bytes(), &mut registers, &mut breakpoints).1.as_bytes(), b"OK");
+        assert_eq!(run(b"m0,4", &mut registers, &mut breakpoints).1.as_bytes(), b"E0e");
+
+        command = alloc::format!("Z0,{:x},1", address + 4);
+        assert_eq!(run(command.as_bytes(), &mut registers, &mut breakpoints).1.as_bytes(), b"OK");
+        assert_eq!(unsafe { TARGET }, [1, 0xaa, 0xbb, 4, INT3, 6, 7, 8]);
+        // no room for a second one
+        command = alloc::format!("Z0,{:x},1", address + 5);
+        assert_eq!(run(command.as_bytes(), &mut registers, &mut breakpoints).1.as_bytes(), b"E01");
+        command = alloc::format!("z0,{:x},1", address + 4);
+        assert_eq!(run(command.as_bytes(), &mut registers, &mut breakpoints).1.as_bytes(), b"OK");
+        assert_eq!(unsafe { TARGET }[4], 5);
+    }
+}
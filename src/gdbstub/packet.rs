@@ -0,0 +1,195 @@
+/* the framing of the GDB remote serial protocol.
+    A packet is `$payload#cc`, cc being the sum of the payload bytes modulo 256 in
+    two hex digits; the receiver answers `+`, or `-` to have it sent again. Buffers
+    are fixed, the stub runs where allocating could deadlock.
+ */
+
+/// Largest payload either way, told to GDB in `qSupported`.
+pub const PACKET_SIZE: usize = 4096;
+
+/// Where packets travel, the serial port or a test buffer.
+pub trait Link {
+    fn read_byte(&mut self) -> u8;
+    fn write_byte(&mut self, byte: u8);
+}
+
+pub fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A number in big endian hex, as addresses and lengths are sent.
+pub fn parse_hex(text: &[u8]) -> Option<u64> {
+    if text.is_empty() || text.len() > 16 {
+        return None;
+    }
+    text.iter().try_fold(0u64, |value, &byte| Some(value << 4 | hex_digit(byte)? as u64))
+}
+
+/// Bytes sent as pairs of hex digits, as memory and register contents are.
+pub fn decode_hex(text: &[u8], bytes: &mut [u8]) -> Option<usize> {
+    if text.len() & 1 != 0 || text.len() / 2 > bytes.len() {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(text.chunks(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(text.len() / 2)
+}
+
+/// Reads the next packet into `buffer`, acknowledging it; returns the payload length.
+pub fn receive(link: &mut impl Link, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        // anything between packets, acknowledgements and interrupt requests, is skipped
+        while link.read_byte() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        let mut overflow = false;
+        loop {
+            match link.read_byte() {
+                b'#' => break,
+                byte if len < PACKET_SIZE => {
+                    buffer[len] = byte;
+                    len += 1;
+                    sum = sum.wrapping_add(byte);
+                }
+                _ => overflow = true,
+            }
+        }
+        let checksum = hex_digit(link.read_byte()).zip(hex_digit(link.read_byte()));
+        if !overflow && checksum.is_some_and(|(high, low)| high << 4 | low == sum) {
+            link.write_byte(b'+');
+            return len;
+        }
+        link.write_byte(b'-');
+    }
+}
+
+/// Sends `payload` until GDB acknowledges it.
+pub fn send(link: &mut impl Link, payload: &[u8]) {
+    let sum = payload.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    loop {
+        link.write_byte(b'$');
+        for &byte in payload {
+            link.write_byte(byte);
+        }
+        link.write_byte(b'#');
+        link.write_byte(HEX[(sum >> 4) as usize]);
+        link.write_byte(HEX[(sum & 0xf) as usize]);
+        if link.read_byte() != b'-' {
+            return;
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// A reply being put together, what does not fit is dropped.
+pub struct Reply {
+    bytes: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    pub const fn new() -> Self {
+        Reply { bytes: [0; PACKET_SIZE], len: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn push(&mut self, text: &[u8]) {
+        let count = text.len().min(PACKET_SIZE - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&text[..count]);
+        self.len += count;
+    }
+
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[HEX[(byte >> 4) as usize], HEX[(byte & 0xf) as usize]]);
+        }
+    }
+
+    /// `size` bytes of `value`, in the target's byte order, little endian.
+    pub fn push_value(&mut self, value: u64, size: usize) {
+        self.push_hex(&value.to_le_bytes()[..size]);
+    }
+
+    /// A register GDB cannot have, `size` bytes long.
+    pub fn push_unavailable(&mut self, size: usize) {
+        for _ in 0..size * 2 {
+            self.push(b"x");
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Default for Reply {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+
+    struct TestLink {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Link for TestLink {
+        fn read_byte(&mut self) -> u8 {
+            self.input.pop_front().expect("read past the input")
+        }
+
+        fn write_byte(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    #[test_case]
+    fn test_hex() {
+        assert_eq!(parse_hex(b"ffffffff8000a0"), Some(0xffffffff8000a0));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12g"), None);
+        let mut bytes = [0; 4];
+        assert_eq!(decode_hex(b"deadBEEF", &mut bytes), Some(4));
+        assert_eq!(bytes, [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(decode_hex(b"abc", &mut bytes), None);
+
+        let mut reply = Box::new(Reply::new());
+        reply.push(b"S");
+        reply.push_value(0x1234, 2);
+        reply.push_unavailable(1);
+        assert_eq!(reply.as_bytes(), b"S3412xx");
+    }
+
+    #[test_case]
+    fn test_framing() {
+        // a corrupted packet is refused, the resent one taken
+        let input = b"+$m10,4#00$m10,4#2e";
+        let mut link = TestLink { input: input.iter().copied().collect(), output: Vec::new() };
+        let mut buffer = Box::new([0; PACKET_SIZE]);
+        let len = receive(&mut link, &mut buffer);
+        assert_eq!(&buffer[..len], b"m10,4");
+        assert_eq!(link.output, b"-+");
+
+        link.output.clear();
+        link.input.extend(b"-+");
+        send(&mut link, b"OK");
+        assert_eq!(link.output, b"$OK#9a$OK#9a");
+    }
+}
@@ -1,52 +1,117 @@
-use core::ptr::addr_of_mut;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 
-use x86_64::registers::segmentation::CS;
+use x86_64::registers::segmentation::{CS, DS, ES, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{Mapper, Page, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::{self, PAGE_SIZE};
+use crate::stack_canary;
+
 
 pub enum ISTIndex {
     _Default            = 0x0,
     DoubleFaultISTIndex = 0x1,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[ISTIndex::DoubleFaultISTIndex as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { addr_of_mut!(STACK) });
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
-        tss
-    };
+// mutable, rsp0 has to point at the kernel stack of whichever thread runs next
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+const DOUBLE_FAULT_STACK_WORDS: usize = 4096 * 5 / 8;
+
+/// An IST stack, with a page below it that `guard_ist_stacks` unmaps.
+#[repr(C, align(4096))]
+struct IstStack {
+    guard: [u8; PAGE_SIZE as usize],
+    stack: [u64; DOUBLE_FAULT_STACK_WORDS],
+}
+
+static mut DOUBLE_FAULT_STACK: IstStack = IstStack { guard: [0; PAGE_SIZE as usize], stack: [0; DOUBLE_FAULT_STACK_WORDS] };
+
+fn init_tss() {
+    let stack_start = VirtAddr::from_ptr(unsafe { addr_of_mut!(DOUBLE_FAULT_STACK.stack) });
+    let stack_end = stack_start + (DOUBLE_FAULT_STACK_WORDS * 8) as u64;
+    unsafe {
+        stack_canary::plant(stack_start.as_u64());
+        (*addr_of_mut!(TSS)).interrupt_stack_table[ISTIndex::DoubleFaultISTIndex as usize] = stack_end;
+    }
 }
 
+/// Panics when the double fault handler overflowed its stack, checked on every timer tick.
+pub fn check_stack_canary() {
+    let bottom = unsafe { addr_of!(DOUBLE_FAULT_STACK.stack) } as u64;
+    if !unsafe { stack_canary::is_intact(bottom) } {
+        panic!("kernel stack overflow: the canary of the double fault stack is smashed");
+    }
+}
+
+/// The page below the double fault stack.
+pub fn double_fault_guard_page() -> u64 {
+    unsafe { addr_of!(DOUBLE_FAULT_STACK.guard) as u64 }
+}
+
+/// Unmaps the guard page below the double fault stack, so a handler overflowing it
+/// faults instead of writing over the statics below; call once memory is set up.
+pub fn guard_ist_stacks() {
+    let guard = Page::<Size4KiB>::containing_address(VirtAddr::new(double_fault_guard_page()));
+    // the frame is part of the kernel image, not the allocator's to take back
+    match unsafe { memory::active_page_table() }.unmap(guard) {
+        Ok((_, flush)) => flush.flush(),
+        Err(error) => log::warn!("double fault stack left without a guard page: {:?}", error),
+    }
+}
+
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
+/* segment order.
+    `sysret` derives the user selectors from a single base: user data right after
+    it and user code after that, so the user data segment has to come first.
+ */
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
+        let data_selector = gdt.append(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) });
+        // the syscall entry stub pushes these as constants
+        assert_eq!(user_data_selector.0, USER_DATA_SELECTOR);
+        assert_eq!(user_code_selector.0, USER_CODE_SELECTOR);
+        (gdt, Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector })
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::Segment;
+    init_tss();
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Stack the CPU switches to when an interrupt or exception arrives from ring 3.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = top };
+}
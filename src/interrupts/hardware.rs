@@ -1,15 +1,15 @@
-use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 
 use crate::interrupts::ExceptionStackFrame;
-use crate::print;
+use crate::percpu;
+use crate::sync::SpinLock;
 
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinLock<ChainedPics> =
+    SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -19,37 +19,31 @@ pub enum InterruptIndex {
 }
 
 
-pub extern "C" fn timer_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
-    print!(".");
+pub extern "C" fn timer_interrupt_handler(stack_frame: &ExceptionStackFrame) {
+    {
+        // not held across the preemption below, the depth belongs to the CPU, not the thread
+        let _irq = percpu::enter_interrupt(InterruptIndex::Timer.as_u8());
+        let now = crate::time::tick();
+        crate::watchdog::check(now, stack_frame);
+        crate::profile::sample(stack_frame);
+        crate::gdt::check_stack_canary();
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        }
     }
+
+    crate::thread::preempt();
 }
 
 pub extern "C" fn keyboard_interrupt_hander(_stack_frame: &ExceptionStackFrame) {
     use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore));
-    }
 
+    let _irq = percpu::enter_interrupt(InterruptIndex::Keyboard.as_u8());
     let mut port = Port::new(0x60);
     let scan_code: u8 = unsafe { port.read() };
-    let mut keyboard = KEYBOARD.lock();
-
-    if let Ok(Some(key_event)) = keyboard.add_byte(scan_code) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(char) => print!("{}", char),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+    crate::task::keyboard::add_scancode(scan_code);
 
     unsafe {
         PICS.lock()
@@ -5,10 +5,11 @@ use x86_64::{PrivilegeLevel, VirtAddr};
 
 use bit_field::BitField;
 use crate::interrupts::hardware::InterruptIndex;
+use crate::smp::IpiVector;
 
 type HandlerWrapper = extern "C" fn() -> !;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 #[repr(u8)]
 pub enum CpuExceptionIndex {
@@ -42,13 +43,20 @@ impl CpuExceptionIndex {
 pub enum IdtIndex {
     CpuException(CpuExceptionIndex),
     Interrupt(InterruptIndex),
+    Ipi(IpiVector),
+    Syscall,
 }
 
+pub const SYSCALL_VECTOR: u8 = 0x80;
+const IDT_ENTRIES: usize = 256;
+
 impl IdtIndex {
     pub fn as_u8(self) -> u8 {
         match self {
             IdtIndex::CpuException(cpu_exception_index) => cpu_exception_index.as_u8(),
             IdtIndex::Interrupt(interrupt_index) => interrupt_index.as_u8(),
+            IdtIndex::Ipi(vector) => vector.as_u8(),
+            IdtIndex::Syscall => SYSCALL_VECTOR,
         }
     }
 
@@ -58,12 +66,12 @@ impl IdtIndex {
 
 }
 
-#[derive(Debug)]
-pub struct Idt([Entry; 64]);
+#[derive(Debug, Clone)]
+pub struct Idt([Entry; IDT_ENTRIES]);
 
 impl Idt {
     pub fn new() -> Self {
-        Idt([Entry::missing(); 64])
+        Idt([Entry::missing(); IDT_ENTRIES])
     }
 
     pub fn set_handler(&mut self, entry: IdtIndex, handler_func: HandlerWrapper) -> &mut EntryOptions {
@@ -194,6 +202,7 @@ mod test {
 
         // interrupts
         assert_eq!(IdtIndex::Interrupt(InterruptIndex::Timer).as_u8(), 32);
+        assert_eq!(IdtIndex::Syscall.as_u8(), 0x80);
     }
 
 }
\ No newline at end of file
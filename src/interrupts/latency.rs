@@ -0,0 +1,251 @@
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::time::tsc;
+use crate::{cmdline, percpu};
+
+/* interrupt latency.
+    With `irqlatency` on the command line the `handler!` wrapper reads the TSC as
+    soon as it has registers to spare and leaves it in the CPU's block; the handler
+    picks it up when it starts counting itself as one, with `percpu::enter_interrupt`
+    or `measure`, and the time from the wrapper to the end of the handler goes into
+    a histogram of its vector. Buckets are a quarter of a power of two wide, so the
+    99th percentile is an upper bound, at most a fifth above the real one.
+    /proc/irqlatency lists every vector seen.
+ */
+
+// values below 4 get a bucket of their own, then four per power of two
+const SUB_BITS: u32 = 2;
+const BUCKETS: usize = 160;
+const VECTORS: usize = 256;
+
+/// Read by the `handler!` wrapper, which skips the timestamp while it is clear.
+pub static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct VectorLatency {
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+    buckets: [AtomicU32; BUCKETS],
+}
+
+static VECTOR_LATENCY: [VectorLatency; VECTORS] = [const { VectorLatency::new() }; VECTORS];
+
+impl VectorLatency {
+    const fn new() -> Self {
+        VectorLatency {
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            buckets: [const { AtomicU32::new(0) }; BUCKETS],
+        }
+    }
+
+    fn add(&self, cycles: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(cycles, Ordering::Relaxed);
+        self.max.fetch_max(cycles, Ordering::Relaxed);
+        self.buckets[bucket(cycles)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The smallest bound at least `percent` of the samples stay under.
+    fn percentile(&self, percent: u64) -> u64 {
+        let count = self.count.load(Ordering::Relaxed);
+        let wanted = (count * percent).div_ceil(100);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed) as u64;
+            if seen >= wanted {
+                return bucket_bound(index).min(self.max.load(Ordering::Relaxed));
+            }
+        }
+        self.max.load(Ordering::Relaxed)
+    }
+
+    fn clear(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn bucket(cycles: u64) -> usize {
+    if cycles < 1 << SUB_BITS {
+        return cycles as usize;
+    }
+    let octave = 63 - cycles.leading_zeros();
+    let sub = (cycles >> (octave - SUB_BITS)) & ((1 << SUB_BITS) - 1);
+    ((((octave - SUB_BITS + 1) as u64) << SUB_BITS) + sub).min(BUCKETS as u64 - 1) as usize
+}
+
+/// The largest value in `bucket`.
+fn bucket_bound(bucket: usize) -> u64 {
+    if bucket < 1 << SUB_BITS {
+        return bucket as u64;
+    }
+    if bucket == BUCKETS - 1 {
+        return u64::MAX;
+    }
+    let octave = (bucket >> SUB_BITS) as u32 + SUB_BITS - 1;
+    let sub = (bucket & ((1 << SUB_BITS) - 1)) as u64;
+    let width = 1 << (octave - SUB_BITS);
+    (((1 << SUB_BITS) + sub) << (octave - SUB_BITS)) + width - 1
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Starts measuring, call on the boot CPU once its per-CPU block is set up.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Forgets the latencies measured so far.
+pub fn reset() {
+    for vector in &VECTOR_LATENCY {
+        vector.clear();
+    }
+}
+
+/// Turns measuring on when the command line asks for it.
+pub fn init() {
+    if cmdline::flag("irqlatency") {
+        enable();
+    }
+}
+
+/// The time the wrapper of the running interrupt entered, 0 when it was not measured.
+pub(crate) fn take_entry() -> u64 {
+    if !is_enabled() {
+        return 0;
+    }
+    percpu!(irq_entry_tsc).swap(0, Ordering::Relaxed)
+}
+
+/// Counts the time from `entry`, what `take_entry` returned, to now against `vector`.
+pub(crate) fn record(vector: u8, entry: u64) {
+    if entry != 0 {
+        VECTOR_LATENCY[vector as usize].add(tsc::read().saturating_sub(entry));
+    }
+}
+
+/// Measures interrupt `vector` until dropped, for handlers that do not count
+/// themselves with `percpu::enter_interrupt`.
+pub fn measure(vector: u8) -> Measured {
+    Measured { vector, entry: take_entry() }
+}
+
+pub struct Measured {
+    vector: u8,
+    entry: u64,
+}
+
+impl Drop for Measured {
+    fn drop(&mut self) {
+        record(self.vector, self.entry);
+    }
+}
+
+/// What was measured for one vector, in cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    pub vector: u8,
+    pub count: u64,
+    pub mean: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// Every vector measured at least once.
+pub fn latencies() -> impl Iterator<Item = Latency> {
+    VECTOR_LATENCY.iter().enumerate().filter_map(|(vector, latency)| {
+        let count = latency.count.load(Ordering::Relaxed);
+        (count > 0).then(|| Latency {
+            vector: vector as u8,
+            count,
+            mean: latency.total.load(Ordering::Relaxed) / count,
+            p99: latency.percentile(99),
+            max: latency.max.load(Ordering::Relaxed),
+        })
+    })
+}
+
+struct Micros(u64);
+
+impl core::fmt::Display for Micros {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        // hundredths, without floating point
+        let hundredths = tsc::cycles_to_duration(self.0).as_nanos() as u64 / 10;
+        let text = alloc::format!("{}.{:02}", hundredths / 100, hundredths % 100);
+        f.pad(&text)
+    }
+}
+
+/// The latencies in microseconds, for /proc/irqlatency.
+pub fn report() -> String {
+    let mut text = String::from("vector      count    mean us     p99 us     max us\n");
+    for latency in latencies() {
+        let _ = writeln!(
+            text,
+            "{:>6} {:>10} {:>10} {:>10} {:>10}",
+            latency.vector, latency.count, Micros(latency.mean), Micros(latency.p99), Micros(latency.max),
+        );
+    }
+    if !is_enabled() {
+        text.push_str("off, boot with irqlatency\n");
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interrupts::hardware::InterruptIndex;
+
+    #[test_case]
+    fn test_buckets() {
+        for cycles in [0, 3, 4, 7, 8, 9, 100, 1000, 123_456, 1 << 30] {
+            let bucket = bucket(cycles);
+            assert!(cycles <= bucket_bound(bucket), "{} in bucket {}", cycles, bucket);
+            assert!(bucket == 0 || cycles > bucket_bound(bucket - 1), "{} in bucket {}", cycles, bucket);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+
+        let latency = VectorLatency::new();
+        for cycles in 1..=1000 {
+            latency.add(cycles);
+        }
+        let p99 = latency.percentile(99);
+        assert!((990..=1000).contains(&p99), "{}", p99);
+        assert_eq!(latency.percentile(100), 1000);
+    }
+
+    #[test_case]
+    fn test_timer_latency() {
+        let was_enabled = is_enabled();
+        enable();
+        let timer = InterruptIndex::Timer.as_u8();
+        let count = || latencies().find(|latency| latency.vector == timer).map_or(0, |latency| latency.count);
+        let before = count();
+        let start = crate::time::ticks();
+        while crate::time::ticks() < start + 3 {
+            core::hint::spin_loop();
+        }
+        if !was_enabled {
+            disable();
+        }
+        assert!(count() > before);
+        let timer = latencies().find(|latency| latency.vector == timer).unwrap();
+        assert!(timer.mean <= timer.max && timer.p99 <= timer.max);
+        assert!(report().lines().any(|line| line.trim_start().starts_with("32 ")));
+    }
+}
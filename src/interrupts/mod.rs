@@ -1,20 +1,35 @@
 
 pub mod idt;
 pub mod hardware;
+pub mod latency;
 mod page_fault;
 mod cpu_flags;
 
 use core::arch::asm;
 use core::fmt::Formatter;
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 
 use crate::interrupts::idt::{CpuExceptionIndex, Idt, IdtIndex};
 use crate::interrupts::page_fault::PageFaultErrorCode;
-use crate::gdt;
+use crate::backtrace::Backtrace;
+use crate::testing::{self, Expected};
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+use crate::{gdbstub, gdt};
+#[cfg(feature = "shell")]
+use crate::percpu;
 use crate::interrupts::cpu_flags::CpuFlags;
 use crate::interrupts::hardware::{InterruptIndex, keyboard_interrupt_hander};
 use crate::interrupts::hardware::{timer_interrupt_handler};
-use crate::println;
+use crate::fpu::device_not_available_handler;
+use crate::process::user::{self, SIGFPE, SIGILL, SIGSEGV};
+use crate::smp::{
+    call_function_interrupt_handler, halt_interrupt_handler, reschedule_interrupt_handler,
+    spurious_interrupt_handler, tlb_shootdown_interrupt_handler, IpiVector,
+};
 
 #[repr(C)]
 pub struct ExceptionStackFrame {
@@ -25,6 +40,40 @@ pub struct ExceptionStackFrame {
     stack_segment: u64,
 }
 
+impl ExceptionStackFrame {
+    pub fn instruction_pointer(&self) -> u64 {
+        self.instruction_pointer
+    }
+
+    pub fn code_segment(&self) -> u64 {
+        self.code_segment
+    }
+
+    pub fn cpu_flags(&self) -> u64 {
+        self.cpu_flags
+    }
+
+    pub fn stack_pointer(&self) -> u64 {
+        self.stack_pointer
+    }
+
+    pub fn stack_segment(&self) -> u64 {
+        self.stack_segment
+    }
+
+    /// The interrupted code ran in ring 3.
+    pub fn is_user(&self) -> bool {
+        self.code_segment & 0b11 == 3
+    }
+
+    /// Makes iretq go to `rip` instead, for handlers abandoning the interrupted code.
+    pub(crate) fn resume_at(&mut self, rip: u64, stack_pointer: u64, cpu_flags: u64) {
+        self.instruction_pointer = rip;
+        self.stack_pointer = stack_pointer;
+        self.cpu_flags = cpu_flags;
+    }
+}
+
 impl core::fmt::Debug for ExceptionStackFrame {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("ExceptionStackFrame")
@@ -38,6 +87,12 @@ impl core::fmt::Debug for ExceptionStackFrame {
 }
 
 
+/* the entry of handlers without an error code.
+    Interrupts of kernel code save the scratch registers only. Coming from ring 3 all
+    registers are saved, laid out as a syscall frame, so that on the way back pending
+    signals can be delivered the way they are after a syscall, a handler's signal
+    frame holding everything the interrupted code had.
+ */
 #[macro_export]
 macro_rules! handler {
     ($name: ident) => {{
@@ -45,6 +100,9 @@ macro_rules! handler {
         extern "C" fn wrapper() -> ! {
             unsafe {
                 asm!(
+                    "test byte ptr [rsp + 8], 3",
+                    "jnz 5f",
+
                     // save scratch registers
                     "push rax",
                     "push rcx",
@@ -56,9 +114,25 @@ macro_rules! handler {
                     "push r10",
                     "push r11",
 
+                    // when the interrupt came in, while latency is measured
+                    "cmp byte ptr [rip + {latency}], 0",
+                    "je 4f",
+                    "rdtsc",
+                    "shl rdx, 32",
+                    "or rax, rdx",
+                    "mov gs:[{entry_tsc}], rax",
+                    "4:",
+
                     "mov rdi, rsp",
                     "add rdi, 72",
+
+                    // a frame record holding the interrupted rip, for backtraces to follow
+                    "push qword ptr [rdi]",
+                    "push rbp",
+                    "mov rbp, rsp",
                     "call {func}",
+                    "pop rbp",
+                    "add rsp, 8",
 
                     // restore scratch registers after func call
                     "pop r11",
@@ -71,8 +145,75 @@ macro_rules! handler {
                     "pop rcx",
                     "pop rax",
 
+                    "cli",
+                    "iretq",
+
+                    // coming from ring 3, the GS base is still the user's
+                    "5:",
+                    "swapgs",
+
+                    // save all registers, in the order of a syscall frame
+                    "push rax",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rsi",
+                    "push rdi",
+                    "push rbp",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+
+                    "cmp byte ptr [rip + {latency}], 0",
+                    "je 6f",
+                    "rdtsc",
+                    "shl rdx, 32",
+                    "or rax, rdx",
+                    "mov gs:[{entry_tsc}], rax",
+                    "6:",
+
+                    "mov rdi, rsp",
+                    "add rdi, 120",
+
+                    "push qword ptr [rdi]",
+                    "push rbp",
+                    "mov rbp, rsp",
+                    "call {func}",
+                    "pop rbp",
+                    "add rsp, 8",
+
+                    // twenty words below the aligned interrupt frame, as for a syscall
+                    "mov rdi, rsp",
+                    "call {deliver}",
+
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rbp",
+                    "pop rdi",
+                    "pop rsi",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "pop rax",
+
+                    "cli",
+                    "swapgs",
                     "iretq",
                     func = sym $name,
+                    deliver = sym $crate::process::signal::deliver_after_interrupt,
+                    latency = sym $crate::interrupts::latency::ENABLED,
+                    entry_tsc = const $crate::percpu::IRQ_ENTRY_TSC_OFFSET,
                     options(noreturn)
                 );
             }
@@ -88,6 +229,12 @@ macro_rules! handler_with_error_code {
         extern "C" fn wrapper() -> ! {
             unsafe {
                 asm!(
+                    // coming from ring 3, the GS base is still the user's
+                    "test byte ptr [rsp + 16], 3",
+                    "jz 2f",
+                    "swapgs",
+                    "2:",
+
                     // save scratch registers
                     "push rax",
                     "push rcx",
@@ -107,9 +254,14 @@ macro_rules! handler_with_error_code {
                     // It becomes not aligned again
                     "sub rsp, 8",
 
+                    // a frame record holding the interrupted rip, for backtraces to follow
+                    "push qword ptr [rdi]",
+                    "push rbp",
+                    "mov rbp, rsp",
                     "call {func}",
+                    "pop rbp",
 
-                    "add rsp, 8",
+                    "add rsp, 16",
 
                     // restore scratch registers after func call
                     "pop r11",
@@ -125,6 +277,11 @@ macro_rules! handler_with_error_code {
                     // remove error code from the stack.
                     // after that, rsp points to stack_frame which causes the error
                     "add rsp, 8",
+                    "cli",
+                    "test byte ptr [rsp + 8], 3",
+                    "jz 3f",
+                    "swapgs",
+                    "3:",
                     "iretq",
                     func = sym $name,
                     options(noreturn)
@@ -142,13 +299,28 @@ lazy_static! {
                 handler_with_error_code!(double_fault_handler))
             .set_stack_index(gdt::ISTIndex::DoubleFaultISTIndex as u16);
         idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::DivisionError), handler!(divide_by_zero_exception));
-        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::Breakpoint), handler!(breakpoint_exception));
+        // the debugger's entries, which hand breakpoints back here while it is off
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::Debug), gdbstub::debug_entry);
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::Breakpoint), gdbstub::breakpoint_entry);
         idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::InvalidOpcode), handler!(invalid_opcode_handler));
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::DeviceNotAvailable), handler!(device_not_available_handler));
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::GeneralProtectionFault),
+                handler_with_error_code!(general_protection_fault_handler));
         idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::PageFault), handler_with_error_code!(page_fault_handler));
 
+        // reachable from ring 3
+        idt.set_handler(IdtIndex::Syscall, crate::syscall::entry::int80_entry).set_privilege_level(3);
+
         // interrupts
         idt.set_handler(IdtIndex::Interrupt(InterruptIndex::Timer), handler!(timer_interrupt_handler));
         idt.set_handler(IdtIndex::Interrupt(InterruptIndex::Keyboard), handler!(keyboard_interrupt_hander));
+
+        // interprocessor interrupts
+        idt.set_handler(IdtIndex::Ipi(IpiVector::Reschedule), handler!(reschedule_interrupt_handler));
+        idt.set_handler(IdtIndex::Ipi(IpiVector::TlbShootdown), handler!(tlb_shootdown_interrupt_handler));
+        idt.set_handler(IdtIndex::Ipi(IpiVector::CallFunction), handler!(call_function_interrupt_handler));
+        idt.set_handler(IdtIndex::Ipi(IpiVector::Halt), handler!(halt_interrupt_handler));
+        idt.set_handler(IdtIndex::Ipi(IpiVector::Spurious), handler!(spurious_interrupt_handler));
         idt
     };
 }
@@ -157,30 +329,135 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "C" fn breakpoint_exception(stack_frame: &ExceptionStackFrame) {
-    println!("\nBREAKPOINT\n{:#?}", stack_frame);
+// interrupts taken on each vector, over all CPUs
+static VECTOR_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Counts one interrupt on `vector`, for the handlers of hardware interrupts and IPIs.
+pub(crate) fn count(vector: u8) {
+    VECTOR_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Interrupts taken so far on every vector taken at least once.
+pub fn counts() -> impl Iterator<Item = (u8, u64)> {
+    VECTOR_COUNTS.iter().enumerate()
+        .map(|(vector, count)| (vector as u8, count.load(Ordering::Relaxed)))
+        .filter(|&(_, count)| count > 0)
+}
+
+/// Registers `irqstat`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("irqstat", "interrupts taken, by CPU and by vector", irqstat);
+}
+
+#[cfg(feature = "shell")]
+fn irqstat(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("irqstat"));
+    }
+    let _ = writeln!(out, "cpu   interrupts       ipis   syscalls");
+    for cpu in percpu::cpus() {
+        let stats = &cpu.stats;
+        let _ = writeln!(
+            out,
+            "{:>3} {:>12} {:>10} {:>10}",
+            cpu.cpu_id, stats.interrupts.load(Ordering::Relaxed), stats.ipis.load(Ordering::Relaxed), stats.syscalls.load(Ordering::Relaxed),
+        );
+    }
+    let _ = writeln!(out, "vector      count");
+    for (vector, count) in counts() {
+        let _ = writeln!(out, "{:>6} {:>10}", vector, count);
+    }
+    if latency::is_enabled() {
+        let _ = write!(out, "{}", latency::report());
+    }
+    Ok(())
+}
+
+/// The kernel's table, for tables built on top of it.
+pub(crate) fn kernel_idt() -> &'static Idt {
+    &IDT
+}
+
+/// Does what the kernel's table does for `exception`, for tables that take over its vectors.
+pub(crate) fn handle_exception(exception: CpuExceptionIndex, stack_frame: &ExceptionStackFrame, error_code: u64) {
+    match exception {
+        CpuExceptionIndex::DivisionError => divide_by_zero_exception(stack_frame),
+        CpuExceptionIndex::InvalidOpcode => invalid_opcode_handler(stack_frame),
+        CpuExceptionIndex::GeneralProtectionFault => general_protection_fault_handler(stack_frame, error_code),
+        CpuExceptionIndex::PageFault => page_fault_handler(stack_frame, error_code),
+        CpuExceptionIndex::DoubleFault => double_fault_handler(stack_frame, error_code),
+        // the kernel has no handler for the rest, the CPU would triple fault
+        _ => {
+            if stack_frame.is_user() {
+                user::kill_on_fault("exception", stack_frame.instruction_pointer, SIGSEGV);
+            }
+            panic!("EXCEPTION: {:?}\nerror code: {:#x}\n{:#?}\n{}",
+                exception, error_code, stack_frame, Backtrace::from_exception(stack_frame));
+        }
+    }
+}
+
+pub(crate) extern "C" fn breakpoint_exception(stack_frame: &ExceptionStackFrame) {
+    log::info!("breakpoint\n{:#?}", stack_frame);
 }
 
 extern "C" fn divide_by_zero_exception(stack_frame: &ExceptionStackFrame) {
-    println!("\nEXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
+    if stack_frame.is_user() {
+        user::kill_on_fault("divide by zero", stack_frame.instruction_pointer, SIGFPE);
+    }
+    testing::failed(Expected::Fault(CpuExceptionIndex::DivisionError));
+    gdbstub::exception(SIGFPE, stack_frame);
+    log::error!("EXCEPTION: DIVIDE BY ZERO\n{:#?}\n{}", stack_frame, Backtrace::from_exception(stack_frame));
 }
 
 extern "C" fn invalid_opcode_handler(stack_frame: &ExceptionStackFrame) {
-    println!("\nEXCEPTION: INVALID OPCODE at {:#x}\n{:#?}",
-        stack_frame.instruction_pointer, stack_frame);
+    if stack_frame.is_user() {
+        user::kill_on_fault("invalid opcode", stack_frame.instruction_pointer, SIGILL);
+    }
+    testing::failed(Expected::Fault(CpuExceptionIndex::InvalidOpcode));
+    gdbstub::exception(SIGILL, stack_frame);
+    log::error!("EXCEPTION: INVALID OPCODE at {:#x}\n{:#?}\n{}",
+        stack_frame.instruction_pointer, stack_frame, Backtrace::from_exception(stack_frame));
+}
+
+extern "C" fn general_protection_fault_handler(stack_frame: &ExceptionStackFrame, error_code: u64) {
+    if stack_frame.is_user() {
+        user::kill_on_fault("general protection fault", stack_frame.instruction_pointer, SIGSEGV);
+    }
+    testing::failed(Expected::Fault(CpuExceptionIndex::GeneralProtectionFault));
+    gdbstub::exception(SIGSEGV, stack_frame);
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT\nerror code: {:#x}\n{:#?}\n{}",
+        error_code, stack_frame, Backtrace::from_exception(stack_frame));
 }
 
 extern "C" fn page_fault_handler(stack_frame: &ExceptionStackFrame, error_code: u64) {
     use x86_64::registers::control;
-    println!("\nEXCEPTION: PAGE FAULT while accessing {:#x}\
-        \nerror code: {:?}\n{:#?}",
+    crate::trace_event!(mm, "page fault at {:#x}, error {:#x}, rip {:#x}",
+        control::Cr2::read_raw(), error_code, stack_frame.instruction_pointer);
+    if stack_frame.is_user() {
+        let error = PageFaultErrorCode::from_bits_truncate(error_code);
+        let address = control::Cr2::read().unwrap();
+        if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+            && user::resolve_write_fault(address) {
+            return;
+        }
+        user::kill_on_fault("page fault", stack_frame.instruction_pointer, SIGSEGV);
+    }
+    testing::failed(Expected::Fault(CpuExceptionIndex::PageFault));
+    gdbstub::exception(SIGSEGV, stack_frame);
+    log::error!("EXCEPTION: PAGE FAULT while accessing {:#x}\
+        \nerror code: {:?}\n{:#?}\n{}",
         control::Cr2::read().unwrap(),
         PageFaultErrorCode::from_bits(error_code).unwrap(),
-        stack_frame);
+        stack_frame,
+        Backtrace::from_exception(stack_frame));
 }
 
 extern "C" fn double_fault_handler(stack_frame: &ExceptionStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    testing::failed(Expected::Fault(CpuExceptionIndex::DoubleFault));
+    gdbstub::exception(SIGSEGV, stack_frame);
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\n{}", stack_frame, Backtrace::from_exception(stack_frame));
 }
 
 #[cfg(test)]
@@ -190,12 +467,7 @@ mod test {
         x86_64::instructions::interrupts::int3();
     }
 
-    // The following two tests are commented out on purpose
-    // The instruction fails the CPU cannot get bypassed, causing the kernel into a dead loop
-
-    #[test_case]
-    fn test_divide_by_zero_exception() {
-        /*
+    crate::should_fault!(DivisionError, fn test_divide_by_zero_exception() {
         use core::arch::asm;
         unsafe {
             asm!(
@@ -205,12 +477,9 @@ mod test {
                 out("rax") _,
             );
         }
-         */
-    }
+    });
 
-    #[test_case]
-    #[allow(deref_nullptr)]
-    fn test_page_fault_exception() {
-        // unsafe { *(0x0 as *mut u64) = 1 };
-    }
+    crate::should_fault!(PageFault, fn test_page_fault_exception() {
+        unsafe { core::ptr::null_mut::<u64>().write_volatile(1) };
+    });
 }
\ No newline at end of file
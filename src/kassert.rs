@@ -0,0 +1,136 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::backtrace::Backtrace;
+use crate::dmesg::Uptime;
+use crate::logger::Context;
+use crate::time::tsc;
+use crate::{crash, exit_qemu, halt_loop, serial_println, smp, testing, QemuExitCode};
+
+/* kernel assertions.
+    `kassert!(cond)` and `kassert_eq!(left, right)`, with an optional message like
+    `assert!`, say where they failed, the values that did not hold, the thread and
+    CPU and the time since boot:
+        kassert failed at src/fs/fat32.rs:210: entry.cluster == cluster
+          left:  4093
+          right: 17
+          on cpu0 fat32-test, at     3.041721
+    In a test run they end it with `QemuExitCode::KernelAssertion` rather than
+    through the panic handler, which knows none of that, outside of one they panic.
+ */
+
+static TESTING: AtomicBool = AtomicBool::new(false);
+
+/// Called by the test runner, failed assertions end the run from now on.
+pub fn set_testing() {
+    TESTING.store(true, Ordering::Relaxed);
+}
+
+/// Panics with context if `$cond` is false, see the top of kassert.rs.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::kassert::failed(file!(), line!(), stringify!($cond), None, None);
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kassert::failed(file!(), line!(), stringify!($cond), None, Some(format_args!($($arg)+)));
+        }
+    };
+}
+
+/// Panics with context and both values if they differ, see the top of kassert.rs.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => if !(*left == *right) {
+                $crate::kassert::failed(file!(), line!(), concat!(stringify!($left), " == ", stringify!($right)),
+                    Some((left, right)), None);
+            }
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => if !(*left == *right) {
+                $crate::kassert::failed(file!(), line!(), concat!(stringify!($left), " == ", stringify!($right)),
+                    Some((left, right)), Some(format_args!($($arg)+)));
+            }
+        }
+    };
+}
+
+/// What a failed assertion reports.
+struct Failure<'a> {
+    file: &'static str,
+    line: u32,
+    condition: &'static str,
+    values: Option<(&'a dyn fmt::Debug, &'a dyn fmt::Debug)>,
+    message: Option<fmt::Arguments<'a>>,
+}
+
+impl fmt::Display for Failure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "kassert failed at {}:{}: {}", self.file, self.line, self.condition)?;
+        if let Some(message) = self.message {
+            write!(f, "\n  {}", message)?;
+        }
+        if let Some((left, right)) = self.values {
+            write!(f, "\n  left:  {:?}\n  right: {:?}", left, right)?;
+        }
+        write!(f, "\n  on {}, at {}", Context::current(), Uptime(tsc::since_boot()))
+    }
+}
+
+/// Called by `kassert!` and `kassert_eq!` when they fail.
+#[cold]
+#[inline(never)]
+pub fn failed(
+    file: &'static str,
+    line: u32,
+    condition: &'static str,
+    values: Option<(&dyn fmt::Debug, &dyn fmt::Debug)>,
+    message: Option<fmt::Arguments>,
+) -> ! {
+    let failure = Failure { file, line, condition, values, message };
+    if !TESTING.load(Ordering::Relaxed) {
+        panic!("{}", failure);
+    }
+    // a test expecting to panic still passes
+    testing::failed(testing::Expected::Panic);
+    let registers = crash::Registers::capture();
+    let backtrace = Backtrace::capture();
+    smp::halt_others();
+    serial_println!("[failed]\n");
+    serial_println!("{}\n", failure);
+    crash::dump(&registers, &backtrace);
+    exit_qemu(QemuExitCode::KernelAssertion);
+    halt_loop();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn test_report() {
+        let values = (&3u32 as &dyn fmt::Debug, &4u32 as &dyn fmt::Debug);
+        let failure = Failure { file: "src/kassert.rs", line: 7, condition: "a == b", values: Some(values), message: None };
+        let text = format!("{}", failure);
+        assert!(text.starts_with("kassert failed at src/kassert.rs:7: a == b\n  left:  3\n  right: 4\n  on cpu"), "{}", text);
+
+        let values = [1, 2, 3];
+        crate::kassert!(values.len() == 3);
+        crate::kassert_eq!(values.iter().sum::<i32>(), 6, "summing {:?}", values);
+    }
+
+    crate::should_panic! {
+        fn test_failing_kassert() {
+            let values = [1, 2, 3];
+            crate::kassert_eq!(values.len(), 4, "{:?} has three", values);
+        }
+    }
+}
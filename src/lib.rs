@@ -7,50 +7,185 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+pub mod console;
 pub mod serial;
+pub mod qemu;
+pub mod fw_cfg;
+pub mod cmdline;
+pub mod logger;
+pub mod dmesg;
+pub mod crash;
+pub mod kassert;
+pub mod trace;
+pub mod profile;
+pub mod fault_inject;
+pub mod stack_canary;
+pub mod backtrace;
+pub mod symbols;
+pub mod gdbstub;
+pub mod testing;
+pub mod bench;
+#[cfg(feature = "graphics")]
 pub mod vga_buffer;
 pub mod interrupts;
 pub mod gdt;
+pub mod random;
+pub mod msr;
+pub mod percpu;
+pub mod fpu;
+pub mod time;
+pub mod watchdog;
+pub mod allocator;
+pub mod task;
+pub mod thread;
+pub mod memory;
+pub mod process;
+pub mod syscall;
+pub mod tty;
+#[cfg(feature = "shell")]
+pub mod shell;
+pub mod sync;
+pub mod smp;
+pub mod workqueue;
+pub mod block;
+pub mod fs;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod pci;
+pub mod cmos;
+pub mod acpi;
+pub mod power;
+pub mod banner;
 
 extern crate bit_field;
+extern crate alloc;
 
 use core::arch::asm;
 use core::panic::PanicInfo;
 
+use bootloader::BootInfo;
+#[cfg(test)]
+use bootloader::entry_point;
+
+pub use qemu::{exit_qemu, QemuExitCode};
+
 pub fn halt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
 
-pub fn init() {
-    gdt::init();
-    interrupts::init_idt();
-    unsafe {
-        interrupts::hardware::PICS.lock().initialize();
-        // enable interrupts
-        asm!( "sti", options(preserves_flags, nostack));
+/* subsystems.
+    The parts of the kernel a build may leave out, each behind the Cargo feature
+    of its name: a kernel built for a test that needs none of them boots in less
+    time and builds faster. `init` brings up those the build has at the step
+    their phase names, in the order listed, each a stage of the boot report.
+    `graphics` has nothing to bring up and is not listed.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Once threads run and the root filesystem is there.
+    Threads,
+    /// Last, with the timer running, before interrupts are enabled.
+    Interrupts,
+}
+
+pub struct Subsystem {
+    pub name: &'static str,
+    pub phase: Phase,
+    pub init: fn(&'static BootInfo),
+}
+
+pub const SUBSYSTEMS: &[Subsystem] = &[
+    #[cfg(feature = "fs")]
+    Subsystem { name: "fs", phase: Phase::Threads, init: |_| fs::init_filesystems() },
+    #[cfg(feature = "net")]
+    Subsystem { name: "net", phase: Phase::Threads, init: |_| net::init() },
+    #[cfg(feature = "smp")]
+    Subsystem { name: "smp", phase: Phase::Interrupts, init: |_| smp::init() },
+    #[cfg(feature = "shell")]
+    Subsystem { name: "shell", phase: Phase::Interrupts, init: |_| register_commands() },
+];
+
+fn init_subsystems(phase: Phase, boot_info: &'static BootInfo) {
+    for subsystem in SUBSYSTEMS.iter().filter(|subsystem| subsystem.phase == phase) {
+        (subsystem.init)(boot_info);
+        time::boot::stage(subsystem.name);
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
+/// The shell and the commands of every subsystem.
+#[cfg(feature = "shell")]
+fn register_commands() {
+    shell::init();
+    block::register_commands();
+    interrupts::register_commands();
+    memory::register_commands();
+    pci::register_commands();
+    power::register_commands();
+    thread::register_commands();
+    time::register_commands();
 }
 
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
+pub fn init(boot_info: &'static BootInfo) {
+    use time::boot::stage;
 
+    time::tsc::mark_boot();
+    gdt::init();
+    stage("gdt");
+    fpu::init();
+    stage("fpu");
+    allocator::init_heap();
+    stage("heap");
+    cmdline::init();
+    logger::init();
+    console::init();
+    fault_inject::init();
+    stage("cmdline");
+    memory::init(boot_info);
+    gdt::guard_ist_stacks();
+    allocator::leaks::init();
+    stage("memory");
+    percpu::init(0);
+    trace::init();
+    interrupts::latency::init();
+    stage("percpu");
+    thread::init();
+    workqueue::init();
+    stage("threads");
+    fs::init();
+    stage("vfs");
+    init_subsystems(Phase::Threads, boot_info);
+    interrupts::init_idt();
+    gdbstub::init();
+    stage("idt");
+    syscall::init();
+    stage("syscall");
+    unsafe {
+        interrupts::hardware::PICS.lock().initialize();
+    }
+    stage("pic");
+    // calibrates the TSC, the stages are only reported after it
+    time::init();
+    stage("time");
+    profile::init();
+    random::init();
+    stage("random");
+    init_subsystems(Phase::Interrupts, boot_info);
     unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+        // enable interrupts
+        asm!( "sti", options(preserves_flags, nostack));
     }
+    time::boot::report();
 }
 
+/// Longest a test may run, the timer interrupt fails the run with its name after that.
+pub const TEST_TIMEOUT_MS: u64 = 30_000;
+
 pub trait Testable {
     fn run(&self) -> ();
+    fn name(&self) -> &'static str;
 }
 
 impl<T> Testable for T
@@ -58,32 +193,60 @@ impl<T> Testable for T
         T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        serial_print!("{}...\t", self.name());
         self();
         serial_println!("[ok]");
     }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    testing::failed(testing::Expected::Panic);
+    let registers = crash::Registers::capture();
+    let backtrace = backtrace::Backtrace::capture();
+    smp::halt_others();
+    crash::unlock_consoles();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
+    crash::dump(&registers, &backtrace);
+    exit_qemu(testing::panic_exit_code(info));
     halt_loop();
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
+    kassert::set_testing();
+    let filter = testing::receive_filter();
+    let selected = tests.iter().filter(|test| filter.selects(test.name())).count();
+    if filter.is_all() {
+        serial_println!("Running {} tests", tests.len());
+    } else {
+        serial_println!("Running {} of {} tests, filter '{}'", selected, tests.len(), filter);
+    }
+    let mut failed = 0;
+    for test in tests.iter().filter(|test| filter.selects(test.name())) {
+        let _deadline = watchdog::register(test.name(), TEST_TIMEOUT_MS)
+            .expect("no free watchdog slot");
+        if !testing::run_isolated(*test) {
+            failed += 1;
+        }
     }
 
+    if failed > 0 {
+        serial_println!("{} of {} tests failed", failed, selected);
+        exit_qemu(QemuExitCode::Failed);
+    }
     exit_qemu(QemuExitCode::Success);
 }
 
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
+entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     halt_loop();
 }
@@ -0,0 +1,206 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::dmesg::Uptime;
+use crate::sync::SpinLock;
+use crate::time::tsc;
+use crate::{cmdline, console, dmesg, percpu, thread};
+
+/* kernel logging.
+    The `log` crate's macros, `log::info!` and friends, are how the kernel reports
+    what it does; this is the logger behind them. A filter picks the records by
+    level, per module if wanted, and every record that passes goes to the console,
+    whose sinks have levels of their own, see console.rs, while `dmesg` keeps it
+    either way. The filter comes from the command line:
+        log=warn,net=debug,thread::scheduler=trace
+    Module paths are given without the crate name, the longest that matches wins.
+    Each line tells when, where and who, so the lines of CPUs and threads running
+    side by side can be told apart:
+        [    1.204518] INFO  cpu1 dhcp net::dhcp: leased 10.0.2.15
+ */
+
+const DEFAULT_FILTER: &str = "info";
+const CRATE_PREFIX: &str = "blog_os::";
+// longer thread names are cut, as Linux does
+const TASK_NAME_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    /// A level that is not off, error, warn, info, debug or trace.
+    InvalidFilter,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    default: LevelFilter,
+    // by module path, longest first
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    const fn new() -> Self {
+        Filter { default: LevelFilter::Info, modules: Vec::new() }
+    }
+
+    /// A filter from "level,module=level,...", every part optional.
+    fn parse(spec: &str) -> Result<Self, LogError> {
+        let mut filter = Filter::new();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) => {
+                    let level = LevelFilter::from_str(level).map_err(|_| LogError::InvalidFilter)?;
+                    filter.modules.push((module.trim_start_matches(CRATE_PREFIX).to_string(), level));
+                }
+                None => filter.default = LevelFilter::from_str(part).map_err(|_| LogError::InvalidFilter)?,
+            }
+        }
+        filter.modules.sort_by_key(|(module, _)| core::cmp::Reverse(module.len()));
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        self.modules
+            .iter()
+            .find(|(module, _)| target.strip_prefix(module.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::")))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level anything passes at.
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+/// The CPU and thread a record comes from, without allocating or waiting on a lock.
+pub(crate) struct Context {
+    cpu: Option<usize>,
+    thread: u64,
+    name: [u8; TASK_NAME_LEN],
+    name_len: usize,
+}
+
+impl Context {
+    pub(crate) fn current() -> Self {
+        let mut context = Context { cpu: None, thread: 0, name: [0; TASK_NAME_LEN], name_len: 0 };
+        // before per-CPU data is set up neither is known
+        if let Some(cpu) = percpu::try_this_cpu() {
+            context.cpu = Some(cpu.cpu_id);
+            context.thread = thread::current_id().as_u64();
+            context.name_len = thread::try_with_current(|thread| {
+                let name = thread.name();
+                let mut len = name.len().min(TASK_NAME_LEN);
+                while !name.is_char_boundary(len) {
+                    len -= 1;
+                }
+                context.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+                len
+            }).unwrap_or(0);
+        }
+        context
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(cpu) = self.cpu else {
+            return write!(f, "- -");
+        };
+        match self.name_len {
+            // the scheduler was busy, or no thread runs yet
+            0 => write!(f, "cpu{} #{}", cpu, self.thread),
+            len => write!(f, "cpu{} {}", cpu, core::str::from_utf8(&self.name[..len]).unwrap()),
+        }
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+static FILTER: SpinLock<Filter> = SpinLock::new(Filter::new());
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.lock().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let target = record.target().strip_prefix(CRATE_PREFIX).unwrap_or(record.target());
+        let (level, args) = (record.level(), record.args());
+        let (time, context) = (tsc::since_boot(), Context::current());
+        dmesg::record(level, time, format_args!("{} {}: {}", context, target, args));
+        console::log(level, format_args!("[{}] {:<5} {} {}: {}\n", Uptime(time), level, context, target, args));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Replaces the filter, see the top of this file for `spec`.
+pub fn set_filter(spec: &str) -> Result<(), LogError> {
+    let filter = Filter::parse(spec)?;
+    log::set_max_level(filter.max());
+    *FILTER.lock() = filter;
+    Ok(())
+}
+
+/// Installs the logger, configured from the command line.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    // loglevel= is one level for everything, log= can say more and wins
+    let level = cmdline::parse::<LevelFilter>("loglevel").map(|level| level.as_str());
+    if set_filter(cmdline::get("log").or(level).unwrap_or(DEFAULT_FILTER)).is_err() {
+        let _ = set_filter(DEFAULT_FILTER);
+        log::warn!("bad filter on the command line, using {}", DEFAULT_FILTER);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+    use log::Level;
+
+    #[test_case]
+    fn test_filters() {
+        let filter = Filter::parse("warn, net=debug,blog_os::net::tcp=trace,fs=off").unwrap();
+        assert_eq!(filter.level("blog_os::net::tcp"), LevelFilter::Trace);
+        assert_eq!(filter.level("blog_os::net::udp"), LevelFilter::Debug);
+        // a prefix of the name is not a parent module
+        assert_eq!(filter.level("blog_os::network"), LevelFilter::Warn);
+        assert_eq!(filter.level("blog_os::fs::ext2"), LevelFilter::Off);
+        assert_eq!(filter.max(), LevelFilter::Trace);
+        assert_eq!(Filter::parse("").unwrap(), Filter::new());
+        assert_eq!(Filter::parse("net=loud"), Err(LogError::InvalidFilter));
+    }
+
+    #[test_case]
+    fn test_logger() {
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+        set_filter("info,logger-test=error").unwrap();
+        assert!(LOGGER.enabled(&metadata(Level::Info, "blog_os::net")));
+        assert!(!LOGGER.enabled(&metadata(Level::Debug, "blog_os::net")));
+        assert!(!LOGGER.enabled(&metadata(Level::Warn, "logger-test")));
+        assert_eq!(log::max_level(), LevelFilter::Info);
+        set_filter(DEFAULT_FILTER).unwrap();
+    }
+
+    #[test_case]
+    fn test_context() {
+        let context = Context { cpu: Some(1), thread: 7, name: *b"logger-context-w", name_len: 16 };
+        assert_eq!(format!("{}", context), "cpu1 logger-context-w");
+        assert_eq!(format!("{}", Context { name_len: 0, ..context }), "cpu1 #7");
+        assert_eq!(format!("{}", Context { cpu: None, ..context }), "- -");
+
+        let current = Context::current();
+        assert!(current.cpu.is_some());
+        assert_eq!(current.thread, thread::current_id().as_u64());
+    }
+}
@@ -4,30 +4,67 @@
 #![test_runner(blog_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-mod vga_buffer;
-mod serial;
-
 use core::panic::PanicInfo;
-use blog_os::halt_loop;
+use bootloader::{entry_point, BootInfo};
+use blog_os::console::Sinks;
+use blog_os::println;
+use blog_os::task::executor::Executor;
+
+entry_point!(kernel_main);
+
+// log records the panic handler shows again
+#[cfg(not(test))]
+const PANIC_REPLAY: u64 = 32;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     println!("Hello World{}", "!");
 
-    blog_os::init();
+    blog_os::init(boot_info);
+    blog_os::banner::print();
 
     #[cfg(test)]
     test_main();
 
-    halt_loop();
+    if let Some(path) = blog_os::cmdline::get("init") {
+        if let Err(errno) = blog_os::process::user::start(path, &[path], &[]) {
+            log::error!("init={}: {:?}", path, errno);
+        }
+    }
+
+    #[cfg(feature = "net")]
+    {
+        use blog_os::net::httpd;
+        if let Err(error) = blog_os::fs::create_dir_all(httpd::DOCROOT) {
+            log::error!("httpd: {}: {:?}", httpd::DOCROOT, error);
+        } else if let Err(error) = httpd::start(80, httpd::DOCROOT) {
+            log::error!("httpd: {:?}", error);
+        }
+    }
+
+    let mut executor = Executor::new();
+    #[cfg(feature = "shell")]
+    executor.spawn(blog_os::task::Task::new(blog_os::shell::run()));
+    #[cfg(feature = "smoltcp")]
+    executor.spawn(blog_os::task::Task::new(blog_os::net::smoltcp_stack::run()));
+    executor.run();
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    halt_loop();
+    let registers = blog_os::crash::Registers::capture();
+    let backtrace = blog_os::backtrace::Backtrace::capture();
+    blog_os::smp::halt_others();
+    blog_os::crash::unlock_consoles();
+    blog_os::console::write(Sinks::VGA | Sinks::SERIAL, format_args!("{}\n", info));
+    blog_os::crash::dump(&registers, &backtrace);
+    // what the serial console may not have shown, when the log went elsewhere
+    blog_os::dmesg::replay(PANIC_REPLAY, |_, time, level, text| {
+        blog_os::serial_println!("[{}] {:<5} {}", blog_os::dmesg::Uptime(time), level, text);
+    });
+    blog_os::crash::kdump::write(info, &registers, &backtrace);
+    blog_os::halt_loop();
 }
 
 #[cfg(test)]
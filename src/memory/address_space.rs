@@ -0,0 +1,488 @@
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, Translate, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::physical::{Frames, KernelMemory, PhysicalMemory};
+use super::{kernel_p4, PAGE_SIZE};
+
+/// Lowest user address. P4 entry 0 below it holds the kernel image, its heap and
+/// the VGA buffer; the bootloader's other mappings, physical memory, the boot stack
+/// and the boot info, are pinned to the upper half in Cargo.toml, so entries 1 to
+/// 255 are left to user space and not copied into a new address space.
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+/// End of the lower canonical half.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+const USER_P4_FIRST: usize = 1;
+const USER_P4_END: usize = 256;
+
+/// Software bit marking a page that is shared read-only until someone writes to it.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Software bit marking a page that stays shared on fork, writes included.
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/* one set of page tables per process.
+    Level 4 entries outside of the user range are copied from the kernel's table, so the
+    kernel sees the same mappings in every address space. Everything mapped in the user
+    range belongs to the address space and is returned to the frame allocator on drop.
+    The tables live in `M`, all of RAM but for tests.
+ */
+pub struct AddressSpace<M: PhysicalMemory = KernelMemory> {
+    p4: PhysFrame,
+    memory: M,
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        Self::new_in(KernelMemory)
+    }
+
+    pub fn is_user_page(page: Page) -> bool {
+        (USER_START..USER_END).contains(&page.start_address().as_u64())
+    }
+
+    /// Loads this address space into CR3. Kernel mappings stay the same, so the caller keeps running.
+    pub fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.p4, flags) };
+    }
+}
+
+impl<M: PhysicalMemory> AddressSpace<M> {
+    /// An empty address space with its tables in `memory`.
+    pub fn new_in(memory: M) -> Option<Self> {
+        let p4 = memory.allocate()?;
+        let table = unsafe { memory.table(p4) };
+        table.zero();
+        if let Some(kernel) = memory.kernel_p4() {
+            let kernel = unsafe { memory.table(kernel) };
+            for (index, entry) in kernel.iter().enumerate() {
+                if !(USER_P4_FIRST..USER_P4_END).contains(&index) {
+                    table[index] = entry.clone();
+                }
+            }
+        }
+        Some(AddressSpace { p4, memory })
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.memory.is_loaded(self.p4)
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(self.memory.table(self.p4), self.memory.offset()) }
+    }
+
+    /// Maps `frame` at `page`, which must lie in the user range. The address space owns
+    /// the frame from now on.
+    pub fn map(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        assert!(AddressSpace::is_user_page(page), "mapping outside of the user range");
+        let active = self.is_active();
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flush = unsafe {
+            self.mapper().map_to_with_table_flags(page, frame, flags | PageTableFlags::PRESENT, parent_flags, &mut Frames(&self.memory))?
+        };
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// Maps a freshly allocated, zeroed frame at `page`.
+    pub fn map_zeroed(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        let frame = self.memory.allocate().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { self.memory.table(frame).zero() };
+        if let Err(err) = self.map(page, frame, flags) {
+            self.memory.free(frame);
+            return Err(err);
+        }
+        Ok(frame)
+    }
+
+    /// Removes the mapping at `page` and hands its frame back to the caller, who drops
+    /// the reference with `frame::release`, or `release` of the memory it is in.
+    pub fn unmap(&mut self, page: Page) -> Option<PhysFrame> {
+        let active = self.is_active();
+        let (frame, flush) = self.mapper().unmap(page).ok()?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Some(frame)
+    }
+
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> bool {
+        let active = self.is_active();
+        match unsafe { self.mapper().update_flags(page, flags | PageTableFlags::PRESENT) } {
+            Ok(flush) if active => flush.flush(),
+            Ok(flush) => flush.ignore(),
+            Err(_) => return false,
+        }
+        true
+    }
+
+    pub fn flags(&mut self, page: Page) -> Option<PageTableFlags> {
+        match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /* copying through the physical memory mapping.
+        Works whether or not the address space is active, so a process can be filled
+        in before it ever runs. Fails if any page of the range is not mapped.
+     */
+    pub fn copy_to(&mut self, addr: VirtAddr, data: &[u8]) -> bool {
+        let mut done = 0;
+        while done < data.len() {
+            let current = addr + done as u64;
+            let Some(phys) = self.translate(current) else {
+                return false;
+            };
+            let chunk = (PAGE_SIZE - current.as_u64() % PAGE_SIZE).min((data.len() - done) as u64) as usize;
+            let dest = self.memory.to_virt(phys).as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), dest, chunk) };
+            done += chunk;
+        }
+        true
+    }
+
+    pub fn copy_from(&mut self, addr: VirtAddr, buffer: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buffer.len() {
+            let current = addr + done as u64;
+            let Some(phys) = self.translate(current) else {
+                return false;
+            };
+            let chunk = (PAGE_SIZE - current.as_u64() % PAGE_SIZE).min((buffer.len() - done) as u64) as usize;
+            let src = self.memory.to_virt(phys).as_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(src, buffer[done..].as_mut_ptr(), chunk) };
+            done += chunk;
+        }
+        true
+    }
+
+    /* fork.
+        Every user page ends up in both address spaces, backed by the same frame.
+        Writable pages lose their write permission on both sides and are marked
+        copy-on-write; the first write faults and gets a private copy. Shared pages
+        keep their permissions, both sides are meant to see each other's writes.
+     */
+    pub fn fork(&mut self) -> Option<AddressSpace<M>>
+    where
+        M: Clone,
+    {
+        let memory = &self.memory;
+        let mut child = AddressSpace::new_in(memory.clone())?;
+        let p4 = unsafe { memory.table(self.p4) };
+        for p4_index in USER_P4_FIRST..USER_P4_END {
+            if p4[p4_index].is_unused() {
+                continue;
+            }
+            let p3 = unsafe { memory.table(p4[p4_index].frame().ok()?) };
+            for p3_index in 0..512 {
+                let Ok(p2_frame) = p3[p3_index].frame() else { continue };
+                let p2 = unsafe { memory.table(p2_frame) };
+                for p2_index in 0..512 {
+                    let Ok(p1_frame) = p2[p2_index].frame() else { continue };
+                    let p1 = unsafe { memory.table(p1_frame) };
+                    for p1_index in 0..512 {
+                        let entry = &mut p1[p1_index];
+                        let Ok(frame) = entry.frame() else { continue };
+                        let mut flags = entry.flags();
+                        if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                            entry.set_flags(flags);
+                        }
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(p4_index as u16), PageTableIndex::new(p3_index as u16),
+                            PageTableIndex::new(p2_index as u16), PageTableIndex::new(p1_index as u16));
+                        memory.share(frame);
+                        if child.map(page, frame, flags).is_err() {
+                            memory.release(frame);
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        if self.is_active() {
+            // other CPUs may run threads of the same process
+            crate::smp::flush_tlb(None);
+        }
+        Some(child)
+    }
+
+    /* breaking copy-on-write.
+        The last address space holding a shared frame simply gets its write permission
+        back, everyone else copies the contents into a frame of its own.
+     */
+    pub fn prepare_write(&mut self, page: Page) -> bool {
+        let Some(flags) = self.flags(page) else {
+            return false;
+        };
+        if flags.contains(PageTableFlags::WRITABLE) {
+            return true;
+        }
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+
+        let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let old = PhysFrame::containing_address(self.translate(page.start_address()).expect("flags of an unmapped page"));
+        if self.memory.ref_count(old) == 1 {
+            return self.update_flags(page, writable);
+        }
+
+        let Some(new) = self.memory.allocate() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.memory.to_virt(old.start_address()).as_ptr::<u8>(),
+                self.memory.to_virt(new.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+        }
+        self.unmap(page);
+        self.memory.release(old);
+        if self.map(page, new, writable).is_err() {
+            self.memory.free(new);
+            return false;
+        }
+        true
+    }
+
+    /// Frees every frame mapped in the user range along with the tables pointing at them.
+    fn free_user_range(&mut self) {
+        let p4 = unsafe { self.memory.table(self.p4) };
+        for entry in p4.iter_mut().take(USER_P4_END).skip(USER_P4_FIRST) {
+            if !entry.is_unused() {
+                free_table(&self.memory, entry.frame().expect("huge page at level 4"), 3);
+                entry.set_unused();
+            }
+        }
+    }
+}
+
+/// Walks a table of the given level, freeing mapped frames, child tables and the table itself.
+fn free_table(memory: &impl PhysicalMemory, table_frame: PhysFrame, level: u8) {
+    let table: &mut PageTable = unsafe { memory.table(table_frame) };
+    for entry in table.iter_mut().filter(|entry| !entry.is_unused()) {
+        let frame = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            memory.release(frame);
+        } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // huge pages are never created for user space, their frames are not ours to free
+            free_table(memory, frame, level - 1);
+        }
+        entry.set_unused();
+    }
+    memory.free(table_frame);
+}
+
+impl<M: PhysicalMemory> Drop for AddressSpace<M> {
+    fn drop(&mut self) {
+        if self.is_active() {
+            let (_, flags) = Cr3::read();
+            unsafe { Cr3::write(kernel_p4(), flags) };
+        }
+        self.free_user_range();
+        self.memory.free(self.p4);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::fake::FakeMemory;
+    use crate::memory::frame;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_teardown_returns_frames() {
+        let before = frame::allocated_frames();
+        {
+            let mut space = AddressSpace::new().expect("out of frames");
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+            for i in 0..4 {
+                let page = Page::containing_address(VirtAddr::new(USER_START + i * PAGE_SIZE));
+                space.map_zeroed(page, flags).expect("map failed");
+            }
+            assert!(frame::allocated_frames() > before + 4);
+        }
+        assert_eq!(frame::allocated_frames(), before);
+    }
+
+    #[test_case]
+    fn test_mappings_are_private() {
+        let mut space = AddressSpace::new().expect("out of frames");
+        let addr = VirtAddr::new(USER_START);
+        let frame = space.map_zeroed(Page::containing_address(addr), PageTableFlags::USER_ACCESSIBLE)
+            .expect("map failed");
+        assert_eq!(space.translate(addr), Some(frame.start_address()));
+        assert_eq!(AddressSpace::new().expect("out of frames").translate(addr), None);
+    }
+
+    #[test_case]
+    fn test_copy_across_pages() {
+        let mut space = AddressSpace::new().expect("out of frames");
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for i in 0..2 {
+            let page = Page::containing_address(VirtAddr::new(USER_START + i * PAGE_SIZE));
+            space.map_zeroed(page, flags).expect("map failed");
+        }
+        let addr = VirtAddr::new(USER_START + PAGE_SIZE - 3);
+        assert!(space.copy_to(addr, b"abcdef"));
+        let mut buffer = [0u8; 6];
+        assert!(space.copy_from(addr, &mut buffer));
+        assert_eq!(&buffer, b"abcdef");
+        assert!(!space.copy_to(VirtAddr::new(USER_START + 2 * PAGE_SIZE - 1), b"xy"));
+    }
+
+    #[test_case]
+    fn test_fork_copies_on_write() {
+        let before = frame::allocated_frames();
+        let addr = VirtAddr::new(USER_START);
+        let page = Page::containing_address(addr);
+        let mut parent = AddressSpace::new().expect("out of frames");
+        let shared = parent.map_zeroed(page, PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)
+            .expect("map failed");
+        assert!(parent.copy_to(addr, &[5]));
+
+        let mut child = parent.fork().expect("out of frames");
+        assert_eq!(frame::ref_count(shared), 2);
+        for space in [&mut parent, &mut child] {
+            let flags = space.flags(page).unwrap();
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+            assert!(flags.contains(COPY_ON_WRITE));
+        }
+
+        assert!(parent.prepare_write(page));
+        assert!(parent.copy_to(addr, &[7]));
+        let mut value = [0u8];
+        assert!(child.copy_from(addr, &mut value));
+        assert_eq!(value, [5]);
+        assert_ne!(parent.translate(addr), child.translate(addr));
+
+        // the child is the only user left, it keeps the original frame
+        assert!(child.prepare_write(page));
+        assert_eq!(child.translate(addr), Some(shared.start_address()));
+
+        drop(parent);
+        drop(child);
+        assert_eq!(frame::allocated_frames(), before);
+    }
+
+    #[test_case]
+    fn test_walk_fake_tables() {
+        let memory = FakeMemory::new(64);
+        let mut space = AddressSpace::new_in(&memory).expect("out of frames");
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        // either side of every table boundary, and the ends of user space
+        let addresses = [
+            USER_START, USER_START + 511 * PAGE_SIZE, USER_START + 512 * PAGE_SIZE,
+            USER_START + (1 << 30), USER_START + (1 << 39), USER_END - PAGE_SIZE,
+        ];
+        let mut frames = Vec::new();
+        for &address in &addresses {
+            let page = Page::containing_address(VirtAddr::new(address));
+            frames.push(space.map_zeroed(page, flags).expect("map failed"));
+        }
+        // the P4, then the tables each address needs that those before it did not
+        assert_eq!(memory.allocated_frames(), 1 + addresses.len() + 3 + 1 + 2 + 3 + 3);
+
+        for (&address, frame) in addresses.iter().zip(&frames) {
+            let page = Page::containing_address(VirtAddr::new(address));
+            assert_eq!(space.translate(VirtAddr::new(address + 5)), Some(frame.start_address() + 5));
+            assert_eq!(space.flags(page), Some(flags | PageTableFlags::PRESENT));
+            for neighbour in [address - PAGE_SIZE, address + PAGE_SIZE] {
+                if (USER_START..USER_END).contains(&neighbour) && !addresses.contains(&neighbour) {
+                    assert_eq!(space.translate(VirtAddr::new(neighbour)), None);
+                }
+            }
+        }
+        assert!(space.copy_to(VirtAddr::new(USER_END - 4), b"end!"));
+
+        assert!(space.map(Page::containing_address(VirtAddr::new(USER_START)), frames[1], flags).is_err());
+        (&memory).release(space.unmap(Page::containing_address(VirtAddr::new(USER_START))).expect("not mapped"));
+        assert_eq!(space.translate(VirtAddr::new(USER_START)), None);
+        drop(space);
+        assert_eq!(memory.allocated_frames(), 0);
+    }
+
+    #[test_case]
+    fn test_fill_fake_table() {
+        let memory = FakeMemory::new(520);
+        let mut space = AddressSpace::new_in(&memory).expect("out of frames");
+        for index in 0..512 {
+            let page = Page::containing_address(VirtAddr::new(USER_START + index * PAGE_SIZE));
+            space.map_zeroed(page, PageTableFlags::USER_ACCESSIBLE).expect("map failed");
+        }
+        // one table of each level
+        assert_eq!(memory.allocated_frames(), 512 + 4);
+        for index in 0..512 {
+            let address = VirtAddr::new(USER_START + index * PAGE_SIZE);
+            let frame = space.translate(address).expect("not mapped");
+            assert_eq!(space.translate(address + PAGE_SIZE - 1u64), Some(frame + PAGE_SIZE - 1u64));
+        }
+
+        // running out of frames takes nothing with it
+        let mut exhausted = AddressSpace::new_in(&memory).expect("out of frames");
+        let page = Page::containing_address(VirtAddr::new(USER_START));
+        assert!(exhausted.map_zeroed(page, PageTableFlags::USER_ACCESSIBLE).is_err());
+        drop(exhausted);
+        drop(space);
+        assert_eq!(memory.allocated_frames(), 0);
+    }
+
+    #[test_case]
+    fn test_fork_fake_tables() {
+        let memory = FakeMemory::new(32);
+        let addr = VirtAddr::new(USER_START + (1 << 39));
+        let page = Page::containing_address(addr);
+        let mut parent = AddressSpace::new_in(&memory).expect("out of frames");
+        let shared = parent.map_zeroed(page, PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)
+            .expect("map failed");
+        assert!(parent.copy_to(addr, &[5]));
+
+        let mut child = parent.fork().expect("out of frames");
+        assert_eq!((&memory).ref_count(shared), 2);
+        assert!(child.prepare_write(page));
+        assert!(child.copy_to(addr, &[7]));
+        let mut value = [0u8];
+        assert!(parent.copy_from(addr, &mut value));
+        assert_eq!(value, [5]);
+        assert_eq!((&memory).ref_count(shared), 1);
+
+        drop(parent);
+        drop(child);
+        assert_eq!(memory.allocated_frames(), 0);
+    }
+
+    crate::bench_case! {
+        fn bench_map_unmap(bencher) {
+            let mut space = AddressSpace::new().expect("out of frames");
+            let page = Page::containing_address(VirtAddr::new(USER_START));
+            bencher.iter(|| {
+                space.map_zeroed(page, PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)
+                    .expect("map failed");
+                frame::release(space.unmap(page).expect("not mapped"));
+            });
+        }
+    }
+}
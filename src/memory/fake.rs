@@ -0,0 +1,100 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::physical::PhysicalMemory;
+use super::PAGE_SIZE;
+use crate::sync::SpinLock;
+
+/* fake physical memory, for tests.
+    A few frames on the heap stand in for RAM. Frame i has physical address
+    (i + 1) * PAGE_SIZE, so none is at 0 and nothing real is ever touched: the
+    addresses only mean something to the memory they came from. There is no kernel
+    half, and the tables are never loaded.
+ */
+
+#[repr(C, align(4096))]
+struct Frame([u8; PAGE_SIZE as usize]);
+
+struct State {
+    free: Vec<usize>,
+    // 0 for a free frame
+    refs: Vec<usize>,
+}
+
+pub struct FakeMemory {
+    frames: Box<[Frame]>,
+    state: SpinLock<State>,
+}
+
+impl FakeMemory {
+    pub fn new(frames: usize) -> Self {
+        FakeMemory {
+            frames: (0..frames).map(|_| Frame([0; PAGE_SIZE as usize])).collect(),
+            // handed out lowest first
+            state: SpinLock::new(State { free: (0..frames).rev().collect(), refs: alloc::vec![0; frames] }),
+        }
+    }
+
+    pub fn allocated_frames(&self) -> usize {
+        let state = self.state.lock();
+        self.frames.len() - state.free.len()
+    }
+
+    fn index(&self, frame: PhysFrame) -> usize {
+        let index = (frame.start_address().as_u64() / PAGE_SIZE) as usize;
+        assert!(index >= 1 && index <= self.frames.len(), "{:?} is not a fake frame", frame);
+        index - 1
+    }
+}
+
+unsafe impl PhysicalMemory for &FakeMemory {
+    fn offset(&self) -> VirtAddr {
+        VirtAddr::new(self.frames.as_ptr() as u64 - PAGE_SIZE)
+    }
+
+    fn allocate(&self) -> Option<PhysFrame> {
+        let mut state = self.state.lock();
+        let index = state.free.pop()?;
+        state.refs[index] = 1;
+        Some(PhysFrame::containing_address(PhysAddr::new((index as u64 + 1) * PAGE_SIZE)))
+    }
+
+    fn free(&self, frame: PhysFrame) {
+        let index = self.index(frame);
+        let mut state = self.state.lock();
+        assert_eq!(state.refs[index], 1, "freeing {:?} with other references", frame);
+        state.refs[index] = 0;
+        state.free.push(index);
+    }
+
+    fn share(&self, frame: PhysFrame) {
+        let index = self.index(frame);
+        let mut state = self.state.lock();
+        assert_ne!(state.refs[index], 0, "sharing free {:?}", frame);
+        state.refs[index] += 1;
+    }
+
+    fn release(&self, frame: PhysFrame) {
+        let index = self.index(frame);
+        let mut state = self.state.lock();
+        assert_ne!(state.refs[index], 0, "releasing free {:?}", frame);
+        state.refs[index] -= 1;
+        if state.refs[index] == 0 {
+            state.free.push(index);
+        }
+    }
+
+    fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.state.lock().refs[self.index(frame)]
+    }
+
+    fn kernel_p4(&self) -> Option<PhysFrame> {
+        None
+    }
+
+    fn is_loaded(&self, _p4: PhysFrame) -> bool {
+        false
+    }
+}
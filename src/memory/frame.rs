@@ -0,0 +1,175 @@
+use alloc::collections::BTreeMap;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::{phys_to_virt, PAGE_SIZE};
+use crate::fault_inject;
+
+/* physical frame allocator.
+    Fresh frames are handed out by walking the usable regions of the bootloader's memory
+    map once. Freed frames go onto a free list that is threaded through the frames
+    themselves, each one storing the address of the next in its first eight bytes.
+ */
+struct FrameList {
+    memory_map: Option<&'static MemoryMap>,
+    region: usize,
+    next: u64,
+    free_head: Option<PhysFrame>,
+    // references beyond the first, only for frames mapped more than once
+    shared: BTreeMap<PhysFrame, usize>,
+    total: usize,
+    allocated: usize,
+}
+
+const NO_FRAME: u64 = u64::MAX;
+
+impl FrameList {
+    const fn new() -> Self {
+        FrameList {
+            memory_map: None,
+            region: 0,
+            next: 0,
+            free_head: None,
+            shared: BTreeMap::new(),
+            total: 0,
+            allocated: 0,
+        }
+    }
+
+    fn allocate(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_head {
+            let link = phys_to_virt(frame.start_address()).as_ptr::<u64>();
+            let next = unsafe { link.read() };
+            self.free_head = (next != NO_FRAME).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.allocated += 1;
+            return Some(frame);
+        }
+
+        let memory_map = self.memory_map?;
+        while let Some(region) = memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                let address = self.next.max(region.range.start_addr());
+                if address + PAGE_SIZE <= region.range.end_addr() {
+                    self.next = address + PAGE_SIZE;
+                    self.allocated += 1;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(address)));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+
+    fn release(&mut self, frame: PhysFrame) {
+        match self.shared.get_mut(&frame) {
+            Some(1) => {
+                self.shared.remove(&frame);
+            }
+            Some(extra) => *extra -= 1,
+            None => self.free(frame),
+        }
+    }
+
+    fn free(&mut self, frame: PhysFrame) {
+        let next = self.free_head.map_or(NO_FRAME, |head| head.start_address().as_u64());
+        let link = phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+        unsafe { link.write(next) };
+        self.free_head = Some(frame);
+        self.allocated -= 1;
+    }
+}
+
+static FRAMES: Mutex<FrameList> = Mutex::new(FrameList::new());
+
+pub(super) fn init(memory_map: &'static MemoryMap) {
+    let total = memory_map.iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| ((region.range.end_addr() - region.range.start_addr()) / PAGE_SIZE) as usize)
+        .sum();
+    without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        frames.memory_map = Some(memory_map);
+        frames.total = total;
+    });
+}
+
+pub fn allocate() -> Option<PhysFrame> {
+    if fault_inject::FRAMES.should_fail() {
+        return None;
+    }
+    without_interrupts(|| FRAMES.lock().allocate())
+}
+
+/// Returns a frame that is no longer mapped anywhere to the allocator.
+pub fn free(frame: PhysFrame) {
+    without_interrupts(|| FRAMES.lock().free(frame));
+}
+
+/// One more mapping refers to `frame`, it stays allocated until every one of them is released.
+pub fn share(frame: PhysFrame) {
+    without_interrupts(|| *FRAMES.lock().shared.entry(frame).or_insert(0) += 1);
+}
+
+/// Drops one reference to `frame`, freeing it with the last.
+pub fn release(frame: PhysFrame) {
+    without_interrupts(|| FRAMES.lock().release(frame));
+}
+
+pub fn ref_count(frame: PhysFrame) -> usize {
+    without_interrupts(|| FRAMES.lock().shared.get(&frame).map_or(1, |extra| extra + 1))
+}
+
+pub fn allocated_frames() -> usize {
+    without_interrupts(|| FRAMES.lock().allocated)
+}
+
+pub fn total_frames() -> usize {
+    without_interrupts(|| FRAMES.lock().total)
+}
+
+/// Handle passing the global frame allocator to the `x86_64` paging code.
+pub struct KernelFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        free(frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_freed_frame_is_reused() {
+        let before = allocated_frames();
+        let frame = allocate().expect("out of frames");
+        assert_eq!(allocated_frames(), before + 1);
+        free(frame);
+        assert_eq!(allocated_frames(), before);
+        assert_eq!(allocate(), Some(frame));
+        free(frame);
+    }
+
+    #[test_case]
+    fn test_shared_frame_freed_with_last_reference() {
+        let before = allocated_frames();
+        let frame = allocate().expect("out of frames");
+        share(frame);
+        assert_eq!(ref_count(frame), 2);
+        release(frame);
+        assert_eq!(ref_count(frame), 1);
+        assert_eq!(allocated_frames(), before + 1);
+        release(frame);
+        assert_eq!(allocated_frames(), before);
+    }
+}
@@ -0,0 +1,104 @@
+pub mod frame;
+pub mod physical;
+pub mod address_space;
+#[cfg(test)]
+pub mod fake;
+
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::BootInfo;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::Translate;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+#[cfg(feature = "shell")]
+use crate::allocator;
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+
+pub use address_space::AddressSpace;
+use address_space::{USER_END, USER_START};
+pub use frame::KernelFrameAllocator;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// all of physical memory is mapped by the bootloader starting at this address
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_P4: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(boot_info: &'static BootInfo) {
+    // loading a user address space would take them away from under the kernel
+    let mappings = [
+        ("physical memory", boot_info.physical_memory_offset),
+        ("boot info", boot_info as *const _ as u64),
+        ("boot stack", &boot_info as *const _ as u64),
+    ];
+    for (what, address) in mappings {
+        assert!(!(USER_START..USER_END).contains(&address), "the bootloader mapped the {} at {:#x}, in the user range", what, address);
+    }
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::SeqCst);
+    let (p4, _) = Cr3::read();
+    KERNEL_P4.store(p4.start_address().as_u64(), Ordering::SeqCst);
+    frame::init(&boot_info.memory_map);
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// The level 4 table set up by the bootloader, shared by every address space for kernel mappings.
+pub fn kernel_p4() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_P4.load(Ordering::Relaxed)))
+}
+
+/// # Safety
+/// `frame` must hold a page table, and no other reference to it may be alive.
+pub unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// # Safety
+/// Creates a second mutable view of the active page table, callers must not let two of them overlap.
+pub unsafe fn active_page_table() -> OffsetPageTable<'static> {
+    let (p4, _) = Cr3::read();
+    OffsetPageTable::new(table_at(p4), physical_memory_offset())
+}
+
+/// Whether `address` can be read without faulting, false before `init`.
+pub fn is_mapped(address: u64) -> bool {
+    let Ok(address) = VirtAddr::try_new(address) else {
+        return false;
+    };
+    if physical_memory_offset().is_null() {
+        return false;
+    }
+    unsafe { active_page_table() }.translate_addr(address).is_some()
+}
+
+/// Registers `meminfo`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("meminfo", "physical frames and heap in use", meminfo);
+}
+
+#[cfg(feature = "shell")]
+fn meminfo(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("meminfo"));
+    }
+    let (total, used) = (frame::total_frames() as u64, frame::allocated_frames() as u64);
+    let heap_used = allocator::heap_used() as u64;
+    let heap_total = allocator::HEAP_SIZE as u64;
+    let _ = writeln!(out, "             total       used       free");
+    let _ = writeln!(out, "frames  {:>10} {:>10} {:>10}", total, used, total - used);
+    let _ = writeln!(out, "kB      {:>10} {:>10} {:>10}", total * PAGE_SIZE / 1024, used * PAGE_SIZE / 1024, (total - used) * PAGE_SIZE / 1024);
+    let _ = writeln!(out, "heap kB {:>10} {:>10} {:>10}", heap_total / 1024, heap_used / 1024, (heap_total - heap_used) / 1024);
+    Ok(())
+}
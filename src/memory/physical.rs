@@ -0,0 +1,98 @@
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, PageTable, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{frame, kernel_p4, physical_memory_offset};
+
+/* what page tables are built from.
+    An address space walks and edits its tables through the physical memory they are
+    in and gets its frames from an allocator. For the kernel that is all of RAM,
+    reached through the bootloader's mapping, and the frame allocator; tests can hand
+    in a few frames on the heap instead, with addresses of their own, and check the
+    table logic without going near CR3 or real memory.
+ */
+
+/// # Safety
+/// Every frame `allocate` hands out must be reachable at `offset` plus its address,
+/// and used for nothing else while allocated.
+pub unsafe trait PhysicalMemory {
+    /// Where physical address 0 would be, every frame is at this plus its address.
+    fn offset(&self) -> VirtAddr;
+
+    fn allocate(&self) -> Option<PhysFrame>;
+
+    /// Frees a frame with no other references.
+    fn free(&self, frame: PhysFrame);
+
+    /// One more mapping refers to `frame`.
+    fn share(&self, frame: PhysFrame);
+
+    /// Drops one reference to `frame`, freeing it with the last.
+    fn release(&self, frame: PhysFrame);
+
+    fn ref_count(&self, frame: PhysFrame) -> usize;
+
+    /// The table whose kernel entries every address space copies, if there is one.
+    fn kernel_p4(&self) -> Option<PhysFrame>;
+
+    /// Whether the CPU runs on the tables at `p4`.
+    fn is_loaded(&self, p4: PhysFrame) -> bool;
+
+    fn to_virt(&self, address: PhysAddr) -> VirtAddr {
+        self.offset() + address.as_u64()
+    }
+
+    /// # Safety
+    /// `frame` must hold a page table, and no other reference to it may be alive.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn table(&self, frame: PhysFrame) -> &'static mut PageTable {
+        &mut *self.to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+    }
+}
+
+/// All of RAM and the kernel's frame allocator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelMemory;
+
+unsafe impl PhysicalMemory for KernelMemory {
+    fn offset(&self) -> VirtAddr {
+        physical_memory_offset()
+    }
+
+    fn allocate(&self) -> Option<PhysFrame> {
+        frame::allocate()
+    }
+
+    fn free(&self, frame: PhysFrame) {
+        frame::free(frame);
+    }
+
+    fn share(&self, frame: PhysFrame) {
+        frame::share(frame);
+    }
+
+    fn release(&self, frame: PhysFrame) {
+        frame::release(frame);
+    }
+
+    fn ref_count(&self, frame: PhysFrame) -> usize {
+        frame::ref_count(frame)
+    }
+
+    fn kernel_p4(&self) -> Option<PhysFrame> {
+        Some(kernel_p4())
+    }
+
+    fn is_loaded(&self, p4: PhysFrame) -> bool {
+        Cr3::read().0 == p4
+    }
+}
+
+/// Hands the frames of a `PhysicalMemory` to the `x86_64` paging code.
+pub struct Frames<'a, M: PhysicalMemory>(pub &'a M);
+
+unsafe impl<M: PhysicalMemory> FrameAllocator<Size4KiB> for Frames<'_, M> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.0.allocate()
+    }
+}
@@ -0,0 +1,292 @@
+use core::arch::asm;
+use bitflags::bitflags;
+use x86_64::{PhysAddr, VirtAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(u32);
+
+impl Msr {
+    pub const EFER: Msr              = Msr(0xC000_0080);
+    pub const STAR: Msr              = Msr(0xC000_0081);
+    pub const LSTAR: Msr             = Msr(0xC000_0082);
+    pub const SFMASK: Msr            = Msr(0xC000_0084);
+    pub const FS_BASE: Msr           = Msr(0xC000_0100);
+    pub const GS_BASE: Msr           = Msr(0xC000_0101);
+    pub const KERNEL_GS_BASE: Msr    = Msr(0xC000_0102);
+    pub const APIC_BASE: Msr         = Msr(0x0000_001B);
+    pub const MISC_ENABLE: Msr       = Msr(0x0000_01A0);
+    pub const PAT: Msr               = Msr(0x0000_0277);
+    pub const TSC_DEADLINE: Msr      = Msr(0x0000_06E0);
+
+    pub const fn new(address: u32) -> Self {
+        Msr(address)
+    }
+
+    pub const fn address(self) -> u32 {
+        self.0
+    }
+
+    /// # Safety
+    /// Reading an MSR the CPU does not implement raises #GP.
+    pub unsafe fn read(self) -> u64 {
+        let (high, low): (u32, u32);
+        asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high,
+            options(nomem, nostack, preserves_flags));
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    /// # Safety
+    /// Writing an MSR can change paging, syscall or interrupt behavior underneath the kernel.
+    pub unsafe fn write(self, value: u64) {
+        let low = value as u32;
+        let high = (value >> 32) as u32;
+        asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high,
+            options(nostack, preserves_flags));
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EferFlags: u64 {
+        const SYSTEM_CALL_EXTENSIONS = 1 << 0;
+        const LONG_MODE_ENABLE = 1 << 8;
+        const LONG_MODE_ACTIVE = 1 << 10;
+        const NO_EXECUTE_ENABLE = 1 << 11;
+        const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12;
+        const LONG_MODE_SEGMENT_LIMIT_ENABLE = 1 << 13;
+        const FAST_FXSAVE_FXRSTOR = 1 << 14;
+        const TRANSLATION_CACHE_EXTENSION = 1 << 15;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ApicBaseFlags: u64 {
+        const BSP = 1 << 8;
+        const X2APIC_ENABLE = 1 << 10;
+        const APIC_GLOBAL_ENABLE = 1 << 11;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MiscEnableFlags: u64 {
+        const FAST_STRINGS = 1 << 0;
+        const AUTOMATIC_THERMAL_CONTROL = 1 << 3;
+        const PERFORMANCE_MONITORING = 1 << 7;
+        const BRANCH_TRACE_STORAGE_UNAVAILABLE = 1 << 11;
+        const PEBS_UNAVAILABLE = 1 << 12;
+        const ENHANCED_SPEEDSTEP = 1 << 16;
+        const MONITOR_FSM = 1 << 18;
+        const LIMIT_CPUID_MAXVAL = 1 << 22;
+        const XTPR_MESSAGE_DISABLE = 1 << 23;
+        const XD_BIT_DISABLE = 1 << 34;
+    }
+}
+
+pub struct Efer;
+
+impl Efer {
+    pub fn read() -> EferFlags {
+        EferFlags::from_bits_truncate(unsafe { Msr::EFER.read() })
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(flags: EferFlags) {
+        // keep reserved bits untouched
+        let reserved = Msr::EFER.read() & !EferFlags::all().bits();
+        Msr::EFER.write(reserved | flags.bits());
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn update<F: FnOnce(&mut EferFlags)>(f: F) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+}
+
+/* STAR layout
+    63..48  selector base used by sysret (user CS = base + 16, user SS = base + 8)
+    47..32  selector base used by syscall (kernel CS = base, kernel SS = base + 8)
+    31..0   legacy 32-bit syscall target, unused in long mode
+ */
+pub struct Star;
+
+impl Star {
+    pub fn read() -> (u16, u16) {
+        let value = unsafe { Msr::STAR.read() };
+        ((value >> 48) as u16, (value >> 32) as u16)
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(sysret_base: u16, syscall_base: u16) {
+        Msr::STAR.write(((sysret_base as u64) << 48) | ((syscall_base as u64) << 32));
+    }
+}
+
+pub struct LStar;
+
+impl LStar {
+    pub fn read() -> VirtAddr {
+        VirtAddr::new(unsafe { Msr::LSTAR.read() })
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(entry: VirtAddr) {
+        Msr::LSTAR.write(entry.as_u64());
+    }
+}
+
+pub struct SfMask;
+
+impl SfMask {
+    pub fn read() -> u64 {
+        unsafe { Msr::SFMASK.read() }
+    }
+
+    /// RFLAGS bits set in `mask` are cleared on `syscall` entry.
+    ///
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(mask: u64) {
+        Msr::SFMASK.write(mask);
+    }
+}
+
+pub struct GsBase;
+
+impl GsBase {
+    pub fn read() -> VirtAddr {
+        VirtAddr::new(unsafe { Msr::GS_BASE.read() })
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(base: VirtAddr) {
+        Msr::GS_BASE.write(base.as_u64());
+    }
+}
+
+pub struct KernelGsBase;
+
+impl KernelGsBase {
+    pub fn read() -> VirtAddr {
+        VirtAddr::new(unsafe { Msr::KERNEL_GS_BASE.read() })
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(base: VirtAddr) {
+        Msr::KERNEL_GS_BASE.write(base.as_u64());
+    }
+}
+
+pub struct ApicBase;
+
+impl ApicBase {
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    pub fn read() -> (PhysAddr, ApicBaseFlags) {
+        let value = unsafe { Msr::APIC_BASE.read() };
+        (PhysAddr::new(value & Self::ADDRESS_MASK), ApicBaseFlags::from_bits_truncate(value))
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(address: PhysAddr, flags: ApicBaseFlags) {
+        Msr::APIC_BASE.write((address.as_u64() & Self::ADDRESS_MASK) | flags.bits());
+    }
+}
+
+pub struct MiscEnable;
+
+impl MiscEnable {
+    pub fn read() -> MiscEnableFlags {
+        MiscEnableFlags::from_bits_truncate(unsafe { Msr::MISC_ENABLE.read() })
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(flags: MiscEnableFlags) {
+        let reserved = Msr::MISC_ENABLE.read() & !MiscEnableFlags::all().bits();
+        Msr::MISC_ENABLE.write(reserved | flags.bits());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PatMemoryType {
+    Uncacheable    = 0x0,
+    WriteCombining = 0x1,
+    WriteThrough   = 0x4,
+    WriteProtected = 0x5,
+    WriteBack      = 0x6,
+    Uncached       = 0x7,
+}
+
+impl PatMemoryType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x0 => Some(PatMemoryType::Uncacheable),
+            0x1 => Some(PatMemoryType::WriteCombining),
+            0x4 => Some(PatMemoryType::WriteThrough),
+            0x5 => Some(PatMemoryType::WriteProtected),
+            0x6 => Some(PatMemoryType::WriteBack),
+            0x7 => Some(PatMemoryType::Uncached),
+            _ => None,
+        }
+    }
+}
+
+pub struct Pat;
+
+impl Pat {
+    pub fn read() -> [Option<PatMemoryType>; 8] {
+        let value = unsafe { Msr::PAT.read() };
+        let mut entries = [None; 8];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            *entry = PatMemoryType::from_u8(((value >> (i * 8)) & 0x7) as u8);
+        }
+        entries
+    }
+
+    /// # Safety
+    /// See [`Msr::write`].
+    pub unsafe fn write(entries: [PatMemoryType; 8]) {
+        let mut value = 0u64;
+        for (i, entry) in entries.iter().enumerate() {
+            value |= (*entry as u64) << (i * 8);
+        }
+        Msr::PAT.write(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_efer_long_mode_active() {
+        let efer = Efer::read();
+        assert!(efer.contains(EferFlags::LONG_MODE_ENABLE));
+        assert!(efer.contains(EferFlags::LONG_MODE_ACTIVE));
+    }
+
+    #[test_case]
+    fn test_apic_base_bsp() {
+        let (address, flags) = ApicBase::read();
+        assert!(flags.contains(ApicBaseFlags::BSP));
+        assert_eq!(address.as_u64() & 0xfff, 0);
+    }
+
+    #[test_case]
+    fn test_pat_power_on_default() {
+        // the first entry stays write-back unless someone reprograms PAT
+        assert_eq!(Pat::read()[0], Some(PatMemoryType::WriteBack));
+    }
+}
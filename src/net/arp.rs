@@ -0,0 +1,294 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+use core::time::Duration;
+
+use super::ethernet::{self, Frame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::{Interface, MacAddress, NetError};
+use crate::{time, workqueue};
+
+/* ARP.
+    Finds the hardware address behind an IPv4 address on the local link. Answers
+    are kept for a while per interface; a packet for an address still being asked
+    for waits in the entry and goes out with the reply. Nobody answering a few
+    requests drops the waiting packets, as a lost packet would have been.
+ */
+
+/// How long an answer is believed.
+pub const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REQUESTS: u32 = 3;
+// packets waiting for one address, more are dropped
+const MAX_PENDING: usize = 8;
+
+const PACKET_LEN: usize = 28;
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+    operation: u16,
+    sender_mac: MacAddress,
+    sender_ip: Ipv4Addr,
+    target_mac: MacAddress,
+    target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// Only Ethernet and IPv4, nothing else is spoken here.
+    fn parse(bytes: &[u8]) -> Result<Self, NetError> {
+        if bytes.len() < PACKET_LEN
+            || u16::from_be_bytes([bytes[0], bytes[1]]) != HARDWARE_ETHERNET
+            || u16::from_be_bytes([bytes[2], bytes[3]]) != ETHERTYPE_IPV4
+            || bytes[4] != 6
+            || bytes[5] != 4
+        {
+            return Err(NetError::Malformed);
+        }
+        let ip = |offset: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[offset..offset + 4]).unwrap());
+        Ok(Packet {
+            operation: u16::from_be_bytes([bytes[6], bytes[7]]),
+            sender_mac: MacAddress(bytes[8..14].try_into().unwrap()),
+            sender_ip: ip(14),
+            target_mac: MacAddress(bytes[18..24].try_into().unwrap()),
+            target_ip: ip(24),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PACKET_LEN);
+        bytes.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        bytes.extend_from_slice(&[6, 4]);
+        bytes.extend_from_slice(&self.operation.to_be_bytes());
+        bytes.extend_from_slice(&self.sender_mac.0);
+        bytes.extend_from_slice(&self.sender_ip.octets());
+        bytes.extend_from_slice(&self.target_mac.0);
+        bytes.extend_from_slice(&self.target_ip.octets());
+        bytes
+    }
+}
+
+enum Entry {
+    Resolved { mac: MacAddress, expires: u64 },
+    /// Asked `requests` times, with packets to send once answered.
+    Pending { requests: u32, packets: Vec<(u16, Vec<u8>)> },
+}
+
+/// The answers one interface got, in its `arp` field.
+#[derive(Default)]
+pub struct Table {
+    entries: BTreeMap<Ipv4Addr, Entry>,
+}
+
+fn expiry() -> u64 {
+    time::uptime_ms() + ENTRY_LIFETIME.as_millis() as u64
+}
+
+/// The hardware address of `address`, if it is known and not stale.
+pub fn lookup(interface: &Interface, address: Ipv4Addr) -> Option<MacAddress> {
+    match interface.arp.lock().entries.get(&address) {
+        Some(&Entry::Resolved { mac, expires }) if expires > time::uptime_ms() => Some(mac),
+        _ => None,
+    }
+}
+
+/// Resolved addresses of `interface`, stale ones included.
+pub fn entries(interface: &Interface) -> Vec<(Ipv4Addr, MacAddress)> {
+    interface.arp.lock().entries.iter()
+        .filter_map(|(&address, entry)| match entry {
+            Entry::Resolved { mac, .. } => Some((address, *mac)),
+            Entry::Pending { .. } => None,
+        })
+        .collect()
+}
+
+/// Sends `payload` to the station with IPv4 address `next_hop`, asking for its
+/// hardware address first if it is not known. The packet is sent by the time
+/// this returns only if the address was known.
+pub fn send(interface: &Arc<Interface>, next_hop: Ipv4Addr, ether_type: u16, payload: &[u8]) -> Result<(), NetError> {
+    if next_hop.is_broadcast() {
+        return ethernet::transmit(interface, MacAddress::BROADCAST, ether_type, payload);
+    }
+    if interface.is_loopback() {
+        return ethernet::transmit(interface, interface.mac_address(), ether_type, payload);
+    }
+    if payload.len() > interface.mtu() {
+        return Err(NetError::TooLong);
+    }
+    let now = time::uptime_ms();
+    let mut table = interface.arp.lock();
+    let entry = table.entries.entry(next_hop).or_insert(Entry::Pending { requests: 0, packets: Vec::new() });
+    if let Entry::Resolved { expires, .. } = entry {
+        if *expires <= now {
+            *entry = Entry::Pending { requests: 0, packets: Vec::new() };
+        }
+    }
+    match entry {
+        Entry::Resolved { mac, .. } => {
+            let mac = *mac;
+            drop(table);
+            ethernet::transmit(interface, mac, ether_type, payload)
+        }
+        Entry::Pending { requests, packets } => {
+            if packets.len() < MAX_PENDING {
+                packets.push((ether_type, payload.to_vec()));
+            }
+            let first = *requests == 0;
+            if first {
+                *requests = 1;
+            }
+            drop(table);
+            if first {
+                request(interface, next_hop);
+                schedule_retry(interface, next_hop);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn request(interface: &Interface, address: Ipv4Addr) {
+    let packet = Packet {
+        operation: OPERATION_REQUEST,
+        sender_mac: interface.mac_address(),
+        // without an address of our own, a probe
+        sender_ip: interface.ipv4_address().unwrap_or(Ipv4Addr::UNSPECIFIED),
+        target_mac: MacAddress::default(),
+        target_ip: address,
+    };
+    // a request that did not go out is asked again
+    let _ = ethernet::transmit(interface, MacAddress::BROADCAST, ETHERTYPE_ARP, &packet.to_bytes());
+}
+
+fn schedule_retry(interface: &Arc<Interface>, address: Ipv4Addr) {
+    let interface = Arc::downgrade(interface);
+    workqueue::spawn_after(RETRY_INTERVAL, move || {
+        if let Some(interface) = interface.upgrade() {
+            retry(&interface, address);
+        }
+    });
+}
+
+/// Asks again for `address` if it is still unanswered, gives up after a few times.
+fn retry(interface: &Arc<Interface>, address: Ipv4Addr) {
+    let mut table = interface.arp.lock();
+    match table.entries.get_mut(&address) {
+        Some(Entry::Pending { requests, .. }) if *requests < MAX_REQUESTS => {
+            *requests += 1;
+            drop(table);
+            request(interface, address);
+            schedule_retry(interface, address);
+        }
+        Some(Entry::Pending { .. }) => {
+            table.entries.remove(&address);
+        }
+        _ => {}
+    }
+}
+
+/// Learns `mac` for `address` and sends what was waiting for it.
+fn learn(interface: &Interface, address: Ipv4Addr, mac: MacAddress) {
+    let previous = interface.arp.lock().entries.insert(address, Entry::Resolved { mac, expires: expiry() });
+    if let Some(Entry::Pending { packets, .. }) = previous {
+        for (ether_type, payload) in packets {
+            let _ = ethernet::transmit(interface, mac, ether_type, &payload);
+        }
+    }
+}
+
+/* an incoming request or reply.
+    As RFC 826 has it: the sender is updated if already known, and learned if the
+    packet was meant for us, since we will likely talk back. Requests for our own
+    address get a reply.
+ */
+fn receive(interface: &Arc<Interface>, frame: &Frame) {
+    let Ok(packet) = Packet::parse(frame.payload) else {
+        interface.count_dropped();
+        return;
+    };
+    let ours = interface.ipv4_address();
+    let for_us = ours == Some(packet.target_ip);
+    let known = interface.arp.lock().entries.contains_key(&packet.sender_ip);
+    if !packet.sender_ip.is_unspecified() && (known || for_us) {
+        learn(interface, packet.sender_ip, packet.sender_mac);
+    }
+    if packet.operation == OPERATION_REQUEST && for_us {
+        let reply = Packet {
+            operation: OPERATION_REPLY,
+            sender_mac: interface.mac_address(),
+            sender_ip: packet.target_ip,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        let _ = ethernet::transmit(interface, packet.sender_mac, ETHERTYPE_ARP, &reply.to_bytes());
+    }
+}
+
+pub fn init() {
+    ethernet::register_protocol(ETHERTYPE_ARP, receive);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use crate::net::ipv4::{self, Ipv4Config};
+    use crate::net::{register, unregister};
+
+    const MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const GATEWAY_MAC: MacAddress = MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    fn arp_frame(packet: &Packet, destination: MacAddress) -> Vec<u8> {
+        Frame { destination, source: packet.sender_mac, ether_type: ETHERTYPE_ARP, payload: &packet.to_bytes() }.to_bytes()
+    }
+
+    #[test_case]
+    fn test_requests_and_replies() {
+        let device = TestDevice::new(MAC);
+        let interface = register("test-arp", device.clone()).unwrap();
+        ipv4::configure(&interface, Some(Ipv4Config { address: ADDRESS, prefix_len: 24 }), None);
+
+        // asked for our address, we answer and remember who asked
+        let asking = Packet { operation: OPERATION_REQUEST, sender_mac: GATEWAY_MAC, sender_ip: GATEWAY, target_mac: MacAddress::default(), target_ip: ADDRESS };
+        device.inject(&arp_frame(&asking, MacAddress::BROADCAST));
+        workqueue::flush();
+        let sent = device.take_sent();
+        let frame = Frame::parse(&sent[0]).unwrap();
+        assert_eq!(frame.destination, GATEWAY_MAC);
+        let reply = Packet::parse(frame.payload).unwrap();
+        assert_eq!((reply.operation, reply.sender_mac, reply.sender_ip, reply.target_ip), (OPERATION_REPLY, MAC, ADDRESS, GATEWAY));
+        assert_eq!(lookup(&interface, GATEWAY), Some(GATEWAY_MAC));
+
+        // a packet for an unknown address waits for the answer
+        let dns = Ipv4Addr::new(10, 0, 2, 3);
+        let dns_mac = MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x03]);
+        send(&interface, dns, ETHERTYPE_IPV4, b"query").unwrap();
+        send(&interface, dns, ETHERTYPE_IPV4, b"again").unwrap();
+        let sent = device.take_sent();
+        assert_eq!(sent.len(), 1);
+        let request = Packet::parse(Frame::parse(&sent[0]).unwrap().payload).unwrap();
+        assert_eq!((request.operation, request.target_ip), (OPERATION_REQUEST, dns));
+
+        let answer = Packet { operation: OPERATION_REPLY, sender_mac: dns_mac, sender_ip: dns, target_mac: MAC, target_ip: ADDRESS };
+        device.inject(&arp_frame(&answer, MAC));
+        workqueue::flush();
+        let sent = device.take_sent();
+        assert_eq!(sent.len(), 2);
+        let frame = Frame::parse(&sent[1]).unwrap();
+        assert_eq!((frame.destination, frame.ether_type, &frame.payload[..5]), (dns_mac, ETHERTYPE_IPV4, &b"again"[..]));
+        assert!(entries(&interface).contains(&(dns, dns_mac)));
+
+        // somebody else's conversation teaches us nothing
+        let other = Packet { operation: OPERATION_REQUEST, sender_mac: dns_mac, sender_ip: Ipv4Addr::new(10, 0, 2, 9), target_mac: MacAddress::default(), target_ip: GATEWAY };
+        device.inject(&arp_frame(&other, MacAddress::BROADCAST));
+        workqueue::flush();
+        assert!(device.take_sent().is_empty());
+        assert_eq!(lookup(&interface, Ipv4Addr::new(10, 0, 2, 9)), None);
+        unregister("test-arp");
+    }
+}
@@ -0,0 +1,172 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::slice;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use crate::memory::{frame, phys_to_virt, PAGE_SIZE};
+use crate::sync::SpinLock;
+
+/* packet buffers.
+    A pool hands out fixed-size buffers carved from physical frames, so a driver
+    can give their physical address to the card and have it write received frames
+    straight into them. The filled buffer goes to `RxQueue::push_buffer` and the
+    protocol stack reads it where it is; dropping it puts it back into the pool.
+    The frame is parsed in place all the way to the socket, which copies the
+    payload into its receive buffer, the one copy made. The receive queue holds
+    the buffer itself, and the work queue job that drains it is allocated once
+    per batch of frames, not per frame.
+ */
+
+/// Room for a whole Ethernet frame with the header and a VLAN tag.
+pub const BUFFER_SIZE: usize = 2048;
+const BUFFERS_PER_FRAME: usize = PAGE_SIZE as usize / BUFFER_SIZE;
+
+pub struct PacketPool {
+    frames: Vec<PhysFrame>,
+    // indices of the buffers nobody holds, never grows beyond its capacity
+    free: SpinLock<Vec<usize>>,
+}
+
+impl PacketPool {
+    /// A pool of at least `count` buffers, `None` when the frames run out.
+    pub fn new(count: usize) -> Option<Arc<Self>> {
+        let needed = count.div_ceil(BUFFERS_PER_FRAME);
+        let mut frames = Vec::with_capacity(needed);
+        for _ in 0..needed {
+            match frame::allocate() {
+                Some(frame) => frames.push(frame),
+                None => {
+                    frames.into_iter().for_each(frame::free);
+                    return None;
+                }
+            }
+        }
+        // handed out from the end, so the first buffers go first
+        let free = (0..frames.len() * BUFFERS_PER_FRAME).rev().collect();
+        Some(Arc::new(PacketPool { frames, free: SpinLock::new(free) }))
+    }
+
+    /// A buffer of `BUFFER_SIZE` bytes, empty; `None` while all are in use.
+    pub fn allocate(self: &Arc<Self>) -> Option<PacketBuffer> {
+        let index = self.free.lock().pop()?;
+        Some(PacketBuffer { pool: self.clone(), index, len: 0 })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.frames.len() * BUFFERS_PER_FRAME
+    }
+
+    /// How many buffers are left to allocate.
+    pub fn available(&self) -> usize {
+        self.free.lock().len()
+    }
+
+    fn address(&self, index: usize) -> PhysAddr {
+        self.frames[index / BUFFERS_PER_FRAME].start_address() + ((index % BUFFERS_PER_FRAME) * BUFFER_SIZE) as u64
+    }
+}
+
+impl Drop for PacketPool {
+    fn drop(&mut self) {
+        // every buffer holds the pool, none is left
+        self.frames.drain(..).for_each(frame::free);
+    }
+}
+
+/// One buffer of a pool, the first `len()` bytes are the packet.
+pub struct PacketBuffer {
+    pool: Arc<PacketPool>,
+    index: usize,
+    len: usize,
+}
+
+impl PacketBuffer {
+    /// Where the device is to write, or read what it sends.
+    pub fn physical_address(&self) -> PhysAddr {
+        self.pool.address(self.index)
+    }
+
+    /// All `BUFFER_SIZE` bytes, whatever the length of the packet.
+    pub fn storage(&mut self) -> &mut [u8] {
+        let start = phys_to_virt(self.physical_address()).as_mut_ptr();
+        // the buffer belongs to this handle alone until it is dropped
+        unsafe { slice::from_raw_parts_mut(start, BUFFER_SIZE) }
+    }
+
+    /// Sets how much of the storage the packet takes, once the device filled it.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= BUFFER_SIZE, "packet longer than its buffer");
+        self.len = len;
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let start = phys_to_virt(self.physical_address()).as_ptr();
+        unsafe { slice::from_raw_parts(start, self.len) }
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let len = self.len;
+        &mut self.storage()[..len]
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        self.pool.free.lock().push(self.index);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use crate::net::{register, unregister, MacAddress};
+    use crate::workqueue;
+
+    #[test_case]
+    fn test_pool() {
+        let frames = frame::allocated_frames();
+        let pool = PacketPool::new(3).unwrap();
+        assert_eq!((pool.capacity(), pool.available()), (4, 4));
+        let mut buffers: Vec<PacketBuffer> = (0..4).map(|_| pool.allocate().unwrap()).collect();
+        assert!(pool.allocate().is_none());
+        assert_eq!(buffers[1].physical_address(), buffers[0].physical_address() + BUFFER_SIZE as u64);
+
+        let buffer = &mut buffers[2];
+        assert!(buffer.is_empty());
+        buffer.storage()[..5].copy_from_slice(b"frame");
+        buffer.set_len(5);
+        assert_eq!(&buffer[..], b"frame");
+        drop(buffers);
+        assert_eq!(pool.available(), 4);
+        drop(pool);
+        assert_eq!(frame::allocated_frames(), frames);
+    }
+
+    #[test_case]
+    fn test_received_in_place() {
+        let device = TestDevice::new(MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+        let interface = register("test-buffer", device.clone()).unwrap();
+        let pool = PacketPool::new(2).unwrap();
+        let mut buffer = pool.allocate().unwrap();
+        buffer.storage()[..64].fill(2);
+        buffer.set_len(64);
+        device.inject_buffer(buffer);
+        assert_eq!(pool.available(), 1);
+
+        // handled and dropped as nobody speaks its protocol, which frees the buffer
+        workqueue::flush();
+        let stats = interface.stats();
+        assert_eq!((stats.rx_packets, stats.rx_bytes, stats.rx_dropped), (1, 64, 1));
+        assert_eq!(pool.available(), 2);
+        unregister("test-buffer");
+    }
+}
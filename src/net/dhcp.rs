@@ -0,0 +1,351 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::time::Duration;
+
+use super::ipv4::{self, Ipv4Config};
+use super::udp::UdpSocket;
+use super::{Interface, MacAddress, NetError};
+use crate::{random, thread, time};
+
+/* DHCP client.
+    DISCOVER and REQUEST go out as broadcasts from 0.0.0.0 with the broadcast flag
+    set, so the answers come back even though the interface has no address yet.
+    Renewing asks for the address of the lease once more, the way a client that
+    reboots does; if the server does not confirm it, we start over.
+ */
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+// everything up to the options: the BOOTP header and the magic cookie
+const FIXED_LEN: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+// times each message is sent, waiting twice as long for an answer every time
+const ATTEMPTS: u32 = 4;
+const FIRST_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait before trying again when no server answered.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+// for servers that do not say, RFC 2131 has no default
+const DEFAULT_LEASE_TIME: Duration = Duration::from_secs(3600);
+
+/// What the server gave us, in force once `acquire` returns it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    pub lease_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    op: u8,
+    xid: u32,
+    flags: u16,
+    // ours, when the client has one
+    client_address: Ipv4Addr,
+    // what the server offers
+    your_address: Ipv4Addr,
+    mac: MacAddress,
+    options: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Message {
+    fn new(op: u8, xid: u32, mac: MacAddress, message_type: u8) -> Self {
+        let mut options = BTreeMap::new();
+        options.insert(OPTION_MESSAGE_TYPE, Vec::from([message_type]));
+        Message {
+            op,
+            xid,
+            flags: 0,
+            client_address: Ipv4Addr::UNSPECIFIED,
+            your_address: Ipv4Addr::UNSPECIFIED,
+            mac,
+            options,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, NetError> {
+        if bytes.len() < FIXED_LEN || bytes[1] != HARDWARE_ETHERNET || bytes[2] != 6 || bytes[236..240] != MAGIC_COOKIE {
+            return Err(NetError::Malformed);
+        }
+        let address = |offset: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[offset..offset + 4]).unwrap());
+        let mut options = BTreeMap::new();
+        let mut rest = &bytes[FIXED_LEN..];
+        while let Some(&code) = rest.first() {
+            match code {
+                OPTION_END => break,
+                OPTION_PAD => rest = &rest[1..],
+                _ => {
+                    let len = *rest.get(1).ok_or(NetError::Malformed)? as usize;
+                    let value = rest.get(2..2 + len).ok_or(NetError::Malformed)?;
+                    options.insert(code, value.to_vec());
+                    rest = &rest[2 + len..];
+                }
+            }
+        }
+        Ok(Message {
+            op: bytes[0],
+            xid: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+            flags: u16::from_be_bytes([bytes[10], bytes[11]]),
+            client_address: address(12),
+            your_address: address(16),
+            mac: MacAddress(bytes[28..34].try_into().unwrap()),
+            options,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from([self.op, HARDWARE_ETHERNET, 6, 0]);
+        bytes.extend_from_slice(&self.xid.to_be_bytes());
+        // seconds since we started
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&self.flags.to_be_bytes());
+        bytes.extend_from_slice(&self.client_address.octets());
+        bytes.extend_from_slice(&self.your_address.octets());
+        // the server and relay agent addresses
+        bytes.resize(28, 0);
+        bytes.extend_from_slice(&self.mac.0);
+        // the rest of the hardware address, the server name and the boot file
+        bytes.resize(236, 0);
+        bytes.extend_from_slice(&MAGIC_COOKIE);
+        for (&code, value) in &self.options {
+            bytes.extend_from_slice(&[code, value.len() as u8]);
+            bytes.extend_from_slice(value);
+        }
+        bytes.push(OPTION_END);
+        bytes
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        self.options.get(&OPTION_MESSAGE_TYPE)?.first().copied()
+    }
+
+    /// The addresses in option `code`, a list of four bytes each.
+    fn addresses(&self, code: u8) -> Vec<Ipv4Addr> {
+        let Some(value) = self.options.get(&code) else {
+            return Vec::new();
+        };
+        value.chunks_exact(4).map(|chunk| Ipv4Addr::from(<[u8; 4]>::try_from(chunk).unwrap())).collect()
+    }
+
+    fn address(&self, code: u8) -> Option<Ipv4Addr> {
+        self.addresses(code).first().copied()
+    }
+
+    fn lease(&self) -> Result<Lease, NetError> {
+        // servers always send the mask, should one not, a /24 is the likeliest
+        let mask = self.address(OPTION_SUBNET_MASK).unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
+        let lease_time = match self.options.get(&OPTION_LEASE_TIME) {
+            Some(value) => Duration::from_secs(u32::from_be_bytes(value.as_slice().try_into().map_err(|_| NetError::Malformed)?) as u64),
+            None => DEFAULT_LEASE_TIME,
+        };
+        Ok(Lease {
+            address: self.your_address,
+            prefix_len: u32::from(mask).count_ones() as u8,
+            gateway: self.address(OPTION_ROUTER),
+            dns_servers: self.addresses(OPTION_DNS),
+            server: self.address(OPTION_SERVER).ok_or(NetError::Malformed)?,
+            lease_time,
+        })
+    }
+}
+
+/// Broadcasts `message` until a reply of one of the types in `answers` comes,
+/// a NAK ends it early.
+fn exchange(socket: &UdpSocket, interface: &Arc<Interface>, message: &Message, answers: &[u8]) -> Result<Message, NetError> {
+    let mut timeout = FIRST_TIMEOUT;
+    let mut buffer = [0u8; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send_on(interface, &message.to_bytes(), SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT))?;
+        let end = time::uptime_ms() + timeout.as_millis() as u64;
+        while let Some(left) = end.checked_sub(time::uptime_ms()).filter(|&left| left > 0) {
+            let count = match socket.recv_from_timeout(&mut buffer, Duration::from_millis(left)) {
+                Ok((count, _)) => count,
+                Err(NetError::TimedOut) => break,
+                Err(error) => return Err(error),
+            };
+            let Ok(reply) = Message::parse(&buffer[..count]) else {
+                continue;
+            };
+            // answers to other clients, or to an earlier round of ours
+            if reply.op != OP_REPLY || reply.xid != message.xid || reply.mac != message.mac {
+                continue;
+            }
+            match reply.message_type() {
+                Some(NAK) => return Err(NetError::ConnectionRefused),
+                Some(kind) if answers.contains(&kind) => return Ok(reply),
+                _ => {}
+            }
+        }
+        timeout *= 2;
+    }
+    Err(NetError::TimedOut)
+}
+
+/// Gets a lease for `interface` and configures it, with its address, the route
+/// through the gateway and the name servers. With the `previous` lease it asks
+/// to keep that address instead of looking for a server first.
+pub fn acquire(interface: &Arc<Interface>, previous: Option<&Lease>) -> Result<Lease, NetError> {
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let mac = interface.mac_address();
+    let xid = random::next_u64() as u32;
+    let mut request = Message::new(OP_REQUEST, xid, mac, REQUEST);
+    request.flags = FLAG_BROADCAST;
+    request.options.insert(OPTION_PARAMETERS, Vec::from([OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS, OPTION_LEASE_TIME]));
+    let address = match previous {
+        Some(lease) => lease.address,
+        None => {
+            let mut discover = request.clone();
+            discover.options.insert(OPTION_MESSAGE_TYPE, Vec::from([DISCOVER]));
+            let offer = exchange(&socket, interface, &discover, &[OFFER])?;
+            let server = offer.address(OPTION_SERVER).ok_or(NetError::Malformed)?;
+            request.options.insert(OPTION_SERVER, server.octets().to_vec());
+            offer.your_address
+        }
+    };
+    request.options.insert(OPTION_REQUESTED_ADDRESS, address.octets().to_vec());
+    let lease = exchange(&socket, interface, &request, &[ACK])?.lease()?;
+    ipv4::configure(interface, Some(Ipv4Config { address: lease.address, prefix_len: lease.prefix_len }), lease.gateway);
+    super::set_nameservers(lease.dns_servers.clone());
+    Ok(lease)
+}
+
+/// Keeps `interface` configured from a background thread, renewing the lease
+/// halfway through and taking the address away once it runs out unrenewed.
+/// Stops when the interface is unregistered.
+pub fn start(interface: Arc<Interface>) {
+    thread::spawn(&format!("dhcp/{}", interface.name()), move || {
+        let mut lease: Option<(Lease, u64)> = None;
+        while super::get(interface.name()).is_some_and(|registered| Arc::ptr_eq(&registered, &interface)) {
+            match acquire(&interface, lease.as_ref().map(|(lease, _)| lease)) {
+                Ok(new) => {
+                    log::info!("{} is {}/{}, lease for {} s", interface.name(), new.address, new.prefix_len, new.lease_time.as_secs());
+                    let renew = new.lease_time / 2;
+                    lease = Some((new.clone(), time::uptime_ms() + new.lease_time.as_millis() as u64));
+                    time::sleep(renew);
+                }
+                Err(error) => {
+                    log::warn!("{}: {:?}", interface.name(), error);
+                    // the address stays until the lease runs out, a new server may still confirm it
+                    if lease.take().is_some_and(|(_, expires)| time::uptime_ms() >= expires) {
+                        ipv4::configure(&interface, None, None);
+                    }
+                    time::sleep(RETRY_DELAY);
+                }
+            }
+        }
+    })
+    .detach();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use crate::net::{register, unregister};
+    use crate::sync::SpinLock;
+    use crate::workqueue;
+
+    const MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    /// The answer of QEMU's server to `message`.
+    fn answer(message: &Message, message_type: u8) -> Message {
+        let mut reply = Message::new(OP_REPLY, message.xid, message.mac, message_type);
+        reply.your_address = Ipv4Addr::new(10, 0, 2, 15);
+        reply.options.insert(OPTION_SERVER, SERVER.octets().to_vec());
+        reply.options.insert(OPTION_SUBNET_MASK, Vec::from([255, 255, 255, 0]));
+        reply.options.insert(OPTION_ROUTER, SERVER.octets().to_vec());
+        reply.options.insert(OPTION_DNS, Vec::from([10, 0, 2, 3]));
+        reply.options.insert(OPTION_LEASE_TIME, 86400u32.to_be_bytes().to_vec());
+        reply
+    }
+
+    #[test_case]
+    fn test_messages() {
+        let mut discover = Message::new(OP_REQUEST, 0x1234_5678, MAC, DISCOVER);
+        discover.flags = FLAG_BROADCAST;
+        let bytes = discover.to_bytes();
+        assert_eq!(&bytes[..12], &[1, 1, 6, 0, 0x12, 0x34, 0x56, 0x78, 0, 0, 0x80, 0]);
+        assert_eq!(&bytes[240..], &[OPTION_MESSAGE_TYPE, 1, DISCOVER, OPTION_END]);
+        assert_eq!(Message::parse(&bytes), Ok(discover.clone()));
+        assert_eq!(Message::parse(&bytes[..239]), Err(NetError::Malformed));
+
+        let lease = answer(&discover, ACK).lease().unwrap();
+        assert_eq!((lease.address, lease.prefix_len, lease.gateway), (Ipv4Addr::new(10, 0, 2, 15), 24, Some(SERVER)));
+        assert_eq!((lease.dns_servers, lease.lease_time), (Vec::from([Ipv4Addr::new(10, 0, 2, 3)]), Duration::from_secs(86400)));
+    }
+
+    #[test_case]
+    fn test_acquire() {
+        static RESULT: SpinLock<Option<Result<Lease, NetError>>> = SpinLock::new(None);
+
+        let device = TestDevice::new(MAC);
+        let interface = register("test-dhcp", device.clone()).unwrap();
+        // the server is a socket on the same interface, whatever is sent comes back
+        let server = UdpSocket::bind(SERVER_PORT).unwrap();
+        let client = {
+            let interface = interface.clone();
+            thread::spawn("dhcp-test", move || *RESULT.lock() = Some(acquire(&interface, None)))
+        };
+        let mut buffer = [0u8; 1024];
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            for frame in device.take_sent() {
+                device.inject(&frame);
+            }
+            workqueue::flush();
+            let Ok((count, from)) = server.recv_from_timeout(&mut buffer, Duration::from_millis(10)) else {
+                continue;
+            };
+            assert_eq!(from, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT));
+            let message = Message::parse(&buffer[..count]).unwrap();
+            let kind = message.message_type().unwrap();
+            if kind == REQUEST {
+                assert_eq!(message.address(OPTION_REQUESTED_ADDRESS), Some(Ipv4Addr::new(10, 0, 2, 15)));
+                assert_eq!(message.address(OPTION_SERVER), Some(SERVER));
+            }
+            let reply = answer(&message, if kind == DISCOVER { OFFER } else { ACK });
+            server.send_on(&interface, &reply.to_bytes(), SocketAddrV4::new(Ipv4Addr::BROADCAST, CLIENT_PORT)).unwrap();
+            seen.push(kind);
+        }
+        for frame in device.take_sent() {
+            device.inject(&frame);
+        }
+        client.join();
+        assert_eq!(seen, [DISCOVER, REQUEST]);
+        let lease = RESULT.lock().take().unwrap().unwrap();
+        assert_eq!(lease.server, SERVER);
+        assert_eq!(interface.ipv4(), Some(Ipv4Config { address: Ipv4Addr::new(10, 0, 2, 15), prefix_len: 24 }));
+        assert_eq!(ipv4::route(Ipv4Addr::new(1, 1, 1, 1)).map(|(_, next_hop)| next_hop), Some(SERVER));
+        assert_eq!(crate::net::nameservers(), [Ipv4Addr::new(10, 0, 2, 3)]);
+        unregister("test-dhcp");
+    }
+}
@@ -0,0 +1,268 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::pin::pin;
+use core::time::Duration;
+use futures_util::future::{select, Either};
+
+use super::udp::UdpSocket;
+use super::NetError;
+use crate::random;
+use crate::sync::SpinLock;
+use crate::time::{self, sleep_async};
+
+/* DNS stub resolver.
+    Asks the servers DHCP handed out for A records, one after the other, and takes
+    the first answer; the server does the recursion. Answers are cached for their
+    TTL, failures are not.
+ */
+
+const PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+// responses over UDP are no longer than this, longer ones come truncated
+const MAX_MESSAGE_LEN: usize = 512;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NAME_ERROR: u16 = 3;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+// rounds over all servers, and how long to wait for each answer
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(2);
+const CACHE_SIZE: usize = 32;
+/// Answers are cached no longer than this, whatever their TTL.
+const MAX_TTL: u32 = 3600;
+
+// by name, the addresses and when they expire
+static CACHE: SpinLock<BTreeMap<String, (Vec<Ipv4Addr>, u64)>> = SpinLock::new(BTreeMap::new());
+
+/// A query for the A records of `name`.
+fn query(id: u16, name: &str) -> Result<Vec<u8>, NetError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(NetError::InvalidName);
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no records
+    bytes.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(NetError::InvalidName);
+        }
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    bytes.extend_from_slice(&TYPE_A.to_be_bytes());
+    bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(bytes)
+}
+
+/// Where the name at `offset` ends, compressed names end at their first pointer.
+fn skip_name(bytes: &[u8], mut offset: usize) -> Result<usize, NetError> {
+    loop {
+        let len = *bytes.get(offset).ok_or(NetError::Malformed)? as usize;
+        match len {
+            0 => return Ok(offset + 1),
+            _ if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+            _ => offset += 1 + len,
+        }
+    }
+}
+
+/* the addresses in a response.
+    `None` if it does not answer query `id`. The names of the answers are not
+    checked against the question, so the A records at the end of a CNAME chain
+    count as well. The TTL returned is the shortest among them.
+ */
+fn parse(bytes: &[u8], id: u16) -> Result<Option<(Vec<Ipv4Addr>, u32)>, NetError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(NetError::Malformed);
+    }
+    let half = |offset: usize| -> Result<u16, NetError> {
+        let pair = bytes.get(offset..offset + 2).ok_or(NetError::Malformed)?;
+        Ok(u16::from_be_bytes([pair[0], pair[1]]))
+    };
+    let flags = half(2)?;
+    if half(0)? != id || flags & FLAG_RESPONSE == 0 {
+        return Ok(None);
+    }
+    match flags & 0xf {
+        0 => {}
+        RCODE_NAME_ERROR => return Err(NetError::NotFound),
+        // the server failed or refused, another one may know
+        _ => return Err(NetError::Io),
+    }
+    let mut offset = HEADER_LEN;
+    for _ in 0..half(4)? {
+        offset = skip_name(bytes, offset)? + 4;
+    }
+    let mut addresses = Vec::new();
+    let mut ttl = MAX_TTL;
+    for _ in 0..half(6)? {
+        offset = skip_name(bytes, offset)?;
+        let (kind, class, len) = (half(offset)?, half(offset + 2)?, half(offset + 8)? as usize);
+        let data = bytes.get(offset + 10..offset + 10 + len).ok_or(NetError::Malformed)?;
+        if (kind, class, len) == (TYPE_A, CLASS_IN, 4) {
+            addresses.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+            ttl = ttl.min(u32::from_be_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()));
+        }
+        offset += 10 + len;
+    }
+    if addresses.is_empty() {
+        // the name exists, but has no address
+        return Err(NetError::NotFound);
+    }
+    Ok(Some((addresses, ttl)))
+}
+
+fn cached(name: &str) -> Option<Vec<Ipv4Addr>> {
+    let mut cache = CACHE.lock();
+    let (addresses, expires) = cache.get(name)?;
+    if *expires > time::uptime_ms() {
+        return Some(addresses.clone());
+    }
+    cache.remove(name);
+    None
+}
+
+fn remember(name: &str, addresses: &[Ipv4Addr], ttl: u32) {
+    let mut cache = CACHE.lock();
+    if cache.len() >= CACHE_SIZE && !cache.contains_key(name) {
+        // the entry that expires first makes room
+        let oldest = cache.iter().min_by_key(|(_, (_, expires))| *expires).map(|(name, _)| name.clone());
+        cache.remove(&oldest.unwrap());
+    }
+    cache.insert(name.into(), (addresses.to_vec(), time::uptime_ms() + ttl as u64 * 1000));
+}
+
+/// Forgets every cached answer.
+pub fn flush_cache() {
+    CACHE.lock().clear();
+}
+
+/// The IPv4 addresses of `hostname`. An address in dotted form is its own answer.
+pub async fn resolve(hostname: &str) -> Result<Vec<Ipv4Addr>, NetError> {
+    if let Ok(address) = hostname.parse() {
+        return Ok(Vec::from([address]));
+    }
+    let name = hostname.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(addresses) = cached(&name) {
+        return Ok(addresses);
+    }
+    let servers = super::nameservers();
+    if servers.is_empty() {
+        return Err(NetError::Unreachable);
+    }
+    let socket = UdpSocket::bind(0)?;
+    let mut buffer = [0u8; MAX_MESSAGE_LEN];
+    for _ in 0..ATTEMPTS {
+        for &server in &servers {
+            let server = SocketAddrV4::new(server, PORT);
+            let id = random::next_u64() as u16;
+            if socket.send_to(&query(id, &name)?, server).is_err() {
+                continue;
+            }
+            let end = time::uptime_ms() + TIMEOUT.as_millis() as u64;
+            while let Some(left) = end.checked_sub(time::uptime_ms()).filter(|&left| left > 0) {
+                let received = {
+                    let receive = pin!(socket.recv_from_async(&mut buffer));
+                    let timeout = pin!(sleep_async(Duration::from_millis(left)));
+                    match select(receive, timeout).await {
+                        Either::Left((received, _)) => received?,
+                        Either::Right(_) => break,
+                    }
+                };
+                let (count, from) = received;
+                if from != server {
+                    continue;
+                }
+                match parse(&buffer[..count], id) {
+                    Ok(Some((addresses, ttl))) => {
+                        remember(&name, &addresses, ttl);
+                        return Ok(addresses);
+                    }
+                    // late answers to an earlier query
+                    Ok(None) => continue,
+                    Err(NetError::NotFound) => return Err(NetError::NotFound),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+    Err(NetError::TimedOut)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+    use crate::task::Task;
+    use alloc::sync::Arc;
+
+    /// Runs `future` to completion on an executor of its own.
+    fn block_on<T: 'static>(future: impl core::future::Future<Output = T> + 'static) -> T {
+        let result = Arc::new(SpinLock::new(None));
+        let mut executor = Executor::new();
+        let slot = result.clone();
+        executor.spawn(Task::new(async move { *slot.lock() = Some(future.await) }));
+        loop {
+            executor.run_ready_tasks();
+            if let Some(result) = result.lock().take() {
+                return result;
+            }
+            x86_64::instructions::hlt();
+        }
+    }
+
+    /// The answer for example.com through a CNAME, as a server sends it.
+    fn response(id: u16) -> Vec<u8> {
+        let mut bytes = query(id, "example.com").unwrap();
+        bytes[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        bytes[6..8].copy_from_slice(&2u16.to_be_bytes());
+        // a CNAME to www.example.com, then its address; the names point back at the question
+        bytes.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 44, 0, 6, 3, b'w', b'w', b'w', 0xc0, 12]);
+        bytes.extend_from_slice(&[0xc0, 41, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        bytes
+    }
+
+    #[test_case]
+    fn test_messages() {
+        let bytes = query(0x1234, "example.com").unwrap();
+        assert_eq!(&bytes[..HEADER_LEN], &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[HEADER_LEN..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+        assert_eq!(query(1, "example..com"), Err(NetError::InvalidName));
+
+        let response = response(0x1234);
+        assert_eq!(parse(&response, 0x1234), Ok(Some((Vec::from([Ipv4Addr::new(93, 184, 216, 34)]), 60))));
+        assert_eq!(parse(&response, 0x4321), Ok(None));
+        assert_eq!(parse(&response[..response.len() - 2], 0x1234), Err(NetError::Malformed));
+        let mut missing = response.clone();
+        missing[3] |= RCODE_NAME_ERROR as u8;
+        assert_eq!(parse(&missing, 0x1234), Err(NetError::NotFound));
+    }
+
+    #[test_case]
+    fn test_cache() {
+        // the cache keeps the room it grew
+        crate::testing::keeps_heap();
+        assert_eq!(block_on(resolve("10.0.2.2")), Ok(Vec::from([Ipv4Addr::new(10, 0, 2, 2)])));
+        remember("cached.test", &[Ipv4Addr::new(192, 0, 2, 1)], 30);
+        assert_eq!(block_on(resolve("Cached.Test.")), Ok(Vec::from([Ipv4Addr::new(192, 0, 2, 1)])));
+        remember("expired.test", &[Ipv4Addr::new(192, 0, 2, 2)], 0);
+        assert_eq!(cached("expired.test"), None);
+
+        for index in 0..CACHE_SIZE as u8 {
+            remember(&alloc::format!("{}.test", index), &[Ipv4Addr::new(192, 0, 2, index)], 60 + index as u32);
+        }
+        assert_eq!(CACHE.lock().len(), CACHE_SIZE);
+        assert_eq!(cached("cached.test"), None);
+        flush_cache();
+        assert!(CACHE.lock().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{Interface, MacAddress, NetError};
+use crate::sync::SpinLock;
+
+/* Ethernet II.
+    Destination, source and the type of the payload, 14 bytes in front of every
+    frame; the card adds the checksum. Frames shorter than 60 bytes are padded, so a
+    payload may come with trailing zeroes its protocol has to know to ignore.
+ */
+
+pub const HEADER_LEN: usize = 14;
+// without the checksum the card appends
+const MIN_FRAME_LEN: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Handles the payload of a frame that carries its protocol.
+pub type Handler = fn(&Arc<Interface>, &Frame);
+
+static PROTOCOLS: SpinLock<BTreeMap<u16, Handler>> = SpinLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ether_type: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, NetError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(NetError::Malformed);
+        }
+        Ok(Frame {
+            destination: MacAddress(bytes[0..6].try_into().unwrap()),
+            source: MacAddress(bytes[6..12].try_into().unwrap()),
+            ether_type: u16::from_be_bytes([bytes[12], bytes[13]]),
+            payload: &bytes[HEADER_LEN..],
+        })
+    }
+
+    /// The frame as it goes on the wire, padded to the minimum length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((HEADER_LEN + self.payload.len()).max(MIN_FRAME_LEN));
+        bytes.extend_from_slice(&self.destination.0);
+        bytes.extend_from_slice(&self.source.0);
+        bytes.extend_from_slice(&self.ether_type.to_be_bytes());
+        bytes.extend_from_slice(self.payload);
+        bytes.resize(bytes.len().max(MIN_FRAME_LEN), 0);
+        bytes
+    }
+}
+
+/// Has frames of `ether_type` handed to `handler`, replacing any handler before it.
+pub fn register_protocol(ether_type: u16, handler: Handler) {
+    PROTOCOLS.lock().insert(ether_type, handler);
+}
+
+/// Sends `payload` to `destination`, from the address of `interface`.
+pub fn transmit(interface: &Interface, destination: MacAddress, ether_type: u16, payload: &[u8]) -> Result<(), NetError> {
+    if payload.len() > interface.mtu() {
+        return Err(NetError::TooLong);
+    }
+    let frame = Frame { destination, source: interface.mac_address(), ether_type, payload };
+    interface.transmit(&frame.to_bytes())
+}
+
+/// Hands a received frame to its protocol. Frames for other stations, which a card
+/// in promiscuous mode lets through, and frames of unknown types are dropped.
+pub fn receive(interface: &Arc<Interface>, bytes: &[u8]) {
+    let Ok(frame) = Frame::parse(bytes) else {
+        interface.count_dropped();
+        return;
+    };
+    if frame.destination != interface.mac_address() && !frame.destination.is_multicast() {
+        interface.count_dropped();
+        return;
+    }
+    let handler = PROTOCOLS.lock().get(&frame.ether_type).copied();
+    match handler {
+        Some(handler) => handler(interface, &frame),
+        None => interface.count_dropped(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use crate::net::{register, unregister};
+    use crate::workqueue;
+    use alloc::vec;
+
+    const MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const PEER: MacAddress = MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+    // the local experimental type, nothing else uses it
+    const ETHERTYPE_TEST: u16 = 0x88b5;
+
+    // an ARP request for 10.0.2.15 from QEMU's user network gateway
+    const ARP_REQUEST: [u8; 60] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x06,
+        0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02,
+        0x0a, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x02, 0x0f,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    static RECEIVED: SpinLock<Vec<Vec<u8>>> = SpinLock::new(Vec::new());
+
+    #[test_case]
+    fn test_canned_frames() {
+        let frame = Frame::parse(&ARP_REQUEST).unwrap();
+        assert!(frame.destination.is_broadcast());
+        assert_eq!(frame.source, PEER);
+        assert_eq!(frame.ether_type, ETHERTYPE_ARP);
+        assert_eq!(frame.payload.len(), 46);
+        assert_eq!(frame.to_bytes(), ARP_REQUEST);
+        assert_eq!(Frame::parse(&ARP_REQUEST[..13]), Err(NetError::Malformed));
+
+        let short = Frame { destination: PEER, source: MAC, ether_type: ETHERTYPE_IPV4, payload: &[1, 2, 3] }.to_bytes();
+        assert_eq!(short.len(), MIN_FRAME_LEN);
+        assert_eq!(&short[..17], &[0x52, 0x55, 0x0a, 0, 2, 2, 0x52, 0x54, 0, 0x12, 0x34, 0x56, 0x08, 0x00, 1, 2, 3]);
+    }
+
+    #[test_case]
+    fn test_demultiplexing() {
+        let device = TestDevice::new(MAC);
+        let interface = register("test-ethernet", device.clone()).unwrap();
+        register_protocol(ETHERTYPE_TEST, |_, frame| RECEIVED.lock().push(frame.payload.to_vec()));
+
+        transmit(&interface, PEER, ETHERTYPE_TEST, b"ping").unwrap();
+        let sent = device.take_sent();
+        let frame = Frame::parse(&sent[0]).unwrap();
+        assert_eq!((frame.source, frame.destination), (MAC, PEER));
+        assert_eq!(transmit(&interface, PEER, ETHERTYPE_TEST, &vec![0; 1501]), Err(NetError::TooLong));
+
+        // to us, broadcast, to somebody else, of a type nobody handles
+        let dropped = interface.stats().rx_dropped;
+        for (destination, ether_type) in [(MAC, ETHERTYPE_TEST), (MacAddress::BROADCAST, ETHERTYPE_TEST), (PEER, ETHERTYPE_TEST), (MAC, 0x86dd)] {
+            let payload = [ether_type as u8; 46];
+            device.inject(&Frame { destination, source: PEER, ether_type, payload: &payload }.to_bytes());
+        }
+        workqueue::flush();
+        assert_eq!(RECEIVED.lock().len(), 2);
+        assert_eq!(interface.stats().rx_dropped - dropped, 2);
+        PROTOCOLS.lock().remove(&ETHERTYPE_TEST);
+        unregister("test-ethernet");
+    }
+}
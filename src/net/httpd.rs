@@ -0,0 +1,367 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::tcp::{TcpListener, TcpStream};
+use super::NetError;
+use crate::fs::{self, path, File, FileType, FsError, OpenFlags};
+use crate::memory::{frame, PAGE_SIZE};
+use crate::thread::{self, scheduler};
+use crate::{allocator, time};
+
+/* httpd.
+    A small HTTP/1.0 server: GET and HEAD for the files below a directory of the
+    VFS, and `/stats` for what the kernel counts. Every connection gets a thread
+    of its own and one request, the response ends when the connection closes.
+    Only regular files are served, devices never end, and only up to a size the
+    heap can hold; a peer gets so long to send its request and so many
+    connections at once, those above are turned away with a 503.
+ */
+
+/// What the kernel serves, kept apart from the rest of the VFS.
+pub const DOCROOT: &str = "/srv/www";
+
+// the request line and headers, anything longer is turned down
+const MAX_HEAD_LEN: usize = 4096;
+// the largest file served
+const MAX_BODY_LEN: usize = 256 * 1024;
+const MAX_CONNECTIONS: usize = 16;
+// for the whole of the request line and headers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+// connections being served, each by a thread
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    BadRequest,
+    Forbidden,
+    NotFound,
+    RequestTimeout,
+    InternalError,
+    NotImplemented,
+    Unavailable,
+}
+
+impl Status {
+    fn line(self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::Forbidden => "403 Forbidden",
+            Status::NotFound => "404 Not Found",
+            Status::RequestTimeout => "408 Request Timeout",
+            Status::InternalError => "500 Internal Server Error",
+            Status::NotImplemented => "501 Not Implemented",
+            Status::Unavailable => "503 Service Unavailable",
+        }
+    }
+}
+
+struct Response {
+    status: Status,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: Status, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response { status, content_type, body }
+    }
+
+    /// The status line as the body, for errors.
+    fn error(status: Status) -> Self {
+        Response::new(status, "text/plain", format!("{}\n", status.line()).into_bytes())
+    }
+}
+
+/// Method and path of the request line, a missing version counts as HTTP/1.0.
+fn parse_request(head: &[u8]) -> Result<(&str, &str), Status> {
+    let head = core::str::from_utf8(head).map_err(|_| Status::BadRequest)?;
+    let line = head.lines().next().ok_or(Status::BadRequest)?;
+    let mut parts = line.split_ascii_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Status::BadRequest);
+    };
+    if parts.next().is_some_and(|version| !version.starts_with("HTTP/1.")) || parts.next().is_some() {
+        return Err(Status::BadRequest);
+    }
+    if !target.starts_with('/') {
+        return Err(Status::BadRequest);
+    }
+    Ok((method, target.split('?').next().unwrap()))
+}
+
+/// Undoes the %XX escapes of a path.
+fn decode(target: &str) -> Result<String, Status> {
+    let bytes = target.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3).and_then(|hex| core::str::from_utf8(hex).ok());
+            decoded.push(hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()).ok_or(Status::BadRequest)?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| Status::BadRequest)
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html" | "htm") => "text/html",
+        Some("txt" | "md" | "rs") => "text/plain",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn stats() -> String {
+    let mut text = String::new();
+    let total = frame::total_frames() as u64 * PAGE_SIZE;
+    let used = frame::allocated_frames() as u64 * PAGE_SIZE;
+    let _ = writeln!(text, "uptime_ms {}", time::uptime_ms());
+    let _ = writeln!(text, "requests {}", REQUESTS.load(Ordering::Relaxed));
+    let _ = writeln!(text, "memory_total {}\nmemory_used {}", total, used);
+    let _ = writeln!(text, "heap_total {}\nheap_used {}", allocator::HEAP_SIZE, allocator::heap_used());
+    let threads = scheduler::stats();
+    let _ = writeln!(text, "threads {}", threads.len());
+    for thread in threads {
+        let _ = writeln!(
+            text,
+            "thread {} {} {:?} cpu={} run_ms={} switches={}",
+            thread.id.as_u64(), thread.name, thread.state, thread.cpu, thread.run_time.as_millis(), thread.context_switches,
+        );
+    }
+    for name in super::interfaces() {
+        let Some(interface) = super::get(&name) else {
+            continue;
+        };
+        let stats = interface.stats();
+        let _ = writeln!(
+            text,
+            "interface {} rx_packets={} rx_bytes={} rx_dropped={} tx_packets={} tx_bytes={} tx_errors={}",
+            name, stats.rx_packets, stats.rx_bytes, stats.rx_dropped, stats.tx_packets, stats.tx_bytes, stats.tx_errors,
+        );
+    }
+    text
+}
+
+/// The regular file at `path` as a response, 403 when it is larger than a response may be.
+fn regular_file(path: &str, content_type: &'static str) -> Result<Response, FsError> {
+    if fs::stat(path)?.file_type != FileType::Regular {
+        return Err(FsError::NotFound);
+    }
+    // the size may change under the read, so the read is bounded too
+    let file = fs::open(path, OpenFlags::READ)?;
+    let mut body = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(Response::new(Status::Ok, content_type, body)),
+            _ if body.len() >= MAX_BODY_LEN => return Ok(Response::error(Status::Forbidden)),
+            read => body.extend_from_slice(&chunk[..read]),
+        }
+    }
+}
+
+/// The file at `target` below `root`, a directory by its index.html or a listing.
+fn file(root: &str, target: &str) -> Result<Response, FsError> {
+    // ".." stops at the root of the target, so nothing above `root` is reachable
+    let relative = path::normalize(target)?;
+    let path = match relative.as_str() {
+        "/" => root.to_string(),
+        _ => format!("{}{}", root.trim_end_matches('/'), relative),
+    };
+    if fs::stat(&path)?.file_type != FileType::Directory {
+        return regular_file(&path, content_type(&path));
+    }
+    let index = format!("{}/index.html", path.trim_end_matches('/'));
+    if fs::stat(&index).is_ok_and(|metadata| metadata.file_type == FileType::Regular) {
+        return regular_file(&index, "text/html");
+    }
+    let mut listing = String::new();
+    for entry in fs::read_dir(&path)? {
+        let slash = if entry.file_type == FileType::Directory { "/" } else { "" };
+        let _ = writeln!(listing, "{}{}", entry.name, slash);
+    }
+    Ok(Response::new(Status::Ok, "text/plain", listing.into_bytes()))
+}
+
+/// What to answer `head`; the body is left out for HEAD by the caller.
+fn respond(root: &str, head: &[u8]) -> (Response, bool) {
+    let (method, target) = match parse_request(head) {
+        Ok(request) => request,
+        Err(status) => return (Response::error(status), true),
+    };
+    let with_body = method != "HEAD";
+    if method != "GET" && method != "HEAD" {
+        return (Response::error(Status::NotImplemented), true);
+    }
+    let target = match decode(target) {
+        Ok(target) => target,
+        Err(status) => return (Response::error(status), true),
+    };
+    if target == "/stats" {
+        return (Response::new(Status::Ok, "text/plain", stats().into_bytes()), with_body);
+    }
+    let response = match file(root, &target) {
+        Ok(response) => response,
+        Err(FsError::NotFound | FsError::NotDirectory | FsError::InvalidPath | FsError::NameTooLong) => Response::error(Status::NotFound),
+        Err(_) => Response::error(Status::InternalError),
+    };
+    (response, with_body)
+}
+
+/// Reads up to the empty line after the headers, or what came before the peer
+/// stopped sending; `TimedOut` when that takes longer than REQUEST_TIMEOUT.
+fn read_head(stream: &TcpStream) -> Result<Vec<u8>, NetError> {
+    let end = time::uptime_ms() + REQUEST_TIMEOUT.as_millis() as u64;
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 512];
+    while head.len() < MAX_HEAD_LEN && !head.windows(4).any(|window| window == b"\r\n\r\n") && !head.windows(2).any(|window| window == b"\n\n") {
+        let left = end.checked_sub(time::uptime_ms()).filter(|&left| left > 0).ok_or(NetError::TimedOut)?;
+        match stream.read_timeout(&mut buffer, Duration::from_millis(left))? {
+            0 => break,
+            count => head.extend_from_slice(&buffer[..count]),
+        }
+    }
+    Ok(head)
+}
+
+fn send(stream: &TcpStream, response: &Response, with_body: bool) -> Result<(), NetError> {
+    let header = format!(
+        "HTTP/1.0 {}\r\nServer: blog_os\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status.line(), response.content_type, response.body.len(),
+    );
+    stream.write(header.as_bytes())?;
+    if with_body {
+        stream.write(&response.body)?;
+    }
+    Ok(())
+}
+
+fn serve(stream: TcpStream, root: &str) -> Result<(), NetError> {
+    let (response, with_body) = match read_head(&stream) {
+        Err(NetError::TimedOut) => (Response::error(Status::RequestTimeout), true),
+        Err(error) => return Err(error),
+        Ok(head) if head.len() >= MAX_HEAD_LEN => (Response::error(Status::BadRequest), true),
+        Ok(head) => respond(root, &head),
+    };
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    // dropping the stream closes it after the response
+    send(&stream, &response, with_body)
+}
+
+/// Serves the files below `root` on `port` from a background thread.
+pub fn start(port: u16, root: &str) -> Result<(), NetError> {
+    let listener = TcpListener::bind(port)?;
+    let root = root.to_string();
+    thread::spawn(&format!("httpd/{}", port), move || loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::warn!("accept: {:?}", error);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr();
+        if CONNECTIONS.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+            // short enough to fit the window, the accepting thread does not wait on it
+            let _ = send(&stream, &Response::error(Status::Unavailable), true);
+            continue;
+        }
+        let root = root.clone();
+        thread::spawn(&format!("httpd/{}", peer), move || {
+            if let Err(error) = serve(stream, &root) {
+                log::warn!("{}: {:?}", peer, error);
+            }
+            CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        })
+        .detach();
+    })
+    .detach();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::loopback;
+    use core::net::SocketAddrV4;
+
+    fn get(port: u16, request: &[u8]) -> String {
+        let stream = TcpStream::connect(SocketAddrV4::new(loopback::ADDRESS, port)).unwrap();
+        stream.write(request).unwrap();
+        let mut response = Vec::new();
+        let mut buffer = [0u8; 1024];
+        loop {
+            match stream.read(&mut buffer).unwrap() {
+                0 => return String::from_utf8(response).unwrap(),
+                count => response.extend_from_slice(&buffer[..count]),
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_requests() {
+        // the test files stay in the root file system
+        crate::testing::keeps_heap();
+        assert_eq!(parse_request(b"GET /a?b=c HTTP/1.1\r\nHost: x\r\n\r\n"), Ok(("GET", "/a")));
+        assert_eq!(parse_request(b"GET /\r\n\r\n"), Ok(("GET", "/")));
+        assert_eq!(parse_request(b"GET index.html HTTP/1.0\r\n\r\n"), Err(Status::BadRequest));
+        assert_eq!(parse_request(b"GET / SPDY/3\r\n\r\n"), Err(Status::BadRequest));
+        assert_eq!(decode("/a%20b").as_deref(), Ok("/a b"));
+        assert_eq!(decode("/a%2"), Err(Status::BadRequest));
+
+        fs::create_dir_all("/httpd-test/docs").unwrap();
+        fs::write_file("/httpd-test/hello.txt", b"hello\n").unwrap();
+        fs::write_file("/httpd-test/docs/a.md", b"# a\n").unwrap();
+        let (response, _) = respond("/httpd-test", b"GET /hello.txt HTTP/1.0\r\n\r\n");
+        assert_eq!((response.status, response.content_type, &response.body[..]), (Status::Ok, "text/plain", &b"hello\n"[..]));
+        let (response, _) = respond("/httpd-test", b"GET /docs HTTP/1.0\r\n\r\n");
+        assert_eq!(response.body, b"a.md\n");
+        // not above the root, whatever the path says
+        assert_eq!(respond("/httpd-test", b"GET /../httpd-test/hello.txt HTTP/1.0\r\n\r\n").0.status, Status::NotFound);
+        assert_eq!(respond("/httpd-test", b"POST / HTTP/1.0\r\n\r\n").0.status, Status::NotImplemented);
+        assert!(!respond("/httpd-test", b"HEAD / HTTP/1.0\r\n\r\n").1);
+        // devices never end, and files only up to a size
+        assert_eq!(respond("/", b"GET /dev/random HTTP/1.0\r\n\r\n").0.status, Status::NotFound);
+        fs::write_file("/httpd-test/large.bin", &alloc::vec![0u8; MAX_BODY_LEN + 1]).unwrap();
+        assert_eq!(respond("/httpd-test", b"GET /large.bin HTTP/1.0\r\n\r\n").0.status, Status::Forbidden);
+        fs::unlink("/httpd-test/large.bin").unwrap();
+    }
+
+    #[test_case]
+    fn test_over_loopback() {
+        // the server keeps running
+        crate::testing::keeps_heap();
+        fs::create_dir_all("/httpd-test").unwrap();
+        fs::write_file("/httpd-test/index.html", b"<h1>hi</h1>").unwrap();
+        let port = 8088;
+        start(port, "/httpd-test").unwrap();
+        assert_eq!(start(port, "/"), Err(NetError::AddressInUse));
+
+        let response = get(port, b"GET / HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/html\r\nContent-Length: 11\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>hi</h1>"));
+        assert!(get(port, b"GET /missing HTTP/1.0\r\n\r\n").starts_with("HTTP/1.0 404 Not Found\r\n"));
+        let stats = get(port, b"GET /stats HTTP/1.0\r\n\r\n");
+        assert!(stats.contains("\r\nuptime_ms ") && stats.contains("\ninterface lo "));
+        assert!(stats.contains(&format!(" httpd/{} ", port)));
+    }
+}
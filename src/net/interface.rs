@@ -0,0 +1,282 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::buffer::PacketBuffer;
+use super::ipv4::Ipv4Config;
+use super::{arp, MacAddress, NetError};
+use crate::sync::SpinLock;
+use crate::workqueue;
+
+// received frames waiting to be handled, more are dropped
+const RX_QUEUE_LENGTH: usize = 256;
+
+/// A network card, or anything else that sends and receives link layer frames.
+pub trait NetworkDevice: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+
+    /// Largest payload of a frame, 1500 on Ethernet.
+    fn mtu(&self) -> usize;
+
+    /// Sends one whole frame, link layer header included.
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Called once on registration; the driver hands every frame it receives to `rx`.
+    fn attach(&self, rx: RxQueue);
+
+    /// Whether sent frames come straight back, so no neighbour needs resolving.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Received frames nobody took, or that did not fit the queue.
+    pub rx_dropped: u64,
+    pub tx_errors: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    rx_dropped: AtomicU64,
+    tx_errors: AtomicU64,
+}
+
+/// A received frame, copied by `push` or in the buffer the device filled.
+enum RxFrame {
+    Copied(Vec<u8>),
+    Buffer(PacketBuffer),
+}
+
+impl RxFrame {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            RxFrame::Copied(bytes) => bytes,
+            RxFrame::Buffer(buffer) => buffer,
+        }
+    }
+}
+
+struct RxState {
+    frames: VecDeque<RxFrame>,
+    // a work item is queued to empty `frames`
+    scheduled: bool,
+}
+
+pub struct Interface {
+    name: String,
+    device: Arc<dyn NetworkDevice>,
+    counters: Counters,
+    rx: SpinLock<RxState>,
+    ipv4: SpinLock<Option<Ipv4Config>>,
+    pub(super) arp: SpinLock<arp::Table>,
+}
+
+impl Interface {
+    pub(super) fn new(name: &str, device: Arc<dyn NetworkDevice>) -> Arc<Self> {
+        Arc::new(Interface {
+            name: name.to_string(),
+            device,
+            counters: Counters::default(),
+            rx: SpinLock::new(RxState { frames: VecDeque::with_capacity(RX_QUEUE_LENGTH), scheduled: false }),
+            ipv4: SpinLock::new(None),
+            arp: SpinLock::new(arp::Table::default()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn device(&self) -> &Arc<dyn NetworkDevice> {
+        &self.device
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.device.mac_address()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.device.is_loopback()
+    }
+
+    /// The address and subnet, set with `ipv4::configure`.
+    pub fn ipv4(&self) -> Option<Ipv4Config> {
+        *self.ipv4.lock()
+    }
+
+    pub fn ipv4_address(&self) -> Option<Ipv4Addr> {
+        self.ipv4().map(|config| config.address)
+    }
+
+    pub(super) fn set_ipv4(&self, config: Option<Ipv4Config>) {
+        *self.ipv4.lock() = config;
+    }
+
+    pub fn stats(&self) -> InterfaceStats {
+        let counters = &self.counters;
+        InterfaceStats {
+            rx_packets: counters.rx_packets.load(Ordering::Relaxed),
+            rx_bytes: counters.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: counters.tx_packets.load(Ordering::Relaxed),
+            tx_bytes: counters.tx_bytes.load(Ordering::Relaxed),
+            rx_dropped: counters.rx_dropped.load(Ordering::Relaxed),
+            tx_errors: counters.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sends `frame` as it is, the caller built the link layer header.
+    pub fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        match self.device.transmit(frame) {
+            Ok(()) => {
+                self.counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+                self.counters.tx_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(error) => {
+                self.counters.tx_errors.fetch_add(1, Ordering::Relaxed);
+                Err(error)
+            }
+        }
+    }
+
+    pub(super) fn count_dropped(&self) {
+        self.counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hands the queued frames to the protocol stack, runs on the work queue.
+    fn process(self: &Arc<Self>) {
+        loop {
+            let frame = {
+                let mut rx = self.rx.lock();
+                match rx.frames.pop_front() {
+                    Some(frame) => frame,
+                    None => {
+                        rx.scheduled = false;
+                        return;
+                    }
+                }
+            };
+            // a pooled buffer goes back to its pool once the stack is done with it
+            super::receive(self, frame.bytes());
+        }
+    }
+}
+
+/* where a driver puts received frames.
+    Pushing copies the frame and never blocks, so drivers push straight from their
+    interrupt handler. The first frame in an empty queue has the work queue empty it.
+ */
+#[derive(Clone)]
+pub struct RxQueue {
+    interface: Weak<Interface>,
+}
+
+impl RxQueue {
+    pub(super) fn new(interface: &Arc<Interface>) -> Self {
+        RxQueue { interface: Arc::downgrade(interface) }
+    }
+
+    /// Queues a copy of `frame`.
+    pub fn push(&self, frame: &[u8]) {
+        self.queue(frame.len(), || RxFrame::Copied(frame.to_vec()));
+    }
+
+    /// Queues the frame in `buffer` without copying it, see `net::buffer`.
+    pub fn push_buffer(&self, buffer: PacketBuffer) {
+        self.queue(buffer.len(), || RxFrame::Buffer(buffer));
+    }
+
+    fn queue(&self, len: usize, frame: impl FnOnce() -> RxFrame) {
+        let Some(interface) = self.interface.upgrade() else {
+            return;
+        };
+        interface.counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+        interface.counters.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        let schedule = {
+            let mut rx = interface.rx.lock();
+            if rx.frames.len() >= RX_QUEUE_LENGTH {
+                drop(rx);
+                interface.count_dropped();
+                return;
+            }
+            rx.frames.push_back(frame());
+            !core::mem::replace(&mut rx.scheduled, true)
+        };
+        if schedule {
+            let interface = self.interface.clone();
+            workqueue::spawn(move || {
+                if let Some(interface) = interface.upgrade() {
+                    interface.process();
+                }
+            });
+        }
+    }
+}
+
+/// A device that keeps what is sent and receives what a test injects.
+#[cfg(test)]
+pub(crate) struct TestDevice {
+    pub mac: MacAddress,
+    pub sent: SpinLock<Vec<Vec<u8>>>,
+    rx: SpinLock<Option<RxQueue>>,
+}
+
+#[cfg(test)]
+impl TestDevice {
+    pub fn new(mac: MacAddress) -> Arc<Self> {
+        Arc::new(TestDevice { mac, sent: SpinLock::new(Vec::new()), rx: SpinLock::new(None) })
+    }
+
+    /// Receives `frame` as if it came off the wire.
+    pub fn inject(&self, frame: &[u8]) {
+        let rx = self.rx.lock().clone().expect("device not registered");
+        rx.push(frame);
+    }
+
+    /// Receives the frame in `buffer`, as a driver with a packet pool does.
+    pub fn inject_buffer(&self, buffer: PacketBuffer) {
+        let rx = self.rx.lock().clone().expect("device not registered");
+        rx.push_buffer(buffer);
+    }
+
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut *self.sent.lock())
+    }
+}
+
+#[cfg(test)]
+impl NetworkDevice for TestDevice {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.sent.lock().push(frame.to_vec());
+        Ok(())
+    }
+
+    fn attach(&self, rx: RxQueue) {
+        *self.rx.lock() = Some(rx);
+    }
+}
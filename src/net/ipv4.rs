@@ -0,0 +1,437 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+
+use super::ethernet::{self, Frame, ETHERTYPE_IPV4};
+use super::{arp, Interface, NetError};
+use crate::sync::SpinLock;
+use crate::time;
+
+/* IPv4.
+    Packets go where the routing table says: straight to the destination on a
+    subnet an interface is on, through the gateway otherwise. Packets larger than
+    the interface takes are sent in fragments, and fragments received are put back
+    together before the protocol sees them. Options are skipped, never sent.
+ */
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_LEN: usize = 20;
+const MAX_PACKET_LEN: usize = 65535;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+/// How long the fragments of a packet wait for the rest.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// packets being put together at once, fragments of more are dropped
+const MAX_REASSEMBLIES: usize = 16;
+
+/// Handles the payload of a packet that carries its protocol.
+pub type Handler = fn(&Arc<Interface>, &Packet);
+
+static PROTOCOLS: SpinLock<BTreeMap<u8, Handler>> = SpinLock::new(BTreeMap::new());
+static ROUTES: SpinLock<Vec<Route>> = SpinLock::new(Vec::new());
+static REASSEMBLIES: SpinLock<BTreeMap<FragmentKey, Reassembly>> = SpinLock::new(BTreeMap::new());
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+/// The address of an interface and the size of its subnet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+impl Ipv4Config {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(mask(self.prefix_len))
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !mask(self.prefix_len))
+    }
+
+    /// Whether `address` is on the subnet.
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        (u32::from(address) ^ u32::from(self.address)) & mask(self.prefix_len) == 0
+    }
+}
+
+/// A received packet, put back together if it came in fragments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub payload: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    ttl: u8,
+    identification: u16,
+    more_fragments: bool,
+    // in bytes
+    fragment_offset: usize,
+}
+
+/// The Internet checksum of RFC 1071, over `parts` as if they were one buffer.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    let mut high = true;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        sum += if high { (byte as u64) << 8 } else { byte as u64 };
+        high = !high;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// What TCP and UDP checksums cover of the IP header, for a payload of `len` bytes.
+pub fn pseudo_header(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, len: usize) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[..4].copy_from_slice(&source.octets());
+    header[4..8].copy_from_slice(&destination.octets());
+    header[9] = protocol;
+    header[10..].copy_from_slice(&(len as u16).to_be_bytes());
+    header
+}
+
+fn address_at(bytes: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[offset..offset + 4]).unwrap())
+}
+
+/// Checks the header of `bytes`, returns it with the payload, link layer padding cut off.
+fn parse(bytes: &[u8]) -> Result<(Header, &[u8]), NetError> {
+    if bytes.len() < HEADER_LEN || bytes[0] >> 4 != 4 {
+        return Err(NetError::Malformed);
+    }
+    let header_len = (bytes[0] & 0xf) as usize * 4;
+    let total_len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > bytes.len() || checksum(&[&bytes[..header_len]]) != 0 {
+        return Err(NetError::Malformed);
+    }
+    let fragment = u16::from_be_bytes([bytes[6], bytes[7]]);
+    let header = Header {
+        source: address_at(bytes, 12),
+        destination: address_at(bytes, 16),
+        protocol: bytes[9],
+        ttl: bytes[8],
+        identification: u16::from_be_bytes([bytes[4], bytes[5]]),
+        more_fragments: fragment & FLAG_MORE_FRAGMENTS != 0,
+        fragment_offset: (fragment & FRAGMENT_OFFSET) as usize * 8,
+    };
+    Ok((header, &bytes[header_len..total_len]))
+}
+
+fn build(header: &Header, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&[0x45, 0]);
+    bytes.extend_from_slice(&((HEADER_LEN + payload.len()) as u16).to_be_bytes());
+    bytes.extend_from_slice(&header.identification.to_be_bytes());
+    let mut fragment = (header.fragment_offset / 8) as u16;
+    if header.more_fragments {
+        fragment |= FLAG_MORE_FRAGMENTS;
+    }
+    bytes.extend_from_slice(&fragment.to_be_bytes());
+    bytes.extend_from_slice(&[header.ttl, header.protocol, 0, 0]);
+    bytes.extend_from_slice(&header.source.octets());
+    bytes.extend_from_slice(&header.destination.octets());
+    let sum = checksum(&[&bytes]);
+    bytes[10..12].copy_from_slice(&sum.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// A route to the addresses that start with the first `prefix_len` bits of `destination`.
+#[derive(Clone)]
+pub struct Route {
+    pub destination: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Where to send the packets, `None` when the destination is on the link.
+    pub gateway: Option<Ipv4Addr>,
+    pub interface: Arc<Interface>,
+}
+
+impl Route {
+    fn matches(&self, address: Ipv4Addr) -> bool {
+        (u32::from(address) ^ u32::from(self.destination)) & mask(self.prefix_len) == 0
+    }
+}
+
+/// Adds `route`, replacing one to the same destination and prefix.
+pub fn add_route(route: Route) {
+    let mut routes = ROUTES.lock();
+    routes.retain(|other| (other.destination, other.prefix_len) != (route.destination, route.prefix_len));
+    routes.push(route);
+}
+
+/// Removes every route through `interface`.
+pub fn remove_routes(interface: &Interface) {
+    ROUTES.lock().retain(|route| !core::ptr::eq(&*route.interface, interface));
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+/// The interface to send a packet for `destination` on and the next hop on its
+/// link, by the longest matching prefix.
+pub fn route(destination: Ipv4Addr) -> Option<(Arc<Interface>, Ipv4Addr)> {
+    let routes = ROUTES.lock();
+    let route = routes.iter().filter(|route| route.matches(destination)).max_by_key(|route| route.prefix_len)?;
+    Some((route.interface.clone(), route.gateway.unwrap_or(destination)))
+}
+
+/// Gives `interface` an address, with a route to its subnet and, through `gateway`,
+/// one to everywhere else. `None` takes the address and the routes away.
+pub fn configure(interface: &Arc<Interface>, config: Option<Ipv4Config>, gateway: Option<Ipv4Addr>) {
+    remove_routes(interface);
+    interface.set_ipv4(config);
+    let Some(config) = config else {
+        return;
+    };
+    let network = Ipv4Addr::from(u32::from(config.address) & mask(config.prefix_len));
+    add_route(Route { destination: network, prefix_len: config.prefix_len, gateway: None, interface: interface.clone() });
+    if let Some(gateway) = gateway {
+        add_route(Route { destination: Ipv4Addr::UNSPECIFIED, prefix_len: 0, gateway: Some(gateway), interface: interface.clone() });
+    }
+}
+
+/// Has packets of `protocol` handed to `handler`, replacing any handler before it.
+pub fn register_protocol(protocol: u8, handler: Handler) {
+    PROTOCOLS.lock().insert(protocol, handler);
+}
+
+/// Sends `payload` to `destination`, from the address of the interface the route goes through.
+pub fn send(destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let (interface, next_hop) = route(destination).ok_or(NetError::Unreachable)?;
+    let source = interface.ipv4_address().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    send_to(&interface, source, destination, next_hop, protocol, payload)
+}
+
+/// Sends `payload` from `source` on `interface` without asking the routing table,
+/// for talking on a link before it has an address.
+pub fn send_on(interface: &Arc<Interface>, source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    send_to(interface, source, destination, destination, protocol, payload)
+}
+
+fn send_to(interface: &Arc<Interface>, source: Ipv4Addr, destination: Ipv4Addr, next_hop: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    if HEADER_LEN + payload.len() > MAX_PACKET_LEN {
+        return Err(NetError::TooLong);
+    }
+    // a broadcast goes to every station on the link, whatever the route says
+    let next_hop = match interface.ipv4() {
+        Some(config) if destination == config.broadcast() => Ipv4Addr::BROADCAST,
+        _ if destination.is_broadcast() => Ipv4Addr::BROADCAST,
+        _ => next_hop,
+    };
+    let identification = NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    // every fragment but the last carries a multiple of 8 bytes
+    let fragment_len = if HEADER_LEN + payload.len() <= interface.mtu() {
+        payload.len()
+    } else {
+        (interface.mtu() - HEADER_LEN) & !7
+    };
+    let mut offset = 0;
+    loop {
+        let end = (offset + fragment_len).min(payload.len());
+        let header = Header {
+            source,
+            destination,
+            protocol,
+            ttl: DEFAULT_TTL,
+            identification,
+            more_fragments: end < payload.len(),
+            fragment_offset: offset,
+        };
+        arp::send(interface, next_hop, ETHERTYPE_IPV4, &build(&header, &payload[offset..end]))?;
+        if end == payload.len() {
+            return Ok(());
+        }
+        offset = end;
+    }
+}
+
+// fragments belong together if they agree on these, as RFC 791 says
+type FragmentKey = (Ipv4Addr, Ipv4Addr, u8, u16);
+
+struct Reassembly {
+    // by offset
+    fragments: BTreeMap<usize, Vec<u8>>,
+    // known once the last fragment came
+    total: Option<usize>,
+    expires: u64,
+}
+
+/// Adds a fragment, returns the whole payload once every piece of it is there.
+fn reassemble(header: &Header, payload: &[u8]) -> Option<Vec<u8>> {
+    let now = time::uptime_ms();
+    let mut reassemblies = REASSEMBLIES.lock();
+    reassemblies.retain(|_, reassembly| reassembly.expires > now);
+    let key = (header.source, header.destination, header.protocol, header.identification);
+    if !reassemblies.contains_key(&key) && reassemblies.len() >= MAX_REASSEMBLIES {
+        return None;
+    }
+    let end = header.fragment_offset + payload.len();
+    if HEADER_LEN + end > MAX_PACKET_LEN {
+        reassemblies.remove(&key);
+        return None;
+    }
+    let reassembly = reassemblies.entry(key).or_insert_with(|| Reassembly {
+        fragments: BTreeMap::new(),
+        total: None,
+        expires: now + REASSEMBLY_TIMEOUT.as_millis() as u64,
+    });
+    if !header.more_fragments {
+        reassembly.total = Some(end);
+    }
+    reassembly.fragments.insert(header.fragment_offset, payload.to_vec());
+
+    let total = reassembly.total?;
+    let mut covered = 0;
+    for (&offset, data) in &reassembly.fragments {
+        if offset > covered {
+            return None;
+        }
+        covered = covered.max(offset + data.len());
+    }
+    if covered < total {
+        return None;
+    }
+    let reassembly = reassemblies.remove(&key).unwrap();
+    drop(reassemblies);
+    let mut whole = vec![0u8; total];
+    for (offset, data) in reassembly.fragments.into_iter().filter(|&(offset, _)| offset < total) {
+        let end = (offset + data.len()).min(total);
+        whole[offset..end].copy_from_slice(&data[..end - offset]);
+    }
+    Some(whole)
+}
+
+/// Whether a packet for `destination` is ours. Before it has an address an
+/// interface takes everything, a DHCP offer comes to the address being offered.
+fn accepts(interface: &Interface, destination: Ipv4Addr) -> bool {
+    match interface.ipv4() {
+        // every address of its subnet leads back to the machine itself
+        Some(config) if interface.is_loopback() => config.contains(destination),
+        Some(config) => destination.is_broadcast() || destination == config.address || destination == config.broadcast(),
+        None => true,
+    }
+}
+
+fn receive(interface: &Arc<Interface>, frame: &Frame) {
+    let Ok((header, payload)) = parse(frame.payload) else {
+        interface.count_dropped();
+        return;
+    };
+    if !accepts(interface, header.destination) {
+        interface.count_dropped();
+        return;
+    }
+    let whole;
+    let payload = if header.more_fragments || header.fragment_offset != 0 {
+        match reassemble(&header, payload) {
+            Some(payload) => {
+                whole = payload;
+                &whole[..]
+            }
+            None => return,
+        }
+    } else {
+        payload
+    };
+    let packet = Packet { source: header.source, destination: header.destination, protocol: header.protocol, ttl: header.ttl, payload };
+    let handler = PROTOCOLS.lock().get(&header.protocol).copied();
+    match handler {
+        Some(handler) => handler(interface, &packet),
+        None => interface.count_dropped(),
+    }
+}
+
+pub fn init() {
+    ethernet::register_protocol(ETHERTYPE_IPV4, receive);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use crate::net::{register, unregister, MacAddress};
+    use crate::workqueue;
+
+    // the example in every explanation of the checksum, a UDP packet from 192.168.0.1
+    const HEADER: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+        0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    // one of the experimental numbers
+    const PROTOCOL_TEST: u8 = 253;
+
+    static RECEIVED: SpinLock<Vec<(Ipv4Addr, Vec<u8>)>> = SpinLock::new(Vec::new());
+
+    #[test_case]
+    fn test_headers() {
+        let mut packet = HEADER.to_vec();
+        packet.resize(0x73 + 4, 0xee);
+        let (header, payload) = parse(&packet).unwrap();
+        assert_eq!((header.source, header.destination), (Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(192, 168, 0, 199)));
+        assert_eq!((header.protocol, header.ttl, header.more_fragments), (PROTOCOL_UDP, 64, false));
+        // the padding after the total length is not part of the payload
+        assert_eq!(payload.len(), 0x73 - HEADER_LEN);
+        assert_eq!(parse(&build(&header, payload)), Ok((header, payload)));
+        assert_eq!(checksum(&[&HEADER]), 0);
+        assert_eq!(checksum(&[&HEADER[..10], &[0, 0], &HEADER[12..]]), 0xb861);
+
+        packet[10] ^= 1;
+        assert_eq!(parse(&packet), Err(NetError::Malformed));
+        assert_eq!(parse(&HEADER), Err(NetError::Malformed));
+
+        let config = Ipv4Config { address: Ipv4Addr::new(10, 0, 2, 15), prefix_len: 24 };
+        assert_eq!((config.netmask(), config.broadcast()), (Ipv4Addr::new(255, 255, 255, 0), Ipv4Addr::new(10, 0, 2, 255)));
+        assert!(config.contains(Ipv4Addr::new(10, 0, 2, 2)) && !config.contains(Ipv4Addr::new(10, 0, 3, 2)));
+    }
+
+    #[test_case]
+    fn test_routing_and_fragments() {
+        let device = TestDevice::new(MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+        let interface = register("test-ipv4", device.clone()).unwrap();
+        let gateway = Ipv4Addr::new(10, 0, 2, 2);
+        configure(&interface, Some(Ipv4Config { address: Ipv4Addr::new(10, 0, 2, 15), prefix_len: 24 }), Some(gateway));
+        let (through, next_hop) = route(Ipv4Addr::new(10, 0, 2, 3)).unwrap();
+        assert_eq!((through.name(), next_hop), ("test-ipv4", Ipv4Addr::new(10, 0, 2, 3)));
+        assert_eq!(route(Ipv4Addr::new(1, 1, 1, 1)).unwrap().1, gateway);
+
+        // sent to the broadcast address in three fragments, then received back
+        register_protocol(PROTOCOL_TEST, |_, packet| RECEIVED.lock().push((packet.source, packet.payload.to_vec())));
+        let payload: Vec<u8> = (0..3000u32).map(|index| index as u8).collect();
+        send(Ipv4Addr::BROADCAST, PROTOCOL_TEST, &payload).unwrap();
+        let frames = device.take_sent();
+        assert_eq!(frames.len(), 3);
+        for frame in frames.iter().rev() {
+            device.inject(frame);
+        }
+        workqueue::flush();
+        assert_eq!(RECEIVED.lock().pop(), Some((Ipv4Addr::new(10, 0, 2, 15), payload)));
+
+        unregister("test-ipv4");
+        assert!(route(gateway).is_none());
+        PROTOCOLS.lock().remove(&PROTOCOL_TEST);
+    }
+}
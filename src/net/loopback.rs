@@ -0,0 +1,115 @@
+use alloc::sync::Arc;
+use core::net::Ipv4Addr;
+
+use super::ipv4::{self, Ipv4Config};
+use super::{MacAddress, NetError, NetworkDevice, RxQueue};
+use crate::sync::SpinLock;
+
+/* the loopback interface.
+    `lo` hands every frame it sends to its own receive queue, so the whole stack
+    runs between two sockets on the same machine without a card, tests included.
+    Frames still carry an Ethernet header, to the all-zero address of the device.
+ */
+
+pub const NAME: &str = "lo";
+pub const ADDRESS: Ipv4Addr = Ipv4Addr::LOCALHOST;
+// as large as an IP packet gets, nothing on the way needs fragments
+const MTU: usize = 65535;
+
+struct Loopback {
+    rx: SpinLock<Option<RxQueue>>,
+}
+
+impl NetworkDevice for Loopback {
+    fn mac_address(&self) -> MacAddress {
+        MacAddress::default()
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        let rx = self.rx.lock().clone().ok_or(NetError::Io)?;
+        rx.push(frame);
+        Ok(())
+    }
+
+    fn attach(&self, rx: RxQueue) {
+        *self.rx.lock() = Some(rx);
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+}
+
+/// Registers `lo` with 127.0.0.1/8.
+pub fn init() {
+    let interface = super::register(NAME, Arc::new(Loopback { rx: SpinLock::new(None) })).expect("lo registered twice");
+    ipv4::configure(&interface, Some(Ipv4Config { address: ADDRESS, prefix_len: 8 }), None);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::tcp::{State, TcpListener, TcpStream};
+    use crate::net::udp::UdpSocket;
+    use crate::thread;
+    use alloc::vec::Vec;
+    use core::net::SocketAddrV4;
+    use core::time::Duration;
+
+    const RESPONSE_LEN: usize = 200_000;
+
+    #[test_case]
+    fn test_udp_echo() {
+        let client = UdpSocket::bind(0).unwrap();
+        let echo = UdpSocket::bind(7).unwrap();
+        let before = super::super::get(NAME).unwrap().stats();
+        client.send_to(b"ping", SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 2), 7)).unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (count, from) = echo.recv_from_timeout(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert_eq!((&buffer[..count], from), (&b"ping"[..], SocketAddrV4::new(ADDRESS, client.port())));
+        echo.send_to(&buffer[..count], from).unwrap();
+        let (count, from) = client.recv_from_timeout(&mut buffer, Duration::from_secs(1)).unwrap();
+        assert_eq!((&buffer[..count], from), (&b"ping"[..], SocketAddrV4::new(ADDRESS, 7)));
+
+        let after = super::super::get(NAME).unwrap().stats();
+        assert!(after.tx_packets - before.tx_packets >= 2 && after.rx_packets - before.rx_packets >= 2);
+    }
+
+    #[test_case]
+    fn test_tcp_request() {
+        let listener = TcpListener::bind(8080).unwrap();
+        let server = thread::spawn("lo-server", move || {
+            let stream = listener.accept().unwrap();
+            let mut request = [0u8; 64];
+            let count = stream.read(&mut request).unwrap();
+            assert_eq!(&request[..count], b"GET / HTTP/1.0\r\n\r\n");
+            let body: Vec<u8> = (0..RESPONSE_LEN).map(|index| index as u8).collect();
+            assert_eq!(stream.write(&body), Ok(RESPONSE_LEN));
+            // dropping the stream sends the FIN after the data
+        });
+
+        let stream = TcpStream::connect(SocketAddrV4::new(ADDRESS, 8080)).unwrap();
+        assert_eq!(stream.state(), State::Established);
+        assert_eq!(stream.peer_addr(), SocketAddrV4::new(ADDRESS, 8080));
+        stream.write(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match stream.read(&mut buffer).unwrap() {
+                0 => break,
+                count => response.extend_from_slice(&buffer[..count]),
+            }
+        }
+        server.join();
+        assert_eq!(response.len(), RESPONSE_LEN);
+        assert!(response.iter().enumerate().all(|(index, &byte)| byte == index as u8));
+
+        // nobody listens on this one, a reset answers
+        assert_eq!(TcpStream::connect(SocketAddrV4::new(ADDRESS, 8081)).err(), Some(NetError::ConnectionRefused));
+    }
+}
@@ -0,0 +1,192 @@
+pub mod arp;
+pub mod buffer;
+pub mod dhcp;
+pub mod dns;
+pub mod ethernet;
+pub mod httpd;
+pub mod interface;
+pub mod ipv4;
+pub mod loopback;
+pub mod netconsole;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp_stack;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::net::Ipv4Addr;
+
+use crate::process::signal::Interrupted;
+use crate::sync::SpinLock;
+
+pub use interface::{Interface, InterfaceStats, NetworkDevice, RxQueue};
+
+/* networking.
+    Drivers register a device and get an interface back: the name, the statistics
+    and the queue received frames wait in. The protocol stack only ever talks to
+    interfaces, so it runs the same over any card. Received frames are handled on
+    the work queue, never in the interrupt handler that got them.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The frame or packet does not fit the interface.
+    TooLong,
+    /// The device reported a failure.
+    Io,
+    /// An interface with that name is already registered.
+    Exists,
+    NoInterface,
+    /// No route leads to the destination.
+    Unreachable,
+    /// A received packet that does not parse.
+    Malformed,
+    /// The peer turned the request down, with a TCP reset or a DHCP NAK.
+    ConnectionRefused,
+    ConnectionReset,
+    /// The peer stopped answering.
+    TimedOut,
+    AddressInUse,
+    /// The connection is not, or no longer, open for this.
+    NotConnected,
+    /// A signal arrived while waiting.
+    Interrupted,
+    /// The name server knows no address for the name.
+    NotFound,
+    /// A host name that cannot be looked up, with an empty or overlong label.
+    InvalidName,
+    /// A call that does not fit the state of the socket, as binding twice.
+    InvalidArgument,
+    /// A call the kind of socket does not have, as listening for datagrams.
+    Unsupported,
+    IsConnected,
+}
+
+impl From<Interrupted> for NetError {
+    fn from(_: Interrupted) -> Self {
+        NetError::Interrupted
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    /// Group addresses have the lowest bit of the first byte set, broadcast included.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+impl fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Hooks the protocols into the Ethernet layer and brings up `lo`.
+pub fn init() {
+    arp::init();
+    ipv4::init();
+    tcp::init();
+    udp::init();
+    loopback::init();
+}
+
+static INTERFACES: SpinLock<BTreeMap<String, Arc<Interface>>> = SpinLock::new(BTreeMap::new());
+static NAMESERVERS: SpinLock<Vec<Ipv4Addr>> = SpinLock::new(Vec::new());
+
+/// Makes `device` available as interface `name`, as in "eth0".
+pub fn register(name: &str, device: Arc<dyn NetworkDevice>) -> Result<Arc<Interface>, NetError> {
+    let mut interfaces = INTERFACES.lock();
+    if interfaces.contains_key(name) {
+        return Err(NetError::Exists);
+    }
+    let interface = Interface::new(name, device);
+    interfaces.insert(name.to_string(), interface.clone());
+    drop(interfaces);
+    interface.device().attach(RxQueue::new(&interface));
+    Ok(interface)
+}
+
+/// Removes interface `name` and the routes through it, frames it receives from
+/// now on are dropped.
+pub fn unregister(name: &str) -> Option<Arc<Interface>> {
+    let interface = INTERFACES.lock().remove(name)?;
+    ipv4::remove_routes(&interface);
+    Some(interface)
+}
+
+pub fn get(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.lock().get(name).cloned()
+}
+
+/// Names of all registered interfaces, sorted.
+pub fn interfaces() -> Vec<String> {
+    INTERFACES.lock().keys().cloned().collect()
+}
+
+/// The DNS servers to ask, in order, as DHCP handed them out.
+pub fn nameservers() -> Vec<Ipv4Addr> {
+    NAMESERVERS.lock().clone()
+}
+
+pub fn set_nameservers(servers: Vec<Ipv4Addr>) {
+    *NAMESERVERS.lock() = servers;
+}
+
+/// Hands a received frame to the protocol stack.
+fn receive(interface: &Arc<Interface>, frame: &[u8]) {
+    #[cfg(feature = "smoltcp")]
+    if smoltcp_stack::divert(interface, frame) {
+        return;
+    }
+    ethernet::receive(interface, frame);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use alloc::vec;
+    use crate::workqueue;
+
+    #[test_case]
+    fn test_registry() {
+        let device = TestDevice::new(MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+        let interface = register("test-registry", device.clone()).expect("register failed");
+        assert_eq!(register("test-registry", device.clone()).err(), Some(NetError::Exists));
+        assert!(interfaces().iter().any(|name| name == "test-registry"));
+        assert_eq!(interface.mac_address().to_string(), "52:54:00:12:34:56");
+        assert!(MacAddress::BROADCAST.is_multicast() && !interface.mac_address().is_multicast());
+
+        interface.transmit(&[1; 60]).unwrap();
+        assert_eq!(device.take_sent(), [vec![1; 60]]);
+        device.inject(&[2; 64]);
+        workqueue::flush();
+        let stats = get("test-registry").unwrap().stats();
+        assert_eq!((stats.tx_packets, stats.tx_bytes, stats.rx_packets, stats.rx_bytes), (1, 60, 1, 64));
+        assert_eq!(stats.rx_dropped, 1);
+
+        assert!(unregister("test-registry").is_some());
+        assert!(get("test-registry").is_none());
+    }
+}
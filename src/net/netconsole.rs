@@ -0,0 +1,165 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::net::SocketAddrV4;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::udp::UdpSocket;
+use super::NetError;
+use crate::sync::SpinLock;
+use crate::{thread, workqueue};
+
+/* netconsole.
+    Sends what the kernel prints on the serial port to a UDP host:port as well, one
+    datagram per line, so runs nobody watches the serial console of still leave a
+    log (`nc -ulk 6666` on the host collects it). Lines are queued where they are
+    printed and sent from the work queue: printing never waits for the network,
+    and works from interrupt handlers as before. What the sender itself prints
+    goes to the serial port only.
+ */
+
+/// The port lines are sent from, as on Linux.
+pub const LOCAL_PORT: u16 = 6665;
+// lines waiting to be sent, more are dropped
+const MAX_QUEUED: usize = 256;
+// longer lines are cut into pieces of this size
+const MAX_LINE_LEN: usize = 1024;
+const NO_THREAD: u64 = u64::MAX;
+
+struct Console {
+    socket: UdpSocket,
+    destination: SocketAddrV4,
+}
+
+struct Pending {
+    // what was printed since the last newline
+    partial: String,
+    lines: VecDeque<String>,
+    // a work item is queued to send `lines`
+    scheduled: bool,
+    dropped: u64,
+}
+
+static CONSOLE: SpinLock<Option<Console>> = SpinLock::new(None);
+static PENDING: SpinLock<Pending> = SpinLock::new(Pending { partial: String::new(), lines: VecDeque::new(), scheduled: false, dropped: 0 });
+// checked before anything is locked, printing costs nothing while stopped
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// the thread sending right now
+static SENDER: AtomicU64 = AtomicU64::new(NO_THREAD);
+
+/// Starts sending kernel output to `destination`, or changes where it goes.
+pub fn start(destination: SocketAddrV4) -> Result<(), NetError> {
+    let mut console = CONSOLE.lock();
+    match &mut *console {
+        Some(console) => console.destination = destination,
+        None => *console = Some(Console { socket: UdpSocket::bind(LOCAL_PORT)?, destination }),
+    }
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops sending, lines not sent yet are dropped.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+    *CONSOLE.lock() = None;
+    let mut pending = PENDING.lock();
+    pending.partial.clear();
+    pending.lines.clear();
+}
+
+/// Where output goes, `None` while stopped.
+pub fn destination() -> Option<SocketAddrV4> {
+    CONSOLE.lock().as_ref().map(|console| console.destination)
+}
+
+/// Lines that did not fit the queue since boot.
+pub fn dropped() -> u64 {
+    PENDING.lock().dropped
+}
+
+impl Write for Pending {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for piece in text.split_inclusive('\n') {
+            self.partial.push_str(piece);
+            if piece.ends_with('\n') || self.partial.len() >= MAX_LINE_LEN {
+                let line = core::mem::take(&mut self.partial);
+                if self.lines.len() < MAX_QUEUED {
+                    self.lines.push_back(line);
+                } else {
+                    self.dropped += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Queues printed output, called for everything that goes to the serial port.
+pub(crate) fn capture(args: fmt::Arguments) {
+    if !ENABLED.load(Ordering::Acquire) || SENDER.load(Ordering::Relaxed) == thread::current_id().as_u64() {
+        return;
+    }
+    let schedule = {
+        let mut pending = PENDING.lock();
+        let _ = pending.write_fmt(args);
+        !pending.lines.is_empty() && !core::mem::replace(&mut pending.scheduled, true)
+    };
+    if schedule {
+        workqueue::spawn(send);
+    }
+}
+
+fn send() {
+    SENDER.store(thread::current_id().as_u64(), Ordering::Relaxed);
+    loop {
+        let line = {
+            let mut pending = PENDING.lock();
+            match pending.lines.pop_front() {
+                Some(line) => line,
+                None => {
+                    pending.scheduled = false;
+                    break;
+                }
+            }
+        };
+        if let Some(console) = &*CONSOLE.lock() {
+            // nobody to tell if it fails, the next line tries again
+            let _ = console.socket.send_to(line.as_bytes(), console.destination);
+        }
+    }
+    SENDER.store(NO_THREAD, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::loopback;
+    use crate::serial_println;
+    use core::time::Duration;
+
+    #[test_case]
+    fn test_lines() {
+        // the console keeps sending
+        crate::testing::keeps_heap();
+        let receiver = UdpSocket::bind(6666).unwrap();
+        start(SocketAddrV4::new(loopback::ADDRESS, 6666)).unwrap();
+        assert_eq!(destination(), Some(SocketAddrV4::new(loopback::ADDRESS, 6666)));
+        serial_println!("netconsole {}", "one");
+        serial_println!("netconsole two\nnetconsole three");
+        workqueue::flush();
+
+        let mut buffer = [0u8; MAX_LINE_LEN];
+        let mut lines = alloc::vec::Vec::new();
+        while let Ok((count, from)) = receiver.recv_from_timeout(&mut buffer, Duration::from_millis(200)) {
+            assert_eq!(from, SocketAddrV4::new(loopback::ADDRESS, LOCAL_PORT));
+            let line = String::from_utf8(buffer[..count].to_vec()).unwrap();
+            // other threads may print meanwhile
+            if line.starts_with("netconsole") {
+                lines.push(line);
+            }
+        }
+        stop();
+        assert_eq!(lines, ["netconsole one\n", "netconsole two\n", "netconsole three\n"]);
+        assert_eq!(destination(), None);
+    }
+}
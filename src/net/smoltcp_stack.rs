@@ -0,0 +1,276 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::future::select;
+use futures_util::task::AtomicWaker;
+use smoltcp::iface::{self, Config, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr, Ipv4Address};
+
+use super::{ethernet, Interface, NetError};
+use crate::random;
+use crate::sync::SpinLock;
+use crate::time::{self, sleep_async};
+
+/* smoltcp as the protocol stack.
+    Built with the `smoltcp` feature, an interface can be attached to smoltcp
+    instead of the stack in `net`: every frame it receives then goes to smoltcp,
+    and smoltcp sends through the same `NetworkDevice`. The poll task runs on the
+    kernel's executor and drives all attached interfaces, waking up when a frame
+    arrives, a socket was used or a smoltcp timer is due.
+ */
+
+// received frames waiting for the poll task, more are dropped
+const RX_QUEUE_LENGTH: usize = 256;
+// how long the poll task sleeps when smoltcp has no timer running
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+static STACKS: SpinLock<BTreeMap<String, Arc<Stack>>> = SpinLock::new(BTreeMap::new());
+// something for the poll task to do, and where to find it
+static PENDING: AtomicBool = AtomicBool::new(false);
+static POLL_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The adapter smoltcp sends and receives through, made for one poll.
+struct Device<'a> {
+    interface: &'a Interface,
+    rx: &'a SpinLock<VecDeque<Vec<u8>>>,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken<'a>(&'a Interface);
+
+impl phy::Device for Device<'_> {
+    type RxToken<'b> = RxToken where Self: 'b;
+    type TxToken<'b> = TxToken<'b> where Self: 'b;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.rx.lock().pop_front()?;
+        Some((RxToken(frame), TxToken(self.interface)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(self.interface))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        // smoltcp counts the Ethernet header in
+        capabilities.max_transmission_unit = self.interface.mtu() + ethernet::HEADER_LEN;
+        capabilities
+    }
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0u8; len];
+        let result = f(&mut frame);
+        // a failure shows in the interface statistics, smoltcp retransmits what needs it
+        let _ = self.0.transmit(&frame);
+        result
+    }
+}
+
+struct Inner {
+    iface: iface::Interface,
+    sockets: SocketSet<'static>,
+}
+
+pub struct Stack {
+    interface: Arc<Interface>,
+    rx: SpinLock<VecDeque<Vec<u8>>>,
+    inner: SpinLock<Inner>,
+}
+
+fn now() -> Instant {
+    Instant::from_millis(time::uptime_ms() as i64)
+}
+
+/// Has the poll task look at the stacks again.
+fn wake_poller() {
+    PENDING.store(true, Ordering::Release);
+    POLL_WAKER.wake();
+}
+
+impl Stack {
+    /* hands `interface` to smoltcp.
+        The address it has from `ipv4::configure`, if any, carries over; routes
+        and everything else are set up through `with`. Frames received from now
+        on no longer reach the stack in `net`.
+     */
+    pub fn attach(interface: Arc<Interface>) -> Result<Arc<Stack>, NetError> {
+        let mut stacks = STACKS.lock();
+        if stacks.contains_key(interface.name()) {
+            return Err(NetError::Exists);
+        }
+        let rx = SpinLock::new(VecDeque::new());
+        let mut config = Config::new(HardwareAddress::Ethernet(EthernetAddress(interface.mac_address().0)));
+        config.random_seed = random::next_u64();
+        let mut iface = iface::Interface::new(config, &mut Device { interface: &interface, rx: &rx }, now());
+        if let Some(ipv4) = interface.ipv4() {
+            iface.update_ip_addrs(|addresses| {
+                let _ = addresses.push(IpCidr::new(Ipv4Address(ipv4.address.octets()).into(), ipv4.prefix_len));
+            });
+        }
+        let stack = Arc::new(Stack { interface, rx, inner: SpinLock::new(Inner { iface, sockets: SocketSet::new(Vec::new()) }) });
+        stacks.insert(stack.interface.name().to_string(), stack.clone());
+        Ok(stack)
+    }
+
+    pub fn interface(&self) -> &Arc<Interface> {
+        &self.interface
+    }
+
+    /// Runs `f` on the smoltcp interface and its sockets, then has the poll
+    /// task send whatever `f` queued.
+    pub fn with<R>(&self, f: impl FnOnce(&mut iface::Interface, &mut SocketSet<'static>) -> R) -> R {
+        let result = {
+            let mut inner = self.inner.lock();
+            let Inner { iface, sockets } = &mut *inner;
+            f(iface, sockets)
+        };
+        wake_poller();
+        result
+    }
+
+    /// Lets smoltcp handle the received frames and its timers, returns when it
+    /// wants to be polled next.
+    fn poll(&self) -> Option<Duration> {
+        let mut inner = self.inner.lock();
+        let Inner { iface, sockets } = &mut *inner;
+        let mut device = Device { interface: &self.interface, rx: &self.rx };
+        let now = now();
+        iface.poll(now, &mut device, sockets);
+        iface.poll_delay(now, sockets).map(|delay| Duration::from_micros(delay.total_micros()))
+    }
+}
+
+/// Gives interface `name` back to the stack in `net`.
+pub fn detach(name: &str) -> Option<Arc<Stack>> {
+    STACKS.lock().remove(name)
+}
+
+/// Queues `frame` for smoltcp if `interface` is attached to it; called for
+/// every received frame, before the stack in `net` sees it.
+pub(super) fn divert(interface: &Arc<Interface>, frame: &[u8]) -> bool {
+    let Some(stack) = STACKS.lock().get(interface.name()).cloned() else {
+        return false;
+    };
+    let mut rx = stack.rx.lock();
+    if rx.len() >= RX_QUEUE_LENGTH {
+        drop(rx);
+        interface.count_dropped();
+        return true;
+    }
+    rx.push_back(frame.to_vec());
+    drop(rx);
+    wake_poller();
+    true
+}
+
+/// The poll task, to be spawned on the executor once; it never returns.
+pub async fn run() {
+    loop {
+        PENDING.store(false, Ordering::Release);
+        let stacks: Vec<Arc<Stack>> = STACKS.lock().values().cloned().collect();
+        let delay = stacks.iter().filter_map(|stack| stack.poll()).min().unwrap_or(IDLE_POLL);
+        if delay.is_zero() {
+            continue;
+        }
+        let woken = pin!(poll_fn(|cx| {
+            if PENDING.load(Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            POLL_WAKER.register(cx.waker());
+            // the flag may have been set before the waker was registered
+            if PENDING.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+        select(woken, pin!(sleep_async(delay))).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::ethernet::{Frame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+    use crate::net::interface::TestDevice;
+    use crate::net::ipv4::{self, Ipv4Config};
+    use crate::net::{arp, register, unregister, MacAddress};
+    use crate::workqueue;
+    use core::net::Ipv4Addr;
+    use smoltcp::socket::udp::{PacketBuffer, PacketMetadata, Socket};
+
+    const MAC: MacAddress = MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    const GATEWAY_MAC: MacAddress = MacAddress([0x52, 0x55, 0x0a, 0, 2, 2]);
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    #[test_case]
+    fn test_udp_through_smoltcp() {
+        let device = TestDevice::new(MAC);
+        let interface = register("test-smoltcp", device.clone()).unwrap();
+        ipv4::configure(&interface, Some(Ipv4Config { address: Ipv4Addr::new(10, 0, 2, 15), prefix_len: 24 }), None);
+        let stack = Stack::attach(interface.clone()).unwrap();
+        assert_eq!(Stack::attach(interface.clone()).err(), Some(NetError::Exists));
+
+        let buffer = || PacketBuffer::new(vec![PacketMetadata::EMPTY; 4], vec![0u8; 1024]);
+        let handle = stack.with(|_, sockets| {
+            let mut socket = Socket::new(buffer(), buffer());
+            socket.bind(5000).unwrap();
+            socket.send_slice(b"ping", (Ipv4Address(GATEWAY.octets()), 7)).unwrap();
+            sockets.add(socket)
+        });
+
+        // smoltcp asks for the gateway first
+        stack.poll();
+        let sent = device.take_sent();
+        let request = Frame::parse(&sent[0]).unwrap();
+        assert_eq!((request.destination, request.ether_type, &request.payload[24..28]), (MacAddress::BROADCAST, ETHERTYPE_ARP, &GATEWAY.octets()[..]));
+
+        // the answer goes to smoltcp, not to the ARP table in `net`
+        let mut reply = [0u8; 28];
+        reply[..8].copy_from_slice(&[0, 1, 8, 0, 6, 4, 0, 2]);
+        reply[8..14].copy_from_slice(&GATEWAY_MAC.0);
+        reply[14..18].copy_from_slice(&GATEWAY.octets());
+        reply[18..24].copy_from_slice(&MAC.0);
+        reply[24..28].copy_from_slice(&[10, 0, 2, 15]);
+        device.inject(&Frame { destination: MAC, source: GATEWAY_MAC, ether_type: ETHERTYPE_ARP, payload: &reply }.to_bytes());
+        workqueue::flush();
+        assert_eq!(arp::lookup(&interface, GATEWAY), None);
+
+        // smoltcp sends the datagram as soon as it sees the answer
+        stack.poll();
+        let sent = device.take_sent();
+        let datagram = Frame::parse(&sent[0]).unwrap();
+        assert_eq!((datagram.destination, datagram.ether_type), (GATEWAY_MAC, ETHERTYPE_IPV4));
+        assert!(datagram.payload.windows(4).any(|window| window == b"ping"));
+        stack.with(|_, sockets| sockets.remove(handle));
+
+        assert!(detach("test-smoltcp").is_some());
+        unregister("test-smoltcp");
+    }
+}
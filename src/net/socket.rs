@@ -0,0 +1,207 @@
+use alloc::sync::Arc;
+use core::any::Any;
+use core::net::SocketAddrV4;
+
+use super::tcp::{TcpListener, TcpStream};
+use super::udp::UdpSocket;
+use super::NetError;
+use crate::fs::{File, FileType, FsError, Metadata};
+use crate::sync::SpinLock;
+
+/* sockets as files.
+    What a descriptor from `socket()` refers to. A socket starts out as nothing in
+    particular and becomes a listener, a connection or a bound UDP socket through
+    the calls made on it, the way the BSD interface has it. Blocking calls hold on
+    to what they work on, never to the state lock, so one thread can read a
+    connection while another writes it.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    Stream,
+    Datagram,
+}
+
+enum State {
+    Unbound,
+    // TCP only, until `listen`
+    Bound(u16),
+    Listening(Arc<TcpListener>),
+    Connected(Arc<TcpStream>),
+    // where `send` goes once connected
+    Udp { socket: Arc<UdpSocket>, peer: Option<SocketAddrV4> },
+}
+
+pub struct Socket {
+    kind: SocketType,
+    state: SpinLock<State>,
+}
+
+impl Socket {
+    pub fn new(kind: SocketType) -> Arc<Self> {
+        Arc::new(Socket { kind, state: SpinLock::new(State::Unbound) })
+    }
+
+    pub fn kind(&self) -> SocketType {
+        self.kind
+    }
+
+    /// Takes the port of `address`, on every address of the machine whatever it says.
+    pub fn bind(&self, address: SocketAddrV4) -> Result<(), NetError> {
+        let mut state = self.state.lock();
+        if !matches!(*state, State::Unbound) {
+            return Err(NetError::InvalidArgument);
+        }
+        *state = match self.kind {
+            SocketType::Stream => State::Bound(address.port()),
+            SocketType::Datagram => State::Udp { socket: Arc::new(UdpSocket::bind(address.port())?), peer: None },
+        };
+        Ok(())
+    }
+
+    pub fn listen(&self) -> Result<(), NetError> {
+        if self.kind == SocketType::Datagram {
+            return Err(NetError::Unsupported);
+        }
+        let mut state = self.state.lock();
+        match *state {
+            State::Bound(port) => *state = State::Listening(Arc::new(TcpListener::bind(port)?)),
+            State::Listening(_) => {}
+            _ => return Err(NetError::InvalidArgument),
+        }
+        Ok(())
+    }
+
+    /// Waits for a connection on a listening socket, it comes as a socket of its own.
+    pub fn accept(&self) -> Result<Arc<Socket>, NetError> {
+        let listener = match &*self.state.lock() {
+            State::Listening(listener) => listener.clone(),
+            _ if self.kind == SocketType::Datagram => return Err(NetError::Unsupported),
+            _ => return Err(NetError::InvalidArgument),
+        };
+        let stream = listener.accept()?;
+        Ok(Arc::new(Socket { kind: SocketType::Stream, state: SpinLock::new(State::Connected(Arc::new(stream))) }))
+    }
+
+    /// Opens a connection, or for UDP sets where datagrams go by default and
+    /// binds a free port if there is none yet. The port a stream was bound to is
+    /// not used, it connects from any free one.
+    pub fn connect(&self, remote: SocketAddrV4) -> Result<(), NetError> {
+        if self.kind == SocketType::Datagram {
+            let socket = self.udp()?;
+            *self.state.lock() = State::Udp { socket, peer: Some(remote) };
+            return Ok(());
+        }
+        match *self.state.lock() {
+            State::Unbound | State::Bound(_) => {}
+            State::Connected(_) => return Err(NetError::IsConnected),
+            _ => return Err(NetError::InvalidArgument),
+        }
+        let stream = TcpStream::connect(remote)?;
+        *self.state.lock() = State::Connected(Arc::new(stream));
+        Ok(())
+    }
+
+    /// The UDP socket, bound to a free port on first use.
+    fn udp(&self) -> Result<Arc<UdpSocket>, NetError> {
+        let mut state = self.state.lock();
+        if let State::Udp { socket, .. } = &*state {
+            return Ok(socket.clone());
+        }
+        let socket = Arc::new(UdpSocket::bind(0)?);
+        *state = State::Udp { socket: socket.clone(), peer: None };
+        Ok(socket)
+    }
+
+    /// The local and the remote address of a connection.
+    pub fn addresses(&self) -> Option<(SocketAddrV4, SocketAddrV4)> {
+        match &*self.state.lock() {
+            State::Connected(stream) => Some((stream.local_addr(), stream.peer_addr())),
+            _ => None,
+        }
+    }
+
+    fn stream(&self) -> Result<Arc<TcpStream>, NetError> {
+        match &*self.state.lock() {
+            State::Connected(stream) => Ok(stream.clone()),
+            _ => Err(NetError::NotConnected),
+        }
+    }
+
+    /// Sends `data`, to `destination` or where the socket is connected to.
+    /// Connections ignore `destination`.
+    pub fn send_to(&self, data: &[u8], destination: Option<SocketAddrV4>) -> Result<usize, NetError> {
+        if self.kind == SocketType::Stream {
+            return self.stream()?.write(data);
+        }
+        let socket = self.udp()?;
+        let peer = match &*self.state.lock() {
+            State::Udp { peer, .. } => *peer,
+            _ => None,
+        };
+        socket.send_to(data, destination.or(peer).ok_or(NetError::NotConnected)?)?;
+        Ok(data.len())
+    }
+
+    /// Waits for data, returns how much there was and where it came from.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddrV4), NetError> {
+        if self.kind == SocketType::Stream {
+            let stream = self.stream()?;
+            return Ok((stream.read(buffer)?, stream.peer_addr()));
+        }
+        let socket = match &*self.state.lock() {
+            State::Udp { socket, .. } => socket.clone(),
+            // nothing can arrive for a socket without a port
+            _ => return Err(NetError::InvalidArgument),
+        };
+        socket.recv_from(buffer)
+    }
+}
+
+impl File for Socket {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(self.recv_from(buffer)?.0)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        Ok(self.send_to(buffer, None)?)
+    }
+
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata { inode: 0, file_type: FileType::Socket, size: 0, links: 1, mode: 0o777 })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::net::Ipv4Addr;
+
+    #[test_case]
+    fn test_states() {
+        let address = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 7070);
+        let stream = Socket::new(SocketType::Stream);
+        assert_eq!(stream.listen(), Err(NetError::InvalidArgument));
+        assert_eq!(stream.send_to(b"x", None), Err(NetError::NotConnected));
+        stream.bind(address).unwrap();
+        assert_eq!(stream.bind(address), Err(NetError::InvalidArgument));
+        stream.listen().unwrap();
+        let other = Socket::new(SocketType::Stream);
+        other.bind(address).unwrap();
+        assert_eq!(other.listen(), Err(NetError::AddressInUse));
+        assert_eq!(other.accept().err(), Some(NetError::InvalidArgument));
+
+        let datagram = Socket::new(SocketType::Datagram);
+        assert_eq!(datagram.listen(), Err(NetError::Unsupported));
+        assert_eq!(datagram.recv_from(&mut [0; 4]), Err(NetError::InvalidArgument));
+        assert_eq!(datagram.send_to(b"x", None), Err(NetError::NotConnected));
+        // sending bound it to a free port, a second socket can have 7070 for UDP
+        datagram.connect(SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 7)).unwrap();
+        Socket::new(SocketType::Datagram).bind(address).unwrap();
+        assert_eq!(datagram.metadata().unwrap().file_type, FileType::Socket);
+    }
+}
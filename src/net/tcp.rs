@@ -0,0 +1,967 @@
+use alloc::borrow::Cow;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::time::Duration;
+
+use super::ipv4::{self, checksum, pseudo_header, Packet, PROTOCOL_TCP};
+use super::{Interface, NetError};
+use crate::process::signal;
+use crate::sync::SpinLock;
+use crate::thread::{self, WaitQueue};
+use crate::time::sleep::{add_timer, cancel_timer};
+use crate::time::TimerAction;
+use crate::{random, time, workqueue};
+
+/* TCP.
+    Each connection is a control block run by the segments that arrive, the calls of
+    its owner and a retransmission timer on the work queue; whatever it decides to
+    send goes out once its lock is dropped. Only segments that arrive in order are
+    taken, the peer resends the others once our acknowledgements tell it what is
+    missing. Unacknowledged data is resent from the first lost byte when the
+    timer runs out, with the timeout doubled each time (go-back-N).
+ */
+
+const HEADER_LEN: usize = 20;
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Segment size assumed when the peer does not say.
+const DEFAULT_MSS: usize = 536;
+/// Bytes queued in either direction per connection.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 60_000;
+// timeouts in a row before the connection is given up
+const MAX_RETRIES: u32 = 8;
+/// How long a closed connection lingers to take care of stray segments, twice a
+/// segment lifetime that is short since peers are mostly on the local link.
+pub const TIME_WAIT: Duration = Duration::from_secs(10);
+const EPHEMERAL_PORTS: (u16, u16) = (49152, 65535);
+// established connections waiting for `accept`
+const MAX_BACKLOG: usize = 16;
+
+type Quad = (SocketAddrV4, SocketAddrV4);
+
+static CONNECTIONS: SpinLock<BTreeMap<Quad, Arc<Connection>>> = SpinLock::new(BTreeMap::new());
+static LISTENERS: SpinLock<BTreeMap<u16, Arc<Listener>>> = SpinLock::new(BTreeMap::new());
+static NEXT_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS.0);
+
+/// Sequence numbers wrap, `a` comes before `b` if it is less than half the space behind.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn before_or_at(a: u32, b: u32) -> bool {
+    !before(b, a)
+}
+
+// a received segment's payload is borrowed from the packet, one to send owns its copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: Cow<'a, [u8]>,
+}
+
+impl<'a> Segment<'a> {
+    fn new(seq: u32, flags: u8) -> Self {
+        Segment { seq, ack: 0, flags, window: 0, mss: None, payload: Cow::Borrowed(&[]) }
+    }
+
+    /// Sequence numbers taken up, SYN and FIN count as one each.
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+
+    /// Checks the segment in `bytes` sent from `source` to `destination`, returns
+    /// it with the source and destination ports.
+    fn parse(bytes: &'a [u8], source: Ipv4Addr, destination: Ipv4Addr) -> Result<(u16, u16, Segment<'a>), NetError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(NetError::Malformed);
+        }
+        let header_len = (bytes[12] >> 4) as usize * 4;
+        let pseudo = pseudo_header(source, destination, PROTOCOL_TCP, bytes.len());
+        if header_len < HEADER_LEN || header_len > bytes.len() || checksum(&[&pseudo, bytes]) != 0 {
+            return Err(NetError::Malformed);
+        }
+        let word = |offset: usize| u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let half = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut mss = None;
+        let mut options = &bytes[HEADER_LEN..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1).ok_or(NetError::Malformed)? as usize;
+                    if len < 2 || len > options.len() {
+                        return Err(NetError::Malformed);
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        let segment = Segment {
+            seq: word(4),
+            ack: word(8),
+            flags: bytes[13],
+            window: half(14),
+            mss,
+            payload: Cow::Borrowed(&bytes[header_len..]),
+        };
+        Ok((half(0), half(2), segment))
+    }
+
+    fn to_bytes(&self, local: SocketAddrV4, remote: SocketAddrV4) -> Vec<u8> {
+        let header_len = HEADER_LEN + if self.mss.is_some() { 4 } else { 0 };
+        let mut bytes = Vec::with_capacity(header_len + self.payload.len());
+        bytes.extend_from_slice(&local.port().to_be_bytes());
+        bytes.extend_from_slice(&remote.port().to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.ack.to_be_bytes());
+        bytes.extend_from_slice(&[(header_len as u8 / 4) << 4, self.flags]);
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        if let Some(mss) = self.mss {
+            bytes.extend_from_slice(&[OPTION_MSS, 4]);
+            bytes.extend_from_slice(&mss.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        let pseudo = pseudo_header(*local.ip(), *remote.ip(), PROTOCOL_TCP, bytes.len());
+        let sum = checksum(&[&pseudo, &bytes]);
+        bytes[16..18].copy_from_slice(&sum.to_be_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// The control block of a connection, everything but where it is.
+struct Tcb {
+    state: State,
+    iss: u32,
+    // oldest unacknowledged and next sequence number to send
+    snd_una: u32,
+    snd_nxt: u32,
+    // what the peer lets us have in flight
+    snd_wnd: u32,
+    // sequence number of the first byte in `send_buffer`, which keeps data until acknowledged
+    send_start: u32,
+    send_buffer: VecDeque<u8>,
+    // the owner is done writing, a FIN follows the data
+    fin_queued: bool,
+    rcv_nxt: u32,
+    recv_buffer: VecDeque<u8>,
+    fin_received: bool,
+    // largest segment we send, and the one we ask for
+    mss: usize,
+    local_mss: usize,
+    rto: u64,
+    srtt: Option<u64>,
+    rttvar: u64,
+    // one segment at a time is timed, by the sequence number that acknowledges it
+    rtt_sample: Option<(u32, u64)>,
+    retries: u32,
+    deadline: Option<u64>,
+    ack_needed: bool,
+    reset_needed: bool,
+    error: Option<NetError>,
+}
+
+impl Tcb {
+    fn new(state: State, iss: u32, local_mss: usize) -> Self {
+        Tcb {
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            send_start: iss.wrapping_add(1),
+            send_buffer: VecDeque::new(),
+            fin_queued: false,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            fin_received: false,
+            mss: DEFAULT_MSS.min(local_mss),
+            local_mss,
+            rto: INITIAL_RTO_MS,
+            srtt: None,
+            rttvar: 0,
+            rtt_sample: None,
+            retries: 0,
+            deadline: None,
+            ack_needed: false,
+            reset_needed: false,
+            error: None,
+        }
+    }
+
+    /// An active open, the SYN goes out with the first `output`.
+    fn connect(iss: u32, local_mss: usize) -> Self {
+        Self::new(State::SynSent, iss, local_mss)
+    }
+
+    /// A passive open, answering `syn`.
+    fn accept(iss: u32, local_mss: usize, syn: &Segment) -> Self {
+        let mut tcb = Self::new(State::SynReceived, iss, local_mss);
+        tcb.rcv_nxt = syn.seq.wrapping_add(1);
+        tcb.snd_wnd = syn.window as u32;
+        tcb.take_mss(syn);
+        tcb
+    }
+
+    fn take_mss(&mut self, syn: &Segment) {
+        self.mss = syn.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(self.local_mss);
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.recv_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn fin_seq(&self) -> u32 {
+        self.send_start.wrapping_add(self.send_buffer.len() as u32)
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_queued && self.snd_una == self.fin_seq().wrapping_add(1)
+    }
+
+    fn sends_data(&self) -> bool {
+        matches!(self.state, State::Established | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck)
+    }
+
+    fn close_with(&mut self, error: Option<NetError>) {
+        self.state = State::Closed;
+        self.error = error;
+        self.deadline = None;
+        self.send_buffer.clear();
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.deadline = Some(now + TIME_WAIT.as_millis() as u64);
+    }
+
+    /// Segments to send now; starts the retransmission timer for them.
+    fn output(&mut self, now: u64) -> Vec<Segment<'static>> {
+        let mut segments = Vec::new();
+        if self.reset_needed {
+            self.reset_needed = false;
+            let mut reset = Segment::new(self.snd_nxt, RST | ACK);
+            reset.ack = self.rcv_nxt;
+            return Vec::from([reset]);
+        }
+        if matches!(self.state, State::SynSent | State::SynReceived) && self.snd_nxt == self.iss {
+            let mut syn = Segment::new(self.iss, SYN);
+            syn.mss = Some(self.local_mss as u16);
+            segments.push(syn);
+            self.snd_nxt = self.iss.wrapping_add(1);
+        }
+        while self.sends_data() {
+            let offset = self.snd_nxt.wrapping_sub(self.send_start) as usize;
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+            let room = self.snd_wnd.saturating_sub(in_flight) as usize;
+            if offset < self.send_buffer.len() {
+                let len = (self.send_buffer.len() - offset).min(self.mss).min(room);
+                if len == 0 {
+                    break;
+                }
+                let mut segment = Segment::new(self.snd_nxt, PSH);
+                segment.payload = Cow::Owned(self.send_buffer.range(offset..offset + len).copied().collect());
+                self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                if self.rtt_sample.is_none() {
+                    self.rtt_sample = Some((self.snd_nxt, now));
+                }
+                segments.push(segment);
+            } else if self.fin_queued && self.snd_nxt == self.fin_seq() {
+                segments.push(Segment::new(self.snd_nxt, FIN));
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            } else {
+                break;
+            }
+        }
+        if segments.is_empty() && self.ack_needed && self.state != State::SynSent {
+            segments.push(Segment::new(self.snd_nxt, 0));
+        }
+        self.ack_needed = false;
+        for segment in &mut segments {
+            segment.window = self.window();
+            if self.state != State::SynSent {
+                segment.flags |= ACK;
+                segment.ack = self.rcv_nxt;
+            }
+        }
+        // also when a closed window keeps data back, the timer probes it
+        let unsent = self.snd_nxt.wrapping_sub(self.send_start) as usize != self.send_buffer.len() && self.sends_data();
+        if self.deadline.is_none() && (self.snd_nxt != self.snd_una || unsent) {
+            self.deadline = Some(now + self.rto);
+        }
+        segments
+    }
+
+    fn sample_rtt(&mut self, rtt: u64) {
+        // as RFC 6298 has it
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + (4 * self.rttvar).max(1)).clamp(MIN_RTO_MS, MAX_RTO_MS);
+    }
+
+    /// The peer has everything before `ack`.
+    fn acknowledge(&mut self, ack: u32, now: u64) {
+        if before(self.send_start, ack) {
+            let acked = (ack.wrapping_sub(self.send_start) as usize).min(self.send_buffer.len());
+            self.send_buffer.drain(..acked);
+            self.send_start = self.send_start.wrapping_add(acked as u32);
+        }
+        self.snd_una = ack;
+        if let Some((seq, sent)) = self.rtt_sample {
+            if before_or_at(seq, ack) {
+                self.rtt_sample = None;
+                self.sample_rtt(now - sent);
+            }
+        }
+        self.retries = 0;
+        self.deadline = (self.snd_una != self.snd_nxt).then_some(now + self.rto);
+    }
+
+    fn on_syn_sent(&mut self, segment: &Segment, now: u64) {
+        let acceptable = segment.flags & ACK != 0 && segment.ack == self.iss.wrapping_add(1);
+        if segment.flags & ACK != 0 && !acceptable {
+            self.reset_needed = segment.flags & RST == 0;
+            return;
+        }
+        if segment.flags & RST != 0 {
+            if acceptable {
+                self.close_with(Some(NetError::ConnectionRefused));
+            }
+            return;
+        }
+        // a SYN without an ACK would be a simultaneous open, which nobody does
+        if segment.flags & SYN == 0 || !acceptable {
+            return;
+        }
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.snd_wnd = segment.window as u32;
+        self.take_mss(segment);
+        self.acknowledge(segment.ack, now);
+        self.state = State::Established;
+        self.ack_needed = true;
+    }
+
+    /* a segment for this connection.
+        RFC 793's "segment arrives", with data only taken in order: check that it is
+        in the window, then RST, SYN, ACK, the data and at last the FIN.
+     */
+    fn on_segment(&mut self, segment: &Segment, now: u64) {
+        match self.state {
+            State::Closed => return,
+            State::SynSent => return self.on_syn_sent(segment, now),
+            _ => {}
+        }
+        let window = self.window() as u32;
+        let end = segment.seq.wrapping_add(segment.len());
+        let in_window = |seq: u32| before_or_at(self.rcv_nxt, seq) && before(seq, self.rcv_nxt.wrapping_add(window.max(1)));
+        let acceptable = if segment.len() == 0 {
+            in_window(segment.seq)
+        } else {
+            in_window(segment.seq) || (window > 0 && in_window(end.wrapping_sub(1)))
+        };
+        if !acceptable {
+            // a duplicate tells the peer where we are
+            self.ack_needed = segment.flags & RST == 0;
+            return;
+        }
+        if segment.flags & RST != 0 {
+            self.close_with(Some(NetError::ConnectionReset));
+            return;
+        }
+        if segment.flags & SYN != 0 {
+            self.ack_needed = true;
+            return;
+        }
+        if segment.flags & ACK == 0 {
+            return;
+        }
+
+        if self.state == State::SynReceived {
+            if !(before(self.snd_una, segment.ack) && before_or_at(segment.ack, self.snd_nxt)) {
+                self.reset_needed = true;
+                return;
+            }
+            self.state = State::Established;
+        }
+        if before(self.snd_nxt, segment.ack) {
+            self.ack_needed = true;
+            return;
+        }
+        if before(self.snd_una, segment.ack) {
+            self.acknowledge(segment.ack, now);
+        }
+        if before_or_at(self.snd_una, segment.ack) {
+            self.snd_wnd = segment.window as u32;
+        }
+        if self.fin_acked() {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => {
+                    self.close_with(None);
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        if !segment.payload.is_empty() && matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+            let skip = self.rcv_nxt.wrapping_sub(segment.seq) as usize;
+            if before_or_at(segment.seq, self.rcv_nxt) && skip < segment.payload.len() {
+                let take = (segment.payload.len() - skip).min(BUFFER_SIZE - self.recv_buffer.len());
+                self.recv_buffer.extend(&segment.payload[skip..skip + take]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(take as u32);
+            }
+            self.ack_needed = true;
+        }
+
+        let fin_at = segment.seq.wrapping_add(segment.payload.len() as u32);
+        if segment.flags & FIN != 0 && fin_at == self.rcv_nxt && !self.fin_received {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_needed = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+    }
+
+    /// Resends from the oldest unacknowledged byte once the deadline passed.
+    fn on_timer(&mut self, now: u64) {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        self.deadline = None;
+        if self.state == State::TimeWait {
+            self.close_with(None);
+            return;
+        }
+        if self.snd_una != self.snd_nxt {
+            self.retries += 1;
+            if self.retries > MAX_RETRIES {
+                self.close_with(Some(NetError::TimedOut));
+                return;
+            }
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO_MS);
+        // Karn: a resent segment says nothing about the round trip
+        self.rtt_sample = None;
+        self.snd_nxt = self.snd_una;
+        // a closed window is probed with a byte
+        self.snd_wnd = self.snd_wnd.max(1);
+    }
+
+    /// Takes what was received, `None` if the caller has to wait for more.
+    fn read(&mut self, buffer: &mut [u8]) -> Option<Result<usize, NetError>> {
+        if !self.recv_buffer.is_empty() {
+            // a window that was too small to send into opens up
+            self.ack_needed = (self.window() as usize) < self.mss;
+            let count = buffer.len().min(self.recv_buffer.len());
+            for (to, from) in buffer.iter_mut().zip(self.recv_buffer.drain(..count)) {
+                *to = from;
+            }
+            return Some(Ok(count));
+        }
+        if self.fin_received {
+            return Some(Ok(0));
+        }
+        match self.state {
+            State::Closed => Some(self.error.map_or(Ok(0), Err)),
+            _ => None,
+        }
+    }
+
+    /// Queues what fits of `buffer`, 0 if the caller has to wait for room.
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, NetError> {
+        if self.fin_queued {
+            return Err(NetError::NotConnected);
+        }
+        match self.state {
+            State::SynSent | State::SynReceived | State::Established | State::CloseWait => {}
+            _ => return Err(self.error.unwrap_or(NetError::NotConnected)),
+        }
+        let count = buffer.len().min(BUFFER_SIZE - self.send_buffer.len());
+        self.send_buffer.extend(&buffer[..count]);
+        Ok(count)
+    }
+
+    /// No more data from us, the FIN follows what is queued.
+    fn close(&mut self) {
+        match self.state {
+            State::SynSent => self.close_with(None),
+            State::SynReceived | State::Established => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
+            _ => return,
+        }
+        self.fin_queued = true;
+    }
+
+    fn abort(&mut self) {
+        self.reset_needed = self.state != State::Closed;
+        self.close_with(None);
+    }
+}
+
+struct Connection {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    tcb: SpinLock<Tcb>,
+    // readers, writers and connectors wait here for anything to change
+    events: WaitQueue,
+    // where the connection goes once established, for passive opens
+    listener: Weak<Listener>,
+    timer_armed: AtomicBool,
+}
+
+impl Connection {
+    fn new(local: SocketAddrV4, remote: SocketAddrV4, tcb: Tcb, listener: Weak<Listener>) -> Arc<Self> {
+        Arc::new(Connection { local, remote, tcb: SpinLock::new(tcb), events: WaitQueue::new(), listener, timer_armed: AtomicBool::new(false) })
+    }
+
+    /// Runs `f` on the control block and sends what comes of it.
+    fn update(self: &Arc<Self>, f: impl FnOnce(&mut Tcb, u64)) {
+        let now = time::uptime_ms();
+        let (segments, before, after, deadline) = {
+            let mut tcb = self.tcb.lock();
+            let before = tcb.state;
+            f(&mut tcb, now);
+            (tcb.output(now), before, tcb.state, tcb.deadline)
+        };
+        for segment in segments {
+            // lost like on the wire, the timer resends
+            let _ = ipv4::send(*self.remote.ip(), PROTOCOL_TCP, &segment.to_bytes(self.local, self.remote));
+        }
+        if before == State::SynReceived && after != State::SynReceived && after != State::Closed {
+            match self.listener.upgrade() {
+                Some(listener) => listener.push(self.clone()),
+                None => self.update(|tcb, _| tcb.abort()),
+            }
+        }
+        if after == State::Closed {
+            CONNECTIONS.lock().remove(&(self.local, self.remote));
+        } else if let Some(deadline) = deadline {
+            self.arm(deadline.saturating_sub(now));
+        }
+        self.events.wake_all();
+    }
+
+    fn arm(self: &Arc<Self>, delay: u64) {
+        if self.timer_armed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let connection = Arc::downgrade(self);
+        workqueue::spawn_after(Duration::from_millis(delay), move || {
+            if let Some(connection) = connection.upgrade() {
+                connection.timer_armed.store(false, Ordering::Release);
+                connection.update(|tcb, now| tcb.on_timer(now));
+            }
+        });
+    }
+
+    fn state(&self) -> State {
+        self.tcb.lock().state
+    }
+}
+
+struct Listener {
+    port: u16,
+    backlog: SpinLock<VecDeque<Arc<Connection>>>,
+    events: WaitQueue,
+}
+
+impl Listener {
+    fn push(&self, connection: Arc<Connection>) {
+        self.backlog.lock().push_back(connection);
+        self.events.wake_all();
+    }
+}
+
+fn initial_sequence() -> u32 {
+    random::next_u64() as u32
+}
+
+fn local_mss(interface: &Interface) -> usize {
+    interface.mtu() - ipv4::HEADER_LEN - HEADER_LEN
+}
+
+/// Answers a segment nobody wants, as RFC 793 says to.
+fn reset(local: SocketAddrV4, remote: SocketAddrV4, segment: &Segment) {
+    let mut reply = if segment.flags & ACK != 0 {
+        Segment::new(segment.ack, RST)
+    } else {
+        let mut reply = Segment::new(0, RST | ACK);
+        reply.ack = segment.seq.wrapping_add(segment.len());
+        reply
+    };
+    reply.window = 0;
+    let _ = ipv4::send(*remote.ip(), PROTOCOL_TCP, &reply.to_bytes(local, remote));
+}
+
+fn receive(interface: &Arc<Interface>, packet: &Packet) {
+    let Ok((source_port, destination_port, segment)) = Segment::parse(packet.payload, packet.source, packet.destination) else {
+        interface.count_dropped();
+        return;
+    };
+    let local = SocketAddrV4::new(packet.destination, destination_port);
+    let remote = SocketAddrV4::new(packet.source, source_port);
+    let connection = CONNECTIONS.lock().get(&(local, remote)).cloned();
+    if let Some(connection) = connection {
+        connection.update(|tcb, now| tcb.on_segment(&segment, now));
+        return;
+    }
+    if segment.flags & RST != 0 {
+        return;
+    }
+    let listener = LISTENERS.lock().get(&destination_port).cloned();
+    match listener {
+        Some(listener) if segment.flags & (SYN | ACK) == SYN && listener.backlog.lock().len() < MAX_BACKLOG => {
+            let tcb = Tcb::accept(initial_sequence(), local_mss(interface), &segment);
+            let connection = Connection::new(local, remote, tcb, Arc::downgrade(&listener));
+            CONNECTIONS.lock().insert((local, remote), connection.clone());
+            connection.update(|_, _| {});
+        }
+        _ => reset(local, remote, &segment),
+    }
+}
+
+/// A free port to connect from.
+fn ephemeral_port(remote: SocketAddrV4, local: Ipv4Addr) -> Result<u16, NetError> {
+    let (first, last) = EPHEMERAL_PORTS;
+    let connections = CONNECTIONS.lock();
+    for _ in first..=last {
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let port = if (first..=last).contains(&port) { port } else { first };
+        if !connections.contains_key(&(SocketAddrV4::new(local, port), remote)) && !LISTENERS.lock().contains_key(&port) {
+            return Ok(port);
+        }
+    }
+    Err(NetError::AddressInUse)
+}
+
+/// Waits for connections on a port, of every address the machine has.
+pub struct TcpListener {
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        }
+        let listener = Arc::new(Listener { port, backlog: SpinLock::new(VecDeque::new()), events: WaitQueue::new() });
+        listeners.insert(port, listener.clone());
+        Ok(TcpListener { listener })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.port
+    }
+
+    /// Waits for the next connection that finished its handshake.
+    pub fn accept(&self) -> Result<TcpStream, NetError> {
+        let mut connection = None;
+        signal::wait_interruptible(&self.listener.events, || {
+            connection = self.listener.backlog.lock().pop_front();
+            connection.is_some()
+        })?;
+        Ok(TcpStream { connection: connection.unwrap() })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.listener.port);
+        let backlog = core::mem::take(&mut *self.listener.backlog.lock());
+        for connection in backlog {
+            connection.update(|tcb, _| tcb.abort());
+        }
+    }
+}
+
+/// One end of a connection. Dropping it closes the connection, data already
+/// written is still delivered.
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Opens a connection to `remote`, waiting for the handshake to finish.
+    pub fn connect(remote: SocketAddrV4) -> Result<Self, NetError> {
+        let (interface, _) = ipv4::route(*remote.ip()).ok_or(NetError::Unreachable)?;
+        let address = interface.ipv4_address().ok_or(NetError::Unreachable)?;
+        let local = SocketAddrV4::new(address, ephemeral_port(remote, address)?);
+        let connection = Connection::new(local, remote, Tcb::connect(initial_sequence(), local_mss(&interface)), Weak::new());
+        CONNECTIONS.lock().insert((local, remote), connection.clone());
+        connection.update(|_, _| {});
+        let stream = TcpStream { connection };
+        signal::wait_interruptible(&stream.connection.events, || stream.state() != State::SynSent)?;
+        let error = stream.connection.tcb.lock().error;
+        match error {
+            Some(error) => Err(error),
+            None => Ok(stream),
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.connection.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.connection.remote
+    }
+
+    pub fn state(&self) -> State {
+        self.connection.state()
+    }
+
+    /// Waits for data, returns 0 once the peer closed its side.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, NetError> {
+        self.receive(buffer, None)
+    }
+
+    /// Like `read`, but gives up with `TimedOut` after `timeout`.
+    pub fn read_timeout(&self, buffer: &mut [u8], timeout: Duration) -> Result<usize, NetError> {
+        self.receive(buffer, Some(time::ticks() + time::duration_to_ticks(timeout)))
+    }
+
+    fn receive(&self, buffer: &mut [u8], deadline: Option<u64>) -> Result<usize, NetError> {
+        let timer = deadline.map(|deadline| add_timer(deadline, TimerAction::Unpark(thread::current_id())));
+        let mut result = None;
+        let waited = signal::wait_interruptible(&self.connection.events, || {
+            result = self.connection.tcb.lock().read(buffer);
+            result.is_some() || deadline.is_some_and(|deadline| time::ticks() >= deadline)
+        });
+        if let Some(timer) = timer {
+            cancel_timer(timer);
+        }
+        waited?;
+        // the read may have opened the window
+        self.connection.update(|_, _| {});
+        result.unwrap_or(Err(NetError::TimedOut))
+    }
+
+    /// Queues all of `buffer`, waiting for room as needed. A signal cuts it short,
+    /// with what was queued until then.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, NetError> {
+        let mut written = 0;
+        while written < buffer.len() {
+            let mut result = Ok(0);
+            let waited = signal::wait_interruptible(&self.connection.events, || {
+                result = self.connection.tcb.lock().write(&buffer[written..]);
+                result != Ok(0)
+            });
+            match (waited, result) {
+                (Err(_), _) if written > 0 => break,
+                (Err(interrupted), _) => return Err(interrupted.into()),
+                (Ok(()), Ok(count)) => written += count,
+                (Ok(()), Err(error)) => return Err(error),
+            }
+            self.connection.update(|_, _| {});
+        }
+        Ok(written)
+    }
+
+    /// Sends a FIN after the queued data, reading goes on until the peer closes too.
+    pub fn shutdown(&self) {
+        self.connection.update(|tcb, _| tcb.close());
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+pub fn init() {
+    ipv4::register_protocol(PROTOCOL_TCP, receive);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    const MSS: usize = 1460;
+
+    /// Passes segments between `a` and `b` until neither has anything to say,
+    /// dropping those `lose` picks. Returns the number of segments passed.
+    fn exchange(a: &mut Tcb, b: &mut Tcb, now: u64, mut lose: impl FnMut(&Segment) -> bool) -> usize {
+        let mut passed = 0;
+        loop {
+            let from_a = a.output(now);
+            let from_b = b.output(now);
+            if from_a.is_empty() && from_b.is_empty() {
+                return passed;
+            }
+            for segment in from_a.iter().filter(|segment| !lose(segment)) {
+                b.on_segment(segment, now);
+                passed += 1;
+            }
+            for segment in from_b.iter().filter(|segment| !lose(segment)) {
+                a.on_segment(segment, now);
+                passed += 1;
+            }
+        }
+    }
+
+    fn connected() -> (Tcb, Tcb) {
+        let mut client = Tcb::connect(0xffff_fff0, MSS);
+        let syn = client.output(0).pop().unwrap();
+        assert_eq!((syn.flags, syn.mss), (SYN, Some(MSS as u16)));
+        let mut server = Tcb::accept(5000, MSS, &syn);
+        exchange(&mut client, &mut server, 0, |_| false);
+        assert_eq!((client.state, server.state), (State::Established, State::Established));
+        (client, server)
+    }
+
+    fn read_all(tcb: &mut Tcb) -> Vec<u8> {
+        let mut data = vec![0u8; BUFFER_SIZE];
+        let count = tcb.read(&mut data).map_or(0, Result::unwrap);
+        data.truncate(count);
+        data
+    }
+
+    #[test_case]
+    fn test_segments() {
+        let (local, remote) = (SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 49152), SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 80));
+        let mut segment = Segment::new(1, SYN | ACK);
+        segment.ack = 7;
+        segment.window = 1000;
+        segment.mss = Some(1460);
+        segment.payload = Cow::Borrowed(b"hello");
+        let mut bytes = segment.to_bytes(local, remote);
+        assert_eq!(Segment::parse(&bytes, *local.ip(), *remote.ip()), Ok((49152, 80, segment)));
+        // the pseudo header is covered
+        assert_eq!(Segment::parse(&bytes, Ipv4Addr::new(10, 0, 2, 16), *remote.ip()), Err(NetError::Malformed));
+        bytes[20] = OPTION_MSS;
+        bytes[21] = 40;
+        assert!(Segment::parse(&bytes, *local.ip(), *remote.ip()).is_err());
+        assert!(before(u32::MAX, 1) && !before(1, u32::MAX));
+    }
+
+    #[test_case]
+    fn test_data_and_window() {
+        let (mut client, mut server) = connected();
+        // the sequence numbers of the client wrap around here
+        assert_eq!(client.write(b"GET / HTTP/1.0\r\n\r\n"), Ok(18));
+        exchange(&mut client, &mut server, 0, |_| false);
+        assert_eq!(read_all(&mut server), b"GET / HTTP/1.0\r\n\r\n");
+        assert!(client.send_buffer.is_empty());
+
+        // more than the server takes: the window closes until it reads
+        let data: Vec<u8> = (0..BUFFER_SIZE + 10000).map(|index| index as u8).collect();
+        let mut written = client.write(&data).unwrap();
+        assert_eq!(written, BUFFER_SIZE);
+        exchange(&mut client, &mut server, 0, |_| false);
+        assert_eq!(server.window(), 0);
+        written += client.write(&data[written..]).unwrap();
+        assert_eq!(written, data.len());
+        let mut received = read_all(&mut server);
+        exchange(&mut client, &mut server, 0, |_| false);
+        received.extend(read_all(&mut server));
+        assert_eq!(received, data);
+        assert_eq!(server.read(&mut [0u8; 1]), None);
+    }
+
+    #[test_case]
+    fn test_retransmission() {
+        let (mut client, mut server) = connected();
+        client.write(&[7u8; 3000]).unwrap();
+        // the first of three segments is lost, the others are not taken out of order
+        let mut first = true;
+        exchange(&mut client, &mut server, 0, |segment| !segment.payload.is_empty() && core::mem::replace(&mut first, false));
+        assert!(read_all(&mut server).is_empty());
+        assert_eq!(client.deadline, Some(INITIAL_RTO_MS));
+
+        client.on_timer(INITIAL_RTO_MS);
+        exchange(&mut client, &mut server, INITIAL_RTO_MS, |_| false);
+        assert_eq!(read_all(&mut server), [7u8; 3000]);
+        assert_eq!((client.deadline, client.retries), (None, 0));
+
+        // nobody answers any more
+        client.write(b"x").unwrap();
+        let mut now = 2000;
+        client.output(now);
+        while client.state != State::Closed {
+            now = client.deadline.unwrap();
+            client.on_timer(now);
+            client.output(now);
+        }
+        assert_eq!(client.error, Some(NetError::TimedOut));
+        assert_eq!(client.write(b"x"), Err(NetError::TimedOut));
+    }
+
+    #[test_case]
+    fn test_teardown() {
+        let (mut client, mut server) = connected();
+        client.write(b"bye").unwrap();
+        client.close();
+        exchange(&mut client, &mut server, 0, |_| false);
+        assert_eq!((client.state, server.state), (State::FinWait2, State::CloseWait));
+        assert_eq!(read_all(&mut server), b"bye");
+        assert_eq!(server.read(&mut [0u8; 4]), Some(Ok(0)));
+
+        server.close();
+        exchange(&mut client, &mut server, 0, |_| false);
+        assert_eq!((client.state, server.state), (State::TimeWait, State::Closed));
+        client.on_timer(TIME_WAIT.as_millis() as u64);
+        assert_eq!(client.state, State::Closed);
+
+        // refused: a RST answers the SYN
+        let mut client = Tcb::connect(1, MSS);
+        let syn = client.output(0).pop().unwrap();
+        let mut refusal = Segment::new(0, RST | ACK);
+        refusal.ack = syn.seq.wrapping_add(syn.len());
+        client.on_segment(&refusal, 0);
+        assert_eq!((client.state, client.error), (State::Closed, Some(NetError::ConnectionRefused)));
+    }
+}
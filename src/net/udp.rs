@@ -0,0 +1,245 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+
+use super::ipv4::{self, checksum, pseudo_header, Packet, PROTOCOL_UDP};
+use super::{Interface, NetError};
+use crate::process::signal;
+use crate::sync::SpinLock;
+use crate::thread::{self, WaitQueue};
+use crate::time::sleep::{add_timer, cancel_timer};
+use crate::time::{self, TimerAction};
+
+/* UDP.
+    Sockets are bound to a port on every address; a datagram for a port nobody
+    bound is dropped. Each socket keeps a short queue of what it received, more
+    is dropped as well.
+ */
+
+pub const HEADER_LEN: usize = 8;
+// received datagrams waiting per socket
+const MAX_QUEUED: usize = 64;
+const EPHEMERAL_PORTS: (u16, u16) = (49152, 65535);
+
+static SOCKETS: SpinLock<BTreeMap<u16, Arc<Socket>>> = SpinLock::new(BTreeMap::new());
+static NEXT_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS.0);
+
+struct Socket {
+    port: u16,
+    queue: SpinLock<VecDeque<Datagram>>,
+    events: WaitQueue,
+    // an async task waiting in `recv_from_async`
+    waker: AtomicWaker,
+}
+
+fn build(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = HEADER_LEN + payload.len();
+    let mut bytes = Vec::with_capacity(len);
+    bytes.extend_from_slice(&source.port().to_be_bytes());
+    bytes.extend_from_slice(&destination.port().to_be_bytes());
+    bytes.extend_from_slice(&(len as u16).to_be_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(payload);
+    let pseudo = pseudo_header(*source.ip(), *destination.ip(), PROTOCOL_UDP, len);
+    // zero says there is no checksum, one that comes out as zero is sent as all ones
+    let sum = match checksum(&[&pseudo, &bytes]) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    bytes[6..8].copy_from_slice(&sum.to_be_bytes());
+    bytes
+}
+
+/// Checks the datagram in `bytes`, returns the source and destination ports with the payload.
+fn parse(bytes: &[u8], source: Ipv4Addr, destination: Ipv4Addr) -> Result<(u16, u16, &[u8]), NetError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(NetError::Malformed);
+    }
+    let half = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+    let len = half(4) as usize;
+    if len < HEADER_LEN || len > bytes.len() {
+        return Err(NetError::Malformed);
+    }
+    let bytes = &bytes[..len];
+    if half(6) != 0 && checksum(&[&pseudo_header(source, destination, PROTOCOL_UDP, len), bytes]) != 0 {
+        return Err(NetError::Malformed);
+    }
+    Ok((half(0), half(2), &bytes[HEADER_LEN..]))
+}
+
+fn receive(interface: &Arc<Interface>, packet: &Packet) {
+    let Ok((source_port, destination_port, payload)) = parse(packet.payload, packet.source, packet.destination) else {
+        interface.count_dropped();
+        return;
+    };
+    let socket = SOCKETS.lock().get(&destination_port).cloned();
+    let Some(socket) = socket else {
+        interface.count_dropped();
+        return;
+    };
+    let mut queue = socket.queue.lock();
+    if queue.len() >= MAX_QUEUED {
+        drop(queue);
+        interface.count_dropped();
+        return;
+    }
+    queue.push_back((SocketAddrV4::new(packet.source, source_port), payload.to_vec()));
+    drop(queue);
+    socket.events.wake_all();
+    socket.waker.wake();
+}
+
+type Datagram = (SocketAddrV4, Vec<u8>);
+
+/// Copies the payload of `datagram` to `buffer`, what does not fit is lost.
+fn deliver(buffer: &mut [u8], (source, payload): Datagram) -> (usize, SocketAddrV4) {
+    let count = buffer.len().min(payload.len());
+    buffer[..count].copy_from_slice(&payload[..count]);
+    (count, source)
+}
+
+pub struct UdpSocket {
+    socket: Arc<Socket>,
+}
+
+impl UdpSocket {
+    /// Takes `port` for the socket, 0 for any free one.
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => {
+                let (first, last) = EPHEMERAL_PORTS;
+                (first..=last)
+                    .map(|_| match NEXT_PORT.fetch_add(1, Ordering::Relaxed) {
+                        port if port >= first => port,
+                        _ => first,
+                    })
+                    .find(|port| !sockets.contains_key(port))
+                    .ok_or(NetError::AddressInUse)?
+            }
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let socket = Arc::new(Socket { port, queue: SpinLock::new(VecDeque::new()), events: WaitQueue::new(), waker: AtomicWaker::new() });
+        sockets.insert(port, socket.clone());
+        Ok(UdpSocket { socket })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket.port
+    }
+
+    /// Sends `payload` to `destination`, from the address of the interface the route goes through.
+    pub fn send_to(&self, payload: &[u8], destination: SocketAddrV4) -> Result<(), NetError> {
+        let (interface, _) = ipv4::route(*destination.ip()).ok_or(NetError::Unreachable)?;
+        let source = SocketAddrV4::new(interface.ipv4_address().unwrap_or(Ipv4Addr::UNSPECIFIED), self.port());
+        ipv4::send(*destination.ip(), PROTOCOL_UDP, &build(source, destination, payload))
+    }
+
+    /// Sends `payload` out of `interface` without asking the routing table, from
+    /// 0.0.0.0 while it has no address.
+    pub fn send_on(&self, interface: &Arc<Interface>, payload: &[u8], destination: SocketAddrV4) -> Result<(), NetError> {
+        let source = interface.ipv4_address().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let datagram = build(SocketAddrV4::new(source, self.port()), destination, payload);
+        ipv4::send_on(interface, source, *destination.ip(), PROTOCOL_UDP, &datagram)
+    }
+
+    /// Waits for a datagram and copies it to `buffer`, what does not fit is lost.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddrV4), NetError> {
+        self.receive(buffer, None)
+    }
+
+    /// Like `recv_from`, but gives up with `TimedOut` after `timeout`.
+    pub fn recv_from_timeout(&self, buffer: &mut [u8], timeout: Duration) -> Result<(usize, SocketAddrV4), NetError> {
+        self.receive(buffer, Some(time::ticks() + time::duration_to_ticks(timeout)))
+    }
+
+    fn receive(&self, buffer: &mut [u8], deadline: Option<u64>) -> Result<(usize, SocketAddrV4), NetError> {
+        let timer = deadline.map(|deadline| add_timer(deadline, TimerAction::Unpark(thread::current_id())));
+        let mut datagram = None;
+        let waited = signal::wait_interruptible(&self.socket.events, || {
+            datagram = self.socket.queue.lock().pop_front();
+            datagram.is_some() || deadline.is_some_and(|deadline| time::ticks() >= deadline)
+        });
+        if let Some(timer) = timer {
+            cancel_timer(timer);
+        }
+        waited?;
+        Ok(deliver(buffer, datagram.ok_or(NetError::TimedOut)?))
+    }
+
+    /// Like `recv_from`, for async tasks.
+    pub async fn recv_from_async(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddrV4), NetError> {
+        let datagram = poll_fn(|cx| {
+            if let Some(datagram) = self.socket.queue.lock().pop_front() {
+                return Poll::Ready(datagram);
+            }
+            self.socket.waker.register(cx.waker());
+            // a datagram may have arrived before the waker was registered
+            match self.socket.queue.lock().pop_front() {
+                Some(datagram) => Poll::Ready(datagram),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        Ok(deliver(buffer, datagram))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.socket.port);
+    }
+}
+
+pub fn init() {
+    ipv4::register_protocol(PROTOCOL_UDP, receive);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::interface::TestDevice;
+    use crate::net::ipv4::Ipv4Config;
+    use crate::net::{register, unregister, MacAddress};
+    use crate::workqueue;
+
+    #[test_case]
+    fn test_datagrams() {
+        let (source, destination) = (SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 68), SocketAddrV4::new(Ipv4Addr::BROADCAST, 67));
+        let mut bytes = build(source, destination, b"hello");
+        assert_eq!(parse(&bytes, *source.ip(), *destination.ip()), Ok((68, 67, &b"hello"[..])));
+        bytes[6..8].copy_from_slice(&[0, 0]);
+        assert!(parse(&bytes, Ipv4Addr::UNSPECIFIED, *destination.ip()).is_ok());
+        bytes[4] = 1;
+        assert_eq!(parse(&bytes, *source.ip(), *destination.ip()), Err(NetError::Malformed));
+
+        let device = TestDevice::new(MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+        let interface = register("test-udp", device.clone()).unwrap();
+        ipv4::configure(&interface, Some(Ipv4Config { address: Ipv4Addr::new(10, 0, 2, 15), prefix_len: 24 }), None);
+        let sender = UdpSocket::bind(0).unwrap();
+        let receiver = UdpSocket::bind(5353).unwrap();
+        assert_eq!(UdpSocket::bind(5353).err(), Some(NetError::AddressInUse));
+
+        // to the broadcast address of the subnet, which comes back when injected
+        sender.send_to(b"ping", SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 255), 5353)).unwrap();
+        for frame in device.take_sent() {
+            device.inject(&frame);
+        }
+        workqueue::flush();
+        let mut buffer = [0u8; 2];
+        let (count, from) = receiver.recv_from_timeout(&mut buffer, Duration::from_millis(100)).unwrap();
+        assert_eq!((count, &buffer, from), (2, b"pi", SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), sender.port())));
+        assert_eq!(receiver.recv_from_timeout(&mut buffer, Duration::from_millis(20)), Err(NetError::TimedOut));
+
+        drop(receiver);
+        assert!(UdpSocket::bind(5353).is_ok());
+        unregister("test-udp");
+    }
+}
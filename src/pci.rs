@@ -0,0 +1,149 @@
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+use x86_64::instructions::port::Port;
+
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+use crate::sync::SpinLock;
+
+/* the PCI bus.
+    Only enough to see what is there: functions are found by reading the vendor of
+    every bus, slot and function through configuration mechanism 1, an address
+    written to 0xcf8 and the register read from 0xcfc, one at a time. Nothing is
+    configured, no driver binds to anything found yet.
+ */
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+// what a read of a function that is not there returns
+const NO_VENDOR: u16 = 0xffff;
+const SLOTS: u8 = 32;
+const FUNCTIONS: u8 = 8;
+const MULTI_FUNCTION: u8 = 0x80;
+
+// the address and data ports, in one lock as they are used in pairs
+static CONFIG: SpinLock<(Port<u32>, Port<u32>)> = SpinLock::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// Where a function sits on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+}
+
+impl Location {
+    /// The register of this function at `offset`, a multiple of 4 below 256.
+    pub fn read(self, offset: u8) -> u32 {
+        let address = 1 << 31 | (self.bus as u32) << 16 | (self.slot as u32) << 11 | (self.function as u32) << 8 | (offset & 0xfc) as u32;
+        let mut ports = CONFIG.lock();
+        unsafe {
+            ports.0.write(address);
+            ports.1.read()
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.slot, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub location: Location,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub revision: u8,
+}
+
+impl Device {
+    fn probe(location: Location) -> Option<(Device, u8)> {
+        let id = location.read(0x00);
+        if id as u16 == NO_VENDOR {
+            return None;
+        }
+        let class = location.read(0x08);
+        let header_type = (location.read(0x0c) >> 16) as u8;
+        let device = Device {
+            location,
+            vendor: id as u16,
+            device: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            revision: class as u8,
+        };
+        Some((device, header_type))
+    }
+
+    /// What the class code says the device is.
+    pub fn class_name(&self) -> &'static str {
+        const CLASSES: [&str; 20] = [
+            "unclassified", "mass storage controller", "network controller", "display controller",
+            "multimedia controller", "memory controller", "bridge", "communication controller",
+            "system peripheral", "input device controller", "docking station", "processor",
+            "serial bus controller", "wireless controller", "intelligent controller",
+            "satellite communication controller", "encryption controller",
+            "signal processing controller", "processing accelerator", "instrumentation",
+        ];
+        CLASSES.get(self.class as usize).copied().unwrap_or("unknown class")
+    }
+}
+
+/// Every function on the bus, in order of location.
+pub fn devices() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=u8::MAX {
+        for slot in 0..SLOTS {
+            let Some((device, header_type)) = Device::probe(Location { bus, slot, function: 0 }) else {
+                continue;
+            };
+            devices.push(device);
+            if header_type & MULTI_FUNCTION != 0 {
+                devices.extend((1..FUNCTIONS).filter_map(|function| Device::probe(Location { bus, slot, function })).map(|(device, _)| device));
+            }
+        }
+    }
+    devices
+}
+
+/// Registers `lspci`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("lspci", "list the devices on the PCI bus", lspci);
+}
+
+#[cfg(feature = "shell")]
+fn lspci(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("lspci"));
+    }
+    for device in devices() {
+        let _ = writeln!(
+            out,
+            "{} {:04x}:{:04x} rev {:02x} {} ({:02x}{:02x})",
+            device.location, device.vendor, device.device, device.revision, device.class_name(), device.class, device.subclass,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_host_bridge() {
+        // QEMU's machines all have their host bridge first
+        let devices = devices();
+        let first = devices.first().expect("no PCI devices");
+        assert_eq!(first.location, Location { bus: 0, slot: 0, function: 0 });
+        assert_eq!((first.class, first.subclass), (0x06, 0x00));
+        assert!(devices.windows(2).all(|pair| pair[0].location < pair[1].location));
+    }
+}
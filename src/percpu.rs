@@ -0,0 +1,170 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+use crate::interrupts::latency;
+use crate::msr::{GsBase, KernelGsBase};
+use crate::sync::lockdep::HeldLocks;
+use crate::sync::SpinLock;
+use crate::trace;
+
+/* per-CPU data.
+    Every CPU owns a `PerCpu` block, found through the GS base: its first word points
+    back at the block, so `this_cpu` is a single `mov` from gs:[0]. In user mode the GS
+    base belongs to the program, the entry and exit stubs swap it with `swapgs`
+    whenever they cross between ring 3 and the kernel.
+ */
+#[repr(C)]
+pub struct PerCpu {
+    this: u64,
+    /// Stack pointer of the interrupted program, saved by the `syscall` entry stub.
+    pub user_rsp: AtomicU64,
+    /// Top of the running thread's kernel stack, loaded by the `syscall` entry stub.
+    pub kernel_rsp: AtomicU64,
+    pub cpu_id: usize,
+    /// Local APIC id, what interprocessor interrupts are addressed to.
+    pub apic_id: u32,
+    /// Id of the thread running on this CPU.
+    pub current_thread: AtomicU64,
+    /// Hardware interrupt handlers currently running.
+    pub interrupt_depth: AtomicUsize,
+    /// TSC at the last interrupt entry, left by the `handler!` wrapper for interrupts::latency.
+    pub(crate) irq_entry_tsc: AtomicU64,
+    pub stats: CpuStats,
+    /// Spinlocks this CPU holds, for lock checking in debug builds.
+    pub(crate) held_locks: HeldLocks,
+    /// Events traced on this CPU.
+    pub(crate) trace: trace::Ring,
+}
+
+#[derive(Debug, Default)]
+pub struct CpuStats {
+    pub context_switches: AtomicU64,
+    pub interrupts: AtomicU64,
+    pub syscalls: AtomicU64,
+    pub ipis: AtomicU64,
+}
+
+/// Offsets used by the assembly entry stubs.
+pub const USER_RSP_OFFSET: usize = offset_of!(PerCpu, user_rsp);
+pub const KERNEL_RSP_OFFSET: usize = offset_of!(PerCpu, kernel_rsp);
+pub const IRQ_ENTRY_TSC_OFFSET: usize = offset_of!(PerCpu, irq_entry_tsc);
+
+static CPUS: SpinLock<Vec<&'static PerCpu>> = SpinLock::new(Vec::new());
+
+/// Reaches a field of the current CPU's block, as in `percpu!(stats.syscalls)`.
+#[macro_export]
+macro_rules! percpu {
+    ($($field:ident).+) => {
+        &$crate::percpu::this_cpu().$($field).+
+    };
+}
+
+/// Sets up the block of the calling CPU, before anything reads per-CPU data on it.
+pub fn init(cpu_id: usize) {
+    let block = Box::leak(Box::new(PerCpu {
+        this: 0,
+        user_rsp: AtomicU64::new(0),
+        kernel_rsp: AtomicU64::new(0),
+        cpu_id,
+        // the initial APIC id, as reported by cpuid
+        apic_id: unsafe { __cpuid(1) }.ebx >> 24,
+        current_thread: AtomicU64::new(u64::MAX),
+        interrupt_depth: AtomicUsize::new(0),
+        irq_entry_tsc: AtomicU64::new(0),
+        stats: CpuStats::default(),
+        held_locks: HeldLocks::new(),
+        trace: trace::Ring::new(),
+    }));
+    block.this = block as *const PerCpu as u64;
+    unsafe {
+        GsBase::write(VirtAddr::new(block.this));
+        // what `swapgs` hands to user mode
+        KernelGsBase::write(VirtAddr::zero());
+    }
+    CPUS.lock().push(block);
+}
+
+pub fn this_cpu() -> &'static PerCpu {
+    let block: *const PerCpu;
+    unsafe { asm!("mov {}, qword ptr gs:[0]", out(reg) block, options(nostack, preserves_flags, readonly)) };
+    unsafe { &*block }
+}
+
+/// The calling CPU's block, `None` before `init` ran on it.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if GsBase::read().is_null() {
+        None
+    } else {
+        Some(this_cpu())
+    }
+}
+
+/// The blocks of every CPU set up so far, in the order they came up.
+pub fn cpus() -> Vec<&'static PerCpu> {
+    CPUS.lock().clone()
+}
+
+/// Counts the handler of hardware interrupt `vector` until the guard is dropped.
+pub fn enter_interrupt(vector: u8) -> InterruptGuard {
+    let cpu = this_cpu();
+    cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+    crate::interrupts::count(vector);
+    crate::trace_event!(irq, "vector {} entry", vector);
+    InterruptGuard { vector, entry: latency::take_entry() }
+}
+
+pub struct InterruptGuard {
+    vector: u8,
+    // when the wrapper entered, for interrupts::latency
+    entry: u64,
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        latency::record(self.vector, self.entry);
+        crate::trace_event!(irq, "vector {} exit", self.vector);
+        percpu!(interrupt_depth).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether a hardware interrupt handler is running on this CPU.
+pub fn in_interrupt() -> bool {
+    percpu!(interrupt_depth).load(Ordering::Relaxed) > 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread;
+
+    #[test_case]
+    fn test_block_is_reached_through_gs() {
+        let cpu = this_cpu();
+        assert_eq!(cpu.this, cpu as *const PerCpu as u64);
+        assert_eq!(cpu.cpu_id, 0);
+        assert!(cpus().iter().any(|other| core::ptr::eq(*other, cpu)));
+        assert!(!in_interrupt());
+        {
+            let _irq = enter_interrupt(0);
+            assert!(in_interrupt());
+        }
+        assert!(!in_interrupt());
+    }
+
+    #[test_case]
+    fn test_current_thread_follows_switches() {
+        let switches = percpu!(stats.context_switches).load(Ordering::Relaxed);
+        let me = thread::current_id().as_u64();
+        thread::spawn("percpu", move || {
+            assert_ne!(percpu!(current_thread).load(Ordering::Relaxed), me);
+        }).join();
+        assert_eq!(percpu!(current_thread).load(Ordering::Relaxed), me);
+        assert!(percpu!(stats.context_switches).load(Ordering::Relaxed) > switches);
+    }
+}
@@ -0,0 +1,223 @@
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, Fadt, GenericAddress};
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+use crate::time::tsc;
+use crate::{memory, smp};
+#[cfg(feature = "shell")]
+use crate::fs;
+
+/* rebooting and turning off.
+    `reboot` tries the ways there are to reset the machine in turn, giving each a
+    moment to take before the next: the reset line of the keyboard controller, the
+    ACPI reset register when the FADT has one, and last a triple fault, an
+    exception with no IDT to handle it, which every x86 CPU takes for a reset.
+    `shutdown` puts the machine in ACPI's S5 state: the SLP_TYP values of the DSDT's
+    \_S5 package with SLP_EN, set in the PM1 control registers, whose other bits
+    are left as they were. Test builds, which only ever run in QEMU, write QEMU's
+    and Bochs' fixed ports first instead.
+ */
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+// the controller still has a byte of ours to take
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+// how long a way is given to reset the machine
+const RESET_WAIT_MS: u64 = 100;
+
+// in the PM1 control registers
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+// how long the firmware is given to hand over the hardware
+const ACPI_ENABLE_WAIT_MS: u64 = 300;
+// the PM1a control port with S5's SLP_TYP and SLP_EN: QEMU's PIIX4 and ICH9, and Bochs and older QEMU
+const EMULATOR_SHUTDOWN: [(u16, u16); 2] = [(0x604, 0x2000), (0xb004, 0x2000)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMethod {
+    Keyboard,
+    Acpi,
+    TripleFault,
+}
+
+/// In the order `reboot` tries them.
+pub const RESET_METHODS: [ResetMethod; 3] = [ResetMethod::Keyboard, ResetMethod::Acpi, ResetMethod::TripleFault];
+
+fn deadline(ms: u64) -> u64 {
+    tsc::read() + tsc::frequency() * ms / 1000
+}
+
+fn wait_ms(ms: u64) {
+    let deadline = deadline(ms);
+    while tsc::read() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+fn keyboard_reset() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+    let mut command: Port<u8> = Port::new(KBC_COMMAND);
+    unsafe {
+        // a controller that never takes the command is not there
+        for _ in 0..0x10000 {
+            if status.read() & KBC_INPUT_FULL == 0 {
+                command.write(KBC_PULSE_RESET);
+                return;
+            }
+        }
+    }
+}
+
+fn acpi_reset() {
+    let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset) else {
+        return;
+    };
+    match register.space {
+        GenericAddress::SYSTEM_IO => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+        GenericAddress::SYSTEM_MEMORY => {
+            let address = memory::phys_to_virt(x86_64::PhysAddr::new(register.address));
+            unsafe { address.as_mut_ptr::<u8>().write_volatile(value) };
+        }
+        // in PCI configuration space, left out
+        _ => {}
+    }
+}
+
+fn triple_fault() {
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+}
+
+/// Resets the machine with `method`; returns when it did not take.
+pub fn reset(method: ResetMethod) {
+    match method {
+        ResetMethod::Keyboard => keyboard_reset(),
+        ResetMethod::Acpi => acpi_reset(),
+        ResetMethod::TripleFault => triple_fault(),
+    }
+    wait_ms(RESET_WAIT_MS);
+}
+
+/// Resets the machine, with whatever works; filesystems are the caller's to sync.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    smp::halt_others();
+    for method in RESET_METHODS {
+        reset(method);
+        log::warn!("reboot: {:?} reset did not take", method);
+    }
+    loop {
+        hlt();
+    }
+}
+
+/// Hands the power management hardware from the firmware to the OS, if it is not already.
+fn enable_acpi(fadt: &Fadt) -> bool {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control as u16);
+    if unsafe { control.read() } & SCI_EN != 0 {
+        return true;
+    }
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return false;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    let deadline = deadline(ACPI_ENABLE_WAIT_MS);
+    while tsc::read() < deadline {
+        if unsafe { control.read() } & SCI_EN != 0 {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Sets the SLP_TYP field of the PM1 control register at `port` to `sleep_type`, and SLP_EN.
+unsafe fn enter_sleep_state(port: u32, sleep_type: u8) {
+    let mut control: Port<u16> = Port::new(port as u16);
+    let value = control.read() & !SLP_TYP_MASK;
+    control.write(value | (sleep_type as u16) << SLP_TYP_SHIFT & SLP_TYP_MASK | SLP_EN);
+}
+
+fn acpi_shutdown() {
+    let (Some(fadt), Some((type_a, type_b))) = (acpi::fadt(), acpi::s5_sleep_types()) else {
+        log::warn!("shutdown: no ACPI S5 state");
+        return;
+    };
+    if fadt.pm1a_control == 0 || !enable_acpi(&fadt) {
+        log::warn!("shutdown: the firmware did not hand over ACPI");
+        return;
+    }
+    unsafe {
+        enter_sleep_state(fadt.pm1a_control, type_a);
+        if fadt.pm1b_control != 0 {
+            enter_sleep_state(fadt.pm1b_control, type_b);
+        }
+    }
+    wait_ms(RESET_WAIT_MS);
+}
+
+fn emulator_shutdown() {
+    for (port, value) in EMULATOR_SHUTDOWN {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    wait_ms(RESET_WAIT_MS);
+}
+
+/// Turns the machine off; filesystems are the caller's to sync.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    smp::halt_others();
+    if cfg!(test) {
+        emulator_shutdown();
+    }
+    acpi_shutdown();
+    // the firmware of old emulators has no \_S5
+    emulator_shutdown();
+    log::error!("shutdown: the machine is still on");
+    loop {
+        hlt();
+    }
+}
+
+/// Registers `reboot` and `poweroff`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("reboot", "sync the filesystems and reset the machine", reboot_command);
+    shell::register_command("poweroff", "sync the filesystems and turn the machine off", poweroff_command);
+}
+
+#[cfg(feature = "shell")]
+fn reboot_command(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("reboot"));
+    }
+    if let Err(error) = fs::sync() {
+        let _ = writeln!(out, "reboot: sync failed: {:?}", error);
+    }
+    let _ = writeln!(out, "rebooting");
+    reboot();
+}
+
+#[cfg(feature = "shell")]
+fn poweroff_command(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("poweroff"));
+    }
+    if let Err(error) = fs::sync() {
+        let _ = writeln!(out, "poweroff: sync failed: {:?}", error);
+    }
+    let _ = writeln!(out, "powering off");
+    shutdown();
+}
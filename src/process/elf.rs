@@ -0,0 +1,362 @@
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::address_space::{USER_END, USER_START};
+use crate::memory::{AddressSpace, PAGE_SIZE};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Top of the initial user stack, one unmapped guard page below the end of user space.
+pub const USER_STACK_TOP: u64 = USER_END - PAGE_SIZE;
+pub const USER_STACK_PAGES: u64 = 16;
+
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    Truncated,
+    UnsupportedFormat,
+    NotExecutable,
+    WrongMachine,
+    BadSegment,
+    OutsideUserSpace,
+    /// The entry point is not in an executable PT_LOAD segment.
+    BadEntry,
+    OutOfMemory,
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => ElfError::OutOfMemory,
+            _ => ElfError::BadSegment,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A parsed, validated ELF64 executable borrowing the file contents.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: Vec<ProgramHeader>,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(if data.starts_with(&ELF_MAGIC) { ElfError::Truncated } else { ElfError::NotElf });
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16)? != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18)? != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let entry = read_u64(data, 24)?;
+        let ph_offset = read_u64(data, 32)? as usize;
+        let ph_entry_size = read_u16(data, 54)? as usize;
+        let ph_count = read_u16(data, 56)? as usize;
+        if ph_count > 0 && ph_entry_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::UnsupportedFormat);
+        }
+
+        let mut program_headers = Vec::with_capacity(ph_count);
+        for i in 0..ph_count {
+            let base = ph_offset.checked_add(i * PROGRAM_HEADER_SIZE).ok_or(ElfError::Truncated)?;
+            let header = ProgramHeader {
+                kind: read_u32(data, base)?,
+                flags: read_u32(data, base + 4)?,
+                offset: read_u64(data, base + 8)?,
+                vaddr: read_u64(data, base + 16)?,
+                file_size: read_u64(data, base + 32)?,
+                mem_size: read_u64(data, base + 40)?,
+            };
+            if header.kind == PT_LOAD {
+                Self::check_segment(data, &header)?;
+            }
+            program_headers.push(header);
+        }
+        // the segments are in user space, so an entry in one of them is too
+        let executes_entry = |header: &ProgramHeader| {
+            header.kind == PT_LOAD && header.flags & PF_X != 0
+                && entry >= header.vaddr && entry - header.vaddr < header.mem_size
+        };
+        if !program_headers.iter().any(executes_entry) {
+            return Err(ElfError::BadEntry);
+        }
+
+        Ok(ElfFile { data, entry, program_headers })
+    }
+
+    fn check_segment(data: &[u8], header: &ProgramHeader) -> Result<(), ElfError> {
+        if header.file_size > header.mem_size {
+            return Err(ElfError::BadSegment);
+        }
+        let file_end = header.offset.checked_add(header.file_size).ok_or(ElfError::BadSegment)?;
+        if file_end > data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        let mem_end = header.vaddr.checked_add(header.mem_size).ok_or(ElfError::OutsideUserSpace)?;
+        if header.vaddr < USER_START || mem_end > USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE {
+            return Err(ElfError::OutsideUserSpace);
+        }
+        Ok(())
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn program_headers(&self) -> &[ProgramHeader] {
+        &self.program_headers
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers.iter().filter(|header| header.kind == PT_LOAD)
+    }
+
+    /* maps every PT_LOAD segment.
+        Pages are zero-filled before the file contents are copied in, which also takes
+        care of .bss. Two segments sharing a page get the union of their permissions.
+     */
+    pub fn load(&self, space: &mut AddressSpace) -> Result<(), ElfError> {
+        for header in self.load_segments() {
+            let flags = header.page_flags();
+            let start = Page::containing_address(VirtAddr::new(header.vaddr));
+            let end = Page::containing_address(VirtAddr::new(header.vaddr + header.mem_size.max(1) - 1));
+            for page in Page::range_inclusive(start, end) {
+                match space.map_zeroed(page, flags) {
+                    Ok(_) => {}
+                    Err(MapToError::PageAlreadyMapped(_)) => Self::merge_flags(space, page, flags),
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            let contents = &self.data[header.offset as usize..(header.offset + header.file_size) as usize];
+            if !space.copy_to(VirtAddr::new(header.vaddr), contents) {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_flags(space: &mut AddressSpace, page: Page, flags: PageTableFlags) {
+        let existing = space.flags(page).unwrap_or(PageTableFlags::empty());
+        // executable if either segment is
+        let no_execute = existing & flags & PageTableFlags::NO_EXECUTE;
+        space.update_flags(page, ((existing | flags) - PageTableFlags::NO_EXECUTE) | no_execute);
+    }
+}
+
+/* initial user stack, System V layout.
+    From the top: argument and environment strings, then 16 byte aligned the auxiliary
+    vector, envp with a null terminator, argv with a null terminator and finally argc,
+    which the returned stack pointer points at.
+ */
+pub fn setup_stack(space: &mut AddressSpace, entry: VirtAddr, argv: &[&str], envp: &[&str]) -> Result<VirtAddr, ElfError> {
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+    for i in 0..USER_STACK_PAGES {
+        let page = Page::containing_address(VirtAddr::new(bottom + i * PAGE_SIZE));
+        space.map_zeroed(page, flags)?;
+    }
+
+    let mut sp = USER_STACK_TOP;
+    let mut push_string = |space: &mut AddressSpace, value: &str| -> Result<u64, ElfError> {
+        sp -= value.len() as u64 + 1;
+        if sp < bottom || !space.copy_to(VirtAddr::new(sp), value.as_bytes()) {
+            return Err(ElfError::OutOfMemory);
+        }
+        Ok(sp)
+    };
+    let mut env_pointers = Vec::with_capacity(envp.len());
+    for value in envp {
+        env_pointers.push(push_string(space, value)?);
+    }
+    let mut arg_pointers = Vec::with_capacity(argv.len());
+    for value in argv {
+        arg_pointers.push(push_string(space, value)?);
+    }
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&arg_pointers);
+    words.push(0);
+    words.extend_from_slice(&env_pointers);
+    words.push(0);
+    words.extend_from_slice(&[AT_PAGESZ, PAGE_SIZE, AT_ENTRY, entry.as_u64(), AT_NULL, 0]);
+
+    let table_size = (words.len() * 8) as u64;
+    let rsp = (sp - table_size) & !0xf;
+    if rsp < bottom {
+        return Err(ElfError::OutOfMemory);
+    }
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    if !space.copy_to(VirtAddr::new(rsp), &bytes) {
+        return Err(ElfError::OutOfMemory);
+    }
+    Ok(VirtAddr::new(rsp))
+}
+
+/// Where a freshly loaded program starts running.
+#[derive(Debug, Clone, Copy)]
+pub struct UserEntry {
+    pub instruction_pointer: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads a static executable into `space` and prepares its stack.
+pub fn load_program(space: &mut AddressSpace, data: &[u8], argv: &[&str], envp: &[&str]) -> Result<UserEntry, ElfError> {
+    let elf = ElfFile::parse(data)?;
+    elf.load(space)?;
+    let stack_pointer = setup_stack(space, elf.entry(), argv, envp)?;
+    Ok(UserEntry { instruction_pointer: elf.entry(), stack_pointer })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) const CODE_ADDRESS: u64 = USER_START + 0x1000;
+
+    /// A minimal static executable with one read-execute segment holding `code`
+    /// and one writable segment of `bss_size` zero bytes right after it.
+    pub(crate) fn build_elf(code: &[u8], bss_size: u64) -> Vec<u8> {
+        let code_offset = (ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE) as u64;
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&ELF_MAGIC);
+        elf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_X86_64.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&CODE_ADDRESS.to_le_bytes());
+        elf.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        elf.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&2u16.to_le_bytes());
+        elf.extend_from_slice(&[0; 6]);
+
+        let mut program_header = |flags: u32, offset: u64, vaddr: u64, file_size: u64, mem_size: u64| {
+            elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+            elf.extend_from_slice(&flags.to_le_bytes());
+            elf.extend_from_slice(&offset.to_le_bytes());
+            elf.extend_from_slice(&vaddr.to_le_bytes());
+            elf.extend_from_slice(&vaddr.to_le_bytes());
+            elf.extend_from_slice(&file_size.to_le_bytes());
+            elf.extend_from_slice(&mem_size.to_le_bytes());
+            elf.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+        };
+        program_header(PF_X | 4, code_offset, CODE_ADDRESS, code.len() as u64, code.len() as u64);
+        program_header(PF_W | 4, 0, CODE_ADDRESS + PAGE_SIZE, 0, bss_size);
+        elf.extend_from_slice(code);
+        elf
+    }
+
+    #[test_case]
+    fn test_rejects_garbage() {
+        assert_eq!(ElfFile::parse(b"hello").err(), Some(ElfError::NotElf));
+        let mut elf = build_elf(&[0xf4], 0);
+        elf[18] = 0x28;
+        assert_eq!(ElfFile::parse(&elf).err(), Some(ElfError::WrongMachine));
+        let elf = build_elf(&[0xf4], 0);
+        assert_eq!(ElfFile::parse(&elf[..100]).err(), Some(ElfError::Truncated));
+    }
+
+    #[test_case]
+    fn test_rejects_entry_outside_code() {
+        // in the writable segment, past the code, and in the kernel
+        for entry in [CODE_ADDRESS + PAGE_SIZE, CODE_ADDRESS + 1, 0xffff_8000_0000_0000] {
+            let mut elf = build_elf(&[0xf4], PAGE_SIZE);
+            elf[24..32].copy_from_slice(&entry.to_le_bytes());
+            assert_eq!(ElfFile::parse(&elf).err(), Some(ElfError::BadEntry));
+        }
+    }
+
+    #[test_case]
+    fn test_load_maps_segments() {
+        let elf = build_elf(&[0x90, 0x90, 0xf4], 2 * PAGE_SIZE);
+        let mut space = AddressSpace::new().expect("out of frames");
+        let entry = load_program(&mut space, &elf, &["init", "-v"], &["HOME=/"]).expect("load failed");
+        assert_eq!(entry.instruction_pointer.as_u64(), CODE_ADDRESS);
+
+        let mut code = [0u8; 3];
+        assert!(space.copy_from(VirtAddr::new(CODE_ADDRESS), &mut code));
+        assert_eq!(code, [0x90, 0x90, 0xf4]);
+        let code_flags = space.flags(Page::containing_address(VirtAddr::new(CODE_ADDRESS))).unwrap();
+        assert!(!code_flags.contains(PageTableFlags::WRITABLE));
+        assert!(!code_flags.contains(PageTableFlags::NO_EXECUTE));
+        let bss_flags = space.flags(Page::containing_address(VirtAddr::new(CODE_ADDRESS + PAGE_SIZE))).unwrap();
+        assert!(bss_flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+        let mut words = [0u8; 24];
+        assert!(space.copy_from(entry.stack_pointer, &mut words));
+        assert_eq!(entry.stack_pointer.as_u64() % 16, 0);
+        assert_eq!(u64::from_le_bytes(words[0..8].try_into().unwrap()), 2);
+        let argv1 = u64::from_le_bytes(words[16..24].try_into().unwrap());
+        let mut arg = [0u8; 3];
+        assert!(space.copy_from(VirtAddr::new(argv1), &mut arg));
+        assert_eq!(&arg, b"-v\0");
+    }
+}
@@ -0,0 +1,120 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fs::{self, File, OpenFlags};
+
+/// Descriptors a process may have open at once.
+pub const MAX_FILES: usize = 64;
+
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+pub const STDERR: i32 = 2;
+
+/* open file descriptors of a process.
+    Each slot holds a reference to an open file; dup2 and fork copy the reference,
+    not the file, so the copies share one position. The file is closed when the last
+    descriptor referring to it goes. Removed files are handed back to the caller to
+    drop outside the process lock, closing may have to sleep.
+ */
+#[derive(Default, Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        FileTable { files: Vec::new() }
+    }
+
+    /// Standard input, output and error all on the terminal, empty without one.
+    pub fn with_terminal() -> Self {
+        let mut table = FileTable::new();
+        if let Ok(terminal) = fs::open("/dev/tty0", OpenFlags::READ | OpenFlags::WRITE) {
+            for fd in [STDIN, STDOUT, STDERR] {
+                table.set(fd, terminal.clone());
+            }
+        }
+        table
+    }
+
+    fn slot(fd: i32) -> Option<usize> {
+        usize::try_from(fd).ok().filter(|&slot| slot < MAX_FILES)
+    }
+
+    pub fn get(&self, fd: i32) -> Option<Arc<dyn File>> {
+        self.files.get(Self::slot(fd)?)?.clone()
+    }
+
+    /// Puts `file` in the lowest free descriptor, `None` when all are taken.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Option<i32> {
+        let slot = match self.files.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[slot] = Some(file);
+        Some(slot as i32)
+    }
+
+    /// Makes `fd` refer to `file`, returns what it referred to before.
+    /// `fd` has to be a valid descriptor number.
+    pub fn set(&mut self, fd: i32, file: Arc<dyn File>) -> Option<Arc<dyn File>> {
+        let slot = Self::slot(fd).expect("descriptor out of range");
+        if slot >= self.files.len() {
+            self.files.resize(slot + 1, None);
+        }
+        self.files[slot].replace(file)
+    }
+
+    pub fn remove(&mut self, fd: i32) -> Option<Arc<dyn File>> {
+        let file = self.files.get_mut(Self::slot(fd)?)?.take();
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        file
+    }
+
+    pub fn is_valid(fd: i32) -> bool {
+        Self::slot(fd).is_some()
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::SeekFrom;
+
+    #[test_case]
+    fn test_descriptors_share_open_files() {
+        let mut table = FileTable::with_terminal();
+        assert_eq!(table.open_count(), 3);
+        fs::write_file("/files-test", b"0123456789").unwrap();
+        let file = fs::open("/files-test", OpenFlags::READ).unwrap();
+        let fd = table.insert(file).unwrap();
+        assert_eq!(fd, 3);
+
+        // the copy in a forked table and a dup2'd descriptor share the position
+        let forked = table.clone();
+        assert!(table.set(10, table.get(fd).unwrap()).is_none());
+        let mut buffer = [0u8; 4];
+        table.get(fd).unwrap().read(&mut buffer).unwrap();
+        assert_eq!(forked.get(fd).unwrap().seek(SeekFrom::Current(0)), Ok(4));
+        assert_eq!(table.get(10).unwrap().read(&mut buffer), Ok(4));
+        assert_eq!(&buffer, b"4567");
+
+        assert!(table.remove(STDIN).is_some());
+        assert!(table.remove(STDIN).is_none());
+        assert_eq!(table.insert(table.get(STDOUT).unwrap()), Some(STDIN));
+        assert!(table.get(-1).is_none() && table.get(MAX_FILES as i32).is_none());
+        while table.insert(table.get(STDOUT).unwrap()).is_some() {}
+        assert_eq!(table.open_count(), MAX_FILES);
+        fs::unlink("/files-test").unwrap();
+    }
+}
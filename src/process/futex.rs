@@ -0,0 +1,191 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::signal::interrupted;
+use super::Process;
+use crate::memory::address_space::COPY_ON_WRITE;
+use crate::memory::{phys_to_virt, AddressSpace};
+use crate::sync::SpinLock;
+use crate::thread::{self, ThreadId};
+use crate::time::sleep::{add_timer, cancel_timer};
+use crate::time::{self, TimerAction};
+
+/* futexes.
+    A futex is a 32-bit word in user memory that threads sleep on while it holds an
+    expected value. Waiters are keyed on the physical address of the word, so threads of
+    one process and processes sharing the page through shared memory meet on the same
+    key. The value check and the queueing happen under one lock, and wakers take the
+    same lock, so a wake right after the value changed is never lost.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// Not mapped, or a read-only page that cannot become writable.
+    Fault,
+    Misaligned,
+    /// The word no longer held the expected value.
+    WouldBlock,
+    TimedOut,
+    Interrupted,
+}
+
+struct Waiter {
+    thread: ThreadId,
+    woken: AtomicBool,
+}
+
+static FUTEXES: SpinLock<BTreeMap<PhysAddr, VecDeque<Arc<Waiter>>>> = SpinLock::new(BTreeMap::new());
+
+/* the key of a futex word.
+    A copy-on-write page is split first, the frame behind it is about to change on the
+    next write anyway, and waiter and waker have to agree on the frame.
+ */
+fn key(space: &mut AddressSpace, address: VirtAddr) -> Result<PhysAddr, FutexError> {
+    if !address.is_aligned(4u64) {
+        return Err(FutexError::Misaligned);
+    }
+    let page = Page::containing_address(address);
+    if !AddressSpace::is_user_page(page) {
+        return Err(FutexError::Fault);
+    }
+    match space.flags(page) {
+        Some(flags) if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+            if flags.contains(COPY_ON_WRITE) && !space.prepare_write(page) {
+                return Err(FutexError::Fault);
+            }
+        }
+        _ => return Err(FutexError::Fault),
+    }
+    space.translate(address).ok_or(FutexError::Fault)
+}
+
+fn load(key: PhysAddr) -> u32 {
+    unsafe { (*phys_to_virt(key).as_ptr::<AtomicU32>()).load(Ordering::SeqCst) }
+}
+
+/// Sleeps while the word at `address` holds `expected`, until woken, a signal arrives or
+/// the tick `deadline` passes.
+pub fn wait(process: &Arc<Mutex<Process>>, address: VirtAddr, expected: u32, deadline: Option<u64>) -> Result<(), FutexError> {
+    let waiter = Arc::new(Waiter { thread: thread::current_id(), woken: AtomicBool::new(false) });
+    let key = without_interrupts(|| {
+        let mut process = process.lock();
+        let key = key(process.address_space_mut(), address)?;
+        let mut futexes = FUTEXES.lock();
+        if load(key) != expected {
+            return Err(FutexError::WouldBlock);
+        }
+        futexes.entry(key).or_default().push_back(waiter.clone());
+        Ok(key)
+    })?;
+
+    let timer = deadline.map(|deadline| add_timer(deadline, TimerAction::Unpark(waiter.thread)));
+    let result = loop {
+        if waiter.woken.load(Ordering::Acquire) {
+            break Ok(());
+        }
+        if interrupted() {
+            break Err(FutexError::Interrupted);
+        }
+        if deadline.is_some_and(|deadline| time::ticks() >= deadline) {
+            break Err(FutexError::TimedOut);
+        }
+        thread::park();
+    };
+    if let Some(timer) = timer {
+        cancel_timer(timer);
+    }
+    if result.is_ok() || !dequeue(key, &waiter) {
+        // a wake that raced with giving up still counts
+        return Ok(());
+    }
+    result
+}
+
+/// Takes `waiter` off the queue of `key`, false if a wake already did.
+fn dequeue(key: PhysAddr, waiter: &Arc<Waiter>) -> bool {
+    let mut futexes = FUTEXES.lock();
+    let Some(waiters) = futexes.get_mut(&key) else {
+        return false;
+    };
+    let before = waiters.len();
+    waiters.retain(|queued| !Arc::ptr_eq(queued, waiter));
+    let removed = waiters.len() != before;
+    if waiters.is_empty() {
+        futexes.remove(&key);
+    }
+    removed
+}
+
+/// Wakes up to `count` threads waiting on the word at `address`, returns how many.
+pub fn wake(process: &Arc<Mutex<Process>>, address: VirtAddr, count: usize) -> Result<usize, FutexError> {
+    let woken = without_interrupts(|| {
+        let mut process = process.lock();
+        let key = key(process.address_space_mut(), address)?;
+        let mut futexes = FUTEXES.lock();
+        let Some(waiters) = futexes.get_mut(&key) else {
+            return Ok(VecDeque::new());
+        };
+        let woken: VecDeque<_> = waiters.drain(..count.min(waiters.len())).collect();
+        if waiters.is_empty() {
+            futexes.remove(&key);
+        }
+        for waiter in &woken {
+            waiter.woken.store(true, Ordering::Release);
+        }
+        Ok(woken)
+    })?;
+    // unparked outside of the locks, it may switch to the woken thread
+    for waiter in &woken {
+        thread::unpark(waiter.thread);
+    }
+    Ok(woken.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::USER_START;
+    use crate::memory::PAGE_SIZE;
+    use crate::thread::{spawn, yield_now};
+
+    fn process_with_word(value: u32) -> (Arc<Mutex<Process>>, VirtAddr) {
+        let mut process = Process::create("futex").expect("out of frames");
+        let address = VirtAddr::new(USER_START + PAGE_SIZE - 4);
+        let page = Page::containing_address(address);
+        let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        process.address_space_mut().map_zeroed(page, flags).expect("map failed");
+        assert!(process.address_space_mut().copy_to(address, &value.to_le_bytes()));
+        (Arc::new(Mutex::new(process)), address)
+    }
+
+    #[test_case]
+    fn test_wait_checks_value_and_alignment() {
+        let (process, address) = process_with_word(1);
+        assert_eq!(wait(&process, address, 0, None), Err(FutexError::WouldBlock));
+        assert_eq!(wait(&process, address + 1u64, 1, None), Err(FutexError::Misaligned));
+        assert_eq!(wait(&process, address + 4u64, 1, None), Err(FutexError::Fault));
+        assert_eq!(wait(&process, address, 1, Some(time::ticks() + 1)), Err(FutexError::TimedOut));
+        assert_eq!(wake(&process, address, 1), Ok(0));
+    }
+
+    #[test_case]
+    fn test_wake_releases_waiter() {
+        let (process, address) = process_with_word(0);
+        let waiter = {
+            let process = process.clone();
+            spawn("futex-waiter", move || assert_eq!(wait(&process, address, 0, None), Ok(())))
+        };
+        let mut woken = 0;
+        while woken == 0 {
+            yield_now();
+            woken = wake(&process, address, usize::MAX).expect("wake failed");
+        }
+        assert_eq!(woken, 1);
+        waiter.join();
+    }
+}
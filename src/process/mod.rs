@@ -0,0 +1,208 @@
+pub mod elf;
+pub mod files;
+pub mod futex;
+pub mod shm;
+pub mod signal;
+pub mod table;
+pub mod user;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::memory::AddressSpace;
+pub use files::FileTable;
+use shm::Attachments;
+use signal::Signals;
+use table::Status;
+use crate::thread::{ExitCode, ThreadId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        // 0 is left unused, it reads as "no process" in user space
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The id user space passed in, which may not name any process.
+    pub fn from_u64(id: u64) -> Self {
+        ProcessId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/* a process.
+    Owns an address space, its open files and the threads running in it. Dropping the
+    process tears the address space down, handing every frame back to the allocator.
+    The exit code outlives it in the process table until the parent collects it.
+ */
+pub struct Process {
+    id: ProcessId,
+    name: String,
+    address_space: AddressSpace,
+    files: FileTable,
+    signals: Signals,
+    shm: Attachments,
+    threads: Vec<ThreadId>,
+    // the signal that killed a thread of it, which outranks any exit code
+    killed_by: Option<i32>,
+}
+
+impl Process {
+    /// Creates an empty process, or `None` when there are no frames left for its page tables.
+    pub fn create(name: &str) -> Option<Self> {
+        let process = Process {
+            id: ProcessId::new(),
+            name: String::from(name),
+            address_space: AddressSpace::new()?,
+            files: FileTable::with_terminal(),
+            signals: Signals::new(),
+            shm: Attachments::new(),
+            threads: Vec::new(),
+            killed_by: None,
+        };
+        table::insert(process.id, None);
+        Some(process)
+    }
+
+    /// A copy of this process sharing its memory copy-on-write, without any threads yet.
+    pub fn fork(&mut self) -> Option<Self> {
+        let child = Process {
+            id: ProcessId::new(),
+            name: self.name.clone(),
+            address_space: self.address_space.fork()?,
+            files: self.files.clone(),
+            signals: self.signals.fork(),
+            shm: self.shm.fork(),
+            threads: Vec::new(),
+            killed_by: None,
+        };
+        table::insert(child.id, Some(self.id));
+        Some(child)
+    }
+
+    /// Swaps in the address space of a new program image and returns the old one.
+    /// The caller drops it once the new space is loaded, which frees the old image.
+    pub fn replace_image(&mut self, name: &str, address_space: AddressSpace) -> AddressSpace {
+        self.name = String::from(name);
+        self.signals.reset_handlers();
+        self.shm.clear();
+        core::mem::replace(&mut self.address_space, address_space)
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    pub fn files(&self) -> &FileTable {
+        &self.files
+    }
+
+    pub fn files_mut(&mut self) -> &mut FileTable {
+        &mut self.files
+    }
+
+    pub fn signals(&self) -> &Signals {
+        &self.signals
+    }
+
+    pub fn signals_mut(&mut self) -> &mut Signals {
+        &mut self.signals
+    }
+
+    pub fn threads(&self) -> &[ThreadId] {
+        &self.threads
+    }
+
+    pub fn add_thread(&mut self, id: ThreadId) {
+        self.threads.push(id);
+    }
+
+    pub fn remove_thread(&mut self, id: ThreadId) {
+        self.threads.retain(|&thread| thread != id);
+    }
+
+    pub fn parent(&self) -> Option<ProcessId> {
+        table::parent_of(self.id)
+    }
+
+    /// A thread of it was killed by `signal`, which is how the process ends.
+    pub fn set_killed_by(&mut self, signal: i32) {
+        self.killed_by = Some(signal);
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        shm::creator_exited(self.id);
+        table::remove_running(self.id);
+    }
+}
+
+/// Called by an exiting thread, the exit code of the last one becomes the process' own,
+/// unless a signal killed one of them.
+pub(crate) fn thread_exited(process: &Arc<Mutex<Process>>, thread: ThreadId, code: ExitCode) {
+    let (exited, files) = without_interrupts(|| {
+        let mut process = process.lock();
+        process.remove_thread(thread);
+        if !process.threads.is_empty() {
+            return (None, None);
+        }
+        let status = process.killed_by.map_or(Status::Exited(code), Status::Killed);
+        (Some((process.id, status)), Some(core::mem::take(&mut process.files)))
+    });
+    // closing may sleep, which cannot happen under the process lock
+    drop(files);
+    if let Some((id, status)) = exited {
+        shm::creator_exited(id);
+        crate::tty::process_exited(id, table::parent_of(id));
+        table::exited(id, Some(status));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::USER_START;
+    use crate::memory::frame;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::VirtAddr;
+
+    #[test_case]
+    fn test_create_and_teardown() {
+        let before = frame::allocated_frames();
+        let mut process = Process::create("test").expect("out of frames");
+        let other = Process::create("other").expect("out of frames");
+        assert_ne!(process.id(), other.id());
+
+        let page = Page::containing_address(VirtAddr::new(USER_START));
+        process.address_space_mut().map_zeroed(page, PageTableFlags::USER_ACCESSIBLE).expect("map failed");
+        process.add_thread(crate::thread::current_id());
+        assert_eq!(process.threads().len(), 1);
+
+        drop(process);
+        drop(other);
+        assert_eq!(frame::allocated_frames(), before);
+    }
+}
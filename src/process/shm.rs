@@ -0,0 +1,300 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::{Process, ProcessId};
+use crate::memory::address_space::{SHARED, USER_END, USER_START};
+use crate::memory::{frame, phys_to_virt, AddressSpace, PAGE_SIZE};
+use crate::sync::SpinLock;
+
+/* shared memory segments.
+    A segment is a set of zeroed frames owned by the kernel. Attaching it maps those very
+    frames into the caller's address space, read-only or writable per attachment, so
+    processes exchange data without any copying. The pages are marked shared, fork
+    keeps them shared instead of copy-on-write. A segment stays, attached or not,
+    until it is removed or the process that created it is gone; from then on it
+    cannot be attached any more and is freed with its last attachment.
+ */
+
+/// Largest segment that can be created.
+pub const MAX_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Attachments placed by the kernel go from here up, far above any program image.
+pub const ATTACH_BASE: u64 = 0x0000_4000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShmId(u64);
+
+impl ShmId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ShmId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The id user space passed in, which may not name any segment.
+    pub fn from_u64(id: u64) -> Self {
+        ShmId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    InvalidSize,
+    NoSegment,
+    /// Misaligned, outside the user range or overlapping an existing mapping.
+    BadAddress,
+    NotAttached,
+    OutOfMemory,
+}
+
+struct Segment {
+    // the segment holds a reference to each frame, every attachment one more
+    frames: Vec<PhysFrame>,
+    attachments: usize,
+    creator: ProcessId,
+    removed: bool,
+}
+
+static SEGMENTS: SpinLock<BTreeMap<ShmId, Segment>> = SpinLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy)]
+struct Attachment {
+    id: ShmId,
+    pages: u64,
+}
+
+impl Attachment {
+    fn end(&self, start: u64) -> u64 {
+        start + self.pages * PAGE_SIZE
+    }
+}
+
+/// The segments a process has attached, by start address.
+#[derive(Debug, Default)]
+pub struct Attachments(BTreeMap<u64, Attachment>);
+
+impl Attachments {
+    pub fn new() -> Self {
+        Attachments(BTreeMap::new())
+    }
+
+    /// The same attachments for a forked child, whose address space maps the same frames.
+    pub fn fork(&self) -> Self {
+        let mut segments = SEGMENTS.lock();
+        for attachment in self.0.values() {
+            segments.get_mut(&attachment.id).expect("attached segment is gone").attachments += 1;
+        }
+        Attachments(self.0.clone())
+    }
+
+    /// Forgets every attachment, their pages are released along with the address space.
+    pub fn clear(&mut self) {
+        let mut segments = SEGMENTS.lock();
+        for attachment in core::mem::take(&mut self.0).into_values() {
+            detached(&mut segments, attachment.id);
+        }
+    }
+
+    /// Lowest gap of `pages` above `ATTACH_BASE` between the existing attachments.
+    fn find_gap(&self, pages: u64) -> u64 {
+        let mut candidate = ATTACH_BASE;
+        for (&start, attachment) in self.0.range(ATTACH_BASE..) {
+            if candidate + pages * PAGE_SIZE <= start {
+                break;
+            }
+            candidate = candidate.max(attachment.end(start));
+        }
+        candidate
+    }
+}
+
+impl Drop for Attachments {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Creates a segment of at least `size` bytes, rounded up to whole pages, that
+/// lives no longer than `creator`.
+pub fn create(creator: ProcessId, size: u64) -> Result<ShmId, ShmError> {
+    if size == 0 || size > MAX_SEGMENT_SIZE {
+        return Err(ShmError::InvalidSize);
+    }
+    let pages = size.div_ceil(PAGE_SIZE);
+    let mut frames = Vec::with_capacity(pages as usize);
+    for _ in 0..pages {
+        let Some(frame) = frame::allocate() else {
+            frames.into_iter().for_each(frame::free);
+            return Err(ShmError::OutOfMemory);
+        };
+        unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, PAGE_SIZE as usize) };
+        frames.push(frame);
+    }
+    let id = ShmId::new();
+    SEGMENTS.lock().insert(id, Segment { frames, attachments: 0, creator, removed: false });
+    Ok(id)
+}
+
+/// Marks segment `id` for removal, it goes with its last attachment or right away.
+pub fn remove(id: ShmId) -> Result<(), ShmError> {
+    let mut segments = SEGMENTS.lock();
+    let segment = segments.get_mut(&id).filter(|segment| !segment.removed).ok_or(ShmError::NoSegment)?;
+    segment.removed = true;
+    free_if_unused(&mut segments, id);
+    Ok(())
+}
+
+/// Removes the segments process `id` created.
+pub fn creator_exited(id: ProcessId) {
+    let mut segments = SEGMENTS.lock();
+    let created: Vec<ShmId> = segments.iter().filter(|(_, segment)| segment.creator == id).map(|(&id, _)| id).collect();
+    for shm_id in created {
+        segments.get_mut(&shm_id).unwrap().removed = true;
+        free_if_unused(&mut segments, shm_id);
+    }
+}
+
+/// Size of a segment in bytes.
+pub fn size(id: ShmId) -> Option<u64> {
+    SEGMENTS.lock().get(&id).map(|segment| segment.frames.len() as u64 * PAGE_SIZE)
+}
+
+pub fn exists(id: ShmId) -> bool {
+    SEGMENTS.lock().contains_key(&id)
+}
+
+/// Maps segment `id` into `process` at `address`, or wherever there is room when `None`.
+pub fn attach(process: &mut Process, id: ShmId, address: Option<VirtAddr>, writable: bool) -> Result<VirtAddr, ShmError> {
+    let mut segments = SEGMENTS.lock();
+    let segment = segments.get_mut(&id).filter(|segment| !segment.removed).ok_or(ShmError::NoSegment)?;
+    let pages = segment.frames.len() as u64;
+    let start = match address {
+        Some(address) => address.as_u64(),
+        None => process.shm.find_gap(pages),
+    };
+    let space = &mut process.address_space;
+    if !range_is_free(space, start, pages) {
+        return Err(ShmError::BadAddress);
+    }
+
+    let mut flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE | SHARED;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    for (index, &frame) in segment.frames.iter().enumerate() {
+        frame::share(frame);
+        if space.map(page_at(start, index as u64), frame, flags).is_err() {
+            frame::release(frame);
+            unmap_pages(space, start, index as u64);
+            return Err(ShmError::OutOfMemory);
+        }
+    }
+    segment.attachments += 1;
+    process.shm.0.insert(start, Attachment { id, pages });
+    Ok(VirtAddr::new(start))
+}
+
+/// Unmaps the attachment starting at `address`, a removed segment goes with its last one.
+pub fn detach(process: &mut Process, address: VirtAddr) -> Result<(), ShmError> {
+    let start = address.as_u64();
+    let attachment = process.shm.0.remove(&start).ok_or(ShmError::NotAttached)?;
+    unmap_pages(&mut process.address_space, start, attachment.pages);
+    detached(&mut SEGMENTS.lock(), attachment.id);
+    Ok(())
+}
+
+fn detached(segments: &mut BTreeMap<ShmId, Segment>, id: ShmId) {
+    segments.get_mut(&id).expect("attached segment is gone").attachments -= 1;
+    free_if_unused(segments, id);
+}
+
+fn free_if_unused(segments: &mut BTreeMap<ShmId, Segment>, id: ShmId) {
+    if segments.get(&id).is_some_and(|segment| segment.removed && segment.attachments == 0) {
+        let segment = segments.remove(&id).unwrap();
+        segment.frames.into_iter().for_each(frame::release);
+    }
+}
+
+fn page_at(start: u64, index: u64) -> Page {
+    Page::containing_address(VirtAddr::new(start + index * PAGE_SIZE))
+}
+
+fn range_is_free(space: &mut AddressSpace, start: u64, pages: u64) -> bool {
+    let fits = start & (PAGE_SIZE - 1) == 0 && start >= USER_START
+        && start.checked_add(pages * PAGE_SIZE).is_some_and(|end| end <= USER_END);
+    fits && (0..pages).all(|index| space.flags(page_at(start, index)).is_none())
+}
+
+fn unmap_pages(space: &mut AddressSpace, start: u64, pages: u64) {
+    for index in 0..pages {
+        if let Some(frame) = space.unmap(page_at(start, index)) {
+            frame::release(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_attachments_share_frames() {
+        let before = frame::allocated_frames();
+        let mut writer = Process::create("writer").expect("out of frames");
+        let mut reader = Process::create("reader").expect("out of frames");
+        let id = create(writer.id(), PAGE_SIZE + 1).expect("create failed");
+        assert_eq!(size(id), Some(2 * PAGE_SIZE));
+
+        let at = attach(&mut writer, id, None, true).expect("attach failed");
+        let seen = attach(&mut reader, id, None, false).expect("attach failed");
+        assert_eq!(attach(&mut reader, id, Some(seen), false), Err(ShmError::BadAddress));
+        assert!(writer.address_space_mut().copy_to(at + PAGE_SIZE, b"shared"));
+        let mut buffer = [0u8; 6];
+        assert!(reader.address_space_mut().copy_from(seen + PAGE_SIZE, &mut buffer));
+        assert_eq!(&buffer, b"shared");
+        let flags = reader.address_space_mut().flags(Page::containing_address(seen)).unwrap();
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+        let mut child = writer.fork().expect("out of frames");
+        assert!(child.address_space_mut().copy_to(at, b"child"));
+        assert!(reader.address_space_mut().copy_from(seen, &mut buffer[..5]));
+        assert_eq!(&buffer[..5], b"child");
+
+        assert_eq!(detach(&mut writer, at + 1u64), Err(ShmError::NotAttached));
+        detach(&mut writer, at).expect("detach failed");
+        drop(child);
+        drop(reader);
+        // unattached, the segment stays for its creator
+        assert!(exists(id));
+        drop(writer);
+        assert!(!exists(id));
+        assert_eq!(frame::allocated_frames(), before);
+    }
+
+    #[test_case]
+    fn test_remove() {
+        let before = frame::allocated_frames();
+        let mut process = Process::create("remover").expect("out of frames");
+        let unused = create(process.id(), PAGE_SIZE).expect("create failed");
+        remove(unused).expect("remove failed");
+        assert!(!exists(unused));
+        assert_eq!(remove(unused), Err(ShmError::NoSegment));
+
+        let id = create(process.id(), PAGE_SIZE).expect("create failed");
+        let at = attach(&mut process, id, None, true).expect("attach failed");
+        remove(id).expect("remove failed");
+        // still mapped where it is attached, but not to be attached again
+        assert!(exists(id));
+        assert_eq!(attach(&mut process, id, None, true), Err(ShmError::NoSegment));
+        assert!(process.address_space_mut().copy_to(at, b"still here"));
+        detach(&mut process, at).expect("detach failed");
+        assert!(!exists(id));
+        drop(process);
+        assert_eq!(frame::allocated_frames(), before);
+    }
+}
@@ -0,0 +1,434 @@
+use core::mem::size_of;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{table, ProcessId};
+use crate::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::address_space::USER_END;
+use crate::syscall::{Errno, SyscallFrame, UserSlice};
+use crate::thread::{self, WaitQueue};
+
+pub const SIGHUP: i32 = 1;
+pub const SIGINT: i32 = 2;
+pub const SIGQUIT: i32 = 3;
+pub const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+pub const SIGABRT: i32 = 6;
+pub const SIGBUS: i32 = 7;
+pub const SIGFPE: i32 = 8;
+pub const SIGKILL: i32 = 9;
+pub const SIGUSR1: i32 = 10;
+pub const SIGSEGV: i32 = 11;
+pub const SIGUSR2: i32 = 12;
+pub const SIGPIPE: i32 = 13;
+pub const SIGALRM: i32 = 14;
+pub const SIGTERM: i32 = 15;
+pub const SIGCHLD: i32 = 17;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+pub const SIGTSTP: i32 = 20;
+pub const SIGTTIN: i32 = 21;
+pub const SIGTTOU: i32 = 22;
+pub const SIGURG: i32 = 23;
+pub const SIGWINCH: i32 = 28;
+/// One more than the highest signal number, there are no real-time signals.
+pub const NSIG: i32 = 32;
+
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+pub fn is_valid(signal: i32) -> bool {
+    (1..NSIG).contains(&signal)
+}
+
+/// SIGKILL and SIGSTOP can be neither caught, ignored nor blocked.
+pub fn is_catchable(signal: i32) -> bool {
+    is_valid(signal) && signal != SIGKILL && signal != SIGSTOP
+}
+
+/* default actions.
+    There is no job control, so the stop and continue signals are ignored along with
+    the ones that are ignored anyway. Everything else terminates.
+ */
+fn terminates_by_default(signal: i32) -> bool {
+    !matches!(signal, SIGCHLD | SIGURG | SIGWINCH | SIGCONT | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU)
+}
+
+/// A set of signals, signal n is bit n - 1 as in the Linux `sigset_t`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SignalSet(u64);
+
+impl SignalSet {
+    pub const fn empty() -> Self {
+        SignalSet(0)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        SignalSet(bits & ((1 << (NSIG - 1)) - 1))
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn of(signal: i32) -> Self {
+        SignalSet(1 << (signal - 1))
+    }
+
+    pub fn contains(self, signal: i32) -> bool {
+        self.0 & Self::of(signal).0 != 0
+    }
+
+    pub fn insert(&mut self, signal: i32) {
+        self.0 |= Self::of(signal).0;
+    }
+
+    pub fn remove(&mut self, signal: i32) {
+        self.0 &= !Self::of(signal).0;
+    }
+
+    pub fn union(self, other: SignalSet) -> Self {
+        SignalSet(self.0 | other.0)
+    }
+
+    pub fn difference(self, other: SignalSet) -> Self {
+        SignalSet(self.0 & !other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = i32> {
+        (1..NSIG).filter(move |&signal| self.contains(signal))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// A user function, returning through `restorer` which has to call `rt_sigreturn`.
+    Handler { handler: u64, restorer: u64, mask: SignalSet, flags: u64 },
+}
+
+/// What has to happen for a signal taken off the pending set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Terminate(i32),
+    Handle(i32, Action),
+}
+
+/* per-process signal state.
+    Signals are sent to a process as a whole and stay pending until one of its threads
+    returns to user mode with them unblocked.
+ */
+#[derive(Debug, Clone)]
+pub struct Signals {
+    actions: [Action; NSIG as usize],
+    mask: SignalSet,
+    pending: SignalSet,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Signals { actions: [Action::Default; NSIG as usize], mask: SignalSet::empty(), pending: SignalSet::empty() }
+    }
+
+    /// A forked child keeps the actions and the mask but starts with nothing pending.
+    pub fn fork(&self) -> Self {
+        Signals { pending: SignalSet::empty(), ..self.clone() }
+    }
+
+    /// The caught signals go back to their defaults, the old handlers are gone with the image.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let Action::Handler { .. } = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    pub fn action(&self, signal: i32) -> Action {
+        self.actions[signal as usize]
+    }
+
+    pub fn set_action(&mut self, signal: i32, action: Action) {
+        assert!(is_catchable(signal));
+        self.actions[signal as usize] = action;
+        if action == Action::Ignore || (action == Action::Default && !terminates_by_default(signal)) {
+            self.pending.remove(signal);
+        }
+    }
+
+    pub fn mask(&self) -> SignalSet {
+        self.mask
+    }
+
+    pub fn set_mask(&mut self, mask: SignalSet) {
+        let mut mask = mask;
+        mask.remove(SIGKILL);
+        mask.remove(SIGSTOP);
+        self.mask = mask;
+    }
+
+    pub fn pending(&self) -> SignalSet {
+        self.pending
+    }
+
+    pub fn raise(&mut self, signal: i32) {
+        self.pending.insert(signal);
+    }
+
+    fn is_ignored(&self, signal: i32) -> bool {
+        match self.action(signal) {
+            Action::Ignore => true,
+            Action::Default => !terminates_by_default(signal),
+            Action::Handler { .. } => false,
+        }
+    }
+
+    /// Something pending and unblocked that is not simply ignored.
+    pub fn has_deliverable(&self) -> bool {
+        self.pending.difference(self.mask).iter().any(|signal| !self.is_ignored(signal))
+    }
+
+    /// Takes the lowest pending, unblocked signal that needs doing something, dropping ignored ones on the way.
+    pub fn take(&mut self) -> Option<Disposition> {
+        for signal in self.pending.difference(self.mask).iter() {
+            self.pending.remove(signal);
+            if self.is_ignored(signal) {
+                continue;
+            }
+            return Some(match self.action(signal) {
+                Action::Default | Action::Ignore => Disposition::Terminate(signal),
+                action => Disposition::Handle(signal, action),
+            });
+        }
+        None
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A signal arrived for the sleeping process, the wait was cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+/// Makes `signal` pending for the process `id`. Returns false when there is no such process.
+pub fn send(id: ProcessId, signal: i32) -> bool {
+    let Some(process) = table::find(id) else {
+        // zombies take signals without effect
+        return table::exists(id);
+    };
+    let threads = without_interrupts(|| {
+        let mut process = process.lock();
+        process.signals_mut().raise(signal);
+        process.threads().to_vec()
+    });
+    // sleepers in interruptible waits recheck and notice the signal
+    for thread in threads {
+        thread::unpark(thread);
+    }
+    true
+}
+
+/// Whether the current thread should stop waiting to take care of a signal.
+pub fn interrupted() -> bool {
+    let Some(process) = thread::current_process() else {
+        return false;
+    };
+    without_interrupts(|| process.lock().signals().has_deliverable())
+}
+
+/// Sleeps on `queue` until `condition` holds or a signal arrives for the current process.
+pub fn wait_interruptible<F: FnMut() -> bool>(queue: &WaitQueue, mut condition: F) -> Result<(), Interrupted> {
+    let mut done = false;
+    queue.wait_until(|| {
+        done = condition();
+        done || interrupted()
+    });
+    if done { Ok(()) } else { Err(Interrupted) }
+}
+
+/// Kills the current thread with `signal`, the way the default action does it; the
+/// process' parent learns of the signal, not of an exit code.
+pub fn terminate(signal: i32) -> ! {
+    if let Some(process) = thread::current_process() {
+        without_interrupts(|| process.lock().set_killed_by(signal));
+    }
+    thread::exit(super::user::killed_by(signal));
+}
+
+// below the interrupted stack pointer, for leaf functions using the red zone
+const RED_ZONE: u64 = 128;
+
+/// What a handler's restorer hands back to `rt_sigreturn`, saved on the user stack.
+#[derive(Debug, Clone)]
+#[repr(C)]
+struct SignalContext {
+    registers: SyscallFrame,
+    mask: u64,
+}
+
+impl SignalContext {
+    fn to_bytes(&self) -> [u8; size_of::<SignalContext>()] {
+        unsafe { core::mem::transmute_copy(self) }
+    }
+
+    fn from_bytes(bytes: &[u8; size_of::<SignalContext>()]) -> Self {
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const SignalContext) }
+    }
+}
+
+/* delivery on the way back to user mode after a syscall or an interrupt.
+    For a handler the user registers are saved on the user stack and `frame` is
+    rewritten to enter the handler with the restorer as its return address, a
+    syscall's result is part of what gets restored by `rt_sigreturn`. An interrupt's
+    entry saves every register in a syscall frame too, so a program stuck in a loop
+    gets its signals, caught or not, at the next timer tick.
+ */
+pub fn deliver(frame: &mut SyscallFrame) {
+    let Some(process) = thread::current_process() else {
+        return;
+    };
+    let next = without_interrupts(|| process.lock().signals_mut().take());
+    let Some(disposition) = next else {
+        return;
+    };
+    match disposition {
+        Disposition::Terminate(signal) => {
+            drop(process);
+            terminate(signal);
+        }
+        Disposition::Handle(signal, Action::Handler { handler, restorer, mask, flags }) => {
+            let old_mask = without_interrupts(|| {
+                let mut process = process.lock();
+                let signals = process.signals_mut();
+                let old_mask = signals.mask();
+                let mut blocked = old_mask.union(mask);
+                if flags & SA_NODEFER == 0 {
+                    blocked.insert(signal);
+                }
+                signals.set_mask(blocked);
+                if flags & SA_RESETHAND != 0 {
+                    signals.set_action(signal, Action::Default);
+                }
+                old_mask
+            });
+            drop(process);
+            if push_signal_frame(frame, signal, handler, restorer, old_mask).is_err() {
+                // no room on the user stack for the handler
+                terminate(SIGSEGV);
+            }
+        }
+        Disposition::Handle(..) => unreachable!("handle without a handler"),
+    }
+}
+
+/// `deliver` for the entry of interrupts that came in from ring 3.
+pub extern "C" fn deliver_after_interrupt(frame: &mut SyscallFrame) {
+    deliver(frame);
+}
+
+fn push_signal_frame(frame: &mut SyscallFrame, signal: i32, handler: u64, restorer: u64, old_mask: SignalSet)
+    -> Result<(), Errno> {
+    let context = SignalContext { registers: frame.clone(), mask: old_mask.bits() };
+    let size = size_of::<SignalContext>() as u64;
+    let context_address = frame.rsp.checked_sub(RED_ZONE + size).ok_or(Errno::EFAULT)? & !0xf;
+    UserSlice::new(context_address, size as usize)?.write(&context.to_bytes())?;
+    // the handler starts as if called, with the return address on an otherwise aligned stack
+    let return_address = context_address - 8;
+    UserSlice::new(return_address, 8)?.write(&restorer.to_le_bytes())?;
+
+    frame.rsp = return_address;
+    frame.rip = handler;
+    frame.rdi = signal as u64;
+    frame.rsi = 0;
+    frame.rdx = 0;
+    Ok(())
+}
+
+// flags user code may change through `rt_sigreturn`: CF, PF, AF, ZF, SF, TF, DF, OF, AC
+const USER_RFLAGS_MASK: u64 = 0x4_0dd5;
+const RFLAGS_IF: u64 = 1 << 9;
+
+/* `rt_sigreturn`, jumped to by a handler's restorer.
+    The saved registers come from user memory, so the selectors are forced back to
+    ring 3, only harmless flags are taken over and the return address must be a user
+    one, anything else would fault in the kernel on `iretq`.
+ */
+pub fn restore(frame: &mut SyscallFrame) -> Option<u64> {
+    let mut bytes = [0u8; size_of::<SignalContext>()];
+    UserSlice::new(frame.rsp, bytes.len()).ok()?.read_into(&mut bytes).ok()?;
+    let context = SignalContext::from_bytes(&bytes);
+    let registers = context.registers;
+    if registers.rip >= USER_END || registers.rsp >= USER_END {
+        return None;
+    }
+
+    *frame = SyscallFrame {
+        cs: USER_CODE_SELECTOR as u64,
+        ss: USER_DATA_SELECTOR as u64,
+        rflags: (registers.rflags & USER_RFLAGS_MASK) | RFLAGS_IF | 0x2,
+        ..registers
+    };
+    if let Some(process) = thread::current_process() {
+        without_interrupts(|| process.lock().signals_mut().set_mask(SignalSet::from_bits(context.mask)));
+    }
+    Some(frame.rax)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_signal_set() {
+        let mut set = SignalSet::empty();
+        set.insert(SIGINT);
+        set.insert(SIGTERM);
+        assert_eq!(set.bits(), (1 << 1) | (1 << 14));
+        assert!(set.contains(SIGINT) && !set.contains(SIGKILL));
+        assert_eq!(set.iter().collect::<alloc::vec::Vec<_>>(), [SIGINT, SIGTERM]);
+        set.remove(SIGINT);
+        assert_eq!(set, SignalSet::of(SIGTERM));
+    }
+
+    #[test_case]
+    fn test_take_skips_blocked_and_ignored() {
+        let mut signals = Signals::new();
+        signals.set_action(SIGUSR1, Action::Ignore);
+        signals.set_mask(SignalSet::of(SIGTERM).union(SignalSet::of(SIGKILL)));
+        signals.raise(SIGCHLD);
+        signals.raise(SIGUSR1);
+        signals.raise(SIGTERM);
+        assert!(!signals.has_deliverable());
+        assert_eq!(signals.take(), None);
+        assert_eq!(signals.pending(), SignalSet::of(SIGTERM));
+
+        // SIGKILL cannot be blocked
+        signals.raise(SIGKILL);
+        assert_eq!(signals.take(), Some(Disposition::Terminate(SIGKILL)));
+    }
+
+    #[test_case]
+    fn test_fork_and_exec() {
+        let handler = Action::Handler { handler: 0x1000, restorer: 0x2000, mask: SignalSet::empty(), flags: SA_RESTORER };
+        let mut signals = Signals::new();
+        signals.set_action(SIGINT, handler);
+        signals.set_action(SIGQUIT, Action::Ignore);
+        signals.raise(SIGINT);
+
+        let mut child = signals.fork();
+        assert!(child.pending().is_empty());
+        assert_eq!(child.action(SIGINT), handler);
+        child.reset_handlers();
+        assert_eq!(child.action(SIGINT), Action::Default);
+        assert_eq!(child.action(SIGQUIT), Action::Ignore);
+    }
+}
@@ -0,0 +1,211 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::signal::{self, Interrupted};
+use super::{Process, ProcessId};
+use crate::sync::SpinRwLock;
+use crate::thread::{ExitCode, WaitQueue};
+
+/// How a process ended, as its parent learns it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Exited(ExitCode),
+    /// By the default action of a signal, which is not an exit code.
+    Killed(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Zombie(Status),
+}
+
+#[derive(Debug)]
+struct Entry {
+    parent: Option<ProcessId>,
+    state: State,
+    // set once the process runs, for finding it by id
+    process: Weak<Mutex<Process>>,
+}
+
+/* parent/child bookkeeping.
+    The `Process` itself goes away with its last thread, what is left of an exited
+    child is this small entry holding how it ended until the parent waits for it.
+    Orphans have no parent to wait for them and are forgotten as soon as they exit.
+    Lookups by id far outnumber process creation and exit, hence the reader-writer lock.
+ */
+static TABLE: SpinRwLock<BTreeMap<ProcessId, Entry>> = SpinRwLock::new(BTreeMap::new());
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+/// Which children a wait is interested in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitTarget {
+    Any,
+    Child(ProcessId),
+}
+
+impl WaitTarget {
+    fn matches(self, id: ProcessId) -> bool {
+        match self {
+            WaitTarget::Any => true,
+            WaitTarget::Child(child) => child == id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    Exited(ProcessId, Status),
+    /// Only returned by non-blocking waits.
+    StillRunning,
+    NoChild,
+}
+
+pub(super) fn insert(id: ProcessId, parent: Option<ProcessId>) {
+    TABLE.write().insert(id, Entry { parent, state: State::Running, process: Weak::new() });
+}
+
+pub(crate) fn attach(id: ProcessId, process: &Arc<Mutex<Process>>) {
+    if let Some(entry) = TABLE.write().get_mut(&id) {
+        entry.process = Arc::downgrade(process);
+    }
+}
+
+/// The running process with this id, `None` for zombies and unknown ids.
+pub fn find(id: ProcessId) -> Option<Arc<Mutex<Process>>> {
+    TABLE.read().get(&id).and_then(|entry| entry.process.upgrade())
+}
+
+pub fn exists(id: ProcessId) -> bool {
+    TABLE.read().contains_key(&id)
+}
+
+/// Every process in the table, zombies included, in id order.
+pub fn ids() -> Vec<ProcessId> {
+    TABLE.read().keys().copied().collect()
+}
+
+/// How a process ended that was not waited for yet.
+pub fn zombie_status(id: ProcessId) -> Option<Status> {
+    match TABLE.read().get(&id)?.state {
+        State::Zombie(status) => Some(status),
+        State::Running => None,
+    }
+}
+
+/* a process is done.
+    With a status it stays a zombie for its parent to collect, without one (the
+    process was dropped without ever exiting) or without a live parent it is removed.
+    Either way its children become orphans and those already dead are reaped.
+ */
+pub(super) fn exited(id: ProcessId, status: Option<Status>) {
+    {
+        let mut table = TABLE.write();
+        table.retain(|_, entry| !(entry.parent == Some(id) && matches!(entry.state, State::Zombie(_))));
+        for entry in table.values_mut().filter(|entry| entry.parent == Some(id)) {
+            entry.parent = None;
+        }
+
+        let parent_alive = table.get(&id).and_then(|entry| entry.parent)
+            .and_then(|parent| table.get(&parent))
+            .is_some_and(|parent| parent.state == State::Running);
+        match status {
+            Some(status) if parent_alive => {
+                if let Some(entry) = table.get_mut(&id) {
+                    entry.state = State::Zombie(status);
+                }
+            }
+            _ => {
+                table.remove(&id);
+            }
+        }
+    }
+    CHILD_EXITED.wake_all();
+}
+
+/// Forgets a process dropped while still running, zombies stay until collected.
+pub(super) fn remove_running(id: ProcessId) {
+    let running = TABLE.read().get(&id).is_some_and(|entry| entry.state == State::Running);
+    if running {
+        exited(id, None);
+    }
+}
+
+pub fn parent_of(id: ProcessId) -> Option<ProcessId> {
+    TABLE.read().get(&id).and_then(|entry| entry.parent)
+}
+
+fn try_wait(parent: ProcessId, target: WaitTarget) -> WaitResult {
+    loop {
+        let mut found = false;
+        let mut zombie = None;
+        for (&id, entry) in TABLE.read().iter() {
+            if entry.parent != Some(parent) || !target.matches(id) {
+                continue;
+            }
+            found = true;
+            if let State::Zombie(status) = entry.state {
+                zombie = Some((id, status));
+                break;
+            }
+        }
+        match zombie {
+            // another waiter may have collected it in the meantime
+            Some((id, status)) => if TABLE.write().remove(&id).is_some() {
+                return WaitResult::Exited(id, status);
+            },
+            None if found => return WaitResult::StillRunning,
+            None => return WaitResult::NoChild,
+        }
+    }
+}
+
+/// Collects an exited child of `parent`, sleeping until one exits unless `block` is false.
+pub fn wait(parent: ProcessId, target: WaitTarget, block: bool) -> Result<WaitResult, Interrupted> {
+    let mut result = try_wait(parent, target);
+    if block && result == WaitResult::StillRunning {
+        signal::wait_interruptible(&CHILD_EXITED, || {
+            result = try_wait(parent, target);
+            result != WaitResult::StillRunning
+        })?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_zombie_collected_once() {
+        let parent = ProcessId::new();
+        let child = ProcessId::new();
+        insert(parent, None);
+        insert(child, Some(parent));
+        assert_eq!(wait(parent, WaitTarget::Child(child), false), Ok(WaitResult::StillRunning));
+
+        exited(child, Some(Status::Exited(ExitCode(3))));
+        assert_eq!(wait(parent, WaitTarget::Any, true), Ok(WaitResult::Exited(child, Status::Exited(ExitCode(3)))));
+        assert_eq!(wait(parent, WaitTarget::Any, true), Ok(WaitResult::NoChild));
+        remove_running(parent);
+    }
+
+    #[test_case]
+    fn test_orphans_are_reaped() {
+        let parent = ProcessId::new();
+        let dead = ProcessId::new();
+        let orphan = ProcessId::new();
+        insert(parent, None);
+        insert(dead, Some(parent));
+        insert(orphan, Some(parent));
+        exited(dead, Some(Status::Killed(signal::SIGKILL)));
+
+        exited(parent, Some(Status::Exited(ExitCode::SUCCESS)));
+        assert_eq!(parent_of(orphan), None);
+        assert!(!exists(dead));
+        exited(orphan, Some(Status::Exited(ExitCode::SUCCESS)));
+        assert!(!exists(orphan));
+    }
+}
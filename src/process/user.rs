@@ -0,0 +1,143 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::arch::asm;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+use super::elf::{self, UserEntry};
+use super::{Process, ProcessId};
+use crate::memory::AddressSpace;
+use crate::syscall::{Errno, SyscallFrame};
+use crate::thread::{self, ExitCode, Thread};
+use crate::{fs, gdt, tty};
+
+// interrupts enabled plus the always-set reserved bit 1
+const USER_RFLAGS: u64 = 0x202;
+
+pub use super::signal::{SIGFPE, SIGILL, SIGSEGV};
+
+/// Exit code of a thread killed by `signal`, following the shell convention.
+pub fn killed_by(signal: i32) -> ExitCode {
+    ExitCode(128 + signal)
+}
+
+/* dropping to ring 3.
+    iretq pops rip, cs, rflags, rsp and ss, so a frame pointing at the user program is
+    built on the kernel stack. All general purpose registers are cleared first so no
+    kernel values leak into user space, and the GS base is handed back to the program.
+ */
+pub fn enter(entry: UserEntry) -> ! {
+    let selectors = gdt::selectors();
+    let code = selectors.user_code_selector.0 as u64;
+    let data = selectors.user_data_selector.0 as u64;
+    unsafe {
+        asm!(
+            "push {data}",
+            "push {stack}",
+            "push {rflags}",
+            "push {code}",
+            "push {rip}",
+            "cli",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "xor rax, rax",
+            "xor rbx, rbx",
+            "xor rcx, rcx",
+            "xor rdx, rdx",
+            "xor rsi, rsi",
+            "xor rdi, rdi",
+            "xor rbp, rbp",
+            "xor r8, r8",
+            "xor r9, r9",
+            "xor r10, r10",
+            "xor r11, r11",
+            "xor r12, r12",
+            "xor r13, r13",
+            "xor r14, r14",
+            "xor r15, r15",
+            "swapgs",
+            "iretq",
+            data = in(reg) data,
+            stack = in(reg) entry.stack_pointer.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            code = in(reg) code,
+            rip = in(reg) entry.instruction_pointer.as_u64(),
+            options(noreturn)
+        );
+    }
+}
+
+/// Resumes user mode with every register taken from `frame`.
+pub fn return_to(frame: &SyscallFrame) -> ! {
+    unsafe {
+        asm!(
+            "cli",
+            // the frame has the layout the syscall entry stub pops from
+            "mov rsp, {frame}",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "swapgs",
+            "iretq",
+            frame = in(reg) frame as *const SyscallFrame,
+            options(noreturn)
+        );
+    }
+}
+
+/// Starts a thread in `process` that enters ring 3 at `entry`.
+pub fn spawn(process: Arc<Mutex<Process>>, entry: UserEntry) -> Thread {
+    let name = without_interrupts(|| String::from(process.lock().name()));
+    thread::spawn_in(process, &name, move || enter(entry))
+}
+
+/// Starts the program at `path` in a new process that has the terminal,
+/// as the kernel does for the one named by `init=` on the command line.
+pub fn start(path: &str, argv: &[&str], envp: &[&str]) -> Result<ProcessId, Errno> {
+    let image = fs::read_file(path)?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut process = Process::create(name).ok_or(Errno::ENOMEM)?;
+    let entry = elf::load_program(process.address_space_mut(), &image, argv, envp)?;
+    let id = process.id();
+    spawn(Arc::new(Mutex::new(process)), entry).detach();
+    tty::set_foreground(Some(id));
+    Ok(id)
+}
+
+/// Starts a thread in `process` that continues in ring 3 with the registers in `frame`.
+pub fn spawn_with_frame(process: Arc<Mutex<Process>>, frame: SyscallFrame) -> Thread {
+    let name = without_interrupts(|| String::from(process.lock().name()));
+    thread::spawn_in(process, &name, move || return_to(&frame))
+}
+
+/// A write hit a read-only page, which is fine if it is copy-on-write. Returns whether
+/// the faulting instruction can be retried.
+pub(crate) fn resolve_write_fault(address: VirtAddr) -> bool {
+    let Some(process) = thread::current_process() else {
+        return false;
+    };
+    let page = Page::containing_address(address);
+    let mut process = process.lock();
+    AddressSpace::is_user_page(page) && process.address_space_mut().prepare_write(page)
+}
+
+/// Terminates the current thread after a fault it caused in user mode.
+pub(crate) fn kill_on_fault(description: &str, instruction_pointer: u64, signal: i32) -> ! {
+    log::warn!("user fault: {} at {:#x}, killing thread {}",
+        description, instruction_pointer, thread::current_id().as_u64());
+    super::signal::terminate(signal);
+}
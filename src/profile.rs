@@ -0,0 +1,251 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::interrupts::ExceptionStackFrame;
+use crate::sync::SpinLock;
+use crate::{cmdline, symbols, workqueue};
+
+/* the sampling profiler.
+    With `profile`, or `profile=<n>` for every n-th tick, on the command line the
+    timer interrupt counts the instruction it interrupted in a fixed table; samples
+    in user mode are only counted. Every `profile.report=<seconds>`, 10 by default and
+    0 for never, the hottest functions since boot go to the log, and /proc/profile
+    lists them all. Addresses are put together into functions by the symbol table
+    only when reported, the tick stays cheap.
+ */
+
+const SLOTS: usize = 4096;
+const DEFAULT_REPORT_SECS: u64 = 10;
+// functions the periodic report names
+const HOTTEST: usize = 10;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    // 0 for a free slot
+    address: u64,
+    count: u64,
+}
+
+// open addressing by instruction address, with linear probing
+struct Histogram<const N: usize> {
+    samples: [Sample; N],
+    total: u64,
+    user: u64,
+    // kernel samples of addresses that found the table full
+    dropped: u64,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INTERVAL: AtomicU64 = AtomicU64::new(1);
+// ticks until the next sample
+static COUNTDOWN: AtomicU64 = AtomicU64::new(1);
+static HISTOGRAM: SpinLock<Histogram<SLOTS>> = SpinLock::new(Histogram::new());
+
+impl<const N: usize> Histogram<N> {
+    const fn new() -> Self {
+        Histogram { samples: [Sample { address: 0, count: 0 }; N], total: 0, user: 0, dropped: 0 }
+    }
+
+    fn add(&mut self, address: u64) {
+        self.total += 1;
+        let mut slot = (address.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 40) as usize % N;
+        for _ in 0..N {
+            let sample = &mut self.samples[slot];
+            if sample.address == address || sample.address == 0 {
+                sample.address = address;
+                sample.count += 1;
+                return;
+            }
+            slot = (slot + 1) % N;
+        }
+        self.dropped += 1;
+    }
+
+    fn add_user(&mut self) {
+        self.total += 1;
+        self.user += 1;
+    }
+
+    fn clear(&mut self) {
+        self.samples.fill(Sample { address: 0, count: 0 });
+        (self.total, self.user, self.dropped) = (0, 0, 0);
+    }
+}
+
+/// A function, or an address outside the symbol table, and the samples that hit it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotspot {
+    pub name: Option<&'static str>,
+    pub address: u64,
+    pub samples: u64,
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Samples every `interval` ticks from now on.
+pub fn enable(interval: u64) {
+    INTERVAL.store(interval.max(1), Ordering::Relaxed);
+    COUNTDOWN.store(1, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Forgets the samples taken so far.
+pub fn reset() {
+    HISTOGRAM.lock().clear();
+}
+
+/// Starts sampling when the command line asks for it, and the periodic report.
+pub fn init() {
+    let Some(interval) = cmdline::get("profile") else {
+        return;
+    };
+    enable(if interval.is_empty() { 1 } else { cmdline::parse("profile").unwrap_or(1) });
+    let every = cmdline::parse("profile.report").unwrap_or(DEFAULT_REPORT_SECS);
+    if every > 0 {
+        schedule_report(Duration::from_secs(every));
+    }
+}
+
+fn schedule_report(every: Duration) {
+    workqueue::spawn_after(every, move || {
+        if !is_enabled() {
+            return;
+        }
+        let (total, user, hottest) = snapshot();
+        log::info!("profile: {} samples, {} in user mode, hottest:", total, user);
+        for hotspot in hottest.iter().take(HOTTEST) {
+            log::info!("profile: {:>6} {}", hotspot.samples, Name(hotspot));
+        }
+        schedule_report(every);
+    });
+}
+
+/// Called from the timer interrupt with the frame of what it interrupted.
+pub fn sample(stack_frame: &ExceptionStackFrame) {
+    if !is_enabled() || COUNTDOWN.fetch_sub(1, Ordering::Relaxed) > 1 {
+        return;
+    }
+    COUNTDOWN.store(INTERVAL.load(Ordering::Relaxed), Ordering::Relaxed);
+    // a report on another CPU holds it only briefly, a tick skipped meanwhile does no harm
+    let Some(mut histogram) = HISTOGRAM.try_lock() else {
+        return;
+    };
+    if stack_frame.is_user() {
+        histogram.add_user();
+    } else {
+        histogram.add(stack_frame.instruction_pointer());
+    }
+}
+
+/// Samples so far, those in user mode, and the kernel ones by function, hottest first.
+fn snapshot() -> (u64, u64, Vec<Hotspot>) {
+    // copied out first, resolving symbols takes too long to hold the lock
+    let mut samples = Vec::with_capacity(SLOTS);
+    let (total, user, dropped) = {
+        let histogram = HISTOGRAM.lock();
+        samples.extend(histogram.samples.iter().filter(|sample| sample.address != 0).copied());
+        (histogram.total, histogram.user, histogram.dropped)
+    };
+
+    let mut hottest: Vec<Hotspot> = Vec::new();
+    for sample in samples {
+        // the interrupted instruction itself, not a return address
+        let name = symbols::resolve(sample.address).map(|(name, _)| name);
+        let same = hottest.iter_mut().find(|hotspot| match name {
+            Some(name) => hotspot.name == Some(name),
+            None => hotspot.address == sample.address,
+        });
+        match same {
+            Some(hotspot) => hotspot.samples += sample.count,
+            None => hottest.push(Hotspot { name, address: sample.address, samples: sample.count }),
+        }
+    }
+    if dropped > 0 {
+        hottest.push(Hotspot { name: Some("(table full)"), address: 0, samples: dropped });
+    }
+    hottest.sort_unstable_by_key(|hotspot| core::cmp::Reverse(hotspot.samples));
+    (total, user, hottest)
+}
+
+struct Name<'a>(&'a Hotspot);
+
+impl core::fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0.name {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#018x}", self.0.address),
+        }
+    }
+}
+
+/// The kernel functions sampled, hottest first.
+pub fn hottest() -> Vec<Hotspot> {
+    snapshot().2
+}
+
+/// Every function sampled with its share of the samples, for /proc/profile.
+pub fn report() -> String {
+    let (total, user, hottest) = snapshot();
+    let mut text = String::new();
+    let _ = writeln!(text, "{} samples, {} in user mode", total, user);
+    for hotspot in &hottest {
+        // tenths of a percent, without floating point
+        let permille = hotspot.samples * 1000 / total.max(1);
+        let _ = writeln!(text, "{:>8} {:>3}.{}% {}", hotspot.samples, permille / 10, permille % 10, Name(hotspot));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn test_histogram() {
+        let mut histogram = Histogram::<4>::new();
+        for address in [0x1000, 0x2000, 0x1000, 0x3000, 0x4000, 0x5000] {
+            histogram.add(address);
+        }
+        histogram.add_user();
+        assert_eq!((histogram.total, histogram.user, histogram.dropped), (7, 1, 1));
+        let count = |address| histogram.samples.iter().find(|sample| sample.address == address).map(|sample| sample.count);
+        assert_eq!(count(0x1000), Some(2));
+        assert_eq!(count(0x5000), None);
+    }
+
+    #[inline(never)]
+    fn spin_for_ticks(ticks: u64) {
+        let start = time::ticks();
+        while time::ticks() < start + ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test_case]
+    fn test_sampling() {
+        let was_enabled = is_enabled();
+        enable(1);
+        spin_for_ticks(5);
+        if !was_enabled {
+            disable();
+        }
+        let hottest = hottest();
+        assert!(!hottest.is_empty());
+        // the test runner embeds the symbol table, a plain cargo build does not
+        if symbols::is_loaded() {
+            let spinning = |name: &str| name.contains("spin_for_ticks") || name.contains("time::ticks");
+            assert!(hottest.iter().any(|hotspot| hotspot.name.is_some_and(spinning)), "{:?}", hottest);
+        }
+        assert!(report().lines().next().unwrap().ends_with("in user mode"));
+    }
+}
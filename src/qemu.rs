@@ -0,0 +1,111 @@
+use x86_64::instructions::port::Port;
+
+/* leaving QEMU with a status.
+    The isa-debug-exit device ends QEMU when its port is written, with the value
+    shifted left and or'ed with 1 as the exit status. Where the device sits has to
+    match the -device line in Cargo.toml; a kernel run with another one is built
+    with BLOG_OS_EXIT_PORT and BLOG_OS_EXIT_SIZE, in hex or decimal, as in
+        BLOG_OS_EXIT_PORT=0x501 BLOG_OS_EXIT_SIZE=2 cargo test
+    Each way a run can fail has a code of its own, which runner.sh names.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    /// QEMU exits with 33, what bootimage takes for a passed test run.
+    Success = 0x10,
+    /// 35, one or more tests failed or faulted.
+    Failed = 0x11,
+    /// 37, a watchdog, and with it a test, ran out of time.
+    Timeout = 0x12,
+    /// 39, a panic other than a failed assertion.
+    Panic = 0x13,
+    /// 41, an `assert!` did not hold.
+    AssertionFailed = 0x14,
+    /// 43, a `kassert!` or `kassert_eq!` did not hold.
+    KernelAssertion = 0x15,
+}
+
+impl QemuExitCode {
+    /// The status QEMU exits with.
+    pub fn status(self) -> u32 {
+        (self as u32) << 1 | 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitDevice {
+    port: u16,
+    // bytes written at once, the device's iosize
+    size: u8,
+}
+
+/// A number given at build time, `default` without one; not a number fails the build.
+const fn parse(text: Option<&str>, default: u32) -> u32 {
+    let Some(text) = text else {
+        return default;
+    };
+    let bytes = text.as_bytes();
+    let (mut index, radix) = match bytes {
+        [b'0', b'x' | b'X', ..] => (2, 16),
+        _ => (0, 10),
+    };
+    assert!(index < bytes.len(), "empty number");
+    let mut value = 0;
+    while index < bytes.len() {
+        let digit = match bytes[index] {
+            byte @ b'0'..=b'9' => byte - b'0',
+            byte @ b'a'..=b'f' => byte - b'a' + 10,
+            byte @ b'A'..=b'F' => byte - b'A' + 10,
+            _ => panic!("not a number"),
+        } as u32;
+        assert!(digit < radix, "not a number");
+        value = value * radix + digit;
+        index += 1;
+    }
+    value
+}
+
+/// The device the kernel was built for.
+pub const EXIT_DEVICE: ExitDevice = ExitDevice::new(
+    parse(option_env!("BLOG_OS_EXIT_PORT"), 0xf4) as u16,
+    parse(option_env!("BLOG_OS_EXIT_SIZE"), 4) as u8,
+);
+
+impl ExitDevice {
+    pub const fn new(port: u16, size: u8) -> Self {
+        assert!(matches!(size, 1 | 2 | 4), "isa-debug-exit takes 1, 2 or 4 bytes");
+        ExitDevice { port, size }
+    }
+
+    /// Ends QEMU; returns when there is no such device, on real hardware.
+    pub fn exit(&self, code: QemuExitCode) {
+        let value = code as u32;
+        unsafe {
+            match self.size {
+                1 => Port::<u8>::new(self.port).write(value as u8),
+                2 => Port::<u16>::new(self.port).write(value as u16),
+                _ => Port::<u32>::new(self.port).write(value),
+            }
+        }
+    }
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    EXIT_DEVICE.exit(exit_code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_build_settings() {
+        assert_eq!(parse(None, 0xf4), 0xf4);
+        assert_eq!(parse(Some("0x501"), 0), 0x501);
+        assert_eq!(parse(Some("244"), 0), 244);
+        assert_eq!(QemuExitCode::Success.status(), 33);
+        assert_eq!(QemuExitCode::AssertionFailed.status(), 41);
+        assert_eq!(QemuExitCode::KernelAssertion.status(), 43);
+    }
+}
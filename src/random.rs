@@ -0,0 +1,317 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::fs::devfs::CharDevice;
+use crate::fs::FsError;
+use crate::time::pit;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const BLOCK_SIZE: usize = 64;
+const SEED_SIZE: usize = 32;
+
+pub struct ChaCha20 {
+    key: [u32; 8],
+    nonce: [u32; 3],
+    counter: u32,
+}
+
+impl ChaCha20 {
+    pub const fn new(key: [u32; 8], nonce: [u32; 3], counter: u32) -> Self {
+        ChaCha20 { key, nonce, counter }
+    }
+
+    pub fn from_seed(seed: &[u8; SEED_SIZE]) -> Self {
+        let mut key = [0u32; 8];
+        for (word, chunk) in key.iter_mut().zip(seed.chunks_exact(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        ChaCha20::new(key, [0; 3], 0)
+    }
+
+    fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(16);
+        state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(12);
+        state[a] = state[a].wrapping_add(state[b]); state[d] = (state[d] ^ state[a]).rotate_left(8);
+        state[c] = state[c].wrapping_add(state[d]); state[b] = (state[b] ^ state[c]).rotate_left(7);
+    }
+
+    /// Produces the next 64 byte keystream block and advances the block counter.
+    pub fn next_block(&mut self, out: &mut [u8; BLOCK_SIZE]) {
+        let mut input = [0u32; 16];
+        input[0..4].copy_from_slice(&CHACHA_CONSTANTS);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter;
+        input[13..16].copy_from_slice(&self.nonce);
+
+        let mut state = input;
+        for _ in 0..10 {
+            // column rounds
+            Self::quarter_round(&mut state, 0, 4, 8, 12);
+            Self::quarter_round(&mut state, 1, 5, 9, 13);
+            Self::quarter_round(&mut state, 2, 6, 10, 14);
+            Self::quarter_round(&mut state, 3, 7, 11, 15);
+            // diagonal rounds
+            Self::quarter_round(&mut state, 0, 5, 10, 15);
+            Self::quarter_round(&mut state, 1, 6, 11, 12);
+            Self::quarter_round(&mut state, 2, 7, 8, 13);
+            Self::quarter_round(&mut state, 3, 4, 9, 14);
+        }
+
+        for (i, word) in state.iter().enumerate() {
+            let word = word.wrapping_add(input[i]);
+            out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        self.counter = self.counter.wrapping_add(1);
+        if self.counter == 0 {
+            self.nonce[0] = self.nonce[0].wrapping_add(1);
+        }
+    }
+}
+
+pub struct KernelRng {
+    cipher: ChaCha20,
+    buffer: [u8; BLOCK_SIZE],
+    position: usize,
+    seeded: bool,
+}
+
+impl KernelRng {
+    const fn new() -> Self {
+        KernelRng {
+            cipher: ChaCha20::new([0; 8], [0; 3], 0),
+            buffer: [0; BLOCK_SIZE],
+            position: BLOCK_SIZE,
+            seeded: false,
+        }
+    }
+
+    fn reseed(&mut self, seed: &[u8; SEED_SIZE]) {
+        // fold the new seed into the current key so a weak reseed never lowers the entropy
+        let mut mixed = [0u8; SEED_SIZE];
+        self.fill(&mut mixed);
+        for (m, s) in mixed.iter_mut().zip(seed.iter()) {
+            *m ^= *s;
+        }
+        self.cipher = ChaCha20::from_seed(&mixed);
+        self.position = BLOCK_SIZE;
+        self.seeded = true;
+    }
+
+    fn fill(&mut self, dest: &mut [u8]) {
+        for byte in dest.iter_mut() {
+            if self.position == BLOCK_SIZE {
+                self.cipher.next_block(&mut self.buffer);
+                self.position = 0;
+            }
+            *byte = self.buffer[self.position];
+            // wipe consumed keystream so it cannot be recovered later
+            self.buffer[self.position] = 0;
+            self.position += 1;
+        }
+        self.rekey();
+    }
+
+    /* fast key erasure.
+        After handing out output, the key is replaced with fresh keystream,
+        so a later compromise of the state does not reveal earlier output.
+     */
+    fn rekey(&mut self) {
+        let mut block = [0u8; BLOCK_SIZE];
+        self.cipher.next_block(&mut block);
+        let mut seed = [0u8; SEED_SIZE];
+        seed.copy_from_slice(&block[..SEED_SIZE]);
+        self.cipher = ChaCha20::from_seed(&seed);
+        self.position = BLOCK_SIZE;
+    }
+}
+
+static RNG: Mutex<KernelRng> = Mutex::new(KernelRng::new());
+
+/// From the strongest to the weakest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntropySource {
+    RdSeed,
+    RdRand,
+    TimerJitter,
+}
+
+pub fn has_rdrand() -> bool {
+    let leaf = unsafe { __cpuid(1) };
+    leaf.ecx & (1 << 30) != 0
+}
+
+pub fn has_rdseed() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 {
+        return false;
+    }
+    let leaf = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+    leaf.ebx & (1 << 18) != 0
+}
+
+fn rdseed64() -> Option<u64> {
+    for _ in 0..64 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/* RDRAND.
+    A clear carry flag means the generator had no number ready and the register holds
+    nothing, not a random zero. Intel counts ten tries failing in a row as the
+    generator being broken.
+ */
+const RDRAND_RETRIES: usize = 10;
+
+fn rdrand64() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {0}", "setc {1}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/* timer jitter.
+    The TSC and the PIT run off unrelated clocks, so sampling one against the other
+    leaves a few bits of noise per sample. Plenty of samples are folded into the pool,
+    and ChaCha takes care of whitening.
+ */
+fn jitter64() -> u64 {
+    let mut pool = 0u64;
+    for _ in 0..64 {
+        let before = rdtsc();
+        let pit = pit::read_count() as u64;
+        let delta = rdtsc().wrapping_sub(before);
+        pool = pool.rotate_left(7) ^ delta ^ (pit << 32);
+    }
+    pool
+}
+
+/// Fills `seed` from the best source there is, returning the weakest one it came to use.
+fn gather_seed(seed: &mut [u8; SEED_SIZE]) -> EntropySource {
+    let best = if has_rdseed() {
+        EntropySource::RdSeed
+    } else if has_rdrand() {
+        EntropySource::RdRand
+    } else {
+        EntropySource::TimerJitter
+    };
+
+    let mut used = best;
+    for chunk in seed.chunks_exact_mut(8) {
+        let rdrand = || rdrand64().map(|value| (EntropySource::RdRand, value));
+        let hardware = match best {
+            EntropySource::RdSeed => rdseed64().map(|value| (EntropySource::RdSeed, value)).or_else(rdrand),
+            EntropySource::RdRand => rdrand(),
+            EntropySource::TimerJitter => None,
+        };
+        // jitter only once the hardware has run out of tries
+        let (source, value) = hardware.unwrap_or_else(|| (EntropySource::TimerJitter, jitter64()));
+        used = used.max(source);
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    if used != best {
+        log::warn!("random: {:?} failed, seeded from {:?}", best, used);
+    }
+    used
+}
+
+pub fn init() -> EntropySource {
+    let mut seed = [0u8; SEED_SIZE];
+    let source = gather_seed(&mut seed);
+    without_interrupts(|| RNG.lock().reseed(&seed));
+    source
+}
+
+pub fn reseed() {
+    let mut seed = [0u8; SEED_SIZE];
+    gather_seed(&mut seed);
+    without_interrupts(|| RNG.lock().reseed(&seed));
+}
+
+pub fn fill(dest: &mut [u8]) {
+    without_interrupts(|| {
+        let mut rng = RNG.lock();
+        assert!(rng.seeded, "random::fill called before random::init");
+        rng.fill(dest);
+    });
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn next_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    fill(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+/// /dev/random, never blocks once seeded. Writes are taken and ignored.
+pub struct RandomDevice;
+
+impl CharDevice for RandomDevice {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        fill(buffer);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        Ok(buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_chacha20_block_vector() {
+        // RFC 7539 section 2.3.2
+        let key = [
+            0x03020100, 0x07060504, 0x0b0a0908, 0x0f0e0d0c,
+            0x13121110, 0x17161514, 0x1b1a1918, 0x1f1e1d1c,
+        ];
+        let mut cipher = ChaCha20::new(key, [0x09000000, 0x4a000000, 0x00000000], 1);
+        let mut block = [0u8; 64];
+        cipher.next_block(&mut block);
+        assert_eq!(&block[..16], &[
+            0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15,
+            0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71, 0xc4,
+        ]);
+        assert_eq!(&block[60..], &[0xa2, 0x50, 0x3c, 0x4e]);
+    }
+
+    #[test_case]
+    fn test_fill_produces_distinct_output() {
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        fill(&mut a);
+        fill(&mut b);
+        assert_ne!(a, b);
+        assert!(a.iter().any(|&x| x != 0));
+    }
+}
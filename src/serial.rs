@@ -1,12 +1,21 @@
+use core::time::Duration;
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
 
+use crate::fs::devfs::CharDevice;
+use crate::fs::FsError;
+use crate::process::signal::{self, Interrupted};
+use crate::sync::SpinLock;
+use crate::time;
+
+// the port raises no interrupt for input, readers check this often
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: SpinLock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        SpinLock::new(serial_port)
     };
 }
 
@@ -14,19 +23,45 @@ lazy_static! {
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    #[cfg(feature = "net")]
+    crate::net::netconsole::capture(args);
 }
 
-#[macro_export]
-macro_rules! serial_print {
-    ($($arg:tt)*) => {
-        $crate::serial::_print(format_args!($($arg)*));
-    };
-}
+/// The first serial port as /dev/ttyS0.
+pub struct SerialDevice;
+
+impl CharDevice for SerialDevice {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let mut count = 0;
+            {
+                let mut port = SERIAL1.lock();
+                while count < buffer.len() {
+                    let Ok(byte) = port.try_receive() else {
+                        break;
+                    };
+                    buffer[count] = byte;
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                return Ok(count);
+            }
+            if signal::interrupted() {
+                return Err(Interrupted.into());
+            }
+            time::sleep(POLL_INTERVAL);
+        }
+    }
 
-#[macro_export]
-macro_rules! serial_println {
-    () => {$crate::serial_print!("\n")};
-    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
-        concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut port = SERIAL1.lock();
+        for &byte in buffer {
+            port.send_raw(byte);
+        }
+        Ok(buffer.len())
+    }
+}
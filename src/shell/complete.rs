@@ -0,0 +1,74 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::{self, FileType};
+
+/* completing a word.
+    The word before the cursor is taken up to the last space, quotes and escapes are
+    not looked at. The first word of a line is a command, the names registered that
+    start with it are the candidates; any other is a path, and the entries of its
+    directory that start with what follows the last slash are, directories with a
+    slash after them. Paths are taken from the root, the shell has no directory of
+    its own.
+ */
+
+/// Where the word before `cursor` starts, and what it could be completed to, sorted.
+pub fn candidates(line: &str, cursor: usize) -> (usize, Vec<String>) {
+    let before = &line[..cursor];
+    let start = before.rfind(' ').map_or(0, |space| space + 1);
+    let word = &before[start..];
+    let candidates = if before[..start].trim().is_empty() {
+        super::commands().into_iter().filter(|name| name.starts_with(word)).map(String::from).collect()
+    } else {
+        paths(word)
+    };
+    (start, candidates)
+}
+
+fn paths(word: &str) -> Vec<String> {
+    let (directory, prefix) = match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    };
+    let lookup = if directory.is_empty() { "/" } else { directory };
+    let Ok(entries) = fs::read_dir(lookup) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries.into_iter()
+        .filter(|entry| entry.name.starts_with(prefix) && entry.name != "." && entry.name != "..")
+        .map(|entry| {
+            let mut path = String::from(directory);
+            path.push_str(&entry.name);
+            if entry.file_type == FileType::Directory {
+                path.push('/');
+            }
+            path
+        })
+        .collect();
+    paths.sort_unstable();
+    paths
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_candidates() {
+        assert_eq!(candidates("ec", 2), (0, vec![String::from("echo")]));
+        assert_eq!(candidates("he", 2).1, vec![String::from("help")]);
+        assert_eq!(candidates("  ec", 4), (2, vec![String::from("echo")]));
+
+        fs::create_dir_all("/complete-test/dir").unwrap();
+        fs::write_file("/complete-test/file", b"").unwrap();
+        let (start, paths) = candidates("echo /complete-test/ more", 20);
+        assert_eq!((start, paths), (5, vec![String::from("/complete-test/dir/"), String::from("/complete-test/file")]));
+        assert_eq!(candidates("echo /complete-test/f", 21).1, vec![String::from("/complete-test/file")]);
+        assert_eq!(candidates("echo complete-t", 15).1, vec![String::from("complete-test/")]);
+        assert!(candidates("echo /nowhere/", 14).1.is_empty());
+        for path in ["/complete-test/file", "/complete-test/dir", "/complete-test"] {
+            fs::unlink(path).unwrap();
+        }
+    }
+}
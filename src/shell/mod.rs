@@ -0,0 +1,538 @@
+mod complete;
+mod parse;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::sync::SpinLock;
+use crate::task::keyboard::{self, Keys};
+use crate::{print, tty, vga_buffer};
+
+pub use parse::split;
+
+/* the kernel shell.
+    Reads lines from the keyboard, echoing them on the screen, splits them into
+    words as in parse.rs and runs the command the first one names; what a command
+    prints and why it failed go to the screen. While a process is in the foreground
+    the keys are its own, they go to the terminal as before.
+
+    The shell knows only a few commands of its own; the rest come from the
+    subsystems they are about, which register them with `register_command`, and
+    `help` lists whatever is registered.
+
+    The line is edited where the cursor is, moved with the arrows, Home and End;
+    up and down bring back the lines run before, the same line twice in a row is
+    kept once. Tab completes the word before the cursor as in complete.rs, with the
+    first candidate; Tab again right after puts the next one in its place, round.
+ */
+
+const PROMPT: &str = "> ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellError {
+    UnknownCommand(String),
+    UnterminatedQuote,
+    TrailingBackslash,
+    /// The arguments were wrong, the usage of the command.
+    Usage(&'static str),
+    Failed(String),
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand(name) => write!(f, "{}: command not found", name),
+            ShellError::UnterminatedQuote => f.write_str("unterminated quote"),
+            ShellError::TrailingBackslash => f.write_str("backslash at the end of the line"),
+            ShellError::Usage(usage) => write!(f, "usage: {}", usage),
+            ShellError::Failed(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Runs with the words of the line after the command's name, printing to `out`.
+pub type Handler = fn(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError>;
+
+#[derive(Clone, Copy)]
+struct Command {
+    help: &'static str,
+    handler: Handler,
+}
+
+static COMMANDS: SpinLock<BTreeMap<&'static str, Command>> = SpinLock::new(BTreeMap::new());
+
+/// Makes `name` a command, replacing any command of that name before it; `help`
+/// is the line `help` shows for it.
+pub fn register_command(name: &'static str, help: &'static str, handler: Handler) {
+    COMMANDS.lock().insert(name, Command { help, handler });
+}
+
+/// The names of the commands, in order.
+pub fn commands() -> Vec<&'static str> {
+    COMMANDS.lock().keys().copied().collect()
+}
+
+/// Registers the shell's own commands.
+pub fn init() {
+    register_command("clear", "clear the screen", clear);
+    register_command("echo", "print the arguments", echo);
+    register_command("help", "list the commands, or show what one does", help);
+}
+
+fn clear(_args: &[&str], _out: &mut dyn Write) -> Result<(), ShellError> {
+    vga_buffer::WRITER.lock().clear();
+    Ok(())
+}
+
+fn echo(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    let _ = writeln!(out, "{}", args.join(" "));
+    Ok(())
+}
+
+fn help(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    // copied, a command may print a lot
+    let commands: Vec<(&str, &str)> = match args {
+        [] => COMMANDS.lock().iter().map(|(name, command)| (*name, command.help)).collect(),
+        [name] => {
+            let help = COMMANDS.lock().get(name).map(|command| command.help)
+                .ok_or_else(|| ShellError::UnknownCommand(String::from(*name)))?;
+            alloc::vec![(*name, help)]
+        }
+        _ => return Err(ShellError::Usage("help [command]")),
+    };
+    for (name, help) in commands {
+        let _ = writeln!(out, "{:<10} {}", name, help);
+    }
+    Ok(())
+}
+
+/// Splits `line` and runs the command it names, printing to `out`.
+pub fn execute(line: &str, out: &mut dyn Write) -> Result<(), ShellError> {
+    let words = split(line)?;
+    let Some((name, args)) = words.split_first() else {
+        return Ok(());
+    };
+    let command = COMMANDS.lock().get(name.as_str()).copied()
+        .ok_or_else(|| ShellError::UnknownCommand(name.clone()))?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    (command.handler)(&args, out)
+}
+
+/// Where the shell takes a line: text as any writer, and the row it is typed on.
+pub trait Terminal: Write {
+    fn width(&self) -> usize;
+
+    /// Where on the last row writing goes on.
+    fn column(&self) -> usize;
+
+    /// Makes writing go on at `column` of the last row, the cursor with it.
+    fn set_column(&mut self, column: usize);
+
+    /// Writes `text` on the last row from `column` on, leaving the cursor alone.
+    fn write_at(&mut self, column: usize, text: &str);
+}
+
+/// The screen, what the shell prints on.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        print!("{}", text);
+        Ok(())
+    }
+}
+
+impl Terminal for Console {
+    fn width(&self) -> usize {
+        vga_buffer::BUFFER_WIDTH
+    }
+
+    fn column(&self) -> usize {
+        vga_buffer::WRITER.lock().column()
+    }
+
+    fn set_column(&mut self, column: usize) {
+        vga_buffer::WRITER.lock().set_column(column);
+    }
+
+    fn write_at(&mut self, column: usize, text: &str) {
+        vga_buffer::WRITER.lock().write_at(column, text);
+    }
+}
+
+const TAB: char = '\t';
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+// lines kept for up and down
+const HISTORY: usize = 32;
+
+/// A line being typed, and the lines typed before it.
+pub struct Shell {
+    line: String,
+    // where in the line the next character goes, the line is ASCII so a byte index
+    cursor: usize,
+    // the column the line starts at, after the prompt
+    start: usize,
+    history: VecDeque<String>,
+    // the line of the history shown, and the one being typed when going up from it
+    browsing: Option<usize>,
+    typed: String,
+    // the candidates of the last Tab, while nothing else was pressed after it
+    completion: Option<Completion>,
+}
+
+struct Completion {
+    // where the word starts in the line
+    start: usize,
+    candidates: Vec<String>,
+    // the candidate in the line
+    shown: usize,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Shell { line: String::new(), cursor: 0, start: 0, history: VecDeque::new(), browsing: None, typed: String::new(), completion: None }
+    }
+
+    pub fn prompt(&mut self, out: &mut dyn Terminal) {
+        let _ = write!(out, "{}", PROMPT);
+        self.start = out.column();
+    }
+
+    /// Takes a key typed at the prompt, running the line on Enter.
+    pub fn key(&mut self, key: DecodedKey, out: &mut dyn Terminal) {
+        let completion = self.completion.take();
+        match key {
+            DecodedKey::Unicode(TAB) => self.complete(completion, out),
+            DecodedKey::Unicode('\n') => {
+                out.set_column(self.start + self.line.len());
+                let _ = writeln!(out);
+                let line = self.take_line();
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == HISTORY {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                if let Err(error) = execute(&line, out) {
+                    let _ = writeln!(out, "shell: {}", error);
+                }
+                self.prompt(out);
+            }
+            DecodedKey::Unicode(tty::INTERRUPT) => {
+                out.set_column(self.start + self.line.len());
+                self.take_line();
+                let _ = writeln!(out, "^C");
+                self.prompt(out);
+            }
+            DecodedKey::Unicode(BACKSPACE) if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                self.redraw(self.cursor, 1, out);
+            }
+            DecodedKey::Unicode(DELETE) if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw(self.cursor, 1, out);
+            }
+            DecodedKey::Unicode(character) if character.is_ascii() && !character.is_ascii_control() => {
+                // one row, the cursor stays on the screen after the last character
+                if self.start + self.line.len() + 1 < out.width() {
+                    self.line.insert(self.cursor, character);
+                    self.cursor += 1;
+                    self.redraw(self.cursor - 1, 0, out);
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.move_to(self.cursor.saturating_sub(1), out),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => self.move_to((self.cursor + 1).min(self.line.len()), out),
+            DecodedKey::RawKey(KeyCode::Home) => self.move_to(0, out),
+            DecodedKey::RawKey(KeyCode::End) => self.move_to(self.line.len(), out),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                let shown = match self.browsing {
+                    None if !self.history.is_empty() => {
+                        self.typed = self.line.clone();
+                        self.history.len() - 1
+                    }
+                    Some(shown) if shown > 0 => shown - 1,
+                    _ => return,
+                };
+                self.browsing = Some(shown);
+                let line = self.history[shown].clone();
+                self.replace_line(line, out);
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) => {
+                let Some(shown) = self.browsing else {
+                    return;
+                };
+                let line = if shown + 1 < self.history.len() {
+                    self.browsing = Some(shown + 1);
+                    self.history[shown + 1].clone()
+                } else {
+                    self.browsing = None;
+                    core::mem::take(&mut self.typed)
+                };
+                self.replace_line(line, out);
+            }
+            _ => {}
+        }
+    }
+
+    /// The first candidate for the word before the cursor, or the next after `last`.
+    fn complete(&mut self, last: Option<Completion>, out: &mut dyn Terminal) {
+        let completion = match last {
+            Some(mut completion) => {
+                completion.shown = (completion.shown + 1) % completion.candidates.len();
+                completion
+            }
+            None => {
+                let (start, mut candidates) = complete::candidates(&self.line, self.cursor);
+                // only what fits on the row
+                let rest = self.line.len() - (self.cursor - start);
+                candidates.retain(|candidate| candidate.is_ascii() && self.start + rest + candidate.len() < out.width());
+                if candidates.is_empty() {
+                    return;
+                }
+                Completion { start, candidates, shown: 0 }
+            }
+        };
+        let candidate = &completion.candidates[completion.shown];
+        let removed = (self.cursor - completion.start).saturating_sub(candidate.len());
+        self.line.replace_range(completion.start..self.cursor, candidate);
+        self.cursor = completion.start + candidate.len();
+        self.redraw(completion.start, removed, out);
+        self.completion = Some(completion);
+    }
+
+    fn take_line(&mut self) -> String {
+        self.cursor = 0;
+        self.browsing = None;
+        self.typed.clear();
+        core::mem::take(&mut self.line)
+    }
+
+    fn move_to(&mut self, cursor: usize, out: &mut dyn Terminal) {
+        self.cursor = cursor;
+        out.set_column(self.start + cursor);
+    }
+
+    /// Writes the line again from `from` on, blanking `removed` more characters past
+    /// its new end, and puts the cursor back.
+    fn redraw(&mut self, from: usize, removed: usize, out: &mut dyn Terminal) {
+        let mut text = String::from(&self.line[from..]);
+        text.extend(core::iter::repeat_n(' ', removed));
+        out.write_at(self.start + from, &text);
+        self.move_to(self.cursor, out);
+    }
+
+    fn replace_line(&mut self, line: String, out: &mut dyn Terminal) {
+        let removed = self.line.len().saturating_sub(line.len());
+        self.line = line;
+        self.cursor = self.line.len();
+        self.redraw(0, removed, out);
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The shell task, reading the keyboard from now on.
+pub async fn run() {
+    let mut keys = Keys::new();
+    let mut shell = Shell::new();
+    shell.prompt(&mut Console);
+    while let Some(key) = keys.next().await {
+        if tty::foreground().is_some() {
+            keyboard::forward_to_tty(key);
+        } else {
+            shell.key(key, &mut Console);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A terminal that keeps what it shows as text, rows without their trailing blanks.
+    #[derive(Default)]
+    struct Screen {
+        rows: String,
+        row: Vec<u8>,
+        column: usize,
+    }
+
+    impl Screen {
+        /// The rows finished since the last call.
+        fn take_rows(&mut self) -> String {
+            core::mem::take(&mut self.rows)
+        }
+
+        fn row(&self) -> &str {
+            core::str::from_utf8(&self.row).unwrap().trim_end()
+        }
+    }
+
+    impl Write for Screen {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            for byte in text.bytes() {
+                if byte == b'\n' {
+                    let row = String::from(self.row());
+                    self.rows.push_str(&row);
+                    self.rows.push('\n');
+                    self.row.clear();
+                    self.column = 0;
+                } else {
+                    self.write_at(self.column, core::str::from_utf8(&[byte]).unwrap());
+                    self.column += 1;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Terminal for Screen {
+        fn width(&self) -> usize {
+            80
+        }
+
+        fn column(&self) -> usize {
+            self.column
+        }
+
+        fn set_column(&mut self, column: usize) {
+            self.column = column;
+        }
+
+        fn write_at(&mut self, column: usize, text: &str) {
+            if self.row.len() < column + text.len() {
+                self.row.resize(column + text.len(), b' ');
+            }
+            self.row[column..column + text.len()].copy_from_slice(text.as_bytes());
+        }
+    }
+
+    fn type_keys(shell: &mut Shell, screen: &mut Screen, text: &str) {
+        for character in text.chars() {
+            shell.key(DecodedKey::Unicode(character), screen);
+        }
+    }
+
+    fn press(shell: &mut Shell, screen: &mut Screen, keys: &[KeyCode]) {
+        for &key in keys {
+            shell.key(DecodedKey::RawKey(key), screen);
+        }
+    }
+
+    #[test_case]
+    fn test_lines() {
+        let (mut shell, mut screen) = (Shell::new(), Screen::default());
+        shell.prompt(&mut screen);
+        type_keys(&mut shell, &mut screen, "echo 'hello  world' again\n");
+        assert_eq!(screen.take_rows(), "> echo 'hello  world' again\nhello  world again\n");
+        type_keys(&mut shell, &mut screen, "nope 1\n");
+        assert_eq!(screen.take_rows(), "> nope 1\nshell: nope: command not found\n");
+        type_keys(&mut shell, &mut screen, "echo \"open\n");
+        assert_eq!(screen.take_rows(), "> echo \"open\nshell: unterminated quote\n");
+        type_keys(&mut shell, &mut screen, "help\n");
+        assert!(screen.take_rows().contains("echo       print the arguments\n"));
+        type_keys(&mut shell, &mut screen, "\n");
+        assert_eq!(screen.take_rows(), ">\n");
+        assert_eq!(screen.row(), ">");
+    }
+
+    #[test_case]
+    fn test_line_editing() {
+        let (mut shell, mut screen) = (Shell::new(), Screen::default());
+        shell.prompt(&mut screen);
+        type_keys(&mut shell, &mut screen, "echo ac");
+        press(&mut shell, &mut screen, &[KeyCode::ArrowLeft]);
+        type_keys(&mut shell, &mut screen, "b");
+        assert_eq!((screen.row(), screen.column()), ("> echo abc", 9));
+        press(&mut shell, &mut screen, &[KeyCode::Home]);
+        type_keys(&mut shell, &mut screen, "\u{7f}\u{7f}\u{7f}\u{7f}\u{7f}");
+        assert_eq!((screen.row(), screen.column()), ("> abc", 2));
+        type_keys(&mut shell, &mut screen, "echo x");
+        press(&mut shell, &mut screen, &[KeyCode::End]);
+        type_keys(&mut shell, &mut screen, "\u{8}\n");
+        assert_eq!(screen.take_rows(), "> echo xab\nxab\n");
+
+        // nothing to delete at the ends of the line
+        type_keys(&mut shell, &mut screen, "\u{8}\u{7f}");
+        press(&mut shell, &mut screen, &[KeyCode::ArrowLeft, KeyCode::ArrowRight]);
+        assert_eq!((screen.row(), screen.column()), (">", 2));
+    }
+
+    #[test_case]
+    fn test_history() {
+        let (mut shell, mut screen) = (Shell::new(), Screen::default());
+        shell.prompt(&mut screen);
+        type_keys(&mut shell, &mut screen, "echo one\necho two\necho two\nx");
+        press(&mut shell, &mut screen, &[KeyCode::ArrowUp]);
+        assert_eq!(screen.row(), "> echo two");
+        press(&mut shell, &mut screen, &[KeyCode::ArrowUp]);
+        assert_eq!(screen.row(), "> echo one");
+        // the oldest line stays at the top
+        press(&mut shell, &mut screen, &[KeyCode::ArrowUp, KeyCode::ArrowDown]);
+        assert_eq!(screen.row(), "> echo two");
+        press(&mut shell, &mut screen, &[KeyCode::ArrowDown]);
+        assert_eq!((screen.row(), screen.column()), ("> x", 3));
+        press(&mut shell, &mut screen, &[KeyCode::ArrowUp]);
+        screen.take_rows();
+        type_keys(&mut shell, &mut screen, "\n");
+        assert_eq!(screen.take_rows(), "> echo two\ntwo\n");
+    }
+
+    #[test_case]
+    fn test_tab_completion() {
+        fn nothing(_args: &[&str], _out: &mut dyn Write) -> Result<(), ShellError> {
+            Ok(())
+        }
+        register_command("tab-test-one", "", nothing);
+        register_command("tab-test-two", "", nothing);
+        let (mut shell, mut screen) = (Shell::new(), Screen::default());
+        shell.prompt(&mut screen);
+        type_keys(&mut shell, &mut screen, "tab-t\t");
+        assert_eq!((screen.row(), screen.column()), ("> tab-test-one", 14));
+        type_keys(&mut shell, &mut screen, "\t");
+        assert_eq!(screen.row(), "> tab-test-two");
+        type_keys(&mut shell, &mut screen, "\t");
+        assert_eq!(screen.row(), "> tab-test-one");
+
+        // another key ends the round, the next Tab starts from the new word
+        type_keys(&mut shell, &mut screen, " /pro\t");
+        assert_eq!(screen.row(), "> tab-test-one /proc/");
+        type_keys(&mut shell, &mut screen, "me\t\t");
+        assert_eq!(screen.row(), "> tab-test-one /proc/meminfo");
+        type_keys(&mut shell, &mut screen, " /nowhere/\t\n");
+        assert_eq!(screen.take_rows(), "> tab-test-one /proc/meminfo /nowhere/\n");
+        COMMANDS.lock().remove("tab-test-one");
+        COMMANDS.lock().remove("tab-test-two");
+    }
+
+    #[test_case]
+    fn test_register_command() {
+        fn count(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+            if args.is_empty() {
+                return Err(ShellError::Usage("count-test <word>..."));
+            }
+            let _ = writeln!(out, "{}", args.len());
+            Ok(())
+        }
+        register_command("count-test", "count the arguments", count);
+        let mut out = String::new();
+        execute("count-test a b 'c d'", &mut out).unwrap();
+        assert_eq!(out, "3\n");
+        assert_eq!(execute("count-test", &mut out), Err(ShellError::Usage("count-test <word>...")));
+        assert!(commands().contains(&"count-test"));
+
+        out.clear();
+        execute("help count-test", &mut out).unwrap();
+        assert_eq!(out, "count-test count the arguments\n");
+        COMMANDS.lock().remove("count-test");
+    }
+}
@@ -0,0 +1,69 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::ShellError;
+
+/* splitting a command line.
+    Words are separated by spaces. Within single quotes everything is taken as it
+    is, within double quotes only a backslash still escapes, and outside of quotes a
+    backslash takes the next character as it is, a space too:
+        echo 'a  b' "c \"d\"" e\ f      ->  echo, a  b, c "d", e f
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+pub fn split(line: &str) -> Result<Vec<String>, ShellError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // a word was started, "" is one too
+    let mut in_word = false;
+    let mut quote = Quote::None;
+    let mut chars = line.chars();
+    while let Some(character) = chars.next() {
+        match (quote, character) {
+            (Quote::None, ' ' | '\t') => {
+                if in_word {
+                    words.push(core::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (Quote::None, '\'') => (quote, in_word) = (Quote::Single, true),
+            (Quote::None, '"') => (quote, in_word) = (Quote::Double, true),
+            (Quote::Single, '\'') | (Quote::Double, '"') => quote = Quote::None,
+            (Quote::None | Quote::Double, '\\') => {
+                word.push(chars.next().ok_or(ShellError::TrailingBackslash)?);
+                in_word = true;
+            }
+            (_, character) => {
+                word.push(character);
+                in_word = true;
+            }
+        }
+    }
+    if quote != Quote::None {
+        return Err(ShellError::UnterminatedQuote);
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_split() {
+        assert_eq!(split("  echo a\tb  ").unwrap(), ["echo", "a", "b"]);
+        assert_eq!(split(r#"echo 'a  b' "c \"d\"" e\ f ''"#).unwrap(), ["echo", "a  b", "c \"d\"", "e f", ""]);
+        assert!(split("").unwrap().is_empty());
+        assert_eq!(split("echo 'a"), Err(ShellError::UnterminatedQuote));
+        assert_eq!(split("echo a\\"), Err(ShellError::TrailingBackslash));
+    }
+}
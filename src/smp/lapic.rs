@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::phys_to_virt;
+use crate::msr::{ApicBase, ApicBaseFlags};
+
+/* the local APIC.
+    Every CPU reaches its own local APIC at the same physical address, registers are
+    32 bits wide and 16 byte aligned. The bootloader maps physical memory up to the end
+    of the 4 GiB range, the APIC page included, so no mapping of its own is needed.
+ */
+
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const DELIVERY_EXT_INT: u32 = 0b111 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Who an interprocessor interrupt goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Apic(u32),
+    This,
+    All,
+    AllButThis,
+}
+
+impl Destination {
+    fn shorthand(self) -> u32 {
+        let bits = match self {
+            Destination::Apic(_) => 0b00,
+            Destination::This => 0b01,
+            Destination::All => 0b10,
+            Destination::AllButThis => 0b11,
+        };
+        bits << 18
+    }
+}
+
+fn base() -> VirtAddr {
+    let (address, _) = ApicBase::read();
+    phys_to_virt(address)
+}
+
+fn read(register: usize) -> u32 {
+    unsafe { (base() + register as u64).as_ptr::<u32>().read_volatile() }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { (base() + register as u64).as_mut_ptr::<u32>().write_volatile(value) };
+}
+
+/* enabling next to the 8259.
+    The PICs keep delivering the timer and keyboard through LINT0 in virtual wire mode,
+    so LINT0 stays ExtINT and LINT1 NMI, as the firmware set them up. The APIC timer is
+    not used yet and stays masked.
+ */
+pub fn init(spurious_vector: u8) {
+    let (address, flags) = ApicBase::read();
+    unsafe { ApicBase::write(address, flags | ApicBaseFlags::APIC_GLOBAL_ENABLE) };
+    write(SPURIOUS, SOFTWARE_ENABLE | spurious_vector as u32);
+    write(LVT_TIMER, LVT_MASKED);
+    write(LVT_LINT0, DELIVERY_EXT_INT);
+    write(LVT_LINT1, DELIVERY_NMI);
+    write(TASK_PRIORITY, 0);
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+pub fn id() -> u32 {
+    read(ID) >> 24
+}
+
+/// Physical address of the register page, the same on every CPU.
+pub fn address() -> PhysAddr {
+    ApicBase::read().0
+}
+
+/// Acknowledges the interrupt being handled, needed for everything the APIC delivered.
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Sends a fixed interrupt with `vector` and waits until the APIC accepted it.
+pub fn send_ipi(destination: Destination, vector: u8) {
+    let target = match destination {
+        Destination::Apic(id) => id << 24,
+        _ => 0,
+    };
+    write(ICR_HIGH, target);
+    // writing the low half is what sends it
+    write(ICR_LOW, destination.shorthand() | ICR_ASSERT | vector as u32);
+    while read(ICR_LOW) & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
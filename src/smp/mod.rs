@@ -0,0 +1,228 @@
+pub mod lapic;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+use crate::interrupts::{latency, ExceptionStackFrame};
+use crate::{cmdline, percpu};
+use crate::percpu::PerCpu;
+use crate::sync::SpinLock;
+use crate::thread;
+use lapic::Destination;
+
+/* interprocessor interrupts.
+    Vectors at the top of the IDT, above anything the PICs deliver. Requests that need
+    an answer go through a mailbox: the sender posts a value, marks every other online
+    CPU as a target and waits until each one handled it and cleared its bit.
+ */
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum IpiVector {
+    Reschedule = 0xf0,
+    TlbShootdown,
+    CallFunction,
+    Halt,
+    Spurious = 0xff,
+}
+
+impl IpiVector {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+pub fn init() {
+    // without it there are no IPIs, everything here falls back to this CPU alone
+    if cmdline::flag("noapic") {
+        log::info!("noapic: local APIC left disabled");
+        return;
+    }
+    lapic::init(IpiVector::Spurious.as_u8());
+}
+
+/// Number of CPUs that set up their per-CPU block and take part in IPIs.
+pub fn online_cpus() -> usize {
+    percpu::cpus().len()
+}
+
+fn cpu_bit(cpu: &PerCpu) -> u64 {
+    1 << cpu.cpu_id
+}
+
+struct Mailbox<T: Copy> {
+    // one request at a time
+    sending: Mutex<()>,
+    request: SpinLock<Option<T>>,
+    // CPUs that still have to handle the request
+    targets: AtomicU64,
+    vector: IpiVector,
+    run: fn(T),
+}
+
+impl<T: Copy> Mailbox<T> {
+    const fn new(vector: IpiVector, run: fn(T)) -> Self {
+        Mailbox { sending: Mutex::new(()), request: SpinLock::new(None), targets: AtomicU64::new(0), vector, run }
+    }
+
+    /* runs `request` on every online CPU, this one last.
+        Waiting happens with interrupts possibly disabled, so while spinning for the
+        mailbox or for the answers this CPU keeps serving requests addressed to it.
+        Otherwise two CPUs sending at the same time would wait on each other forever.
+     */
+    fn send(&self, request: T) {
+        let _sending = loop {
+            if let Some(guard) = self.sending.try_lock() {
+                break guard;
+            }
+            serve_mailboxes();
+            core::hint::spin_loop();
+        };
+        let this = cpu_bit(percpu::this_cpu());
+        let others = percpu::cpus().iter().map(|cpu| cpu_bit(cpu)).fold(0, |mask, bit| mask | bit) & !this;
+        if others != 0 && lapic::is_enabled() {
+            *self.request.lock() = Some(request);
+            self.targets.store(others, Ordering::Release);
+            lapic::send_ipi(Destination::AllButThis, self.vector.as_u8());
+            while self.targets.load(Ordering::Acquire) != 0 {
+                serve_mailboxes();
+                core::hint::spin_loop();
+            }
+            *self.request.lock() = None;
+        }
+        without_interrupts(|| (self.run)(request));
+    }
+
+    /// Handles the pending request if this CPU is one of its targets.
+    fn serve(&self) {
+        let this = cpu_bit(percpu::this_cpu());
+        if self.targets.load(Ordering::Acquire) & this == 0 {
+            return;
+        }
+        let request = *self.request.lock();
+        if let Some(request) = request {
+            (self.run)(request);
+        }
+        self.targets.fetch_and(!this, Ordering::AcqRel);
+    }
+}
+
+// the borrowed closure outlives the request, `call_on_each_cpu` waits for every CPU
+type Function = &'static (dyn Fn() + Sync);
+
+#[derive(Debug, Clone, Copy)]
+enum Flush {
+    All,
+    Page(VirtAddr),
+}
+
+static CALLS: Mailbox<Function> = Mailbox::new(IpiVector::CallFunction, |function| function());
+static SHOOTDOWNS: Mailbox<Flush> = Mailbox::new(IpiVector::TlbShootdown, |flush| match flush {
+    Flush::All => tlb::flush_all(),
+    Flush::Page(address) => tlb::flush(address),
+});
+
+fn serve_mailboxes() {
+    CALLS.serve();
+    SHOOTDOWNS.serve();
+}
+
+/// Runs `f` on every online CPU with interrupts disabled, returns once all of them did.
+pub fn call_on_each_cpu<F: Fn() + Sync>(f: F) {
+    let function: &(dyn Fn() + Sync) = &f;
+    let function: Function = unsafe { core::mem::transmute::<&(dyn Fn() + Sync), Function>(function) };
+    CALLS.send(function);
+}
+
+/// Drops the TLB entry for `address` on every CPU, or everything with `None`.
+pub fn flush_tlb(address: Option<VirtAddr>) {
+    SHOOTDOWNS.send(address.map_or(Flush::All, Flush::Page));
+}
+
+/// Asks `cpu` to look for a more important thread to run.
+pub fn reschedule(cpu: &PerCpu) {
+    if lapic::is_enabled() {
+        lapic::send_ipi(Destination::Apic(cpu.apic_id), IpiVector::Reschedule.as_u8());
+    }
+}
+
+/// Stops every other CPU for good, used when the kernel panics.
+pub fn halt_others() {
+    if lapic::is_enabled() && online_cpus() > 1 {
+        lapic::send_ipi(Destination::AllButThis, IpiVector::Halt.as_u8());
+    }
+}
+
+fn count_ipi(vector: IpiVector) {
+    percpu!(stats.ipis).fetch_add(1, Ordering::Relaxed);
+    crate::interrupts::count(vector.as_u8());
+}
+
+pub extern "C" fn reschedule_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+    {
+        let _latency = latency::measure(IpiVector::Reschedule.as_u8());
+        count_ipi(IpiVector::Reschedule);
+        lapic::end_of_interrupt();
+    }
+    thread::preempt_if_needed();
+}
+
+pub extern "C" fn tlb_shootdown_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+    let _latency = latency::measure(IpiVector::TlbShootdown.as_u8());
+    count_ipi(IpiVector::TlbShootdown);
+    SHOOTDOWNS.serve();
+    lapic::end_of_interrupt();
+}
+
+pub extern "C" fn call_function_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+    let _latency = latency::measure(IpiVector::CallFunction.as_u8());
+    count_ipi(IpiVector::CallFunction);
+    CALLS.serve();
+    lapic::end_of_interrupt();
+}
+
+pub extern "C" fn halt_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+    interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Spurious interrupts are not acknowledged, the APIC did not count them as in service.
+pub extern "C" fn spurious_interrupt_handler(_stack_frame: &ExceptionStackFrame) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test_case]
+    fn test_call_on_each_cpu() {
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        let before = CALLED.load(Ordering::SeqCst);
+        call_on_each_cpu(|| {
+            assert!(!interrupts::are_enabled());
+            CALLED.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(CALLED.load(Ordering::SeqCst) - before, online_cpus());
+        flush_tlb(None);
+    }
+
+    #[cfg(feature = "smp")]
+    #[test_case]
+    fn test_reschedule_ipi_to_self() {
+        let before = percpu!(stats.ipis).load(Ordering::Relaxed);
+        reschedule(percpu::this_cpu());
+        // a fixed IPI to ourselves arrives as soon as interrupts are enabled
+        while percpu!(stats.ipis).load(Ordering::Relaxed) == before {
+            core::hint::spin_loop();
+        }
+        assert_eq!(percpu!(stats.ipis).load(Ordering::Relaxed), before + 1);
+    }
+}
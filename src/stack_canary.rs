@@ -0,0 +1,49 @@
+/* stack canaries.
+    The lowest words of every kernel stack hold a known value, so a stack that grew
+    into them has overflowed. Thread stacks come from the heap without a guard page
+    below them, and an overflow writes over whatever was allocated there instead of
+    faulting. Thread stacks are checked when their thread is switched away from and
+    on every timer tick: late, but before the damage spreads far. So is the double
+    fault stack, for the time before its guard page is unmapped.
+ */
+
+pub const WORDS: usize = 4;
+const VALUE: u64 = 0x5ca1_ab1e_57ac_c0de;
+
+/// Writes the canary at the lowest address of a stack.
+///
+/// # Safety
+///
+/// `bottom` must be the 8 byte aligned start of a stack at least `WORDS` words long.
+pub unsafe fn plant(bottom: u64) {
+    let words = bottom as *mut u64;
+    for index in 0..WORDS {
+        unsafe { words.add(index).write_volatile(VALUE) };
+    }
+}
+
+/// Whether the canary `plant` wrote at `bottom` is still there.
+///
+/// # Safety
+///
+/// As for `plant`.
+pub unsafe fn is_intact(bottom: u64) -> bool {
+    let words = bottom as *const u64;
+    (0..WORDS).all(|index| unsafe { words.add(index).read_volatile() } == VALUE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_plant_and_smash() {
+        let mut stack = [0u64; 16];
+        let bottom = stack.as_mut_ptr() as u64;
+        unsafe { plant(bottom) };
+        assert!(unsafe { is_intact(bottom) });
+        // through the pointer, the array itself is not read again
+        unsafe { (bottom as *mut u64).add(WORDS - 1).write_volatile(0) };
+        assert!(unsafe { !is_intact(bottom) });
+    }
+}
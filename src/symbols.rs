@@ -0,0 +1,96 @@
+use core::hint::black_box;
+
+/* the kernel's symbol table.
+    Space for it is reserved in the `.ksyms` section, and `ksyms.sh` fills it in after
+    linking, which the cargo runner and build-image.sh do. It is text: a "KSYM" line,
+    then one line per function, sorted by address,
+        ffffffff80012340 blog_os::thread::scheduler::Scheduler::schedule
+    so backtraces show `function+offset` rather than bare addresses. A kernel built
+    without the script just has no names.
+ */
+
+pub const TABLE_SIZE: usize = 1024 * 1024;
+const MAGIC: &[u8] = b"KSYM\n";
+const ADDRESS_LEN: usize = 16;
+
+const fn empty_table() -> [u8; TABLE_SIZE] {
+    let mut table = [0; TABLE_SIZE];
+    let mut index = 0;
+    while index < MAGIC.len() {
+        table[index] = MAGIC[index];
+        index += 1;
+    }
+    table
+}
+
+// written into the kernel file, never by the kernel
+#[used]
+#[link_section = ".ksyms"]
+static TABLE: [u8; TABLE_SIZE] = empty_table();
+
+fn table() -> &'static [u8] {
+    // hidden from the compiler, which would otherwise read the empty table it built
+    let start = black_box(TABLE.as_ptr());
+    let table = unsafe { core::slice::from_raw_parts(start, TABLE_SIZE) };
+    let len = table.iter().position(|&byte| byte == 0).unwrap_or(TABLE_SIZE);
+    &table[..len]
+}
+
+fn parse_line(line: &[u8]) -> Option<(u64, &str)> {
+    let address = core::str::from_utf8(line.get(..ADDRESS_LEN)?).ok()?;
+    let name = core::str::from_utf8(line.get(ADDRESS_LEN + 1..)?).ok()?;
+    Some((u64::from_str_radix(address, 16).ok()?, name))
+}
+
+/// The last symbol at or below `address` in `table`, and how far past it `address` is.
+fn find(table: &[u8], address: u64) -> Option<(&str, u64)> {
+    let lines = table.strip_prefix(MAGIC)?.split(|&byte| byte == b'\n');
+    let mut found = None;
+    for (start, name) in lines.filter_map(parse_line) {
+        if start > address {
+            break;
+        }
+        found = Some((name, address - start));
+    }
+    found
+}
+
+/// The function `address` is in, and its offset from the start; `None` without a table.
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+    find(table(), address)
+}
+
+/// Whether `ksyms.sh` filled in the table.
+pub fn is_loaded() -> bool {
+    table().len() > MAGIC.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_find() {
+        let table = b"KSYM\n\
+            0000000000001000 first\n\
+            0000000000001040 <T as core::fmt::Display>::fmt\n\
+            0000000000002000 last";
+        assert_eq!(find(table, 0x0fff), None);
+        assert_eq!(find(table, 0x1000), Some(("first", 0)));
+        assert_eq!(find(table, 0x1044), Some(("<T as core::fmt::Display>::fmt", 4)));
+        assert_eq!(find(table, 0x2100), Some(("last", 0x100)));
+        assert_eq!(find(b"garbage", 0x1000), None);
+    }
+
+    #[test_case]
+    fn test_resolve() {
+        // the test runner embeds the table, a plain cargo build does not
+        if !is_loaded() {
+            return;
+        }
+        let start = resolve as *const () as u64;
+        let (name, offset) = resolve(start + 4).unwrap();
+        assert!(name.ends_with("symbols::resolve"), "{}", name);
+        assert_eq!(offset, 4);
+    }
+}
@@ -0,0 +1,337 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use crate::thread::WaitQueue;
+
+/* bounded channels.
+    `try_send` never blocks or allocates and only touches interrupt-safe locks, so
+    interrupt handlers can hand work to a thread or an async task with it. On
+    the receiving side a thread sleeps in `recv`, and a task polls the receiver as a
+    `Stream`. Either way there is exactly one receiver.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+/// Every sender is gone and nothing is left to receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+/// Lock-free ring for one producer and one consumer, cheaper than the multi-producer queue.
+struct SpscRing<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    // both only ever grow, the slot is the index modulo the capacity
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for SpscRing<T> {}
+unsafe impl<T: Send> Sync for SpscRing<T> {}
+
+impl<T> SpscRing<T> {
+    fn new(capacity: usize) -> Self {
+        let slots = (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+        SpscRing { slots, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == self.slots.len() {
+            return Err(value);
+        }
+        unsafe { (*self.slots[tail % self.slots.len()].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.slots[head % self.slots.len()].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T> Drop for SpscRing<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+enum Buffer<T> {
+    Multi(Box<ArrayQueue<T>>),
+    Single(SpscRing<T>),
+}
+
+struct Shared<T> {
+    buffer: Buffer<T>,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    // a thread blocked in `recv`, or the task polling the stream
+    receiver: WaitQueue,
+    waker: AtomicWaker,
+    // threads blocked in `send` waiting for room
+    room: WaitQueue,
+}
+
+impl<T> Shared<T> {
+    fn new(buffer: Buffer<T>) -> Arc<Self> {
+        Arc::new(Shared {
+            buffer,
+            senders: AtomicUsize::new(1),
+            receiver_alive: AtomicBool::new(true),
+            receiver: WaitQueue::new(),
+            waker: AtomicWaker::new(),
+            room: WaitQueue::new(),
+        })
+    }
+
+    fn push(&self, value: T) -> Result<(), T> {
+        match &self.buffer {
+            Buffer::Multi(queue) => queue.push(value),
+            Buffer::Single(ring) => ring.push(value),
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        match &self.buffer {
+            Buffer::Multi(queue) => queue.pop(),
+            Buffer::Single(ring) => ring.pop(),
+        }
+    }
+
+    fn wake_receiver(&self) {
+        self.receiver.wake_one();
+        self.waker.wake();
+    }
+
+    fn disconnected(&self) -> bool {
+        self.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Never blocks, safe to call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(value));
+        }
+        self.shared.push(value).map_err(TrySendError::Full)?;
+        self.shared.wake_receiver();
+        Ok(())
+    }
+
+    /// Sleeps while the channel is full, for threads only. Hands the value back if the receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        let mut result = Ok(());
+        self.shared.room.wait_until(|| match self.try_send(value.take().unwrap()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(back)) => {
+                value = Some(back);
+                false
+            }
+            Err(TrySendError::Disconnected(back)) => {
+                result = Err(back);
+                true
+            }
+        });
+        result
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.wake_receiver();
+        }
+    }
+}
+
+/// The only producer of a single-producer channel, unlike `Sender` it cannot be cloned.
+pub struct SpscSender<T> {
+    sender: Sender<T>,
+}
+
+impl<T> SpscSender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.sender.try_send(value)
+    }
+
+    pub fn send(&self, value: T) -> Result<(), T> {
+        self.sender.send(value)
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    // a shared receiver would be two, the single consumer ring relies on there being one
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.shared.pop() {
+            Some(value) => {
+                self.shared.room.wake_one();
+                Ok(value)
+            }
+            // a last send may land right before the sender count drops, look once more
+            None if self.shared.disconnected() => self.shared.pop().ok_or(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Sleeps until a value arrives, fails once every sender is dropped and the buffer is drained.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut result = Err(RecvError);
+        self.shared.receiver.wait_until(|| match self.try_recv() {
+            Ok(value) => {
+                result = Ok(value);
+                true
+            }
+            Err(TryRecvError::Disconnected) => true,
+            Err(TryRecvError::Empty) => false,
+        });
+        result
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+        self.shared.room.wake_all();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.shared.waker.register(cx.waker());
+        // a value may have arrived before the waker was registered
+        match self.try_recv() {
+            Ok(value) => {
+                self.shared.waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+/// A bounded channel any number of senders can feed.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Shared::new(Buffer::Multi(Box::new(ArrayQueue::new(capacity))));
+    (Sender { shared: shared.clone() }, Receiver { shared, _not_sync: PhantomData })
+}
+
+/// A bounded channel with a single sender, e.g. one interrupt handler feeding one thread.
+pub fn spsc_channel<T>(capacity: usize) -> (SpscSender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Shared::new(Buffer::Single(SpscRing::new(capacity)));
+    (SpscSender { sender: Sender { shared: shared.clone() } }, Receiver { shared, _not_sync: PhantomData })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::spawn;
+    use alloc::vec::Vec;
+    use futures_util::task::noop_waker_ref;
+    use futures_util::StreamExt;
+
+    #[test_case]
+    fn test_spsc_order_and_capacity() {
+        let (sender, receiver) = spsc_channel(2);
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.try_send(2), Ok(()));
+        assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(sender.try_send(3), Ok(()));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Ok(3));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test_case]
+    fn test_blocking_recv_from_many_senders() {
+        let (sender, receiver) = channel(4);
+        let producers: Vec<_> = (0..3u64).map(|producer| {
+            let sender = sender.clone();
+            spawn("channel-producer", move || {
+                for i in 0..10 {
+                    sender.send(producer * 100 + i).unwrap();
+                }
+            })
+        }).collect();
+        drop(sender);
+
+        let mut received = Vec::new();
+        while let Ok(value) = receiver.recv() {
+            received.push(value);
+        }
+        for producer in producers {
+            producer.join();
+        }
+        received.sort_unstable();
+        let expected: Vec<u64> = (0..3).flat_map(|producer| (0..10).map(move |i| producer * 100 + i)).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test_case]
+    fn test_stream_and_disconnect() {
+        let (sender, mut receiver) = channel(1);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(receiver.poll_next_unpin(&mut cx), Poll::Pending);
+        sender.try_send(7).unwrap();
+        assert_eq!(receiver.poll_next_unpin(&mut cx), Poll::Ready(Some(7)));
+        drop(sender);
+        assert_eq!(receiver.poll_next_unpin(&mut cx), Poll::Ready(None));
+
+        let (sender, receiver) = channel(1);
+        drop(receiver);
+        assert_eq!(sender.try_send(1), Err(TrySendError::Disconnected(1)));
+    }
+}
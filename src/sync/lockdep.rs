@@ -0,0 +1,350 @@
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use uart_16550::SerialPort;
+
+use crate::percpu;
+
+/* lock dependency checking, in debug builds.
+    Every SpinLock gets a number the first time it is taken, and each CPU keeps a list
+    of the ones it holds with the places they were taken. Taking a lock while holding
+    others records that they come first; one such order closing a cycle with orders
+    seen before means that many CPUs, each on one of the paths, can deadlock each
+    other. Taking a lock the CPU already holds spins forever, which is what happens
+    when an exception handler prints while the code it interrupted held the serial
+    port, and a sleeping Mutex must not be taken in interrupt context at all.
+
+    The first problem found is printed with every call site involved, straight to the
+    serial port since its lock may be the one at fault, and checking stops there.
+    Numbers are not reused, a lock that is freed leaves its orders behind; once the
+    table of orders is full checking stops too, saying so, rather than going on with
+    orders it can no longer record.
+ */
+
+const MAX_HELD: usize = 16;
+const MAX_ORDERS: usize = 1024;
+const COM1: u16 = 0x3F8;
+
+type Site = &'static Location<'static>;
+
+/// A lock's number, 0 until it is first taken.
+pub struct LockId(AtomicU32);
+
+impl LockId {
+    pub const fn new() -> Self {
+        LockId(AtomicU32::new(0))
+    }
+
+    fn get(&self) -> u32 {
+        static NEXT: AtomicU32 = AtomicU32::new(1);
+        match self.0.load(Ordering::Relaxed) {
+            0 => {
+                let id = NEXT.fetch_add(1, Ordering::Relaxed);
+                match self.0.compare_exchange(0, id, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => id,
+                    Err(other) => other,
+                }
+            }
+            id => id,
+        }
+    }
+}
+
+impl Default for LockId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    lock: u32,
+    site: Site,
+}
+
+struct HeldStack {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+/// The locks a CPU holds, only touched by that CPU with interrupts disabled.
+pub struct HeldLocks(UnsafeCell<HeldStack>);
+
+unsafe impl Sync for HeldLocks {}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        HeldLocks(UnsafeCell::new(HeldStack { locks: [None; MAX_HELD], len: 0 }))
+    }
+}
+
+impl Default for HeldLocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeldStack {
+    fn held(&self) -> impl Iterator<Item = Held> + '_ {
+        self.locks[..self.len].iter().flatten().copied()
+    }
+
+    fn push(&mut self, held: Held) {
+        // deeper nesting goes unchecked
+        if self.len < MAX_HELD {
+            self.locks[self.len] = Some(held);
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, lock: u32) {
+        // usually the last one, guards may be dropped in any order though
+        if let Some(index) = self.locks[..self.len].iter().rposition(|held| held.is_some_and(|held| held.lock == lock)) {
+            self.locks.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+            self.locks[self.len] = None;
+        }
+    }
+}
+
+/// `after` was taken while `before` was held.
+#[derive(Clone, Copy)]
+struct Order {
+    before: Held,
+    after: Held,
+}
+
+struct Orders<const N: usize> {
+    orders: [Option<Order>; N],
+    len: usize,
+    // scratch for `path`, too big for the stacks it runs on
+    queue: [u16; N],
+    reached_by: [Option<u16>; N],
+}
+
+impl<const N: usize> Orders<N> {
+    const fn new() -> Self {
+        Orders { orders: [None; N], len: 0, queue: [0; N], reached_by: [None; N] }
+    }
+
+    fn order(&self, index: usize) -> Order {
+        self.orders[index].expect("orders are never removed")
+    }
+
+    fn contains(&self, before: u32, after: u32) -> bool {
+        (0..self.len).map(|index| self.order(index)).any(|order| order.before.lock == before && order.after.lock == after)
+    }
+
+    /// Records an order unless it is full, returns whether it did.
+    fn insert(&mut self, order: Order) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.orders[self.len] = Some(order);
+        self.len += 1;
+        true
+    }
+
+    /// Walks the orders breadth first from `from`; calls `f` with those on a way to
+    /// `to`, last first, and returns whether there is one.
+    fn path(&mut self, from: u32, to: u32, mut f: impl FnMut(Order)) -> bool {
+        self.reached_by = [None; N];
+        let (mut head, mut tail) = (0, 0);
+        for index in 0..self.len {
+            if self.order(index).before.lock == from {
+                self.reached_by[index] = Some(index as u16);
+                self.queue[tail] = index as u16;
+                tail += 1;
+            }
+        }
+        while head < tail {
+            let index = self.queue[head] as usize;
+            head += 1;
+            let order = self.order(index);
+            if order.after.lock == to {
+                let mut index = index;
+                loop {
+                    f(self.order(index));
+                    match self.reached_by[index] {
+                        Some(previous) if previous as usize != index => index = previous as usize,
+                        _ => return true,
+                    }
+                }
+            }
+            for next in 0..self.len {
+                if self.reached_by[next].is_none() && self.order(next).before.lock == order.after.lock {
+                    self.reached_by[next] = Some(index as u16);
+                    self.queue[tail] = next as u16;
+                    tail += 1;
+                }
+            }
+        }
+        false
+    }
+}
+
+// release builds compile the checks out
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+static ORDERS: spin::Mutex<Orders<MAX_ORDERS>> = spin::Mutex::new(Orders::new());
+
+/// Prints a report and stops checking, only the first problem is told.
+fn report(f: impl FnOnce(&mut SerialPort) -> fmt::Result) {
+    if !ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    // not through SERIAL1, its lock may be the one in trouble
+    let mut report = unsafe { SerialPort::new(COM1) };
+    let _ = writeln!(report, "\nlockdep: possible deadlock on cpu {}", percpu::this_cpu().cpu_id);
+    let _ = f(&mut report);
+    let _ = writeln!(report, "lockdep: turning off lock checking");
+}
+
+/// Stops checking for a reason other than a problem found.
+fn give_up(reason: &str) {
+    if !ENABLED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let mut port = unsafe { SerialPort::new(COM1) };
+    let _ = writeln!(port, "\nlockdep: {}, turning off lock checking", reason);
+}
+
+/// Calls `f` with the running CPU's held locks, nothing is tracked before its block is set up.
+fn with_held_stack(f: impl FnOnce(&mut HeldStack)) {
+    if let Some(cpu) = percpu::try_this_cpu() {
+        // only this CPU touches it, with interrupts off
+        f(unsafe { &mut *cpu.held_locks.0.get() });
+    }
+}
+
+fn is_enabled() -> bool {
+    cfg!(debug_assertions) && ENABLED.load(Ordering::Relaxed)
+}
+
+/// Called with interrupts disabled before spinning for `lock`.
+#[inline]
+pub fn acquire(lock: &LockId, site: Site) {
+    if !is_enabled() {
+        return;
+    }
+    with_held_stack(|stack| {
+        let taking = Held { lock: lock.get(), site };
+        if let Some(holding) = stack.held().find(|held| held.lock == taking.lock) {
+            report(|report| {
+                writeln!(report, "lock #{} taken at {}", taking.lock, taking.site)?;
+                writeln!(report, "  is already held by this cpu, taken at {}", holding.site)?;
+                if percpu::in_interrupt() {
+                    writeln!(report, "  from interrupt context")?;
+                }
+                Ok(())
+            });
+            return;
+        }
+        for before in stack.held() {
+            check_order(Order { before, after: taking });
+        }
+        stack.push(taking);
+    });
+}
+
+fn check_order(order: Order) {
+    let mut orders = ORDERS.lock();
+    if orders.contains(order.before.lock, order.after.lock) {
+        return;
+    }
+    // a way back from the lock being taken to the one held closes a cycle
+    let mut cycle: [Option<Order>; 8] = [None; 8];
+    let mut len = 0;
+    let found = orders.path(order.after.lock, order.before.lock, |earlier| {
+        if len < cycle.len() {
+            cycle[len] = Some(earlier);
+            len += 1;
+        }
+    });
+    if !found {
+        if !orders.insert(order) {
+            drop(orders);
+            give_up("no room for more lock orders");
+        }
+        return;
+    }
+    drop(orders);
+    report(|report| {
+        writeln!(report, "lock #{} taken at {}", order.after.lock, order.after.site)?;
+        writeln!(report, "  while holding lock #{} taken at {}", order.before.lock, order.before.site)?;
+        writeln!(report, "but before that, the other way around:")?;
+        for earlier in cycle.iter().flatten().rev() {
+            writeln!(report, "lock #{} taken at {}", earlier.after.lock, earlier.after.site)?;
+            writeln!(report, "  while holding lock #{} taken at {}", earlier.before.lock, earlier.before.site)?;
+        }
+        Ok(())
+    });
+}
+
+/// Called once a `try_lock` got the lock; it did not wait, so it adds no order.
+#[inline]
+pub fn acquired_without_waiting(lock: &LockId, site: Site) {
+    if is_enabled() {
+        with_held_stack(|stack| stack.push(Held { lock: lock.get(), site }));
+    }
+}
+
+/// Called with interrupts still disabled when `lock` is released.
+#[inline]
+pub fn release(lock: &LockId) {
+    if is_enabled() {
+        with_held_stack(|stack| stack.remove(lock.get()));
+    }
+}
+
+/// Called by `Mutex::lock`, which may sleep.
+#[inline]
+pub fn sleeping_lock(site: Site) {
+    if is_enabled() && percpu::try_this_cpu().is_some() && percpu::in_interrupt() {
+        report(|report| writeln!(report, "sleeping mutex taken at {}\n  from interrupt context", site));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn order(before: u32, after: u32) -> Order {
+        let site = Location::caller();
+        Order { before: Held { lock: before, site }, after: Held { lock: after, site } }
+    }
+
+    #[test_case]
+    fn test_cycles() {
+        let mut orders = Orders::<8>::new();
+        for (before, after) in [(1, 2), (2, 3), (3, 4), (5, 1)] {
+            assert!(orders.insert(order(before, after)));
+        }
+        assert!(orders.contains(2, 3) && !orders.contains(3, 2));
+        let mut full = Orders::<2>::new();
+        assert!(full.insert(order(1, 2)) && full.insert(order(2, 3)));
+        assert!(!full.insert(order(3, 4)));
+
+        // 1 taken while holding 4 would close 1 -> 2 -> 3 -> 4
+        assert!(!orders.path(4, 1, |_| {}));
+        let mut path = [0; 3];
+        let mut len = 0;
+        assert!(orders.path(1, 4, |earlier| {
+            path[len] = earlier.after.lock;
+            len += 1;
+        }));
+        assert_eq!(path, [4, 3, 2]);
+        assert!(!orders.path(4, 5, |_| {}));
+    }
+
+    #[test_case]
+    fn test_held_stack() {
+        let mut stack = HeldStack { locks: [None; MAX_HELD], len: 0 };
+        let site = Location::caller();
+        for lock in 1..=3 {
+            stack.push(Held { lock, site });
+        }
+        stack.remove(2);
+        assert_eq!(stack.held().map(|held| held.lock).collect::<alloc::vec::Vec<_>>(), [1, 3]);
+    }
+}
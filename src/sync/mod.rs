@@ -0,0 +1,12 @@
+pub mod channel;
+pub mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spin_lock;
+
+pub use channel::{channel, spsc_channel, Receiver, Sender, SpscSender};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard, SpinRwLock, SpinRwLockReadGuard, SpinRwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use spin_lock::{SpinLock, SpinLockGuard};
@@ -0,0 +1,240 @@
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+
+use super::{lockdep, SpinLock};
+use crate::thread::{self, Priority, ThreadId, WaitQueue};
+
+/* a sleeping lock for kernel threads.
+    Contended callers block on a wait queue instead of spinning, so it may be held
+    across anything that sleeps, but never taken from an interrupt handler.
+
+    With priority inheritance a waiter lends its priority to the owner, so a low
+    priority owner cannot be starved by medium priority threads while something more
+    important waits for it. The boost ends when the owner unlocks, what waiters on
+    other mutexes it holds lent stays; whoever gets the lock next is lent the
+    priorities of those still waiting.
+ */
+pub struct Mutex<T: ?Sized> {
+    owner: SpinLock<Option<ThreadId>>,
+    waiters: WaitQueue,
+    inherit_priority: bool,
+    // with priority inheritance, who waits and with what priority
+    waiting: SpinLock<Vec<(ThreadId, Priority)>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::with_inheritance(value, false)
+    }
+
+    pub const fn with_priority_inheritance(value: T) -> Self {
+        Self::with_inheritance(value, true)
+    }
+
+    const fn with_inheritance(value: T, inherit_priority: bool) -> Self {
+        Mutex {
+            owner: SpinLock::new(None),
+            waiters: WaitQueue::new(),
+            inherit_priority,
+            waiting: SpinLock::new(Vec::new()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    // what the owner's lent priorities are recorded under
+    fn key(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    fn try_acquire(&self, id: ThreadId) -> Result<(), ThreadId> {
+        let mut owner = self.owner.lock();
+        match *owner {
+            None => {
+                *owner = Some(id);
+                Ok(())
+            }
+            Some(holder) => Err(holder),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        lockdep::sleeping_lock(Location::caller());
+        let id = thread::current_id();
+        if self.try_acquire(id).is_err() {
+            let priority = self.inherit_priority.then(thread::current_priority);
+            if let Some(priority) = priority {
+                self.waiting.lock().push((id, priority));
+            }
+            self.waiters.wait_until(|| match self.try_acquire(id) {
+                Ok(()) => true,
+                Err(holder) => {
+                    assert_ne!(holder, id, "thread {} locked a mutex it already holds", id.as_u64());
+                    if let Some(priority) = priority {
+                        thread::inherit_priority(holder, self.key(), priority);
+                    }
+                    false
+                }
+            });
+            if priority.is_some() {
+                let still_waiting = {
+                    let mut waiting = self.waiting.lock();
+                    waiting.retain(|&(waiter, _)| waiter != id);
+                    waiting.iter().map(|&(_, priority)| priority).max()
+                };
+                if let Some(priority) = still_waiting {
+                    thread::inherit_priority(id, self.key(), priority);
+                }
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_acquire(thread::current_id()).ok()?;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.lock().is_some()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        *self.owner.lock() = None;
+        if self.inherit_priority {
+            thread::drop_inherited_priority(self.key());
+        }
+        self.waiters.wake_one();
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::{spawn, spawn_with_priority, yield_now, Priority};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[test_case]
+    fn test_contended_lock_sleeps() {
+        let counter = Arc::new(Mutex::new(0u64));
+        let workers: alloc::vec::Vec<_> = (0..4).map(|_| {
+            let counter = counter.clone();
+            spawn("mutex-worker", move || {
+                for _ in 0..100 {
+                    let mut value = counter.lock();
+                    let read = *value;
+                    // give the others a chance to run into the held lock
+                    yield_now();
+                    *value = read + 1;
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join();
+        }
+        assert_eq!(*counter.lock(), 400);
+        assert!(counter.try_lock().is_some());
+    }
+
+    #[test_case]
+    fn test_owner_inherits_waiter_priority() {
+        static RELEASE: AtomicBool = AtomicBool::new(false);
+        let mutex = Arc::new(Mutex::with_priority_inheritance(()));
+        let guard = mutex.lock();
+        let own = thread::current_priority();
+
+        let waiter = {
+            let mutex = mutex.clone();
+            spawn_with_priority("mutex-waiter", Priority::HIGH, move || {
+                drop(mutex.lock());
+                RELEASE.store(true, Ordering::SeqCst);
+            })
+        };
+        // the waiter ran right away, found the lock taken and lent us its priority
+        assert_eq!(thread::current_priority(), Priority::HIGH.max(own));
+        drop(guard);
+        assert!(RELEASE.load(Ordering::SeqCst));
+        assert_eq!(thread::current_priority(), own);
+        waiter.join();
+    }
+
+    #[test_case]
+    fn test_unlock_keeps_what_other_waiters_lent() {
+        let first = Arc::new(Mutex::with_priority_inheritance(()));
+        let second = Arc::new(Mutex::with_priority_inheritance(()));
+        let first_guard = first.lock();
+        let second_guard = second.lock();
+        let own = thread::current_priority();
+
+        let waiters = [(&second, Priority::HIGH), (&first, Priority::REALTIME)].map(|(mutex, priority)| {
+            let mutex = mutex.clone();
+            spawn_with_priority("mutex-waiter", priority, move || drop(mutex.lock()))
+        });
+        assert_eq!(thread::current_priority(), Priority::REALTIME.max(own));
+        // the waiter on the second mutex still waits on us
+        drop(first_guard);
+        assert_eq!(thread::current_priority(), Priority::HIGH.max(own));
+        drop(second_guard);
+        assert_eq!(thread::current_priority(), own);
+        for waiter in waiters {
+            waiter.join();
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+use super::SpinLock;
+use crate::thread::WaitQueue;
+
+/* writer preference.
+    Both variants let any number of readers in at once, but only while no writer holds
+    or waits for the lock. A steady stream of readers can therefore never keep a
+    writer out, new readers queue up behind it instead.
+ */
+
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// Spinning reader-writer lock, interrupts stay disabled while it is held like with `SpinLock`.
+pub struct SpinRwLock<T: ?Sized> {
+    // reader count, or WRITER
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for SpinRwLock<T> {}
+
+pub struct SpinRwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SpinRwLock<T>,
+    interrupts_were_enabled: bool,
+}
+
+pub struct SpinRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SpinRwLock<T>,
+    interrupts_were_enabled: bool,
+}
+
+fn disable_interrupts() -> bool {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    enabled
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+impl<T> SpinRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinRwLock { state: AtomicUsize::new(0), waiting_writers: AtomicUsize::new(0), data: UnsafeCell::new(value) }
+    }
+}
+
+impl<T: ?Sized> SpinRwLock<T> {
+    fn try_acquire_read(&self) -> bool {
+        if self.waiting_writers.load(Ordering::Relaxed) != 0 {
+            return false;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        state & WRITER == 0
+            && self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn read(&self) -> SpinRwLockReadGuard<'_, T> {
+        let interrupts_were_enabled = disable_interrupts();
+        while !self.try_acquire_read() {
+            spin_loop();
+        }
+        SpinRwLockReadGuard { lock: self, interrupts_were_enabled }
+    }
+
+    pub fn write(&self) -> SpinRwLockWriteGuard<'_, T> {
+        let interrupts_were_enabled = disable_interrupts();
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        while !self.try_acquire_write() {
+            spin_loop();
+        }
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+        SpinRwLockWriteGuard { lock: self, interrupts_were_enabled }
+    }
+
+    pub fn try_read(&self) -> Option<SpinRwLockReadGuard<'_, T>> {
+        let interrupts_were_enabled = disable_interrupts();
+        // a weak exchange may fail spuriously, only give up on real contention
+        loop {
+            if self.try_acquire_read() {
+                return Some(SpinRwLockReadGuard { lock: self, interrupts_were_enabled });
+            }
+            if self.waiting_writers.load(Ordering::Relaxed) != 0 || self.state.load(Ordering::Relaxed) & WRITER != 0 {
+                restore_interrupts(interrupts_were_enabled);
+                return None;
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<SpinRwLockWriteGuard<'_, T>> {
+        let interrupts_were_enabled = disable_interrupts();
+        if self.try_acquire_write() {
+            Some(SpinRwLockWriteGuard { lock: self, interrupts_were_enabled })
+        } else {
+            restore_interrupts(interrupts_were_enabled);
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for SpinRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        restore_interrupts(self.interrupts_were_enabled);
+    }
+}
+
+impl<T: ?Sized> Deref for SpinRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        restore_interrupts(self.interrupts_were_enabled);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("SpinRwLock").field("data", &&*guard).finish(),
+            None => f.write_str("SpinRwLock { <locked> }"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+/// Sleeping reader-writer lock for kernel threads, not to be taken from interrupt handlers.
+pub struct RwLock<T: ?Sized> {
+    state: SpinLock<State>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: SpinLock::new(State { readers: 0, writer: false, waiting_writers: 0 }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn try_acquire_read(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer || state.waiting_writers > 0 {
+            return false;
+        }
+        state.readers += 1;
+        true
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return false;
+        }
+        state.writer = true;
+        true
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.readers.wait_until(|| self.try_acquire_read());
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if !self.try_acquire_write() {
+            self.state.lock().waiting_writers += 1;
+            self.writers.wait_until(|| self.try_acquire_write());
+            self.state.lock().waiting_writers -= 1;
+        }
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.try_acquire_read().then_some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.try_acquire_write().then_some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn read_unlock(&self) {
+        let last = {
+            let mut state = self.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        };
+        if last {
+            self.writers.wake_one();
+        }
+    }
+
+    fn write_unlock(&self) {
+        let writers_waiting = {
+            let mut state = self.state.lock();
+            state.writer = false;
+            state.waiting_writers > 0
+        };
+        if writers_waiting {
+            self.writers.wake_one();
+        } else {
+            self.readers.wake_all();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.write_str("RwLock { <locked> }"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::{spawn, yield_now};
+    use alloc::sync::Arc;
+
+    #[test_case]
+    fn test_spin_readers_share() {
+        let lock = SpinRwLock::new(1);
+        {
+            let first = lock.read();
+            let second = lock.read();
+            assert_eq!(*first + *second, 2);
+            assert!(lock.try_write().is_none());
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        *lock.write() += 1;
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test_case]
+    fn test_waiting_writer_keeps_new_readers_out() {
+        let lock = Arc::new(RwLock::new(0));
+        let reader = lock.read();
+        let writer = {
+            let lock = lock.clone();
+            spawn("rwlock-writer", move || *lock.write() = 1)
+        };
+        while lock.state.lock().waiting_writers == 0 {
+            yield_now();
+        }
+        // only readers hold the lock, but the writer goes first
+        assert!(lock.try_read().is_none());
+        drop(reader);
+        writer.join();
+        assert_eq!(*lock.read(), 1);
+    }
+}
@@ -0,0 +1,81 @@
+use super::SpinLock;
+use crate::thread::WaitQueue;
+
+/// A counting semaphore, `acquire` sleeps while no permits are left.
+pub struct Semaphore {
+    permits: SpinLock<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { permits: SpinLock::new(permits), waiters: WaitQueue::new() }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.lock();
+        if *permits == 0 {
+            return false;
+        }
+        *permits -= 1;
+        true
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn release(&self) {
+        *self.permits.lock() += 1;
+        self.waiters.wake_one();
+    }
+
+    /// A permit handed back when the returned guard is dropped.
+    pub fn access(&self) -> SemaphorePermit<'_> {
+        self.acquire();
+        SemaphorePermit { semaphore: self }
+    }
+
+    pub fn available(&self) -> usize {
+        *self.permits.lock()
+    }
+}
+
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::{spawn, yield_now};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test_case]
+    fn test_permits_limit_concurrency() {
+        static SEMAPHORE: Semaphore = Semaphore::new(2);
+        static INSIDE: AtomicUsize = AtomicUsize::new(0);
+        static MOST: AtomicUsize = AtomicUsize::new(0);
+
+        let workers: alloc::vec::Vec<_> = (0..5).map(|_| spawn("semaphore-worker", || {
+            let _permit = SEMAPHORE.access();
+            let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+            MOST.fetch_max(inside, Ordering::SeqCst);
+            yield_now();
+            INSIDE.fetch_sub(1, Ordering::SeqCst);
+        })).collect();
+        for worker in workers {
+            worker.join();
+        }
+        assert_eq!(MOST.load(Ordering::SeqCst), 2);
+        assert_eq!(SEMAPHORE.available(), 2);
+        assert!(SEMAPHORE.try_acquire());
+        SEMAPHORE.release();
+    }
+}
@@ -0,0 +1,157 @@
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+use super::lockdep::{self, LockId};
+
+/* a spinlock that is safe to share with interrupt handlers.
+    Interrupts are disabled before the lock is taken and stay off while it is held,
+    so a handler can never spin on a lock the code it interrupted is holding. The
+    previous interrupt state comes back when the guard is dropped, which makes
+    nesting inside `without_interrupts` or other spinlocks fine. Debug builds check
+    the order locks are taken in, see lockdep.rs.
+ */
+pub struct SpinLock<T: ?Sized> {
+    id: LockId,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized + 'a> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    id: &'a LockId,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock { id: LockId::new(), inner: spin::Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(&self.id, Location::caller());
+        SpinLockGuard { guard: ManuallyDrop::new(self.inner.lock()), id: &self.id, interrupts_were_enabled }
+    }
+
+    /// Takes the lock if it is free, leaving the interrupt state alone otherwise.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lockdep::acquired_without_waiting(&self.id, Location::caller());
+                Some(SpinLockGuard { guard: ManuallyDrop::new(guard), id: &self.id, interrupts_were_enabled })
+            }
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Releases the lock without a guard, for code that will never return to the holder.
+    ///
+    /// # Safety
+    ///
+    /// The holder must not touch the data again, it would race with the new owner.
+    pub unsafe fn force_unlock(&self) {
+        lockdep::release(&self.id);
+        unsafe { self.inner.force_unlock() };
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock first, an interrupt right after enabling may want the lock
+        lockdep::release(self.id);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner.try_lock() {
+            Some(guard) => f.debug_struct("SpinLock").field("data", &&*guard).finish(),
+            None => f.write_str("SpinLock { <locked> }"),
+        }
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    #[test_case]
+    fn test_interrupts_off_while_held() {
+        let lock = SpinLock::new(0);
+        assert!(interrupts::are_enabled());
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+            assert!(lock.try_lock().is_none());
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*lock.lock(), 1);
+    }
+
+    #[test_case]
+    fn test_nested_restores_outer_state() {
+        let outer = SpinLock::new(());
+        let inner = SpinLock::new(());
+        let outer_guard = outer.lock();
+        drop(inner.lock());
+        assert!(!interrupts::are_enabled());
+        drop(outer_guard);
+        assert!(interrupts::are_enabled());
+
+        without_interrupts(|| drop(inner.lock()));
+        assert!(interrupts::are_enabled());
+    }
+}
@@ -0,0 +1,144 @@
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+
+use super::{dispatch, SyscallArgs};
+use crate::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::percpu;
+use crate::percpu::{KERNEL_RSP_OFFSET, USER_RSP_OFFSET};
+
+/* user registers as saved on the kernel stack, lowest address first.
+    The general purpose registers are pushed by the entry stubs, the last five words
+    are an interrupt frame: pushed by the CPU for `int 0x80`, and built by hand for
+    `syscall`, so both paths leave through the same `iretq`.
+ */
+#[derive(Debug, Default, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    pub fn args(&self) -> SyscallArgs {
+        SyscallArgs::new([self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9])
+    }
+}
+
+/// Top of the running thread's kernel stack, `syscall` does not switch stacks by itself.
+pub fn set_kernel_stack(top: u64) {
+    percpu!(kernel_rsp).store(top & !0xf, Ordering::Relaxed);
+}
+
+#[naked]
+pub(super) extern "C" fn syscall_entry() -> ! {
+    unsafe {
+        asm!(
+            // rcx holds the user rip, r11 the user rflags, rsp is still the user's
+            "swapgs",
+            "mov qword ptr gs:[{user_rsp}], rsp",
+            "mov rsp, qword ptr gs:[{kernel_rsp}]",
+            "push {user_ss}",
+            "push qword ptr gs:[{user_rsp}]",
+            "push r11",
+            "push {user_cs}",
+            "push rcx",
+            "jmp {common}",
+            user_rsp = const USER_RSP_OFFSET,
+            kernel_rsp = const KERNEL_RSP_OFFSET,
+            user_ss = const USER_DATA_SELECTOR as u64,
+            user_cs = const USER_CODE_SELECTOR as u64,
+            common = sym syscall_common,
+            options(noreturn)
+        );
+    }
+}
+
+/// Gate for `int 0x80`, which leaves the GS base to the entry code.
+#[naked]
+pub(crate) extern "C" fn int80_entry() -> ! {
+    unsafe {
+        asm!(
+            // the saved cs tells whether the GS base is still the user's
+            "test byte ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "jmp {common}",
+            common = sym syscall_common,
+            options(noreturn)
+        );
+    }
+}
+
+/// Where both gates continue once the interrupt frame is built and GS is the kernel's.
+#[naked]
+extern "C" fn syscall_common() -> ! {
+    unsafe {
+        asm!(
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+
+            // twenty words below an aligned top, the call sees a 16 byte aligned stack
+            "mov rdi, rsp",
+            "call {dispatch}",
+
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "test byte ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "iretq",
+            dispatch = sym dispatch,
+            options(noreturn)
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use x86_64::VirtAddr;
+
+use super::user_ptr::read_timespec;
+use super::{Errno, SyscallFrame, SyscallResult};
+use crate::process::futex::{self, FutexError};
+use crate::thread;
+use crate::time;
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+// keys are physical addresses either way, so private futexes need no special casing
+const FUTEX_PRIVATE_FLAG: u64 = 128;
+
+impl From<FutexError> for Errno {
+    fn from(err: FutexError) -> Self {
+        match err {
+            FutexError::Fault => Errno::EFAULT,
+            FutexError::Misaligned => Errno::EINVAL,
+            FutexError::WouldBlock => Errno::EAGAIN,
+            FutexError::TimedOut => Errno::ETIMEDOUT,
+            FutexError::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+/// `futex(address, op, value, timeout)`, only `FUTEX_WAIT` with a relative timeout and `FUTEX_WAKE`.
+pub fn sys_futex(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let address: VirtAddr = args.get(0)?;
+    let value: u32 = args.raw(2) as u32;
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    match args.raw(1) & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => {
+            let deadline = match args.raw(3) {
+                0 => None,
+                timeout => Some(time::ticks() + time::duration_to_ticks(read_timespec(timeout)?)),
+            };
+            futex::wait(&process, address, value, deadline)?;
+            Ok(0)
+        }
+        FUTEX_WAKE => Ok(futex::wake(&process, address, value as usize)? as u64),
+        _ => Err(Errno::ENOSYS),
+    }
+}
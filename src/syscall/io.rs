@@ -0,0 +1,151 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::process::PATH_MAX;
+use super::user_ptr::read_c_string;
+use super::{Errno, SyscallFrame, SyscallResult};
+use crate::fs::{self, File, OpenFlags, SeekFrom};
+use crate::process::FileTable;
+use crate::thread;
+
+// longest single transfer, larger requests return short counts like a pipe would
+pub(super) const MAX_TRANSFER: usize = 4096;
+
+// open flags, values as on Linux
+const O_ACCMODE: u32 = 0o3;
+const O_RDONLY: u32 = 0o0;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const O_DIRECTORY: u32 = 0o200000;
+const O_SUPPORTED: u32 = O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND | O_DIRECTORY;
+
+const SEEK_SET: u32 = 0;
+const SEEK_CUR: u32 = 1;
+const SEEK_END: u32 = 2;
+
+/// The calling process' descriptor table, locked for `f`.
+pub(super) fn with_files<R>(f: impl FnOnce(&mut FileTable) -> R) -> Result<R, Errno> {
+    let process = thread::current_process().ok_or(Errno::EBADF)?;
+    Ok(without_interrupts(|| f(process.lock().files_mut())))
+}
+
+/// The file behind `fd`, held on to so the table is not locked during the transfer.
+pub(super) fn file(fd: i32) -> Result<Arc<dyn File>, Errno> {
+    with_files(|files| files.get(fd))?.ok_or(Errno::EBADF)
+}
+
+fn open_flags(flags: u32) -> Result<OpenFlags, Errno> {
+    let mut open = match flags & O_ACCMODE {
+        O_RDONLY => OpenFlags::READ,
+        O_WRONLY => OpenFlags::WRITE,
+        O_RDWR => OpenFlags::READ | OpenFlags::WRITE,
+        _ => return Err(Errno::EINVAL),
+    };
+    // unknown bits are ignored, as Linux does
+    for (bit, flag) in [
+        (O_CREAT, OpenFlags::CREATE),
+        (O_EXCL, OpenFlags::EXCLUSIVE),
+        (O_TRUNC, OpenFlags::TRUNCATE),
+        (O_APPEND, OpenFlags::APPEND),
+        (O_DIRECTORY, OpenFlags::DIRECTORY),
+    ] {
+        if flags & O_SUPPORTED & bit != 0 {
+            open |= flag;
+        }
+    }
+    Ok(open)
+}
+
+pub fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let fd: i32 = args.get(0)?;
+    let slice = args.user_slice(1)?;
+    let file = file(fd)?;
+    if slice.is_empty() {
+        return Ok(0);
+    }
+
+    let mut buffer = vec![0u8; slice.len().min(MAX_TRANSFER)];
+    let count = file.read(&mut buffer)?;
+    slice.write(&buffer[..count])?;
+    Ok(count as u64)
+}
+
+pub fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let fd: i32 = args.get(0)?;
+    let slice = args.user_slice(1)?;
+    let file = file(fd)?;
+
+    let mut buffer = vec![0u8; slice.len().min(MAX_TRANSFER)];
+    slice.read_into(&mut buffer)?;
+    Ok(file.write(&buffer)? as u64)
+}
+
+/// `open(path, flags, mode)`, there are no permissions so `mode` is ignored.
+pub fn sys_open(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let path = read_c_string(args.raw(0), PATH_MAX)?;
+    let flags = open_flags(args.get(1)?)?;
+    let file = fs::open(&path, flags)?;
+    match with_files(|files| files.insert(file))? {
+        Some(fd) => Ok(fd as u64),
+        None => Err(Errno::EMFILE),
+    }
+}
+
+pub fn sys_close(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd: i32 = frame.args().get(0)?;
+    let file = with_files(|files| files.remove(fd))?.ok_or(Errno::EBADF)?;
+    // the last descriptor closes the file, outside the table lock
+    drop(file);
+    Ok(0)
+}
+
+/// `lseek(fd, offset, whence)`, returns the new position.
+pub fn sys_lseek(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let fd: i32 = args.get(0)?;
+    let offset: i64 = args.get(1)?;
+    let to = match args.get::<u32>(2)? {
+        SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| Errno::EINVAL)?),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(file(fd)?.seek(to)?)
+}
+
+/// `dup2(old, new)`, closes whatever `new` referred to first.
+pub fn sys_dup2(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let old: i32 = args.get(0)?;
+    let new: i32 = args.get(1)?;
+    let file = file(old)?;
+    if !FileTable::is_valid(new) {
+        return Err(Errno::EBADF);
+    }
+    if old != new {
+        let replaced = with_files(|files| files.set(new, file))?;
+        drop(replaced);
+    }
+    Ok(new as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_open_flags() {
+        assert_eq!(open_flags(O_RDONLY), Ok(OpenFlags::READ));
+        assert_eq!(open_flags(O_RDWR | O_CREAT | O_APPEND), Ok(OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::APPEND));
+        assert_eq!(open_flags(O_WRONLY | O_TRUNC | 0o4000000), Ok(OpenFlags::WRITE | OpenFlags::TRUNCATE));
+        assert_eq!(open_flags(O_ACCMODE), Err(Errno::EINVAL));
+    }
+}
@@ -0,0 +1,328 @@
+pub mod entry;
+pub mod futex;
+pub mod io;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod numbers;
+pub mod process;
+pub mod shm;
+pub mod signal;
+pub mod user_ptr;
+
+use core::sync::atomic::Ordering;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::{gdt, percpu};
+use crate::msr::{Efer, EferFlags, LStar, SfMask, Star};
+use crate::fs::FsError;
+#[cfg(feature = "net")]
+use crate::net::NetError;
+use crate::process::elf::ElfError;
+use crate::process::signal::Interrupted;
+pub use entry::SyscallFrame;
+pub use user_ptr::UserSlice;
+
+/// Error numbers returned to user space, values as on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ENOTSOCK = 88,
+    EMSGSIZE = 90,
+    EPROTONOSUPPORT = 93,
+    EOPNOTSUPP = 95,
+    EAFNOSUPPORT = 97,
+    EADDRINUSE = 98,
+    ENETUNREACH = 101,
+    ECONNRESET = 104,
+    EISCONN = 106,
+    ENOTCONN = 107,
+    ETIMEDOUT = 110,
+    ECONNREFUSED = 111,
+}
+
+impl From<ElfError> for Errno {
+    fn from(err: ElfError) -> Self {
+        match err {
+            ElfError::OutOfMemory => Errno::ENOMEM,
+            _ => Errno::ENOEXEC,
+        }
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound | FsError::InvalidPath => Errno::ENOENT,
+            FsError::NotDirectory => Errno::ENOTDIR,
+            FsError::IsDirectory => Errno::EISDIR,
+            FsError::Exists => Errno::EEXIST,
+            FsError::NotEmpty => Errno::ENOTEMPTY,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::NameTooLong => Errno::ENAMETOOLONG,
+            FsError::CrossDevice => Errno::EXDEV,
+            FsError::Busy => Errno::EBUSY,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::BadMode => Errno::EBADF,
+            FsError::NotSeekable => Errno::ESPIPE,
+            FsError::InvalidArgument => Errno::EINVAL,
+            FsError::Unsupported => Errno::EPERM,
+            FsError::Interrupted => Errno::EINTR,
+            FsError::Corrupted | FsError::Io(_) => Errno::EIO,
+            #[cfg(feature = "net")]
+            FsError::Net(error) => error.into(),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+impl From<NetError> for Errno {
+    fn from(err: NetError) -> Self {
+        match err {
+            NetError::TooLong => Errno::EMSGSIZE,
+            NetError::Exists => Errno::EEXIST,
+            NetError::NoInterface | NetError::Unreachable => Errno::ENETUNREACH,
+            NetError::ConnectionRefused => Errno::ECONNREFUSED,
+            NetError::ConnectionReset => Errno::ECONNRESET,
+            NetError::TimedOut => Errno::ETIMEDOUT,
+            NetError::AddressInUse => Errno::EADDRINUSE,
+            NetError::NotConnected => Errno::ENOTCONN,
+            NetError::IsConnected => Errno::EISCONN,
+            NetError::Interrupted => Errno::EINTR,
+            NetError::NotFound => Errno::ENOENT,
+            NetError::InvalidName | NetError::InvalidArgument => Errno::EINVAL,
+            NetError::Unsupported => Errno::EOPNOTSUPP,
+            NetError::Io | NetError::Malformed => Errno::EIO,
+        }
+    }
+}
+
+impl From<Interrupted> for Errno {
+    fn from(_: Interrupted) -> Self {
+        Errno::EINTR
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Results travel back in rax, errors as the negated errno.
+pub fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+/// A raw register value decoded into a typed syscall argument.
+pub trait SyscallArg: Sized {
+    fn decode(raw: u64) -> Result<Self, Errno>;
+}
+
+impl SyscallArg for u64 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw)
+    }
+}
+
+impl SyscallArg for i64 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw as i64)
+    }
+}
+
+impl SyscallArg for usize {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw as usize)
+    }
+}
+
+impl SyscallArg for u32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        u32::try_from(raw).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl SyscallArg for i32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        // 32-bit values may arrive sign- or zero-extended
+        i32::try_from(raw as i64).or_else(|_| u32::try_from(raw).map(|value| value as i32)).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl SyscallArg for VirtAddr {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        VirtAddr::try_new(raw).map_err(|_| Errno::EFAULT)
+    }
+}
+
+/// The six argument registers in syscall order: rdi, rsi, rdx, r10, r8, r9.
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallArgs([u64; 6]);
+
+impl SyscallArgs {
+    pub fn new(raw: [u64; 6]) -> Self {
+        SyscallArgs(raw)
+    }
+
+    pub fn raw(&self, index: usize) -> u64 {
+        self.0[index]
+    }
+
+    pub fn get<T: SyscallArg>(&self, index: usize) -> Result<T, Errno> {
+        T::decode(self.0[index])
+    }
+
+    /// A user buffer given as pointer and length in two consecutive arguments.
+    pub fn user_slice(&self, index: usize) -> Result<UserSlice, Errno> {
+        UserSlice::new(self.0[index], self.get(index + 1)?)
+    }
+}
+
+pub type Handler = fn(&mut SyscallFrame) -> SyscallResult;
+
+pub struct SyscallEntry {
+    pub number: u64,
+    pub name: &'static str,
+    pub handler: Handler,
+}
+
+static SYSCALLS: &[SyscallEntry] = &[
+    SyscallEntry { number: numbers::READ, name: "read", handler: io::sys_read },
+    SyscallEntry { number: numbers::WRITE, name: "write", handler: io::sys_write },
+    SyscallEntry { number: numbers::OPEN, name: "open", handler: io::sys_open },
+    SyscallEntry { number: numbers::CLOSE, name: "close", handler: io::sys_close },
+    SyscallEntry { number: numbers::LSEEK, name: "lseek", handler: io::sys_lseek },
+    SyscallEntry { number: numbers::RT_SIGACTION, name: "rt_sigaction", handler: signal::sys_rt_sigaction },
+    SyscallEntry { number: numbers::RT_SIGPROCMASK, name: "rt_sigprocmask", handler: signal::sys_rt_sigprocmask },
+    SyscallEntry { number: numbers::RT_SIGRETURN, name: "rt_sigreturn", handler: signal::sys_rt_sigreturn },
+    SyscallEntry { number: numbers::SCHED_YIELD, name: "sched_yield", handler: process::sys_sched_yield },
+    SyscallEntry { number: numbers::SHMGET, name: "shmget", handler: shm::sys_shmget },
+    SyscallEntry { number: numbers::SHMAT, name: "shmat", handler: shm::sys_shmat },
+    SyscallEntry { number: numbers::SHMCTL, name: "shmctl", handler: shm::sys_shmctl },
+    SyscallEntry { number: numbers::DUP2, name: "dup2", handler: io::sys_dup2 },
+    SyscallEntry { number: numbers::NANOSLEEP, name: "nanosleep", handler: process::sys_nanosleep },
+    SyscallEntry { number: numbers::GETPID, name: "getpid", handler: process::sys_getpid },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::SOCKET, name: "socket", handler: net::sys_socket },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::CONNECT, name: "connect", handler: net::sys_connect },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::ACCEPT, name: "accept", handler: net::sys_accept },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::SENDTO, name: "sendto", handler: net::sys_sendto },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::RECVFROM, name: "recvfrom", handler: net::sys_recvfrom },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::BIND, name: "bind", handler: net::sys_bind },
+    #[cfg(feature = "net")]
+    SyscallEntry { number: numbers::LISTEN, name: "listen", handler: net::sys_listen },
+    SyscallEntry { number: numbers::FORK, name: "fork", handler: process::sys_fork },
+    SyscallEntry { number: numbers::EXECVE, name: "execve", handler: process::sys_execve },
+    SyscallEntry { number: numbers::EXIT, name: "exit", handler: process::sys_exit },
+    SyscallEntry { number: numbers::WAIT4, name: "wait4", handler: process::sys_wait4 },
+    SyscallEntry { number: numbers::KILL, name: "kill", handler: signal::sys_kill },
+    SyscallEntry { number: numbers::SHMDT, name: "shmdt", handler: shm::sys_shmdt },
+    SyscallEntry { number: numbers::FUTEX, name: "futex", handler: futex::sys_futex },
+];
+
+pub fn lookup(number: u64) -> Option<&'static SyscallEntry> {
+    SYSCALLS.iter().find(|entry| entry.number == number)
+}
+
+/// Runs the syscall described by `frame` and stores its result in the saved rax.
+pub fn handle(frame: &mut SyscallFrame) {
+    let result = match lookup(frame.rax) {
+        Some(entry) => (entry.handler)(frame),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = encode(result);
+}
+
+/* common entry of both gates.
+    Called with interrupts disabled on the thread's kernel stack. Handlers may block,
+    so interrupts are back on while they run, and off again for the return to ring 3.
+ */
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    percpu!(stats.syscalls).fetch_add(1, Ordering::Relaxed);
+    interrupts::enable();
+    handle(frame);
+    crate::process::signal::deliver(frame);
+    interrupts::disable();
+}
+
+/* STAR, LSTAR, SFMASK.
+    `syscall` loads the kernel selectors from the STAR syscall base and masks the
+    listed RFLAGS bits, interrupts among them, until the entry stub is on a kernel stack.
+ */
+pub fn init() {
+    const RFLAGS_TF: u64 = 1 << 8;
+    const RFLAGS_IF: u64 = 1 << 9;
+    const RFLAGS_DF: u64 = 1 << 10;
+    const RFLAGS_AC: u64 = 1 << 18;
+
+    let selectors = gdt::selectors();
+    // sysret uses base + 8 for the stack and base + 16 for the code selector
+    let sysret_base = selectors.user_data_selector.0 - 8;
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+        Star::write(sysret_base, selectors.code_selector.0);
+        LStar::write(VirtAddr::new(entry::syscall_entry as *const () as u64));
+        SfMask::write(RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_errno_encoding() {
+        assert_eq!(encode(Ok(42)), 42);
+        assert_eq!(encode(Err(Errno::ENOSYS)) as i64, -38);
+    }
+
+    #[test_case]
+    fn test_argument_decoding() {
+        let args = SyscallArgs::new([1, u64::MAX, 1 << 40, 0xffff_ffff, 0, 0x8000_0000_0000]);
+        assert_eq!(args.get::<i32>(0), Ok(1));
+        assert_eq!(args.get::<i32>(1), Ok(-1));
+        assert_eq!(args.get::<i32>(2), Err(Errno::EINVAL));
+        assert_eq!(args.get::<i32>(3), Ok(-1));
+        assert_eq!(args.get::<u32>(2), Err(Errno::EINVAL));
+        assert_eq!(args.get::<VirtAddr>(5), Err(Errno::EFAULT));
+    }
+
+    #[test_case]
+    fn test_unknown_syscall() {
+        let mut frame = SyscallFrame { rax: 0xdead, ..SyscallFrame::default() };
+        handle(&mut frame);
+        assert_eq!(frame.rax as i64, -(Errno::ENOSYS as i64));
+    }
+}
@@ -0,0 +1,181 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use super::io::{file, with_files, MAX_TRANSFER};
+use super::{Errno, SyscallFrame, SyscallResult, UserSlice};
+use crate::fs::File;
+use crate::net::socket::{Socket, SocketType};
+
+// values as on Linux
+const AF_INET: u16 = 2;
+const SOCK_STREAM: u32 = 1;
+const SOCK_DGRAM: u32 = 2;
+// the rest of the type are SOCK_NONBLOCK and SOCK_CLOEXEC, which are ignored
+const SOCK_TYPE_MASK: u32 = 0xf;
+const IPPROTO_TCP: u32 = 6;
+const IPPROTO_UDP: u32 = 17;
+// a `struct sockaddr_in`: family, port and address, then eight bytes of padding
+const SOCKADDR_IN_LEN: usize = 16;
+
+fn socket_type(kind: u32, protocol: u32) -> Result<SocketType, Errno> {
+    let kind = match kind & SOCK_TYPE_MASK {
+        SOCK_STREAM => SocketType::Stream,
+        SOCK_DGRAM => SocketType::Datagram,
+        _ => return Err(Errno::EINVAL),
+    };
+    match (kind, protocol) {
+        (_, 0) | (SocketType::Stream, IPPROTO_TCP) | (SocketType::Datagram, IPPROTO_UDP) => Ok(kind),
+        _ => Err(Errno::EPROTONOSUPPORT),
+    }
+}
+
+fn decode_address(bytes: &[u8; SOCKADDR_IN_LEN]) -> Result<SocketAddrV4, Errno> {
+    if u16::from_le_bytes([bytes[0], bytes[1]]) != AF_INET {
+        return Err(Errno::EAFNOSUPPORT);
+    }
+    let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+    Ok(SocketAddrV4::new(Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7]), port))
+}
+
+fn encode_address(address: SocketAddrV4) -> [u8; SOCKADDR_IN_LEN] {
+    let mut bytes = [0u8; SOCKADDR_IN_LEN];
+    bytes[..2].copy_from_slice(&AF_INET.to_le_bytes());
+    bytes[2..4].copy_from_slice(&address.port().to_be_bytes());
+    bytes[4..8].copy_from_slice(&address.ip().octets());
+    bytes
+}
+
+/// The `sockaddr_in` given as pointer and length in arguments `index` and `index + 1`.
+fn read_address(frame: &SyscallFrame, index: usize) -> Result<SocketAddrV4, Errno> {
+    let args = frame.args();
+    let len: usize = args.get(index + 1)?;
+    if len < SOCKADDR_IN_LEN {
+        return Err(Errno::EINVAL);
+    }
+    let mut bytes = [0u8; SOCKADDR_IN_LEN];
+    UserSlice::new(args.raw(index), SOCKADDR_IN_LEN)?.read_into(&mut bytes)?;
+    decode_address(&bytes)
+}
+
+/// Stores `address` for the caller when it passed a buffer, cut to the length in
+/// `*len` as Linux does; `*len` gets the full length.
+fn write_address(address: SocketAddrV4, addr: u64, len: u64) -> Result<(), Errno> {
+    if addr == 0 {
+        return Ok(());
+    }
+    let len_slice = UserSlice::new(len, 4)?;
+    let mut room = [0u8; 4];
+    len_slice.read_into(&mut room)?;
+    let room = (u32::from_le_bytes(room) as usize).min(SOCKADDR_IN_LEN);
+    UserSlice::new(addr, room)?.write(&encode_address(address)[..room])?;
+    len_slice.write(&(SOCKADDR_IN_LEN as u32).to_le_bytes())
+}
+
+/// The socket behind `fd`, the file is returned to keep it open meanwhile.
+fn socket(fd: i32) -> Result<Arc<dyn File>, Errno> {
+    let file = file(fd)?;
+    if !file.as_any().is::<Socket>() {
+        return Err(Errno::ENOTSOCK);
+    }
+    Ok(file)
+}
+
+fn as_socket(file: &Arc<dyn File>) -> &Socket {
+    file.as_any().downcast_ref().expect("checked by socket()")
+}
+
+fn insert(socket: Arc<Socket>) -> SyscallResult {
+    match with_files(|files| files.insert(socket))? {
+        Some(fd) => Ok(fd as u64),
+        None => Err(Errno::EMFILE),
+    }
+}
+
+/// `socket(domain, type, protocol)`, IPv4 TCP and UDP only.
+pub fn sys_socket(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    if args.get::<u32>(0)? != AF_INET as u32 {
+        return Err(Errno::EAFNOSUPPORT);
+    }
+    insert(Socket::new(socket_type(args.get(1)?, args.get(2)?)?))
+}
+
+/// `bind(fd, addr, len)`
+pub fn sys_bind(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = socket(frame.args().get(0)?)?;
+    as_socket(&file).bind(read_address(frame, 1)?)?;
+    Ok(0)
+}
+
+/// `listen(fd, backlog)`, the backlog is the stack's own.
+pub fn sys_listen(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = socket(frame.args().get(0)?)?;
+    as_socket(&file).listen()?;
+    Ok(0)
+}
+
+/// `accept(fd, addr, len)`, returns the descriptor of the new connection.
+pub fn sys_accept(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let file = socket(args.get(0)?)?;
+    let connection = as_socket(&file).accept()?;
+    let (_, peer) = connection.addresses().ok_or(Errno::ENOTCONN)?;
+    write_address(peer, args.raw(1), args.raw(2))?;
+    insert(connection)
+}
+
+/// `connect(fd, addr, len)`
+pub fn sys_connect(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = socket(frame.args().get(0)?)?;
+    as_socket(&file).connect(read_address(frame, 1)?)?;
+    Ok(0)
+}
+
+/// `sendto(fd, buf, len, flags, addr, addr_len)`, no flags are known. Without
+/// an address it sends where the socket is connected to.
+pub fn sys_sendto(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let file = socket(args.get(0)?)?;
+    let slice = args.user_slice(1)?;
+    let destination = match args.raw(4) {
+        0 => None,
+        _ => Some(read_address(frame, 4)?),
+    };
+    let mut buffer = vec![0u8; slice.len().min(MAX_TRANSFER)];
+    slice.read_into(&mut buffer)?;
+    Ok(as_socket(&file).send_to(&buffer, destination)? as u64)
+}
+
+/// `recvfrom(fd, buf, len, flags, addr, addr_len)`, no flags are known.
+pub fn sys_recvfrom(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let file = socket(args.get(0)?)?;
+    let slice = args.user_slice(1)?;
+    let mut buffer = vec![0u8; slice.len().min(MAX_TRANSFER)];
+    let (count, source) = as_socket(&file).recv_from(&mut buffer)?;
+    slice.write(&buffer[..count])?;
+    write_address(source, args.raw(4), args.raw(5))?;
+    Ok(count as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_arguments() {
+        assert_eq!(socket_type(SOCK_STREAM | 0o4000, 0), Ok(SocketType::Stream));
+        assert_eq!(socket_type(SOCK_DGRAM, IPPROTO_UDP), Ok(SocketType::Datagram));
+        assert_eq!(socket_type(SOCK_DGRAM, IPPROTO_TCP), Err(Errno::EPROTONOSUPPORT));
+        assert_eq!(socket_type(3, 0), Err(Errno::EINVAL));
+
+        let address = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 8080);
+        let bytes = encode_address(address);
+        assert_eq!(&bytes[..8], &[2, 0, 0x1f, 0x90, 10, 0, 2, 2]);
+        assert_eq!(decode_address(&bytes), Ok(address));
+        let mut inet6 = bytes;
+        inet6[0] = 10;
+        assert_eq!(decode_address(&inet6), Err(Errno::EAFNOSUPPORT));
+    }
+}
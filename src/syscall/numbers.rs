@@ -0,0 +1,33 @@
+/* syscall numbers.
+    The Linux x86_64 numbering is kept, so existing tooling and hand written
+    assembly can be reused for user programs.
+ */
+pub const READ: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const OPEN: u64 = 2;
+pub const CLOSE: u64 = 3;
+pub const LSEEK: u64 = 8;
+pub const RT_SIGACTION: u64 = 13;
+pub const RT_SIGPROCMASK: u64 = 14;
+pub const RT_SIGRETURN: u64 = 15;
+pub const SCHED_YIELD: u64 = 24;
+pub const SHMGET: u64 = 29;
+pub const SHMAT: u64 = 30;
+pub const SHMCTL: u64 = 31;
+pub const DUP2: u64 = 33;
+pub const NANOSLEEP: u64 = 35;
+pub const GETPID: u64 = 39;
+pub const SOCKET: u64 = 41;
+pub const CONNECT: u64 = 42;
+pub const ACCEPT: u64 = 43;
+pub const SENDTO: u64 = 44;
+pub const RECVFROM: u64 = 45;
+pub const BIND: u64 = 49;
+pub const LISTEN: u64 = 50;
+pub const FORK: u64 = 57;
+pub const EXECVE: u64 = 59;
+pub const EXIT: u64 = 60;
+pub const WAIT4: u64 = 61;
+pub const KILL: u64 = 62;
+pub const SHMDT: u64 = 67;
+pub const FUTEX: u64 = 202;
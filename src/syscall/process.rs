@@ -0,0 +1,155 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::user_ptr::{read_c_string, read_timespec, read_u64};
+use super::{Errno, SyscallArgs, SyscallFrame, SyscallResult, UserSlice};
+use crate::memory::AddressSpace;
+use crate::process::elf::{self, UserEntry};
+use crate::process::table::{self, Status, WaitResult, WaitTarget};
+use crate::process::{user, ProcessId};
+use crate::thread::{self, ExitCode};
+use crate::{fs, time, tty};
+
+pub(super) const PATH_MAX: usize = 4096;
+const MAX_ARG_STRINGS: usize = 256;
+// argument and environment strings together, they have to fit on the initial stack
+const ARG_MAX: usize = 32 * 1024;
+const WNOHANG: u64 = 1;
+
+pub fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    let code: i32 = frame.args().get(0)?;
+    thread::exit(ExitCode(code));
+}
+
+pub fn sys_sched_yield(_frame: &mut SyscallFrame) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+pub fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    let id = without_interrupts(|| process.lock().id());
+    Ok(id.as_u64())
+}
+
+/// `nanosleep(req, rem)`, sleeps are never interrupted so `rem` is left alone.
+pub fn sys_nanosleep(frame: &mut SyscallFrame) -> SyscallResult {
+    let duration = read_timespec(frame.args().raw(0))?;
+    time::sleep(duration);
+    Ok(0)
+}
+
+/// Returns the child's pid in the parent, the child continues from the same point with 0.
+pub fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    let parent = thread::current_process().ok_or(Errno::ESRCH)?;
+    let child = without_interrupts(|| parent.lock().fork()).ok_or(Errno::ENOMEM)?;
+    let pid = child.id();
+
+    let mut child_frame = frame.clone();
+    child_frame.rax = 0;
+    user::spawn_with_frame(Arc::new(Mutex::new(child)), child_frame).detach();
+    Ok(pid.as_u64())
+}
+
+/// `execve(path, argv, envp)`, only returns on failure, with the old image still in place.
+pub fn sys_execve(frame: &mut SyscallFrame) -> SyscallResult {
+    let entry = exec(frame.args())?;
+    user::enter(entry);
+}
+
+/* the new image is loaded into a fresh address space first, so every error leaves the
+    caller untouched. Only then the spaces are swapped and the old one is freed.
+ */
+fn exec(args: SyscallArgs) -> Result<UserEntry, Errno> {
+    let path = read_c_string(args.raw(0), PATH_MAX)?;
+    let mut budget = ARG_MAX;
+    let argv = read_string_array(args.raw(1), &mut budget)?;
+    let envp = read_string_array(args.raw(2), &mut budget)?;
+    let image = fs::read_file(&path)?;
+
+    let mut space = AddressSpace::new().ok_or(Errno::ENOMEM)?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    let entry = elf::load_program(&mut space, &image, &argv, &envp)?;
+
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    let (old, id) = without_interrupts(|| {
+        let mut process = process.lock();
+        // other threads would keep running on the old image
+        if process.threads().len() > 1 {
+            return Err(Errno::EBUSY);
+        }
+        let old = process.replace_image(name, space);
+        thread::switch_address_space(process.address_space());
+        Ok((old, process.id()))
+    })?;
+    drop(old);
+    tty::set_foreground(Some(id));
+    Ok(entry)
+}
+
+/// A NULL terminated array of string pointers, a NULL array counts as empty.
+fn read_string_array(addr: u64, budget: &mut usize) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let pointer = read_u64(addr + 8 * strings.len() as u64)?;
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_ARG_STRINGS {
+            return Err(Errno::E2BIG);
+        }
+        let string = read_c_string(pointer, *budget).map_err(|err| match err {
+            Errno::ENAMETOOLONG => Errno::E2BIG,
+            err => err,
+        })?;
+        *budget -= string.len();
+        strings.push(string);
+    }
+}
+
+/* `wait4(pid, wstatus, options, rusage)`, which also serves as `waitpid`.
+    A pid of -1 waits for any child. There are no process groups, so 0 and other
+    negative pids are rejected, and no resource usage is tracked, `rusage` is ignored.
+ */
+pub fn sys_wait4(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let pid: i32 = args.get(0)?;
+    let status_pointer = args.raw(1);
+    let options = args.raw(2);
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let target = match pid {
+        -1 => WaitTarget::Any,
+        pid if pid > 0 => WaitTarget::Child(ProcessId::from_u64(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let process = thread::current_process().ok_or(Errno::ECHILD)?;
+    let parent = without_interrupts(|| process.lock().id());
+    drop(process);
+    match table::wait(parent, target, options & WNOHANG == 0)? {
+        WaitResult::Exited(child, status) => {
+            if status_pointer != 0 {
+                let status = match status {
+                    // WIFEXITED, with the exit code in the second byte
+                    Status::Exited(code) => (code.0 & 0xff) << 8,
+                    // WIFSIGNALED, with the signal in the low seven bits
+                    Status::Killed(signal) => signal & 0x7f,
+                };
+                UserSlice::new(status_pointer, 4)?.write(&status.to_le_bytes())?;
+            }
+            Ok(child.as_u64())
+        }
+        WaitResult::StillRunning => Ok(0),
+        WaitResult::NoChild => Err(Errno::ECHILD),
+    }
+}
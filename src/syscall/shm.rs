@@ -0,0 +1,63 @@
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+use super::{Errno, SyscallFrame, SyscallResult};
+use crate::process::shm::{self, ShmError, ShmId};
+use crate::thread;
+
+const IPC_PRIVATE: u64 = 0;
+const SHM_RDONLY: u64 = 0o10000;
+const IPC_RMID: u64 = 0;
+
+impl From<ShmError> for Errno {
+    fn from(err: ShmError) -> Self {
+        match err {
+            ShmError::OutOfMemory => Errno::ENOMEM,
+            _ => Errno::EINVAL,
+        }
+    }
+}
+
+/// `shmget(key, size, flags)`, only private segments, there are no keys to look up.
+pub fn sys_shmget(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    if args.raw(0) != IPC_PRIVATE {
+        return Err(Errno::EINVAL);
+    }
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    let creator = without_interrupts(|| process.lock().id());
+    Ok(shm::create(creator, args.raw(1))?.as_u64())
+}
+
+/// `shmat(id, address, flags)`, a null address lets the kernel pick one.
+pub fn sys_shmat(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let id = ShmId::from_u64(args.raw(0));
+    let address = match args.raw(1) {
+        0 => None,
+        _ => Some(args.get::<VirtAddr>(1).map_err(|_| Errno::EINVAL)?),
+    };
+    let writable = args.raw(2) & SHM_RDONLY == 0;
+
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    let address = without_interrupts(|| shm::attach(&mut process.lock(), id, address, writable))?;
+    Ok(address.as_u64())
+}
+
+/// `shmdt(address)`, takes the address `shmat` returned.
+pub fn sys_shmdt(frame: &mut SyscallFrame) -> SyscallResult {
+    let address = frame.args().get::<VirtAddr>(0).map_err(|_| Errno::EINVAL)?;
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    without_interrupts(|| shm::detach(&mut process.lock(), address))?;
+    Ok(0)
+}
+
+/// `shmctl(id, command, buffer)`, only IPC_RMID, which takes no buffer.
+pub fn sys_shmctl(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    if args.raw(1) != IPC_RMID {
+        return Err(Errno::EINVAL);
+    }
+    shm::remove(ShmId::from_u64(args.raw(0)))?;
+    Ok(0)
+}
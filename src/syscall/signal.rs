@@ -0,0 +1,140 @@
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{Errno, SyscallFrame, SyscallResult, UserSlice};
+use crate::process::signal::{self, Action, SignalSet, SA_RESTORER, SIGSEGV};
+use crate::process::ProcessId;
+use crate::thread;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+// handler, flags, restorer and mask of the kernel's `struct sigaction`
+const SIGACTION_SIZE: usize = 32;
+const SIGSET_SIZE: usize = 8;
+
+fn encode_action(action: Action) -> [u8; SIGACTION_SIZE] {
+    let (handler, flags, restorer, mask) = match action {
+        Action::Default => (SIG_DFL, 0, 0, 0),
+        Action::Ignore => (SIG_IGN, 0, 0, 0),
+        Action::Handler { handler, restorer, mask, flags } => (handler, flags, restorer, mask.bits()),
+    };
+    let mut bytes = [0u8; SIGACTION_SIZE];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip([handler, flags, restorer, mask]) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// A handler needs a restorer, which is how it gets back into the kernel to `rt_sigreturn`.
+fn decode_action(bytes: &[u8; SIGACTION_SIZE]) -> Result<Action, Errno> {
+    let word = |index: usize| u64::from_le_bytes(bytes[index * 8..index * 8 + 8].try_into().unwrap());
+    let (handler, flags, restorer, mask) = (word(0), word(1), word(2), word(3));
+    Ok(match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        _ if flags & SA_RESTORER == 0 => return Err(Errno::EINVAL),
+        _ => Action::Handler { handler, restorer, mask: SignalSet::from_bits(mask), flags },
+    })
+}
+
+/// `rt_sigaction(signal, act, oldact, sigsetsize)`
+pub fn sys_rt_sigaction(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let signal: i32 = args.get(0)?;
+    let (new, old) = (args.raw(1), args.raw(2));
+    if args.raw(3) != SIGSET_SIZE as u64 || !signal::is_valid(signal) {
+        return Err(Errno::EINVAL);
+    }
+    let action = if new != 0 {
+        if !signal::is_catchable(signal) {
+            return Err(Errno::EINVAL);
+        }
+        let mut bytes = [0u8; SIGACTION_SIZE];
+        UserSlice::new(new, SIGACTION_SIZE)?.read_into(&mut bytes)?;
+        Some(decode_action(&bytes)?)
+    } else {
+        None
+    };
+
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    let previous = without_interrupts(|| {
+        let mut process = process.lock();
+        let signals = process.signals_mut();
+        let previous = signals.action(signal);
+        if let Some(action) = action {
+            signals.set_action(signal, action);
+        }
+        previous
+    });
+    if old != 0 {
+        UserSlice::new(old, SIGACTION_SIZE)?.write(&encode_action(previous))?;
+    }
+    Ok(0)
+}
+
+/// `rt_sigprocmask(how, set, oldset, sigsetsize)`
+pub fn sys_rt_sigprocmask(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let (how, new, old) = (args.raw(0), args.raw(1), args.raw(2));
+    if args.raw(3) != SIGSET_SIZE as u64 {
+        return Err(Errno::EINVAL);
+    }
+    let set = if new != 0 {
+        let mut bytes = [0u8; SIGSET_SIZE];
+        UserSlice::new(new, SIGSET_SIZE)?.read_into(&mut bytes)?;
+        Some(SignalSet::from_bits(u64::from_le_bytes(bytes)))
+    } else {
+        None
+    };
+
+    let process = thread::current_process().ok_or(Errno::ESRCH)?;
+    let previous = without_interrupts(|| {
+        let mut process = process.lock();
+        let signals = process.signals_mut();
+        let previous = signals.mask();
+        if let Some(set) = set {
+            let mask = match how {
+                SIG_BLOCK => previous.union(set),
+                SIG_UNBLOCK => previous.difference(set),
+                SIG_SETMASK => set,
+                _ => return Err(Errno::EINVAL),
+            };
+            signals.set_mask(mask);
+        }
+        Ok(previous)
+    })?;
+    if old != 0 {
+        UserSlice::new(old, SIGSET_SIZE)?.write(&previous.bits().to_le_bytes())?;
+    }
+    Ok(0)
+}
+
+/// Returns the interrupted code's rax, so the result it was about to see is kept.
+pub fn sys_rt_sigreturn(frame: &mut SyscallFrame) -> SyscallResult {
+    match signal::restore(frame) {
+        Some(rax) => Ok(rax),
+        // a clobbered signal frame, there is nothing sensible to return to
+        None => signal::terminate(SIGSEGV),
+    }
+}
+
+/// `kill(pid, signal)`, signal 0 only checks that the process exists. No process groups.
+pub fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult {
+    let args = frame.args();
+    let pid: i32 = args.get(0)?;
+    let signal: i32 = args.get(1)?;
+    if pid <= 0 || !(signal == 0 || signal::is_valid(signal)) {
+        return Err(Errno::EINVAL);
+    }
+    let id = ProcessId::from_u64(pid as u64);
+    let found = if signal == 0 {
+        crate::process::table::exists(id)
+    } else {
+        signal::send(id, signal)
+    };
+    if found { Ok(0) } else { Err(Errno::ESRCH) }
+}
@@ -0,0 +1,166 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::Errno;
+use crate::memory::address_space::{USER_END, USER_START};
+use crate::memory::{AddressSpace, PAGE_SIZE};
+use crate::thread;
+
+/* a buffer in the calling process' memory.
+    Syscalls never dereference user pointers directly. The range is checked against
+    the user half first, and every page must be mapped user accessible (and writable,
+    for buffers the kernel fills) in the caller's address space before it is copied.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: VirtAddr,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: u64, len: usize) -> Result<Self, Errno> {
+        let end = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+        if addr < USER_START || end > USER_END {
+            return Err(Errno::EFAULT);
+        }
+        Ok(UserSlice { addr: VirtAddr::new(addr), len })
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn pages(&self) -> PageRangeInclusive {
+        let first = Page::containing_address(self.addr);
+        if self.len == 0 {
+            // an empty range, nothing to check
+            return Page::range_inclusive(first + 1, first);
+        }
+        let last = Page::containing_address(self.addr + (self.len as u64 - 1));
+        Page::range_inclusive(first, last)
+    }
+
+    fn check_readable(&self, space: &mut AddressSpace) -> Result<(), Errno> {
+        for page in self.pages() {
+            match space.flags(page) {
+                Some(flags) if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {}
+                _ => return Err(Errno::EFAULT),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy-on-write pages are the kernel's to copy before it writes, no fault happens for it.
+    fn check_writable(&self, space: &mut AddressSpace) -> Result<(), Errno> {
+        self.check_readable(space)?;
+        for page in self.pages() {
+            if !space.prepare_write(page) {
+                return Err(Errno::EFAULT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the current address space, with interrupts off while the process is locked.
+    fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> Result<R, Errno>) -> Result<R, Errno> {
+        let process = thread::current_process().ok_or(Errno::EFAULT)?;
+        without_interrupts(|| f(process.lock().address_space_mut()))
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, Errno> {
+        let mut buffer = vec![0u8; self.len];
+        self.read_into(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Copies the start of the slice into `buffer`, which must not be longer than the slice.
+    pub fn read_into(&self, buffer: &mut [u8]) -> Result<(), Errno> {
+        assert!(buffer.len() <= self.len);
+        Self::with_space(|space| {
+            self.check_readable(space)?;
+            space.copy_from(self.addr, buffer).then_some(()).ok_or(Errno::EFAULT)
+        })
+    }
+
+    /// Copies `data` to the start of the slice, which must be at least as long.
+    pub fn write(&self, data: &[u8]) -> Result<(), Errno> {
+        if data.len() > self.len {
+            return Err(Errno::EINVAL);
+        }
+        Self::with_space(|space| {
+            self.check_writable(space)?;
+            space.copy_to(self.addr, data).then_some(()).ok_or(Errno::EFAULT)
+        })
+    }
+}
+
+/* a NUL terminated string in user memory.
+    Read a page at a time so a string ending right before an unmapped page works.
+    Fails with ENAMETOOLONG when no terminator shows up within `max` bytes.
+ */
+pub fn read_c_string(addr: u64, max: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    let mut next = addr;
+    while bytes.len() <= max {
+        let to_page_end = PAGE_SIZE - next % PAGE_SIZE;
+        let chunk = UserSlice::new(next, to_page_end.min((max + 1 - bytes.len()) as u64) as usize)?.read()?;
+        if let Some(end) = chunk.iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+        }
+        bytes.extend_from_slice(&chunk);
+        next += chunk.len() as u64;
+    }
+    Err(Errno::ENAMETOOLONG)
+}
+
+/// Reads one pointer sized word from user memory.
+pub fn read_u64(addr: u64) -> Result<u64, Errno> {
+    let mut bytes = [0u8; 8];
+    UserSlice::new(addr, 8)?.read_into(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads a `struct timespec`, rejecting negative and denormalized values.
+pub fn read_timespec(addr: u64) -> Result<Duration, Errno> {
+    let mut timespec = [0u8; 16];
+    UserSlice::new(addr, 16)?.read_into(&mut timespec)?;
+    let seconds = i64::from_le_bytes(timespec[..8].try_into().unwrap());
+    let nanoseconds = i64::from_le_bytes(timespec[8..].try_into().unwrap());
+    if seconds < 0 || !(0..1_000_000_000).contains(&nanoseconds) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(Duration::new(seconds as u64, nanoseconds as u32))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_rejects_kernel_addresses() {
+        assert_eq!(UserSlice::new(0x20_0000, 16).err(), Some(Errno::EFAULT));
+        assert_eq!(UserSlice::new(USER_END - 8, 16).err(), Some(Errno::EFAULT));
+        assert_eq!(UserSlice::new(u64::MAX - 4, 16).err(), Some(Errno::EFAULT));
+    }
+
+    #[test_case]
+    fn test_kernel_thread_has_no_user_memory() {
+        let slice = UserSlice::new(USER_START, 16).unwrap();
+        assert_eq!(slice.read().err(), Some(Errno::EFAULT));
+    }
+}
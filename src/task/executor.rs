@@ -0,0 +1,218 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+use super::{Task, TaskId};
+
+const TASK_QUEUE_CAPACITY: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // set by a waker that found the queue full, its task's wakeup is lost
+    overflow: Arc<AtomicBool>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_CAPACITY)),
+            overflow: Arc::new(AtomicBool::new(false)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("task queue full");
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Polls every task that has been woken since the last call.
+    pub fn run_ready_tasks(&mut self) {
+        // destructure `self` to avoid borrow checker errors
+        let Self {
+            tasks,
+            task_queue,
+            overflow,
+            waker_cache,
+        } = self;
+
+        // every task, once the queue lost a wakeup
+        let mut lost = Vec::new();
+        loop {
+            let task_id = match task_queue.pop() {
+                Some(task_id) => task_id,
+                None => {
+                    if lost.is_empty() && overflow.swap(false, Ordering::AcqRel) {
+                        // which task it was is not known, polling a task too often is fine
+                        lost = tasks.keys().copied().collect();
+                    }
+                    match lost.pop() {
+                        Some(task_id) => task_id,
+                        None => break,
+                    }
+                }
+            };
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone(), overflow.clone()));
+            let mut context = Context::from_waker(waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    // task done -> remove it and its cached waker
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // an interrupt between the check and hlt could push a wakeup we would sleep through
+        interrupts::disable();
+        if self.task_queue.is_empty() && !self.overflow.load(Ordering::Acquire) {
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    overflow: Arc<AtomicBool>,
+}
+
+impl TaskWaker {
+    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, overflow: Arc<AtomicBool>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            overflow,
+        }))
+    }
+
+    fn wake_task(&self) {
+        // the queue may hold nothing of this task, so the executor is told to look at all
+        if self.task_queue.push(self.task_id).is_err() {
+            self.overflow.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::AtomicUsize;
+    use crate::sync::SpinLock;
+
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_executor_runs_tasks_to_completion() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        for _ in 0..3 {
+            executor.spawn(Task::new(async {
+                YieldOnce(false).await;
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        executor.run_ready_tasks();
+        assert!(executor.is_empty());
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+    }
+
+    #[test_case]
+    fn test_wakeup_into_a_full_queue() {
+        static WAKER: SpinLock<Option<Waker>> = SpinLock::new(None);
+        static WOKEN: AtomicBool = AtomicBool::new(false);
+
+        let mut executor = Executor::new();
+        // polled first, it leaves its waker for the other task
+        executor.spawn(Task::new(core::future::poll_fn(|cx| {
+            if WOKEN.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            *WAKER.lock() = Some(cx.waker().clone());
+            Poll::Pending
+        })));
+        // fills the queue with itself, the wakeup after that finds no room
+        executor.spawn(Task::new(async {
+            core::future::poll_fn(|cx| {
+                for _ in 0..TASK_QUEUE_CAPACITY {
+                    cx.waker().wake_by_ref();
+                }
+                WOKEN.store(true, Ordering::SeqCst);
+                WAKER.lock().take().unwrap().wake();
+                Poll::Ready(())
+            })
+            .await;
+            YieldOnce(false).await;
+        }));
+        executor.run_ready_tasks();
+        assert!(executor.is_empty());
+        assert!(!executor.overflow.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,118 @@
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+use crate::print;
+
+const SCANCODE_QUEUE_CAPACITY: usize = 100;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called by the keyboard interrupt handler, must not block or allocate.
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        if queue.push(scancode).is_err() {
+            log::warn!("scancode queue full; dropping keyboard input");
+        } else {
+            WAKER.wake();
+        }
+    } else {
+        log::warn!("scancode queue uninitialized");
+    }
+}
+
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("scancode queue not initialized");
+
+        // fast path
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(cx.waker());
+        // a scancode may have arrived before the waker was registered
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Keys as typed on a US keyboard, for the one task reading the keyboard.
+pub struct Keys {
+    scancodes: ScancodeStream,
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl Keys {
+    pub fn new() -> Self {
+        Keys {
+            scancodes: ScancodeStream::new(),
+            keyboard: Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::MapLettersToUnicode),
+        }
+    }
+
+    /// The next key pressed; releases and modifiers alone make none.
+    pub async fn next(&mut self) -> Option<DecodedKey> {
+        while let Some(scancode) = self.scancodes.next().await {
+            if let Ok(Some(key_event)) = self.keyboard.add_byte(scancode) {
+                if let Some(key) = self.keyboard.process_keyevent(key_event) {
+                    return Some(key);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hands a key to the terminal, echoed, for the process in the foreground.
+pub fn forward_to_tty(key: DecodedKey) {
+    match key {
+        DecodedKey::Unicode(character) => {
+            // the terminal echoes interrupts itself
+            if character != crate::tty::INTERRUPT {
+                print!("{}", character);
+            }
+            crate::tty::push_char(character);
+        }
+        DecodedKey::RawKey(key) => print!("{:?}", key),
+    }
+}
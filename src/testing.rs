@@ -0,0 +1,443 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+
+use crate::allocator;
+use crate::backtrace::Backtrace;
+use crate::interrupts::idt::{CpuExceptionIndex, Idt, IdtIndex};
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::sync::SpinLock;
+use crate::thread::{self, ExitCode, ThreadId};
+use crate::time::tsc;
+use crate::{gdt, handler, handler_with_error_code, workqueue};
+use crate::{exit_qemu, halt_loop, serial_print, serial_println, QemuExitCode, Testable};
+
+/* tests that are meant to fail.
+    Without unwinding a panic or a fault cannot be caught, so such a test runs on a
+    thread of its own, and the panic or exception handler ends that thread when it
+    failed the way it expected to; the runner joins it and looks at how it ended.
+        should_panic! { fn test_overflow() { ... } }
+        should_fault!(PageFault, fn test_null() { ... });
+    Locks the test held stay held, it should fail outside of them.
+ */
+
+// what the thread of a test that failed as expected exits with
+const FAILED_AS_EXPECTED: ExitCode = ExitCode(101);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Panic,
+    Fault(CpuExceptionIndex),
+}
+
+// the thread running a test that expects to fail, and how
+static EXPECTING: SpinLock<Option<(ThreadId, Expected)>> = SpinLock::new(None);
+
+pub struct ExpectedFailure {
+    name: &'static str,
+    expected: Expected,
+    test: fn(),
+}
+
+impl ExpectedFailure {
+    pub const fn new(name: &'static str, expected: Expected, test: fn()) -> Self {
+        ExpectedFailure { name, expected, test }
+    }
+}
+
+impl Testable for ExpectedFailure {
+    fn run(&self) {
+        serial_print!("{}...\t", self.name);
+        let (test, expected) = (self.test, self.expected);
+        let code = thread::spawn("expected-failure", move || {
+            *EXPECTING.lock() = Some((thread::current_id(), expected));
+            test();
+        }).join();
+        *EXPECTING.lock() = None;
+        if code != FAILED_AS_EXPECTED {
+            serial_println!("[failed]\n");
+            serial_println!("Error: expected {:?}, the test returned\n", expected);
+            exit_qemu(QemuExitCode::Failed);
+            halt_loop();
+        }
+        serial_println!("[ok]");
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// How a test run that panicked ends, failed assertions told apart from other panics.
+pub fn panic_exit_code(info: &PanicInfo) -> QemuExitCode {
+    // the start of the message is enough, "assertion failed: .." or "assertion `left == right` failed"
+    struct Prefix {
+        bytes: [u8; 9],
+        len: usize,
+    }
+
+    impl fmt::Write for Prefix {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            let count = text.len().min(self.bytes.len() - self.len);
+            self.bytes[self.len..self.len + count].copy_from_slice(&text.as_bytes()[..count]);
+            self.len += count;
+            Ok(())
+        }
+    }
+
+    let mut prefix = Prefix { bytes: [0; 9], len: 0 };
+    let _ = write!(prefix, "{}", info.message());
+    match &prefix.bytes[..prefix.len] {
+        b"assertion" => QemuExitCode::AssertionFailed,
+        _ => QemuExitCode::Panic,
+    }
+}
+
+/// Ends the current thread when it is a test expecting to fail this way,
+/// called by the panic handler and the exception handlers.
+pub fn failed(how: Expected) {
+    let expecting = EXPECTING.try_lock().and_then(|expecting| *expecting);
+    if expecting == Some((thread::current_id(), how)) {
+        thread::exit(FAILED_AS_EXPECTED);
+    }
+}
+
+/// A test that passes by panicking.
+#[macro_export]
+macro_rules! should_panic {
+    (fn $name: ident() $body: block) => {
+        fn $name() $body
+
+        mod $name {
+            #[test_case]
+            const TEST: $crate::testing::ExpectedFailure =
+                $crate::testing::ExpectedFailure::new(module_path!(), $crate::testing::Expected::Panic, super::$name);
+        }
+    };
+}
+
+/// A test that passes by raising the CPU exception `$exception`, a `CpuExceptionIndex`.
+#[macro_export]
+macro_rules! should_fault {
+    ($exception: ident, fn $name: ident() $body: block) => {
+        fn $name() $body
+
+        mod $name {
+            #[test_case]
+            const TEST: $crate::testing::ExpectedFailure = $crate::testing::ExpectedFailure::new(
+                module_path!(),
+                $crate::testing::Expected::Fault($crate::interrupts::idt::CpuExceptionIndex::$exception),
+                super::$name,
+            );
+        }
+    };
+}
+
+/* isolating tests from faults.
+    A test runs with a table of its own loaded, the kernel's with the faults taken
+    over. A fault in kernel code, which the kernel's handlers would log and retry or
+    panic on, is reported and the run goes on with the next test: the handler
+    returns into `run_isolated` where the test was started, the way longjmp would,
+    leaving the rest of the test behind. Faults in ring 3 still go to the kernel's
+    handlers, and the kernel's table is back between tests. The other CPUs keep the
+    kernel's table, a test's threads are caught when they fault on this one.
+ */
+
+// the registers `guarded_call` started the test with, for the fault handler to go back to
+#[repr(C)]
+struct Recovery {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rflags: u64,
+}
+
+static mut RECOVERY: Recovery = Recovery { rbx: 0, rbp: 0, r12: 0, r13: 0, r14: 0, r15: 0, rsp: 0, rflags: 0 };
+
+const NO_RUNNER: u64 = u64::MAX;
+
+// the thread in `run_isolated`, and whether the test it runs faulted
+static RUNNER: AtomicU64 = AtomicU64::new(NO_RUNNER);
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn run_test(test: *const &dyn Testable) {
+    unsafe { (*test).run() };
+}
+
+/// Runs the test, returning 0; the fault handler makes it return 1 instead.
+#[naked]
+unsafe extern "C" fn guarded_call(test: *const &dyn Testable) -> u64 {
+    unsafe {
+        asm!(
+            "lea rdx, [rip + {recovery}]",
+            "mov [rdx], rbx",
+            "mov [rdx + 8], rbp",
+            "mov [rdx + 16], r12",
+            "mov [rdx + 24], r13",
+            "mov [rdx + 32], r14",
+            "mov [rdx + 40], r15",
+            "mov [rdx + 48], rsp",
+            "pushfq",
+            "pop qword ptr [rdx + 56]",
+
+            "sub rsp, 8",
+            "call {run}",
+            "add rsp, 8",
+            "xor eax, eax",
+            "ret",
+            recovery = sym RECOVERY,
+            run = sym run_test,
+            options(noreturn)
+        );
+    }
+}
+
+/// Where a faulted test is left for, it returns from `guarded_call` with 1.
+#[naked]
+extern "C" fn resume_runner() -> ! {
+    unsafe {
+        asm!(
+            "lea rdx, [rip + {recovery}]",
+            "mov rbx, [rdx]",
+            "mov rbp, [rdx + 8]",
+            "mov r12, [rdx + 16]",
+            "mov r13, [rdx + 24]",
+            "mov r14, [rdx + 32]",
+            "mov r15, [rdx + 40]",
+            "mov rsp, [rdx + 48]",
+            "mov eax, 1",
+            "ret",
+            recovery = sym RECOVERY,
+            options(noreturn)
+        );
+    }
+}
+
+fn trapped(exception: CpuExceptionIndex, stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    if stack_frame.is_user() {
+        interrupts::handle_exception(exception, stack_frame, error_code);
+        return;
+    }
+    failed(Expected::Fault(exception));
+
+    // the faulted code may hold the serial lock, it is never returned to
+    unsafe { crate::serial::SERIAL1.force_unlock() };
+    serial_println!("[failed]\n");
+    serial_println!("Error: EXCEPTION {:?}, error code {:#x}\n{:#?}\n{}\n",
+        exception, error_code, stack_frame, Backtrace::from_exception(stack_frame));
+    FAULTED.store(true, Ordering::Relaxed);
+    // a thread the test started is ended, the test goes on and is failed when it returns
+    if RUNNER.load(Ordering::Relaxed) != thread::current_id().as_u64() {
+        thread::exit(ExitCode::FAILURE);
+    }
+    let recovery = unsafe { &*core::ptr::addr_of!(RECOVERY) };
+    stack_frame.resume_at(resume_runner as *const () as u64, recovery.rsp, recovery.rflags);
+}
+
+macro_rules! trap {
+    ($name: ident, $exception: ident) => {
+        extern "C" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            trapped(CpuExceptionIndex::$exception, stack_frame, 0);
+        }
+    };
+    ($name: ident, $exception: ident, error_code) => {
+        extern "C" fn $name(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+            trapped(CpuExceptionIndex::$exception, stack_frame, error_code);
+        }
+    };
+}
+
+trap!(trap_divide_error, DivisionError);
+trap!(trap_overflow, Overflow);
+trap!(trap_bound_range, BoundRangeExceeded);
+trap!(trap_invalid_opcode, InvalidOpcode);
+trap!(trap_double_fault, DoubleFault, error_code);
+trap!(trap_invalid_tss, InvalidTSS, error_code);
+trap!(trap_segment_not_present, SegmentNotPresent, error_code);
+trap!(trap_stack_segment, StackSegmentFault, error_code);
+trap!(trap_general_protection, GeneralProtectionFault, error_code);
+trap!(trap_page_fault, PageFault, error_code);
+
+lazy_static! {
+    static ref TEST_IDT: Idt = {
+        use CpuExceptionIndex::*;
+        // breakpoints and the lazy FPU switch are not faults, they keep the kernel's entries
+        let mut idt = interrupts::kernel_idt().clone();
+        idt.set_handler(IdtIndex::CpuException(DivisionError), handler!(trap_divide_error));
+        idt.set_handler(IdtIndex::CpuException(Overflow), handler!(trap_overflow));
+        idt.set_handler(IdtIndex::CpuException(BoundRangeExceeded), handler!(trap_bound_range));
+        idt.set_handler(IdtIndex::CpuException(InvalidOpcode), handler!(trap_invalid_opcode));
+        idt.set_handler(IdtIndex::CpuException(DoubleFault), handler_with_error_code!(trap_double_fault))
+            .set_stack_index(gdt::ISTIndex::DoubleFaultISTIndex as u16);
+        idt.set_handler(IdtIndex::CpuException(InvalidTSS), handler_with_error_code!(trap_invalid_tss));
+        idt.set_handler(IdtIndex::CpuException(SegmentNotPresent), handler_with_error_code!(trap_segment_not_present));
+        idt.set_handler(IdtIndex::CpuException(StackSegmentFault), handler_with_error_code!(trap_stack_segment));
+        idt.set_handler(IdtIndex::CpuException(GeneralProtectionFault),
+                handler_with_error_code!(trap_general_protection));
+        idt.set_handler(IdtIndex::CpuException(PageFault), handler_with_error_code!(trap_page_fault));
+        idt
+    };
+}
+
+/* leak checks.
+    A test is to leave the heap as it found it. Work it queued is waited for and the
+    threads it ended get a chance to be freed, then whatever is still allocated on
+    top of what was before is reported with the test's name and fails it. Tests that
+    leave things behind on purpose, files in the root file system or a server that
+    keeps running, call `keeps_heap`.
+ */
+
+static KEEPS_HEAP: AtomicBool = AtomicBool::new(false);
+
+/// Exempts the running test from the leak check.
+pub fn keeps_heap() {
+    KEEPS_HEAP.store(true, Ordering::Relaxed);
+}
+
+fn leak_check(test: &dyn Testable, before: allocator::HeapUsage) -> bool {
+    workqueue::flush();
+    thread::yield_now();
+    let after = allocator::usage();
+    if after.allocations <= before.allocations && after.bytes <= before.bytes {
+        return true;
+    }
+    serial_println!("LEAK: '{}' left {} allocations, {} bytes on the heap\n", test.name(),
+        after.allocations as isize - before.allocations as isize,
+        after.bytes as isize - before.bytes as isize);
+    false
+}
+
+/// Runs `test` with faults trapped, returns whether it passed; one that faulted or
+/// leaked has been reported.
+pub fn run_isolated(test: &dyn Testable) -> bool {
+    FAULTED.store(false, Ordering::Relaxed);
+    KEEPS_HEAP.store(false, Ordering::Relaxed);
+    let before = allocator::usage();
+    RUNNER.store(thread::current_id().as_u64(), Ordering::Relaxed);
+    TEST_IDT.load();
+    let abandoned = unsafe { guarded_call(&test) } != 0;
+    interrupts::init_idt();
+    RUNNER.store(NO_RUNNER, Ordering::Relaxed);
+    if abandoned || FAULTED.load(Ordering::Relaxed) {
+        return false;
+    }
+    KEEPS_HEAP.load(Ordering::Relaxed) || leak_check(test, before)
+}
+
+/* picking tests from the host.
+    Before the tests start the runner may send a line over the serial port,
+        filter <pattern> <pattern> ...
+    and only the tests whose names contain one of the patterns run; runner.sh sends
+    BLOG_OS_TEST_FILTER that way, as in
+        BLOG_OS_TEST_FILTER=fat32 cargo test
+    Without a line waiting soon after boot, or an empty one, every test runs. Test
+    binaries may run without a heap or a calibrated clock, this needs neither; with
+    no clock it only takes what already came in.
+ */
+
+const FILTER_PREFIX: &[u8] = b"filter ";
+// how long to wait for the line to start, and then for it to end
+const FILTER_WAIT_MS: u64 = 50;
+const FILTER_LINE_MS: u64 = 1000;
+const MAX_FILTER_LEN: usize = 256;
+
+/// The patterns the host picked tests with.
+pub struct Filter {
+    line: [u8; MAX_FILTER_LEN],
+    len: usize,
+}
+
+impl Filter {
+    pub const fn all() -> Self {
+        Filter { line: [0; MAX_FILTER_LEN], len: 0 }
+    }
+
+    fn patterns(&self) -> impl Iterator<Item = &str> {
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or("").split_ascii_whitespace()
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.patterns().next().is_none()
+    }
+
+    /// Whether the test `name` is picked, every test when there are no patterns.
+    pub fn selects(&self, name: &str) -> bool {
+        self.is_all() || self.patterns().any(|pattern| name.contains(pattern))
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, pattern) in self.patterns().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(pattern)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the filter line the host sent, all tests without one.
+pub fn receive_filter() -> Filter {
+    let cycles = |ms: u64| tsc::frequency() * ms / 1000;
+    let mut line = [0u8; MAX_FILTER_LEN + FILTER_PREFIX.len()];
+    let mut len = 0;
+    let mut deadline = tsc::read() + cycles(FILTER_WAIT_MS);
+    loop {
+        let received = crate::serial::SERIAL1.lock().try_receive();
+        match received {
+            Ok(b'\n') => break,
+            Ok(byte) => {
+                if len == 0 {
+                    deadline = tsc::read() + cycles(FILTER_LINE_MS);
+                }
+                if len < line.len() {
+                    line[len] = byte;
+                    len += 1;
+                }
+            }
+            Err(_) if tsc::read() >= deadline => break,
+            Err(_) => core::hint::spin_loop(),
+        }
+    }
+    let mut filter = Filter::all();
+    if let Some(patterns) = line[..len].strip_prefix(FILTER_PREFIX) {
+        let patterns = patterns.strip_suffix(b"\r").unwrap_or(patterns);
+        filter.line[..patterns.len()].copy_from_slice(patterns);
+        filter.len = patterns.len();
+    }
+    filter
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_filter() {
+        let mut filter = Filter::all();
+        assert!(filter.selects("blog_os::trace::test::test_render"));
+        let patterns = b"fat32  test_fork";
+        filter.line[..patterns.len()].copy_from_slice(patterns);
+        filter.len = patterns.len();
+        assert!(filter.selects("blog_os::fs::fat32::test::test_mount"));
+        assert!(filter.selects("blog_os::memory::address_space::test::test_fork_copies_on_write"));
+        assert!(!filter.selects("blog_os::trace::test::test_render"));
+        assert_eq!(alloc::format!("{}", filter), "fat32 test_fork");
+    }
+
+    crate::should_panic! {
+        fn test_should_panic() {
+            let values: [u8; 0] = [];
+            let index = core::hint::black_box(3);
+            let _ = values[index];
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use core::arch::asm;
+
+/* callee-saved registers pushed by `switch_context`, lowest address first.
+    A new thread gets the same layout with its entry trampoline as return address,
+    so switching to it for the first time looks like returning from `switch_context`.
+ */
+const SAVED_REGISTERS: usize = 6;
+const R12_SLOT: usize = 3;
+
+/// Saves the callee-saved registers on the current stack, stores the stack pointer
+/// into `old_rsp` and continues on the stack `new_rsp` left by an earlier switch.
+///
+/// # Safety
+/// `new_rsp` must come from a previous `switch_context` or `init_stack`, and interrupts
+/// must be disabled so nothing runs on a half-switched stack.
+#[naked]
+pub unsafe extern "C" fn switch_context(old_rsp: *mut u64, new_rsp: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    );
+}
+
+#[naked]
+unsafe extern "C" fn thread_trampoline() -> ! {
+    asm!(
+        // r12 carries the boxed entry closure prepared by init_stack
+        "mov rdi, r12",
+        "call {start}",
+        "ud2",
+        start = sym super::thread_start,
+        options(noreturn)
+    );
+}
+
+/// Prepares a fresh stack so that `switch_context` into it starts `thread_start(arg)`.
+/// Returns the initial stack pointer.
+pub fn init_stack(stack_top: u64, arg: u64) -> u64 {
+    // after `ret` pops the trampoline address rsp is 16 byte aligned, as the ABI wants before a call
+    let top = stack_top & !0xf;
+    let rsp = top - 8 * (SAVED_REGISTERS as u64 + 1);
+    let frame = rsp as *mut u64;
+    unsafe {
+        for slot in 0..SAVED_REGISTERS {
+            frame.add(slot).write(0);
+        }
+        frame.add(R12_SLOT).write(arg);
+        frame.add(SAVED_REGISTERS).write(thread_trampoline as *const () as u64);
+    }
+    rsp
+}
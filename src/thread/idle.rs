@@ -0,0 +1,32 @@
+use x86_64::instructions::interrupts;
+
+use super::scheduler::SCHEDULER;
+use super::yield_now;
+use crate::time;
+
+/* runs whenever no other thread is ready.
+    With no timer due soon the periodic tick is replaced by a single longer PIT
+    one-shot, so an idle kernel is not woken up a hundred times a second for nothing.
+ */
+pub(super) fn idle_loop() {
+    loop {
+        interrupts::disable();
+        if SCHEDULER.lock().has_ready() {
+            interrupts::enable();
+            yield_now();
+            continue;
+        }
+
+        let now = time::ticks();
+        let sleep = match time::next_timer_deadline() {
+            Some(deadline) => deadline.saturating_sub(now),
+            None => u64::MAX,
+        };
+        time::enter_tickless(sleep);
+
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+        time::exit_tickless();
+        interrupts::enable();
+    }
+}
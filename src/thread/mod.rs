@@ -0,0 +1,633 @@
+pub mod context;
+pub mod scheduler;
+mod idle;
+pub mod wait_queue;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::structures::paging::PhysFrame;
+
+use crate::fpu::FpuState;
+use crate::memory::AddressSpace;
+use crate::{percpu, stack_canary};
+use crate::process::{self, Process};
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+use scheduler::{Decision, SCHEDULER};
+
+pub use scheduler::{AffinityError, CpuMask, Priority};
+pub use wait_queue::WaitQueue;
+
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitCode(pub i32);
+
+impl ExitCode {
+    pub const SUCCESS: ExitCode = ExitCode(0);
+    pub const FAILURE: ExitCode = ExitCode(1);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Blocked,
+    Dead,
+}
+
+#[derive(Debug, Default)]
+struct Context {
+    rsp: u64,
+}
+
+pub struct KernelStack {
+    // words, for the canary at the bottom to be aligned
+    memory: Box<[u64]>,
+}
+
+impl KernelStack {
+    fn new(size: usize) -> Self {
+        let stack = KernelStack { memory: vec![0u64; size / 8].into_boxed_slice() };
+        unsafe { stack_canary::plant(stack.bottom()) };
+        stack
+    }
+
+    /// Whether the stack stayed above its canary.
+    pub fn canary_intact(&self) -> bool {
+        unsafe { stack_canary::is_intact(self.bottom()) }
+    }
+
+    pub fn bottom(&self) -> u64 {
+        self.memory.as_ptr() as u64
+    }
+
+    pub fn top(&self) -> u64 {
+        self.bottom() + core::mem::size_of_val(&*self.memory) as u64
+    }
+}
+
+pub struct ThreadControlBlock {
+    id: ThreadId,
+    name: String,
+    state: ThreadState,
+    priority: Priority,
+    // static priority plus aging boost while waiting in a ready queue
+    effective_priority: Priority,
+    // lent by more important threads waiting on mutexes this one holds, by mutex
+    inherited: Vec<(usize, Priority)>,
+    wakeup_pending: bool,
+    affinity: CpuMask,
+    // the CPU it runs or last ran on, preferred when it becomes ready again
+    cpu: usize,
+    // TSC cycles spent running, not counting the current stretch
+    run_cycles: u64,
+    switches: u64,
+    // TSC reading when it last got or gave up a CPU, 0 if it never ran
+    last_ran: u64,
+    context: Context,
+    // None for the boot thread, which keeps running on the bootloader's stack
+    stack: Option<KernelStack>,
+    fpu_state: Box<FpuState>,
+    // kernel threads have neither and run on the kernel's page tables
+    process: Option<Arc<Mutex<Process>>>,
+    page_table: Option<PhysFrame>,
+}
+
+type ThreadEntry = Box<dyn FnOnce() + Send + 'static>;
+
+impl ThreadControlBlock {
+    fn new(name: &str, priority: Priority, entry: ThreadEntry) -> Self {
+        let stack = KernelStack::new(KERNEL_STACK_SIZE);
+        let entry = Box::into_raw(Box::new(entry));
+        let rsp = context::init_stack(stack.top(), entry as u64);
+        ThreadControlBlock {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state: ThreadState::Ready,
+            priority,
+            effective_priority: priority,
+            inherited: Vec::new(),
+            wakeup_pending: false,
+            affinity: CpuMask::ALL,
+            cpu: 0,
+            run_cycles: 0,
+            switches: 0,
+            last_ran: 0,
+            context: Context { rsp },
+            stack: Some(stack),
+            fpu_state: Box::new(FpuState::new()),
+            process: None,
+            page_table: None,
+        }
+    }
+
+    fn adopt_boot() -> Self {
+        ThreadControlBlock {
+            id: ThreadId::new(),
+            name: String::from("boot"),
+            state: ThreadState::Running,
+            priority: Priority::NORMAL,
+            effective_priority: Priority::NORMAL,
+            inherited: Vec::new(),
+            wakeup_pending: false,
+            affinity: CpuMask::ALL,
+            cpu: 0,
+            run_cycles: 0,
+            switches: 0,
+            last_ran: 0,
+            context: Context::default(),
+            stack: None,
+            fpu_state: Box::new(FpuState::new()),
+            process: None,
+            page_table: None,
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// The priority the thread is scheduled at when not aged, including an inherited one.
+    pub fn base_priority(&self) -> Priority {
+        self.inherited.iter().map(|&(_, priority)| priority).fold(self.priority, Priority::max)
+    }
+
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    pub fn stack(&self) -> Option<&KernelStack> {
+        self.stack.as_ref()
+    }
+
+    pub fn process(&self) -> Option<&Arc<Mutex<Process>>> {
+        self.process.as_ref()
+    }
+
+    fn fpu_state_ptr(&self) -> *mut FpuState {
+        &*self.fpu_state as *const FpuState as *mut FpuState
+    }
+}
+
+extern "C" fn thread_start(entry: *mut ThreadEntry) -> ! {
+    // we got here through a switch with interrupts disabled
+    SCHEDULER.lock().finish_switch();
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit(ExitCode::SUCCESS);
+}
+
+// joiners of all threads share one queue and recheck for their own exit code
+static JOINERS: WaitQueue = WaitQueue::new();
+
+/// Handle to a spawned thread. Dropping it without joining detaches the thread.
+pub struct Thread {
+    id: ThreadId,
+    joined: bool,
+}
+
+impl Thread {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Waits for the thread to exit and returns its exit code.
+    pub fn join(mut self) -> ExitCode {
+        assert_ne!(self.id, current_id(), "thread tried to join itself");
+        self.joined = true;
+        let id = self.id;
+        let mut code = None;
+        JOINERS.wait_until(|| {
+            code = without_interrupts(|| SCHEDULER.lock().take_exit_code(id));
+            code.is_some()
+        });
+        code.expect("woken without an exit code")
+    }
+
+    /// Lets the thread run on its own, its stack and exit code are freed once it exits.
+    pub fn detach(self) {}
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if !self.joined {
+            without_interrupts(|| SCHEDULER.lock().detach(self.id));
+        }
+    }
+}
+
+/// Takes the calling CPU into scheduling and sets up its idle thread; the code calling
+/// this becomes the CPU's first thread.
+pub fn init() {
+    let idle = Box::new(ThreadControlBlock::new("idle", Priority::IDLE, Box::new(idle::idle_loop)));
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.add_cpu();
+        scheduler.set_idle(idle);
+    });
+}
+
+pub fn spawn<F>(name: &str, f: F) -> Thread
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_priority(name, Priority::NORMAL, f)
+}
+
+pub fn spawn_with_priority<F>(name: &str, priority: Priority, f: F) -> Thread
+where
+    F: FnOnce() + Send + 'static,
+{
+    start(Box::new(ThreadControlBlock::new(name, priority, Box::new(f))))
+}
+
+/// Spawns a thread belonging to `process`, running on its address space.
+pub fn spawn_in<F>(process: Arc<Mutex<Process>>, name: &str, f: F) -> Thread
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut thread = Box::new(ThreadControlBlock::new(name, Priority::NORMAL, Box::new(f)));
+    without_interrupts(|| {
+        let mut locked = process.lock();
+        process::table::attach(locked.id(), &process);
+        locked.add_thread(thread.id);
+        thread.page_table = Some(locked.address_space().p4_frame());
+    });
+    thread.process = Some(process);
+    start(thread)
+}
+
+fn start(thread: Box<ThreadControlBlock>) -> Thread {
+    let id = thread.id;
+    without_interrupts(|| SCHEDULER.lock().add(thread));
+    reschedule_if_needed();
+    Thread { id, joined: false }
+}
+
+/* outside of interrupt handlers and critical sections a more important thread is
+    switched to right away, otherwise the next timer tick takes care of it.
+ */
+fn reschedule_if_needed() {
+    if interrupts::are_enabled() && without_interrupts(|| SCHEDULER.lock().take_need_resched()) {
+        switch();
+    }
+}
+
+fn switch() {
+    without_interrupts(|| loop {
+        // the lock must be released before the stack changes under us
+        let decision = SCHEDULER.lock().schedule();
+        match decision {
+            Decision::Switch(switch) => {
+                unsafe { context::switch_context(switch.old_rsp, switch.new_rsp) };
+                // back on this thread, possibly on another CPU
+                SCHEDULER.lock().finish_switch();
+                break;
+            }
+            Decision::Stay => break,
+            Decision::Idle => {
+                // wait for an interrupt to make some thread runnable
+                interrupts::enable_and_hlt();
+                interrupts::disable();
+            }
+        }
+    });
+}
+
+pub fn yield_now() {
+    switch();
+}
+
+/// Blocks the current thread until `unpark` is called for it.
+/// Returns right away if it was unparked since the last call.
+pub fn park() {
+    without_interrupts(|| {
+        SCHEDULER.lock().block_current();
+        switch();
+    });
+}
+
+pub fn unpark(id: ThreadId) -> bool {
+    let found = without_interrupts(|| SCHEDULER.lock().unblock(id));
+    reschedule_if_needed();
+    found
+}
+
+pub fn set_priority(priority: Priority) {
+    without_interrupts(|| SCHEDULER.lock().set_priority(priority));
+    reschedule_if_needed();
+}
+
+pub fn current_priority() -> Priority {
+    without_interrupts(|| SCHEDULER.lock().current().base_priority())
+}
+
+/// Lends `priority` to thread `id` for as long as it holds the mutex `lock`, until
+/// it calls `drop_inherited_priority` for it.
+pub fn inherit_priority(id: ThreadId, lock: usize, priority: Priority) {
+    without_interrupts(|| SCHEDULER.lock().inherit_priority(id, lock, priority));
+}
+
+/// Gives up what was lent for `lock`, keeping what waiters on the other mutexes the
+/// current thread holds lent it; gives way if that is now too low.
+pub fn drop_inherited_priority(lock: usize) {
+    without_interrupts(|| SCHEDULER.lock().drop_inherited_priority(lock));
+    reschedule_if_needed();
+}
+
+/// Restricts thread `id` to the CPUs in `mask`; a running thread leaves a CPU it may
+/// no longer use at its next reschedule.
+pub fn set_affinity(id: ThreadId, mask: CpuMask) -> Result<(), AffinityError> {
+    let result = without_interrupts(|| SCHEDULER.lock().set_affinity(id, mask));
+    reschedule_if_needed();
+    result
+}
+
+pub fn affinity(id: ThreadId) -> Option<CpuMask> {
+    without_interrupts(|| SCHEDULER.lock().affinity(id))
+}
+
+/* preemption, called from the timer interrupt after the PIC got its EOI.
+    The interrupted thread's scratch registers and interrupt frame already sit on its
+    own stack and the callee-saved ones are pushed by `switch_context`, so switching
+    here saves the complete register set. The thread resumes by returning from this
+    interrupt once it is scheduled again.
+ */
+pub fn preempt() {
+    let need_resched = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.tick();
+        scheduler.take_need_resched()
+    };
+    if need_resched {
+        switch();
+    }
+}
+
+/// Switches away from an interrupt handler if the scheduler asked for it, without
+/// accounting a tick. The reschedule interprocessor interrupt ends up here.
+pub fn preempt_if_needed() {
+    if SCHEDULER.lock().take_need_resched() {
+        switch();
+    }
+}
+
+pub fn exit(code: ExitCode) -> ! {
+    if let Some(process) = current_process() {
+        process::thread_exited(&process, current_id(), code);
+    }
+    // with interrupts off, waking the joiners cannot switch away before we are marked dead
+    interrupts::disable();
+    SCHEDULER.lock().exit_current(code);
+    JOINERS.wake_all();
+    switch();
+    unreachable!("dead thread was scheduled again");
+}
+
+pub fn current_id() -> ThreadId {
+    ThreadId(percpu!(current_thread).load(Ordering::Relaxed))
+}
+
+/// Calls `f` with the current thread, for the logger and the panic handler:
+/// `None` instead of waiting when the scheduler is locked, or before this CPU runs threads.
+pub fn try_with_current<R>(f: impl FnOnce(&ThreadControlBlock) -> R) -> Option<R> {
+    let cpu = percpu::try_this_cpu()?.cpu_id;
+    without_interrupts(|| Some(f(SCHEDULER.try_lock()?.current_on(cpu)?)))
+}
+
+pub fn current_process() -> Option<Arc<Mutex<Process>>> {
+    without_interrupts(|| SCHEDULER.lock().current().process().cloned())
+}
+
+/// Runs the current thread on `space` from now on, loading it right away.
+pub fn switch_address_space(space: &AddressSpace) {
+    without_interrupts(|| {
+        SCHEDULER.lock().set_current_page_table(space.p4_frame());
+        space.activate();
+    });
+}
+
+/// Registers `ps`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("ps", "list the threads", ps);
+}
+
+#[cfg(feature = "shell")]
+fn ps(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("ps"));
+    }
+    let _ = writeln!(out, "   ID CPU PRI STATE      SWITCHES      TIME NAME");
+    for thread in scheduler::stats() {
+        // hundredths of a second
+        let time = thread.run_time.as_millis() / 10;
+        let _ = writeln!(
+            out,
+            "{:>5} {:>3} {:>3} {:<8} {:>10} {:>6}.{:02} {}",
+            thread.id.as_u64(), thread.cpu, thread.priority.level(), alloc::format!("{:?}", thread.state),
+            thread.context_switches, time / 100, time % 100, thread.name,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+    #[test_case]
+    fn test_spawn_and_yield() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        for _ in 0..4 {
+            spawn("worker", || {
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+                yield_now();
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        while COUNTER.load(Ordering::SeqCst) < 8 {
+            yield_now();
+        }
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 8);
+    }
+
+    #[test_case]
+    fn test_threads_have_own_stacks() {
+        static SEEN: AtomicU64 = AtomicU64::new(0);
+
+        let main_id = current_id();
+        spawn("stack-check", || {
+            let local = 0u64;
+            SEEN.store(&local as *const u64 as u64, Ordering::SeqCst);
+        });
+        while SEEN.load(Ordering::SeqCst) == 0 {
+            yield_now();
+        }
+        let local = 0u64;
+        assert_ne!(SEEN.load(Ordering::SeqCst) & !0xfff, &local as *const u64 as u64 & !0xfff);
+        assert_eq!(current_id(), main_id);
+    }
+
+    #[test_case]
+    fn test_preemption() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+
+        spawn("preempt-target", || FLAG.store(true, Ordering::SeqCst));
+        // never yields, only the timer can let the other thread run
+        while !FLAG.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test_case]
+    fn test_park_unpark() {
+        static STAGE: AtomicUsize = AtomicUsize::new(0);
+
+        let main_id = current_id();
+        let worker = spawn("parker", move || {
+            STAGE.store(1, Ordering::SeqCst);
+            park();
+            STAGE.store(2, Ordering::SeqCst);
+            unpark(main_id);
+        });
+        while STAGE.load(Ordering::SeqCst) != 1 {
+            yield_now();
+        }
+        assert!(unpark(worker.id()));
+        park();
+        assert_eq!(STAGE.load(Ordering::SeqCst), 2);
+    }
+
+    #[test_case]
+    fn test_join_returns_exit_code() {
+        let workers: alloc::vec::Vec<Thread> = (0..3)
+            .map(|i| spawn("joinee", move || {
+                yield_now();
+                exit(ExitCode(i));
+            }))
+            .collect();
+        for (i, worker) in workers.into_iter().enumerate() {
+            assert_eq!(worker.join(), ExitCode(i as i32));
+        }
+        assert_eq!(spawn("returns", || {}).join(), ExitCode::SUCCESS);
+    }
+
+    #[test_case]
+    fn test_detached_thread_is_reclaimed() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        let worker = spawn("detached", || DONE.store(true, Ordering::SeqCst));
+        let id = worker.id();
+        worker.detach();
+        while !DONE.load(Ordering::SeqCst) {
+            yield_now();
+        }
+        // nobody will join it, so no exit code is kept around
+        assert_eq!(without_interrupts(|| SCHEDULER.lock().take_exit_code(id)), None);
+    }
+
+    #[test_case]
+    fn test_higher_priority_runs_first() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+
+        // switched to as soon as it is spawned
+        spawn_with_priority("high", Priority::HIGH, || RAN.store(true, Ordering::SeqCst));
+        assert!(RAN.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn test_aging_prevents_starvation() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+
+        spawn_with_priority("low", Priority::LOW, || RAN.store(true, Ordering::SeqCst));
+        // a higher priority busy loop, the low thread only gets in through aging
+        while !RAN.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[test_case]
+    fn test_affinity() {
+        let me = current_id();
+        assert_eq!(affinity(me), Some(CpuMask::ALL));
+        assert_eq!(set_affinity(me, CpuMask::from_bits(0)), Err(AffinityError::NoOnlineCpu));
+        assert_eq!(set_affinity(me, CpuMask::single(63)), Err(AffinityError::NoOnlineCpu));
+
+        let pinned = spawn("pinned", || {
+            yield_now();
+            assert_eq!(percpu::this_cpu().cpu_id, 0);
+        });
+        assert_eq!(set_affinity(pinned.id(), CpuMask::single(0)), Ok(()));
+        assert_eq!(affinity(pinned.id()), Some(CpuMask::single(0)));
+        pinned.join();
+        assert_eq!(set_affinity(ThreadId(u64::MAX), CpuMask::ALL), Err(AffinityError::NoThread));
+    }
+
+    #[test_case]
+    fn test_scheduler_stats() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        let spinner = spawn("spinner", || {
+            while !STOP.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+        });
+        let start = crate::time::ticks();
+        while crate::time::ticks() < start + 4 {
+            yield_now();
+        }
+        let stats = scheduler::stats();
+        let me = stats.iter().find(|thread| thread.id == current_id()).expect("current thread missing");
+        assert_eq!(me.state, ThreadState::Running);
+        assert!(me.last_ran.is_some());
+        let other = stats.iter().find(|thread| thread.id == spinner.id()).expect("spawned thread missing");
+        assert_eq!(other.name, "spinner");
+        assert!(other.context_switches > 0);
+        assert!(other.run_time > core::time::Duration::ZERO);
+        assert!(stats.windows(2).all(|pair| pair[0].id < pair[1].id));
+
+        let id = spinner.id();
+        STOP.store(true, Ordering::SeqCst);
+        spinner.join();
+        assert!(scheduler::stats().iter().all(|thread| thread.id != id));
+    }
+}
@@ -0,0 +1,705 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+use super::{ExitCode, ThreadControlBlock, ThreadId, ThreadState};
+use crate::percpu::PerCpu;
+use crate::time::tsc;
+use crate::{fpu, gdt, memory, percpu, smp, syscall};
+
+/// Timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 2;
+/// Ready threads move up one priority level every this many ticks.
+pub const AGING_INTERVAL_TICKS: u64 = 10;
+/// Every this many ticks a CPU with little to do pulls a thread over from a busy one.
+pub const BALANCE_INTERVAL_TICKS: u64 = 20;
+pub const NUM_PRIORITIES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority(u8);
+
+impl Priority {
+    pub const IDLE: Priority     = Priority(0);
+    pub const LOW: Priority      = Priority(2);
+    pub const NORMAL: Priority   = Priority(4);
+    pub const HIGH: Priority     = Priority(6);
+    pub const REALTIME: Priority = Priority(7);
+
+    pub const fn new(level: u8) -> Self {
+        assert!((level as usize) < NUM_PRIORITIES, "priority level out of range");
+        Priority(level)
+    }
+
+    pub fn level(self) -> usize {
+        self.0 as usize
+    }
+
+    fn raised(self) -> Self {
+        Priority((self.0 + 1).min(NUM_PRIORITIES as u8 - 1))
+    }
+}
+
+/// The CPUs a thread may run on, bit n standing for the CPU with id n.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMask(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityError {
+    NoThread,
+    /// None of the CPUs in the mask is online.
+    NoOnlineCpu,
+}
+
+impl CpuMask {
+    pub const ALL: CpuMask = CpuMask(u64::MAX);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        CpuMask(bits)
+    }
+
+    pub const fn single(cpu: usize) -> Self {
+        CpuMask(1 << cpu)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, cpu: usize) -> bool {
+        cpu < 64 && self.0 & (1 << cpu) != 0
+    }
+}
+
+pub(super) static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+/// Panics naming the thread when its stack grew into the canary below it.
+fn check_stack(thread: &ThreadControlBlock) {
+    if thread.stack.as_ref().is_some_and(|stack| !stack.canary_intact()) {
+        panic!("kernel stack overflow: the canary of thread {} '{}' is smashed", thread.id.as_u64(), thread.name);
+    }
+}
+
+/// One FIFO per priority level, threads are queued by their effective (aged) priority.
+struct ReadyQueues {
+    queues: [VecDeque<Box<ThreadControlBlock>>; NUM_PRIORITIES],
+}
+
+impl ReadyQueues {
+    const fn new() -> Self {
+        ReadyQueues { queues: [const { VecDeque::new() }; NUM_PRIORITIES] }
+    }
+
+    fn push(&mut self, thread: Box<ThreadControlBlock>) {
+        self.queues[thread.effective_priority.level()].push_back(thread);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Box<ThreadControlBlock>> {
+        self.queues.iter().flatten()
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn highest(&self) -> Option<Priority> {
+        (0..NUM_PRIORITIES).rev()
+            .find(|&level| !self.queues[level].is_empty())
+            .map(|level| Priority(level as u8))
+    }
+
+    fn pop_highest(&mut self) -> Option<Box<ThreadControlBlock>> {
+        self.queues.iter_mut().rev().find_map(|queue| queue.pop_front())
+    }
+
+    fn contains(&self, id: ThreadId) -> bool {
+        self.queues.iter().flatten().any(|thread| thread.id == id)
+    }
+
+    fn find_mut(&mut self, id: ThreadId) -> Option<&mut Box<ThreadControlBlock>> {
+        self.queues.iter_mut().flat_map(|queue| queue.iter_mut()).find(|thread| thread.id == id)
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Box<ThreadControlBlock>> {
+        self.remove_where(|thread| thread.id == id)
+    }
+
+    /// Takes out the least important thread matching `predicate`.
+    fn remove_where<P: Fn(&ThreadControlBlock) -> bool>(&mut self, predicate: P) -> Option<Box<ThreadControlBlock>> {
+        self.queues.iter_mut().find_map(|queue| {
+            let index = queue.iter().rposition(|thread| predicate(thread))?;
+            queue.remove(index)
+        })
+    }
+
+    /* starvation avoidance.
+        Every waiting thread climbs one level; walking from the top down makes sure
+        a thread is raised only once per round. The boost is dropped when it runs.
+     */
+    fn age(&mut self) {
+        for level in (0..NUM_PRIORITIES - 1).rev() {
+            while let Some(mut thread) = self.queues[level].pop_front() {
+                thread.effective_priority = thread.effective_priority.raised();
+                self.queues[level + 1].push_back(thread);
+            }
+        }
+    }
+}
+
+/* what one CPU runs.
+    Each CPU picks threads from its own ready queues only, so CPUs do not fight over
+    a shared queue; threads move between CPUs when they are woken up, when their
+    affinity changes, or when the balancer evens out the load.
+ */
+struct RunQueue {
+    cpu: &'static PerCpu,
+    current: Option<Box<ThreadControlBlock>>,
+    // switched away from but still on its stack until the switch completes
+    previous: Option<Box<ThreadControlBlock>>,
+    ready: ReadyQueues,
+    // kept out of the ready queues so aging never lifts it above real work
+    idle: Option<Box<ThreadControlBlock>>,
+    idle_id: Option<ThreadId>,
+    slice_remaining: u64,
+    aging_countdown: u64,
+    balance_countdown: u64,
+    need_resched: bool,
+    // TSC reading when the current thread got the CPU
+    running_since: u64,
+}
+
+impl RunQueue {
+    fn new(cpu: &'static PerCpu) -> Self {
+        RunQueue {
+            cpu,
+            current: None,
+            previous: None,
+            ready: ReadyQueues::new(),
+            idle: None,
+            idle_id: None,
+            slice_remaining: TIME_SLICE_TICKS,
+            aging_countdown: AGING_INTERVAL_TICKS,
+            balance_countdown: BALANCE_INTERVAL_TICKS,
+            need_resched: false,
+            running_since: tsc::read(),
+        }
+    }
+
+    fn current_mut(&mut self) -> &mut Box<ThreadControlBlock> {
+        self.current.as_mut().expect("CPU has no current thread")
+    }
+
+    fn current_is_idle(&self) -> bool {
+        self.current.as_ref().is_some_and(|current| Some(current.id) == self.idle_id)
+    }
+
+    /// Threads that want this CPU, the one running included unless it is the idle thread.
+    fn load(&self) -> usize {
+        self.ready.len() + usize::from(!self.current_is_idle())
+    }
+
+    fn cpu_id(&self) -> usize {
+        self.cpu.cpu_id
+    }
+}
+
+pub(super) struct Scheduler {
+    // indexed by CPU id
+    cpus: Vec<RunQueue>,
+    blocked: BTreeMap<ThreadId, Box<ThreadControlBlock>>,
+    // threads with a live handle, their exit code is kept until joined
+    joinable: BTreeSet<ThreadId>,
+    exit_codes: BTreeMap<ThreadId, ExitCode>,
+}
+
+/// Stack pointers handed to `switch_context` once the scheduler lock is dropped.
+pub(super) struct Switch {
+    pub old_rsp: *mut u64,
+    pub new_rsp: u64,
+}
+
+pub(super) enum Decision {
+    Switch(Switch),
+    Stay,
+    // the current thread cannot continue and nothing else is ready
+    Idle,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler {
+            cpus: Vec::new(),
+            blocked: BTreeMap::new(),
+            joinable: BTreeSet::new(),
+            exit_codes: BTreeMap::new(),
+        }
+    }
+
+    /// Takes the calling CPU into scheduling, the code running on it becomes a thread.
+    pub fn add_cpu(&mut self) {
+        let cpu = percpu::this_cpu();
+        assert_eq!(cpu.cpu_id, self.cpus.len(), "CPUs have to join in the order of their ids");
+        let mut queue = RunQueue::new(cpu);
+        let mut thread = Box::new(ThreadControlBlock::adopt_boot());
+        thread.cpu = cpu.cpu_id;
+        fpu::adopt(thread.fpu_state_ptr());
+        cpu.current_thread.store(thread.id.as_u64(), Ordering::Relaxed);
+        queue.current = Some(thread);
+        self.cpus.push(queue);
+    }
+
+    fn local(&mut self) -> &mut RunQueue {
+        &mut self.cpus[percpu::this_cpu().cpu_id]
+    }
+
+    pub fn current(&mut self) -> &ThreadControlBlock {
+        self.local().current_mut()
+    }
+
+    fn current_mut(&mut self) -> &mut Box<ThreadControlBlock> {
+        self.local().current_mut()
+    }
+
+    /// The thread running on `cpu`, if the CPU has joined and runs one.
+    pub fn current_on(&self, cpu: usize) -> Option<&ThreadControlBlock> {
+        self.cpus.get(cpu)?.current.as_deref()
+    }
+
+    pub fn add(&mut self, mut thread: Box<ThreadControlBlock>) {
+        self.joinable.insert(thread.id);
+        // new threads start out next to their creator, the balancer spreads them later
+        thread.cpu = percpu::this_cpu().cpu_id;
+        self.make_ready(thread);
+    }
+
+    pub fn set_idle(&mut self, mut thread: Box<ThreadControlBlock>) {
+        thread.state = ThreadState::Ready;
+        let queue = self.local();
+        thread.cpu = queue.cpu_id();
+        queue.idle_id = Some(thread.id);
+        queue.idle = Some(thread);
+    }
+
+    pub fn has_ready(&mut self) -> bool {
+        self.local().ready.highest().is_some()
+    }
+
+    /* where a runnable thread goes.
+        The CPU it last ran on while the affinity allows it, its caches may still be
+        warm; otherwise the least loaded CPU it may use.
+     */
+    fn pick_cpu(&self, thread: &ThreadControlBlock) -> usize {
+        if thread.affinity.contains(thread.cpu) && thread.cpu < self.cpus.len() {
+            return thread.cpu;
+        }
+        self.cpus.iter()
+            .filter(|queue| thread.affinity.contains(queue.cpu_id()))
+            .min_by_key(|queue| queue.load())
+            .map(|queue| queue.cpu_id())
+            .expect("thread may not run on any online CPU")
+    }
+
+    fn make_ready(&mut self, mut thread: Box<ThreadControlBlock>) {
+        thread.state = ThreadState::Ready;
+        thread.effective_priority = thread.base_priority();
+        thread.cpu = self.pick_cpu(&thread);
+        let this = percpu::this_cpu().cpu_id;
+        let queue = &mut self.cpus[thread.cpu];
+        let preempts = queue.current_is_idle()
+            || queue.current.as_ref().is_some_and(|current| thread.effective_priority > current.base_priority());
+        queue.ready.push(thread);
+        if preempts && !queue.need_resched {
+            queue.need_resched = true;
+            if queue.cpu_id() != this {
+                smp::reschedule(queue.cpu);
+            }
+        }
+    }
+
+    pub fn exit_current(&mut self, code: ExitCode) {
+        let current = self.current_mut();
+        current.state = ThreadState::Dead;
+        let id = current.id;
+        if self.joinable.remove(&id) {
+            self.exit_codes.insert(id, code);
+        }
+    }
+
+    /// The current thread's process got a new address space, loaded on every switch to it from now on.
+    pub fn set_current_page_table(&mut self, p4: PhysFrame) {
+        self.current_mut().page_table = Some(p4);
+    }
+
+    pub fn take_exit_code(&mut self, id: ThreadId) -> Option<ExitCode> {
+        self.exit_codes.remove(&id)
+    }
+
+    /// Nobody is going to join `id`, its exit code is dropped instead of kept around.
+    pub fn detach(&mut self, id: ThreadId) {
+        if !self.joinable.remove(&id) {
+            self.exit_codes.remove(&id);
+        }
+    }
+
+    /// Marks the current thread blocked, unless a wakeup already arrived.
+    pub fn block_current(&mut self) {
+        let current = self.current_mut();
+        if current.wakeup_pending {
+            current.wakeup_pending = false;
+        } else {
+            current.state = ThreadState::Blocked;
+        }
+    }
+
+    /// A thread running or in the middle of being switched away from on some CPU.
+    fn on_cpu_mut(&mut self, id: ThreadId) -> Option<&mut Box<ThreadControlBlock>> {
+        self.cpus.iter_mut()
+            .flat_map(|queue| queue.current.iter_mut().chain(queue.previous.iter_mut()))
+            .find(|thread| thread.id == id)
+    }
+
+    fn ready_mut(&mut self, id: ThreadId) -> Option<&mut Box<ThreadControlBlock>> {
+        self.cpus.iter_mut().find_map(|queue| queue.ready.find_mut(id))
+    }
+
+    /// Makes a blocked thread runnable again. Waking a thread that is not blocked yet
+    /// is remembered, so its next block returns immediately.
+    pub fn unblock(&mut self, id: ThreadId) -> bool {
+        if let Some(thread) = self.blocked.remove(&id) {
+            self.make_ready(thread);
+            return true;
+        }
+
+        if let Some(thread) = self.on_cpu_mut(id) {
+            if thread.state == ThreadState::Blocked {
+                // still on its way out, `finish_switch` queues it as ready
+                thread.state = ThreadState::Running;
+            } else {
+                thread.wakeup_pending = true;
+            }
+            return true;
+        }
+
+        match self.ready_mut(id) {
+            Some(thread) => {
+                thread.wakeup_pending = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        let queue = self.local();
+        let current = queue.current_mut();
+        current.priority = priority;
+        current.effective_priority = current.base_priority();
+        let running = current.effective_priority;
+        if queue.ready.highest().is_some_and(|highest| highest > running) {
+            queue.need_resched = true;
+        }
+    }
+
+    /// Raises thread `id` to at least `priority` while it holds `lock`, moving it up if
+    /// it is waiting to run.
+    pub fn inherit_priority(&mut self, id: ThreadId, lock: usize, priority: Priority) {
+        let raise = |thread: &mut ThreadControlBlock| {
+            match thread.inherited.iter_mut().find(|(held, _)| *held == lock) {
+                Some((_, lent)) => *lent = (*lent).max(priority),
+                None => thread.inherited.push((lock, priority)),
+            }
+            thread.effective_priority = thread.effective_priority.max(thread.base_priority());
+        };
+        if let Some(thread) = self.on_cpu_mut(id) {
+            raise(thread);
+        } else if let Some(thread) = self.blocked.get_mut(&id) {
+            raise(thread);
+        } else if let Some(queue) = self.cpus.iter_mut().find(|queue| queue.ready.contains(id)) {
+            let mut thread = queue.ready.remove(id).unwrap();
+            raise(&mut thread);
+            queue.ready.push(thread);
+        }
+    }
+
+    pub fn drop_inherited_priority(&mut self, lock: usize) {
+        let queue = self.local();
+        let current = queue.current_mut();
+        current.inherited.retain(|&(held, _)| held != lock);
+        current.effective_priority = current.base_priority();
+        let running = current.effective_priority;
+        if queue.ready.highest().is_some_and(|highest| highest > running) {
+            queue.need_resched = true;
+        }
+    }
+
+    /// Pins thread `id` to the CPUs in `mask`, moving it off a CPU it may no longer use.
+    pub fn set_affinity(&mut self, id: ThreadId, mask: CpuMask) -> Result<(), AffinityError> {
+        if !self.cpus.iter().any(|queue| mask.contains(queue.cpu_id())) {
+            return Err(AffinityError::NoOnlineCpu);
+        }
+        let this = percpu::this_cpu().cpu_id;
+        let running = self.cpus.iter_mut()
+            .find(|queue| queue.current.as_ref().is_some_and(|current| current.id == id));
+        if let Some(queue) = running {
+            queue.current_mut().affinity = mask;
+            // `schedule` moves it away at the next opportunity
+            if !mask.contains(queue.cpu_id()) {
+                queue.need_resched = true;
+                if queue.cpu_id() != this {
+                    smp::reschedule(queue.cpu);
+                }
+            }
+            return Ok(());
+        }
+        if let Some(thread) = self.on_cpu_mut(id) {
+            thread.affinity = mask;
+            return Ok(());
+        }
+        if let Some(thread) = self.blocked.get_mut(&id) {
+            thread.affinity = mask;
+            return Ok(());
+        }
+        let queue = self.cpus.iter_mut()
+            .find(|queue| queue.ready.contains(id))
+            .ok_or(AffinityError::NoThread)?;
+        let mut thread = queue.ready.remove(id).unwrap();
+        thread.affinity = mask;
+        if mask.contains(queue.cpu_id()) {
+            queue.ready.push(thread);
+        } else {
+            self.make_ready(thread);
+        }
+        Ok(())
+    }
+
+    pub fn affinity(&mut self, id: ThreadId) -> Option<CpuMask> {
+        if let Some(thread) = self.on_cpu_mut(id) {
+            return Some(thread.affinity);
+        }
+        if let Some(thread) = self.blocked.get(&id) {
+            return Some(thread.affinity);
+        }
+        self.ready_mut(id).map(|thread| thread.affinity)
+    }
+
+    /// Accounts one timer tick on the calling CPU.
+    pub fn tick(&mut self) {
+        let queue = self.local();
+        if let Some(current) = &queue.current {
+            check_stack(current);
+        }
+        queue.aging_countdown -= 1;
+        if queue.aging_countdown == 0 {
+            queue.aging_countdown = AGING_INTERVAL_TICKS;
+            queue.ready.age();
+        }
+        queue.balance_countdown -= 1;
+        if queue.balance_countdown == 0 {
+            queue.balance_countdown = BALANCE_INTERVAL_TICKS;
+            self.balance();
+        }
+
+        let queue = self.local();
+        queue.slice_remaining = queue.slice_remaining.saturating_sub(1);
+        if queue.current_is_idle() {
+            queue.need_resched |= queue.ready.highest().is_some();
+            return;
+        }
+        let running = queue.current_mut().effective_priority;
+        match queue.ready.highest() {
+            Some(highest) if highest > running => queue.need_resched = true,
+            Some(highest) if highest == running && queue.slice_remaining == 0 => queue.need_resched = true,
+            _ => {}
+        }
+    }
+
+    /* load balancing, pulled by the CPU with less work.
+        When the busiest CPU has at least two threads more than this one, one of its
+        waiting threads that may run here moves over. Running threads stay where they are.
+     */
+    fn balance(&mut self) {
+        let this = percpu::this_cpu().cpu_id;
+        let load = self.cpus[this].load();
+        let Some(busiest) = self.cpus.iter().filter(|queue| queue.cpu_id() != this).max_by_key(|queue| queue.load()) else {
+            return;
+        };
+        if busiest.load() < load + 2 {
+            return;
+        }
+        let busiest = busiest.cpu_id();
+        let Some(mut thread) = self.cpus[busiest].ready.remove_where(|thread| thread.affinity.contains(this)) else {
+            return;
+        };
+        thread.cpu = this;
+        let queue = &mut self.cpus[this];
+        queue.need_resched |= queue.current_is_idle();
+        queue.ready.push(thread);
+    }
+
+    pub fn take_need_resched(&mut self) -> bool {
+        core::mem::replace(&mut self.local().need_resched, false)
+    }
+
+    /// Picks the next thread to run. A runnable current thread keeps the CPU unless
+    /// something of at least its priority is ready or it may no longer run here; the
+    /// thread switched away from is put where it belongs by `finish_switch`.
+    pub fn schedule(&mut self) -> Decision {
+        let queue = self.local();
+        queue.need_resched = false;
+        if let Some(current) = &queue.current {
+            check_stack(current);
+        }
+
+        let cpu_id = queue.cpu_id();
+        let current_is_idle = queue.current_is_idle();
+        let current = queue.current_mut();
+        let (current_state, running) = (current.state, current.effective_priority);
+        let runnable = current_state == ThreadState::Running && current.affinity.contains(cpu_id);
+        let mut next = match queue.ready.highest() {
+            Some(highest) if current_is_idle || !runnable || highest >= running => {
+                queue.ready.pop_highest().expect("ready queues changed under the lock")
+            }
+            _ if runnable => {
+                queue.slice_remaining = TIME_SLICE_TICKS;
+                return Decision::Stay;
+            }
+            _ => match queue.idle.take() {
+                Some(idle) => idle,
+                None => return Decision::Idle,
+            },
+        };
+
+        next.state = ThreadState::Running;
+        next.effective_priority = next.base_priority();
+        next.cpu = cpu_id;
+        let new_rsp = next.context.rsp;
+        fpu::set_current(next.fpu_state_ptr());
+        queue.cpu.current_thread.store(next.id.as_u64(), Ordering::Relaxed);
+        queue.cpu.stats.context_switches.fetch_add(1, Ordering::Relaxed);
+        let now = tsc::read();
+        next.switches += 1;
+        next.last_ran = now;
+        let mut previous = queue.current.replace(next).expect("no current thread");
+        previous.run_cycles += now - queue.running_since;
+        previous.last_ran = now;
+        queue.running_since = now;
+        queue.slice_remaining = TIME_SLICE_TICKS;
+        crate::trace_event!(sched, "switch {} -> {}", previous.id.as_u64(), queue.current_mut().id.as_u64());
+
+        // the box keeps its address until `finish_switch` moves it on
+        let old_rsp = &mut previous.context.rsp as *mut u64;
+        assert!(queue.previous.replace(previous).is_none(), "previous switch was never finished");
+        Decision::Switch(Switch { old_rsp, new_rsp })
+    }
+
+    /* after the switch, run by the thread switched to.
+        Only now the previous thread's registers are all saved, so only now another CPU
+        may pick it up, and a dead thread's stack is no longer in use and can be freed.
+        The thread's address space is loaded here too, on its own stack.
+     */
+    pub fn finish_switch(&mut self) {
+        let queue = self.local();
+        Self::load_address_space(queue.current_mut());
+        let Some(mut previous) = queue.previous.take() else {
+            return;
+        };
+        if Some(previous.id) == queue.idle_id {
+            previous.state = ThreadState::Ready;
+            queue.idle = Some(previous);
+            return;
+        }
+        match previous.state {
+            ThreadState::Dead => {
+                fpu::release(previous.fpu_state_ptr());
+                drop(previous);
+            }
+            ThreadState::Blocked => {
+                self.blocked.insert(previous.id, previous);
+            }
+            ThreadState::Running | ThreadState::Ready => {
+                self.make_ready(previous);
+            }
+        }
+    }
+
+    fn stats(&self) -> Vec<ThreadStats> {
+        let now = tsc::read();
+        let mut stats = Vec::new();
+        for queue in &self.cpus {
+            if let Some(current) = &queue.current {
+                stats.push(ThreadStats::new(current, now - queue.running_since, Some(now)));
+            }
+            let waiting = queue.previous.iter().chain(&queue.idle).chain(queue.ready.iter());
+            stats.extend(waiting.map(|thread| ThreadStats::new(thread, 0, None)));
+        }
+        stats.extend(self.blocked.values().map(|thread| ThreadStats::new(thread, 0, None)));
+        stats
+    }
+
+    /* entering the current thread's world.
+        Interrupts from ring 3 must land on its own kernel stack, and its process'
+        page tables get loaded. Run only once `switch_context` left the previous
+        thread's stack, which need not be mapped in the new address space: only
+        kernel mappings are the same in every one, and the stack in use then is the
+        thread's own, which it can reach in its own page tables.
+     */
+    fn load_address_space(next: &ThreadControlBlock) {
+        if let Some(stack) = &next.stack {
+            let top = stack.top() & !0xf;
+            gdt::set_kernel_stack(VirtAddr::new(top));
+            syscall::entry::set_kernel_stack(top);
+        }
+        let p4 = next.page_table.unwrap_or_else(memory::kernel_p4);
+        let (current, flags) = Cr3::read();
+        if current != p4 {
+            unsafe { Cr3::write(p4, flags) };
+        }
+    }
+}
+
+/// What the scheduler knows about one thread.
+#[derive(Debug, Clone)]
+pub struct ThreadStats {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// The CPU it runs or last ran on.
+    pub cpu: usize,
+    /// Time spent running, up to now for a running thread.
+    pub run_time: Duration,
+    /// How often it was switched to.
+    pub context_switches: u64,
+    /// TSC time it last got or gave up a CPU, `None` if it never ran.
+    pub last_ran: Option<Duration>,
+}
+
+impl ThreadStats {
+    fn new(thread: &ThreadControlBlock, running_cycles: u64, now: Option<u64>) -> Self {
+        let last_ran = now.or(Some(thread.last_ran).filter(|&last_ran| last_ran != 0));
+        ThreadStats {
+            id: thread.id,
+            name: thread.name.clone(),
+            state: thread.state,
+            priority: thread.base_priority(),
+            cpu: thread.cpu,
+            run_time: tsc::cycles_to_duration(thread.run_cycles + running_cycles),
+            context_switches: thread.switches,
+            last_ran: last_ran.map(tsc::cycles_to_duration),
+        }
+    }
+}
+
+/// A snapshot of every live thread, ordered by id.
+pub fn stats() -> Vec<ThreadStats> {
+    let mut stats = without_interrupts(|| SCHEDULER.lock().stats());
+    stats.sort_by_key(|thread| thread.id);
+    stats
+}
@@ -0,0 +1,109 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{current_id, park, unpark, ThreadId};
+
+/// Threads sleeping until some condition changes. Waking is safe from interrupt handlers.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(VecDeque::new()) }
+    }
+
+    /* no lost wakeups.
+        The thread is queued before the condition is checked a second time. A wake
+        that comes in between leaves a pending unpark, so the following park returns
+        immediately instead of sleeping forever.
+     */
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        let id = current_id();
+        loop {
+            if condition() {
+                return;
+            }
+            without_interrupts(|| {
+                let mut waiters = self.waiters.lock();
+                if !waiters.contains(&id) {
+                    waiters.push_back(id);
+                }
+            });
+            if condition() {
+                self.remove(id);
+                return;
+            }
+            park();
+        }
+    }
+
+    /// Sleeps until woken, without a condition to recheck.
+    pub fn wait(&self) {
+        let id = current_id();
+        without_interrupts(|| self.waiters.lock().push_back(id));
+        park();
+    }
+
+    pub fn wake_one(&self) -> bool {
+        while let Some(id) = without_interrupts(|| self.waiters.lock().pop_front()) {
+            if unpark(id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn wake_all(&self) -> usize {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        waiters.into_iter().filter(|&id| unpark(id)).count()
+    }
+
+    pub fn len(&self) -> usize {
+        without_interrupts(|| self.waiters.lock().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, id: ThreadId) {
+        without_interrupts(|| self.waiters.lock().retain(|&waiter| waiter != id));
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread::{spawn, yield_now};
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test_case]
+    fn test_wait_until_woken() {
+        static QUEUE: WaitQueue = WaitQueue::new();
+        static READY: AtomicBool = AtomicBool::new(false);
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+
+        for _ in 0..3 {
+            spawn("waiter", || {
+                QUEUE.wait_until(|| READY.load(Ordering::SeqCst));
+                DONE.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        while QUEUE.len() < 3 {
+            yield_now();
+        }
+        READY.store(true, Ordering::SeqCst);
+        QUEUE.wake_all();
+        while DONE.load(Ordering::SeqCst) < 3 {
+            yield_now();
+        }
+    }
+}
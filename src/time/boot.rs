@@ -0,0 +1,72 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::tsc;
+use crate::sync::SpinLock;
+
+/* how long booting took.
+    `lib::init` marks the end of each of its stages with the TSC, the first one
+    starting at `tsc::mark_boot`; the counter runs long before it is calibrated, so
+    marks are cycles and turned into time only when reported, once init is done.
+ */
+
+const MAX_STAGES: usize = 32;
+
+struct Stages {
+    marks: [(&'static str, u64); MAX_STAGES],
+    len: usize,
+    // when the last stage ended, where the next one starts
+    last: u64,
+}
+
+static STAGES: SpinLock<Stages> = SpinLock::new(Stages { marks: [("", 0); MAX_STAGES], len: 0, last: 0 });
+
+/// Ends the stage `name`, which began where the one before it ended.
+pub fn stage(name: &'static str) {
+    let now = tsc::read();
+    let mut stages = STAGES.lock();
+    let start = match stages.len {
+        0 => tsc::boot(),
+        _ => stages.last,
+    };
+    stages.last = now;
+    let len = stages.len;
+    if len < MAX_STAGES {
+        stages.marks[len] = (name, now - start);
+        stages.len += 1;
+    }
+}
+
+/// The stages marked so far and how long each took.
+pub fn stages() -> Vec<(&'static str, Duration)> {
+    let stages = STAGES.lock();
+    stages.marks[..stages.len].iter().map(|&(name, cycles)| (name, tsc::cycles_to_duration(cycles))).collect()
+}
+
+/// Logs how long each stage took and its share of the whole.
+pub fn report() {
+    let stages = stages();
+    let total: Duration = stages.iter().map(|&(_, time)| time).sum();
+    log::info!("boot took {} us:", total.as_micros());
+    for (name, time) in stages {
+        // tenths of a percent, without floating point
+        let permille = time.as_nanos() * 1000 / total.as_nanos().max(1);
+        log::info!("boot: {:<10} {:>8} us {:>3}.{}%", name, time.as_micros(), permille / 10, permille % 10);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_stages() {
+        let stages = stages();
+        let names: Vec<&str> = stages.iter().map(|&(name, _)| name).collect();
+        for name in ["gdt", "memory", "idt", "pic", "time"] {
+            assert!(names.contains(&name), "{:?}", names);
+        }
+        let total: Duration = stages.iter().map(|&(_, time)| time).sum();
+        assert!(total > Duration::ZERO && total <= tsc::since_boot());
+    }
+}
@@ -0,0 +1,199 @@
+pub mod wheel;
+pub mod sleep;
+pub mod pit;
+pub mod tsc;
+pub mod boot;
+pub mod rtc;
+
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "shell")]
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::task::Waker;
+use core::time::Duration;
+use spin::Mutex;
+
+#[cfg(feature = "shell")]
+use crate::shell::{self, ShellError};
+use crate::thread::{self, ThreadId};
+use crate::workqueue::{self, Work};
+use wheel::TimerWheel;
+
+pub use sleep::{sleep, sleep_async, Sleep};
+
+pub use pit::PIT_FREQUENCY;
+
+pub const TIMER_HZ: u64 = 100;
+const PIT_DIVISOR: u16 = (PIT_FREQUENCY / TIMER_HZ) as u16;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// ticks covered by the pending one-shot interrupt, 0 while the timer is periodic
+static ONE_SHOT_TICKS: AtomicU64 = AtomicU64::new(0);
+static ONE_SHOT_COUNT: AtomicU16 = AtomicU16::new(0);
+
+pub(crate) enum TimerAction {
+    Unpark(ThreadId),
+    Wake(Waker),
+    // handed to the work queue, the work runs in thread context
+    Queue(Work),
+}
+
+// only locked with interrupts disabled, the timer interrupt services it
+static TIMERS: Mutex<TimerWheel<TimerAction>> = Mutex::new(TimerWheel::new());
+
+pub fn init() {
+    tsc::calibrate();
+    pit::set_periodic(PIT_DIVISOR);
+}
+
+/// Called from the timer interrupt, returns the new tick count.
+pub fn tick() -> u64 {
+    let elapsed = match ONE_SHOT_TICKS.swap(0, Ordering::Relaxed) {
+        0 => 1,
+        ticks => {
+            pit::set_periodic(PIT_DIVISOR);
+            ticks
+        }
+    };
+    advance(elapsed)
+}
+
+fn advance(elapsed: u64) -> u64 {
+    let now = TICKS.fetch_add(elapsed, Ordering::Relaxed) + elapsed;
+    run_timers(now);
+    now
+}
+
+/// Longest stretch a single PIT one-shot can cover.
+pub const MAX_ONE_SHOT_TICKS: u64 = u16::MAX as u64 / PIT_DIVISOR as u64;
+
+/* tickless idle.
+    Instead of waking up every tick, the PIT is programmed to fire once after `ticks`
+    (at most MAX_ONE_SHOT_TICKS). Call with interrupts disabled, right before halting.
+ */
+pub fn enter_tickless(ticks: u64) {
+    let ticks = ticks.min(MAX_ONE_SHOT_TICKS);
+    if ticks <= 1 {
+        return;
+    }
+    let count = (ticks * PIT_DIVISOR as u64) as u16;
+    ONE_SHOT_COUNT.store(count, Ordering::Relaxed);
+    ONE_SHOT_TICKS.store(ticks, Ordering::Relaxed);
+    pit::set_one_shot(count);
+}
+
+/// Back to periodic ticks after another interrupt ended the halt early,
+/// the time spent so far is accounted from the PIT counter.
+pub fn exit_tickless() {
+    if ONE_SHOT_TICKS.swap(0, Ordering::Relaxed) == 0 {
+        return;
+    }
+    let remaining = pit::read_count();
+    let programmed = ONE_SHOT_COUNT.load(Ordering::Relaxed);
+    pit::set_periodic(PIT_DIVISOR);
+    let elapsed = programmed.saturating_sub(remaining) / PIT_DIVISOR;
+    if elapsed > 0 {
+        advance(elapsed as u64);
+    }
+}
+
+pub fn next_timer_deadline() -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| TIMERS.lock().next_deadline())
+}
+
+fn run_timers(now: u64) {
+    let mut expired = Vec::new();
+    TIMERS.lock().advance(now, |action| expired.push(action));
+    // woken outside the lock, a woken task may want to add a timer right away
+    for action in expired {
+        match action {
+            TimerAction::Unpark(id) => {
+                thread::unpark(id);
+            }
+            TimerAction::Wake(waker) => waker.wake(),
+            TimerAction::Queue(work) => workqueue::queue(work),
+        }
+    }
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TIMER_HZ).div_ceil(1000)
+}
+
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() as u64 * TIMER_HZ).div_ceil(1_000_000_000)
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / TIMER_HZ
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// Time since boot from the TSC, or the tick count before it is calibrated.
+pub fn uptime() -> Duration {
+    match tsc::since_boot() {
+        Duration::ZERO => Duration::from_millis(uptime_ms()),
+        up => up,
+    }
+}
+
+/// A duration for people, as in "2 days, 3:04:05".
+pub struct Elapsed(pub Duration);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        match seconds / 86_400 {
+            0 => {}
+            1 => write!(f, "1 day, ")?,
+            days => write!(f, "{} days, ", days)?,
+        }
+        write!(f, "{}:{:02}:{:02}", seconds / 3600 % 24, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+/// Registers `uptime` and `date`.
+#[cfg(feature = "shell")]
+pub fn register_commands() {
+    shell::register_command("uptime", "time since boot", uptime_command);
+    shell::register_command("date", "the date and time of the real time clock", date_command);
+}
+
+#[cfg(feature = "shell")]
+fn uptime_command(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("uptime"));
+    }
+    let _ = writeln!(out, "up {}", Elapsed(uptime()));
+    Ok(())
+}
+
+#[cfg(feature = "shell")]
+fn date_command(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("date"));
+    }
+    let _ = writeln!(out, "{}", rtc::read());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn test_elapsed() {
+        assert_eq!(Elapsed(Duration::from_secs(65)).to_string(), "0:01:05");
+        assert_eq!(Elapsed(Duration::from_secs(86_400 + 3 * 3600 + 4 * 60 + 5)).to_string(), "1 day, 3:04:05");
+        assert_eq!(Elapsed(Duration::from_secs(2 * 86_400)).to_string(), "2 days, 0:00:00");
+    }
+}
@@ -0,0 +1,43 @@
+use x86_64::instructions::port::Port;
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+// channel 0, lobyte/hibyte access
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0x30;
+const MODE_SQUARE_WAVE: u8 = 0x36;
+const LATCH_CHANNEL0: u8 = 0x00;
+
+fn program(mode: u8, count: u16) {
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel0: Port<u8> = Port::new(CHANNEL0);
+    unsafe {
+        command.write(mode);
+        channel0.write((count & 0xff) as u8);
+        channel0.write((count >> 8) as u8);
+    }
+}
+
+/// IRQ0 fires every `divisor` PIT cycles.
+pub fn set_periodic(divisor: u16) {
+    program(MODE_SQUARE_WAVE, divisor);
+}
+
+/// IRQ0 fires once after `count` PIT cycles.
+pub fn set_one_shot(count: u16) {
+    program(MODE_INTERRUPT_ON_TERMINAL_COUNT, count);
+}
+
+/// Current value of the channel 0 down-counter.
+pub fn read_count() -> u16 {
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel0: Port<u8> = Port::new(CHANNEL0);
+    unsafe {
+        command.write(LATCH_CHANNEL0);
+        let low = channel0.read() as u16;
+        let high = channel0.read() as u16;
+        high << 8 | low
+    }
+}
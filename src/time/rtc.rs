@@ -0,0 +1,152 @@
+use core::fmt;
+
+use crate::{acpi, cmos};
+
+/* the real time clock.
+    The wall clock time, read from the CMOS registers the clock keeps it in. The
+    clock runs on through the read, so it is read until two reads in a row agree,
+    each after an update has finished. Firmware may keep the fields in BCD and the
+    hour on a 12 hour clock, status register B says which. The century is only kept
+    where the FADT says; without it the year is taken to be in this one. QEMU keeps
+    the clock in UTC.
+ */
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// in status A, the clock is updating its fields
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// in status B
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+// in the hours register of a 12 hour clock
+const PM: u8 = 1 << 7;
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Days since 1970-01-01.
+    pub fn days(&self) -> i64 {
+        // counted from March, so the leap day is last in the year
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            month => (self.year as i64, month as i64 - 3),
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix_seconds(&self) -> i64 {
+        self.days() * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    pub fn weekday(&self) -> &'static str {
+        WEEKDAYS[self.days().rem_euclid(7) as usize]
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.weekday(), self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// the raw registers, seconds to year and the century
+type Registers = [u8; 7];
+
+fn read_registers(century: u8) -> Registers {
+    while cmos::read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let [second, minute, hour, day, month, year] = [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(cmos::read);
+    let century = if century != 0 { cmos::read(century) } else { 0 };
+    [second, minute, hour, day, month, year, century]
+}
+
+/// Makes a date of the registers as status B says they are kept.
+fn decode(registers: Registers, status_b: u8, has_century: bool) -> DateTime {
+    let [second, minute, hours, day, month, year, century] = registers;
+    let field = |value: u8| if status_b & BINARY != 0 { value } else { from_bcd(value) };
+    let mut hour = field(hours & !PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight, 12 PM noon
+        hour = hour % 12 + if hours & PM != 0 { 12 } else { 0 };
+    }
+    let century = if has_century { field(century) as u16 } else { 20 };
+    DateTime {
+        year: century * 100 + field(year) as u16,
+        month: field(month),
+        day: field(day),
+        hour,
+        minute: field(minute),
+        second: field(second),
+    }
+}
+
+/// The date and time now.
+pub fn read() -> DateTime {
+    let century = acpi::fadt().map_or(0, |fadt| fadt.century);
+    let mut registers = read_registers(century);
+    loop {
+        let again = read_registers(century);
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    decode(registers, cmos::read(STATUS_B), century != 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn test_decode() {
+        // 2024-02-29 01:02:03, at 1 AM on a 12 hour clock, in BCD
+        let date = decode([0x03, 0x02, 0x01, 0x29, 0x02, 0x24, 0x20], 0, true);
+        assert_eq!(date, DateTime { year: 2024, month: 2, day: 29, hour: 1, minute: 2, second: 3 });
+        assert_eq!(date.to_string(), "Thu 2024-02-29 01:02:03 UTC");
+        assert_eq!(date.unix_seconds(), 1_709_168_523);
+        // 12 PM, binary, no century register
+        let date = decode([0, 0, 12 | PM, 1, 1, 70, 0], BINARY, false);
+        assert_eq!((date.year, date.hour), (2070, 12));
+        let midnight = decode([0, 0, 0x12, 1, 1, 0x70, 0x19], 0, true);
+        assert_eq!((midnight.hour, midnight.unix_seconds()), (0, 0));
+    }
+
+    #[test_case]
+    fn test_read() {
+        let now = read();
+        assert!(now.year >= 2020 && (1..=12).contains(&now.month) && (1..=31).contains(&now.day), "{}", now);
+    }
+}
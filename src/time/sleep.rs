@@ -0,0 +1,100 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use super::{duration_to_ticks, ticks, TimerAction, TIMERS};
+use super::wheel::TimerId;
+use crate::thread;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub(crate) fn add_timer(deadline: u64, action: TimerAction) -> TimerId {
+    without_interrupts(|| TIMERS.lock().add(deadline, action))
+}
+
+pub(crate) fn cancel_timer(id: TimerId) {
+    // dropped outside the lock, a waker may free memory
+    let action = without_interrupts(|| TIMERS.lock().cancel(id));
+    drop(action);
+}
+
+/// Blocks the calling thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let deadline = ticks() + duration_to_ticks(duration);
+    let id = thread::current_id();
+    // an unrelated unpark can end the park early, so keep going until the deadline
+    while ticks() < deadline {
+        let timer = add_timer(deadline, TimerAction::Unpark(id));
+        thread::park();
+        cancel_timer(timer);
+    }
+}
+
+/// Completes after at least `duration` when awaited from an async task.
+pub fn sleep_async(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: ticks() + duration_to_ticks(duration),
+        timer: None,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<TimerId>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if let Some(timer) = self.timer.take() {
+            cancel_timer(timer);
+        }
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        // re-armed on every poll so the latest waker is the one woken
+        self.timer = Some(add_timer(self.deadline, TimerAction::Wake(cx.waker().clone())));
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            cancel_timer(timer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::executor::Executor;
+    use crate::task::Task;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[test_case]
+    fn test_thread_sleep() {
+        let start = ticks();
+        sleep(Duration::from_millis(50));
+        assert!(ticks() - start >= duration_to_ticks(Duration::from_millis(50)));
+    }
+
+    #[test_case]
+    fn test_async_sleep() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        let start = ticks();
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            sleep_async(Duration::from_millis(30)).await;
+            DONE.store(true, Ordering::SeqCst);
+        }));
+        while !DONE.load(Ordering::SeqCst) {
+            executor.run_ready_tasks();
+            x86_64::instructions::hlt();
+        }
+        assert!(ticks() - start >= duration_to_ticks(Duration::from_millis(30)));
+    }
+}
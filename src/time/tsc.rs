@@ -0,0 +1,128 @@
+use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::pit::{self, PIT_FREQUENCY};
+
+/* the time stamp counter.
+    Counts CPU cycles, far finer than the timer tick, which makes it the clock for
+    measuring how long threads run. Its rate is not architectural, so it is measured
+    once at boot against a PIT one-shot, from the first decrement of the loaded count
+    to zero.
+ */
+
+const CALIBRATION_HZ: u64 = 100;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static BOOT: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// The counter before timed code, which may not start before it is read.
+#[inline(always)]
+pub fn start() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let cycles = _rdtsc();
+        _mm_lfence();
+        cycles
+    }
+}
+
+/// The counter after timed code, read once all of it has run.
+#[inline(always)]
+pub fn stop() -> u64 {
+    let mut cpu = 0;
+    unsafe {
+        let cycles = __rdtscp(&mut cpu);
+        _mm_lfence();
+        cycles
+    }
+}
+
+/// Measures the TSC rate, call with interrupts disabled before the PIT is set up for ticks.
+pub fn calibrate() {
+    let count = (PIT_FREQUENCY / CALIBRATION_HZ) as u16;
+    pit::set_one_shot(count);
+    // the counter takes the count on its next clock, until then it reads what it had,
+    // right after that it is close to the count
+    let mut loaded = pit::read_count();
+    while loaded <= count / 2 || loaded > count {
+        loaded = pit::read_count();
+    }
+    // start on a decrement, so the PIT cycles counted are whole ones
+    let mut first = pit::read_count();
+    while first == loaded {
+        first = pit::read_count();
+    }
+    let start = read();
+    // counts down to zero, then wraps around and keeps going
+    let mut last = first;
+    loop {
+        let now = pit::read_count();
+        if now == 0 || now > last {
+            break;
+        }
+        last = now;
+    }
+    let cycles = read() - start;
+    FREQUENCY.store(cycles * PIT_FREQUENCY / first as u64, Ordering::Relaxed);
+}
+
+/// Cycles per second, 0 before `calibrate`.
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    match frequency() {
+        0 => Duration::ZERO,
+        hz => Duration::from_nanos((cycles as u128 * 1_000_000_000 / hz as u128) as u64),
+    }
+}
+
+/// Time since the TSC was reset, which happens at power on.
+pub fn now() -> Duration {
+    cycles_to_duration(read())
+}
+
+/// Remembers when the kernel started, call first thing at boot.
+pub fn mark_boot() {
+    BOOT.store(read(), Ordering::Relaxed);
+}
+
+/// The counter when `mark_boot` ran.
+pub fn boot() -> u64 {
+    BOOT.load(Ordering::Relaxed)
+}
+
+/// Time since `mark_boot`, finer than the tick count; zero until calibrated.
+pub fn since_boot() -> Duration {
+    to_uptime(read())
+}
+
+/// The time since boot at which the counter read `cycles`.
+pub fn to_uptime(cycles: u64) -> Duration {
+    cycles_to_duration(cycles.saturating_sub(BOOT.load(Ordering::Relaxed)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn test_tsc_is_calibrated() {
+        assert!(frequency() > 0);
+        let before = read();
+        let start = time::ticks();
+        while time::ticks() < start + 2 {
+            core::hint::spin_loop();
+        }
+        // at least one full tick went by
+        let elapsed = cycles_to_duration(read() - before);
+        assert!(elapsed >= Duration::from_millis(5), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,181 @@
+use alloc::vec::Vec;
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const LEVELS: usize = 4;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Entry<T> {
+    id: TimerId,
+    deadline: u64,
+    payload: T,
+}
+
+/* hierarchical timer wheel.
+    Level 0 has one slot per tick, each slot of level n spans 64^n ticks. A timer goes
+    into the lowest level whose range covers its distance to now; whenever a level
+    wraps around, the next slot of the level above is cascaded down. Adding and firing
+    are O(1), cancelling scans the slots.
+ */
+pub struct TimerWheel<T> {
+    levels: [[Vec<Entry<T>>; SLOTS]; LEVELS],
+    current: u64,
+    next_id: u64,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub const fn new() -> Self {
+        TimerWheel {
+            levels: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+            current: 0,
+            next_id: 0,
+            len: 0,
+        }
+    }
+
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Schedules `payload` for tick `deadline`; deadlines in the past fire on the next tick.
+    pub fn add(&mut self, deadline: u64, payload: T) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let deadline = deadline.max(self.current + 1);
+        self.insert(Entry { id, deadline, payload });
+        self.len += 1;
+        id
+    }
+
+    fn insert(&mut self, entry: Entry<T>) {
+        // cascading entries may be due on the current tick, whose slot is processed next
+        let delta = entry.deadline.saturating_sub(self.current);
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1u64 << (LEVEL_BITS * (level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        // beyond the wheel's range a timer is parked in the last slot it can reach, and cascades again
+        let deadline = entry.deadline.min(self.current + (1u64 << (LEVEL_BITS * LEVELS as u32)) - 1);
+        let slot = ((deadline >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as usize;
+        self.levels[level][slot].push(entry);
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        for level in self.levels.iter_mut() {
+            for slot in level.iter_mut() {
+                if let Some(index) = slot.iter().position(|entry| entry.id == id) {
+                    self.len -= 1;
+                    return Some(slot.swap_remove(index).payload);
+                }
+            }
+        }
+        None
+    }
+
+    /// Moves the wheel forward to `now`, handing every expired payload to `fire`.
+    pub fn advance<F: FnMut(T)>(&mut self, now: u64, mut fire: F) {
+        while self.current < now {
+            self.current += 1;
+
+            for level in 1..LEVELS {
+                let span_mask = (1u64 << (LEVEL_BITS * level as u32)) - 1;
+                if self.current & span_mask != 0 {
+                    break;
+                }
+                let slot = ((self.current >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as usize;
+                for entry in core::mem::take(&mut self.levels[level][slot]) {
+                    self.insert(entry);
+                }
+            }
+
+            let slot = (self.current & SLOT_MASK) as usize;
+            let entries = core::mem::take(&mut self.levels[0][slot]);
+            for entry in entries {
+                if entry.deadline <= self.current {
+                    self.len -= 1;
+                    fire(entry.payload);
+                } else {
+                    // clamped far-future timer, not due yet
+                    self.insert(entry);
+                }
+            }
+        }
+    }
+
+    /// Earliest deadline of all pending timers.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.levels.iter()
+            .flat_map(|level| level.iter())
+            .flat_map(|slot| slot.iter())
+            .map(|entry| entry.deadline)
+            .min()
+    }
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    #[test_case]
+    fn test_timers_fire_on_their_tick() {
+        let mut wheel = Box::new(TimerWheel::new());
+        let deadlines = [1u64, 5, 63, 64, 65, 100, 4095, 4096, 5000, 300_000];
+        for &deadline in deadlines.iter().rev() {
+            wheel.add(deadline, deadline);
+        }
+
+        let mut fired = vec![];
+        for now in 1..=300_000u64 {
+            wheel.advance(now, |deadline| fired.push((deadline, now)));
+        }
+        assert_eq!(fired.len(), deadlines.len());
+        for (deadline, now) in fired {
+            assert_eq!(deadline, now);
+        }
+        assert!(wheel.is_empty());
+    }
+
+    #[test_case]
+    fn test_cancel() {
+        let mut wheel = Box::new(TimerWheel::new());
+        let keep = wheel.add(10, 1);
+        let drop = wheel.add(10, 2);
+        assert_eq!(wheel.cancel(drop), Some(2));
+        assert_eq!(wheel.cancel(drop), None);
+        assert_eq!(wheel.next_deadline(), Some(10));
+
+        let mut fired = vec![];
+        wheel.advance(20, |payload| fired.push(payload));
+        assert_eq!(fired, vec![1]);
+        assert_eq!(wheel.cancel(keep), None);
+    }
+
+    #[test_case]
+    fn test_late_add_fires_next_tick() {
+        let mut wheel = Box::new(TimerWheel::new());
+        wheel.advance(50, |_: u32| unreachable!());
+        wheel.add(10, 7);
+        let mut fired = vec![];
+        wheel.advance(51, |payload| fired.push(payload));
+        assert_eq!(fired, vec![7]);
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::dmesg::Uptime;
+use crate::time::tsc;
+use crate::{cmdline, percpu};
+
+/* static tracepoints.
+    `trace_event!(sched, "switch {} -> {}", from, to)` records an event in the running
+    CPU's ring with a TSC timestamp, when tracing is on. Nothing is formatted then: an
+    event is the address of a static holding its subsystem and format, and up to four
+    numbers, which `dump` puts together later. Rings are written only by their own CPU
+    without a lock; a slot is claimed by bumping the head, so an interrupt tracing in
+    the middle of an event takes the next one, and carries a sequence number that is
+    cleared while it is written, so a dump on another CPU skips it rather than showing
+    half an event. The oldest events are overwritten.
+
+    On with `trace` on the command line, or `enable`; /proc/trace is the dump.
+ */
+
+pub const RING_SIZE: usize = 1024;
+pub const MAX_ARGS: usize = 4;
+
+/// What a tracepoint records besides its arguments, one static per `trace_event!`.
+pub struct Event {
+    pub subsystem: &'static str,
+    pub format: &'static str,
+}
+
+#[derive(Default)]
+struct Slot {
+    // index + 1 once written, 0 while being written
+    sequence: AtomicU64,
+    cycles: AtomicU64,
+    event: AtomicU64,
+    args: [AtomicU64; MAX_ARGS],
+}
+
+/// A CPU's events.
+pub struct Ring {
+    slots: Box<[Slot]>,
+    head: AtomicUsize,
+}
+
+impl Ring {
+    pub fn new() -> Self {
+        Ring { slots: (0..RING_SIZE).map(|_| Slot::default()).collect(), head: AtomicUsize::new(0) }
+    }
+
+    fn record(&self, event: &'static Event, args: &[u64]) {
+        let index = self.head.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[index % self.slots.len()];
+        slot.sequence.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.cycles.store(tsc::read(), Ordering::Relaxed);
+        slot.event.store(event as *const Event as u64, Ordering::Relaxed);
+        for (arg, &value) in slot.args.iter().zip(args) {
+            arg.store(value, Ordering::Relaxed);
+        }
+        slot.sequence.store(index as u64 + 1, Ordering::Release);
+    }
+
+    /// The events kept, each read whole or not at all.
+    fn events(&self, cpu: usize, events: &mut Vec<Recorded>) {
+        for slot in self.slots.iter() {
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence == 0 {
+                continue;
+            }
+            let recorded = Recorded {
+                cpu,
+                cycles: slot.cycles.load(Ordering::Relaxed),
+                event: slot.event.load(Ordering::Relaxed),
+                args: core::array::from_fn(|index| slot.args[index].load(Ordering::Relaxed)),
+            };
+            fence(Ordering::Acquire);
+            if slot.sequence.load(Ordering::Relaxed) == sequence {
+                events.push(recorded);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        for slot in self.slots.iter() {
+            slot.sequence.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for Ring {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Recorded {
+    cpu: usize,
+    cycles: u64,
+    event: u64,
+    args: [u64; MAX_ARGS],
+}
+
+impl Recorded {
+    fn event(&self) -> &'static Event {
+        // only ever the address of a static `Event`
+        unsafe { &*(self.event as *const Event) }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Records an event on the running CPU's ring when tracing is on, as in
+/// `trace_event!(irq, "vector {} entry", vector)`; the arguments are cast to `u64`.
+#[macro_export]
+macro_rules! trace_event {
+    ($subsystem:ident, $format:literal $(, $arg:expr)* $(,)?) => {
+        if $crate::trace::is_enabled() {
+            static EVENT: $crate::trace::Event =
+                $crate::trace::Event { subsystem: stringify!($subsystem), format: $format };
+            const _: () = assert!(<[&str]>::len(&[$(stringify!($arg)),*]) <= $crate::trace::MAX_ARGS, "too many trace arguments");
+            $crate::trace::record(&EVENT, &[$($arg as u64),*]);
+        }
+    };
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Turns tracing on when the command line asks for it.
+pub fn init() {
+    if cmdline::flag("trace") {
+        enable();
+    }
+}
+
+/// Called by `trace_event!`; events before the CPU's block is set up are dropped.
+pub fn record(event: &'static Event, args: &[u64]) {
+    if let Some(cpu) = percpu::try_this_cpu() {
+        cpu.trace.record(event, args);
+    }
+}
+
+/// Forgets every event recorded so far.
+pub fn clear() {
+    for cpu in percpu::cpus() {
+        cpu.trace.clear();
+    }
+}
+
+/// `format` with each `{}`, `{:x}` or `{:#x}` replaced by the next argument.
+fn render(out: &mut impl Write, format: &str, args: &[u64]) -> fmt::Result {
+    let mut args = args.iter();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        out.write_str(&rest[..start])?;
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            return out.write_str(&rest[start..]);
+        };
+        let value = args.next().copied().unwrap_or(0);
+        match &rest[start + 1..end] {
+            ":x" => write!(out, "{:x}", value)?,
+            ":#x" => write!(out, "{:#x}", value)?,
+            _ => write!(out, "{}", value)?,
+        }
+        rest = &rest[end + 1..];
+    }
+    out.write_str(rest)
+}
+
+/// Writes the events of every CPU, oldest first, one line each.
+pub fn dump(out: &mut impl Write) -> fmt::Result {
+    let mut events = Vec::new();
+    for cpu in percpu::cpus() {
+        cpu.trace.events(cpu.cpu_id, &mut events);
+    }
+    events.sort_unstable_by_key(|recorded| recorded.cycles);
+    for recorded in &events {
+        let event = recorded.event();
+        write!(out, "[{}] cpu{} {:<8} ", Uptime(tsc::to_uptime(recorded.cycles)), recorded.cpu, event.subsystem)?;
+        render(out, event.format, &recorded.args)?;
+        out.write_char('\n')?;
+    }
+    Ok(())
+}
+
+/// The dump as text, for /proc/trace.
+pub fn text() -> String {
+    let mut text = String::new();
+    let _ = dump(&mut text);
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_render() {
+        let mut text = String::new();
+        render(&mut text, "fault at {:#x}, code {:x} from {}", &[0xdead000, 0x1f, 3]).unwrap();
+        assert_eq!(text, "fault at 0xdead000, code 1f from 3");
+        text.clear();
+        render(&mut text, "no arguments {", &[]).unwrap();
+        assert_eq!(text, "no arguments {");
+    }
+
+    #[test_case]
+    fn test_events() {
+        let was_enabled = is_enabled();
+        enable();
+        crate::trace_event!(test, "trace test {} of {}", 1, 2);
+        let ring = Ring::new();
+        for index in 0..RING_SIZE as u64 + 3 {
+            static EVENT: Event = Event { subsystem: "test", format: "{}" };
+            ring.record(&EVENT, &[index]);
+        }
+        if !was_enabled {
+            disable();
+        }
+
+        // the ring keeps the newest events
+        let mut events = Vec::new();
+        ring.events(0, &mut events);
+        assert_eq!(events.len(), RING_SIZE);
+        assert_eq!(events.iter().map(|recorded| recorded.args[0]).min(), Some(3));
+        assert!(text().contains("test     trace test 1 of 2\n"));
+    }
+}
@@ -0,0 +1,124 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::fs::devfs::CharDevice;
+use crate::fs::FsError;
+use crate::print;
+use crate::process::signal::{self, Interrupted, SIGINT};
+use crate::process::ProcessId;
+use crate::thread::WaitQueue;
+
+const INPUT_CAPACITY: usize = 256;
+/// Ctrl+C, sends SIGINT to the foreground process instead of being read.
+pub const INTERRUPT: char = '\u{3}';
+
+static INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+static READERS: WaitQueue = WaitQueue::new();
+
+/* the process keyboard interrupts go to.
+    Without process groups this is a single process: whoever exec'd a program last
+    takes the terminal over, and hands it back to its parent when it exits, which is
+    how a shell running a command gets it back.
+ */
+static FOREGROUND: Mutex<Option<ProcessId>> = Mutex::new(None);
+
+pub fn foreground() -> Option<ProcessId> {
+    without_interrupts(|| *FOREGROUND.lock())
+}
+
+pub fn set_foreground(process: Option<ProcessId>) {
+    without_interrupts(|| *FOREGROUND.lock() = process);
+}
+
+pub(crate) fn process_exited(process: ProcessId, parent: Option<ProcessId>) {
+    without_interrupts(|| {
+        let mut foreground = FOREGROUND.lock();
+        if *foreground == Some(process) {
+            *foreground = parent;
+        }
+    });
+}
+
+/// Drops pending input, like a terminal does on an interrupt, and signals the foreground process.
+fn interrupt() {
+    print!("^C\n");
+    without_interrupts(|| INPUT.lock().clear());
+    if let Some(process) = foreground() {
+        signal::send(process, SIGINT);
+    }
+}
+
+/// Queues typed characters for readers of the terminal, dropping what does not fit.
+pub fn push_input(bytes: &[u8]) {
+    without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let room = INPUT_CAPACITY - input.len();
+        input.extend(bytes.iter().take(room));
+    });
+    READERS.wake_all();
+}
+
+pub fn push_char(character: char) {
+    if character == INTERRUPT {
+        interrupt();
+        return;
+    }
+    let mut encoded = [0u8; 4];
+    push_input(character.encode_utf8(&mut encoded).as_bytes());
+}
+
+/// Blocks until input is available, then takes as much of it as fits into `buffer`.
+/// A signal for the calling process ends the wait early.
+pub fn read(buffer: &mut [u8]) -> Result<usize, Interrupted> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    signal::wait_interruptible(&READERS, || {
+        count = without_interrupts(|| {
+            let mut input = INPUT.lock();
+            let count = input.len().min(buffer.len());
+            for (slot, byte) in buffer.iter_mut().zip(input.drain(..count)) {
+                *slot = byte;
+            }
+            count
+        });
+        count > 0
+    })?;
+    Ok(count)
+}
+
+pub fn write(bytes: &[u8]) {
+    print!("{}", String::from_utf8_lossy(bytes));
+}
+
+/// The terminal as /dev/tty0: keyboard input, output on the screen.
+pub struct Terminal;
+
+impl CharDevice for Terminal {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(read(buffer)?)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FsError> {
+        write(buffer);
+        Ok(buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_read_takes_queued_input() {
+        push_input(b"abc");
+        let mut buffer = [0u8; 2];
+        assert_eq!(read(&mut buffer), Ok(2));
+        assert_eq!(&buffer, b"ab");
+        assert_eq!(read(&mut buffer), Ok(1));
+        assert_eq!(buffer[0], b'c');
+    }
+}
@@ -1,5 +1,9 @@
 use volatile::Volatile;
+use core::cell::UnsafeCell;
 use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -41,7 +45,13 @@ struct ScreenChar {
 }
 
 const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
+
+// the CRT controller's registers holding the cursor's cell, high byte and low byte
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CURSOR_HIGH: u8 = 0x0e;
+const CURSOR_LOW: u8 = 0x0f;
 
 #[repr(transparent)]
 struct Buffer {
@@ -78,12 +88,61 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
+        self.write_bytes(s.as_bytes());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
             match byte {
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
                 _ => self.write_byte(0xfe),
             }
         }
+        self.move_cursor();
+    }
+
+    /// Blanks the screen, writing goes on at the start of the bottom row.
+    pub fn clear(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        self.move_cursor();
+    }
+
+    /// Where on the bottom row writing goes on.
+    pub fn column(&self) -> usize {
+        self.column_position
+    }
+
+    /// Makes writing go on at `column` of the bottom row, the cursor with it.
+    pub fn set_column(&mut self, column: usize) {
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.move_cursor();
+    }
+
+    /// Writes `s` on the bottom row from `column` on, cut at the edge of the screen,
+    /// without moving where writing goes on.
+    pub fn write_at(&mut self, column: usize, s: &str) {
+        let color_code = self.color_code;
+        for (col, byte) in (column..BUFFER_WIDTH).zip(s.bytes()) {
+            let ascii_character = if (0x20..=0x7e).contains(&byte) { byte } else { 0xfe };
+            self.buffer.chars[BUFFER_HEIGHT - 1][col].write(ScreenChar { ascii_character, color_code });
+        }
+    }
+
+    /// Puts the blinking cursor where writing goes on.
+    fn move_cursor(&self) {
+        // past the last column it waits at the edge, the next byte starts a new line
+        let position = (BUFFER_HEIGHT - 1) * BUFFER_WIDTH + self.column_position.min(BUFFER_WIDTH - 1);
+        let mut index: Port<u8> = Port::new(CRTC_INDEX);
+        let mut data: Port<u8> = Port::new(CRTC_DATA);
+        unsafe {
+            index.write(CURSOR_LOW);
+            data.write(position as u8);
+            index.write(CURSOR_HIGH);
+            data.write((position >> 8) as u8);
+        }
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -116,40 +175,182 @@ impl fmt::Write for Writer {
 }
 
 use lazy_static::lazy_static;
-use spin::Mutex;
+
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+use crate::percpu;
+use crate::sync::lockdep::{self, LockId};
+
+/* locking the screen.
+    Interrupts are off while the writer is held, as under a spinlock, so no interrupt
+    handler spins on it under the code it interrupted. Exceptions and NMIs come all
+    the same, and one that prints on the CPU holding the lock would spin there
+    forever; so the lock is the number of the CPU holding it, taken and recorded in
+    one compare-exchange with nothing in between for an NMI to land on, and
+    printing on that CPU goes into a buffer instead, written out when the holder lets go, or
+    when the next holder takes the lock should the holder never come back. The
+    panic handler takes the screen away from whoever holds it with `force_unlock`,
+    after the other CPUs were halted.
+ */
+
+const DEFERRED_SIZE: usize = 1024;
+const NO_OWNER: usize = usize::MAX;
+
+/// What was printed on the CPU holding the screen, from under it. Only that CPU
+/// touches it, the code it interrupted always resumes after the nested printing is
+/// done.
+struct Deferred {
+    bytes: UnsafeCell<[u8; DEFERRED_SIZE]>,
+    // bytes reserved, beyond the end for what did not fit
+    len: AtomicUsize,
+}
+
+unsafe impl Sync for Deferred {}
+
+impl fmt::Write for &Deferred {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = self.len.fetch_add(s.len(), Ordering::Relaxed);
+        let bytes = unsafe { &mut *self.bytes.get() };
+        for (at, &byte) in (start..DEFERRED_SIZE).zip(s.as_bytes()) {
+            bytes[at] = byte;
+        }
+        Ok(())
+    }
+}
+
+/// The writer's lock, see above.
+pub struct ScreenLock {
+    id: LockId,
+    writer: UnsafeCell<Writer>,
+    // the CPU holding the lock, NO_OWNER while it is free
+    owner: AtomicUsize,
+    deferred: Deferred,
+}
+
+unsafe impl Sync for ScreenLock {}
+
+pub struct ScreenGuard<'a> {
+    screen: &'a ScreenLock,
+    interrupts_were_enabled: bool,
+}
+
+fn cpu_id() -> usize {
+    // the first CPU until it has its block
+    percpu::try_this_cpu().map_or(0, |cpu| cpu.cpu_id)
+}
+
+impl ScreenLock {
+    fn new(writer: Writer) -> Self {
+        ScreenLock {
+            id: LockId::new(),
+            writer: UnsafeCell::new(writer),
+            owner: AtomicUsize::new(NO_OWNER),
+            deferred: Deferred { bytes: UnsafeCell::new([0; DEFERRED_SIZE]), len: AtomicUsize::new(0) },
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> ScreenGuard<'_> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(&self.id, Location::caller());
+        let cpu = cpu_id();
+        while self.owner.compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        let mut guard = ScreenGuard { screen: self, interrupts_were_enabled };
+        guard.flush_deferred();
+        guard
+    }
+
+    /// Whether the running CPU holds the lock, an exception or NMI under the holder.
+    pub fn is_held_here(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == cpu_id()
+    }
+
+    /// Releases the lock without a guard, for the panic handler.
+    ///
+    /// # Safety
+    ///
+    /// The holder must never write to the screen again.
+    pub unsafe fn force_unlock(&self) {
+        lockdep::release(&self.id);
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+impl ScreenGuard<'_> {
+    fn flush_deferred(&mut self) {
+        let deferred = &self.screen.deferred;
+        let mut written = 0;
+        loop {
+            let len = deferred.len.load(Ordering::Relaxed);
+            let end = len.min(DEFERRED_SIZE);
+            let bytes = unsafe { &*deferred.bytes.get() };
+            self.write_bytes(&bytes[written.min(end)..end]);
+            written = end;
+            // more may have come from an NMI meanwhile
+            if deferred.len.compare_exchange(len, 0, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                if len > DEFERRED_SIZE {
+                    self.write_string("\n[output lost]\n");
+                }
+                return;
+            }
+        }
+    }
+}
+
+impl Deref for ScreenGuard<'_> {
+    type Target = Writer;
+
+    fn deref(&self) -> &Writer {
+        unsafe { &*self.screen.writer.get() }
+    }
+}
+
+impl DerefMut for ScreenGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Writer {
+        unsafe { &mut *self.screen.writer.get() }
+    }
+}
+
+impl Drop for ScreenGuard<'_> {
+    fn drop(&mut self) {
+        self.flush_deferred();
+        // unlock first, an interrupt right after enabling may want the screen
+        lockdep::release(&self.screen.id);
+        self.screen.owner.store(NO_OWNER, Ordering::Release);
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: ScreenLock = ScreenLock::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     });
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => {$crate::vga_buffer::_print(format_args!($($arg)*))};
-}
-#[macro_export]
-macro_rules! println {
-    () => {$crate::print!("\n")};
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
-}
-
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts::without_interrupts;
-
-    without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    if WRITER.is_held_here() {
+        let _ = (&WRITER.deferred).write_fmt(args);
+        return;
+    }
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::println;
+
     #[test_case]
     fn test_println_simple() {
         println!("test_println_simple output");
@@ -165,16 +366,47 @@ mod test {
     #[test_case]
     fn test_println_output() {
         use core::fmt::Write;
-        use x86_64::instructions::interrupts::without_interrupts;
-
-        without_interrupts(|| {
-            let mut writer = WRITER.lock();
-            let s = "Some test string that fits on a single line";
-            writeln!(writer, "\n{}", s).expect("test failed");
-            for (i, c) in s.chars().enumerate() {
-                let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-                assert_eq!(char::from(screen_char.ascii_character), c);
-            }
-        });
+
+        // holding the writer keeps the timer from printing in between
+        let mut writer = WRITER.lock();
+        let s = "Some test string that fits on a single line";
+        writeln!(writer, "\n{}", s).expect("test failed");
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    }
+
+    #[test_case]
+    fn test_write_at() {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nabcdef");
+        writer.write_at(2, "XY");
+        writer.write_at(BUFFER_WIDTH - 1, "Zcut");
+        assert_eq!(writer.column(), 6);
+        writer.set_column(1);
+        writer.write_string("_");
+        let row: alloc::string::String = (0..7).map(|col| char::from(writer.buffer.chars[BUFFER_HEIGHT - 1][col].read().ascii_character)).collect();
+        assert_eq!(row, "a_XYef ");
+        assert_eq!(writer.buffer.chars[BUFFER_HEIGHT - 1][BUFFER_WIDTH - 1].read().ascii_character, b'Z');
+        assert_eq!(writer.column(), 2);
+        writer.write_string("\n");
+    }
+
+    #[test_case]
+    fn test_print_under_the_holder() {
+        let row = |writer: &Writer, row: usize| -> alloc::string::String {
+            (0..8).map(|col| char::from(writer.buffer.chars[row][col].read().ascii_character)).collect()
+        };
+        let mut writer = WRITER.lock();
+        writer.write_string("\nholder");
+        // as an exception handler would, on the CPU holding the screen
+        println!("nested");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), "holder  ");
+        drop(writer);
+
+        let writer = WRITER.lock();
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 2), "holderne");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), "        ");
     }
 }
\ No newline at end of file
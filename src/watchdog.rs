@@ -0,0 +1,108 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::backtrace::Backtrace;
+use crate::interrupts::ExceptionStackFrame;
+use crate::{exit_qemu, halt_loop, serial_println, time, QemuExitCode};
+
+const MAX_WATCHDOGS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Watchdog {
+    name: &'static str,
+    timeout_ticks: u64,
+    last_pet: u64,
+}
+
+static WATCHDOGS: Mutex<[Option<Watchdog>; MAX_WATCHDOGS]> = Mutex::new([None; MAX_WATCHDOGS]);
+
+#[derive(Debug)]
+pub struct WatchdogHandle(usize);
+
+impl WatchdogHandle {
+    pub fn pet(&self) {
+        let now = time::ticks();
+        without_interrupts(|| {
+            if let Some(watchdog) = WATCHDOGS.lock()[self.0].as_mut() {
+                watchdog.last_pet = now;
+            }
+        });
+    }
+
+    pub fn set_timeout(&self, timeout_ms: u64) {
+        let now = time::ticks();
+        without_interrupts(|| {
+            if let Some(watchdog) = WATCHDOGS.lock()[self.0].as_mut() {
+                watchdog.timeout_ticks = time::ms_to_ticks(timeout_ms);
+                watchdog.last_pet = now;
+            }
+        });
+    }
+}
+
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        without_interrupts(|| WATCHDOGS.lock()[self.0] = None);
+    }
+}
+
+/// Starts a watchdog that fails the run unless `pet` is called at least every `timeout_ms`.
+/// Returns None when all slots are taken.
+pub fn register(name: &'static str, timeout_ms: u64) -> Option<WatchdogHandle> {
+    let now = time::ticks();
+    without_interrupts(|| {
+        let mut watchdogs = WATCHDOGS.lock();
+        let index = watchdogs.iter().position(|slot| slot.is_none())?;
+        watchdogs[index] = Some(Watchdog {
+            name,
+            timeout_ticks: time::ms_to_ticks(timeout_ms),
+            last_pet: now,
+        });
+        Some(WatchdogHandle(index))
+    })
+}
+
+/// Called from the timer interrupt.
+pub fn check(now: u64, stack_frame: &ExceptionStackFrame) {
+    // the interrupted code may be registering or petting, just look again next tick
+    let expired = match WATCHDOGS.try_lock() {
+        Some(watchdogs) => watchdogs.iter()
+            .flatten()
+            .find(|watchdog| now.saturating_sub(watchdog.last_pet) > watchdog.timeout_ticks)
+            .copied(),
+        None => None,
+    };
+
+    if let Some(watchdog) = expired {
+        bark(watchdog, now, stack_frame);
+    }
+}
+
+fn bark(watchdog: Watchdog, now: u64, stack_frame: &ExceptionStackFrame) -> ! {
+    // the interrupted context may hold the serial lock, we are never returning to it
+    unsafe { crate::serial::SERIAL1.force_unlock() };
+    serial_println!("[failed]\n");
+    serial_println!("WATCHDOG: '{}' not pet for {} ms (timeout {} ms, uptime {} ms)",
+        watchdog.name,
+        time::ticks_to_ms(now - watchdog.last_pet),
+        time::ticks_to_ms(watchdog.timeout_ticks),
+        time::ticks_to_ms(now));
+    serial_println!("interrupted context:\n{:#?}\n{}", stack_frame, Backtrace::from_exception(stack_frame));
+    exit_qemu(QemuExitCode::Timeout);
+    halt_loop();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn test_register_and_release_slot() {
+        let handle = register("test", 1000).expect("no free watchdog slot");
+        let index = handle.0;
+        handle.pet();
+        assert!(without_interrupts(|| WATCHDOGS.lock()[index].is_some()));
+        drop(handle);
+        assert!(without_interrupts(|| WATCHDOGS.lock()[index].is_none()));
+    }
+}
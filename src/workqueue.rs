@@ -0,0 +1,117 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::sync::SpinLock;
+use crate::thread::{self, WaitQueue};
+use crate::time::sleep::add_timer;
+use crate::time::{self, TimerAction};
+
+/* kernel work queues.
+    Work that may sleep or take a while is handed to a small pool of kernel threads
+    instead of running where it was requested, filesystem writeback and driver
+    housekeeping for example. Queueing never blocks, so it also works from interrupt
+    handlers; the work itself always runs in thread context.
+ */
+
+/// Worker threads started by `init`.
+pub const NUM_WORKERS: usize = 2;
+
+pub(crate) type Work = Box<dyn FnOnce() + Send + 'static>;
+
+static QUEUE: SpinLock<VecDeque<Work>> = SpinLock::new(VecDeque::new());
+// queued or running, `flush` waits for it to drop to zero
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static WORKERS: WaitQueue = WaitQueue::new();
+static FLUSHERS: WaitQueue = WaitQueue::new();
+
+pub fn init() {
+    for index in 0..NUM_WORKERS {
+        thread::spawn(&format!("kworker/{}", index), worker).detach();
+    }
+}
+
+/// Queues `f` to run on one of the worker threads.
+pub fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    queue(Box::new(f));
+}
+
+/// Queues `f` once `delay` has passed, it does not count as pending until then.
+pub fn spawn_after<F>(delay: Duration, f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let deadline = time::ticks() + time::duration_to_ticks(delay);
+    add_timer(deadline, TimerAction::Queue(Box::new(f)));
+}
+
+pub(crate) fn queue(work: Work) {
+    PENDING.fetch_add(1, Ordering::AcqRel);
+    QUEUE.lock().push_back(work);
+    WORKERS.wake_one();
+}
+
+/// Number of work items queued or running.
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Acquire)
+}
+
+/// Waits until every queued work item, including any queued meanwhile, has run.
+pub fn flush() {
+    FLUSHERS.wait_until(|| pending() == 0);
+}
+
+fn worker() {
+    loop {
+        let mut work = None;
+        WORKERS.wait_until(|| {
+            work = QUEUE.lock().pop_front();
+            work.is_some()
+        });
+        (work.expect("woken without work"))();
+        if PENDING.fetch_sub(1, Ordering::AcqRel) == 1 {
+            FLUSHERS.wake_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicU64;
+
+    #[test_case]
+    fn test_work_runs_on_workers() {
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+        static RAN_ON: AtomicU64 = AtomicU64::new(u64::MAX);
+
+        for _ in 0..8 {
+            spawn(|| {
+                thread::yield_now();
+                DONE.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        spawn(|| RAN_ON.store(thread::current_id().as_u64(), Ordering::SeqCst));
+        flush();
+        assert_eq!(pending(), 0);
+        assert_eq!(DONE.load(Ordering::SeqCst), 8);
+        assert_ne!(RAN_ON.load(Ordering::SeqCst), thread::current_id().as_u64());
+    }
+
+    #[test_case]
+    fn test_delayed_work() {
+        static RAN_AT: AtomicU64 = AtomicU64::new(0);
+
+        let start = time::ticks();
+        spawn_after(Duration::from_millis(30), || RAN_AT.store(time::ticks(), Ordering::SeqCst));
+        while RAN_AT.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        assert!(RAN_AT.load(Ordering::SeqCst) >= start + time::ms_to_ticks(30));
+    }
+}
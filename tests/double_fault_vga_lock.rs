@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use blog_os::backtrace::Backtrace;
+use blog_os::crash::{self, Registers};
+use blog_os::{exit_qemu, println, QemuExitCode, serial_print, serial_println};
+
+/* a double fault while the screen is locked.
+    The stack overflows with the VGA writer held, as if it happened in the middle of
+    printing; the kernel's double fault handler panics, and the panic handler, doing
+    what the kernel's does, has to get its report onto the screen all the same
+    rather than spin on the lock forever.
+ */
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("double_fault_vga_lock::double_fault_holding_the_writer...\t");
+
+    blog_os::init(boot_info);
+    // never dropped; the guard keeps interrupts off, the timer prints too
+    core::mem::forget(blog_os::vga_buffer::WRITER.lock());
+
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    let backtrace = Backtrace::capture();
+    blog_os::smp::halt_others();
+    crash::unlock_consoles();
+    println!("{}", info);
+    crash::dump(&registers, &backtrace);
+
+    let message = alloc::format!("{}", info.message());
+    if !message.starts_with("EXCEPTION: DOUBLE FAULT") {
+        serial_println!("[failed]\n\nError: {}\n", info);
+        exit_qemu(QemuExitCode::Panic);
+        blog_os::halt_loop();
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::halt_loop();
+}
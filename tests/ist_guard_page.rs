@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(naked_functions)]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+
+use blog_os::{exit_qemu, handler_with_error_code, QemuExitCode, serial_print, serial_println};
+use blog_os::interrupts::ExceptionStackFrame;
+use blog_os::interrupts::idt::{IdtIndex, Idt, CpuExceptionIndex};
+use blog_os::memory::PAGE_SIZE;
+
+/* the guard page of the double fault stack.
+    The double fault handler overflows its own IST stack on purpose. It runs into
+    the guard page below, the page fault cannot be pushed there either, and the
+    double fault that makes starts at the top of the IST stack again: the second
+    entry passes when CR2 says the guard page was hit. Without the guard the
+    handler would write on through the statics below the stack.
+ */
+
+entry_point!(main);
+
+static ENTRIES: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("ist_guard_page::overflow_double_fault_stack...\t");
+
+    blog_os::init(boot_info);
+    // the test table has nothing for the timer
+    x86_64::instructions::interrupts::disable();
+    let guard = blog_os::gdt::double_fault_guard_page();
+    if blog_os::memory::is_mapped(guard) {
+        serial_println!("[failed]\n\nError: the guard page at {:#x} is mapped\n", guard);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    init_test_idt();
+
+    // the first double fault, on the bootloader's stack
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info);
+}
+
+lazy_static! {
+    static ref TEST_IDT: Idt = {
+        let mut idt = Idt::new();
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::DoubleFault),
+                handler_with_error_code!(test_double_fault_handler))
+            .set_stack_index(blog_os::gdt::ISTIndex::DoubleFaultISTIndex as u16);
+        idt
+    };
+}
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "C" fn test_double_fault_handler(_stack_frame: &ExceptionStackFrame, _error_code: u64) -> ! {
+    if ENTRIES.fetch_add(1, Ordering::SeqCst) == 0 {
+        stack_overflow();
+        serial_println!("[failed]\n\nError: the double fault stack overflowed without faulting\n");
+        exit_qemu(QemuExitCode::Failed);
+        blog_os::halt_loop()
+    }
+    let guard = blog_os::gdt::double_fault_guard_page();
+    let address = Cr2::read_raw();
+    if !(guard..guard + PAGE_SIZE).contains(&address) {
+        serial_println!("[failed]\n\nError: faulted at {:#x}, the guard page is at {:#x}\n", address, guard);
+        exit_qemu(QemuExitCode::Failed);
+        blog_os::halt_loop()
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::halt_loop()
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(naked_functions)]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+
+use blog_os::{exit_qemu, handler, handler_with_error_code, QemuExitCode, serial_print, serial_println};
+use blog_os::interrupts::ExceptionStackFrame;
+use blog_os::interrupts::idt::{IdtIndex, Idt, CpuExceptionIndex};
+
+/* exceptions inside exception handlers.
+    A breakpoint handler that hits breakpoints itself, three deep, has to come back
+    out to the test with its state as it was. Then a page fault handler that faults
+    again and again nests on the bootloader's stack until there is no room left for
+    another frame, and the double fault that makes comes in on its IST stack.
+ */
+
+const BREAKPOINT_DEPTH: usize = 3;
+
+static BREAKPOINTS: AtomicUsize = AtomicUsize::new(0);
+static DEEPEST_BREAKPOINT: AtomicUsize = AtomicUsize::new(0);
+static PAGE_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::gdt::init();
+    init_test_idt();
+
+    serial_print!("nested_exceptions::breakpoint_in_breakpoint...\t");
+    let before = core::hint::black_box(0x5eed_u64);
+    x86_64::instructions::interrupts::int3();
+    let deepest = DEEPEST_BREAKPOINT.load(Ordering::SeqCst);
+    if deepest != BREAKPOINT_DEPTH || BREAKPOINTS.load(Ordering::SeqCst) != 0 || before != 0x5eed {
+        serial_println!("[failed]\n\nError: {} breakpoints deep, {} left\n", deepest, BREAKPOINTS.load(Ordering::SeqCst));
+        exit_qemu(QemuExitCode::Failed);
+    }
+    serial_println!("[ok]");
+
+    serial_print!("nested_exceptions::page_fault_in_page_fault...\t");
+    unsafe { core::ptr::null::<u64>().read_volatile() };
+
+    panic!("Execution continued after the page faults");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info);
+}
+
+lazy_static! {
+    static ref TEST_IDT: Idt = {
+        let mut idt = Idt::new();
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::Breakpoint), handler!(test_breakpoint_handler));
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::PageFault),
+                handler_with_error_code!(test_page_fault_handler));
+        idt.set_handler(IdtIndex::CpuException(CpuExceptionIndex::DoubleFault),
+                handler_with_error_code!(test_double_fault_handler))
+            .set_stack_index(blog_os::gdt::ISTIndex::DoubleFaultISTIndex as u16);
+        idt
+    };
+}
+
+fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "C" fn test_breakpoint_handler(_stack_frame: &ExceptionStackFrame) {
+    let depth = BREAKPOINTS.fetch_add(1, Ordering::SeqCst) + 1;
+    DEEPEST_BREAKPOINT.fetch_max(depth, Ordering::SeqCst);
+    if depth < BREAKPOINT_DEPTH {
+        x86_64::instructions::interrupts::int3();
+    }
+    BREAKPOINTS.fetch_sub(1, Ordering::SeqCst);
+}
+
+extern "C" fn test_page_fault_handler(_stack_frame: &ExceptionStackFrame, _error_code: u64) {
+    PAGE_FAULTS.fetch_add(1, Ordering::SeqCst);
+    unsafe { core::ptr::null::<u64>().read_volatile() };
+}
+
+extern "C" fn test_double_fault_handler(_stack_frame: &ExceptionStackFrame, _error_code: u64) -> ! {
+    let page_faults = PAGE_FAULTS.load(Ordering::SeqCst);
+    if page_faults < 2 {
+        serial_println!("[failed]\n\nError: double fault after {} page faults\n", page_faults);
+        exit_qemu(QemuExitCode::Failed);
+        blog_os::halt_loop()
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::halt_loop()
+}
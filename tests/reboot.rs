@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+use blog_os::{cmos, exit_qemu, power, QemuExitCode, serial_print, serial_println};
+
+/* rebooting.
+    The first boot leaves a marker in the CMOS, which outlives the reset, and
+    reboots; QEMU comes back up into this test again, which finds the marker and
+    passes. A reboot that did not happen hangs or panics instead.
+ */
+
+entry_point!(main);
+
+const MARKER: u8 = 0x5a;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info);
+
+    if cmos::read(cmos::SCRATCH) == MARKER {
+        cmos::write(cmos::SCRATCH, 0);
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        blog_os::halt_loop();
+    }
+
+    serial_print!("reboot::reboot_and_come_back...\t");
+    cmos::write(cmos::SCRATCH, MARKER);
+    power::reboot();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cmos::write(cmos::SCRATCH, 0);
+    blog_os::test_panic_handler(info);
+}
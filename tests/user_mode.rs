@@ -0,0 +1,529 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use blog_os::memory::address_space::USER_START;
+use blog_os::memory::{frame, PAGE_SIZE};
+use blog_os::process::elf::UserEntry;
+use blog_os::process::signal::{self, SA_RESTORER, SIGINT, SIGTERM, SIGUSR1};
+use blog_os::process::user::{self, SIGSEGV};
+use blog_os::process::{Process, ProcessId};
+use blog_os::thread::{self, ExitCode, Thread};
+use blog_os::{fs, tty};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init(boot_info);
+    test_main();
+    blog_os::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info);
+}
+
+const DATA_ADDRESS: u64 = USER_START + 2 * PAGE_SIZE;
+
+/// Maps `code` at the start of user space, `data` in a writable page at `DATA_ADDRESS`
+/// and a one page stack above it, then starts the code in ring 3.
+fn start_user_with_data(code: &[u8], data: &[u8]) -> (ProcessId, Thread) {
+    let mut process = Process::create("user-test").expect("out of frames");
+    let space = process.address_space_mut();
+    let code_page = Page::containing_address(VirtAddr::new(USER_START));
+    let data_page = Page::containing_address(VirtAddr::new(DATA_ADDRESS));
+    let stack_page = Page::containing_address(VirtAddr::new(USER_START + 4 * PAGE_SIZE));
+    let data_flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map_zeroed(code_page, PageTableFlags::USER_ACCESSIBLE).expect("map failed");
+    space.map_zeroed(data_page, data_flags).expect("map failed");
+    space.map_zeroed(stack_page, data_flags).expect("map failed");
+    assert!(space.copy_to(VirtAddr::new(USER_START), code));
+    assert!(space.copy_to(VirtAddr::new(DATA_ADDRESS), data));
+
+    let entry = UserEntry {
+        instruction_pointer: VirtAddr::new(USER_START),
+        stack_pointer: VirtAddr::new(USER_START + 5 * PAGE_SIZE),
+    };
+    let id = process.id();
+    (id, user::spawn(Arc::new(Mutex::new(process)), entry))
+}
+
+fn start_user(code: &[u8]) -> (ProcessId, Thread) {
+    start_user_with_data(code, &[])
+}
+
+fn run_user(code: &[u8]) -> ExitCode {
+    start_user(code).1.join()
+}
+
+#[test_case]
+fn test_hlt_in_user_mode_is_killed() {
+    // hlt is privileged, ring 3 gets a general protection fault
+    assert_eq!(run_user(&[0xf4]), user::killed_by(SIGSEGV));
+}
+
+#[test_case]
+fn test_reading_kernel_memory_is_killed() {
+    static SECRET: u64 = 0x5ec7e7;
+    // mov rax, [SECRET]
+    let mut code = [0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0];
+    code[2..].copy_from_slice(&(&SECRET as *const u64 as u64).to_le_bytes());
+    assert_eq!(run_user(&code), user::killed_by(SIGSEGV));
+}
+
+#[test_case]
+fn test_killed_process_frees_its_frames() {
+    let before = frame::allocated_frames();
+    for _ in 0..3 {
+        assert_eq!(run_user(&[0xf4]), user::killed_by(SIGSEGV));
+    }
+    // the dead threads, and with them their processes, are reaped on the next switch
+    thread::yield_now();
+    assert_eq!(frame::allocated_frames(), before);
+}
+
+#[test_case]
+fn test_hello_world() {
+    let mut code = alloc::vec![
+        0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, 1 (write)
+        0xbf, 0x01, 0x00, 0x00, 0x00,       // mov edi, 1
+        0x48, 0x8d, 0x35, 0x10, 0, 0, 0,    // lea rsi, [rip + message]
+        0xba, 0x06, 0x00, 0x00, 0x00,       // mov edx, 6
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ];
+    code.extend_from_slice(b"hello\n");
+    // exits with what write returned
+    assert_eq!(run_user(&code), ExitCode(6));
+}
+
+#[test_case]
+fn test_echo() {
+    let code = [
+        0x48, 0x83, 0xec, 0x40,             // sub rsp, 64
+        0x31, 0xc0,                         // xor eax, eax (read)
+        0x31, 0xff,                         // xor edi, edi
+        0x48, 0x89, 0xe6,                   // mov rsi, rsp
+        0xba, 0x40, 0x00, 0x00, 0x00,       // mov edx, 64
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc2,                         // mov edx, eax
+        0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, 1 (write)
+        0xbf, 0x01, 0x00, 0x00, 0x00,       // mov edi, 1
+        0x48, 0x89, 0xe6,                   // mov rsi, rsp
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ];
+    let (_, thread) = start_user(&code);
+    tty::push_input(b"hi\n");
+    assert_eq!(thread.join(), ExitCode(3));
+}
+
+#[test_case]
+fn test_getpid_through_int_0x80() {
+    let code = [
+        0xb8, 0x27, 0x00, 0x00, 0x00,       // mov eax, 39 (getpid)
+        0xcd, 0x80,                         // int 0x80
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0xcd, 0x80,                         // int 0x80
+    ];
+    let (id, thread) = start_user(&code);
+    assert_eq!(thread.join(), ExitCode(id.as_u64() as i32));
+}
+
+#[test_case]
+fn test_bad_pointer_returns_efault() {
+    let code = [
+        0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, 1 (write)
+        0xbf, 0x01, 0x00, 0x00, 0x00,       // mov edi, 1
+        0xbe, 0x00, 0x00, 0x20, 0x00,       // mov esi, 0x200000 (kernel image)
+        0xba, 0x04, 0x00, 0x00, 0x00,       // mov edx, 4
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ];
+    assert_eq!(run_user(&code), ExitCode(-14));
+}
+
+#[test_case]
+fn test_fork_parent_and_child_write_independently() {
+    let mut code = alloc::vec![0x48, 0xbb];                 // mov rbx, DATA_ADDRESS
+    code.extend_from_slice(&DATA_ADDRESS.to_le_bytes());
+    code.extend_from_slice(&[
+        0xb8, 0x39, 0x00, 0x00, 0x00,       // mov eax, 57 (fork)
+        0x0f, 0x05,                         // syscall
+        0x85, 0xc0,                         // test eax, eax
+        0x75, 0x0f,                         // jnz parent
+        // child
+        0xc7, 0x03, 0x07, 0x00, 0x00, 0x00, // mov dword [rbx], 7
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x31, 0xff,                         // xor edi, edi
+        0x0f, 0x05,                         // syscall
+        // parent, gives the child time to write before looking
+        0x48, 0x8d, 0x7b, 0x08,             // lea rdi, [rbx + 8]
+        0x31, 0xf6,                         // xor esi, esi
+        0xb8, 0x23, 0x00, 0x00, 0x00,       // mov eax, 35 (nanosleep)
+        0x0f, 0x05,                         // syscall
+        0x8b, 0x3b,                         // mov edi, [rbx]
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ]);
+    let mut data = [0u8; 24];
+    data[0] = 5;
+    // timespec of 20 ms for the parent's nanosleep
+    data[16..24].copy_from_slice(&20_000_000u64.to_le_bytes());
+
+    let before = frame::allocated_frames();
+    let (_, parent) = start_user_with_data(&code, &data);
+    assert_eq!(parent.join(), ExitCode(5));
+    thread::yield_now();
+    assert_eq!(frame::allocated_frames(), before);
+}
+
+/// A static executable with a single segment holding the headers followed by `code`.
+fn build_elf(code: &[u8]) -> alloc::vec::Vec<u8> {
+    const HEADERS_SIZE: u64 = 64 + 56;
+    let size = HEADERS_SIZE + code.len() as u64;
+    let mut elf = alloc::vec::Vec::new();
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend_from_slice(&2u16.to_le_bytes());                     // ET_EXEC
+    elf.extend_from_slice(&0x3eu16.to_le_bytes());                  // x86_64
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&(USER_START + HEADERS_SIZE).to_le_bytes());
+    elf.extend_from_slice(&64u64.to_le_bytes());                    // program headers
+    elf.extend_from_slice(&0u64.to_le_bytes());                     // no sections
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&[64, 0, 56, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend_from_slice(&1u32.to_le_bytes());                     // PT_LOAD
+    elf.extend_from_slice(&5u32.to_le_bytes());                     // read, execute
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&USER_START.to_le_bytes());
+    elf.extend_from_slice(&USER_START.to_le_bytes());
+    elf.extend_from_slice(&size.to_le_bytes());
+    elf.extend_from_slice(&size.to_le_bytes());
+    elf.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+    elf.extend_from_slice(code);
+    elf
+}
+
+/// Calls `execve(DATA_ADDRESS, DATA_ADDRESS + 32, NULL)` and exits with its result if it returns.
+fn exec_from_data() -> [u8; 32] {
+    let mut code = [0u8; 32];
+    code[..2].copy_from_slice(&[0x48, 0xbf]);                       // mov rdi, DATA_ADDRESS
+    code[2..10].copy_from_slice(&DATA_ADDRESS.to_le_bytes());
+    code[10..].copy_from_slice(&[
+        0x48, 0x8d, 0x77, 0x20,             // lea rsi, [rdi + 32]
+        0x31, 0xd2,                         // xor edx, edx
+        0xb8, 0x3b, 0x00, 0x00, 0x00,       // mov eax, 59 (execve)
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ]);
+    code
+}
+
+/// Data for `exec_from_data`: the path, then an argv of `argc` copies of "x".
+fn exec_data(path: &str, argc: usize) -> alloc::vec::Vec<u8> {
+    let mut data = alloc::vec![0u8; 32];
+    data[..path.len()].copy_from_slice(path.as_bytes());
+    data[16] = b'x';
+    for _ in 0..argc {
+        data.extend_from_slice(&(DATA_ADDRESS + 16).to_le_bytes());
+    }
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data
+}
+
+#[test_case]
+fn test_execve_runs_new_image_with_arguments() {
+    fs::create_dir_all("/bin").unwrap();
+    fs::write_file("/bin/argc", &build_elf(&[
+        0x8b, 0x3c, 0x24,                   // mov edi, [rsp] (argc)
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ])).unwrap();
+    let before = frame::allocated_frames();
+    let (_, thread) = start_user_with_data(&exec_from_data(), &exec_data("/bin/argc", 3));
+    assert_eq!(thread.join(), ExitCode(3));
+    thread::yield_now();
+    assert_eq!(frame::allocated_frames(), before);
+}
+
+#[test_case]
+fn test_execve_failure_returns_to_caller() {
+    let (_, thread) = start_user_with_data(&exec_from_data(), &exec_data("/bin/missing", 1));
+    assert_eq!(thread.join(), ExitCode(-2));
+
+    fs::create_dir_all("/bin").unwrap();
+    fs::write_file("/bin/garbage", b"not an executable").unwrap();
+    let (_, thread) = start_user_with_data(&exec_from_data(), &exec_data("/bin/garbage", 1));
+    assert_eq!(thread.join(), ExitCode(-8));
+}
+
+#[test_case]
+fn test_wait_collects_child_exit_code() {
+    let mut code = alloc::vec![0x48, 0xbb];                 // mov rbx, DATA_ADDRESS
+    code.extend_from_slice(&DATA_ADDRESS.to_le_bytes());
+    code.extend_from_slice(&[
+        0xb8, 0x39, 0x00, 0x00, 0x00,       // mov eax, 57 (fork)
+        0x0f, 0x05,                         // syscall
+        0x85, 0xc0,                         // test eax, eax
+        0x75, 0x0c,                         // jnz parent
+        // child
+        0xbf, 0x07, 0x00, 0x00, 0x00,       // mov edi, 7
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+        // parent
+        0xbf, 0xff, 0xff, 0xff, 0xff,       // mov edi, -1
+        0x48, 0x89, 0xde,                   // mov rsi, rbx
+        0x31, 0xd2,                         // xor edx, edx
+        0x45, 0x31, 0xd2,                   // xor r10d, r10d
+        0xb8, 0x3d, 0x00, 0x00, 0x00,       // mov eax, 61 (wait4)
+        0x0f, 0x05,                         // syscall
+        // a second wait finds no child left
+        0xbf, 0xff, 0xff, 0xff, 0xff,       // mov edi, -1
+        0x31, 0xf6,                         // xor esi, esi
+        0xb8, 0x3d, 0x00, 0x00, 0x00,       // mov eax, 61 (wait4)
+        0x0f, 0x05,                         // syscall
+        0x8b, 0x3b,                         // mov edi, [rbx]
+        0xc1, 0xef, 0x08,                   // shr edi, 8
+        0x01, 0xc7,                         // add edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ]);
+
+    let before = frame::allocated_frames();
+    let (_, parent) = start_user_with_data(&code, &[]);
+    // the child's exit code 7 plus -ECHILD from the second wait
+    assert_eq!(parent.join(), ExitCode(7 - 10));
+    thread::yield_now();
+    assert_eq!(frame::allocated_frames(), before);
+}
+
+#[test_case]
+fn test_wait_reports_child_killed_by_signal() {
+    let mut code = alloc::vec![0x48, 0xbb];                 // mov rbx, DATA_ADDRESS
+    code.extend_from_slice(&DATA_ADDRESS.to_le_bytes());
+    code.extend_from_slice(&[
+        0xb8, 0x39, 0x00, 0x00, 0x00,       // mov eax, 57 (fork)
+        0x0f, 0x05,                         // syscall
+        0x85, 0xc0,                         // test eax, eax
+        0x75, 0x01,                         // jnz parent
+        // child
+        0xf4,                               // hlt, killed with SIGSEGV
+        // parent
+        0xbf, 0xff, 0xff, 0xff, 0xff,       // mov edi, -1
+        0x48, 0x89, 0xde,                   // mov rsi, rbx
+        0x31, 0xd2,                         // xor edx, edx
+        0x45, 0x31, 0xd2,                   // xor r10d, r10d
+        0xb8, 0x3d, 0x00, 0x00, 0x00,       // mov eax, 61 (wait4)
+        0x0f, 0x05,                         // syscall
+        0x8b, 0x3b,                         // mov edi, [rbx]
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ]);
+
+    let (_, parent) = start_user_with_data(&code, &[]);
+    // WIFSIGNALED with WTERMSIG of SIGSEGV, not an exit code of 139
+    assert_eq!(parent.join(), ExitCode(SIGSEGV));
+}
+
+#[test_case]
+fn test_shared_memory_survives_fork() {
+    let code = [
+        0x31, 0xff,                         // xor edi, edi (IPC_PRIVATE)
+        0xbe, 0x00, 0x10, 0x00, 0x00,       // mov esi, 4096
+        0x31, 0xd2,                         // xor edx, edx
+        0xb8, 0x1d, 0x00, 0x00, 0x00,       // mov eax, 29 (shmget)
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0x31, 0xf6,                         // xor esi, esi
+        0x31, 0xd2,                         // xor edx, edx
+        0xb8, 0x1e, 0x00, 0x00, 0x00,       // mov eax, 30 (shmat)
+        0x0f, 0x05,                         // syscall
+        0x48, 0x89, 0xc3,                   // mov rbx, rax
+        0xb8, 0x39, 0x00, 0x00, 0x00,       // mov eax, 57 (fork)
+        0x0f, 0x05,                         // syscall
+        0x85, 0xc0,                         // test eax, eax
+        0x75, 0x0f,                         // jnz parent
+        // child
+        0xc7, 0x03, 0x2a, 0x00, 0x00, 0x00, // mov dword [rbx], 42
+        0x31, 0xff,                         // xor edi, edi
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+        // parent
+        0xbf, 0xff, 0xff, 0xff, 0xff,       // mov edi, -1
+        0x31, 0xf6,                         // xor esi, esi
+        0x31, 0xd2,                         // xor edx, edx
+        0x45, 0x31, 0xd2,                   // xor r10d, r10d
+        0xb8, 0x3d, 0x00, 0x00, 0x00,       // mov eax, 61 (wait4)
+        0x0f, 0x05,                         // syscall
+        0x8b, 0x3b,                         // mov edi, [rbx]
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ];
+
+    let before = frame::allocated_frames();
+    // the child's write is visible to the parent, no copy-on-write in between
+    assert_eq!(run_user(&code), ExitCode(42));
+    thread::yield_now();
+    assert_eq!(frame::allocated_frames(), before);
+}
+
+#[test_case]
+fn test_kill_with_default_action_terminates() {
+    let code = [
+        0xb8, 0x27, 0x00, 0x00, 0x00,       // mov eax, 39 (getpid)
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xbe, 0x0f, 0x00, 0x00, 0x00,       // mov esi, 15 (SIGTERM)
+        0xb8, 0x3e, 0x00, 0x00, 0x00,       // mov eax, 62 (kill)
+        0x0f, 0x05,                         // syscall
+        0x31, 0xff,                         // xor edi, edi
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ];
+    assert_eq!(run_user(&code), user::killed_by(SIGTERM));
+}
+
+#[test_case]
+fn test_signal_handler_runs_and_returns() {
+    const HANDLER: usize = 0x50;
+    const RESTORER: usize = 0x60;
+    let mut code = alloc::vec![0x48, 0xbb];                 // mov rbx, DATA_ADDRESS
+    code.extend_from_slice(&DATA_ADDRESS.to_le_bytes());
+    code.extend_from_slice(&[
+        0xbf, 0x0a, 0x00, 0x00, 0x00,       // mov edi, 10 (SIGUSR1)
+        0x48, 0x89, 0xde,                   // mov rsi, rbx (the sigaction)
+        0x31, 0xd2,                         // xor edx, edx
+        0x41, 0xba, 0x08, 0x00, 0x00, 0x00, // mov r10d, 8
+        0xb8, 0x0d, 0x00, 0x00, 0x00,       // mov eax, 13 (rt_sigaction)
+        0x0f, 0x05,                         // syscall
+        0xb8, 0x27, 0x00, 0x00, 0x00,       // mov eax, 39 (getpid)
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xbe, 0x0a, 0x00, 0x00, 0x00,       // mov esi, 10 (SIGUSR1)
+        0xb8, 0x3e, 0x00, 0x00, 0x00,       // mov eax, 62 (kill)
+        0x0f, 0x05,                         // syscall
+        // back from the handler, with rbx and kill's result restored
+        0x8b, 0x7b, 0x20,                   // mov edi, [rbx + 32]
+        0x01, 0xc7,                         // add edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ]);
+    code.resize(HANDLER, 0xcc);
+    code.extend_from_slice(&[
+        0x89, 0x7b, 0x20,                   // mov [rbx + 32], edi (the signal number)
+        0x31, 0xdb,                         // xor ebx, ebx
+        0xc3,                               // ret
+    ]);
+    code.resize(RESTORER, 0xcc);
+    code.extend_from_slice(&[
+        0xb8, 0x0f, 0x00, 0x00, 0x00,       // mov eax, 15 (rt_sigreturn)
+        0x0f, 0x05,                         // syscall
+    ]);
+
+    let mut data = alloc::vec::Vec::new();
+    for word in [USER_START + HANDLER as u64, SA_RESTORER, USER_START + RESTORER as u64, 0] {
+        data.extend_from_slice(&word.to_le_bytes());
+    }
+    let (_, thread) = start_user_with_data(&code, &data);
+    assert_eq!(thread.join(), ExitCode(10));
+}
+
+#[test_case]
+fn test_signal_handler_runs_in_a_loop_without_syscalls() {
+    const HANDLER: usize = 0x50;
+    const RESTORER: usize = 0x60;
+    let mut code = alloc::vec![0x48, 0xbb];                 // mov rbx, DATA_ADDRESS
+    code.extend_from_slice(&DATA_ADDRESS.to_le_bytes());
+    code.extend_from_slice(&[
+        0xbf, 0x0a, 0x00, 0x00, 0x00,       // mov edi, 10 (SIGUSR1)
+        0x48, 0x89, 0xde,                   // mov rsi, rbx (the sigaction)
+        0x31, 0xd2,                         // xor edx, edx
+        0x41, 0xba, 0x08, 0x00, 0x00, 0x00, // mov r10d, 8
+        0xb8, 0x0d, 0x00, 0x00, 0x00,       // mov eax, 13 (rt_sigaction)
+        0x0f, 0x05,                         // syscall
+        // spin until the handler has run, with rbx restored after it
+        0x83, 0x7b, 0x20, 0x00,             // cmp dword [rbx + 32], 0
+        0x74, 0xfa,                         // je back to the cmp
+        0x8b, 0x7b, 0x20,                   // mov edi, [rbx + 32]
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ]);
+    code.resize(HANDLER, 0xcc);
+    code.extend_from_slice(&[
+        0x89, 0x7b, 0x20,                   // mov [rbx + 32], edi (the signal number)
+        0x31, 0xdb,                         // xor ebx, ebx
+        0xc3,                               // ret
+    ]);
+    code.resize(RESTORER, 0xcc);
+    code.extend_from_slice(&[
+        0xb8, 0x0f, 0x00, 0x00, 0x00,       // mov eax, 15 (rt_sigreturn)
+        0x0f, 0x05,                         // syscall
+    ]);
+
+    let mut data = alloc::vec::Vec::new();
+    for word in [USER_START + HANDLER as u64, SA_RESTORER, USER_START + RESTORER as u64, 0] {
+        data.extend_from_slice(&word.to_le_bytes());
+    }
+    let (id, thread) = start_user_with_data(&code, &data);
+    // let it install the handler and start spinning first
+    for _ in 0..10 {
+        thread::yield_now();
+    }
+    assert!(signal::send(id, SIGUSR1));
+    assert_eq!(thread.join(), ExitCode(10));
+}
+
+#[test_case]
+fn test_ctrl_c_interrupts_running_process() {
+    // jmp $
+    let (id, thread) = start_user(&[0xeb, 0xfe]);
+    tty::set_foreground(Some(id));
+    tty::push_char(tty::INTERRUPT);
+    assert_eq!(thread.join(), user::killed_by(SIGINT));
+    tty::set_foreground(None);
+}
+
+#[test_case]
+fn test_ctrl_c_interrupts_blocked_read() {
+    let code = [
+        0x48, 0x83, 0xec, 0x40,             // sub rsp, 64
+        0x31, 0xc0,                         // xor eax, eax (read)
+        0x31, 0xff,                         // xor edi, edi
+        0x48, 0x89, 0xe6,                   // mov rsi, rsp
+        0xba, 0x40, 0x00, 0x00, 0x00,       // mov edx, 64
+        0x0f, 0x05,                         // syscall
+        0x89, 0xc7,                         // mov edi, eax
+        0xb8, 0x3c, 0x00, 0x00, 0x00,       // mov eax, 60 (exit)
+        0x0f, 0x05,                         // syscall
+    ];
+    let (id, thread) = start_user(&code);
+    tty::set_foreground(Some(id));
+    // let it block in read first
+    for _ in 0..10 {
+        thread::yield_now();
+    }
+    tty::push_char(tty::INTERRUPT);
+    assert_eq!(thread.join(), user::killed_by(SIGINT));
+    tty::set_foreground(None);
+}